@@ -0,0 +1,1211 @@
+use std::collections::HashMap;
+use std::fs;
+use encoding_rs::WINDOWS_1252;
+use crate::ConversionResult;
+use crate::converters::document_converter::convert_docx_to_txt;
+use crate::converters::docx_reader::convert_docx_to_html;
+use crate::converters::pdf_tools::finish;
+use crate::converters::rich_text::{
+    css_color, embedded_sources, html_document, plain_text, push_paragraph, store_image, Block, CellMerge, Format,
+    Inline, ListEntry, MergedCells, Paragraph, RichDocument, TableRow,
+};
+use crate::converters::rtf_converter::{convert_rtf_to_html, convert_rtf_to_txt, hyperlink_target};
+use crate::converters::text_tools::{is_monospace_font, list_number};
+
+// ---------------------------------------------------------------------------
+// Reading
+// ---------------------------------------------------------------------------
+
+/// Convert a Word 97-2003 (.doc) file to plain text, one line per paragraph.
+/// Files that are really RTF or DOCX with a .doc extension are passed on to
+/// those readers
+pub fn convert_doc_to_txt(input_path: &str, output_path: &str) -> ConversionResult {
+    match disguised_format(input_path) {
+        Some("rtf") => convert_rtf_to_txt(input_path, output_path),
+        Some("docx") => convert_docx_to_txt(input_path, output_path),
+        _ => finish(try_convert_doc_to_txt(input_path, output_path), output_path),
+    }
+}
+
+fn try_convert_doc_to_txt(input_path: &str, output_path: &str) -> Result<String, String> {
+    let doc = read_doc(input_path)?;
+    fs::write(output_path, plain_text(&doc, "\n")).map_err(|e| format!("Failed to write text file: {}", e))?;
+    Ok(doc.summary())
+}
+
+/// Convert a Word 97-2003 (.doc) file to HTML, keeping headings, bold,
+/// italic, underline, colors, links, lists, tables, footnotes and inline
+/// PNG/JPEG pictures
+pub fn convert_doc_to_html(input_path: &str, output_path: &str) -> ConversionResult {
+    match disguised_format(input_path) {
+        Some("rtf") => convert_rtf_to_html(input_path, output_path),
+        Some("docx") => convert_docx_to_html(input_path, output_path),
+        _ => finish(try_convert_doc_to_html(input_path, output_path), output_path),
+    }
+}
+
+fn try_convert_doc_to_html(input_path: &str, output_path: &str) -> Result<String, String> {
+    let doc = read_doc(input_path)?;
+    let html = html_document(&doc, &embedded_sources(&doc), input_path);
+    fs::write(output_path, &html).map_err(|e| format!("Failed to write HTML file: {}", e))?;
+    Ok(doc.summary())
+}
+
+/// Old archives often hold RTF or DOCX files saved with a .doc extension
+fn disguised_format(input_path: &str) -> Option<&'static str> {
+    let mut head = [0u8; 5];
+    let mut file = fs::File::open(input_path).ok()?;
+    std::io::Read::read_exact(&mut file, &mut head).ok()?;
+    if head.starts_with(b"{\\rtf") {
+        Some("rtf")
+    } else if head.starts_with(b"PK\x03\x04") {
+        Some("docx")
+    } else {
+        None
+    }
+}
+
+// Compound File Binary container
+
+const CFB_SIGNATURE: [u8; 8] = [0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
+const MAX_REGULAR_SECTOR: u32 = 0xFFFF_FFFA;
+
+struct DirEntry {
+    name: String,
+    kind: u8,
+    start: u32,
+    size: u64,
+}
+
+/// The streams of an OLE2 compound file, read from memory
+struct CompoundFile {
+    data: Vec<u8>,
+    sector_size: usize,
+    fat: Vec<u32>,
+    mini_fat: Vec<u32>,
+    mini_stream: Vec<u8>,
+    mini_cutoff: u64,
+    entries: Vec<DirEntry>,
+}
+
+impl CompoundFile {
+    fn parse(data: Vec<u8>) -> Result<Self, String> {
+        if data.len() < 512 || data[..8] != CFB_SIGNATURE {
+            return Err("Not a Word 97-2003 document (missing OLE2 header)".to_string());
+        }
+        let sector_shift = le_u16(&data, 0x1E) as u32;
+        if !(7..=16).contains(&sector_shift) {
+            return Err("Invalid OLE2 sector size".to_string());
+        }
+        let mut file = CompoundFile {
+            data,
+            sector_size: 1 << sector_shift,
+            fat: Vec::new(),
+            mini_fat: Vec::new(),
+            mini_stream: Vec::new(),
+            mini_cutoff: 4096,
+            entries: Vec::new(),
+        };
+        file.mini_cutoff = le_u32(&file.data, 0x38) as u64;
+
+        // The first 109 FAT sector numbers are in the header, the rest in a
+        // chain of DIFAT sectors
+        let mut fat_sectors: Vec<u32> = (0..109).map(|i| le_u32(&file.data, 0x4C + i * 4)).collect();
+        let mut difat = le_u32(&file.data, 0x44);
+        let per_sector = file.sector_size / 4 - 1;
+        let mut seen = vec![false; file.data.len() / file.sector_size];
+        while difat <= MAX_REGULAR_SECTOR {
+            let sector = file.sector(difat).ok_or("Truncated OLE2 DIFAT")?;
+            match seen.get_mut(difat as usize) {
+                Some(visited) if !*visited => *visited = true,
+                _ => break,
+            }
+            fat_sectors.extend((0..per_sector).map(|i| le_u32(sector, i * 4)));
+            difat = le_u32(sector, per_sector * 4);
+        }
+        let mut fat = Vec::new();
+        for &n in fat_sectors.iter().filter(|&&n| n <= MAX_REGULAR_SECTOR) {
+            let sector = file.sector(n).ok_or("Truncated OLE2 FAT")?;
+            fat.extend((0..file.sector_size / 4).map(|i| le_u32(sector, i * 4)));
+        }
+        file.fat = fat;
+
+        let directory = file.chain(le_u32(&file.data, 0x30));
+        file.entries = directory
+            .chunks_exact(128)
+            .map(|entry| {
+                let name_len = (le_u16(entry, 0x40) as usize).min(64);
+                let units: Vec<u16> = (0..name_len.saturating_sub(2) / 2).map(|i| le_u16(entry, i * 2)).collect();
+                DirEntry {
+                    name: String::from_utf16_lossy(&units),
+                    kind: entry[0x42],
+                    start: le_u32(entry, 0x74),
+                    size: le_u32(entry, 0x78) as u64 | (le_u32(entry, 0x7C) as u64) << 32,
+                }
+            })
+            .collect();
+        let mini_fat = file.chain(le_u32(&file.data, 0x3C));
+        file.mini_fat = (0..mini_fat.len() / 4).map(|i| le_u32(&mini_fat, i * 4)).collect();
+        if let Some(root) = file.entries.iter().find(|e| e.kind == 5) {
+            let mut stream = file.chain(root.start);
+            stream.truncate(root.size as usize);
+            file.mini_stream = stream;
+        }
+        Ok(file)
+    }
+
+    fn sector(&self, n: u32) -> Option<&[u8]> {
+        let start = (n as usize + 1).checked_mul(self.sector_size)?;
+        self.data.get(start..start + self.sector_size)
+    }
+
+    /// Concatenate a chain of regular sectors. Each sector is read at most
+    /// once, so the result is never larger than the file
+    fn chain(&self, n: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        for n in chain_sectors(&self.fat, n) {
+            match self.sector(n) {
+                Some(sector) => out.extend_from_slice(sector),
+                None => break,
+            }
+        }
+        out
+    }
+
+    /// The contents of the stream with this name, in any storage
+    fn stream(&self, name: &str) -> Option<Vec<u8>> {
+        let entry = self.entries.iter().find(|e| e.kind == 2 && e.name.eq_ignore_ascii_case(name))?;
+        let size = entry.size as usize;
+        let mut out = Vec::new();
+        if entry.size < self.mini_cutoff {
+            for n in chain_sectors(&self.mini_fat, entry.start) {
+                if out.len() >= size {
+                    break;
+                }
+                let start = n as usize * 64;
+                out.extend_from_slice(self.mini_stream.get(start..start + 64)?);
+            }
+        } else {
+            out = self.chain(entry.start);
+        }
+        out.truncate(size);
+        Some(out)
+    }
+}
+
+/// The sector numbers of a chain in a FAT or mini-FAT. The chain stops at
+/// its end marker, at a sector the table doesn't cover or at a sector it
+/// already went through, so a cycle can't make it run on
+fn chain_sectors(table: &[u32], mut n: u32) -> Vec<u32> {
+    let mut visited = vec![false; table.len()];
+    let mut sectors = Vec::new();
+    while n <= MAX_REGULAR_SECTOR {
+        match visited.get_mut(n as usize) {
+            Some(seen) if !*seen => *seen = true,
+            _ => break,
+        }
+        sectors.push(n);
+        n = table[n as usize];
+    }
+    sectors
+}
+
+fn le_u16(data: &[u8], at: usize) -> u16 {
+    data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]])).unwrap_or(0)
+}
+
+fn le_u32(data: &[u8], at: usize) -> u32 {
+    data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).unwrap_or(0)
+}
+
+// Word binary format
+
+/// Indexes of the offset/length pairs in FibRgFcLcb97
+const FIB_STSHF: usize = 1;
+const FIB_PLCFFNDREF: usize = 2;
+const FIB_PLCFFNDTXT: usize = 3;
+const FIB_PLCFBTECHPX: usize = 12;
+const FIB_PLCFBTEPAPX: usize = 13;
+const FIB_STTBFFFN: usize = 15;
+const FIB_CLX: usize = 33;
+const FIB_PLCFENDREF: usize = 46;
+const FIB_PLCFENDTXT: usize = 47;
+const FIB_PLFLST: usize = 73;
+const FIB_PLFLFO: usize = 74;
+
+/// Colors of the ico palette used by sprmCIco and sprmCHighlight
+const ICO_COLORS: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [0, 0, 255],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 0, 255],
+    [255, 0, 0],
+    [255, 255, 0],
+    [255, 255, 255],
+    [0, 0, 128],
+    [0, 128, 128],
+    [0, 128, 0],
+    [128, 0, 128],
+    [128, 0, 0],
+    [128, 128, 0],
+    [128, 128, 128],
+    [192, 192, 192],
+];
+
+/// Character properties resolved from styles and CHPX sprms
+#[derive(Debug, Clone, Default)]
+struct CharProps {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strike: bool,
+    hidden: bool,
+    deleted: bool,
+    special: bool,
+    vert: u8,
+    color: Option<[u8; 3]>,
+    background: Option<[u8; 3]>,
+    font: Option<u16>,
+    picture: Option<u32>,
+}
+
+/// Paragraph properties resolved from styles and PAPX sprms
+#[derive(Debug, Clone, Default)]
+struct ParaProps {
+    istd: u16,
+    align: u8,
+    in_table: bool,
+    row_end: bool,
+    inner_row_end: bool,
+    header_row: bool,
+    ilvl: u8,
+    ilfo: u16,
+    /// Merge flags of the row's cells, from the table properties of its end mark
+    cell_merges: Vec<CellMerge>,
+}
+
+struct Style {
+    sti: u16,
+    name: String,
+    base: u16,
+    para_sprms: Vec<u8>,
+    char_sprms: Vec<u8>,
+}
+
+struct ListLevel {
+    nfc: u8,
+    start: usize,
+    /// Number text where units below 9 stand for the number of that level
+    text: Vec<u16>,
+}
+
+/// A formatting run of an FKP page: file offsets and its grpprl
+struct Run {
+    start: u32,
+    end: u32,
+    istd: u16,
+    sprms: Vec<u8>,
+}
+
+struct Field {
+    code: String,
+    result: bool,
+    link: Option<String>,
+}
+
+#[derive(Default)]
+struct TableBuilder {
+    rows: Vec<TableRow>,
+    cells: Vec<Vec<Block>>,
+    cell: Vec<Block>,
+    merges: MergedCells,
+}
+
+/// Split a grpprl into (sprm, operand) pairs
+fn sprms(grpprl: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos + 2 <= grpprl.len() {
+        let op = le_u16(grpprl, pos);
+        pos += 2;
+        let len = match op >> 13 {
+            0 | 1 => 1,
+            2 | 4 | 5 => 2,
+            3 => 4,
+            7 => 3,
+            // sprmTDefTable carries a two-byte length
+            _ if op == 0xD608 => le_u16(grpprl, pos) as usize + 1,
+            _ => grpprl.get(pos).map(|&n| n as usize + 1).unwrap_or(1),
+        };
+        let end = (pos + len).min(grpprl.len());
+        out.push((op, &grpprl[pos..end]));
+        pos = end;
+    }
+    out
+}
+
+/// A toggle operand: 0x80 keeps and 0x81 inverts the style's value
+fn toggle(arg: &[u8], base: bool) -> bool {
+    match arg.first() {
+        Some(1) => true,
+        Some(0x80) => base,
+        Some(0x81) => !base,
+        _ => false,
+    }
+}
+
+fn apply_char_sprm(props: &mut CharProps, op: u16, arg: &[u8], base: &CharProps) {
+    let byte = arg.first().copied().unwrap_or(0);
+    match op {
+        0x0835 => props.bold = toggle(arg, base.bold),
+        0x0836 => props.italic = toggle(arg, base.italic),
+        0x0837 | 0x2A53 => props.strike = toggle(arg, base.strike),
+        0x083C => props.hidden = toggle(arg, base.hidden),
+        0x0800 => props.deleted = byte != 0,
+        0x0855 => props.special = byte != 0,
+        0x2A3E => props.underline = byte != 0,
+        0x2A48 => props.vert = byte,
+        0x2A42 => props.color = ICO_COLORS.get((byte as usize).wrapping_sub(1)).copied().filter(|c| *c != [0, 0, 0]),
+        // COLORREF with 0xFF in the last byte for the automatic color
+        0x6870 if arg.len() == 4 => props.color = Some([arg[0], arg[1], arg[2]]).filter(|c| arg[3] != 0xFF && *c != [0, 0, 0]),
+        0x2A0C => props.background = ICO_COLORS.get((byte as usize).wrapping_sub(1)).copied(),
+        0x4A4F => props.font = Some(le_u16(arg, 0)),
+        0x6A03 => props.picture = Some(le_u32(arg, 0)),
+        _ => {}
+    }
+}
+
+fn apply_para_sprm(props: &mut ParaProps, op: u16, arg: &[u8]) {
+    let byte = arg.first().copied().unwrap_or(0);
+    match op {
+        0x2403 | 0x2461 => props.align = byte,
+        0x2416 => props.in_table = byte != 0,
+        0x2417 => props.row_end = byte != 0,
+        0x6649 => props.in_table = le_u32(arg, 0) > 0,
+        0x244C => props.inner_row_end = byte != 0,
+        0x3404 => props.header_row = byte != 0,
+        0x260A => props.ilvl = byte.min(8),
+        0x460B => props.ilfo = le_u16(arg, 0),
+        // sprmTDefTable: cell count, cell edges and a TC per cell whose
+        // first bits hold the horizontal and vertical merge flags
+        0xD608 => {
+            let count = arg.get(2).copied().unwrap_or(0) as usize;
+            let tcs = 3 + (count + 1) * 2;
+            props.cell_merges = (0..count)
+                .map(|n| {
+                    let flags = le_u16(arg, tcs + n * 20);
+                    let vertical = (flags >> 5) & 3;
+                    CellMerge {
+                        first_column: flags & 3 == 1,
+                        merged_column: flags & 2 != 0,
+                        first_row: vertical == 3,
+                        merged_row: vertical == 1,
+                    }
+                })
+                .collect();
+        }
+        // sprmTVertMerge: cell index and its vertical merge flag
+        0xD62B if arg.len() >= 3 => {
+            let n = arg[1] as usize;
+            if props.cell_merges.len() <= n {
+                props.cell_merges.resize(n + 1, CellMerge::default());
+            }
+            props.cell_merges[n].first_row = arg[2] == 3;
+            props.cell_merges[n].merged_row = arg[2] == 1;
+        }
+        _ => {}
+    }
+}
+
+fn read_doc(input_path: &str) -> Result<RichDocument, String> {
+    let data = fs::read(input_path).map_err(|e| format!("Failed to read file: {}", e))?;
+    let file = CompoundFile::parse(data)?;
+    let word = file.stream("WordDocument").ok_or("No WordDocument stream; not a Word document")?;
+    if le_u16(&word, 0) != 0xA5EC {
+        return Err("Invalid Word document header".to_string());
+    }
+    if le_u16(&word, 2) < 0xC0 {
+        return Err("Word 6.0/95 documents are not supported; resave the file as Word 97-2003 or DOCX".to_string());
+    }
+    let flags = le_u16(&word, 0x0A);
+    if flags & 0x0100 != 0 {
+        return Err("Password-protected Word documents are not supported".to_string());
+    }
+    let table_name = if flags & 0x0200 != 0 { "1Table" } else { "0Table" };
+    let table = file.stream(table_name).ok_or_else(|| format!("Missing {} stream", table_name))?;
+
+    // FibBase is followed by the counted arrays FibRgW, FibRgLw and FibRgFcLcb
+    let csw = le_u16(&word, 32) as usize;
+    let lw_at = 34 + csw * 2;
+    let cslw = le_u16(&word, lw_at) as usize;
+    let lw = |i: usize| if i < cslw { le_u32(&word, lw_at + 2 + i * 4) } else { 0 };
+    let fc_at = lw_at + 2 + cslw * 4;
+    let pairs: Vec<(usize, usize)> = (0..le_u16(&word, fc_at) as usize)
+        .map(|i| (le_u32(&word, fc_at + 2 + i * 8) as usize, le_u32(&word, fc_at + 6 + i * 8) as usize))
+        .collect();
+    let part = |i: usize| -> &[u8] {
+        pairs
+            .get(i)
+            .filter(|(_, lcb)| *lcb > 0)
+            .and_then(|&(fc, lcb)| table.get(fc..fc.saturating_add(lcb)))
+            .unwrap_or(&[])
+    };
+    let (ccp_text, ccp_ftn, ccp_hdd, ccp_mcr, ccp_atn) = (lw(3), lw(4), lw(5), lw(6), lw(7));
+
+    let (units, fcs) = decode_pieces(&word, part(FIB_CLX)).ok_or("Missing or invalid piece table")?;
+
+    // Footnotes and endnotes are numbered together in reference order
+    let mut notes = note_ranges(part(FIB_PLCFFNDREF), part(FIB_PLCFFNDTXT), ccp_text);
+    let endnote_start = ccp_text + ccp_ftn + ccp_hdd + ccp_mcr + ccp_atn;
+    notes.extend(note_ranges(part(FIB_PLCFENDREF), part(FIB_PLCFENDTXT), endnote_start));
+    notes.sort_by_key(|n| n.0);
+
+    let mut reader = DocReader {
+        chpx: fkp_runs(&word, part(FIB_PLCFBTECHPX), false),
+        papx: fkp_runs(&word, part(FIB_PLCFBTEPAPX), true),
+        styles: parse_styles(part(FIB_STSHF)),
+        fonts: parse_fonts(part(FIB_STTBFFFN)),
+        lists: parse_lists(part(FIB_PLFLST)),
+        lfos: parse_lfos(part(FIB_PLFLFO)),
+        note_numbers: notes.iter().enumerate().map(|(n, note)| (note.0, n + 1)).collect(),
+        data: file.stream("Data").unwrap_or_default(),
+        units,
+        fcs,
+        counters: HashMap::new(),
+        fields: Vec::new(),
+        images: HashMap::new(),
+        paragraphs: 0,
+        tables: 0,
+        image_count: 0,
+    };
+    let blocks = reader.read_range(0, ccp_text as usize, false);
+    let notes = notes
+        .iter()
+        .map(|&(_, start, end)| {
+            reader.fields.clear();
+            reader.read_range(start as usize, end as usize, true)
+        })
+        .collect();
+    Ok(RichDocument {
+        blocks,
+        notes,
+        images: reader.images,
+        title: None,
+        author: None,
+        paragraphs: reader.paragraphs,
+        tables: reader.tables,
+        image_count: reader.image_count,
+    })
+}
+
+/// The text of the document as UTF-16 units, one per character position,
+/// with the file offset of each character for the formatting lookups
+fn decode_pieces(word: &[u8], clx: &[u8]) -> Option<(Vec<u16>, Vec<u32>)> {
+    // Skip the Prc entries in front of the piece table
+    let mut pos = 0;
+    while clx.get(pos) == Some(&0x01) {
+        pos += 3 + le_u16(clx, pos + 1) as usize;
+    }
+    if clx.get(pos) != Some(&0x02) {
+        return None;
+    }
+    let lcb = le_u32(clx, pos + 1) as usize;
+    let plc = clx.get(pos + 5..pos + 5 + lcb)?;
+    let count = plc.len().checked_sub(4)? / 12;
+
+    let cp1252: Vec<u16> = (0..=255u8)
+        .map(|b| WINDOWS_1252.decode_without_bom_handling(&[b]).0.encode_utf16().next().unwrap_or(b as u16))
+        .collect();
+    let mut units = Vec::new();
+    let mut fcs = Vec::new();
+    for i in 0..count {
+        let length = le_u32(plc, i * 4 + 4).saturating_sub(le_u32(plc, i * 4)) as usize;
+        let raw = le_u32(plc, (count + 1) * 4 + i * 8 + 2);
+        // Compressed pieces hold one byte per character at half the offset
+        let compressed = raw & 0x4000_0000 != 0;
+        let fc = (raw & 0x3FFF_FFFF) as usize;
+        for k in 0..length {
+            let (at, unit) = if compressed {
+                let at = fc / 2 + k;
+                (at, cp1252[*word.get(at)? as usize])
+            } else {
+                let at = fc + k * 2;
+                word.get(at + 1)?;
+                (at, le_u16(word, at))
+            };
+            units.push(unit);
+            fcs.push(at as u32);
+        }
+    }
+    Some((units, fcs))
+}
+
+/// Note references with the character range of their text as
+/// (reference position, start, end)
+fn note_ranges(refs: &[u8], texts: &[u8], offset: u32) -> Vec<(u32, u32, u32)> {
+    let count = refs.len().saturating_sub(4) / 6;
+    (0..count)
+        .filter(|i| texts.len() >= (i + 2) * 4)
+        .map(|i| (le_u32(refs, i * 4), offset + le_u32(texts, i * 4), offset + le_u32(texts, i * 4 + 4)))
+        .collect()
+}
+
+/// Read the CHPX or PAPX runs of all FKP pages listed in a PlcBte
+fn fkp_runs(word: &[u8], plc: &[u8], paragraphs: bool) -> Vec<Run> {
+    let count = plc.len().saturating_sub(4) / 8;
+    let mut runs = Vec::new();
+    for i in 0..count {
+        let pn = (le_u32(plc, (count + 1) * 4 + i * 4) & 0x003F_FFFF) as usize;
+        let Some(page) = word.get(pn * 512..pn * 512 + 512) else {
+            continue;
+        };
+        let entries = page[511] as usize;
+        for j in 0..entries {
+            let (start, end) = (le_u32(page, j * 4), le_u32(page, j * 4 + 4));
+            let (istd, sprms) = if paragraphs {
+                let offset = page.get((entries + 1) * 4 + j * 13).copied().unwrap_or(0) as usize * 2;
+                let papx = match page.get(offset) {
+                    _ if offset == 0 => None,
+                    Some(0) => page.get(offset + 1).and_then(|&cb| page.get(offset + 2..offset + 2 + cb as usize * 2)),
+                    Some(&cb) => page.get(offset + 1..offset + cb as usize * 2),
+                    None => None,
+                };
+                match papx {
+                    Some(papx) if papx.len() >= 2 => (le_u16(papx, 0), papx[2..].to_vec()),
+                    _ => (0, Vec::new()),
+                }
+            } else {
+                let offset = page.get((entries + 1) * 4 + j).copied().unwrap_or(0) as usize * 2;
+                let chpx = match page.get(offset) {
+                    Some(&cb) if offset > 0 => page.get(offset + 1..offset + 1 + cb as usize),
+                    _ => None,
+                };
+                (0, chpx.map(|c| c.to_vec()).unwrap_or_default())
+            };
+            runs.push(Run { start, end, istd, sprms });
+        }
+    }
+    runs.sort_by_key(|r| r.start);
+    runs
+}
+
+fn find_run(runs: &[Run], fc: u32) -> Option<&Run> {
+    let i = runs.partition_point(|r| r.start <= fc);
+    runs.get(i.checked_sub(1)?).filter(|r| fc < r.end)
+}
+
+/// The style sheet (STSH); unused slots are None
+fn parse_styles(stsh: &[u8]) -> Vec<Option<Style>> {
+    let header = le_u16(stsh, 0) as usize;
+    let count = le_u16(stsh, 2) as usize;
+    let base_size = le_u16(stsh, 4) as usize;
+    let mut pos = 2 + header;
+    let mut styles = Vec::new();
+    for _ in 0..count {
+        let size = le_u16(stsh, pos) as usize;
+        let Some(std) = stsh.get(pos + 2..pos + 2 + size) else {
+            break;
+        };
+        pos += 2 + size;
+        if size < base_size + 2 {
+            styles.push(None);
+            continue;
+        }
+        let kind = le_u16(std, 2) & 0x000F;
+        let name_len = le_u16(std, base_size) as usize;
+        let name: Vec<u16> = (0..name_len).map(|i| le_u16(std, base_size + 2 + i * 2)).collect();
+        // The UPX entries follow the name and its terminator, each padded to
+        // an even length
+        let mut at = base_size + 4 + name_len * 2;
+        let mut upx = Vec::new();
+        for _ in 0..le_u16(std, 4) & 0x000F {
+            let len = le_u16(std, at) as usize;
+            upx.push(std.get(at + 2..at + 2 + len).unwrap_or(&[]));
+            at += 2 + len + len % 2;
+        }
+        let (para_sprms, char_sprms) = match kind {
+            1 => (upx.first().and_then(|u| u.get(2..)).unwrap_or(&[]), upx.get(1).copied().unwrap_or(&[])),
+            2 => (&[][..], upx.first().copied().unwrap_or(&[])),
+            _ => (&[][..], &[][..]),
+        };
+        styles.push(Some(Style {
+            sti: le_u16(std, 0) & 0x0FFF,
+            name: String::from_utf16_lossy(&name),
+            base: le_u16(std, 2) >> 4,
+            para_sprms: para_sprms.to_vec(),
+            char_sprms: char_sprms.to_vec(),
+        }));
+    }
+    styles
+}
+
+/// Font names from the SttbfFfn table
+fn parse_fonts(sttb: &[u8]) -> Vec<String> {
+    let count = le_u16(sttb, 0) as usize;
+    let mut pos = 4;
+    let mut fonts = Vec::new();
+    for _ in 0..count {
+        let Some(&size) = sttb.get(pos) else {
+            break;
+        };
+        let ffn = sttb.get(pos..pos + 1 + size as usize).unwrap_or(&[]);
+        let name: Vec<u16> = (40..ffn.len())
+            .step_by(2)
+            .map(|at| le_u16(ffn, at))
+            .take_while(|&u| u != 0)
+            .collect();
+        fonts.push(String::from_utf16_lossy(&name));
+        pos += 1 + size as usize;
+    }
+    fonts
+}
+
+/// List definitions (PlfLst) by list id
+fn parse_lists(plf: &[u8]) -> HashMap<i32, Vec<ListLevel>> {
+    let count = le_u16(plf, 0) as usize;
+    let mut lists = HashMap::new();
+    // The LVL records of all lists follow the array of LSTF records
+    let mut pos = 2 + count * 28;
+    for i in 0..count {
+        let lstf = 2 + i * 28;
+        let id = le_u32(plf, lstf) as i32;
+        let simple = plf.get(lstf + 26).map(|f| f & 1 != 0).unwrap_or(false);
+        let mut levels = Vec::new();
+        for _ in 0..if simple { 1 } else { 9 } {
+            let Some(lvlf) = plf.get(pos..pos + 28) else {
+                break;
+            };
+            let grpprls = lvlf[24] as usize + lvlf[25] as usize;
+            let text_at = pos + 28 + grpprls;
+            let text_len = le_u16(plf, text_at) as usize;
+            levels.push(ListLevel {
+                nfc: lvlf[4],
+                start: le_u32(lvlf, 0) as usize,
+                text: (0..text_len).map(|k| le_u16(plf, text_at + 2 + k * 2)).collect(),
+            });
+            pos = text_at + 2 + text_len * 2;
+        }
+        lists.insert(id, levels);
+    }
+    lists
+}
+
+/// List ids of the list format overrides (PlfLfo), indexed by ilfo - 1
+fn parse_lfos(plf: &[u8]) -> Vec<i32> {
+    let count = (le_u32(plf, 0) as usize).min(plf.len() / 16);
+    (0..count).map(|i| le_u32(plf, 4 + i * 16) as i32).collect()
+}
+
+struct DocReader {
+    units: Vec<u16>,
+    fcs: Vec<u32>,
+    data: Vec<u8>,
+    chpx: Vec<Run>,
+    papx: Vec<Run>,
+    styles: Vec<Option<Style>>,
+    fonts: Vec<String>,
+    lists: HashMap<i32, Vec<ListLevel>>,
+    lfos: Vec<i32>,
+    note_numbers: HashMap<u32, usize>,
+    /// Current number of each level per list format override
+    counters: HashMap<u16, [Option<usize>; 9]>,
+    fields: Vec<Field>,
+    images: HashMap<String, Vec<u8>>,
+    paragraphs: usize,
+    tables: usize,
+    image_count: usize,
+}
+
+impl DocReader {
+    fn read_range(&mut self, start: usize, end: usize, in_note: bool) -> Vec<Block> {
+        let end = end.min(self.units.len());
+        let mut blocks = Vec::new();
+        let mut table = TableBuilder::default();
+        let mut positions = Vec::new();
+        for cp in start..end {
+            let unit = self.units[cp];
+            if !matches!(unit, 0x0D | 0x07 | 0x0C) {
+                positions.push(cp);
+                continue;
+            }
+            let props = self.para_props(self.fcs[cp]);
+            let paragraph = self.paragraph(&positions, &props, in_note);
+            positions.clear();
+            // Cells end with 0x07 and rows with an extra 0x07 marked as the
+            // row end; nested tables are flattened into the outer cell
+            if props.in_table && unit != 0x0C {
+                if unit == 0x07 && props.row_end {
+                    if !table.cell.is_empty() {
+                        table.cells.push(std::mem::take(&mut table.cell));
+                    }
+                    let cells = std::mem::take(&mut table.cells)
+                        .into_iter()
+                        .enumerate()
+                        .map(|(n, blocks)| (blocks, props.cell_merges.get(n).copied().unwrap_or_default()))
+                        .collect();
+                    table.merges.push_row(&mut table.rows, props.header_row, cells);
+                } else if !props.inner_row_end {
+                    if let Some((paragraph, list)) = paragraph {
+                        push_paragraph(&mut table.cell, paragraph, list);
+                    }
+                    if unit == 0x07 {
+                        table.cells.push(std::mem::take(&mut table.cell));
+                    }
+                }
+                continue;
+            }
+            self.flush_table(&mut table, &mut blocks);
+            if let Some((paragraph, list)) = paragraph {
+                push_paragraph(&mut blocks, paragraph, list);
+            }
+            if unit == 0x0C {
+                blocks.push(Block::PageBreak);
+            }
+        }
+        if !positions.is_empty() {
+            let paragraph = self.paragraph(&positions, &ParaProps::default(), in_note);
+            self.flush_table(&mut table, &mut blocks);
+            if let Some((paragraph, list)) = paragraph {
+                push_paragraph(&mut blocks, paragraph, list);
+            }
+        }
+        self.flush_table(&mut table, &mut blocks);
+        while matches!(blocks.last(), Some(Block::PageBreak)) {
+            blocks.pop();
+        }
+        blocks
+    }
+
+    fn flush_table(&mut self, table: &mut TableBuilder, blocks: &mut Vec<Block>) {
+        if !table.cell.is_empty() {
+            table.cells.push(std::mem::take(&mut table.cell));
+        }
+        if !table.cells.is_empty() {
+            let cells = std::mem::take(&mut table.cells)
+                .into_iter()
+                .map(|blocks| (blocks, CellMerge::default()))
+                .collect();
+            table.merges.push_row(&mut table.rows, false, cells);
+        }
+        if !table.rows.is_empty() {
+            blocks.push(Block::Table(std::mem::take(&mut table.rows)));
+            self.tables += 1;
+        }
+        table.merges = MergedCells::default();
+    }
+
+    /// A paragraph and, for list items, its place in the list
+    fn paragraph(
+        &mut self,
+        positions: &[usize],
+        props: &ParaProps,
+        in_note: bool,
+    ) -> Option<(Paragraph, Option<ListEntry>)> {
+        let mut base = CharProps::default();
+        self.apply_style_chars(props.istd, &mut base, 0);
+        let mut content = Vec::new();
+        let mut i = 0;
+        while i < positions.len() {
+            let cp = positions[i];
+            let unit = self.units[cp];
+            i += 1;
+            // Fields are 0x13 code 0x14 result 0x15; only results are shown
+            match unit {
+                0x13 => {
+                    self.fields.push(Field { code: String::new(), result: false, link: None });
+                    continue;
+                }
+                0x14 => {
+                    if let Some(field) = self.fields.last_mut() {
+                        field.result = true;
+                        field.link = hyperlink_target(&field.code);
+                    }
+                    continue;
+                }
+                0x15 => {
+                    self.fields.pop();
+                    continue;
+                }
+                _ => {}
+            }
+            let mut text = String::new();
+            let low = positions.get(i).map(|&next| self.units[next]);
+            if (0xD800..0xDC00).contains(&unit) && low.map(|u| (0xDC00..0xE000).contains(&u)).unwrap_or(false) {
+                text.push_str(&String::from_utf16_lossy(&[unit, low.unwrap_or(0)]));
+                i += 1;
+            } else {
+                text.push(char::from_u32(unit as u32).unwrap_or('\u{FFFD}'));
+            }
+            if let Some(field) = self.fields.iter_mut().rev().find(|f| !f.result) {
+                field.code.push_str(&text);
+                continue;
+            }
+
+            let chars = self.char_props(self.fcs[cp], &base);
+            if chars.hidden || chars.deleted {
+                continue;
+            }
+            match unit {
+                0x01 if chars.special => {
+                    content.extend(self.picture(chars.picture));
+                    continue;
+                }
+                0x02 if chars.special && !in_note => {
+                    if let Some(&n) = self.note_numbers.get(&(cp as u32)) {
+                        content.push(Inline::NoteRef(n));
+                    }
+                    continue;
+                }
+                0x0B => {
+                    content.push(Inline::Break);
+                    continue;
+                }
+                0x1E => text = "\u{2011}".to_string(),
+                0x09 => {}
+                0..=0x1F => continue,
+                _ => {}
+            }
+            let format = self.format(&chars);
+            match content.last_mut() {
+                Some(Inline::Text(last, t)) if *last == format => t.push_str(&text),
+                _ => content.push(Inline::Text(format, text)),
+            }
+        }
+        trim(&mut content);
+        if content.is_empty() {
+            return None;
+        }
+
+        let style = self.styles.get(props.istd as usize).and_then(|s| s.as_ref());
+        let heading = style.and_then(|s| match s.sti {
+            1..=9 => Some((s.sti as usize).min(6)),
+            // Title
+            62 => Some(1),
+            _ => s
+                .name
+                .to_lowercase()
+                .strip_prefix("heading ")
+                .and_then(|n| n.trim().parse::<usize>().ok())
+                .filter(|n| (1..=9).contains(n))
+                .map(|n| n.min(6)),
+        });
+        // Bold from a heading style is already carried by the heading tag
+        if heading.is_some() && base.bold {
+            for inline in content.iter_mut() {
+                if let Inline::Text(format, _) = inline {
+                    format.bold = false;
+                }
+            }
+        }
+        let list = match props.ilfo {
+            0 | 0x07FF => None,
+            _ if heading.is_some() => None,
+            ilfo => self.list_item(ilfo, props.ilvl),
+        };
+        self.paragraphs += 1;
+        let paragraph = Paragraph {
+            content,
+            align: match props.align {
+                1 => Some("center"),
+                2 => Some("right"),
+                3 | 4 => Some("justify"),
+                _ => None,
+            },
+            heading,
+            ..Paragraph::default()
+        };
+        Some((paragraph, list))
+    }
+
+    fn apply_style_chars(&self, istd: u16, props: &mut CharProps, depth: usize) {
+        let Some(style) = self.styles.get(istd as usize).and_then(|s| s.as_ref()) else {
+            return;
+        };
+        if style.base != 0x0FFF && style.base != istd && depth < 10 {
+            self.apply_style_chars(style.base, props, depth + 1);
+        }
+        let base = props.clone();
+        for (op, arg) in sprms(&style.char_sprms) {
+            apply_char_sprm(props, op, arg, &base);
+        }
+    }
+
+    fn apply_style_paras(&self, istd: u16, props: &mut ParaProps, depth: usize) {
+        let Some(style) = self.styles.get(istd as usize).and_then(|s| s.as_ref()) else {
+            return;
+        };
+        if style.base != 0x0FFF && style.base != istd && depth < 10 {
+            self.apply_style_paras(style.base, props, depth + 1);
+        }
+        for (op, arg) in sprms(&style.para_sprms) {
+            apply_para_sprm(props, op, arg);
+        }
+    }
+
+    fn para_props(&self, fc: u32) -> ParaProps {
+        let mut props = ParaProps::default();
+        let Some(run) = find_run(&self.papx, fc) else {
+            return props;
+        };
+        self.apply_style_paras(run.istd, &mut props, 0);
+        for (op, arg) in sprms(&run.sprms) {
+            apply_para_sprm(&mut props, op, arg);
+        }
+        props.istd = run.istd;
+        props
+    }
+
+    fn char_props(&self, fc: u32, base: &CharProps) -> CharProps {
+        let mut props = base.clone();
+        if let Some(run) = find_run(&self.chpx, fc) {
+            for (op, arg) in sprms(&run.sprms) {
+                match op {
+                    // Character style
+                    0x4A30 => self.apply_style_chars(le_u16(arg, 0), &mut props, 0),
+                    _ => apply_char_sprm(&mut props, op, arg, base),
+                }
+            }
+        }
+        props
+    }
+
+    fn format(&self, props: &CharProps) -> Format {
+        Format {
+            bold: props.bold,
+            italic: props.italic,
+            underline: props.underline,
+            strike: props.strike,
+            mono: props
+                .font
+                .and_then(|f| self.fonts.get(f as usize))
+                .map(|name| is_monospace_font(name))
+                .unwrap_or(false),
+            vert: match props.vert {
+                1 => Some("sup"),
+                2 => Some("sub"),
+                _ => None,
+            },
+            color: props.color.map(css_color),
+            background: props.background.map(css_color),
+            link: self.fields.iter().rev().find_map(|f| f.link.clone()),
+        }
+    }
+
+    /// An inline picture from the Data stream: a PICF header followed by
+    /// Office drawing records that embed the PNG or JPEG data
+    fn picture(&mut self, location: Option<u32>) -> Option<Inline> {
+        let at = location? as usize;
+        let length = le_u32(&self.data, at) as usize;
+        let region = self.data.get(at..at.checked_add(length)?)?;
+        let bytes = embedded_picture(region)?;
+        let (width, height) = (le_u16(region, 0x1C) as u32, le_u16(region, 0x1E) as u32);
+        let scale = |v: u32| match v {
+            0 => 1000,
+            v => v,
+        };
+        let (mx, my) = (scale(le_u16(region, 0x20) as u32), scale(le_u16(region, 0x22) as u32));
+        // Twips scaled by thousandths, at 96 pixels per inch
+        let size = Some((width * mx / 15000, height * my / 15000)).filter(|(w, h)| *w > 0 && *h > 0);
+        self.image_count += 1;
+        Some(Inline::Image(store_image(&mut self.images, bytes), String::new(), size))
+    }
+
+    fn list_item(&mut self, ilfo: u16, ilvl: u8) -> Option<ListEntry> {
+        let id = *self.lfos.get(ilfo as usize - 1)?;
+        let levels = self.lists.get(&id).filter(|l| !l.is_empty())?;
+        let level = (ilvl as usize).min(levels.len() - 1);
+        let counters = self.counters.entry(ilfo).or_insert([None; 9]);
+        let number = counters[level].map(|n| n + 1).unwrap_or(levels[level].start);
+        counters[level] = Some(number);
+        for deeper in counters[level + 1..].iter_mut() {
+            *deeper = None;
+        }
+
+        let ordered = match levels[level].nfc {
+            23 | 255 => None,
+            1 => Some("I"),
+            2 => Some("i"),
+            3 => Some("A"),
+            4 => Some("a"),
+            _ => Some("1"),
+        };
+        let mut marker = String::new();
+        for &unit in &levels[level].text {
+            match unit {
+                0..=8 => {
+                    if let Some(lvl) = levels.get(unit as usize) {
+                        let n = counters[unit as usize].unwrap_or(lvl.start);
+                        marker.push_str(&nfc_number(n, lvl.nfc));
+                    }
+                }
+                // Symbol font bullets live in the private use area
+                0xF000..=0xF0FF | 0x09..=0x1F => marker.push('\u{2022}'),
+                _ => marker.push(char::from_u32(unit as u32).unwrap_or('\u{2022}')),
+            }
+        }
+        let marker = marker.trim().to_string();
+        let marker = match (marker.is_empty(), ordered) {
+            (true, Some(_)) => format!("{}.", nfc_number(number, levels[level].nfc)),
+            (true, None) => "\u{2022}".to_string(),
+            _ => marker,
+        };
+        Some(ListEntry { level, id: ilfo as usize, ordered, number, marker })
+    }
+}
+
+/// Format a counter in a list level's number format (nfc)
+fn nfc_number(n: usize, nfc: u8) -> String {
+    let kind = match nfc {
+        1 => "I",
+        2 => "i",
+        3 => "A",
+        4 => "a",
+        22 => "01",
+        23 | 255 => "",
+        _ => "1",
+    };
+    list_number(n as i64, kind)
+}
+
+/// The first PNG or JPEG image in a block of drawing records
+fn embedded_picture(region: &[u8]) -> Option<Vec<u8>> {
+    let find = |pattern: &[u8], from: usize| {
+        region
+            .get(from..)?
+            .windows(pattern.len())
+            .position(|w| w == pattern)
+            .map(|p| p + from)
+    };
+    let png = find(b"\x89PNG\r\n\x1a\n", 0);
+    let jpeg = find(&[0xFF, 0xD8, 0xFF], 0);
+    match (png, jpeg) {
+        (Some(start), j) if j.map(|j| start < j).unwrap_or(true) => {
+            let end = find(b"IEND", start)? + 8;
+            region.get(start..end).map(|b| b.to_vec())
+        }
+        (_, Some(start)) => {
+            let end = region.windows(2).rposition(|w| w == [0xFF, 0xD9])? + 2;
+            region.get(start..end).map(|b| b.to_vec())
+        }
+        _ => None,
+    }
+}
+
+/// Drop spaces at the start and end of a paragraph; monospaced text keeps
+/// its leading spaces
+fn trim(content: &mut Vec<Inline>) {
+    while let Some(Inline::Text(format, text)) = content.first_mut() {
+        if format.mono {
+            break;
+        }
+        let trimmed = text.trim_start_matches([' ', '\t']);
+        if !trimmed.is_empty() {
+            *text = trimmed.to_string();
+            break;
+        }
+        content.remove(0);
+    }
+    while let Some(Inline::Text(_, text)) = content.last_mut() {
+        let trimmed = text.trim_end_matches([' ', '\t']).len();
+        if trimmed > 0 {
+            text.truncate(trimmed);
+            break;
+        }
+        content.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FREE: u32 = 0xFFFF_FFFF;
+    const END: u32 = 0xFFFF_FFFE;
+
+    fn put_u32(data: &mut [u8], at: usize, value: u32) {
+        data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn dir_entry(name: &str, kind: u8, start: u32, size: u32) -> Vec<u8> {
+        let mut entry = vec![0u8; 128];
+        let units: Vec<u16> = name.encode_utf16().collect();
+        for (i, unit) in units.iter().enumerate() {
+            entry[i * 2..i * 2 + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entry[0x40..0x42].copy_from_slice(&(((units.len() + 1) * 2) as u16).to_le_bytes());
+        entry[0x42] = kind;
+        put_u32(&mut entry, 0x74, start);
+        put_u32(&mut entry, 0x78, size);
+        entry
+    }
+
+    /// A 512-byte-sector file: FAT in sector 0, directory in sector 1 and a
+    /// 1024-byte "WordDocument" stream in sectors 2 and 3, linked by `fat`
+    fn compound_file(fat: &[u32]) -> Vec<u8> {
+        let mut data = vec![0u8; 512 * 5];
+        data[..8].copy_from_slice(&CFB_SIGNATURE);
+        data[0x1E] = 9;
+        put_u32(&mut data, 0x30, 1);
+        put_u32(&mut data, 0x38, 512);
+        put_u32(&mut data, 0x3C, END);
+        put_u32(&mut data, 0x44, END);
+        for i in 0..109 {
+            put_u32(&mut data, 0x4C + i * 4, FREE);
+        }
+        put_u32(&mut data, 0x4C, 0);
+        for i in 0..128 {
+            put_u32(&mut data, 512 + i * 4, fat.get(i).copied().unwrap_or(FREE));
+        }
+        let mut directory = dir_entry("Root Entry", 5, END, 0);
+        directory.extend(dir_entry("WordDocument", 2, 2, 1024));
+        data[1024..1024 + directory.len()].copy_from_slice(&directory);
+        data[1536..2048].fill(b'a');
+        data[2048..2560].fill(b'b');
+        data
+    }
+
+    #[test]
+    fn reads_a_stream_across_sectors() {
+        let file = CompoundFile::parse(compound_file(&[0xFFFF_FFFD, END, 3, END])).unwrap();
+        let stream = file.stream("WordDocument").unwrap();
+        assert_eq!(stream.len(), 1024);
+        assert!(stream[..512].iter().all(|&b| b == b'a') && stream[512..].iter().all(|&b| b == b'b'));
+        assert!(file.stream("1Table").is_none());
+    }
+
+    #[test]
+    fn truncated_input_is_rejected_or_cut_short() {
+        let data = compound_file(&[0xFFFF_FFFD, END, 3, END]);
+        assert!(CompoundFile::parse(data[..511].to_vec()).is_err());
+        assert!(CompoundFile::parse(data[..700].to_vec()).is_err());
+        // The last stream sector is missing
+        let file = CompoundFile::parse(data[..2048].to_vec()).unwrap();
+        assert_eq!(file.stream("WordDocument").unwrap().len(), 512);
+    }
+
+    #[test]
+    fn cyclic_chains_stop_at_the_repeated_sector() {
+        assert_eq!(chain_sectors(&[1, 2, 0], 0), vec![0, 1, 2]);
+        assert_eq!(chain_sectors(&[0], 0), vec![0]);
+        assert_eq!(chain_sectors(&[7], 0), vec![0]);
+        assert_eq!(chain_sectors(&[END], 3), Vec::<u32>::new());
+
+        let file = CompoundFile::parse(compound_file(&[0xFFFF_FFFD, END, 3, 2])).unwrap();
+        assert_eq!(file.stream("WordDocument").unwrap().len(), 1024);
+    }
+
+    #[test]
+    fn cyclic_difat_terminates() {
+        let mut data = compound_file(&[0xFFFF_FFFD, END, 3, END]);
+        // Sector 4 is a DIFAT sector that names itself as the next one
+        data.resize(512 * 6, 0);
+        put_u32(&mut data, 0x44, 4);
+        for i in 0..127 {
+            put_u32(&mut data, 2560 + i * 4, FREE);
+        }
+        put_u32(&mut data, 2560 + 127 * 4, 4);
+        let file = CompoundFile::parse(data).unwrap();
+        assert_eq!(file.stream("WordDocument").unwrap().len(), 1024);
+    }
+}
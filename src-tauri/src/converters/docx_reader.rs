@@ -0,0 +1,1520 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use crate::ConversionResult;
+use crate::converters::embedded_images::{attribute_value, open_package, read_entry, resolve_href};
+use crate::converters::html_renderer::{find_element, parse_html, HtmlNode};
+use crate::converters::markdown_writer::nodes_to_markdown;
+use crate::converters::pdf_tools::finish;
+use crate::converters::text_tools::{escape_markup, is_monospace_font, list_number, safe_file_name, unique_file_name};
+
+/// Drawing sizes are given in English Metric Units; 9525 EMU make a CSS pixel
+const EMU_PER_PX: u64 = 9525;
+/// Bookmark Word keeps at the last edit position
+const GO_BACK_BOOKMARK: &str = "_GoBack";
+
+/// How pictures are written when converting DOCX to HTML
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct DocxHtmlOptions {
+    /// "embed" (default) inlines pictures as data URIs; "files" writes them
+    /// to a `{name}_files` folder next to the HTML file
+    pub images: Option<String>,
+}
+
+/// Convert DOCX to HTML, with pictures embedded as data URIs
+pub fn convert_docx_to_html(input_path: &str, output_path: &str) -> ConversionResult {
+    convert_docx_to_html_with_options(input_path, output_path, &DocxHtmlOptions::default())
+}
+
+/// Convert DOCX to HTML, mapping paragraph styles to headings, numbering
+/// to lists and keeping run formatting, links, pictures, merged table
+/// cells, footnotes and the page header and footer
+pub fn convert_docx_to_html_with_options(input_path: &str, output_path: &str, options: &DocxHtmlOptions) -> ConversionResult {
+    finish(try_convert_docx_to_html(input_path, output_path, options), output_path)
+}
+
+fn try_convert_docx_to_html(input_path: &str, output_path: &str, options: &DocxHtmlOptions) -> Result<String, String> {
+    let image_dir = match options.images.as_deref().unwrap_or("embed") {
+        "embed" => None,
+        "files" => {
+            let output = Path::new(output_path);
+            let folder = format!(
+                "{}_files",
+                output.file_stem().and_then(|s| s.to_str()).unwrap_or("document")
+            );
+            Some((output.parent().unwrap_or(Path::new("")).join(&folder), folder))
+        }
+        other => return Err(format!("Unknown image mode '{}': use embed or files", other)),
+    };
+
+    let mut reader = DocxReader::new(open_package(input_path, "DOCX")?, image_dir);
+    let body = reader.render()?;
+
+    let title = reader.title.clone().unwrap_or_else(|| {
+        Path::new(input_path)
+            .file_stem()
+            .and_then(|n| n.to_str())
+            .unwrap_or("Document")
+            .to_string()
+    });
+    let author = reader
+        .author
+        .as_ref()
+        .map(|a| format!("\n    <meta name=\"author\" content=\"{}\">", escape_markup(a)))
+        .unwrap_or_default();
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">{}
+    <title>{}</title>
+    <style>
+        body {{
+            font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif;
+            max-width: 800px;
+            margin: 40px auto;
+            padding: 20px;
+            line-height: 1.6;
+        }}
+        table {{
+            border-collapse: collapse;
+            width: 100%;
+            margin: 20px 0;
+        }}
+        th, td {{
+            border: 1px solid #ddd;
+            padding: 8px;
+            text-align: left;
+            vertical-align: top;
+        }}
+        th {{ background: #f5f5f5; }}
+        img {{ max-width: 100%; height: auto; }}
+        blockquote {{ margin: 1em 0; padding-left: 1em; border-left: 4px solid #ddd; color: #555; }}
+        pre {{ background: #f5f5f5; padding: 12px; overflow-x: auto; }}
+        .docx-header, .docx-footer {{ color: #777; font-size: 0.9em; }}
+        .docx-header {{ border-bottom: 1px solid #eee; margin-bottom: 2em; }}
+        .docx-footer {{ border-top: 1px solid #eee; margin-top: 2em; }}
+        .footnotes {{ border-top: 1px solid #ddd; margin-top: 2em; font-size: 0.9em; }}
+    </style>
+</head>
+<body>
+{}</body>
+</html>"#,
+        author,
+        escape_markup(&title),
+        body
+    );
+    fs::write(output_path, &html).map_err(|e| format!("Failed to write HTML file: {}", e))?;
+
+    Ok(format!(
+        "Converted {} paragraph(s), {} table(s), {} image(s) and {} footnote(s)",
+        reader.paragraph_count,
+        reader.table_count,
+        reader.image_count,
+        reader.note_refs.len()
+    ))
+}
+
+/// Convert DOCX to Markdown through its HTML rendering. Pictures are
+/// written to a `{name}_files` folder and footnotes become GFM footnotes;
+/// the page header and footer are left out.
+pub fn convert_docx_to_markdown(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_docx_to_markdown(input_path, output_path), output_path)
+}
+
+fn try_convert_docx_to_markdown(input_path: &str, output_path: &str) -> Result<String, String> {
+    let output = Path::new(output_path);
+    let folder = format!("{}_files", output.file_stem().and_then(|s| s.to_str()).unwrap_or("document"));
+    let image_dir = Some((output.parent().unwrap_or(Path::new("")).join(&folder), folder));
+
+    let mut reader = DocxReader::new(open_package(input_path, "DOCX")?, image_dir);
+    let root = parse_html(&reader.render()?);
+    let mut nodes = find_element(&root, "body").unwrap_or(&root).children.clone();
+    nodes.retain(|node| {
+        !matches!(node, HtmlNode::Element(e)
+            if matches!(e.tag.as_str(), "header" | "footer")
+                && e.attr("class").is_some_and(|c| c.starts_with("docx-")))
+    });
+    fs::write(output_path, nodes_to_markdown(&nodes)).map_err(|e| format!("Failed to write Markdown file: {}", e))?;
+
+    Ok(format!(
+        "Converted {} paragraph(s), {} table(s), {} image(s) and {} footnote(s)",
+        reader.paragraph_count,
+        reader.table_count,
+        reader.image_count,
+        reader.note_refs.len()
+    ))
+}
+
+/// An element of a parsed XML part. Namespace prefixes are dropped from
+/// element and attribute names, so `w:p` is `p` and `r:id` is `id`.
+#[derive(Debug, Clone, Default)]
+pub struct XmlNode {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<XmlChild>,
+}
+
+#[derive(Debug, Clone)]
+pub enum XmlChild {
+    Element(XmlNode),
+    Text(String),
+}
+
+impl XmlNode {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &XmlNode> {
+        self.children.iter().filter_map(|c| match c {
+            XmlChild::Element(e) => Some(e),
+            XmlChild::Text(_) => None,
+        })
+    }
+
+    pub fn child(&self, name: &str) -> Option<&XmlNode> {
+        self.elements().find(|e| e.name == name)
+    }
+
+    /// First element with this name below this one, in document order
+    pub fn descendant(&self, name: &str) -> Option<&XmlNode> {
+        self.elements().find_map(|e| if e.name == name { Some(e) } else { e.descendant(name) })
+    }
+
+    /// All character data below this element
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for child in &self.children {
+            match child {
+                XmlChild::Element(e) => text.push_str(&e.text()),
+                XmlChild::Text(t) => text.push_str(t),
+            }
+        }
+        text
+    }
+
+    /// The `val` attribute of a child, the usual shape of WordprocessingML properties
+    fn val(&self, name: &str) -> Option<&str> {
+        self.child(name).and_then(|c| c.attr("val"))
+    }
+}
+
+/// Parse an XML part into a tree, keeping all text including whitespace
+pub fn parse_xml(bytes: &[u8]) -> Result<XmlNode, String> {
+    let mut reader = Reader::from_reader(bytes);
+    let mut buf = Vec::new();
+    let mut stack = vec![XmlNode::default()];
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) => stack.push(xml_element(&e)),
+            Ok(Event::Empty(e)) => {
+                let node = xml_element(&e);
+                stack.last_mut().unwrap().children.push(XmlChild::Element(node));
+            }
+            Ok(Event::End(_)) if stack.len() > 1 => {
+                let node = stack.pop().unwrap();
+                stack.last_mut().unwrap().children.push(XmlChild::Element(node));
+            }
+            Ok(Event::Text(e)) => {
+                let text = e.unescape().map(|t| t.to_string()).unwrap_or_else(|_| String::from_utf8_lossy(&e).to_string());
+                stack.last_mut().unwrap().children.push(XmlChild::Text(text));
+            }
+            Ok(Event::CData(e)) => {
+                let text = String::from_utf8_lossy(&e).to_string();
+                stack.last_mut().unwrap().children.push(XmlChild::Text(text));
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(format!("Invalid XML at byte {}: {}", reader.buffer_position(), e)),
+            _ => {}
+        }
+        buf.clear();
+    }
+    // Close anything left open by a truncated part
+    while stack.len() > 1 {
+        let node = stack.pop().unwrap();
+        stack.last_mut().unwrap().children.push(XmlChild::Element(node));
+    }
+    stack
+        .pop()
+        .and_then(|root| root.elements().next().cloned())
+        .ok_or_else(|| "XML part has no root element".to_string())
+}
+
+fn xml_element(e: &quick_xml::events::BytesStart) -> XmlNode {
+    XmlNode {
+        name: String::from_utf8_lossy(e.local_name().as_ref()).to_string(),
+        attrs: e
+            .attributes()
+            .flatten()
+            .map(|a| (String::from_utf8_lossy(a.key.local_name().as_ref()).to_string(), attribute_value(&a)))
+            .collect(),
+        children: Vec::new(),
+    }
+}
+
+/// One entry of a part's .rels file
+#[derive(Debug, Clone)]
+struct Relationship {
+    /// Package path for internal targets, the URL for external ones
+    target: String,
+    external: bool,
+    /// Last segment of the relationship type, e.g. "image" or "footnotes"
+    kind: String,
+}
+
+type Relationships = HashMap<String, Relationship>;
+
+/// Run properties as written in a style or a run. `None` leaves the
+/// inherited value alone.
+#[derive(Debug, Clone, Default)]
+struct RunFormat {
+    bold: Option<bool>,
+    italic: Option<bool>,
+    underline: Option<bool>,
+    strike: Option<bool>,
+    caps: Option<bool>,
+    hidden: Option<bool>,
+    mono: Option<bool>,
+    vert_align: Option<String>,
+    /// CSS color, or empty for an explicit "auto"
+    color: Option<String>,
+    background: Option<String>,
+}
+
+impl RunFormat {
+    fn from_rpr(rpr: &XmlNode) -> Self {
+        let toggle = |name: &str| rpr.child(name).map(|e| !matches!(e.attr("val"), Some("0" | "false" | "off")));
+        let highlight = rpr.val("highlight").map(highlight_color).or_else(|| {
+            rpr.child("shd")
+                .and_then(|s| s.attr("fill"))
+                .map(|fill| if fill == "auto" { String::new() } else { format!("#{}", fill) })
+        });
+        RunFormat {
+            bold: toggle("b"),
+            italic: toggle("i"),
+            underline: rpr.val("u").map(|u| u != "none").or_else(|| rpr.child("u").map(|_| true)),
+            strike: toggle("strike").or_else(|| toggle("dstrike")),
+            caps: toggle("caps"),
+            hidden: toggle("vanish"),
+            mono: rpr
+                .child("rFonts")
+                .and_then(|f| f.attr("ascii").or_else(|| f.attr("hAnsi")))
+                .map(is_monospace_font),
+            vert_align: rpr.val("vertAlign").map(str::to_string),
+            color: rpr.val("color").map(|c| match c {
+                "auto" | "000000" => String::new(),
+                hex => format!("#{}", hex),
+            }),
+            background: highlight,
+        }
+    }
+
+    /// These properties with `over`'s set values applied on top
+    fn with(&self, over: &RunFormat) -> RunFormat {
+        RunFormat {
+            bold: over.bold.or(self.bold),
+            italic: over.italic.or(self.italic),
+            underline: over.underline.or(self.underline),
+            strike: over.strike.or(self.strike),
+            caps: over.caps.or(self.caps),
+            hidden: over.hidden.or(self.hidden),
+            mono: over.mono.or(self.mono),
+            vert_align: over.vert_align.clone().or_else(|| self.vert_align.clone()),
+            color: over.color.clone().or_else(|| self.color.clone()),
+            background: over.background.clone().or_else(|| self.background.clone()),
+        }
+    }
+}
+
+/// The resolved formatting of a piece of inline HTML
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Format {
+    link: Option<String>,
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strike: bool,
+    mono: bool,
+    vert: Option<&'static str>,
+    color: Option<String>,
+    background: Option<String>,
+}
+
+impl Format {
+    fn resolve(run: &RunFormat, link: Option<&str>) -> Format {
+        let non_empty = |v: &Option<String>| v.clone().filter(|s| !s.is_empty());
+        Format {
+            link: link.map(str::to_string),
+            bold: run.bold.unwrap_or(false),
+            italic: run.italic.unwrap_or(false),
+            // Links get their own styling, which usually repeats the blue underline
+            underline: run.underline.unwrap_or(false) && link.is_none(),
+            strike: run.strike.unwrap_or(false),
+            mono: run.mono.unwrap_or(false),
+            vert: match run.vert_align.as_deref() {
+                Some("superscript") => Some("sup"),
+                Some("subscript") => Some("sub"),
+                _ => None,
+            },
+            color: if link.is_some() { None } else { non_empty(&run.color) },
+            background: non_empty(&run.background),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct StyleDef {
+    name: String,
+    based_on: Option<String>,
+    outline_level: Option<usize>,
+    num_id: Option<String>,
+    num_level: Option<usize>,
+    align: Option<String>,
+    run: RunFormat,
+}
+
+#[derive(Debug, Clone)]
+struct ListLevel {
+    format: String,
+    text: String,
+    start: usize,
+}
+
+impl Default for ListLevel {
+    fn default() -> Self {
+        ListLevel { format: "decimal".to_string(), text: String::new(), start: 1 }
+    }
+}
+
+/// A `w:num` instance: its abstract definition plus restarted levels
+#[derive(Debug, Clone, Default)]
+struct NumberingInstance {
+    abstract_id: String,
+    start_overrides: HashMap<usize, usize>,
+}
+
+struct OpenList {
+    num_id: String,
+    level: usize,
+    tag: &'static str,
+}
+
+/// A complex field (`w:fldChar` begin ... separate ... end)
+struct Field {
+    instruction: String,
+    link: Option<String>,
+    in_result: bool,
+}
+
+enum ParagraphKind {
+    Normal,
+    Heading(usize),
+    Quote,
+    Code,
+    ListItem(String, usize),
+}
+
+struct DocxReader {
+    archive: zip::ZipArchive<File>,
+    styles: HashMap<String, StyleDef>,
+    default_paragraph_style: Option<String>,
+    abstract_levels: HashMap<String, HashMap<usize, ListLevel>>,
+    numbering: HashMap<String, NumberingInstance>,
+    /// Relationships of the part being converted
+    rels: Relationships,
+    /// Footnote and endnote bodies keyed "footnote:ID" / "endnote:ID"
+    notes: HashMap<String, XmlNode>,
+    note_rels: HashMap<String, Relationships>,
+    /// Referenced notes in reading order; the index + 1 is the note number
+    note_refs: Vec<String>,
+    /// Image part -> src, so repeated pictures are written once
+    images: HashMap<String, String>,
+    image_dir: Option<(PathBuf, String)>,
+    used_names: Vec<String>,
+    lists: Vec<OpenList>,
+    /// "blockquote" or "pre" while consecutive quote or code paragraphs are open
+    container: Option<&'static str>,
+    /// Items seen per (numId, level), for list starts and heading numbers
+    counters: HashMap<(String, usize), usize>,
+    fields: Vec<Field>,
+    pending_anchors: Vec<String>,
+    title: Option<String>,
+    author: Option<String>,
+    paragraph_count: usize,
+    table_count: usize,
+    image_count: usize,
+}
+
+impl DocxReader {
+    fn new(archive: zip::ZipArchive<File>, image_dir: Option<(PathBuf, String)>) -> Self {
+        DocxReader {
+            archive,
+            styles: HashMap::new(),
+            default_paragraph_style: None,
+            abstract_levels: HashMap::new(),
+            numbering: HashMap::new(),
+            rels: HashMap::new(),
+            notes: HashMap::new(),
+            note_rels: HashMap::new(),
+            note_refs: Vec::new(),
+            images: HashMap::new(),
+            image_dir,
+            used_names: Vec::new(),
+            lists: Vec::new(),
+            container: None,
+            counters: HashMap::new(),
+            fields: Vec::new(),
+            pending_anchors: Vec::new(),
+            title: None,
+            author: None,
+            paragraph_count: 0,
+            table_count: 0,
+            image_count: 0,
+        }
+    }
+
+    /// Convert the main document, with header, footer and notes, to body HTML
+    fn render(&mut self) -> Result<String, String> {
+        let package_rels = self.load_rels("");
+        let main_part = package_rels
+            .values()
+            .find(|r| r.kind == "officeDocument")
+            .map(|r| r.target.clone())
+            .unwrap_or_else(|| "word/document.xml".to_string());
+        let core_part = package_rels
+            .values()
+            .find(|r| r.kind == "core-properties")
+            .map(|r| r.target.clone())
+            .unwrap_or_else(|| "docProps/core.xml".to_string());
+        if let Some(core) = self.load_part(&core_part) {
+            let non_empty = |n: Option<&XmlNode>| n.map(|n| n.text().trim().to_string()).filter(|t| !t.is_empty());
+            self.title = non_empty(core.descendant("title"));
+            self.author = non_empty(core.descendant("creator"));
+        }
+
+        let document = self
+            .load_part(&main_part)
+            .ok_or_else(|| format!("Not a Word document: {} is missing or unreadable", main_part))?;
+        let body = document.child("body").ok_or("Word document has no body")?.clone();
+        let main_rels = self.load_rels(&main_part);
+
+        for rel in main_rels.values().filter(|r| !r.external) {
+            match rel.kind.as_str() {
+                "styles" => {
+                    if let Some(styles) = self.load_part(&rel.target) {
+                        self.read_styles(&styles);
+                    }
+                }
+                "numbering" => {
+                    if let Some(numbering) = self.load_part(&rel.target) {
+                        self.read_numbering(&numbering);
+                    }
+                }
+                "footnotes" | "endnotes" => {
+                    let kind = rel.kind.trim_end_matches('s').to_string();
+                    if let Some(notes) = self.load_part(&rel.target) {
+                        for note in notes.elements().filter(|n| n.name == kind) {
+                            if let Some(id) = note.attr("id") {
+                                self.notes.insert(format!("{}:{}", kind, id), note.clone());
+                            }
+                        }
+                    }
+                    let rels = self.load_rels(&rel.target);
+                    self.note_rels.insert(kind, rels);
+                }
+                _ => {}
+            }
+        }
+
+        // The page header and footer of the first section
+        let section = body.descendant("sectPr").cloned();
+        let header_footer = |tag: &str| -> Option<String> {
+            section
+                .as_ref()?
+                .elements()
+                .find(|e| e.name == tag && e.attr("type").unwrap_or("default") == "default")
+                .and_then(|e| e.attr("id"))
+                .and_then(|id| main_rels.get(id))
+                .map(|r| r.target.clone())
+        };
+        let header_part = header_footer("headerReference");
+        let footer_part = header_footer("footerReference");
+
+        let mut html = String::new();
+        if let Some(part) = header_part {
+            let content = self.render_part(&part);
+            if !content.trim().is_empty() {
+                html.push_str(&format!("<header class=\"docx-header\">\n{}</header>\n", content));
+            }
+        }
+
+        self.rels = main_rels;
+        html.push_str(&self.blocks(&body));
+
+        if !self.note_refs.is_empty() {
+            html.push_str("<section class=\"footnotes\">\n<ol>\n");
+            let mut index = 0;
+            while index < self.note_refs.len() {
+                let key = self.note_refs[index].clone();
+                let number = index + 1;
+                index += 1;
+                let kind = key.split(':').next().unwrap_or("footnote").to_string();
+                let note = match self.notes.get(&key) {
+                    Some(n) => n.clone(),
+                    None => continue,
+                };
+                self.rels = self.note_rels.get(&kind).cloned().unwrap_or_default();
+                let mut content = self.blocks(&note);
+                let back = format!(" <a href=\"#fnref{}\" class=\"footnote-back\">\u{21a9}</a>", number);
+                match content.rfind("</p>") {
+                    Some(pos) => content.insert_str(pos, &back),
+                    None => content.push_str(&back),
+                }
+                html.push_str(&format!("<li id=\"fn{}\">{}</li>\n", number, content.trim_end()));
+            }
+            html.push_str("</ol>\n</section>\n");
+        }
+
+        if let Some(part) = footer_part {
+            let content = self.render_part(&part);
+            if !content.trim().is_empty() {
+                html.push_str(&format!("<footer class=\"docx-footer\">\n{}</footer>\n", content));
+            }
+        }
+        Ok(html)
+    }
+
+    /// Convert a header or footer part with its own relationships
+    fn render_part(&mut self, part: &str) -> String {
+        let root = match self.load_part(part) {
+            Some(r) => r,
+            None => return String::new(),
+        };
+        let rels = self.load_rels(part);
+        let saved = std::mem::replace(&mut self.rels, rels);
+        let html = self.blocks(&root);
+        self.rels = saved;
+        html
+    }
+
+    fn load_part(&mut self, path: &str) -> Option<XmlNode> {
+        read_entry(&mut self.archive, path).and_then(|bytes| parse_xml(&bytes).ok())
+    }
+
+    /// The relationships of a part, read from `dir/_rels/name.rels`
+    fn load_rels(&mut self, part: &str) -> Relationships {
+        let (dir, name) = part.rsplit_once('/').unwrap_or(("", part));
+        let rels_path = if dir.is_empty() { format!("_rels/{}.rels", name) } else { format!("{}/_rels/{}.rels", dir, name) };
+        let mut rels = HashMap::new();
+        if let Some(root) = self.load_part(&rels_path) {
+            for rel in root.elements().filter(|e| e.name == "Relationship") {
+                let (id, target) = match (rel.attr("Id"), rel.attr("Target")) {
+                    (Some(id), Some(target)) => (id, target),
+                    _ => continue,
+                };
+                let external = rel.attr("TargetMode") == Some("External");
+                let target = if external {
+                    target.to_string()
+                } else if let Some(absolute) = target.strip_prefix('/') {
+                    absolute.to_string()
+                } else {
+                    resolve_href(dir, target)
+                };
+                let kind = rel.attr("Type").and_then(|t| t.rsplit('/').next()).unwrap_or("").to_string();
+                rels.insert(id.to_string(), Relationship { target, external, kind });
+            }
+        }
+        rels
+    }
+
+    fn read_styles(&mut self, styles: &XmlNode) {
+        for style in styles.elements().filter(|e| e.name == "style") {
+            let id = match style.attr("styleId") {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            let ppr = style.child("pPr");
+            let num_pr = ppr.and_then(|p| p.child("numPr"));
+            let def = StyleDef {
+                name: style.val("name").unwrap_or(&id).to_string(),
+                based_on: style.val("basedOn").map(str::to_string),
+                outline_level: ppr.and_then(|p| p.val("outlineLvl")).and_then(|v| v.parse().ok()),
+                num_id: num_pr.and_then(|n| n.val("numId")).map(str::to_string),
+                num_level: num_pr.and_then(|n| n.val("ilvl")).and_then(|v| v.parse().ok()),
+                align: ppr.and_then(|p| p.val("jc")).map(str::to_string),
+                run: style.child("rPr").map(RunFormat::from_rpr).unwrap_or_default(),
+            };
+            if style.attr("type") == Some("paragraph") && matches!(style.attr("default"), Some("1" | "true")) {
+                self.default_paragraph_style = Some(id.clone());
+            }
+            self.styles.insert(id, def);
+        }
+    }
+
+    fn read_numbering(&mut self, numbering: &XmlNode) {
+        for abstract_num in numbering.elements().filter(|e| e.name == "abstractNum") {
+            let id = match abstract_num.attr("abstractNumId") {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            let mut levels = HashMap::new();
+            for lvl in abstract_num.elements().filter(|e| e.name == "lvl") {
+                let ilvl = lvl.attr("ilvl").and_then(|v| v.parse().ok()).unwrap_or(0);
+                levels.insert(
+                    ilvl,
+                    ListLevel {
+                        format: lvl.val("numFmt").unwrap_or("decimal").to_string(),
+                        text: lvl.val("lvlText").unwrap_or("").to_string(),
+                        start: lvl.val("start").and_then(|v| v.parse().ok()).unwrap_or(1),
+                    },
+                );
+            }
+            self.abstract_levels.insert(id, levels);
+        }
+        for num in numbering.elements().filter(|e| e.name == "num") {
+            let id = match num.attr("numId") {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            let start_overrides = num
+                .elements()
+                .filter(|e| e.name == "lvlOverride")
+                .filter_map(|o| {
+                    let level = o.attr("ilvl")?.parse().ok()?;
+                    let start = o.val("startOverride")?.parse().ok()?;
+                    Some((level, start))
+                })
+                .collect();
+            let abstract_id = num.val("abstractNumId").unwrap_or("").to_string();
+            self.numbering.insert(id, NumberingInstance { abstract_id, start_overrides });
+        }
+    }
+
+    /// A style followed by the styles it is based on
+    fn style_chain(&self, id: &str) -> Vec<&StyleDef> {
+        let mut chain = Vec::new();
+        let mut next = Some(id);
+        while let Some(style) = next.and_then(|id| self.styles.get(id)) {
+            if chain.len() >= 16 {
+                break;
+            }
+            chain.push(style);
+            next = style.based_on.as_deref();
+        }
+        chain
+    }
+
+    fn style_run_format(&self, id: &str) -> RunFormat {
+        self.style_chain(id).iter().rev().fold(RunFormat::default(), |format, style| format.with(&style.run))
+    }
+
+    fn list_level(&self, num_id: &str, level: usize) -> ListLevel {
+        let instance = match self.numbering.get(num_id) {
+            Some(n) => n,
+            None => return ListLevel::default(),
+        };
+        let mut list_level = self
+            .abstract_levels
+            .get(&instance.abstract_id)
+            .and_then(|levels| levels.get(&level))
+            .cloned()
+            .unwrap_or_default();
+        if let Some(start) = instance.start_overrides.get(&level) {
+            list_level.start = *start;
+        }
+        list_level
+    }
+
+    fn paragraph_kind(&self, ppr: Option<&XmlNode>, style_id: Option<&str>) -> ParagraphKind {
+        let chain = style_id.map(|id| self.style_chain(id)).unwrap_or_default();
+
+        let heading = ppr
+            .and_then(|p| p.val("outlineLvl"))
+            .and_then(|v| v.parse::<usize>().ok())
+            .map(|level| level + 1)
+            .or_else(|| {
+                chain.iter().find_map(|style| {
+                    let name = style.name.to_lowercase();
+                    if name == "title" {
+                        Some(1)
+                    } else if let Some(n) = name.strip_prefix("heading ").and_then(|n| n.trim().parse::<usize>().ok()) {
+                        Some(n)
+                    } else {
+                        style.outline_level.map(|level| level + 1)
+                    }
+                })
+            })
+            .filter(|level| (1..=9).contains(level));
+        if let Some(level) = heading {
+            return ParagraphKind::Heading(level.min(6));
+        }
+
+        let num_pr = ppr.and_then(|p| p.child("numPr"));
+        let num_id = num_pr
+            .and_then(|n| n.val("numId"))
+            .map(str::to_string)
+            .or_else(|| chain.iter().find_map(|s| s.num_id.clone()));
+        if let Some(num_id) = num_id.filter(|id| id != "0" && self.numbering.contains_key(id)) {
+            let level = num_pr
+                .and_then(|n| n.val("ilvl"))
+                .and_then(|v| v.parse().ok())
+                .or_else(|| chain.iter().find_map(|s| s.num_level))
+                .unwrap_or(0);
+            return ParagraphKind::ListItem(num_id, level);
+        }
+
+        let names: Vec<String> = chain.iter().map(|s| s.name.to_lowercase()).collect();
+        if names.iter().any(|n| n.contains("quote")) {
+            ParagraphKind::Quote
+        } else if names
+            .iter()
+            .any(|n| n.contains("code") || n == "html preformatted" || n == "plain text" || n == "macro text")
+        {
+            ParagraphKind::Code
+        } else {
+            ParagraphKind::Normal
+        }
+    }
+
+    /// Convert the block content of a body, cell, note or header. Lists and
+    /// quote or code runs opened inside are closed before returning.
+    fn blocks(&mut self, parent: &XmlNode) -> String {
+        let saved_lists = std::mem::take(&mut self.lists);
+        let saved_container = self.container.take();
+        let mut out = String::new();
+        self.blocks_into(parent, &mut out);
+        self.close_lists(&mut out, 0);
+        self.close_container(&mut out);
+        self.lists = saved_lists;
+        self.container = saved_container;
+        out
+    }
+
+    fn blocks_into(&mut self, parent: &XmlNode, out: &mut String) {
+        for child in parent.elements() {
+            match child.name.as_str() {
+                "p" => self.paragraph(child, out),
+                "tbl" => {
+                    self.close_lists(out, 0);
+                    self.close_container(out);
+                    self.table(child, out);
+                }
+                "sdt" => {
+                    if let Some(content) = child.child("sdtContent") {
+                        self.blocks_into(content, out);
+                    }
+                }
+                "sdtContent" | "customXml" | "ins" | "moveTo" => self.blocks_into(child, out),
+                "bookmarkStart" => {
+                    if let Some(name) = child.attr("name").filter(|n| *n != GO_BACK_BOOKMARK) {
+                        self.pending_anchors.push(name.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn close_lists(&mut self, out: &mut String, keep: usize) {
+        while self.lists.len() > keep {
+            let list = self.lists.pop().unwrap();
+            out.push_str(&format!("</li>\n</{}>\n", list.tag));
+        }
+    }
+
+    fn close_container(&mut self, out: &mut String) {
+        match self.container.take() {
+            Some("pre") => out.push_str("</pre>\n"),
+            Some(tag) => out.push_str(&format!("</{}>\n", tag)),
+            None => {}
+        }
+    }
+
+    fn open_container(&mut self, out: &mut String, tag: &'static str) -> bool {
+        if self.container == Some(tag) {
+            return false;
+        }
+        self.close_container(out);
+        out.push_str(if tag == "pre" { "<pre>" } else { "<blockquote>\n" });
+        self.container = Some(tag);
+        true
+    }
+
+    /// Count a numbered item and restart the levels below it, returning
+    /// how many items its level has had before
+    fn count_item(&mut self, num_id: &str, level: usize) -> usize {
+        self.counters.retain(|(id, l), _| id != num_id || *l <= level);
+        let counter = self.counters.entry((num_id.to_string(), level)).or_insert(0);
+        *counter += 1;
+        *counter - 1
+    }
+
+    /// The label of a numbered item, e.g. "2.1." from a level text of "%1.%2."
+    fn number_label(&self, num_id: &str, level: usize) -> String {
+        let mut label = self.list_level(num_id, level).text;
+        for l in 0..9 {
+            let placeholder = format!("%{}", l + 1);
+            if label.contains(&placeholder) {
+                let list_level = self.list_level(num_id, l);
+                let seen = self.counters.get(&(num_id.to_string(), l)).copied().unwrap_or(0);
+                let value = list_level.start + seen.max(1) - 1;
+                label = label.replace(&placeholder, &format_number(value, &list_level.format));
+            }
+        }
+        label
+    }
+
+    fn list_item(&mut self, out: &mut String, num_id: &str, level: usize) {
+        while let Some(top) = self.lists.last() {
+            if top.level > level || (top.level == level && top.num_id != num_id) {
+                let keep = self.lists.len() - 1;
+                self.close_lists(out, keep);
+            } else {
+                break;
+            }
+        }
+        let seen = self.count_item(num_id, level);
+        if self.lists.last().map(|top| top.level == level).unwrap_or(false) {
+            out.push_str("</li>\n<li>");
+            return;
+        }
+
+        let list_level = self.list_level(num_id, level);
+        let (tag, attrs) = match list_level.format.as_str() {
+            "bullet" => ("ul", String::new()),
+            "none" => ("ul", " style=\"list-style: none\"".to_string()),
+            format => {
+                let mut attrs = match format {
+                    "lowerLetter" => " type=\"a\"".to_string(),
+                    "upperLetter" => " type=\"A\"".to_string(),
+                    "lowerRoman" => " type=\"i\"".to_string(),
+                    "upperRoman" => " type=\"I\"".to_string(),
+                    _ => String::new(),
+                };
+                let start = list_level.start + seen;
+                if start != 1 {
+                    attrs.push_str(&format!(" start=\"{}\"", start));
+                }
+                ("ol", attrs)
+            }
+        };
+        if self.lists.is_empty() {
+            out.push_str(&format!("<{}{}>\n<li>", tag, attrs));
+        } else {
+            out.push_str(&format!("\n<{}{}>\n<li>", tag, attrs));
+        }
+        self.lists.push(OpenList { num_id: num_id.to_string(), level, tag });
+    }
+
+    fn paragraph(&mut self, p: &XmlNode, out: &mut String) {
+        let ppr = p.child("pPr");
+        let style_id = ppr
+            .and_then(|p| p.val("pStyle"))
+            .map(str::to_string)
+            .or_else(|| self.default_paragraph_style.clone());
+        let kind = self.paragraph_kind(ppr, style_id.as_deref());
+        let base = style_id.as_deref().map(|id| self.style_run_format(id)).unwrap_or_default();
+        let align = ppr
+            .and_then(|p| p.val("jc"))
+            .map(str::to_string)
+            .or_else(|| style_id.as_deref().and_then(|id| self.style_chain(id).iter().find_map(|s| s.align.clone())));
+        let break_before = ppr.and_then(|p| p.child("pageBreakBefore")).map(|b| b.attr("val") != Some("0")).unwrap_or(false);
+
+        let mut pieces: Vec<(Format, String)> = std::mem::take(&mut self.pending_anchors)
+            .into_iter()
+            .map(|name| (Format::default(), format!("<a id=\"{}\"></a>", escape_markup(&name))))
+            .collect();
+        let mut page_break = None;
+        self.inline(p, &base, None, &mut pieces, &mut page_break);
+        let content = render_pieces(&pieces);
+        let has_text = pieces.iter().any(|(_, html)| !html.starts_with("<a id="));
+
+        // A page break ahead of the paragraph's text goes before it, one
+        // inside the text after it
+        let break_html = "<div style=\"page-break-before: always\"></div>\n";
+        let break_after = page_break == Some(false) && has_text && !break_before;
+        if break_before || (page_break.is_some() && !break_after) {
+            self.close_lists(out, 0);
+            self.close_container(out);
+            out.push_str(break_html);
+        }
+
+        let style = match align.as_deref() {
+            Some("center") => " style=\"text-align: center\"",
+            Some("right") | Some("end") => " style=\"text-align: right\"",
+            Some("both") | Some("distribute") => " style=\"text-align: justify\"",
+            _ => "",
+        };
+
+        match kind {
+            ParagraphKind::Heading(level) => {
+                self.close_lists(out, 0);
+                self.close_container(out);
+                if !has_text {
+                    out.push_str(&content);
+                    return;
+                }
+                // Outline-numbered headings keep their number
+                let num_pr = ppr.and_then(|p| p.child("numPr"));
+                let mut label = String::new();
+                if let (Some(num_id), Some(level)) = (
+                    num_pr.and_then(|n| n.val("numId")).filter(|id| *id != "0" && self.numbering.contains_key(*id)),
+                    num_pr.map(|n| n.val("ilvl").and_then(|v| v.parse().ok()).unwrap_or(0)),
+                ) {
+                    let num_id = num_id.to_string();
+                    if !matches!(self.list_level(&num_id, level).format.as_str(), "bullet" | "none") {
+                        self.count_item(&num_id, level);
+                        label = self.number_label(&num_id, level);
+                    }
+                }
+                if !label.is_empty() {
+                    label = format!("{} ", escape_markup(&label));
+                }
+                self.paragraph_count += 1;
+                out.push_str(&format!("<h{}{}>{}{}</h{}>\n", level, style, label, content, level));
+            }
+            ParagraphKind::ListItem(num_id, level) => {
+                self.close_container(out);
+                self.list_item(out, &num_id, level);
+                self.paragraph_count += 1;
+                out.push_str(&content);
+            }
+            ParagraphKind::Code => {
+                self.close_lists(out, 0);
+                if !self.open_container(out, "pre") {
+                    out.push('\n');
+                }
+                self.paragraph_count += 1;
+                out.push_str(&content);
+            }
+            ParagraphKind::Quote | ParagraphKind::Normal => {
+                if !has_text {
+                    // Empty paragraphs are spacing; keep their bookmarks only
+                    out.push_str(&content);
+                } else {
+                    self.close_lists(out, 0);
+                    if matches!(kind, ParagraphKind::Quote) {
+                        self.open_container(out, "blockquote");
+                    } else {
+                        self.close_container(out);
+                    }
+                    self.paragraph_count += 1;
+                    out.push_str(&format!("<p{}>{}</p>\n", style, content));
+                }
+            }
+        }
+
+        if break_after {
+            self.close_lists(out, 0);
+            self.close_container(out);
+            out.push_str(break_html);
+        }
+    }
+
+    /// Convert the runs, links and fields inside a paragraph or inline container
+    fn inline(
+        &mut self,
+        parent: &XmlNode,
+        base: &RunFormat,
+        link: Option<&str>,
+        pieces: &mut Vec<(Format, String)>,
+        page_break: &mut Option<bool>,
+    ) {
+        for child in parent.elements() {
+            match child.name.as_str() {
+                "r" => self.run(child, base, link, pieces, page_break),
+                "hyperlink" => {
+                    let target = child.attr("id").and_then(|id| self.rels.get(id)).map(|r| r.target.clone());
+                    let href = match (target, child.attr("anchor")) {
+                        (Some(url), Some(anchor)) => Some(format!("{}#{}", url, anchor)),
+                        (Some(url), None) => Some(url),
+                        (None, Some(anchor)) => Some(format!("#{}", anchor)),
+                        (None, None) => None,
+                    };
+                    self.inline(child, base, href.as_deref().or(link), pieces, page_break);
+                }
+                "fldSimple" => {
+                    let href = child.attr("instr").and_then(field_link);
+                    self.inline(child, base, href.as_deref().or(link), pieces, page_break);
+                }
+                "sdt" => {
+                    if let Some(content) = child.child("sdtContent") {
+                        self.inline(content, base, link, pieces, page_break);
+                    }
+                }
+                "ins" | "moveTo" | "smartTag" | "customXml" | "sdtContent" => {
+                    self.inline(child, base, link, pieces, page_break)
+                }
+                "bookmarkStart" => {
+                    if let Some(name) = child.attr("name").filter(|n| *n != GO_BACK_BOOKMARK) {
+                        pieces.push((Format::default(), format!("<a id=\"{}\"></a>", escape_markup(name))));
+                    }
+                }
+                "oMathPara" | "oMath" => {
+                    let mut math = String::new();
+                    collect_math_text(child, &mut math);
+                    if !math.is_empty() {
+                        let format = Format { italic: true, ..Format::resolve(base, link) };
+                        pieces.push((format, escape_markup(&math)));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn run(
+        &mut self,
+        r: &XmlNode,
+        base: &RunFormat,
+        link: Option<&str>,
+        pieces: &mut Vec<(Format, String)>,
+        page_break: &mut Option<bool>,
+    ) {
+        let rpr = r.child("rPr");
+        let mut format = base.clone();
+        if let Some(style) = rpr.and_then(|p| p.val("rStyle")) {
+            format = format.with(&self.style_run_format(style));
+        }
+        if let Some(rpr) = rpr {
+            format = format.with(&RunFormat::from_rpr(rpr));
+        }
+        self.run_content(r, &format, link, pieces, page_break);
+    }
+
+    fn run_content(
+        &mut self,
+        parent: &XmlNode,
+        format: &RunFormat,
+        link: Option<&str>,
+        pieces: &mut Vec<(Format, String)>,
+        page_break: &mut Option<bool>,
+    ) {
+        let hidden = format.hidden.unwrap_or(false);
+        for child in parent.elements() {
+            match child.name.as_str() {
+                "fldChar" => match child.attr("fldCharType") {
+                    Some("begin") => self.fields.push(Field { instruction: String::new(), link: None, in_result: false }),
+                    Some("separate") => {
+                        if let Some(field) = self.fields.last_mut() {
+                            field.link = field_link(&field.instruction);
+                            field.in_result = true;
+                        }
+                    }
+                    Some("end") => {
+                        self.fields.pop();
+                    }
+                    _ => {}
+                },
+                "instrText" => {
+                    if let Some(field) = self.fields.last_mut() {
+                        field.instruction.push_str(&child.text());
+                    }
+                }
+                _ if hidden || self.fields.iter().any(|f| !f.in_result) => {}
+                "AlternateContent" => {
+                    let before = pieces.len();
+                    if let Some(choice) = child.child("Choice") {
+                        self.run_content(choice, format, link, pieces, page_break);
+                    }
+                    if pieces.len() == before {
+                        if let Some(fallback) = child.child("Fallback") {
+                            self.run_content(fallback, format, link, pieces, page_break);
+                        }
+                    }
+                }
+                name => {
+                    let field_link = self.fields.iter().rev().find_map(|f| f.link.clone());
+                    let link = field_link.as_deref().or(link);
+                    let resolved = Format::resolve(format, link);
+                    let html = match name {
+                        "t" => {
+                            let text = child.text();
+                            let text = if format.caps.unwrap_or(false) { text.to_uppercase() } else { text };
+                            escape_markup(&text)
+                        }
+                        "tab" | "ptab" => "\u{2003}".to_string(),
+                        "br" | "cr" => match child.attr("type") {
+                            Some("page") => {
+                                if page_break.is_none() {
+                                    *page_break = Some(pieces.iter().all(|(_, html)| html.starts_with("<a id=")));
+                                }
+                                continue;
+                            }
+                            _ => "<br>".to_string(),
+                        },
+                        "noBreakHyphen" => "\u{2011}".to_string(),
+                        "softHyphen" => "\u{ad}".to_string(),
+                        "sym" => match child.attr("char").and_then(|c| u32::from_str_radix(c, 16).ok()) {
+                            // Symbol fonts map their glyphs into the private use area at F000
+                            Some(code) => {
+                                let code = if code >= 0xF000 { code - 0xF000 } else { code };
+                                char::from_u32(code).map(|c| escape_markup(&c.to_string())).unwrap_or_default()
+                            }
+                            None => continue,
+                        },
+                        "drawing" => match self.drawing_image(child) {
+                            Some(img) => img,
+                            None => continue,
+                        },
+                        "pict" | "object" => match self.vml_image(child) {
+                            Some(img) => img,
+                            None => continue,
+                        },
+                        "footnoteReference" | "endnoteReference" => {
+                            let kind = name.trim_end_matches("Reference");
+                            let id = child.attr("id").unwrap_or("");
+                            self.note_refs.push(format!("{}:{}", kind, id));
+                            let number = self.note_refs.len();
+                            let format = Format { vert: None, link: None, ..resolved };
+                            pieces.push((
+                                format,
+                                format!("<sup><a href=\"#fn{0}\" id=\"fnref{0}\">{0}</a></sup>", number),
+                            ));
+                            continue;
+                        }
+                        _ => continue,
+                    };
+                    pieces.push((resolved, html));
+                }
+            }
+        }
+    }
+
+    fn drawing_image(&mut self, drawing: &XmlNode) -> Option<String> {
+        let blip = drawing.descendant("blip")?;
+        let rel_id = blip.attr("embed").or_else(|| blip.attr("link"))?.to_string();
+        let size = drawing.descendant("extent").and_then(|extent| {
+            let cx: u64 = extent.attr("cx")?.parse().ok()?;
+            let cy: u64 = extent.attr("cy")?.parse().ok()?;
+            Some(((cx / EMU_PER_PX) as u32, (cy / EMU_PER_PX) as u32))
+        });
+        let alt = drawing
+            .descendant("docPr")
+            .and_then(|d| d.attr("descr").filter(|s| !s.is_empty()).or_else(|| d.attr("title")))
+            .unwrap_or("")
+            .to_string();
+        self.image_html(&rel_id, size, &alt)
+    }
+
+    /// Pictures from older documents, written as VML `v:imagedata`
+    fn vml_image(&mut self, pict: &XmlNode) -> Option<String> {
+        let data = pict.descendant("imagedata")?;
+        let rel_id = data.attr("id")?.to_string();
+        let size = pict.descendant("shape").and_then(|shape| shape.attr("style")).and_then(|style| {
+            let length = |name: &str| {
+                style.split(';').find_map(|decl| {
+                    let (key, value) = decl.split_once(':')?;
+                    if key.trim() != name {
+                        return None;
+                    }
+                    let value = value.trim();
+                    let (number, factor) = match value.strip_suffix("pt") {
+                        Some(pt) => (pt, 4.0 / 3.0),
+                        None => (value.trim_end_matches("px"), 1.0),
+                    };
+                    number.parse::<f32>().ok().map(|n| (n * factor).round() as u32)
+                })
+            };
+            Some((length("width")?, length("height")?))
+        });
+        let alt = data.attr("title").unwrap_or("").to_string();
+        self.image_html(&rel_id, size, &alt)
+    }
+
+    fn image_html(&mut self, rel_id: &str, size: Option<(u32, u32)>, alt: &str) -> Option<String> {
+        let rel = self.rels.get(rel_id)?.clone();
+        let src = if rel.external { rel.target } else { self.image_source(&rel.target)? };
+        self.image_count += 1;
+        let size = size
+            .filter(|(w, h)| *w > 0 && *h > 0)
+            .map(|(w, h)| format!(" width=\"{}\" height=\"{}\"", w, h))
+            .unwrap_or_default();
+        Some(format!("<img src=\"{}\" alt=\"{}\"{}>", escape_markup(&src), escape_markup(alt), size))
+    }
+
+    /// The src for a picture part: a data URI, or a file in the image folder
+    fn image_source(&mut self, target: &str) -> Option<String> {
+        if let Some(src) = self.images.get(target) {
+            return Some(src.clone());
+        }
+        let bytes = read_entry(&mut self.archive, target)?;
+        let name = target.rsplit('/').next().unwrap_or(target);
+        let extension = name.rsplit_once('.').map(|(_, e)| e.to_lowercase()).unwrap_or_default();
+
+        let src = match &self.image_dir {
+            None => format!("data:{};base64,{}", image_mime(&extension), BASE64.encode(&bytes)),
+            Some((dir, folder)) => {
+                fs::create_dir_all(dir).ok()?;
+                let file_name = unique_file_name(safe_file_name(name).unwrap_or("image"), &mut self.used_names);
+                fs::write(dir.join(&file_name), &bytes).ok()?;
+                format!("{}/{}", folder, file_name).replace(' ', "%20")
+            }
+        };
+        self.images.insert(target.to_string(), src.clone());
+        Some(src)
+    }
+
+    fn table(&mut self, tbl: &XmlNode, out: &mut String) {
+        self.table_count += 1;
+
+        // Lay the cells out on the table grid so vertical merges can be
+        // matched up by column
+        struct Cell<'a> {
+            node: Option<&'a XmlNode>,
+            column: usize,
+            span: usize,
+            merge: Option<bool>,
+        }
+        let mut rows: Vec<(bool, Vec<Cell>)> = Vec::new();
+        for tr in table_rows(tbl) {
+            let trpr = tr.child("trPr");
+            let header = trpr.map(|p| p.child("tblHeader").is_some()).unwrap_or(false);
+            let mut column = 0;
+            let mut cells = Vec::new();
+            let skipped = trpr.and_then(|p| p.val("gridBefore")).and_then(|v| v.parse().ok()).unwrap_or(0);
+            if skipped > 0 {
+                cells.push(Cell { node: None, column, span: skipped, merge: None });
+                column += skipped;
+            }
+            for tc in table_cells(tr) {
+                let tcpr = tc.child("tcPr");
+                let span = tcpr.and_then(|p| p.val("gridSpan")).and_then(|v| v.parse().ok()).unwrap_or(1).max(1);
+                let merge = tcpr.and_then(|p| p.child("vMerge")).map(|m| m.attr("val") == Some("restart"));
+                cells.push(Cell { node: Some(tc), column, span, merge });
+                column += span;
+            }
+            rows.push((header, cells));
+        }
+
+        out.push_str("<table>\n");
+        for (r, (header, cells)) in rows.iter().enumerate() {
+            out.push_str("<tr>\n");
+            for cell in cells {
+                if cell.merge == Some(false) {
+                    continue;
+                }
+                let mut rowspan = 1;
+                if cell.merge == Some(true) {
+                    for (_, below) in &rows[r + 1..] {
+                        if below.iter().any(|c| c.column == cell.column && c.merge == Some(false)) {
+                            rowspan += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                let tag = if *header { "th" } else { "td" };
+                let mut attrs = String::new();
+                if cell.span > 1 {
+                    attrs.push_str(&format!(" colspan=\"{}\"", cell.span));
+                }
+                if rowspan > 1 {
+                    attrs.push_str(&format!(" rowspan=\"{}\"", rowspan));
+                }
+                let content = match cell.node {
+                    Some(tc) => {
+                        if let Some(fill) = tc
+                            .child("tcPr")
+                            .and_then(|p| p.child("shd"))
+                            .and_then(|s| s.attr("fill"))
+                            .filter(|f| *f != "auto")
+                        {
+                            attrs.push_str(&format!(" style=\"background: #{}\"", escape_markup(fill)));
+                        }
+                        let html = self.blocks(tc);
+                        unwrap_single_paragraph(html.trim_end())
+                    }
+                    None => String::new(),
+                };
+                out.push_str(&format!("<{}{}>{}</{}>\n", tag, attrs, content, tag));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+    }
+}
+
+/// Rows of a table, including those wrapped in content controls or revisions
+fn table_rows(tbl: &XmlNode) -> Vec<&XmlNode> {
+    let mut rows = Vec::new();
+    for child in tbl.elements() {
+        match child.name.as_str() {
+            "tr" => rows.push(child),
+            "sdt" | "sdtContent" | "customXml" | "ins" => rows.extend(table_rows(child)),
+            _ => {}
+        }
+    }
+    rows
+}
+
+fn table_cells(tr: &XmlNode) -> Vec<&XmlNode> {
+    let mut cells = Vec::new();
+    for child in tr.elements() {
+        match child.name.as_str() {
+            "tc" => cells.push(child),
+            "sdt" | "sdtContent" | "customXml" | "ins" => cells.extend(table_cells(child)),
+            _ => {}
+        }
+    }
+    cells
+}
+
+/// Write inline pieces, nesting tags only where the formatting changes
+fn render_pieces(pieces: &[(Format, String)]) -> String {
+    let mut html = String::new();
+    let mut i = 0;
+    while i < pieces.len() {
+        let format = &pieces[i].0;
+        let mut inner = String::new();
+        while i < pieces.len() && pieces[i].0 == *format {
+            inner.push_str(&pieces[i].1);
+            i += 1;
+        }
+
+        let mut styles = Vec::new();
+        if let Some(color) = &format.color {
+            styles.push(format!("color: {}", color));
+        }
+        if let Some(background) = &format.background {
+            styles.push(format!("background: {}", background));
+        }
+        if !styles.is_empty() {
+            inner = format!("<span style=\"{}\">{}</span>", escape_markup(&styles.join("; ")), inner);
+        }
+        if let Some(tag) = format.vert {
+            inner = format!("<{0}>{1}</{0}>", tag, inner);
+        }
+        for (on, tag) in [
+            (format.mono, "code"),
+            (format.strike, "s"),
+            (format.underline, "u"),
+            (format.italic, "em"),
+            (format.bold, "strong"),
+        ] {
+            if on {
+                inner = format!("<{0}>{1}</{0}>", tag, inner);
+            }
+        }
+        if let Some(link) = &format.link {
+            inner = format!("<a href=\"{}\">{}</a>", escape_markup(link), inner);
+        }
+        html.push_str(&inner);
+    }
+    html
+}
+
+/// `<p>...</p>` alone in a table cell is written as its bare content
+fn unwrap_single_paragraph(html: &str) -> String {
+    if let Some(inner) = html.strip_prefix("<p>").and_then(|h| h.strip_suffix("</p>")) {
+        if !inner.contains("<p") && !inner.contains("</p>") {
+            return inner.to_string();
+        }
+    }
+    html.to_string()
+}
+
+/// The target of a HYPERLINK field instruction, e.g.
+/// `HYPERLINK "https://example.com"` or `HYPERLINK \l "_Toc123"`
+fn field_link(instruction: &str) -> Option<String> {
+    let mut tokens = Vec::new();
+    let mut chars = instruction.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let token: String = chars.by_ref().take_while(|&c| c != '"').collect();
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    if !tokens.first()?.eq_ignore_ascii_case("HYPERLINK") {
+        return None;
+    }
+
+    let mut url = None;
+    let mut anchor = None;
+    let mut rest = tokens[1..].iter();
+    while let Some(token) = rest.next() {
+        match token.as_str() {
+            "\\l" => anchor = rest.next().cloned(),
+            // Switches that take an argument: target frame, tooltip
+            "\\t" | "\\o" => {
+                rest.next();
+            }
+            switch if switch.starts_with('\\') => {}
+            _ if url.is_none() => url = Some(token.clone()),
+            _ => {}
+        }
+    }
+    match (url, anchor) {
+        (Some(url), Some(anchor)) => Some(format!("{}#{}", url, anchor)),
+        (Some(url), None) => Some(url),
+        (None, Some(anchor)) => Some(format!("#{}", anchor)),
+        (None, None) => None,
+    }
+}
+
+fn collect_math_text(node: &XmlNode, out: &mut String) {
+    for child in node.elements() {
+        if child.name == "t" {
+            out.push_str(&child.text());
+        } else {
+            collect_math_text(child, out);
+        }
+    }
+}
+
+/// Format a list counter the way a numbering level's `numFmt` asks
+fn format_number(value: usize, format: &str) -> String {
+    let kind = match format {
+        "lowerLetter" => "a",
+        "upperLetter" => "A",
+        "lowerRoman" => "i",
+        "upperRoman" => "I",
+        "decimalZero" => "01",
+        "bullet" | "none" => "",
+        _ => "1",
+    };
+    list_number(value as i64, kind)
+}
+
+fn highlight_color(name: &str) -> String {
+    match name {
+        "none" => String::new(),
+        "darkYellow" => "#808000".to_string(),
+        other => other.to_lowercase(),
+    }
+}
+
+fn image_mime(extension: &str) -> &'static str {
+    match extension {
+        "png" => "image/png",
+        "jpg" | "jpeg" | "jpe" => "image/jpeg",
+        "gif" => "image/gif",
+        "bmp" => "image/bmp",
+        "webp" => "image/webp",
+        "tif" | "tiff" => "image/tiff",
+        "svg" => "image/svg+xml",
+        "emf" => "image/x-emf",
+        "wmf" => "image/x-wmf",
+        _ => "application/octet-stream",
+    }
+}
+
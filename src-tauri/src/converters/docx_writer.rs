@@ -0,0 +1,761 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use calamine::{open_workbook_auto, Reader};
+use csv::ReaderBuilder;
+use docx_rs::{
+    AbstractNumbering, AlignmentType, BreakType, Docx, Hyperlink, HyperlinkType, IndentLevel, Level, LevelJc, LevelText,
+    LineSpacing, NumberFormat, Numbering, NumberingId, Paragraph, Pic, Run, RunFonts, Shading, SpecialIndentType, Start,
+    Style, StyleType, Table, TableCell, TableRow, VMergeType, VertAlignType, WidthType,
+};
+use crate::ConversionResult;
+use crate::converters::html_renderer::{
+    css_length, element_style, is_block, is_svg, parse_html, find_element, rasterize_svg, read_image_source, HtmlElement,
+    HtmlNode, TextAlign, TextStyle, HIDDEN_ELEMENTS,
+};
+use crate::converters::markup_converter::markdown_to_html;
+use crate::converters::pdf_tools::finish;
+use crate::converters::spreadsheet_converter::cell_to_string;
+use crate::converters::text_tools::collect_text;
+
+/// Numbering instance shared by every bulleted list. Ids 0 and 1 are
+/// taken by the default numbering docx-rs writes.
+const BULLET_NUMBERING: usize = 2;
+const FIRST_LIST_NUMBERING: usize = 3;
+/// Text width of docx-rs's default A4 section, in twentieths of a point
+const CONTENT_WIDTH_TWIPS: usize = 8500;
+const CONTENT_WIDTH_PT: f32 = 425.0;
+const EMU_PER_PT: f32 = 12700.0;
+const LIST_INDENT_TWIPS: i32 = 720;
+const HEADER_FILL: &str = "F2F2F2";
+const MONO_FONT: &str = "Courier New";
+
+/// Convert Markdown to a Word document, mapping headings to Word's
+/// heading styles and keeping emphasis, lists, tables, links and images
+pub fn convert_markdown_to_docx(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_markdown_to_docx(input_path, output_path), output_path)
+}
+
+fn try_convert_markdown_to_docx(input_path: &str, output_path: &str) -> Result<String, String> {
+    let markdown = fs::read_to_string(input_path).map_err(|e| format!("Failed to read Markdown file: {}", e))?;
+    let html = markdown_to_html(&markdown);
+    write_html_docx(&html, Path::new(input_path).parent().unwrap_or(Path::new("")), output_path)
+}
+
+/// Convert an HTML page to a Word document
+pub fn convert_html_to_docx(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_html_to_docx(input_path, output_path), output_path)
+}
+
+fn try_convert_html_to_docx(input_path: &str, output_path: &str) -> Result<String, String> {
+    let bytes = fs::read(input_path).map_err(|e| format!("Failed to read HTML file: {}", e))?;
+    let html = String::from_utf8_lossy(&bytes);
+    write_html_docx(&html, Path::new(input_path).parent().unwrap_or(Path::new("")), output_path)
+}
+
+/// Convert a text file to a Word document. Paragraphs are separated by
+/// blank lines; code keeps every line and uses a monospace font.
+pub fn convert_text_to_docx(input_path: &str, output_path: &str, code: bool) -> ConversionResult {
+    finish(try_convert_text_to_docx(input_path, output_path, code), output_path)
+}
+
+fn try_convert_text_to_docx(input_path: &str, output_path: &str, code: bool) -> Result<String, String> {
+    let text = fs::read_to_string(input_path).map_err(|e| format!("Failed to read text file: {}", e))?;
+    let text = text.replace("\r\n", "\n");
+    let mut docx = new_docx();
+    let mut paragraphs = 0;
+
+    if code {
+        let mono = TextStyle { mono: true, ..TextStyle::default() };
+        for line in text.lines() {
+            let line = line.replace('\t', "    ");
+            let mut paragraph = Paragraph::new().line_spacing(LineSpacing::new().before(0).after(0));
+            if !line.is_empty() {
+                paragraph = paragraph.add_run(styled_run(&line, &mono));
+            }
+            docx = docx.add_paragraph(paragraph);
+            paragraphs += 1;
+        }
+    } else {
+        let mut lines: Vec<&str> = Vec::new();
+        for line in text.lines().chain(std::iter::once("")) {
+            if !line.trim().is_empty() {
+                lines.push(line);
+                continue;
+            }
+            if lines.is_empty() {
+                continue;
+            }
+            // Line breaks inside a paragraph are kept as they are
+            let mut run = Run::new();
+            for (n, line) in lines.drain(..).enumerate() {
+                if n > 0 {
+                    run = run.add_break(BreakType::TextWrapping);
+                }
+                run = run.add_text(line);
+            }
+            docx = docx.add_paragraph(Paragraph::new().add_run(run).line_spacing(LineSpacing::new().after(160)));
+            paragraphs += 1;
+        }
+    }
+
+    save_docx(docx, output_path)?;
+    Ok(format!("Wrote {} paragraph(s)", paragraphs))
+}
+
+/// Convert a CSV or TSV file to a Word document holding one table, with
+/// the first row as its header
+pub fn convert_csv_to_docx(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_csv_to_docx(input_path, output_path), output_path)
+}
+
+fn try_convert_csv_to_docx(input_path: &str, output_path: &str) -> Result<String, String> {
+    let content = fs::read_to_string(input_path).map_err(|e| format!("Failed to read CSV file: {}", e))?;
+    let tsv = Path::new(input_path)
+        .extension()
+        .map(|e| e.eq_ignore_ascii_case("tsv"))
+        .unwrap_or(false);
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(if tsv { b'\t' } else { b',' })
+        .from_reader(content.as_bytes());
+    let rows: Vec<Vec<String>> = reader
+        .records()
+        .map(|record| record.map(|r| r.iter().map(str::to_string).collect()))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Failed to read CSV file: {}", e))?;
+    if rows.is_empty() {
+        return Err("The CSV file has no rows".to_string());
+    }
+
+    let docx = new_docx().add_table(data_table(&rows));
+    save_docx(docx, output_path)?;
+    Ok(format!("Wrote a table of {} row(s)", rows.len()))
+}
+
+/// Convert a spreadsheet (XLSX, XLS or ODS) to a Word document with one
+/// table per sheet, each under a heading with the sheet's name
+pub fn convert_spreadsheet_to_docx(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_spreadsheet_to_docx(input_path, output_path), output_path)
+}
+
+fn try_convert_spreadsheet_to_docx(input_path: &str, output_path: &str) -> Result<String, String> {
+    let mut workbook = open_workbook_auto(input_path).map_err(|e| format!("Failed to open spreadsheet: {}", e))?;
+    let mut docx = new_docx();
+    let mut tables = 0;
+    for name in workbook.sheet_names().to_vec() {
+        let range = workbook
+            .worksheet_range(&name)
+            .map_err(|e| format!("Failed to read sheet {}: {}", name, e))?;
+        if range.is_empty() {
+            continue;
+        }
+        let rows: Vec<Vec<String>> = range.rows().map(|row| row.iter().map(cell_to_string).collect()).collect();
+        if tables > 0 {
+            docx = docx.add_paragraph(Paragraph::new());
+        }
+        docx = docx
+            .add_paragraph(Paragraph::new().style("Heading1").add_run(Run::new().add_text(&name)))
+            .add_table(data_table(&rows));
+        tables += 1;
+    }
+    if tables == 0 {
+        return Err("The spreadsheet has no data".to_string());
+    }
+    save_docx(docx, output_path)?;
+    Ok(format!("Wrote {} table(s)", tables))
+}
+
+/// A document with heading styles and the bullet list numbering
+fn new_docx() -> Docx {
+    let mut docx = Docx::new().default_size(22);
+    for (level, size) in [32, 26, 24, 22, 22, 20].into_iter().enumerate() {
+        docx = docx.add_style(
+            Style::new(format!("Heading{}", level + 1), StyleType::Paragraph)
+                .name(format!("heading {}", level + 1))
+                .size(size)
+                .bold(),
+        );
+    }
+    let mut bullets = AbstractNumbering::new(BULLET_NUMBERING);
+    for level in 0..9 {
+        bullets = bullets.add_level(list_level(level, 1, "bullet", ["•", "◦", "▪"][level % 3]));
+    }
+    docx.add_abstract_numbering(bullets)
+        .add_numbering(Numbering::new(BULLET_NUMBERING, BULLET_NUMBERING))
+}
+
+fn list_level(level: usize, start: usize, format: &str, text: &str) -> Level {
+    Level::new(level, Start::new(start), NumberFormat::new(format), LevelText::new(text), LevelJc::new("left")).indent(
+        Some(LIST_INDENT_TWIPS * (level as i32 + 1)),
+        Some(SpecialIndentType::Hanging(360)),
+        None,
+        None,
+    )
+}
+
+fn save_docx(docx: Docx, output_path: &str) -> Result<(), String> {
+    let file = fs::File::create(output_path).map_err(|e| format!("Failed to create DOCX file: {}", e))?;
+    docx.build()
+        .pack(file)
+        .map_err(|e| format!("Failed to write DOCX file: {}", e))
+}
+
+/// A table for rows of plain values: the first row is the header and
+/// numeric cells are right aligned
+fn data_table(rows: &[Vec<String>]) -> Table {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(1).max(1);
+    let width = CONTENT_WIDTH_TWIPS / columns;
+    let header = TextStyle { bold: true, ..TextStyle::default() };
+    let normal = TextStyle::default();
+    let table_rows = rows
+        .iter()
+        .enumerate()
+        .map(|(r, row)| {
+            let cells = (0..columns)
+                .map(|c| {
+                    let value = row.get(c).map(String::as_str).unwrap_or("");
+                    let mut paragraph = Paragraph::new();
+                    if !value.is_empty() {
+                        paragraph = paragraph.add_run(styled_run(value, if r == 0 { &header } else { &normal }));
+                    }
+                    if r > 0 && value.trim().replace(',', "").parse::<f64>().is_ok() {
+                        paragraph = paragraph.align(AlignmentType::Right);
+                    }
+                    let cell = TableCell::new().add_paragraph(paragraph).width(width, WidthType::Dxa);
+                    if r == 0 {
+                        cell.shading(Shading::new().fill(HEADER_FILL))
+                    } else {
+                        cell
+                    }
+                })
+                .collect();
+            TableRow::new(cells)
+        })
+        .collect();
+    Table::new(table_rows).set_grid(vec![width; columns])
+}
+
+fn write_html_docx(html: &str, base_dir: &Path, output_path: &str) -> Result<String, String> {
+    let root = parse_html(html);
+    let body = find_element(&root, "body").unwrap_or(&root);
+    let mut writer = DocxWriter {
+        base_dir,
+        numberings: Vec::new(),
+        next_numbering: FIRST_LIST_NUMBERING,
+        pending_bookmarks: Vec::new(),
+        next_bookmark: 0,
+        images: 0,
+    };
+    let mut blocks = Vec::new();
+    writer.block_children(body, &TextStyle::default(), &mut BlockContext::default(), &mut blocks);
+
+    let mut docx = new_docx();
+    for (abstract_numbering, numbering) in writer.numberings {
+        docx = docx.add_abstract_numbering(abstract_numbering).add_numbering(numbering);
+    }
+    let mut paragraphs = 0;
+    let mut tables = 0;
+    for block in blocks {
+        match block {
+            DocxBlock::Paragraph(paragraph) => {
+                docx = docx.add_paragraph(*paragraph);
+                paragraphs += 1;
+            }
+            DocxBlock::Table(table) => {
+                docx = docx.add_table(*table);
+                tables += 1;
+            }
+        }
+    }
+    save_docx(docx, output_path)?;
+    Ok(format!(
+        "Wrote {} paragraph(s), {} table(s) and {} image(s)",
+        paragraphs, tables, writer.images
+    ))
+}
+
+enum DocxBlock {
+    Paragraph(Box<Paragraph>),
+    Table(Box<Table>),
+}
+
+/// Where the paragraphs of a block end up
+#[derive(Debug, Clone, Default)]
+struct BlockContext {
+    /// Left indent in twips, for quotes and list continuations
+    indent: i32,
+    /// Numbering for the next paragraph, which starts a list item
+    list: Option<(usize, usize)>,
+    list_depth: usize,
+    heading: Option<u8>,
+}
+
+/// The paragraph being filled with inline content
+struct ParagraphBuilder {
+    paragraph: Paragraph,
+    empty: bool,
+    /// Whether the content so far ends in whitespace, for collapsing spaces
+    trailing_space: bool,
+}
+
+impl ParagraphBuilder {
+    fn new() -> Self {
+        ParagraphBuilder { paragraph: Paragraph::new(), empty: true, trailing_space: true }
+    }
+
+    fn push(&mut self, run: Run, link: Option<&str>) {
+        let paragraph = std::mem::replace(&mut self.paragraph, Paragraph::new());
+        self.paragraph = match link {
+            Some(target) => {
+                let hyperlink = match target.strip_prefix('#') {
+                    Some(anchor) => Hyperlink::new(bookmark_name(anchor), HyperlinkType::Anchor),
+                    None => Hyperlink::new(target, HyperlinkType::External),
+                };
+                paragraph.add_hyperlink(hyperlink.add_run(run))
+            }
+            None => paragraph.add_run(run),
+        };
+        self.empty = false;
+    }
+}
+
+struct DocxWriter<'a> {
+    base_dir: &'a Path,
+    numberings: Vec<(AbstractNumbering, Numbering)>,
+    next_numbering: usize,
+    /// Element ids waiting for the next paragraph, where they become bookmarks
+    pending_bookmarks: Vec<String>,
+    next_bookmark: usize,
+    images: usize,
+}
+
+impl DocxWriter<'_> {
+    fn block_children(&mut self, element: &HtmlElement, style: &TextStyle, ctx: &mut BlockContext, out: &mut Vec<DocxBlock>) {
+        let mut current = ParagraphBuilder::new();
+        for child in &element.children {
+            match child {
+                HtmlNode::Text(text) => self.text(&mut current, text, style),
+                HtmlNode::Element(e) if is_block(e) => {
+                    self.flush(&mut current, style, ctx, out);
+                    self.block(e, style, ctx, out);
+                }
+                HtmlNode::Element(e) => self.inline(e, style, &mut current),
+            }
+        }
+        self.flush(&mut current, style, ctx, out);
+    }
+
+    fn block(&mut self, element: &HtmlElement, parent: &TextStyle, ctx: &BlockContext, out: &mut Vec<DocxBlock>) {
+        let tag = element.tag.as_str();
+        if HIDDEN_ELEMENTS.contains(&tag) {
+            return;
+        }
+        let style = element_style(element, parent);
+        let page_break = element.style("page-break-before").as_deref() == Some("always")
+            || element.style("break-before").as_deref() == Some("page");
+        if page_break {
+            out.push(DocxBlock::Paragraph(Box::new(Paragraph::new().add_run(Run::new().add_break(BreakType::Page)))));
+        }
+        if let Some(id) = element.attr("id") {
+            self.pending_bookmarks.push(id.to_string());
+        }
+        let mut inner = BlockContext { list: None, ..ctx.clone() };
+
+        match tag {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                // Size and weight come from the heading style, not the runs
+                let style = TextStyle { size: parent.size, bold: parent.bold, ..style };
+                inner.heading = Some(tag.as_bytes()[1] - b'0');
+                self.block_children(element, &style, &mut inner, out);
+            }
+            "ul" | "ol" => self.list(element, &style, ctx, out),
+            "li" => {
+                let level = ctx.list_depth.min(8);
+                self.list_item(element, &style, BULLET_NUMBERING, level, out);
+            }
+            "blockquote" | "dd" => {
+                inner.indent += LIST_INDENT_TWIPS;
+                self.block_children(element, &style, &mut inner, out);
+            }
+            "pre" => self.preformatted(element, &style, &mut inner, out),
+            "hr" => {
+                let mut current = ParagraphBuilder::new();
+                current.push(Run::new().add_text("* * *"), None);
+                let centered = TextStyle { align: TextAlign::Center, ..style };
+                self.flush(&mut current, &centered, &mut inner, out);
+            }
+            "table" => self.table(element, &style, &mut inner, out),
+            _ => self.block_children(element, &style, &mut inner, out),
+        }
+    }
+
+    fn inline(&mut self, element: &HtmlElement, parent: &TextStyle, current: &mut ParagraphBuilder) {
+        let tag = element.tag.as_str();
+        if HIDDEN_ELEMENTS.contains(&tag) {
+            return;
+        }
+        let id = element.attr("id").or_else(|| if tag == "a" { element.attr("name") } else { None });
+        if let Some(id) = id {
+            self.pending_bookmarks.push(id.to_string());
+        }
+        match tag {
+            "br" => {
+                current.push(Run::new().add_break(BreakType::TextWrapping), None);
+                current.trailing_space = true;
+            }
+            "img" => self.image(element, parent, current),
+            "input" => {
+                if element.attr("type").map(|t| t.eq_ignore_ascii_case("checkbox")).unwrap_or(false) {
+                    let symbol = if element.attr("checked").is_some() { "☑ " } else { "☐ " };
+                    current.push(styled_run(symbol, parent), None);
+                    current.trailing_space = true;
+                }
+            }
+            _ => {
+                let style = element_style(element, parent);
+                for child in &element.children {
+                    match child {
+                        HtmlNode::Text(text) => self.text(current, text, &style),
+                        HtmlNode::Element(e) => self.inline(e, &style, current),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add text with HTML whitespace collapsing, unless it's preformatted
+    fn text(&mut self, current: &mut ParagraphBuilder, text: &str, style: &TextStyle) {
+        if style.pre {
+            let text = text.replace('\r', "").replace('\t', "    ");
+            for (n, line) in text.split('\n').enumerate() {
+                if n > 0 {
+                    current.push(Run::new().add_break(BreakType::TextWrapping), None);
+                }
+                if !line.is_empty() {
+                    current.push(styled_run(line, style), style.link.as_deref());
+                }
+            }
+            return;
+        }
+        let mut collapsed = String::new();
+        for c in text.chars() {
+            if matches!(c, ' ' | '\n' | '\t' | '\r' | '\x0c') {
+                if !current.trailing_space {
+                    collapsed.push(' ');
+                    current.trailing_space = true;
+                }
+            } else if !matches!(c, '\u{ad}' | '\u{200b}' | '\u{feff}') {
+                collapsed.push(c);
+                current.trailing_space = false;
+            }
+        }
+        if !collapsed.is_empty() {
+            current.push(styled_run(&collapsed, style), style.link.as_deref());
+        }
+    }
+
+    fn flush(&mut self, current: &mut ParagraphBuilder, style: &TextStyle, ctx: &mut BlockContext, out: &mut Vec<DocxBlock>) {
+        if current.empty {
+            return;
+        }
+        let mut paragraph = std::mem::replace(current, ParagraphBuilder::new()).paragraph;
+        for name in self.pending_bookmarks.drain(..) {
+            paragraph = paragraph
+                .add_bookmark_start(self.next_bookmark, bookmark_name(&name))
+                .add_bookmark_end(self.next_bookmark);
+            self.next_bookmark += 1;
+        }
+        match style.align {
+            TextAlign::Center => paragraph = paragraph.align(AlignmentType::Center),
+            TextAlign::Right => paragraph = paragraph.align(AlignmentType::Right),
+            TextAlign::Left => {}
+        }
+        let spacing = if let Some(level) = ctx.heading {
+            paragraph = paragraph.style(&format!("Heading{}", level)).keep_next(true);
+            LineSpacing::new().before(240).after(80)
+        } else if ctx.list_depth > 0 {
+            LineSpacing::new().after(40)
+        } else {
+            LineSpacing::new().after(160)
+        };
+        paragraph = paragraph.line_spacing(spacing);
+        // Only the first paragraph of a list item carries its number
+        if let Some((numbering, level)) = ctx.list.take() {
+            paragraph = paragraph.numbering(NumberingId::new(numbering), IndentLevel::new(level));
+        } else if ctx.indent > 0 {
+            paragraph = paragraph.indent(Some(ctx.indent), None, None, None);
+        }
+        out.push(DocxBlock::Paragraph(Box::new(paragraph)));
+    }
+
+    fn image(&mut self, element: &HtmlElement, style: &TextStyle, current: &mut ParagraphBuilder) {
+        let source = element.attr("src").unwrap_or("").trim();
+        let picture = read_image_source(source, self.base_dir).and_then(|(bytes, svg)| picture_data(&bytes, svg));
+        let Some((bytes, (natural_width, natural_height))) = picture else {
+            // Images that can't be loaded show their description instead
+            let alt = element.attr("alt").unwrap_or("").trim();
+            if !alt.is_empty() {
+                let style = TextStyle { italic: true, ..style.clone() };
+                current.push(styled_run(&format!("[{}]", alt), &style), None);
+                current.trailing_space = false;
+            }
+            return;
+        };
+        let width = element.attr("width").and_then(css_length).or_else(|| element.style("width").as_deref().and_then(css_length));
+        let height = element.attr("height").and_then(css_length).or_else(|| element.style("height").as_deref().and_then(css_length));
+        let (width, height) = match (width, height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => (w, natural_height * w / natural_width),
+            (None, Some(h)) => (natural_width * h / natural_height, h),
+            (None, None) => (natural_width, natural_height),
+        };
+        let scale = (CONTENT_WIDTH_PT / width).min(1.0);
+        let picture = Pic::new(&bytes).size((width * scale * EMU_PER_PT) as u32, (height * scale * EMU_PER_PT) as u32);
+        current.push(Run::new().add_image(picture), style.link.as_deref());
+        current.trailing_space = false;
+        self.images += 1;
+    }
+
+    fn list(&mut self, list: &HtmlElement, style: &TextStyle, ctx: &BlockContext, out: &mut Vec<DocxBlock>) {
+        let level = ctx.list_depth.min(8);
+        let numbering = if list.tag == "ol" { self.ordered_numbering(list, level) } else { BULLET_NUMBERING };
+        for item in list.elements() {
+            if item.tag == "li" {
+                self.list_item(item, style, numbering, level, out);
+            } else {
+                let inner = BlockContext { indent: LIST_INDENT_TWIPS * (level as i32 + 1), list: None, ..ctx.clone() };
+                self.block(item, style, &inner, out);
+            }
+        }
+    }
+
+    fn list_item(
+        &mut self,
+        item: &HtmlElement,
+        parent: &TextStyle,
+        numbering: usize,
+        level: usize,
+        out: &mut Vec<DocxBlock>,
+    ) {
+        let style = element_style(item, parent);
+        if let Some(id) = item.attr("id") {
+            self.pending_bookmarks.push(id.to_string());
+        }
+        // Task list items show their checkbox instead of a bullet
+        let task = item.elements().next().map(|e| e.tag == "input").unwrap_or(false);
+        let mut inner = BlockContext {
+            indent: LIST_INDENT_TWIPS * (level as i32 + 1),
+            list: if task { None } else { Some((numbering, level)) },
+            list_depth: level + 1,
+            heading: None,
+        };
+        self.block_children(item, &style, &mut inner, out);
+    }
+
+    /// A numbering of its own for each ordered list, so every list starts
+    /// from its own start value
+    fn ordered_numbering(&mut self, list: &HtmlElement, level: usize) -> usize {
+        let id = self.next_numbering;
+        self.next_numbering += 1;
+        let start = list.attr("start").and_then(|s| s.trim().parse::<usize>().ok()).unwrap_or(1);
+        let format = match list.attr("type").unwrap_or("1") {
+            "a" => "lowerLetter",
+            "A" => "upperLetter",
+            "i" => "lowerRoman",
+            "I" => "upperRoman",
+            _ => "decimal",
+        };
+        let mut abstract_numbering = AbstractNumbering::new(id);
+        for l in 0..9 {
+            let (format, start) = if l == level { (format, start) } else { (["decimal", "lowerLetter", "lowerRoman"][l % 3], 1) };
+            abstract_numbering = abstract_numbering.add_level(list_level(l, start, format, &format!("%{}.", l + 1)));
+        }
+        self.numberings.push((abstract_numbering, Numbering::new(id, id)));
+        id
+    }
+
+    fn preformatted(&mut self, element: &HtmlElement, style: &TextStyle, ctx: &mut BlockContext, out: &mut Vec<DocxBlock>) {
+        let mut current = ParagraphBuilder::new();
+        let mut text = String::new();
+        collect_text(element, &mut text);
+        // A newline right after <pre> or before </pre> isn't content
+        let text = text.strip_prefix("\r\n").or_else(|| text.strip_prefix('\n')).unwrap_or(&text);
+        let text = text.trim_end_matches(['\n', '\r']);
+        self.text(&mut current, text, style);
+        self.flush(&mut current, style, ctx, out);
+    }
+
+    fn table(&mut self, table: &HtmlElement, style: &TextStyle, ctx: &mut BlockContext, out: &mut Vec<DocxBlock>) {
+        let mut rows: Vec<&HtmlElement> = Vec::new();
+        for child in table.elements() {
+            match child.tag.as_str() {
+                "caption" => self.block_children(child, &element_style(child, style), ctx, out),
+                "thead" | "tbody" | "tfoot" => rows.extend(child.elements().filter(|r| r.tag == "tr")),
+                "tr" => rows.push(child),
+                _ => {}
+            }
+        }
+
+        // Place the cells on a grid, honouring column and row spans
+        struct Cell<'a> {
+            element: &'a HtmlElement,
+            row: usize,
+            column: usize,
+            columns: usize,
+            rows: usize,
+        }
+        let mut cells: Vec<Cell> = Vec::new();
+        let mut occupied: Vec<Vec<bool>> = vec![Vec::new(); rows.len()];
+        for (r, row) in rows.iter().enumerate() {
+            let mut column = 0;
+            for cell in row.elements().filter(|c| c.tag == "td" || c.tag == "th") {
+                while occupied[r].get(column).copied().unwrap_or(false) {
+                    column += 1;
+                }
+                let span = |name: &str| cell.attr(name).and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(1).max(1);
+                let columns = span("colspan").min(63);
+                let row_span = span("rowspan").min(rows.len() - r);
+                for occupied_row in occupied.iter_mut().skip(r).take(row_span) {
+                    if occupied_row.len() < column + columns {
+                        occupied_row.resize(column + columns, false);
+                    }
+                    occupied_row[column..column + columns].iter_mut().for_each(|o| *o = true);
+                }
+                cells.push(Cell { element: cell, row: r, column, columns, rows: row_span });
+                column += columns;
+            }
+        }
+        let column_count = occupied.iter().map(Vec::len).max().unwrap_or(0);
+        if column_count == 0 {
+            return;
+        }
+        let width = CONTENT_WIDTH_TWIPS / column_count;
+
+        let mut table_rows = Vec::with_capacity(rows.len());
+        for r in 0..rows.len() {
+            let mut row_cells = Vec::new();
+            let mut column = 0;
+            while column < column_count {
+                if let Some(cell) = cells.iter().find(|c| c.row == r && c.column == column) {
+                    let cell_style = element_style(cell.element, style);
+                    let mut blocks = Vec::new();
+                    self.block_children(cell.element, &cell_style, &mut BlockContext::default(), &mut blocks);
+                    let mut table_cell = TableCell::new().width(width * cell.columns, WidthType::Dxa);
+                    let ends_with_paragraph = matches!(blocks.last(), Some(DocxBlock::Paragraph(_)));
+                    for block in blocks {
+                        table_cell = match block {
+                            DocxBlock::Paragraph(paragraph) => table_cell.add_paragraph(*paragraph),
+                            DocxBlock::Table(table) => table_cell.add_table(*table),
+                        };
+                    }
+                    // Word requires every cell to end with a paragraph
+                    if !ends_with_paragraph {
+                        table_cell = table_cell.add_paragraph(Paragraph::new());
+                    }
+                    if cell.columns > 1 {
+                        table_cell = table_cell.grid_span(cell.columns);
+                    }
+                    if cell.rows > 1 {
+                        table_cell = table_cell.vertical_merge(VMergeType::Restart);
+                    }
+                    if cell.element.tag == "th" {
+                        table_cell = table_cell.shading(Shading::new().fill(HEADER_FILL));
+                    }
+                    row_cells.push(table_cell);
+                    column += cell.columns;
+                } else if let Some(cell) = cells.iter().find(|c| c.row < r && r < c.row + c.rows && c.column == column) {
+                    let mut table_cell = TableCell::new()
+                        .add_paragraph(Paragraph::new())
+                        .width(width * cell.columns, WidthType::Dxa)
+                        .vertical_merge(VMergeType::Continue);
+                    if cell.columns > 1 {
+                        table_cell = table_cell.grid_span(cell.columns);
+                    }
+                    row_cells.push(table_cell);
+                    column += cell.columns;
+                } else {
+                    row_cells.push(TableCell::new().add_paragraph(Paragraph::new()).width(width, WidthType::Dxa));
+                    column += 1;
+                }
+            }
+            table_rows.push(TableRow::new(row_cells));
+        }
+        out.push(DocxBlock::Table(Box::new(Table::new(table_rows).set_grid(vec![width; column_count]))));
+        // Keep the next table from merging into this one
+        out.push(DocxBlock::Paragraph(Box::new(Paragraph::new())));
+    }
+}
+
+/// A run with the character formatting of a text style; sizes and
+/// colors are only set where they differ from the document default
+fn styled_run(text: &str, style: &TextStyle) -> Run {
+    let default = TextStyle::default();
+    let mut run = Run::new().add_text(text);
+    if style.bold {
+        run = run.bold();
+    }
+    if style.italic {
+        run = run.italic();
+    }
+    if style.underline {
+        run = run.underline("single");
+    }
+    if style.strike {
+        run = run.strike();
+    }
+    if style.mono {
+        run = run.fonts(RunFonts::new().ascii(MONO_FONT).hi_ansi(MONO_FONT).cs(MONO_FONT));
+    }
+    if style.color != default.color {
+        run = run.color(hex_color(style.color));
+    }
+    if style.rise > 0.0 {
+        run.run_property = run.run_property.vert_align(VertAlignType::SuperScript);
+    } else if style.rise < 0.0 {
+        run.run_property = run.run_property.vert_align(VertAlignType::SubScript);
+    } else if (style.size - default.size).abs() > 0.1 {
+        run = run.size((style.size * 2.0).round() as usize);
+    }
+    // Code spans get a grey background in HTML; marked text is highlighted
+    if let Some([r, g, b]) = style.background {
+        if r > g + 0.1 || b < g - 0.2 {
+            run = run.highlight("yellow");
+        }
+    }
+    run
+}
+
+pub fn hex_color(color: [f32; 3]) -> String {
+    color
+        .iter()
+        .map(|c| format!("{:02X}", (c.clamp(0.0, 1.0) * 255.0).round() as u8))
+        .collect()
+}
+
+/// Word bookmark names start with a letter and hold only letters, digits
+/// and underscores
+fn bookmark_name(id: &str) -> String {
+    let name: String = id.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect();
+    if name.starts_with(|c: char| c.is_alphabetic()) {
+        name.chars().take(40).collect()
+    } else {
+        format!("b_{}", name).chars().take(40).collect()
+    }
+}
+
+/// Image bytes Word can embed, with the natural size in points; SVG is
+/// rasterized to PNG
+pub fn picture_data(bytes: &[u8], svg: bool) -> Option<(Vec<u8>, (f32, f32))> {
+    if is_svg(bytes, svg) {
+        let (rgba, size) = rasterize_svg(bytes)?;
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgba8(rgba)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .ok()?;
+        return Some((png, size));
+    }
+    let decoded = image::load_from_memory(bytes).ok()?;
+    Some((bytes.to_vec(), (decoded.width() as f32 * 0.75, decoded.height() as f32 * 0.75)))
+}
@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::Path;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde_json::json;
+use crate::converters::image_converter::pixmap_to_rgba;
+use crate::converters::pdf_renderer::{decode_pdf_image, image_stream_data};
+use crate::converters::pdf_security::load_pdf_with_password;
+use crate::converters::pdf_tools::finish;
+use crate::converters::text_tools::{safe_file_name, unique_file_name};
+use crate::ConversionResult;
+
+/// Write every image embedded in a PDF to `output_dir`, with a manifest.json
+/// listing the pages each image appears on. JPEG and JPEG 2000 images keep
+/// their original bytes; everything else is decoded and saved as PNG.
+pub fn extract_pdf_images(input_path: &str, output_dir: &str) -> ConversionResult {
+    finish(try_extract_pdf_images(input_path, output_dir), output_dir)
+}
+
+fn try_extract_pdf_images(input_path: &str, output_dir: &str) -> Result<String, String> {
+    let doc = load_pdf_with_password(input_path, None)?;
+    let pages = doc.get_pages();
+
+    // Images in page order, each with every page that references it
+    let mut found: Vec<(ObjectId, Dictionary, Vec<u32>)> = Vec::new();
+    for (&page_number, &page_id) in &pages {
+        let resources = page_resources(&doc, page_id);
+        let mut on_page = Vec::new();
+        collect_xobject_images(&doc, &resources, &mut on_page, 0);
+        for (id, resources) in on_page {
+            match found.iter_mut().find(|(seen, _, _)| *seen == id) {
+                Some((_, _, pages)) => {
+                    if !pages.contains(&page_number) {
+                        pages.push(page_number);
+                    }
+                }
+                None => found.push((id, resources, vec![page_number])),
+            }
+        }
+    }
+
+    fs::create_dir_all(output_dir).map_err(|e| format!("Failed to create output directory: {}", e))?;
+    let width = pages.len().to_string().len();
+
+    let mut images = Vec::new();
+    let mut skipped = Vec::new();
+    let mut per_page: HashMap<u32, usize> = HashMap::new();
+    for (id, resources, image_pages) in found {
+        let stream = match doc.get_object(id).and_then(Object::as_stream) {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        let object = format!("{} {} R", id.0, id.1);
+        let pixel_width = stream.dict.get(b"Width").and_then(Object::as_i64).unwrap_or(0);
+        let pixel_height = stream.dict.get(b"Height").and_then(Object::as_i64).unwrap_or(0);
+
+        let (bytes, format) = match image_stream_data(stream) {
+            Some((data, Some(filter))) if filter == "DCTDecode" => (data, "jpg"),
+            Some((data, Some(filter))) if filter == "JPXDecode" => (data, "jp2"),
+            Some(_) => match decode_pdf_image(&doc, stream, &resources) {
+                Some(pixmap) => {
+                    let mut png = Vec::new();
+                    pixmap_to_rgba(&pixmap)
+                        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+                        .map_err(|e| format!("Failed to encode image {}: {}", object, e))?;
+                    (png, "png")
+                }
+                None => {
+                    let filter = stream.filters().ok().and_then(|f| f.last().cloned()).unwrap_or_default();
+                    skipped.push(json!({
+                        "object": object,
+                        "pages": image_pages,
+                        "reason": format!("Unsupported image encoding {}", filter),
+                    }));
+                    continue;
+                }
+            },
+            None => {
+                skipped.push(json!({ "object": object, "pages": image_pages, "reason": "Corrupt image data" }));
+                continue;
+            }
+        };
+
+        let first_page = image_pages[0];
+        let index = per_page.entry(first_page).or_insert(0);
+        *index += 1;
+        let name = format!("page{:0width$}-{}.{}", first_page, index, format, width = width);
+        let file_path = Path::new(output_dir).join(&name).to_string_lossy().to_string();
+        fs::write(&file_path, &bytes).map_err(|e| format!("Failed to write {}: {}", name, e))?;
+
+        images.push(json!({
+            "file": file_path,
+            "format": format,
+            "width": pixel_width,
+            "height": pixel_height,
+            "bytes": bytes.len(),
+            "object": object,
+            "pages": image_pages,
+        }));
+    }
+
+    let manifest = json!({
+        "source": input_path,
+        "page_count": pages.len(),
+        "image_count": images.len(),
+        "images": images,
+        "skipped": skipped,
+    });
+    write_manifest(output_dir, &manifest)
+}
+
+/// The Resources a page uses, which may be inherited from the page tree
+fn page_resources(doc: &Document, page_id: ObjectId) -> Dictionary {
+    let mut node = doc.get_dictionary(page_id).ok();
+    let mut depth = 0;
+    while let Some(dict) = node {
+        if let Ok(resources) = dict.get_deref(b"Resources", doc).and_then(Object::as_dict) {
+            return resources.clone();
+        }
+        if depth > 32 {
+            break;
+        }
+        depth += 1;
+        node = dict.get_deref(b"Parent", doc).and_then(Object::as_dict).ok();
+    }
+    Dictionary::new()
+}
+
+/// Image XObjects reachable from a resource dictionary, descending into forms.
+/// Each image is paired with the resources it should be decoded against.
+fn collect_xobject_images(doc: &Document, resources: &Dictionary, found: &mut Vec<(ObjectId, Dictionary)>, depth: usize) {
+    let xobjects = match resources.get_deref(b"XObject", doc).and_then(Object::as_dict) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    for (_, reference) in xobjects.iter() {
+        let id = match reference.as_reference() {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+        if found.iter().any(|(seen, _)| *seen == id) {
+            continue;
+        }
+        let stream = match doc.get_object(id).and_then(Object::as_stream) {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        match stream.dict.get(b"Subtype").and_then(Object::as_name).unwrap_or(b"") {
+            b"Image" => found.push((id, resources.clone())),
+            b"Form" if depth < 8 => {
+                let form_resources = stream
+                    .dict
+                    .get_deref(b"Resources", doc)
+                    .and_then(Object::as_dict)
+                    .unwrap_or(resources);
+                collect_xobject_images(doc, form_resources, found, depth + 1);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Copy the pictures out of a DOCX's word/media folder, with a manifest.json
+/// giving the document part and paragraph each one is placed in
+pub fn extract_docx_images(input_path: &str, output_dir: &str) -> ConversionResult {
+    finish(try_extract_docx_images(input_path, output_dir), output_dir)
+}
+
+fn try_extract_docx_images(input_path: &str, output_dir: &str) -> Result<String, String> {
+    let mut archive = open_package(input_path, "DOCX")?;
+    let names: Vec<String> = archive.file_names().map(|n| n.to_string()).collect();
+
+    // Where each media file is used, keyed by its path inside the package
+    let mut locations: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+    let mut parts: Vec<&String> = names
+        .iter()
+        .filter(|n| {
+            n.starts_with("word/")
+                && !n[5..].contains('/')
+                && n.ends_with(".xml")
+                && ["document", "header", "footer", "footnotes", "endnotes"].iter().any(|p| n[5..].starts_with(p))
+        })
+        .collect();
+    parts.sort_by_key(|n| (!n.ends_with("document.xml"), n.to_string()));
+
+    for part in parts {
+        let rels_name = format!("word/_rels/{}.rels", &part[5..]);
+        let targets = match read_entry(&mut archive, &rels_name) {
+            Some(rels) => image_relationships(&rels, "word"),
+            None => continue,
+        };
+        let xml = match read_entry(&mut archive, part) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let mut reader = Reader::from_reader(xml.as_slice());
+        let mut buf = Vec::new();
+        let mut paragraph = 0usize;
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Ok(Event::Start(e)) | Ok(Event::Empty(e)) => {
+                    if e.name().as_ref() == b"w:p" {
+                        paragraph += 1;
+                    }
+                    for attr in e.attributes().flatten() {
+                        let key = attr.key;
+                        if key.prefix().is_none() || !matches!(key.local_name().as_ref(), b"embed" | b"id") {
+                            continue;
+                        }
+                        let rel_id = String::from_utf8_lossy(&attr.value).to_string();
+                        if let Some(target) = targets.get(&rel_id) {
+                            let place = json!({ "part": part, "paragraph": paragraph });
+                            let entry = locations.entry(target.clone()).or_default();
+                            if !entry.contains(&place) {
+                                entry.push(place);
+                            }
+                        }
+                    }
+                }
+                Ok(Event::Eof) | Err(_) => break,
+                _ => {}
+            }
+            buf.clear();
+        }
+    }
+
+    fs::create_dir_all(output_dir).map_err(|e| format!("Failed to create output directory: {}", e))?;
+    let mut media: Vec<&String> = names.iter().filter(|n| n.starts_with("word/media/") && !n.ends_with('/')).collect();
+    media.sort();
+
+    let mut used_names = Vec::new();
+    let mut images = Vec::new();
+    for source in media {
+        let bytes = match read_entry(&mut archive, source) {
+            Some(b) => b,
+            None => continue,
+        };
+        let mut entry = write_package_image(output_dir, source, &bytes, &mut used_names)?;
+        entry["locations"] = json!(locations.remove(source.as_str()).unwrap_or_default());
+        images.push(entry);
+    }
+
+    let manifest = json!({
+        "source": input_path,
+        "image_count": images.len(),
+        "images": images,
+    });
+    write_manifest(output_dir, &manifest)
+}
+
+/// Copy the image items of an EPUB's manifest, with a manifest.json giving
+/// the spine chapters that show each one
+pub fn extract_epub_images(input_path: &str, output_dir: &str) -> ConversionResult {
+    finish(try_extract_epub_images(input_path, output_dir), output_dir)
+}
+
+fn try_extract_epub_images(input_path: &str, output_dir: &str) -> Result<String, String> {
+    let mut archive = open_package(input_path, "EPUB")?;
+
+    let container = read_entry(&mut archive, "META-INF/container.xml")
+        .ok_or_else(|| "Invalid EPUB: META-INF/container.xml is missing".to_string())?;
+    let opf_path = first_attribute(&container, b"rootfile", b"full-path")
+        .ok_or_else(|| "Invalid EPUB: no package document in container.xml".to_string())?;
+    let opf = read_entry(&mut archive, &opf_path)
+        .ok_or_else(|| format!("Invalid EPUB: {} is missing", opf_path))?;
+    let opf_dir = parent_dir(&opf_path);
+
+    // Manifest items as (id, path, media type, properties), plus the spine order
+    let mut items: Vec<(String, String, String, String)> = Vec::new();
+    let mut spine: Vec<String> = Vec::new();
+    let mut cover_id = None;
+    let mut reader = Reader::from_reader(opf.as_slice());
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => {
+                let attrs: HashMap<Vec<u8>, String> = e
+                    .attributes()
+                    .flatten()
+                    .map(|a| (a.key.local_name().as_ref().to_vec(), attribute_value(&a)))
+                    .collect();
+                let get = |key: &[u8]| attrs.get(key).cloned().unwrap_or_default();
+                match e.local_name().as_ref() {
+                    b"item" => items.push((
+                        get(b"id"),
+                        resolve_href(&opf_dir, &get(b"href")),
+                        get(b"media-type"),
+                        get(b"properties"),
+                    )),
+                    b"itemref" => spine.push(get(b"idref")),
+                    b"meta" if get(b"name") == "cover" => cover_id = Some(get(b"content")),
+                    _ => {}
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(format!("Invalid EPUB package document: {}", e)),
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    // Which chapters reference which image paths
+    let mut chapters: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+    for (index, idref) in spine.iter().enumerate() {
+        let chapter_path = match items.iter().find(|(id, ..)| id == idref) {
+            Some((_, path, _, _)) => path.clone(),
+            None => continue,
+        };
+        let xhtml = match read_entry(&mut archive, &chapter_path) {
+            Some(x) => x,
+            None => continue,
+        };
+        let chapter_dir = parent_dir(&chapter_path);
+        for src in image_references(&xhtml) {
+            let place = json!({ "chapter": index + 1, "href": chapter_path });
+            let entry = chapters.entry(resolve_href(&chapter_dir, &src)).or_default();
+            if !entry.contains(&place) {
+                entry.push(place);
+            }
+        }
+    }
+
+    fs::create_dir_all(output_dir).map_err(|e| format!("Failed to create output directory: {}", e))?;
+    let mut used_names = Vec::new();
+    let mut images = Vec::new();
+    for (id, path, media_type, properties) in &items {
+        if !media_type.starts_with("image/") {
+            continue;
+        }
+        let bytes = match read_entry(&mut archive, path) {
+            Some(b) => b,
+            None => continue,
+        };
+        let mut entry = write_package_image(output_dir, path, &bytes, &mut used_names)?;
+        entry["media_type"] = json!(media_type);
+        entry["cover"] = json!(properties.split_whitespace().any(|p| p == "cover-image") || cover_id.as_ref() == Some(id));
+        entry["chapters"] = json!(chapters.remove(path.as_str()).unwrap_or_default());
+        images.push(entry);
+    }
+
+    let manifest = json!({
+        "source": input_path,
+        "chapter_count": spine.len(),
+        "image_count": images.len(),
+        "images": images,
+    });
+    write_manifest(output_dir, &manifest)
+}
+
+pub fn open_package(input_path: &str, kind: &str) -> Result<zip::ZipArchive<File>, String> {
+    let file = File::open(input_path).map_err(|e| format!("Failed to open {} file: {}", kind, e))?;
+    zip::ZipArchive::new(file).map_err(|e| format!("Failed to read {} file: {}", kind, e))
+}
+
+pub fn read_entry(archive: &mut zip::ZipArchive<File>, name: &str) -> Option<Vec<u8>> {
+    let mut entry = archive.by_name(name).ok()?;
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// Write one image under its own file name, numbering repeats, and describe it
+fn write_package_image(
+    output_dir: &str,
+    source: &str,
+    bytes: &[u8],
+    used_names: &mut Vec<String>,
+) -> Result<serde_json::Value, String> {
+    let name = unique_file_name(safe_file_name(source).unwrap_or("image"), used_names);
+    let extension = name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).unwrap_or_default();
+
+    let file_path = Path::new(output_dir).join(&name).to_string_lossy().to_string();
+    fs::write(&file_path, bytes).map_err(|e| format!("Failed to write {}: {}", name, e))?;
+
+    let dimensions = image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|r| r.into_dimensions().ok());
+    Ok(json!({
+        "file": file_path,
+        "format": extension,
+        "width": dimensions.map(|d| d.0),
+        "height": dimensions.map(|d| d.1),
+        "bytes": bytes.len(),
+        "source": source,
+    }))
+}
+
+fn write_manifest(output_dir: &str, manifest: &serde_json::Value) -> Result<String, String> {
+    let text = serde_json::to_string_pretty(manifest).unwrap();
+    let manifest_path = Path::new(output_dir).join("manifest.json");
+    fs::write(&manifest_path, &text).map_err(|e| format!("Failed to write manifest: {}", e))?;
+    Ok(text)
+}
+
+/// Image relationship ids of a .rels part, mapped to package paths
+fn image_relationships(rels: &[u8], base_dir: &str) -> HashMap<String, String> {
+    let mut targets = HashMap::new();
+    let mut reader = Reader::from_reader(rels);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == b"Relationship" => {
+                let attrs: HashMap<Vec<u8>, String> = e
+                    .attributes()
+                    .flatten()
+                    .map(|a| (a.key.as_ref().to_vec(), attribute_value(&a)))
+                    .collect();
+                let external = attrs.get(b"TargetMode".as_slice()).map(|m| m == "External").unwrap_or(false);
+                let is_image = attrs.get(b"Type".as_slice()).map(|t| t.ends_with("/image")).unwrap_or(false);
+                if let (false, true, Some(id), Some(target)) =
+                    (external, is_image, attrs.get(b"Id".as_slice()), attrs.get(b"Target".as_slice()))
+                {
+                    let path = match target.strip_prefix('/') {
+                        Some(absolute) => absolute.to_string(),
+                        None => resolve_href(base_dir, target),
+                    };
+                    targets.insert(id.clone(), path);
+                }
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    targets
+}
+
+/// `src` of <img> and `href` of SVG <image> elements in a chapter
+fn image_references(xhtml: &[u8]) -> Vec<String> {
+    let mut refs = Vec::new();
+    let mut reader = Reader::from_reader(xhtml);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => {
+                let wanted: &[u8] = match e.local_name().as_ref() {
+                    b"img" => b"src",
+                    b"image" => b"href",
+                    _ => b"",
+                };
+                if !wanted.is_empty() {
+                    for attr in e.attributes().flatten() {
+                        if attr.key.local_name().as_ref() == wanted {
+                            refs.push(attribute_value(&attr));
+                        }
+                    }
+                }
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    refs
+}
+
+fn first_attribute(xml: &[u8], element: &[u8], attribute: &[u8]) -> Option<String> {
+    let mut reader = Reader::from_reader(xml);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == element => {
+                return e
+                    .attributes()
+                    .flatten()
+                    .find(|a| a.key.local_name().as_ref() == attribute)
+                    .map(|a| attribute_value(&a));
+            }
+            Ok(Event::Eof) | Err(_) => return None,
+            _ => {}
+        }
+        buf.clear();
+    }
+}
+
+pub fn attribute_value(attr: &quick_xml::events::attributes::Attribute) -> String {
+    attr.unescape_value()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| String::from_utf8_lossy(&attr.value).to_string())
+}
+
+fn parent_dir(path: &str) -> String {
+    path.rsplit_once('/').map(|(dir, _)| dir.to_string()).unwrap_or_default()
+}
+
+/// Resolve a relative, possibly percent-encoded link against a package directory
+pub fn resolve_href(base_dir: &str, href: &str) -> String {
+    let href = href.split(['#', '?']).next().unwrap_or("");
+    let mut parts: Vec<String> = base_dir.split('/').filter(|p| !p.is_empty()).map(str::to_string).collect();
+    for segment in percent_decode(href).split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            other => parts.push(other.to_string()),
+        }
+    }
+    parts.join("/")
+}
+
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = s.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
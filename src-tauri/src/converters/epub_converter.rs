@@ -0,0 +1,1597 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use sha2::{Digest, Sha256};
+use crate::ConversionResult;
+use crate::converters::docx_reader::{convert_docx_to_html, parse_xml, XmlNode};
+use crate::converters::embedded_images::{open_package, read_entry, resolve_href};
+use crate::converters::html_renderer::{
+    collapse_whitespace, find_element, html_markup, is_block, is_svg, parse_html, read_image_source, text_content,
+    HtmlElement, HtmlNode, HIDDEN_ELEMENTS,
+};
+use crate::converters::markdown_writer::nodes_to_markdown;
+use crate::converters::markup_converter::markdown_to_html;
+use crate::converters::pdf_converter::{pdf_date_now, pdf_date_to_iso};
+use crate::converters::pdf_tools::finish;
+use crate::converters::text_tools::{escape_markup, safe_file_name, unique_file_name};
+
+const EPUB_MIME: &str = "application/epub+zip";
+const STYLESHEET_HREF: &str = "styles/book.css";
+/// Heading levels below the chapter level that get their own entries in
+/// the table of contents
+const TOC_SUBLEVELS: u8 = 2;
+/// Elements that never make it into a chapter
+const DROPPED_ELEMENTS: [&str; 6] = ["head", "script", "noscript", "template", "style", "title"];
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source", "track", "wbr",
+];
+
+const BOOK_CSS: &str = r#"body {
+    font-family: Georgia, "Times New Roman", serif;
+    line-height: 1.5;
+    margin: 0 0.5em;
+}
+h1, h2, h3, h4, h5, h6 {
+    font-family: -apple-system, "Segoe UI", Helvetica, Arial, sans-serif;
+    line-height: 1.25;
+    margin: 1.5em 0 0.5em;
+    page-break-after: avoid;
+}
+h1 { font-size: 1.8em; }
+h2 { font-size: 1.5em; }
+h3 { font-size: 1.25em; }
+p { margin: 0 0 0.8em; }
+a { color: #0645ad; }
+img { max-width: 100%; height: auto; }
+figure { margin: 1em 0; text-align: center; }
+blockquote { margin: 1em 0; padding-left: 1em; border-left: 3px solid #ccc; color: #555; }
+pre { font-family: "Courier New", monospace; font-size: 0.85em; white-space: pre-wrap; background: #f5f5f5; padding: 0.6em; }
+code { font-family: "Courier New", monospace; font-size: 0.9em; }
+table { border-collapse: collapse; margin: 1em 0; }
+th, td { border: 1px solid #999; padding: 0.25em 0.5em; vertical-align: top; }
+th { background: #eee; }
+hr { border: none; border-top: 1px solid #ccc; margin: 1.5em 0; }
+nav ol { list-style: none; padding-left: 1em; }
+.cover { margin: 0; padding: 0; text-align: center; }
+.cover img { max-height: 100%; }
+"#;
+
+// ---------------------------------------------------------------------------
+// Reading
+// ---------------------------------------------------------------------------
+
+/// How EPUB books are converted to HTML
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct EpubHtmlOptions {
+    /// "embed" (default) inlines images as data URIs; "files" writes them
+    /// to a `{name}_files` folder next to the HTML file, or an `images`
+    /// folder when split
+    pub images: Option<String>,
+    /// Write one HTML file per chapter and an index.html with the table
+    /// of contents, instead of a single page
+    pub split: Option<bool>,
+}
+
+/// Extract the text of an EPUB book, chapter by chapter in reading order
+pub fn convert_epub_to_txt(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_epub_to_txt(input_path, output_path), output_path)
+}
+
+fn try_convert_epub_to_txt(input_path: &str, output_path: &str) -> Result<String, String> {
+    let book = read_epub(input_path)?;
+    let mut text = String::new();
+    if let Some(title) = &book.title {
+        text.push_str(&format!("Title: {}\n", title));
+    }
+    if !book.authors.is_empty() {
+        text.push_str(&format!("Author: {}\n", book.authors.join(", ")));
+    }
+    if !text.is_empty() {
+        text.push_str("\n---\n\n");
+    }
+    for chapter in &book.chapters {
+        let mut chapter_text = String::new();
+        plain_text(&chapter.nodes, &mut chapter_text);
+        let chapter_text = chapter_text.trim();
+        if !chapter_text.is_empty() {
+            text.push_str(chapter_text);
+            text.push_str("\n\n");
+        }
+    }
+    let text = format!("{}\n", text.trim_end());
+    fs::write(output_path, &text).map_err(|e| format!("Failed to write text file: {}", e))?;
+    Ok(book.summary())
+}
+
+/// Convert EPUB to a single HTML page, with images embedded as data URIs
+pub fn convert_epub_to_html(input_path: &str, output_path: &str) -> ConversionResult {
+    convert_epub_to_html_with_options(input_path, output_path, &EpubHtmlOptions::default())
+}
+
+/// Convert EPUB to a self-contained HTML page: chapters follow in reading
+/// order under a generated table of contents, links between chapters
+/// point at anchors in the page, and images are embedded or extracted
+pub fn convert_epub_to_html_with_options(input_path: &str, output_path: &str, options: &EpubHtmlOptions) -> ConversionResult {
+    finish(try_convert_epub_to_html(input_path, output_path, options), output_path)
+}
+
+fn try_convert_epub_to_html(input_path: &str, output_path: &str, options: &EpubHtmlOptions) -> Result<String, String> {
+    let mut book = read_epub(input_path)?;
+    let output = Path::new(output_path);
+    let image_dir = match image_mode(options)? {
+        false => None,
+        true => {
+            let folder = format!("{}_files", output.file_stem().and_then(|s| s.to_str()).unwrap_or("book"));
+            Some((output.parent().unwrap_or(Path::new("")).join(&folder), folder))
+        }
+    };
+    let mut assembler = BookAssembler::new(&book, image_dir, false, false);
+    let toc = assembler.assemble(&mut book);
+
+    let cover = assembler.cover_source(&mut book);
+    let mut body = book_header(&book, cover);
+    if !toc.is_empty() {
+        body.push_str("<nav class=\"toc\">\n<h2>Contents</h2>\n");
+        html_toc(&toc, &mut body);
+        body.push_str("</nav>\n");
+    }
+    for (index, chapter) in book.chapters.iter().enumerate() {
+        body.push_str(&format!("<section class=\"chapter\" id=\"{}\">\n", chapter_anchor(index)));
+        html_markup(&chapter.nodes, &mut body);
+        body.push_str("\n</section>\n");
+    }
+    let page = html_page(&book.display_title(input_path), book.language.as_deref(), &body);
+    fs::write(output_path, &page).map_err(|e| format!("Failed to write HTML file: {}", e))?;
+    Ok(book.summary())
+}
+
+/// Convert EPUB to one HTML file per chapter in output_dir, with an
+/// index.html holding the table of contents and previous/next links on
+/// every chapter
+pub fn split_epub_to_html(input_path: &str, output_dir: &str, options: &EpubHtmlOptions) -> ConversionResult {
+    finish(try_split_epub_to_html(input_path, output_dir, options), output_dir)
+}
+
+fn try_split_epub_to_html(input_path: &str, output_dir: &str, options: &EpubHtmlOptions) -> Result<String, String> {
+    let mut book = read_epub(input_path)?;
+    fs::create_dir_all(output_dir).map_err(|e| format!("Failed to create output directory: {}", e))?;
+    let image_dir = match image_mode(options)? {
+        false => None,
+        true => Some((Path::new(output_dir).join("images"), "images".to_string())),
+    };
+    let mut assembler = BookAssembler::new(&book, image_dir, true, false);
+    let toc = assembler.assemble(&mut book);
+    let title = book.display_title(input_path);
+
+    let cover = assembler.cover_source(&mut book);
+    let mut index = book_header(&book, cover);
+    index.push_str("<nav class=\"toc\">\n<h2>Contents</h2>\n");
+    html_toc(&toc, &mut index);
+    index.push_str("</nav>\n");
+    let index_page = html_page(&title, book.language.as_deref(), &index);
+    fs::write(Path::new(output_dir).join("index.html"), &index_page).map_err(|e| format!("Failed to write index.html: {}", e))?;
+
+    let count = book.chapters.len();
+    for (n, chapter) in book.chapters.iter().enumerate() {
+        let mut links = Vec::new();
+        if n > 0 {
+            links.push(format!("<a href=\"{}\" rel=\"prev\">Previous</a>", html_chapter_file(n - 1)));
+        }
+        links.push("<a href=\"index.html\">Contents</a>".to_string());
+        if n + 1 < count {
+            links.push(format!("<a href=\"{}\" rel=\"next\">Next</a>", html_chapter_file(n + 1)));
+        }
+        let chapter_nav = format!("<nav class=\"chapter-nav\">{}</nav>\n", links.join(" | "));
+        let mut body = chapter_nav.clone();
+        body.push_str(&format!("<section class=\"chapter\" id=\"{}\">\n", chapter_anchor(n)));
+        html_markup(&chapter.nodes, &mut body);
+        body.push_str("\n</section>\n");
+        body.push_str(&chapter_nav);
+        let page = html_page(&format!("{} - {}", chapter.label, title), book.language.as_deref(), &body);
+        let name = html_chapter_file(n);
+        fs::write(Path::new(output_dir).join(&name), &page).map_err(|e| format!("Failed to write {}: {}", name, e))?;
+    }
+    Ok(format!("Wrote index.html and {} chapter file(s)", count))
+}
+
+/// Convert EPUB to Markdown, with images written to a `{name}_files`
+/// folder next to the Markdown file
+pub fn convert_epub_to_markdown(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_epub_to_markdown(input_path, output_path), output_path)
+}
+
+fn try_convert_epub_to_markdown(input_path: &str, output_path: &str) -> Result<String, String> {
+    let mut book = read_epub(input_path)?;
+    let output = Path::new(output_path);
+    let folder = format!("{}_files", output.file_stem().and_then(|s| s.to_str()).unwrap_or("book"));
+    let image_dir = Some((output.parent().unwrap_or(Path::new("")).join(&folder), folder));
+    // Headings get GitHub-style ids so links to them still resolve
+    let mut assembler = BookAssembler::new(&book, image_dir, false, true);
+    assembler.assemble(&mut book);
+
+    let chapters: Vec<String> = book
+        .chapters
+        .iter()
+        .map(|chapter| nodes_to_markdown(&chapter.nodes))
+        .filter(|markdown| !markdown.trim().is_empty())
+        .collect();
+    let markdown = format!("{}\n", chapters.join("\n---\n\n").trim_end());
+    fs::write(output_path, &markdown).map_err(|e| format!("Failed to write Markdown file: {}", e))?;
+    Ok(book.summary())
+}
+
+struct BookChapter {
+    path: String,
+    label: String,
+    nodes: Vec<HtmlNode>,
+}
+
+struct EpubBook {
+    archive: zip::ZipArchive<File>,
+    title: Option<String>,
+    authors: Vec<String>,
+    language: Option<String>,
+    /// Package path of the cover image
+    cover: Option<String>,
+    media_types: HashMap<String, String>,
+    chapters: Vec<BookChapter>,
+    /// Table of contents from the navigation document or the NCX, with
+    /// links as package paths
+    toc: Vec<TocEntry>,
+}
+
+impl EpubBook {
+    fn display_title(&self, input_path: &str) -> String {
+        self.title.clone().unwrap_or_else(|| {
+            Path::new(input_path).file_stem().and_then(|s| s.to_str()).unwrap_or("Book").to_string()
+        })
+    }
+
+    fn summary(&self) -> String {
+        format!("Converted {} chapter(s)", self.chapters.len())
+    }
+}
+
+fn read_epub(input_path: &str) -> Result<EpubBook, String> {
+    let mut archive = open_package(input_path, "EPUB")?;
+    let container = read_entry(&mut archive, "META-INF/container.xml")
+        .ok_or_else(|| "Invalid EPUB: META-INF/container.xml is missing".to_string())?;
+    let container = parse_xml(&container)?;
+    let opf_path = container
+        .descendant("rootfile")
+        .and_then(|r| r.attr("full-path"))
+        .ok_or_else(|| "Invalid EPUB: no package document in container.xml".to_string())?
+        .to_string();
+    let opf = read_entry(&mut archive, &opf_path).ok_or_else(|| format!("Invalid EPUB: {} is missing", opf_path))?;
+    let opf = parse_xml(&opf).map_err(|e| format!("Invalid EPUB package document: {}", e))?;
+    let opf_dir = parent_path(&opf_path);
+
+    let metadata = opf.descendant("metadata");
+    let meta_text = |name: &str| {
+        metadata
+            .map(|m| {
+                m.elements()
+                    .filter(|e| e.name == name)
+                    .map(|e| collapse_whitespace(&e.text()))
+                    .filter(|t| !t.is_empty())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    };
+    let title = meta_text("title").into_iter().next();
+    let authors = meta_text("creator");
+    let language = meta_text("language").into_iter().next();
+
+    // Manifest items by id, as (path, media type, properties)
+    let mut items: HashMap<String, (String, String, String)> = HashMap::new();
+    let mut media_types = HashMap::new();
+    let mut nav_path = None;
+    let mut cover = None;
+    if let Some(manifest) = opf.descendant("manifest") {
+        for item in manifest.elements().filter(|e| e.name == "item") {
+            let path = resolve_href(&opf_dir, item.attr("href").unwrap_or(""));
+            let media_type = item.attr("media-type").unwrap_or("").to_string();
+            let properties = item.attr("properties").unwrap_or("").to_string();
+            if properties.split_whitespace().any(|p| p == "nav") {
+                nav_path = Some(path.clone());
+            }
+            if properties.split_whitespace().any(|p| p == "cover-image") {
+                cover = Some(path.clone());
+            }
+            media_types.insert(path.clone(), media_type.clone());
+            items.insert(item.attr("id").unwrap_or("").to_string(), (path, media_type, properties));
+        }
+    }
+    if cover.is_none() {
+        let cover_id = metadata
+            .and_then(|m| m.elements().find(|e| e.name == "meta" && e.attr("name") == Some("cover")))
+            .and_then(|m| m.attr("content"));
+        cover = cover_id.and_then(|id| items.get(id)).map(|(path, _, _)| path.clone());
+    }
+
+    let spine = opf.descendant("spine");
+    let mut chapters = Vec::new();
+    for itemref in spine.iter().flat_map(|s| s.elements()).filter(|e| e.name == "itemref") {
+        let Some((path, media_type, _)) = itemref.attr("idref").and_then(|id| items.get(id)) else { continue };
+        if !matches!(media_type.as_str(), "application/xhtml+xml" | "text/html") {
+            continue;
+        }
+        let Some(bytes) = read_entry(&mut archive, path) else { continue };
+        let root = parse_html(&String::from_utf8_lossy(&bytes));
+        let nodes = find_element(&root, "body").map(|b| b.children.clone()).unwrap_or(root.children);
+        chapters.push(BookChapter { path: path.clone(), label: String::new(), nodes });
+    }
+    if chapters.is_empty() {
+        return Err("Invalid EPUB: the spine has no readable chapters".to_string());
+    }
+
+    let mut toc = Vec::new();
+    if let Some(nav_path) = &nav_path {
+        if let Some(bytes) = read_entry(&mut archive, nav_path) {
+            let root = parse_html(&String::from_utf8_lossy(&bytes));
+            let navs = find_all(&root, "nav");
+            let nav = navs
+                .iter()
+                .find(|n| n.attr("epub:type").is_some_and(|t| t.split_whitespace().any(|t| t == "toc")))
+                .or(navs.first());
+            if let Some(list) = nav.and_then(|n| find_element(n, "ol")) {
+                toc = nav_entries(list, &parent_path(nav_path));
+            }
+        }
+    }
+    if toc.is_empty() {
+        let ncx_path = spine
+            .and_then(|s| s.attr("toc"))
+            .and_then(|id| items.get(id))
+            .or_else(|| items.values().find(|(_, media_type, _)| media_type == "application/x-dtbncx+xml"))
+            .map(|(path, _, _)| path.clone());
+        if let Some(ncx_path) = ncx_path {
+            let ncx = read_entry(&mut archive, &ncx_path).and_then(|bytes| parse_xml(&bytes).ok());
+            if let Some(nav_map) = ncx.as_ref().and_then(|n| n.descendant("navMap")) {
+                toc = ncx_entries(nav_map, &parent_path(&ncx_path));
+            }
+        }
+    }
+
+    // Chapter labels come from the table of contents, then the first heading
+    for (n, chapter) in chapters.iter_mut().enumerate() {
+        chapter.label = toc_label(&toc, &chapter.path)
+            .or_else(|| first_heading(&chapter.nodes))
+            .unwrap_or_else(|| format!("Chapter {}", n + 1));
+    }
+
+    Ok(EpubBook { archive, title, authors, language, cover, media_types, chapters, toc })
+}
+
+fn parent_path(path: &str) -> String {
+    path.rsplit_once('/').map(|(dir, _)| dir.to_string()).unwrap_or_default()
+}
+
+/// A link as a package path, keeping its fragment
+fn package_link(base_dir: &str, href: &str) -> String {
+    let path = resolve_href(base_dir, href);
+    match href.split_once('#') {
+        Some((_, fragment)) => format!("{}#{}", path, fragment),
+        None => path,
+    }
+}
+
+fn find_all<'a>(element: &'a HtmlElement, tag: &str) -> Vec<&'a HtmlElement> {
+    let mut found = Vec::new();
+    for child in element.elements() {
+        if child.tag == tag {
+            found.push(child);
+        }
+        found.extend(find_all(child, tag));
+    }
+    found
+}
+
+fn nav_entries(list: &HtmlElement, base_dir: &str) -> Vec<TocEntry> {
+    list.elements()
+        .filter(|e| e.tag == "li")
+        .filter_map(|item| {
+            let link = item.elements().find(|e| e.tag == "a" || e.tag == "span");
+            let label = link.map(label_text).unwrap_or_default();
+            let href = link.and_then(|a| a.attr("href")).map(|h| package_link(base_dir, h)).unwrap_or_default();
+            let children = item.elements().find(|e| e.tag == "ol").map(|l| nav_entries(l, base_dir)).unwrap_or_default();
+            if label.is_empty() && children.is_empty() {
+                None
+            } else {
+                Some(TocEntry { label, href, children })
+            }
+        })
+        .collect()
+}
+
+fn ncx_entries(parent: &XmlNode, base_dir: &str) -> Vec<TocEntry> {
+    parent
+        .elements()
+        .filter(|e| e.name == "navPoint")
+        .map(|point| TocEntry {
+            label: point.child("navLabel").map(|l| collapse_whitespace(&l.text())).unwrap_or_default(),
+            href: point.child("content").and_then(|c| c.attr("src")).map(|s| package_link(base_dir, s)).unwrap_or_default(),
+            children: ncx_entries(point, base_dir),
+        })
+        .collect()
+}
+
+fn toc_label(entries: &[TocEntry], path: &str) -> Option<String> {
+    entries.iter().find_map(|entry| {
+        let entry_path = entry.href.split('#').next().unwrap_or("");
+        if entry_path == path && !entry.label.is_empty() {
+            Some(entry.label.clone())
+        } else {
+            toc_label(&entry.children, path)
+        }
+    })
+}
+
+fn first_heading(nodes: &[HtmlNode]) -> Option<String> {
+    nodes.iter().find_map(|node| match node {
+        HtmlNode::Element(e) if heading_level(node).is_some() => Some(label_text(e)).filter(|l| !l.is_empty()),
+        HtmlNode::Element(e) => first_heading(&e.children),
+        HtmlNode::Text(_) => None,
+    })
+}
+
+fn image_mode(options: &EpubHtmlOptions) -> Result<bool, String> {
+    match options.images.as_deref().unwrap_or("embed") {
+        "embed" => Ok(false),
+        "files" => Ok(true),
+        other => Err(format!("Unknown image mode '{}': use embed or files", other)),
+    }
+}
+
+fn chapter_anchor(index: usize) -> String {
+    format!("chapter-{}", index + 1)
+}
+
+fn html_chapter_file(index: usize) -> String {
+    format!("chapter_{:03}.html", index + 1)
+}
+
+/// Joins the chapters of a book into one document or a set of pages:
+/// ids are made unique across the book, links between chapters are
+/// pointed at the new ids, and images are embedded or copied out
+struct BookAssembler {
+    image_dir: Option<(PathBuf, String)>,
+    /// Links point at chapter files rather than anchors in one page
+    split: bool,
+    /// Headings get ids made from their text, as Markdown renderers do
+    slug_headings: bool,
+    chapter_index: HashMap<String, usize>,
+    /// New id of each id in a chapter, keyed by (chapter path, old id)
+    ids: HashMap<(String, String), String>,
+    /// Id of the first heading in each chapter
+    first_ids: Vec<Option<String>>,
+    images: HashMap<String, Option<String>>,
+    used_names: Vec<String>,
+}
+
+impl BookAssembler {
+    fn new(book: &EpubBook, image_dir: Option<(PathBuf, String)>, split: bool, slug_headings: bool) -> Self {
+        BookAssembler {
+            image_dir,
+            split,
+            slug_headings,
+            chapter_index: book.chapters.iter().enumerate().map(|(n, c)| (c.path.clone(), n)).collect(),
+            ids: HashMap::new(),
+            first_ids: Vec::new(),
+            images: HashMap::new(),
+            used_names: Vec::new(),
+        }
+    }
+
+    /// Rewrite every chapter and return the table of contents with links
+    /// into the result; without one, the chapters are listed
+    fn assemble(&mut self, book: &mut EpubBook) -> Vec<TocEntry> {
+        let mut used: HashSet<String> = (0..book.chapters.len()).map(chapter_anchor).collect();
+        for chapter in &mut book.chapters {
+            let path = chapter.path.clone();
+            self.assign_ids(&mut chapter.nodes, &path, &mut used);
+            self.first_ids.push(first_heading_id(&chapter.nodes));
+        }
+        let mut chapters = std::mem::take(&mut book.chapters);
+        for (index, chapter) in chapters.iter_mut().enumerate() {
+            let path = chapter.path.clone();
+            self.rewrite(&mut chapter.nodes, &path, index, book);
+        }
+        book.chapters = chapters;
+
+        if book.toc.is_empty() {
+            return book
+                .chapters
+                .iter()
+                .enumerate()
+                .map(|(n, c)| TocEntry { label: c.label.clone(), href: self.target(n, None, None), children: Vec::new() })
+                .collect();
+        }
+        self.toc_links(&book.toc)
+    }
+
+    fn toc_links(&self, entries: &[TocEntry]) -> Vec<TocEntry> {
+        entries
+            .iter()
+            .map(|entry| {
+                let (path, fragment) = match entry.href.split_once('#') {
+                    Some((path, fragment)) => (path, Some(fragment)),
+                    None => (entry.href.as_str(), None),
+                };
+                let href = match self.chapter_index.get(path) {
+                    Some(&index) => self.target(index, Some(path), fragment),
+                    None => String::new(),
+                };
+                TocEntry { label: entry.label.clone(), href, children: self.toc_links(&entry.children) }
+            })
+            .collect()
+    }
+
+    fn assign_ids(&mut self, nodes: &mut [HtmlNode], path: &str, used: &mut HashSet<String>) {
+        for node in nodes.iter_mut() {
+            let heading = heading_level(node).is_some();
+            let HtmlNode::Element(e) = node else { continue };
+            if e.tag == "a" && e.attr("id").is_none() {
+                if let Some(position) = e.attrs.iter().position(|(n, _)| n == "name") {
+                    e.attrs[position].0 = "id".to_string();
+                }
+            }
+            let old = e.attr("id").map(str::to_string);
+            let base = match (&old, heading && self.slug_headings) {
+                (_, true) => Some(heading_slug(&label_text(e))),
+                (Some(old), false) => Some(old.clone()),
+                (None, false) => None,
+            };
+            if let Some(base) = base {
+                let id = unique_id(&base, used);
+                e.attrs.retain(|(n, _)| n != "id");
+                e.attrs.push(("id".to_string(), id.clone()));
+                if let Some(old) = old {
+                    self.ids.entry((path.to_string(), old)).or_insert(id);
+                }
+            }
+            self.assign_ids(&mut e.children, path, used);
+        }
+    }
+
+    /// The href of a chapter, or of an id in it
+    fn target(&self, index: usize, path: Option<&str>, fragment: Option<&str>) -> String {
+        let id = path
+            .zip(fragment)
+            .and_then(|(path, fragment)| self.ids.get(&(path.to_string(), fragment.to_string())));
+        match (self.split, id) {
+            (true, Some(id)) => format!("{}#{}", html_chapter_file(index), id),
+            (true, None) => html_chapter_file(index),
+            (false, Some(id)) => format!("#{}", id),
+            // Markdown has no chapter sections, so link to the chapter's heading
+            (false, None) if self.slug_headings => match self.first_ids.get(index).cloned().flatten() {
+                Some(id) => format!("#{}", id),
+                None => String::new(),
+            },
+            (false, None) => format!("#{}", chapter_anchor(index)),
+        }
+    }
+
+    fn rewrite(&mut self, nodes: &mut Vec<HtmlNode>, path: &str, index: usize, book: &mut EpubBook) {
+        nodes.retain(|n| !matches!(n, HtmlNode::Element(e) if matches!(e.tag.as_str(), "script" | "style" | "link" | "meta")));
+        let base_dir = parent_path(path);
+        for node in nodes.iter_mut() {
+            let HtmlNode::Element(e) = node else { continue };
+            match e.tag.as_str() {
+                "a" => {
+                    let href = e.attr("href").map(str::to_string);
+                    if let Some(href) = href.filter(|h| !has_scheme(h)) {
+                        let (target_path, fragment) = match href.split_once('#') {
+                            Some(("", fragment)) => (path.to_string(), Some(fragment)),
+                            Some((_, fragment)) => (resolve_href(&base_dir, &href), Some(fragment)),
+                            None => (resolve_href(&base_dir, &href), None),
+                        };
+                        if let Some(&target) = self.chapter_index.get(&target_path) {
+                            let new = self.target(target, Some(&target_path), fragment);
+                            // In split output a link within the same page only needs its fragment
+                            let new = match (self.split && target == index, new.split_once('#')) {
+                                (true, Some((_, id))) => format!("#{}", id),
+                                _ => new,
+                            };
+                            set_attr(e, "href", new);
+                        }
+                    }
+                }
+                "img" | "image" => {
+                    let attr = if e.tag == "img" { "src" } else if e.attr("href").is_some() { "href" } else { "xlink:href" };
+                    let source = e.attr(attr).filter(|s| !has_scheme(s)).map(|s| resolve_href(&base_dir, s));
+                    if let Some(src) = source.and_then(|s| self.image_source(&s, book)) {
+                        set_attr(e, attr, src);
+                        e.attrs.retain(|(n, _)| n != "srcset");
+                    }
+                }
+                _ => {}
+            }
+            self.rewrite(&mut e.children, path, index, book);
+        }
+    }
+
+    /// The src for an image in the package: a data URI, or a file in the
+    /// image folder
+    fn image_source(&mut self, path: &str, book: &mut EpubBook) -> Option<String> {
+        if let Some(src) = self.images.get(path) {
+            return src.clone();
+        }
+        let src = read_entry(&mut book.archive, path).and_then(|bytes| {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            match &self.image_dir {
+                None => {
+                    let mime = book
+                        .media_types
+                        .get(path)
+                        .filter(|m| m.starts_with("image/"))
+                        .cloned()
+                        .unwrap_or_else(|| mime_guess::from_path(name).first_or_octet_stream().to_string());
+                    Some(format!("data:{};base64,{}", mime, BASE64.encode(&bytes)))
+                }
+                Some((dir, folder)) => {
+                    fs::create_dir_all(dir).ok()?;
+                    let file_name = unique_file_name(safe_file_name(name).unwrap_or("image"), &mut self.used_names);
+                    fs::write(dir.join(&file_name), &bytes).ok()?;
+                    Some(format!("{}/{}", folder, file_name).replace(' ', "%20"))
+                }
+            }
+        });
+        self.images.insert(path.to_string(), src.clone());
+        src
+    }
+
+    /// The cover image's src, when no chapter shows it already
+    fn cover_source(&mut self, book: &mut EpubBook) -> Option<String> {
+        let cover = book.cover.clone()?;
+        if self.images.contains_key(&cover) {
+            return None;
+        }
+        self.image_source(&cover, book)
+    }
+}
+
+fn first_heading_id(nodes: &[HtmlNode]) -> Option<String> {
+    nodes.iter().find_map(|node| match node {
+        HtmlNode::Element(e) if heading_level(node).is_some() => e.attr("id").map(str::to_string),
+        HtmlNode::Element(e) => first_heading_id(&e.children),
+        HtmlNode::Text(_) => None,
+    })
+}
+
+fn has_scheme(href: &str) -> bool {
+    href.split(['/', '#', '?']).next().is_some_and(|first| first.contains(':'))
+}
+
+fn set_attr(element: &mut HtmlElement, name: &str, value: String) {
+    match element.attrs.iter_mut().find(|(n, _)| n == name) {
+        Some(attr) => attr.1 = value,
+        None => element.attrs.push((name.to_string(), value)),
+    }
+}
+
+fn book_header(book: &EpubBook, cover: Option<String>) -> String {
+    let mut header = String::from("<header class=\"book-header\">\n");
+    if let Some(src) = cover {
+        header.push_str(&format!("<img class=\"cover\" src=\"{}\" alt=\"Cover\">\n", escape_markup(&src)));
+    }
+    if let Some(title) = &book.title {
+        header.push_str(&format!("<h1 class=\"title\">{}</h1>\n", escape_markup(title)));
+    }
+    if !book.authors.is_empty() {
+        header.push_str(&format!("<p class=\"author\">by {}</p>\n", escape_markup(&book.authors.join(", "))));
+    }
+    header.push_str("</header>\n");
+    header
+}
+
+fn html_toc(entries: &[TocEntry], out: &mut String) {
+    out.push_str("<ol>\n");
+    for entry in entries {
+        if entry.href.is_empty() {
+            out.push_str(&format!("<li><span>{}</span>", escape_markup(&entry.label)));
+        } else {
+            out.push_str(&format!("<li><a href=\"{}\">{}</a>", escape_markup(&entry.href), escape_markup(&entry.label)));
+        }
+        if !entry.children.is_empty() {
+            out.push('\n');
+            html_toc(&entry.children, out);
+        }
+        out.push_str("</li>\n");
+    }
+    out.push_str("</ol>\n");
+}
+
+fn html_page(title: &str, language: Option<&str>, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="{}">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>{}</title>
+    <style>
+        body {{
+            font-family: Georgia, 'Times New Roman', serif;
+            max-width: 800px;
+            margin: 40px auto;
+            padding: 20px;
+            line-height: 1.8;
+            color: #333;
+        }}
+        h1, h2, h3 {{ color: #222; }}
+        .book-header {{ text-align: center; margin-bottom: 40px; }}
+        .cover {{ max-height: 600px; }}
+        .author {{ color: #666; }}
+        .toc {{ border-bottom: 1px solid #ddd; padding-bottom: 20px; margin-bottom: 40px; }}
+        .toc ol {{ list-style: none; padding-left: 1.2em; }}
+        .chapter {{ border-top: 1px solid #ddd; padding-top: 20px; margin-top: 40px; }}
+        .chapter-nav {{ text-align: center; font-family: sans-serif; font-size: 0.9em; margin: 20px 0; }}
+        img {{ max-width: 100%; height: auto; }}
+        table {{ border-collapse: collapse; }}
+        th, td {{ border: 1px solid #ddd; padding: 6px 10px; }}
+        pre {{ background: #f5f5f5; padding: 12px; overflow-x: auto; }}
+    </style>
+</head>
+<body>
+{}</body>
+</html>
+"#,
+        escape_markup(language.unwrap_or("en")),
+        escape_markup(title),
+        body
+    )
+}
+
+/// Plain text of chapter content, one paragraph per block
+fn plain_text(nodes: &[HtmlNode], out: &mut String) {
+    for node in nodes {
+        match node {
+            HtmlNode::Text(text) => {
+                for c in text.chars() {
+                    if !c.is_whitespace() {
+                        out.push(c);
+                    } else if !out.is_empty() && !out.ends_with([' ', '\n', '\t']) {
+                        out.push(' ');
+                    }
+                }
+            }
+            HtmlNode::Element(e) if HIDDEN_ELEMENTS.contains(&e.tag.as_str()) => {}
+            HtmlNode::Element(e) if e.tag == "br" => end_line(out, "\n"),
+            HtmlNode::Element(e) if e.tag == "pre" => {
+                end_line(out, "\n\n");
+                out.push_str(text_content(e).trim_end());
+                end_line(out, "\n\n");
+            }
+            HtmlNode::Element(e) if e.tag == "td" || e.tag == "th" => {
+                plain_text(&e.children, out);
+                end_line(out, "\t");
+            }
+            HtmlNode::Element(e) if e.tag == "ul" || e.tag == "ol" => {
+                // Nested lists continue their parent item's lines
+                end_line(out, "\n");
+                plain_text(&e.children, out);
+                end_line(out, "\n");
+            }
+            HtmlNode::Element(e) if e.tag == "tr" || e.tag == "li" => {
+                end_line(out, "\n");
+                if e.tag == "li" {
+                    out.push_str("- ");
+                }
+                plain_text(&e.children, out);
+                end_line(out, "\n");
+            }
+            HtmlNode::Element(e) if is_block(e) => {
+                end_line(out, "\n\n");
+                plain_text(&e.children, out);
+                end_line(out, "\n\n");
+            }
+            HtmlNode::Element(e) if e.tag == "img" => {
+                if let Some(alt) = e.attr("alt").filter(|a| !a.trim().is_empty()) {
+                    out.push_str(&format!("[{}]", alt.trim()));
+                }
+            }
+            HtmlNode::Element(e) => plain_text(&e.children, out),
+        }
+    }
+}
+
+/// End the current line with `separator`, unless the text already ends
+/// with a line break
+fn end_line(out: &mut String, separator: &str) {
+    while out.ends_with(' ') {
+        out.pop();
+    }
+    if out.is_empty() {
+        return;
+    }
+    if separator == "\t" {
+        out.push('\t');
+    } else if !out.ends_with(separator) {
+        let trimmed = out.trim_end_matches(['\n', '\t']).len();
+        out.truncate(trimmed);
+        out.push_str(separator);
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Writing
+// ---------------------------------------------------------------------------
+
+/// Book metadata and chapter layout for EPUB output
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct EpubOptions {
+    /// Book title; defaults to the document title or its first heading
+    pub title: Option<String>,
+    /// Author, written as dc:creator; separate several authors with ";"
+    pub author: Option<String>,
+    /// Language tag such as "en" or "de-CH"; defaults to the document's
+    /// lang attribute, then "en"
+    pub language: Option<String>,
+    /// Path to a cover image, shown on its own page before the first chapter
+    pub cover: Option<String>,
+    /// Path to a CSS file packaged after the default book style sheet
+    pub stylesheet: Option<String>,
+    /// Heading level (1-6) that starts a new chapter; by default the
+    /// highest level that occurs more than once
+    pub chapter_level: Option<u8>,
+}
+
+/// Convert Markdown to an EPUB 3 book, one chapter per top-level heading
+pub fn convert_markdown_to_epub(input_path: &str, output_path: &str, options: &EpubOptions) -> ConversionResult {
+    finish(try_convert_markdown_to_epub(input_path, output_path, options), output_path)
+}
+
+fn try_convert_markdown_to_epub(input_path: &str, output_path: &str, options: &EpubOptions) -> Result<String, String> {
+    let markdown = fs::read_to_string(input_path).map_err(|e| format!("Failed to read Markdown file: {}", e))?;
+    let html = markdown_to_html(&markdown);
+    write_html_epub(&html, input_path, false, options, output_path)
+}
+
+/// Convert an HTML page to an EPUB 3 book, one chapter per top-level
+/// heading, packaging its images and style sheets
+pub fn convert_html_to_epub(input_path: &str, output_path: &str, options: &EpubOptions) -> ConversionResult {
+    finish(try_convert_html_to_epub(input_path, output_path, options), output_path)
+}
+
+fn try_convert_html_to_epub(input_path: &str, output_path: &str, options: &EpubOptions) -> Result<String, String> {
+    let bytes = fs::read(input_path).map_err(|e| format!("Failed to read HTML file: {}", e))?;
+    let html = String::from_utf8_lossy(&bytes);
+    write_html_epub(&html, input_path, false, options, output_path)
+}
+
+/// Convert DOCX to an EPUB 3 book through its HTML rendering; the page
+/// header and footer are left out
+pub fn convert_docx_to_epub(input_path: &str, output_path: &str, options: &EpubOptions) -> ConversionResult {
+    finish(try_convert_docx_to_epub(input_path, output_path, options), output_path)
+}
+
+fn try_convert_docx_to_epub(input_path: &str, output_path: &str, options: &EpubOptions) -> Result<String, String> {
+    let temp_html = format!("{}.temp.html", output_path);
+    let html_result = convert_docx_to_html(input_path, &temp_html);
+    let html = fs::read_to_string(&temp_html);
+    let _ = fs::remove_file(&temp_html);
+    if !html_result.success {
+        return Err(html_result.error.unwrap_or_else(|| "Failed to convert DOCX file".to_string()));
+    }
+    let html = html.map_err(|e| format!("Failed to read converted DOCX: {}", e))?;
+    write_html_epub(&html, input_path, true, options, output_path)
+}
+
+struct Chapter {
+    label: String,
+    nodes: Vec<HtmlNode>,
+    svg: bool,
+    mathml: bool,
+}
+
+struct TocEntry {
+    label: String,
+    href: String,
+    children: Vec<TocEntry>,
+}
+
+struct BookImage {
+    href: String,
+    bytes: Vec<u8>,
+    mime: &'static str,
+}
+
+/// Lay out a parsed HTML document as an EPUB package. DOCX renderings
+/// bring their own web page styles and a page header and footer, which
+/// are left out of the book.
+fn write_html_epub(html: &str, input_path: &str, from_docx: bool, options: &EpubOptions, output_path: &str) -> Result<String, String> {
+    let base_dir = Path::new(input_path).parent().unwrap_or(Path::new(""));
+    let root = parse_html(html);
+    let head = find_element(&root, "head");
+    let body = find_element(&root, "body").unwrap_or(&root);
+
+    let title = non_empty(options.title.as_deref())
+        .or_else(|| find_element(&root, "title").map(label_text).filter(|t| !t.is_empty()))
+        .or_else(|| find_element(body, "h1").map(label_text).filter(|t| !t.is_empty()))
+        .unwrap_or_else(|| {
+            Path::new(input_path).file_stem().and_then(|s| s.to_str()).unwrap_or("Book").to_string()
+        });
+    let authors: Vec<String> = non_empty(options.author.as_deref())
+        .or_else(|| head.and_then(|h| meta_content(h, "author")))
+        .map(|a| a.split(';').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+    let language = non_empty(options.language.as_deref())
+        .or_else(|| find_element(&root, "html").and_then(|h| h.attr("lang")).map(|l| l.trim().to_string()))
+        .filter(|l| !l.is_empty())
+        .unwrap_or_else(|| "en".to_string());
+
+    let mut css = BOOK_CSS.to_string();
+    if !from_docx {
+        if let Some(head) = head {
+            document_styles(head, base_dir, &mut css);
+        }
+    }
+    if let Some(path) = non_empty(options.stylesheet.as_deref()) {
+        let extra = fs::read_to_string(&path).map_err(|e| format!("Failed to read style sheet {}: {}", path, e))?;
+        css.push('\n');
+        css.push_str(&extra);
+    }
+
+    let cover = match non_empty(options.cover.as_deref()) {
+        Some(path) => {
+            let bytes = fs::read(&path).map_err(|e| format!("Failed to read cover image {}: {}", path, e))?;
+            let svg = path.to_ascii_lowercase().ends_with(".svg");
+            let (bytes, extension, mime) = epub_image(bytes, svg).ok_or(format!("Unsupported cover image: {}", path))?;
+            Some(BookImage { href: format!("images/cover.{}", extension), bytes, mime })
+        }
+        None => None,
+    };
+
+    // Split the content at the chapter headings
+    let mut content = content_nodes(body);
+    if from_docx {
+        content.retain(|node| {
+            !matches!(node, HtmlNode::Element(e)
+                if matches!(e.tag.as_str(), "header" | "footer")
+                    && e.attr("class").is_some_and(|c| c.starts_with("docx-")))
+        });
+    }
+    let level = options.chapter_level.map(|l| l.clamp(1, 6)).or_else(|| chapter_level(&content));
+    let mut chapters = split_chapters(content, level, &title);
+
+    // Give every heading an id, then note which chapter each id lives in
+    let mut ids = HashSet::new();
+    for chapter in &chapters {
+        collect_ids(&chapter.nodes, &mut ids);
+    }
+    let mut targets = HashMap::new();
+    let mut toc = Vec::new();
+    for (index, chapter) in chapters.iter_mut().enumerate() {
+        let file = chapter_file(index);
+        let mut entry = TocEntry { label: chapter.label.clone(), href: file.clone(), children: Vec::new() };
+        let mut headings = Vec::new();
+        name_anchors(&mut chapter.nodes, index, &mut ids, &mut targets, &mut headings);
+        for (heading_level, label, id) in headings {
+            let depth = match level {
+                Some(level) if heading_level > level && heading_level <= level + TOC_SUBLEVELS => heading_level - level,
+                _ => continue,
+            };
+            add_toc_entry(&mut entry.children, depth as usize - 1, TocEntry {
+                label,
+                href: format!("{}#{}", file, id),
+                children: Vec::new(),
+            });
+        }
+        toc.push(entry);
+    }
+
+    // Package the images and point links at the chapter holding their target
+    let mut packager = Packager { base_dir, targets: &targets, images: Vec::new(), sources: HashMap::new(), svg: false, mathml: false };
+    let mut documents = Vec::new();
+    for (index, chapter) in chapters.iter_mut().enumerate() {
+        packager.svg = false;
+        packager.mathml = false;
+        packager.rewrite(&mut chapter.nodes, index);
+        chapter.svg = packager.svg;
+        chapter.mathml = packager.mathml;
+        let mut body = String::new();
+        xhtml_nodes(&chapter.nodes, &mut body);
+        documents.push(xhtml_document(&chapter.label, &language, "", &body));
+    }
+    let images = packager.images;
+
+    let identifier = book_identifier(&title, &authors, &documents);
+    let modified = pdf_date_to_iso(&pdf_date_now()).unwrap_or_default();
+
+    // Package document
+    let mut metadata = format!(
+        "    <dc:identifier id=\"book-id\">{}</dc:identifier>\n    <dc:title>{}</dc:title>\n    <dc:language>{}</dc:language>\n",
+        identifier,
+        escape_markup(&title),
+        escape_markup(&language)
+    );
+    for author in &authors {
+        metadata.push_str(&format!("    <dc:creator>{}</dc:creator>\n", escape_markup(author)));
+    }
+    metadata.push_str(&format!("    <meta property=\"dcterms:modified\">{}</meta>\n", modified));
+    if cover.is_some() {
+        // EPUB 2 reading systems look for the cover through this meta
+        metadata.push_str("    <meta name=\"cover\" content=\"cover-image\"/>\n");
+    }
+    let mut manifest = String::from(
+        "    <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n\
+         \x20   <item id=\"ncx\" href=\"toc.ncx\" media-type=\"application/x-dtbncx+xml\"/>\n",
+    );
+    manifest.push_str(&format!("    <item id=\"css\" href=\"{}\" media-type=\"text/css\"/>\n", STYLESHEET_HREF));
+    let mut spine = String::new();
+    if let Some(cover) = &cover {
+        manifest.push_str(&format!(
+            "    <item id=\"cover-image\" href=\"{}\" media-type=\"{}\" properties=\"cover-image\"/>\n\
+             \x20   <item id=\"cover\" href=\"cover.xhtml\" media-type=\"application/xhtml+xml\"/>\n",
+            cover.href, cover.mime
+        ));
+        spine.push_str("    <itemref idref=\"cover\"/>\n");
+    }
+    for (index, chapter) in chapters.iter().enumerate() {
+        let properties: Vec<&str> = [(chapter.svg, "svg"), (chapter.mathml, "mathml")]
+            .iter()
+            .filter(|(used, _)| *used)
+            .map(|(_, name)| *name)
+            .collect();
+        manifest.push_str(&format!(
+            "    <item id=\"chapter-{}\" href=\"{}\" media-type=\"application/xhtml+xml\"{}/>\n",
+            index + 1,
+            chapter_file(index),
+            if properties.is_empty() { String::new() } else { format!(" properties=\"{}\"", properties.join(" ")) }
+        ));
+        spine.push_str(&format!("    <itemref idref=\"chapter-{}\"/>\n", index + 1));
+    }
+    for (n, image) in images.iter().enumerate() {
+        manifest.push_str(&format!(
+            "    <item id=\"image-{}\" href=\"{}\" media-type=\"{}\"/>\n",
+            n + 1,
+            image.href,
+            image.mime
+        ));
+    }
+    let opf = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"book-id\" xml:lang=\"{}\">\n\
+         \x20 <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n{}  </metadata>\n\
+         \x20 <manifest>\n{}  </manifest>\n\
+         \x20 <spine toc=\"ncx\">\n{}  </spine>\n</package>\n",
+        escape_markup(&language),
+        metadata,
+        manifest,
+        spine
+    );
+
+    // Navigation document and the NCX for EPUB 2 reading systems
+    let mut nav_list = String::new();
+    nav_items(&toc, &mut nav_list);
+    let mut landmarks = String::new();
+    if cover.is_some() {
+        landmarks.push_str("<li><a epub:type=\"cover\" href=\"cover.xhtml\">Cover</a></li>\n");
+    }
+    landmarks.push_str("<li><a epub:type=\"toc\" href=\"nav.xhtml#toc\">Contents</a></li>\n");
+    landmarks.push_str(&format!("<li><a epub:type=\"bodymatter\" href=\"{}\">Start</a></li>\n", chapter_file(0)));
+    let nav = xhtml_document(
+        &title,
+        &language,
+        "",
+        &format!(
+            "<nav epub:type=\"toc\" id=\"toc\">\n<h1>Contents</h1>\n{}</nav>\n\
+             <nav epub:type=\"landmarks\" id=\"landmarks\" hidden=\"hidden\">\n<ol>\n{}</ol>\n</nav>\n",
+            nav_list, landmarks
+        ),
+    );
+    let mut nav_points = String::new();
+    let mut play_order = 0;
+    ncx_points(&toc, &mut play_order, &mut nav_points);
+    let ncx = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <ncx xmlns=\"http://www.daisy.org/z3986/2005/ncx/\" version=\"2005-1\" xml:lang=\"{}\">\n\
+         <head>\n<meta name=\"dtb:uid\" content=\"{}\"/>\n<meta name=\"dtb:depth\" content=\"{}\"/>\n\
+         <meta name=\"dtb:totalPageCount\" content=\"0\"/>\n<meta name=\"dtb:maxPageNumber\" content=\"0\"/>\n</head>\n\
+         <docTitle><text>{}</text></docTitle>\n{}<navMap>\n{}</navMap>\n</ncx>\n",
+        escape_markup(&language),
+        identifier,
+        toc_depth(&toc),
+        escape_markup(&title),
+        authors.iter().map(|a| format!("<docAuthor><text>{}</text></docAuthor>\n", escape_markup(a))).collect::<String>(),
+        nav_points
+    );
+
+    let cover_page = cover.as_ref().map(|cover| {
+        xhtml_document(
+            &title,
+            &language,
+            " class=\"cover\"",
+            &format!(
+                "<section epub:type=\"cover\">\n<img src=\"{}\" alt=\"{}\"/>\n</section>\n",
+                cover.href,
+                escape_markup(&title)
+            ),
+        )
+    });
+
+    let file = File::create(output_path).map_err(|e| format!("Failed to create EPUB file: {}", e))?;
+    let mut zip = zip::ZipWriter::new(file);
+    let write_error = |e: &dyn std::fmt::Display| format!("Failed to write EPUB file: {}", e);
+    // The mimetype entry comes first and uncompressed so the type can be sniffed
+    let stored = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let deflated = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    zip.start_file("mimetype", stored).map_err(|e| write_error(&e))?;
+    zip.write_all(EPUB_MIME.as_bytes()).map_err(|e| write_error(&e))?;
+    let container = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n\
+         \x20 <rootfiles>\n    <rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\"/>\n\
+         \x20 </rootfiles>\n</container>\n";
+    let mut entries: Vec<(String, &[u8])> = vec![
+        ("META-INF/container.xml".to_string(), container.as_bytes()),
+        ("OEBPS/content.opf".to_string(), opf.as_bytes()),
+        ("OEBPS/nav.xhtml".to_string(), nav.as_bytes()),
+        ("OEBPS/toc.ncx".to_string(), ncx.as_bytes()),
+        (format!("OEBPS/{}", STYLESHEET_HREF), css.as_bytes()),
+    ];
+    if let Some(page) = &cover_page {
+        entries.push(("OEBPS/cover.xhtml".to_string(), page.as_bytes()));
+    }
+    for (index, document) in documents.iter().enumerate() {
+        entries.push((format!("OEBPS/{}", chapter_file(index)), document.as_bytes()));
+    }
+    for (name, data) in entries {
+        zip.start_file(name, deflated).map_err(|e| write_error(&e))?;
+        zip.write_all(data).map_err(|e| write_error(&e))?;
+    }
+    // Images are already compressed
+    for image in cover.iter().chain(images.iter()) {
+        zip.start_file(format!("OEBPS/{}", image.href), stored).map_err(|e| write_error(&e))?;
+        zip.write_all(&image.bytes).map_err(|e| write_error(&e))?;
+    }
+    zip.finish().map_err(|e| write_error(&e))?;
+
+    Ok(format!(
+        "Wrote {} chapter(s) and {} image(s){}",
+        chapters.len(),
+        images.len(),
+        if cover.is_some() { " with a cover" } else { "" }
+    ))
+}
+
+fn non_empty(value: Option<&str>) -> Option<String> {
+    value.map(str::trim).filter(|v| !v.is_empty()).map(str::to_string)
+}
+
+fn label_text(element: &HtmlElement) -> String {
+    text_content(element).split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn meta_content(head: &HtmlElement, name: &str) -> Option<String> {
+    head.elements()
+        .find(|e| e.tag == "meta" && e.attr("name").is_some_and(|n| n.eq_ignore_ascii_case(name)))
+        .and_then(|e| e.attr("content"))
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty())
+}
+
+/// Append the document's own style blocks and local linked style sheets
+fn document_styles(head: &HtmlElement, base_dir: &Path, css: &mut String) {
+    for element in head.elements() {
+        let sheet = match element.tag.as_str() {
+            "style" => Some(text_content_raw(element)),
+            "link" if element.attr("rel").is_some_and(|r| r.split_whitespace().any(|r| r.eq_ignore_ascii_case("stylesheet"))) => {
+                element
+                    .attr("href")
+                    .filter(|href| !href.contains("://"))
+                    .and_then(|href| fs::read_to_string(base_dir.join(href.split(['?', '#']).next().unwrap_or(href))).ok())
+            }
+            _ => None,
+        };
+        if let Some(sheet) = sheet {
+            css.push('\n');
+            css.push_str(sheet.trim());
+            css.push('\n');
+        }
+    }
+}
+
+/// The text of an element, including hidden ones such as `style`
+fn text_content_raw(element: &HtmlElement) -> String {
+    element
+        .children
+        .iter()
+        .map(|c| match c {
+            HtmlNode::Text(t) => t.clone(),
+            HtmlNode::Element(e) => text_content_raw(e),
+        })
+        .collect()
+}
+
+/// The body content, looking through wrappers like `<main>` or a lone
+/// `<div>` that hold the whole document
+fn content_nodes(body: &HtmlElement) -> Vec<HtmlNode> {
+    let mut container = body;
+    loop {
+        let mut elements = container.elements().filter(|e| !DROPPED_ELEMENTS.contains(&e.tag.as_str()));
+        let has_text = container.children.iter().any(|c| matches!(c, HtmlNode::Text(t) if !t.trim().is_empty()));
+        match (elements.next(), elements.next()) {
+            (Some(only), None)
+                if !has_text && matches!(only.tag.as_str(), "div" | "main" | "article" | "section" | "html" | "body") =>
+            {
+                container = only
+            }
+            _ => break,
+        }
+    }
+    container.children.clone()
+}
+
+fn heading_level(node: &HtmlNode) -> Option<u8> {
+    match node {
+        HtmlNode::Element(e) => match e.tag.as_bytes() {
+            [b'h', level @ b'1'..=b'6'] => Some(level - b'0'),
+            _ => None,
+        },
+        HtmlNode::Text(_) => None,
+    }
+}
+
+/// The highest heading level used more than once, so a title heading
+/// above the chapters doesn't swallow the whole book
+fn chapter_level(nodes: &[HtmlNode]) -> Option<u8> {
+    let mut counts = [0usize; 7];
+    for level in nodes.iter().filter_map(heading_level) {
+        counts[level as usize] += 1;
+    }
+    (1..=6u8).find(|&l| counts[l as usize] > 1).or_else(|| (1..=6u8).find(|&l| counts[l as usize] > 0))
+}
+
+fn split_chapters(nodes: Vec<HtmlNode>, level: Option<u8>, title: &str) -> Vec<Chapter> {
+    let mut chapters: Vec<Chapter> = Vec::new();
+    let mut current: Vec<HtmlNode> = Vec::new();
+    let finish_chapter = |nodes: Vec<HtmlNode>, chapters: &mut Vec<Chapter>| {
+        let has_content = nodes.iter().any(|n| match n {
+            HtmlNode::Text(t) => !t.trim().is_empty(),
+            HtmlNode::Element(_) => true,
+        });
+        if !has_content {
+            return;
+        }
+        let label = nodes
+            .iter()
+            .find_map(|n| match n {
+                HtmlNode::Element(e) if heading_level(n).is_some() => Some(label_text(e)).filter(|l| !l.is_empty()),
+                _ => None,
+            })
+            .unwrap_or_else(|| title.to_string());
+        chapters.push(Chapter { label, nodes, svg: false, mathml: false });
+    };
+    for node in nodes {
+        if let HtmlNode::Element(e) = &node {
+            if DROPPED_ELEMENTS.contains(&e.tag.as_str()) {
+                continue;
+            }
+        }
+        let starts_chapter = matches!((heading_level(&node), level), (Some(h), Some(l)) if h <= l);
+        if starts_chapter && !current.is_empty() {
+            finish_chapter(std::mem::take(&mut current), &mut chapters);
+        }
+        current.push(node);
+    }
+    finish_chapter(current, &mut chapters);
+    if chapters.is_empty() {
+        chapters.push(Chapter { label: title.to_string(), nodes: Vec::new(), svg: false, mathml: false });
+    }
+    chapters
+}
+
+fn chapter_file(index: usize) -> String {
+    format!("chapter_{:03}.xhtml", index + 1)
+}
+
+fn collect_ids(nodes: &[HtmlNode], ids: &mut HashSet<String>) {
+    for node in nodes {
+        if let HtmlNode::Element(e) = node {
+            if let Some(id) = e.attr("id") {
+                ids.insert(id.to_string());
+            }
+            collect_ids(&e.children, ids);
+        }
+    }
+}
+
+/// Give headings without an id one made from their text, turn `<a name>`
+/// anchors into ids, and record the chapter of every id and the headings
+/// as (level, label, id)
+fn name_anchors(
+    nodes: &mut [HtmlNode],
+    chapter: usize,
+    ids: &mut HashSet<String>,
+    targets: &mut HashMap<String, usize>,
+    headings: &mut Vec<(u8, String, String)>,
+) {
+    for node in nodes.iter_mut() {
+        let level = heading_level(node);
+        let HtmlNode::Element(e) = node else { continue };
+        if e.tag == "a" && e.attr("id").is_none() {
+            if let Some(position) = e.attrs.iter().position(|(n, _)| n == "name") {
+                e.attrs[position].0 = "id".to_string();
+            }
+        }
+        if let Some(level) = level {
+            let label = label_text(e);
+            let id = match e.attr("id") {
+                Some(id) => id.to_string(),
+                None => {
+                    let id = unique_id(&heading_slug(&label), ids);
+                    e.attrs.push(("id".to_string(), id.clone()));
+                    id
+                }
+            };
+            if !label.is_empty() {
+                headings.push((level, label, id));
+            }
+        }
+        if let Some(id) = e.attr("id") {
+            targets.entry(id.to_string()).or_insert(chapter);
+        }
+        name_anchors(&mut e.children, chapter, ids, targets, headings);
+    }
+}
+
+/// A GitHub-style anchor: lowercase, spaces as hyphens, punctuation dropped
+fn heading_slug(text: &str) -> String {
+    let slug: String = text
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            c if c.is_whitespace() => Some('-'),
+            _ => None,
+        })
+        .collect();
+    if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug
+    }
+}
+
+fn unique_id(base: &str, ids: &mut HashSet<String>) -> String {
+    let mut id = base.to_string();
+    let mut n = 1;
+    while ids.contains(&id) {
+        id = format!("{}-{}", base, n);
+        n += 1;
+    }
+    ids.insert(id.clone());
+    id
+}
+
+/// Add an entry `depth` levels below the top of the list, under the last
+/// entry of each level; missing levels are skipped
+fn add_toc_entry(entries: &mut Vec<TocEntry>, depth: usize, entry: TocEntry) {
+    match entries.last_mut() {
+        Some(last) if depth > 0 => add_toc_entry(&mut last.children, depth - 1, entry),
+        _ => entries.push(entry),
+    }
+}
+
+fn toc_depth(entries: &[TocEntry]) -> usize {
+    entries.iter().map(|e| 1 + toc_depth(&e.children)).max().unwrap_or(0)
+}
+
+fn nav_items(entries: &[TocEntry], out: &mut String) {
+    out.push_str("<ol>\n");
+    for entry in entries {
+        out.push_str(&format!("<li><a href=\"{}\">{}</a>", escape_markup(&entry.href), escape_markup(&entry.label)));
+        if !entry.children.is_empty() {
+            out.push('\n');
+            nav_items(&entry.children, out);
+        }
+        out.push_str("</li>\n");
+    }
+    out.push_str("</ol>\n");
+}
+
+fn ncx_points(entries: &[TocEntry], play_order: &mut usize, out: &mut String) {
+    for entry in entries {
+        *play_order += 1;
+        out.push_str(&format!(
+            "<navPoint id=\"navpoint-{0}\" playOrder=\"{0}\"><navLabel><text>{1}</text></navLabel><content src=\"{2}\"/>\n",
+            play_order,
+            escape_markup(&entry.label),
+            escape_markup(&entry.href)
+        ));
+        ncx_points(&entry.children, play_order, out);
+        out.push_str("</navPoint>\n");
+    }
+}
+
+/// A stable identifier derived from the book's content, formatted as a
+/// name-based UUID
+fn book_identifier(title: &str, authors: &[String], documents: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(title.as_bytes());
+    for author in authors {
+        hasher.update(author.as_bytes());
+    }
+    for document in documents {
+        hasher.update(document.as_bytes());
+    }
+    let mut bytes = hasher.finalize()[..16].to_vec();
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("urn:uuid:{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Image bytes in an EPUB core media type with their file extension and
+/// MIME type; other formats are re-encoded as PNG
+fn epub_image(bytes: Vec<u8>, svg: bool) -> Option<(Vec<u8>, &'static str, &'static str)> {
+    if is_svg(&bytes, svg) {
+        return Some((bytes, "svg", "image/svg+xml"));
+    }
+    match image::guess_format(&bytes).ok()? {
+        image::ImageFormat::Png => Some((bytes, "png", "image/png")),
+        image::ImageFormat::Jpeg => Some((bytes, "jpg", "image/jpeg")),
+        image::ImageFormat::Gif => Some((bytes, "gif", "image/gif")),
+        image::ImageFormat::WebP => Some((bytes, "webp", "image/webp")),
+        _ => {
+            let decoded = image::load_from_memory(&bytes).ok()?;
+            let mut png = Vec::new();
+            decoded.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).ok()?;
+            Some((png, "png", "image/png"))
+        }
+    }
+}
+
+/// Rewrites chapter content for the package: images are copied into it,
+/// fragment links are pointed at the chapter that holds their target and
+/// inline SVG and MathML get their namespaces
+struct Packager<'a> {
+    base_dir: &'a Path,
+    targets: &'a HashMap<String, usize>,
+    images: Vec<BookImage>,
+    /// Package path of each image source, or None if it couldn't be read
+    sources: HashMap<String, Option<String>>,
+    svg: bool,
+    mathml: bool,
+}
+
+impl Packager<'_> {
+    fn rewrite(&mut self, nodes: &mut Vec<HtmlNode>, chapter: usize) {
+        nodes.retain(|n| !matches!(n, HtmlNode::Element(e) if DROPPED_ELEMENTS.contains(&e.tag.as_str())));
+        for node in nodes.iter_mut() {
+            let HtmlNode::Element(e) = node else { continue };
+            match e.tag.as_str() {
+                "img" => {
+                    let href = e.attr("src").and_then(|src| self.image(src));
+                    match href {
+                        Some(href) => {
+                            e.attrs.retain(|(n, _)| n != "src" && n != "srcset");
+                            e.attrs.insert(0, ("src".to_string(), href));
+                            if e.attr("alt").is_none() {
+                                e.attrs.push(("alt".to_string(), String::new()));
+                            }
+                        }
+                        None => {
+                            // Keep the description of a picture that can't be packaged
+                            *node = HtmlNode::Text(e.attr("alt").unwrap_or_default().to_string());
+                            continue;
+                        }
+                    }
+                }
+                "a" => {
+                    let target = e
+                        .attr("href")
+                        .and_then(|href| href.strip_prefix('#'))
+                        .and_then(|id| self.targets.get(id).map(|&c| (c, id.to_string())));
+                    if let Some((target_chapter, id)) = target {
+                        if target_chapter != chapter {
+                            for attr in e.attrs.iter_mut().filter(|(n, _)| n == "href") {
+                                attr.1 = format!("{}#{}", chapter_file(target_chapter), id);
+                            }
+                        }
+                    }
+                }
+                "svg" => {
+                    self.svg = true;
+                    if e.attr("xmlns").is_none() {
+                        e.attrs.push(("xmlns".to_string(), "http://www.w3.org/2000/svg".to_string()));
+                    }
+                }
+                "math" => {
+                    self.mathml = true;
+                    if e.attr("xmlns").is_none() {
+                        e.attrs.push(("xmlns".to_string(), "http://www.w3.org/1998/Math/MathML".to_string()));
+                    }
+                }
+                _ => {}
+            }
+            self.rewrite(&mut e.children, chapter);
+        }
+    }
+
+    fn image(&mut self, source: &str) -> Option<String> {
+        if let Some(href) = self.sources.get(source) {
+            return href.clone();
+        }
+        let href = read_image_source(source, self.base_dir)
+            .and_then(|(bytes, svg)| epub_image(bytes, svg))
+            .map(|(bytes, extension, mime)| {
+                let href = format!("images/image_{:03}.{}", self.images.len() + 1, extension);
+                self.images.push(BookImage { href: href.clone(), bytes, mime });
+                href
+            });
+        self.sources.insert(source.to_string(), href.clone());
+        href
+    }
+}
+
+fn xhtml_document(title: &str, language: &str, body_attrs: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" xml:lang=\"{0}\" lang=\"{0}\">\n\
+         <head>\n<meta charset=\"UTF-8\"/>\n<title>{1}</title>\n<link rel=\"stylesheet\" type=\"text/css\" href=\"{2}\"/>\n</head>\n\
+         <body{3}>\n{4}</body>\n</html>\n",
+        escape_markup(language),
+        escape_markup(title),
+        STYLESHEET_HREF,
+        body_attrs,
+        body
+    )
+}
+
+/// Serialize parsed HTML as well-formed XHTML: void elements are closed,
+/// attributes quoted and event handlers and undeclared prefixes dropped
+fn xhtml_nodes(nodes: &[HtmlNode], out: &mut String) {
+    for node in nodes {
+        match node {
+            HtmlNode::Text(text) => out.push_str(&xml_text(text)),
+            HtmlNode::Element(e) => {
+                out.push('<');
+                out.push_str(&e.tag);
+                let mut written = HashSet::new();
+                for (name, value) in &e.attrs {
+                    if !is_xhtml_attribute(name) || !written.insert(name.as_str()) {
+                        continue;
+                    }
+                    out.push_str(&format!(" {}=\"{}\"", name, xml_text(value).replace('"', "&quot;")));
+                }
+                if VOID_ELEMENTS.contains(&e.tag.as_str()) {
+                    out.push_str("/>");
+                } else {
+                    out.push('>');
+                    xhtml_nodes(&e.children, out);
+                    out.push_str(&format!("</{}>", e.tag));
+                }
+            }
+        }
+    }
+}
+
+fn is_xhtml_attribute(name: &str) -> bool {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'));
+    let prefix_declared = !name.contains(':') || matches!(name, "xml:lang" | "xml:space" | "epub:type");
+    let handler = name.starts_with("on") && name != "open";
+    valid && prefix_declared && !handler
+}
+
+/// Escape text for XML, dropping characters XML 1.0 doesn't allow
+fn xml_text(text: &str) -> String {
+    let mut xml = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => xml.push_str("&amp;"),
+            '<' => xml.push_str("&lt;"),
+            '>' => xml.push_str("&gt;"),
+            '\t' | '\n' | '\r' => xml.push(c),
+            c if (c as u32) < 0x20 || c == '\u{fffe}' || c == '\u{ffff}' => {}
+            c => xml.push(c),
+        }
+    }
+    xml
+}
+
@@ -0,0 +1,593 @@
+use std::fs;
+use std::path::Path;
+use serde::Serialize;
+use serde_json::Value;
+use similar::{capture_diff_slices, group_diff_ops, Algorithm, ChangeTag, DiffOp, TextDiff};
+use crate::{get_file_type, ConversionResult};
+use crate::converters::doc_reader::convert_doc_to_txt;
+use crate::converters::document_converter::convert_docx_to_txt;
+use crate::converters::epub_converter::convert_epub_to_txt;
+use crate::converters::markdown_writer::html_to_markdown;
+use crate::converters::markup_converter::toml_to_json_value;
+use crate::converters::odt_converter::convert_odt_to_txt;
+use crate::converters::pdf_converter::{extract_pdf_text, PdfTextOptions};
+use crate::converters::pdf_tools::finish;
+use crate::converters::text_tools::escape_markup;
+use crate::converters::rtf_converter::convert_rtf_to_txt;
+
+/// Paired lines at least this similar are shown as one changed line with
+/// word-level insertions and deletions
+const CHANGED_LINE_RATIO: f32 = 0.5;
+
+/// Options for comparing two files
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct CompareOptions {
+    /// "html" or "json". Defaults to the output file's extension, then HTML
+    pub format: Option<String>,
+    /// Unchanged lines shown around each change in text comparisons.
+    /// Defaults to 3
+    pub context: Option<usize>,
+    /// Treat runs of spaces as one and skip blank lines (default true)
+    pub ignore_whitespace: Option<bool>,
+    /// Compare text case-insensitively
+    pub ignore_case: Option<bool>,
+}
+
+/// Compare two versions of a file and write an HTML or JSON report of the
+/// insertions and deletions. Documents (DOCX, DOC, ODT, RTF, EPUB, PDF,
+/// HTML and text) are compared line by line on their extracted text, with
+/// word-level changes inside edited lines; JSON, YAML and TOML are
+/// compared structurally, key by key.
+pub fn compare_files(old_path: &str, new_path: &str, output_path: &str, options: &CompareOptions) -> ConversionResult {
+    finish(try_compare_files(old_path, new_path, output_path, options), output_path)
+}
+
+fn try_compare_files(old_path: &str, new_path: &str, output_path: &str, options: &CompareOptions) -> Result<String, String> {
+    let old_type = file_type_of(old_path);
+    let new_type = file_type_of(new_path);
+    let (old_family, new_family) = (family(&old_type)?, family(&new_type)?);
+    if old_family != new_family {
+        return Err(format!(
+            "Can't compare a {} file with a {} file: choose two documents or two data files",
+            old_type, new_type
+        ));
+    }
+
+    let mut report = CompareReport {
+        old_file: file_name(old_path),
+        new_file: file_name(new_path),
+        mode: old_family,
+        identical: true,
+        inserted: 0,
+        deleted: 0,
+        changed: 0,
+        hunks: Vec::new(),
+        changes: Vec::new(),
+    };
+    if old_family == "data" {
+        let old = read_value(old_path, &old_type)?;
+        let new = read_value(new_path, &new_type)?;
+        diff_values("$", &old, &new, &mut report.changes);
+        for change in &report.changes {
+            match change.kind {
+                "added" => report.inserted += 1,
+                "removed" => report.deleted += 1,
+                _ => report.changed += 1,
+            }
+        }
+    } else {
+        let old = extract_text(old_path, &old_type, &format!("{}.old.txt", output_path))?;
+        let new = extract_text(new_path, &new_type, &format!("{}.new.txt", output_path))?;
+        diff_text(&old, &new, options, &mut report);
+    }
+    report.identical = report.inserted + report.deleted + report.changed == 0;
+
+    let format = options.format.clone().unwrap_or_else(|| {
+        match Path::new(output_path).extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("json") => "json".to_string(),
+            _ => "html".to_string(),
+        }
+    });
+    let output = match format.as_str() {
+        "json" => serde_json::to_string_pretty(&report).map_err(|e| format!("Failed to create JSON: {}", e))?,
+        "html" => html_report(&report),
+        other => return Err(format!("Unknown report format '{}': use html or json", other)),
+    };
+    fs::write(output_path, output).map_err(|e| format!("Failed to write report: {}", e))?;
+    Ok(report.summary())
+}
+
+#[derive(Debug, Serialize)]
+struct CompareReport {
+    old_file: String,
+    new_file: String,
+    /// "text" or "data"
+    mode: &'static str,
+    identical: bool,
+    inserted: usize,
+    deleted: usize,
+    changed: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hunks: Vec<Hunk>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    changes: Vec<ValueChange>,
+}
+
+impl CompareReport {
+    fn summary(&self) -> String {
+        if self.identical {
+            "No differences found".to_string()
+        } else {
+            format!("{} inserted, {} deleted and {} changed", self.inserted, self.deleted, self.changed)
+        }
+    }
+}
+
+/// A run of changes with the unchanged lines around it
+#[derive(Debug, Serialize)]
+struct Hunk {
+    old_start: usize,
+    new_start: usize,
+    lines: Vec<DiffLine>,
+}
+
+#[derive(Debug, Serialize)]
+struct DiffLine {
+    /// "equal", "delete", "insert" or "change"
+    kind: &'static str,
+    old_line: Option<usize>,
+    new_line: Option<usize>,
+    text: String,
+    /// Word-level edits of a changed line
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    words: Vec<DiffSegment>,
+}
+
+#[derive(Debug, Serialize)]
+struct DiffSegment {
+    kind: &'static str,
+    text: String,
+}
+
+/// A change in structured data, addressed by a JSONPath-style path
+#[derive(Debug, Serialize)]
+struct ValueChange {
+    path: String,
+    /// "added", "removed" or "changed"
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new: Option<Value>,
+}
+
+fn file_type_of(path: &str) -> String {
+    get_file_type(Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or(""))
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or(path).to_string()
+}
+
+/// Files of one family can be compared with each other
+fn family(file_type: &str) -> Result<&'static str, String> {
+    match file_type {
+        "json" | "yaml" | "toml" => Ok("data"),
+        "pdf" | "docx" | "doc" | "odt" | "rtf" | "epub" | "html" | "text" | "markdown" | "xml" | "config" | "data"
+        | "style" | "script" | "code" | "svg" => Ok("text"),
+        other => Err(format!("Comparing {} files is not supported", other)),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Documents
+// ---------------------------------------------------------------------------
+
+/// The text of a document, through the converter for its type
+fn extract_text(path: &str, file_type: &str, temp_path: &str) -> Result<String, String> {
+    match file_type {
+        "pdf" => text_via(path, temp_path, |i, o| extract_pdf_text(i, o, &PdfTextOptions::default())),
+        "docx" => text_via(path, temp_path, convert_docx_to_txt),
+        "doc" => text_via(path, temp_path, convert_doc_to_txt),
+        "odt" => text_via(path, temp_path, convert_odt_to_txt),
+        "rtf" => text_via(path, temp_path, convert_rtf_to_txt),
+        "epub" => text_via(path, temp_path, convert_epub_to_txt),
+        _ => {
+            let bytes = fs::read(path).map_err(|e| format!("Failed to read {}: {}", file_name(path), e))?;
+            let text = String::from_utf8_lossy(&bytes).into_owned();
+            // Markdown keeps one block per paragraph, unlike the raw markup
+            Ok(if file_type == "html" { html_to_markdown(&text) } else { text })
+        }
+    }
+}
+
+fn text_via(path: &str, temp_path: &str, convert: impl Fn(&str, &str) -> ConversionResult) -> Result<String, String> {
+    let result = convert(path, temp_path);
+    let text = fs::read_to_string(temp_path);
+    let _ = fs::remove_file(temp_path);
+    if !result.success {
+        return Err(result.error.unwrap_or_else(|| format!("Failed to read {}", file_name(path))));
+    }
+    text.map_err(|e| format!("Failed to read text of {}: {}", file_name(path), e))
+}
+
+/// Lines to compare with their line numbers, normalized as the options ask
+fn comparable_lines(text: &str, ignore_whitespace: bool) -> Vec<(usize, String)> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| {
+            let line = if ignore_whitespace {
+                line.split_whitespace().collect::<Vec<_>>().join(" ")
+            } else {
+                line.trim_end().to_string()
+            };
+            (n + 1, line)
+        })
+        .filter(|(_, line)| !ignore_whitespace || !line.is_empty())
+        .collect()
+}
+
+fn diff_text(old: &str, new: &str, options: &CompareOptions, report: &mut CompareReport) {
+    let ignore_whitespace = options.ignore_whitespace.unwrap_or(true);
+    let ignore_case = options.ignore_case.unwrap_or(false);
+    let old_lines = comparable_lines(old, ignore_whitespace);
+    let new_lines = comparable_lines(new, ignore_whitespace);
+    let key = |lines: &[(usize, String)]| -> Vec<String> {
+        lines
+            .iter()
+            .map(|(_, line)| if ignore_case { line.to_lowercase() } else { line.clone() })
+            .collect()
+    };
+    let (old_keys, new_keys) = (key(&old_lines), key(&new_lines));
+    let ops = capture_diff_slices(Algorithm::Patience, &old_keys, &new_keys);
+
+    for group in group_diff_ops(ops, options.context.unwrap_or(3)) {
+        let mut lines = Vec::new();
+        let line = |kind, old: Option<usize>, new: Option<usize>| DiffLine {
+            kind,
+            old_line: old.map(|i| old_lines[i].0),
+            new_line: new.map(|i| new_lines[i].0),
+            text: match (kind, old, new) {
+                ("delete", Some(i), _) => old_lines[i].1.clone(),
+                (_, _, Some(i)) => new_lines[i].1.clone(),
+                (_, Some(i), None) => old_lines[i].1.clone(),
+                _ => String::new(),
+            },
+            words: Vec::new(),
+        };
+        for op in &group {
+            match *op {
+                DiffOp::Equal { old_index, new_index, len } => {
+                    lines.extend((0..len).map(|i| line("equal", Some(old_index + i), Some(new_index + i))));
+                }
+                DiffOp::Delete { old_index, old_len, .. } => {
+                    report.deleted += old_len;
+                    lines.extend((old_index..old_index + old_len).map(|i| line("delete", Some(i), None)));
+                }
+                DiffOp::Insert { new_index, new_len, .. } => {
+                    report.inserted += new_len;
+                    lines.extend((new_index..new_index + new_len).map(|i| line("insert", None, Some(i))));
+                }
+                DiffOp::Replace { old_index, old_len, new_index, new_len } => {
+                    // Pair the replaced lines in order; similar pairs become
+                    // one changed line
+                    for i in 0..old_len.max(new_len) {
+                        let old = (i < old_len).then_some(old_index + i);
+                        let new = (i < new_len).then_some(new_index + i);
+                        if let (Some(o), Some(n)) = (old, new) {
+                            let words = word_diff(&old_lines[o].1, &new_lines[n].1, ignore_case);
+                            if let Some(words) = words {
+                                report.changed += 1;
+                                lines.push(DiffLine { words, ..line("change", old, new) });
+                                continue;
+                            }
+                        }
+                        if old.is_some() {
+                            report.deleted += 1;
+                            lines.push(line("delete", old, None));
+                        }
+                        if new.is_some() {
+                            report.inserted += 1;
+                            lines.push(line("insert", None, new));
+                        }
+                    }
+                }
+            }
+        }
+        let first_old = lines.iter().find_map(|l| l.old_line);
+        let first_new = lines.iter().find_map(|l| l.new_line);
+        report.hunks.push(Hunk {
+            old_start: first_old.unwrap_or(0),
+            new_start: first_new.unwrap_or(0),
+            lines,
+        });
+    }
+}
+
+/// Word-level edits between two versions of a line, or None when they
+/// have too little in common to read as an edit
+fn word_diff(old: &str, new: &str, ignore_case: bool) -> Option<Vec<DiffSegment>> {
+    // Case-folded text is compared, and each token is mapped back to the
+    // original text to show it; without case folding the maps are empty
+    // and the tokens are the original text already
+    let ((old_key, old_offsets), (new_key, new_offsets)) = if ignore_case {
+        (fold_case(old), fold_case(new))
+    } else {
+        ((old.to_string(), Vec::new()), (new.to_string(), Vec::new()))
+    };
+    let diff = TextDiff::from_words(old_key.as_str(), new_key.as_str());
+    if diff.ratio() < CHANGED_LINE_RATIO {
+        return None;
+    }
+    let mut segments: Vec<DiffSegment> = Vec::new();
+    let (mut old_pos, mut new_pos) = (0, 0);
+    for change in diff.iter_all_changes() {
+        let token = change.value();
+        let (kind, text) = match change.tag() {
+            ChangeTag::Equal => {
+                let text = original_text(new, &new_offsets, new_pos, token);
+                old_pos += token.len();
+                new_pos += token.len();
+                ("equal", text)
+            }
+            ChangeTag::Delete => {
+                let text = original_text(old, &old_offsets, old_pos, token);
+                old_pos += token.len();
+                ("delete", text)
+            }
+            ChangeTag::Insert => {
+                let text = original_text(new, &new_offsets, new_pos, token);
+                new_pos += token.len();
+                ("insert", text)
+            }
+        };
+        match segments.last_mut() {
+            Some(last) if last.kind == kind => last.text.push_str(text),
+            _ => segments.push(DiffSegment { kind, text: text.to_string() }),
+        }
+    }
+    Some(segments)
+}
+
+/// Lowercased text, with the byte offset in `text` for every byte offset
+/// in the lowercased copy. Lowercasing can change a character's length,
+/// so offsets inside a character's lowercase form are `usize::MAX`.
+fn fold_case(text: &str) -> (String, Vec<usize>) {
+    let mut folded = String::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+    for (pos, c) in text.char_indices() {
+        offsets.push(pos);
+        folded.extend(c.to_lowercase());
+        offsets.resize(folded.len(), usize::MAX);
+    }
+    offsets.push(text.len());
+    (folded, offsets)
+}
+
+/// The original text behind a token of case-folded text starting at
+/// `start`, or the token itself when it doesn't map onto whole characters
+fn original_text<'a>(original: &'a str, offsets: &[usize], start: usize, token: &'a str) -> &'a str {
+    match (offsets.get(start), offsets.get(start + token.len())) {
+        (Some(&from), Some(&to)) if from != usize::MAX && to != usize::MAX && from <= to => &original[from..to],
+        _ => token,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Structured data
+// ---------------------------------------------------------------------------
+
+fn read_value(path: &str, file_type: &str) -> Result<Value, String> {
+    let name = file_name(path);
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", name, e))?;
+    match file_type {
+        "json" => serde_json::from_str(&content).map_err(|e| format!("Failed to parse JSON in {}: {}", name, e)),
+        "yaml" => serde_yaml::from_str(&content).map_err(|e| format!("Failed to parse YAML in {}: {}", name, e)),
+        _ => toml::from_str::<toml::Value>(&content)
+            .map(|value| toml_to_json_value(&value))
+            .map_err(|e| format!("Failed to parse TOML in {}: {}", name, e)),
+    }
+}
+
+fn diff_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<ValueChange>) {
+    match (old, new) {
+        (Value::Object(old_map), Value::Object(new_map)) => {
+            for (key, old_value) in old_map {
+                let child = child_path(path, key);
+                match new_map.get(key) {
+                    Some(new_value) => diff_values(&child, old_value, new_value, changes),
+                    None => changes.push(ValueChange { path: child, kind: "removed", old: Some(old_value.clone()), new: None }),
+                }
+            }
+            for (key, new_value) in new_map.iter().filter(|(key, _)| !old_map.contains_key(*key)) {
+                changes.push(ValueChange {
+                    path: child_path(path, key),
+                    kind: "added",
+                    old: None,
+                    new: Some(new_value.clone()),
+                });
+            }
+        }
+        (Value::Array(old_items), Value::Array(new_items)) => diff_arrays(path, old_items, new_items, changes),
+        _ if old == new => {}
+        _ => changes.push(ValueChange {
+            path: path.to_string(),
+            kind: "changed",
+            old: Some(old.clone()),
+            new: Some(new.clone()),
+        }),
+    }
+}
+
+/// Match array items by content so an insertion doesn't show as every
+/// later item changing; replaced items are compared in place
+fn diff_arrays(path: &str, old: &[Value], new: &[Value], changes: &mut Vec<ValueChange>) {
+    let key = |items: &[Value]| -> Vec<String> { items.iter().map(|v| v.to_string()).collect() };
+    let item = |index: usize| format!("{}[{}]", path, index);
+    for op in capture_diff_slices(Algorithm::Myers, &key(old), &key(new)) {
+        match op {
+            DiffOp::Equal { .. } => {}
+            DiffOp::Delete { old_index, old_len, .. } => {
+                for (i, value) in old.iter().enumerate().skip(old_index).take(old_len) {
+                    changes.push(ValueChange { path: item(i), kind: "removed", old: Some(value.clone()), new: None });
+                }
+            }
+            DiffOp::Insert { new_index, new_len, .. } => {
+                for (i, value) in new.iter().enumerate().skip(new_index).take(new_len) {
+                    changes.push(ValueChange { path: item(i), kind: "added", old: None, new: Some(value.clone()) });
+                }
+            }
+            DiffOp::Replace { old_index, old_len, new_index, new_len } => {
+                for i in 0..old_len.max(new_len) {
+                    match (old.get(old_index + i).filter(|_| i < old_len), new.get(new_index + i).filter(|_| i < new_len)) {
+                        (Some(o), Some(n)) => diff_values(&item(new_index + i), o, n, changes),
+                        (Some(o), None) => {
+                            changes.push(ValueChange { path: item(old_index + i), kind: "removed", old: Some(o.clone()), new: None })
+                        }
+                        (None, Some(n)) => {
+                            changes.push(ValueChange { path: item(new_index + i), kind: "added", old: None, new: Some(n.clone()) })
+                        }
+                        (None, None) => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    let plain = key.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if plain {
+        format!("{}.{}", path, key)
+    } else {
+        format!("{}[{}]", path, Value::String(key.to_string()))
+    }
+}
+
+// ---------------------------------------------------------------------------
+// HTML report
+// ---------------------------------------------------------------------------
+
+fn html_report(report: &CompareReport) -> String {
+    let mut body = format!(
+        "<h1>{} &rarr; {}</h1>\n",
+        escape_markup(&report.old_file),
+        escape_markup(&report.new_file)
+    );
+    if report.identical {
+        body.push_str("<p class=\"summary\">No differences found.</p>\n");
+    } else {
+        body.push_str(&format!(
+            "<p class=\"summary\"><span class=\"ins\">{} inserted</span> <span class=\"del\">{} deleted</span> <span class=\"chg\">{} changed</span></p>\n",
+            report.inserted, report.deleted, report.changed
+        ));
+    }
+
+    if !report.hunks.is_empty() {
+        body.push_str("<table class=\"diff\">\n");
+        for hunk in &report.hunks {
+            body.push_str(&format!(
+                "<tr class=\"hunk\"><td colspan=\"4\">Line {} &rarr; line {}</td></tr>\n",
+                hunk.old_start, hunk.new_start
+            ));
+            for line in &hunk.lines {
+                let number = |n: Option<usize>| n.map(|n| n.to_string()).unwrap_or_default();
+                let (marker, text) = match line.kind {
+                    "delete" => ("&minus;", format!("<del>{}</del>", escape_markup(&line.text))),
+                    "insert" => ("+", format!("<ins>{}</ins>", escape_markup(&line.text))),
+                    "change" => ("~", html_words(&line.words)),
+                    _ => ("", escape_markup(&line.text)),
+                };
+                body.push_str(&format!(
+                    "<tr class=\"{}\"><td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"mark\">{}</td><td>{}</td></tr>\n",
+                    line.kind,
+                    number(line.old_line),
+                    number(line.new_line),
+                    marker,
+                    text
+                ));
+            }
+        }
+        body.push_str("</table>\n");
+    }
+
+    if !report.changes.is_empty() {
+        body.push_str("<table class=\"changes\">\n<tr><th>Path</th><th>Change</th><th>Old value</th><th>New value</th></tr>\n");
+        for change in &report.changes {
+            let value = |v: &Option<Value>| {
+                v.as_ref()
+                    .map(|v| format!("<code>{}</code>", escape_markup(&v.to_string())))
+                    .unwrap_or_default()
+            };
+            body.push_str(&format!(
+                "<tr class=\"{}\"><td><code>{}</code></td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                change.kind,
+                escape_markup(&change.path),
+                change.kind,
+                value(&change.old),
+                value(&change.new)
+            ));
+        }
+        body.push_str("</table>\n");
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Comparison of {} and {}</title>
+    <style>
+        body {{
+            font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif;
+            max-width: 1000px;
+            margin: 40px auto;
+            padding: 20px;
+            line-height: 1.5;
+        }}
+        h1 {{ font-size: 1.4em; }}
+        .summary span {{ margin-right: 1em; font-weight: 600; }}
+        .summary .ins {{ color: #1a7f37; }}
+        .summary .del {{ color: #cf222e; }}
+        .summary .chg {{ color: #9a6700; }}
+        table {{ border-collapse: collapse; width: 100%; margin: 20px 0; }}
+        td, th {{ padding: 4px 8px; vertical-align: top; text-align: left; }}
+        .diff {{ font-family: ui-monospace, Menlo, Consolas, monospace; font-size: 0.9em; }}
+        .diff td {{ white-space: pre-wrap; word-break: break-word; }}
+        .diff .num {{ color: #888; text-align: right; width: 3em; user-select: none; }}
+        .diff .mark {{ width: 1em; color: #888; }}
+        .hunk td {{ background: #f0f4f8; color: #555; }}
+        tr.delete {{ background: #ffebe9; }}
+        tr.insert {{ background: #e6ffec; }}
+        tr.change {{ background: #fff8c5; }}
+        del {{ background: #ffcecb; text-decoration: line-through; }}
+        ins {{ background: #aceebb; text-decoration: none; }}
+        .changes th, .changes td {{ border: 1px solid #ddd; }}
+        .changes th {{ background: #f5f5f5; }}
+        .changes tr.removed {{ background: #ffebe9; }}
+        .changes tr.added {{ background: #e6ffec; }}
+        .changes tr.changed {{ background: #fff8c5; }}
+    </style>
+</head>
+<body>
+{}</body>
+</html>"#,
+        escape_markup(&report.old_file),
+        escape_markup(&report.new_file),
+        body
+    )
+}
+
+fn html_words(words: &[DiffSegment]) -> String {
+    words
+        .iter()
+        .map(|segment| match segment.kind {
+            "delete" => format!("<del>{}</del>", escape_markup(&segment.text)),
+            "insert" => format!("<ins>{}</ins>", escape_markup(&segment.text)),
+            _ => escape_markup(&segment.text),
+        })
+        .collect()
+}
+
@@ -0,0 +1,2548 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use base64::Engine;
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, Stream};
+use resvg::tiny_skia;
+use resvg::usvg::{self, fontdb};
+use crate::ConversionResult;
+use crate::converters::embedded_images::percent_decode;
+use crate::converters::image_converter::pixmap_to_rgba;
+use crate::converters::pdf_converter::pdf_date_now;
+use crate::converters::pdf_renderer::find_system_font;
+use crate::converters::pdf_tools::{finish, save_pdf};
+use crate::converters::text_tools::{escape_markup, list_number};
+
+// A4 with 20 mm margins, in points
+const PAGE_WIDTH: f32 = 595.28;
+const PAGE_HEIGHT: f32 = 841.89;
+const MARGIN: f32 = 56.69;
+const CONTENT_WIDTH: f32 = PAGE_WIDTH - 2.0 * MARGIN;
+const CONTENT_HEIGHT: f32 = PAGE_HEIGHT - 2.0 * MARGIN;
+
+const BASE_FONT_SIZE: f32 = 11.0;
+const LINE_HEIGHT: f32 = 1.4;
+const PARAGRAPH_SPACING: f32 = 7.0;
+const LIST_INDENT: f32 = 22.0;
+const CELL_PADDING: f32 = 5.0;
+
+const TEXT_COLOR: [f32; 3] = [0.13, 0.13, 0.13];
+const LINK_COLOR: [f32; 3] = [0.02, 0.33, 0.8];
+const MUTED_COLOR: [f32; 3] = [0.4, 0.4, 0.4];
+const BORDER_COLOR: [f32; 3] = [0.75, 0.75, 0.75];
+const SHADE_COLOR: [f32; 3] = [0.95, 0.95, 0.95];
+
+/// Elements that start a new block instead of flowing inside a line
+const BLOCK_ELEMENTS: [&str; 40] = [
+    "address", "article", "aside", "blockquote", "body", "caption", "center", "dd", "details", "div", "dl", "dt",
+    "fieldset", "figcaption", "figure", "footer", "form", "h1", "h2", "h3", "h4", "h5", "h6", "header", "hr",
+    "html", "li", "main", "nav", "ol", "p", "pre", "section", "summary", "table", "tbody", "td", "tfoot", "th", "ul",
+];
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source", "track", "wbr",
+];
+/// Elements whose content is never laid out
+pub const HIDDEN_ELEMENTS: [&str; 8] = ["head", "script", "style", "title", "template", "noscript", "svg", "math"];
+
+/// Lay out an HTML file as an A4 PDF: headings, paragraphs, bold and
+/// italic text, lists, tables, code blocks, images and links
+pub fn convert_html_to_pdf(input_path: &str, output_path: &str) -> ConversionResult {
+    let base_dir = Path::new(input_path).parent().unwrap_or(Path::new(""));
+    finish(try_convert_html_to_pdf(input_path, base_dir, output_path), output_path)
+}
+
+/// Like convert_html_to_pdf, but resolves relative image paths against
+/// base_dir, for HTML that was generated next to the output
+pub fn convert_html_to_pdf_with_base(input_path: &str, base_dir: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_html_to_pdf(input_path, Path::new(base_dir), output_path), output_path)
+}
+
+fn try_convert_html_to_pdf(input_path: &str, base_dir: &Path, output_path: &str) -> Result<String, String> {
+    let bytes = fs::read(input_path).map_err(|e| format!("Failed to read HTML file: {}", e))?;
+    let pages = render_html_to_pdf(&String::from_utf8_lossy(&bytes), base_dir, output_path)?;
+    Ok(format!("Rendered {} page(s)", pages))
+}
+
+/// Render an HTML document to a PDF file and return the number of pages
+pub fn render_html_to_pdf(html: &str, base_dir: &Path, output_path: &str) -> Result<usize, String> {
+    let root = parse_html(html);
+    let body = find_element(&root, "body").unwrap_or(&root);
+
+    let mut layout = Layout {
+        fonts: FontSet::load()?,
+        images: ImageSet { base_dir: base_dir.to_path_buf(), images: Vec::new(), by_source: HashMap::new() },
+        pending_anchors: Vec::new(),
+        longest_word: 0.0,
+        measuring: false,
+    };
+    let mut blocks = Vec::new();
+    layout.block_children(body, &TextStyle::default(), 0.0, CONTENT_WIDTH, &mut blocks, 0);
+    let paged = paginate(blocks);
+
+    let title = find_element(&root, "title")
+        .map(|t| collapse_whitespace(&text_content(t)))
+        .filter(|t| !t.is_empty())
+        .or_else(|| paged.headings.iter().find(|h| h.level == 1).map(|h| h.title.clone()));
+    write_pdf(paged, layout.fonts, layout.images, title, output_path)
+}
+
+// ---------------------------------------------------------------------------
+// HTML parsing
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone)]
+pub enum HtmlNode {
+    Element(HtmlElement),
+    Text(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct HtmlElement {
+    pub tag: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<HtmlNode>,
+}
+
+impl HtmlElement {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// A property from the inline style attribute
+    pub fn style(&self, property: &str) -> Option<String> {
+        self.attr("style")?
+            .split(';')
+            .filter_map(|d| d.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(property))
+            .map(|(_, value)| value.trim().trim_end_matches("!important").trim().to_lowercase())
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &HtmlElement> {
+        self.children.iter().filter_map(|c| match c {
+            HtmlNode::Element(e) => Some(e),
+            HtmlNode::Text(_) => None,
+        })
+    }
+}
+
+/// A forgiving HTML parser: unknown markup is skipped, unclosed elements
+/// are closed where browsers would close them
+pub fn parse_html(html: &str) -> HtmlElement {
+    let mut stack = vec![HtmlElement { tag: "#root".to_string(), attrs: Vec::new(), children: Vec::new() }];
+    let mut pos = 0;
+    while pos < html.len() {
+        let rest = &html[pos..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            pos += 4 + comment.find("-->").map(|i| i + 3).unwrap_or(comment.len());
+        } else if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let end = cdata.find("]]>").unwrap_or(cdata.len());
+            push_node(&mut stack, HtmlNode::Text(cdata[..end].to_string()));
+            pos += 9 + (end + 3).min(cdata.len());
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            pos += rest.find('>').map(|i| i + 1).unwrap_or(rest.len());
+        } else if rest.starts_with("</") {
+            let end = rest.find('>').map(|i| i + 1).unwrap_or(rest.len());
+            close_element(&mut stack, &tag_name(&rest[2..end]));
+            pos += end;
+        } else if rest.starts_with('<') && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let (element, self_closing, length) = parse_tag(rest);
+            pos += length;
+            let tag = element.tag.clone();
+            close_implied(&mut stack, &tag);
+            if matches!(tag.as_str(), "script" | "style" | "title" | "textarea") && !self_closing {
+                // Raw text runs to the matching end tag
+                let end = html[pos..].to_ascii_lowercase().find(&format!("</{}", tag)).map(|i| pos + i).unwrap_or(html.len());
+                let mut element = element;
+                element.children.push(HtmlNode::Text(decode_entities(&html[pos..end])));
+                push_node(&mut stack, HtmlNode::Element(element));
+                pos = html[end..].find('>').map(|i| end + i + 1).unwrap_or(html.len());
+            } else if self_closing || VOID_ELEMENTS.contains(&tag.as_str()) {
+                push_node(&mut stack, HtmlNode::Element(element));
+            } else {
+                stack.push(element);
+            }
+        } else {
+            let skip = rest.chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+            let end = rest[skip..].find('<').map(|i| i + skip).unwrap_or(rest.len());
+            push_node(&mut stack, HtmlNode::Text(decode_entities(&rest[..end])));
+            pos += end;
+        }
+    }
+    while stack.len() > 1 {
+        pop_element(&mut stack);
+    }
+    stack.pop().unwrap()
+}
+
+fn tag_name(s: &str) -> String {
+    let name: String = s
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | ':' | '_'))
+        .collect::<String>()
+        .to_ascii_lowercase();
+    // XHTML may prefix the namespace, as in <html:p>
+    name.rsplit(':').next().unwrap_or_default().to_string()
+}
+
+/// Parse a start tag; returns the element, whether it ends with "/>" and
+/// the number of bytes consumed
+fn parse_tag(s: &str) -> (HtmlElement, bool, usize) {
+    let bytes = s.as_bytes();
+    let tag = tag_name(&s[1..]);
+    let mut i = 1 + s[1..].find(|c: char| c.is_whitespace() || c == '>' || c == '/').unwrap_or(s.len() - 1);
+    let mut attrs = Vec::new();
+    let mut self_closing = false;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'>' => {
+                i += 1;
+                break;
+            }
+            b'/' => {
+                self_closing = bytes.get(i + 1) == Some(&b'>');
+                i += 1;
+            }
+            c if c.is_ascii_whitespace() => i += 1,
+            _ => {
+                let start = i;
+                while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !matches!(bytes[i], b'=' | b'>' | b'/') {
+                    i += 1;
+                }
+                let name = s[start..i].to_ascii_lowercase();
+                while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                let mut value = String::new();
+                if bytes.get(i) == Some(&b'=') {
+                    i += 1;
+                    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                        i += 1;
+                    }
+                    match bytes.get(i) {
+                        Some(&quote) if quote == b'"' || quote == b'\'' => {
+                            let end = s[i + 1..].find(quote as char).map(|e| i + 1 + e).unwrap_or(s.len());
+                            value = decode_entities(&s[i + 1..end]);
+                            i = (end + 1).min(s.len());
+                        }
+                        _ => {
+                            let start = i;
+                            while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+                                i += 1;
+                            }
+                            value = decode_entities(&s[start..i]);
+                        }
+                    }
+                }
+                attrs.push((name, value));
+            }
+        }
+    }
+    (HtmlElement { tag, attrs, children: Vec::new() }, self_closing, i.min(s.len()))
+}
+
+fn push_node(stack: &mut [HtmlElement], node: HtmlNode) {
+    stack.last_mut().unwrap().children.push(node);
+}
+
+fn pop_element(stack: &mut Vec<HtmlElement>) {
+    let element = stack.pop().unwrap();
+    push_node(stack, HtmlNode::Element(element));
+}
+
+fn close_element(stack: &mut Vec<HtmlElement>, tag: &str) {
+    if let Some(index) = stack.iter().skip(1).rposition(|e| e.tag == tag) {
+        while stack.len() > index + 1 {
+            pop_element(stack);
+        }
+    }
+}
+
+/// Close the elements that a new start tag ends implicitly, such as an
+/// open paragraph before a list or the previous item of a list
+fn close_implied(stack: &mut Vec<HtmlElement>, tag: &str) {
+    let (targets, boundaries): (&[&str], &[&str]) = match tag {
+        "li" => (&["li"], &["ul", "ol", "table"]),
+        "dt" | "dd" => (&["dt", "dd"], &["dl", "table"]),
+        "tr" => (&["tr", "td", "th"], &["table", "thead", "tbody", "tfoot"]),
+        "td" | "th" => (&["td", "th"], &["tr", "table"]),
+        "thead" | "tbody" | "tfoot" => (&["thead", "tbody", "tfoot", "tr", "td", "th"], &["table"]),
+        _ => (&[], &[]),
+    };
+    // The outermost match wins, so a new row also closes the open cell
+    let mut close_from = None;
+    for index in (1..stack.len()).rev() {
+        let open = stack[index].tag.as_str();
+        if boundaries.contains(&open) {
+            break;
+        }
+        if targets.contains(&open) {
+            close_from = Some(index);
+        }
+    }
+    if let Some(index) = close_from {
+        while stack.len() > index {
+            pop_element(stack);
+        }
+    }
+    let closes_paragraph = BLOCK_ELEMENTS.contains(&tag) && !matches!(tag, "caption" | "td" | "th" | "tbody" | "tfoot");
+    if closes_paragraph && stack.last().map(|e| e.tag == "p").unwrap_or(false) {
+        pop_element(stack);
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let end = rest[1..]
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '#')
+            .map(|e| e + 1)
+            .unwrap_or(rest.len());
+        let name = &rest[1..end];
+        let decoded = match name.strip_prefix('#') {
+            Some(number) => match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => number.parse().ok(),
+            }
+            .and_then(char::from_u32),
+            None => named_entity(name),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end..];
+                rest = rest.strip_prefix(';').unwrap_or(rest);
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn named_entity(name: &str) -> Option<char> {
+    Some(match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "shy" => '\u{ad}',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "hellip" => '…',
+        "mdash" => '—',
+        "ndash" => '–',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "sbquo" => '‚',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "bdquo" => '„',
+        "laquo" => '«',
+        "raquo" => '»',
+        "bull" => '•',
+        "middot" => '·',
+        "euro" => '€',
+        "pound" => '£',
+        "yen" => '¥',
+        "cent" => '¢',
+        "sect" => '§',
+        "para" => '¶',
+        "deg" => '°',
+        "plusmn" => '±',
+        "times" => '×',
+        "divide" => '÷',
+        "micro" => 'µ',
+        "frac12" => '½',
+        "frac14" => '¼',
+        "frac34" => '¾',
+        "larr" => '←',
+        "rarr" => '→',
+        "uarr" => '↑',
+        "darr" => '↓',
+        "check" => '✓',
+        "dagger" => '†',
+        "Dagger" => '‡',
+        "iexcl" => '¡',
+        "iquest" => '¿',
+        "thinsp" => '\u{2009}',
+        "ensp" => '\u{2002}',
+        "emsp" => '\u{2003}',
+        "zwsp" => '\u{200b}',
+        _ => return None,
+    })
+}
+
+pub fn find_element<'a>(element: &'a HtmlElement, tag: &str) -> Option<&'a HtmlElement> {
+    element.elements().find_map(|e| if e.tag == tag { Some(e) } else { find_element(e, tag) })
+}
+
+pub fn text_content(element: &HtmlElement) -> String {
+    element
+        .children
+        .iter()
+        .map(|c| match c {
+            HtmlNode::Text(t) => t.clone(),
+            HtmlNode::Element(e) if HIDDEN_ELEMENTS.contains(&e.tag.as_str()) => String::new(),
+            HtmlNode::Element(e) => text_content(e),
+        })
+        .collect()
+}
+
+/// Serialize parsed nodes back to HTML markup
+pub fn html_markup(nodes: &[HtmlNode], out: &mut String) {
+    for node in nodes {
+        match node {
+            HtmlNode::Text(text) => out.push_str(&escape_markup(text)),
+            HtmlNode::Element(e) => {
+                out.push('<');
+                out.push_str(&e.tag);
+                for (name, value) in &e.attrs {
+                    out.push_str(&format!(" {}=\"{}\"", name, escape_markup(value)));
+                }
+                out.push('>');
+                if !VOID_ELEMENTS.contains(&e.tag.as_str()) {
+                    if matches!(e.tag.as_str(), "script" | "style") {
+                        // Raw text elements are kept verbatim
+                        e.children.iter().for_each(|c| if let HtmlNode::Text(t) = c { out.push_str(t) });
+                    } else {
+                        html_markup(&e.children, out);
+                    }
+                    out.push_str(&format!("</{}>", e.tag));
+                }
+            }
+        }
+    }
+}
+
+pub fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+pub fn is_block(element: &HtmlElement) -> bool {
+    BLOCK_ELEMENTS.contains(&element.tag.as_str()) || element.tag == "tr"
+}
+
+// ---------------------------------------------------------------------------
+// Styles
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextStyle {
+    pub size: f32,
+    pub bold: bool,
+    pub italic: bool,
+    pub mono: bool,
+    pub color: [f32; 3],
+    pub underline: bool,
+    pub strike: bool,
+    pub background: Option<[f32; 3]>,
+    pub link: Option<String>,
+    /// Baseline shift for superscripts and subscripts
+    pub rise: f32,
+    pub pre: bool,
+    pub align: TextAlign,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        TextStyle {
+            size: BASE_FONT_SIZE,
+            bold: false,
+            italic: false,
+            mono: false,
+            color: TEXT_COLOR,
+            underline: false,
+            strike: false,
+            background: None,
+            link: None,
+            rise: 0.0,
+            pre: false,
+            align: TextAlign::Left,
+        }
+    }
+}
+
+/// The style an element gives its content: the browser defaults for the
+/// tag, then its align attribute and inline style
+pub fn element_style(element: &HtmlElement, parent: &TextStyle) -> TextStyle {
+    let mut style = parent.clone();
+    // Backgrounds only apply to the inline element that sets them
+    style.background = None;
+    match element.tag.as_str() {
+        "b" | "strong" | "th" | "dt" => style.bold = true,
+        "i" | "em" | "cite" | "var" | "dfn" | "address" => style.italic = true,
+        "u" | "ins" => style.underline = true,
+        "s" | "strike" | "del" => style.strike = true,
+        "code" | "kbd" | "samp" | "tt" if !parent.mono => {
+            style.mono = true;
+            style.size *= 0.9;
+            if !parent.pre {
+                style.background = Some(SHADE_COLOR);
+            }
+        }
+        "pre" => {
+            style.mono = true;
+            style.pre = true;
+            style.size = parent.size * 0.88;
+        }
+        "a" => {
+            if let Some(href) = element.attr("href").map(str::trim).filter(|h| !h.is_empty()) {
+                style.link = Some(href.to_string());
+                style.color = LINK_COLOR;
+                style.underline = true;
+            }
+        }
+        "sup" => {
+            style.rise += parent.size * 0.35;
+            style.size *= 0.75;
+        }
+        "sub" => {
+            style.rise -= parent.size * 0.15;
+            style.size *= 0.75;
+        }
+        "small" => style.size *= 0.85,
+        "big" => style.size *= 1.2,
+        "mark" => style.background = Some([1.0, 0.95, 0.6]),
+        "blockquote" => style.color = MUTED_COLOR,
+        "center" => style.align = TextAlign::Center,
+        "figcaption" | "caption" => {
+            style.italic = true;
+            style.size *= 0.9;
+            style.color = MUTED_COLOR;
+            style.align = TextAlign::Center;
+        }
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let scale = [2.0, 1.6, 1.3, 1.15, 1.0, 0.9][(element.tag.as_bytes()[1] - b'1') as usize];
+            style.size = BASE_FONT_SIZE * scale;
+            style.bold = true;
+        }
+        "font" => {
+            if let Some(color) = element.attr("color").and_then(parse_color) {
+                style.color = color;
+            }
+        }
+        _ => {}
+    }
+    // Block-level text alignment is inherited, so only block elements reset it
+    if let Some(align) = element.attr("align").and_then(parse_align) {
+        style.align = align;
+    }
+
+    if let Some(color) = element.style("color").as_deref().and_then(parse_color) {
+        style.color = color;
+    }
+    if let Some(background) = element.style("background-color").or_else(|| element.style("background")) {
+        if !is_block(element) {
+            style.background = parse_color(&background);
+        }
+    }
+    match element.style("font-weight").as_deref() {
+        Some("bold" | "bolder" | "600" | "700" | "800" | "900") => style.bold = true,
+        Some("normal" | "lighter" | "100" | "200" | "300" | "400" | "500") => style.bold = false,
+        _ => {}
+    }
+    match element.style("font-style").as_deref() {
+        Some("italic" | "oblique") => style.italic = true,
+        Some("normal") => style.italic = false,
+        _ => {}
+    }
+    if let Some(decoration) = element.style("text-decoration").or_else(|| element.style("text-decoration-line")) {
+        if decoration.contains("none") {
+            style.underline = false;
+            style.strike = false;
+        }
+        style.underline |= decoration.contains("underline");
+        style.strike |= decoration.contains("line-through");
+    }
+    if let Some(align) = element.style("text-align").as_deref().and_then(parse_align) {
+        style.align = align;
+    }
+    if let Some(family) = element.style("font-family") {
+        if ["mono", "courier", "consolas", "menlo"].iter().any(|m| family.contains(m)) {
+            style.mono = true;
+        }
+    }
+    if let Some(size) = element.style("font-size").as_deref().and_then(|v| font_size(v, parent.size)) {
+        style.size = size.clamp(4.0, 72.0);
+    }
+    style
+}
+
+fn parse_align(value: &str) -> Option<TextAlign> {
+    match value.trim().to_lowercase().as_str() {
+        "left" | "start" | "justify" => Some(TextAlign::Left),
+        "center" | "middle" => Some(TextAlign::Center),
+        "right" | "end" => Some(TextAlign::Right),
+        _ => None,
+    }
+}
+
+fn parse_color(value: &str) -> Option<[f32; 3]> {
+    let value = value.trim().to_lowercase();
+    if let Some(hex) = value.strip_prefix('#') {
+        let digits: Vec<u32> = hex.chars().map(|c| c.to_digit(16)).collect::<Option<_>>()?;
+        let channels: Vec<f32> = match digits.len() {
+            3 | 4 => digits[..3].iter().map(|d| (d * 17) as f32 / 255.0).collect(),
+            6 | 8 => digits[..6].chunks(2).map(|p| (p[0] * 16 + p[1]) as f32 / 255.0).collect(),
+            _ => return None,
+        };
+        return Some([channels[0], channels[1], channels[2]]);
+    }
+    if let Some(args) = value.strip_prefix("rgb(").or_else(|| value.strip_prefix("rgba(")) {
+        let parts: Vec<f32> = args
+            .trim_end_matches(')')
+            .split([',', ' ', '/'])
+            .filter(|p| !p.is_empty())
+            .take(3)
+            .map(|p| match p.strip_suffix('%') {
+                Some(percent) => percent.parse::<f32>().map(|v| v / 100.0),
+                None => p.parse::<f32>().map(|v| v / 255.0),
+            })
+            .collect::<Result<_, _>>()
+            .ok()?;
+        return (parts.len() == 3).then(|| [parts[0].clamp(0.0, 1.0), parts[1].clamp(0.0, 1.0), parts[2].clamp(0.0, 1.0)]);
+    }
+    let hex = match value.as_str() {
+        "black" => 0x000000,
+        "white" => 0xffffff,
+        "red" => 0xff0000,
+        "green" => 0x008000,
+        "blue" => 0x0000ff,
+        "gray" | "grey" => 0x808080,
+        "silver" => 0xc0c0c0,
+        "maroon" => 0x800000,
+        "purple" => 0x800080,
+        "navy" => 0x000080,
+        "teal" => 0x008080,
+        "olive" => 0x808000,
+        "orange" => 0xffa500,
+        "yellow" => 0xffff00,
+        "lime" => 0x00ff00,
+        "aqua" | "cyan" => 0x00ffff,
+        "fuchsia" | "magenta" => 0xff00ff,
+        _ => return None,
+    };
+    Some([((hex >> 16) & 255) as f32 / 255.0, ((hex >> 8) & 255) as f32 / 255.0, (hex & 255) as f32 / 255.0])
+}
+
+/// A CSS length in points, or None for percentages, "auto" and the like
+pub fn css_length(value: &str) -> Option<f32> {
+    let value = value.trim().to_lowercase();
+    let (number, scale) = if let Some(n) = value.strip_suffix("px") {
+        (n, 0.75)
+    } else if let Some(n) = value.strip_suffix("pt") {
+        (n, 1.0)
+    } else if let Some(n) = value.strip_suffix("mm") {
+        (n, 72.0 / 25.4)
+    } else if let Some(n) = value.strip_suffix("cm") {
+        (n, 72.0 / 2.54)
+    } else if let Some(n) = value.strip_suffix("in") {
+        (n, 72.0)
+    } else {
+        // Bare numbers, as in width="300", are pixels
+        (value.as_str(), 0.75)
+    };
+    number.trim().parse::<f32>().ok().filter(|v| *v > 0.0).map(|v| v * scale)
+}
+
+fn font_size(value: &str, parent: f32) -> Option<f32> {
+    let value = value.trim();
+    if let Some(em) = value.strip_suffix("rem") {
+        return em.parse::<f32>().ok().map(|v| v * BASE_FONT_SIZE);
+    }
+    if let Some(em) = value.strip_suffix("em") {
+        return em.parse::<f32>().ok().map(|v| v * parent);
+    }
+    if let Some(percent) = value.strip_suffix('%') {
+        return percent.parse::<f32>().ok().map(|v| v * parent / 100.0);
+    }
+    match value {
+        "smaller" | "small" => Some(parent * 0.85),
+        "larger" | "large" => Some(parent * 1.2),
+        "x-large" => Some(BASE_FONT_SIZE * 1.5),
+        "xx-large" => Some(BASE_FONT_SIZE * 2.0),
+        _ => css_length(value),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Layout
+// ---------------------------------------------------------------------------
+
+/// Something to draw, positioned relative to the top left of its line,
+/// with y growing downwards
+#[derive(Debug, Clone)]
+enum Draw {
+    Text { x: f32, y: f32, face: usize, size: f32, color: [f32; 3], text: String, width: f32 },
+    Rect { x: f32, y: f32, w: f32, h: f32, color: [f32; 3] },
+    Rule { x1: f32, y1: f32, x2: f32, y2: f32, width: f32, color: [f32; 3] },
+    Image { x: f32, y: f32, w: f32, h: f32, image: usize },
+    Link { x: f32, y: f32, w: f32, h: f32, target: String },
+}
+
+impl Draw {
+    fn shift(&mut self, dx: f32, dy: f32) {
+        match self {
+            Draw::Text { x, y, .. } | Draw::Rect { x, y, .. } | Draw::Image { x, y, .. } | Draw::Link { x, y, .. } => {
+                *x += dx;
+                *y += dy;
+            }
+            Draw::Rule { x1, y1, x2, y2, .. } => {
+                *x1 += dx;
+                *x2 += dx;
+                *y1 += dy;
+                *y2 += dy;
+            }
+        }
+    }
+
+    /// Right edge of the content, for measuring table columns
+    fn right(&self) -> f32 {
+        match self {
+            Draw::Text { x, width, .. } => x + width,
+            Draw::Image { x, w, .. } => x + w,
+            _ => 0.0,
+        }
+    }
+}
+
+/// One unbreakable strip of the page: a line of text, a table row (or a
+/// page-sized strip of a taller one) or a rule
+#[derive(Debug, Default)]
+struct Line {
+    height: f32,
+    baseline: f32,
+    draws: Vec<Draw>,
+    /// Element ids that link targets can point at
+    anchors: Vec<String>,
+    heading: Option<(u8, String)>,
+    /// Headings stay on the same page as the line after them
+    keep_with_next: bool,
+}
+
+#[derive(Debug)]
+enum Block {
+    Line(Line),
+    /// Vertical margin; adjacent margins collapse and are dropped at the top of a page
+    Space(f32),
+    PageBreak,
+}
+
+enum Inline {
+    Text(String, TextStyle),
+    Image { image: usize, width: f32, height: f32, style: TextStyle },
+    /// A forced line break; soft breaks only end lines that have content
+    Break { size: f32, hard: bool },
+    Anchor(String),
+}
+
+enum Atom {
+    Word { text: String, style: TextStyle, face: usize, width: f32 },
+    Space { style: TextStyle, face: usize, width: f32 },
+    Image { image: usize, width: f32, height: f32, style: TextStyle },
+    Break { size: f32, hard: bool },
+    Anchor(String),
+}
+
+impl Atom {
+    fn width(&self) -> f32 {
+        match self {
+            Atom::Word { width, .. } | Atom::Space { width, .. } | Atom::Image { width, .. } => *width,
+            _ => 0.0,
+        }
+    }
+}
+
+struct Layout {
+    fonts: FontSet,
+    images: ImageSet,
+    /// Ids of block elements waiting for their first line
+    pending_anchors: Vec<String>,
+    longest_word: f32,
+    /// Set while finding the natural widths of table cells
+    measuring: bool,
+}
+
+impl Layout {
+    /// Lay out the children of a block element. Runs of inline content
+    /// become lines; block children are laid out in turn.
+    fn block_children(&mut self, element: &HtmlElement, style: &TextStyle, x: f32, width: f32, out: &mut Vec<Block>, depth: usize) {
+        let mut inlines = Vec::new();
+        for child in &element.children {
+            match child {
+                HtmlNode::Text(text) => inlines.push(Inline::Text(text.clone(), style.clone())),
+                HtmlNode::Element(e) if is_block(e) => {
+                    self.flow(&mut inlines, x, width, style.align, out);
+                    self.block(e, style, x, width, out, depth);
+                }
+                HtmlNode::Element(e) => self.inline(e, style, &mut inlines),
+            }
+        }
+        self.flow(&mut inlines, x, width, style.align, out);
+    }
+
+    fn block(&mut self, element: &HtmlElement, parent: &TextStyle, x: f32, width: f32, out: &mut Vec<Block>, depth: usize) {
+        let tag = element.tag.as_str();
+        if HIDDEN_ELEMENTS.contains(&tag) {
+            return;
+        }
+        let style = element_style(element, parent);
+        let page_break = element.style("page-break-before").as_deref() == Some("always")
+            || element.style("break-before").as_deref() == Some("page");
+        if page_break {
+            out.push(Block::PageBreak);
+        }
+        if let Some(id) = element.attr("id") {
+            self.pending_anchors.push(id.to_string());
+        }
+
+        match tag {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                push_space(out, style.size * 0.8);
+                let start = out.len();
+                self.block_children(element, &style, x, width, out, depth);
+                let title = collapse_whitespace(&text_content(element));
+                let mut first = true;
+                for block in &mut out[start..] {
+                    if let Block::Line(line) = block {
+                        line.keep_with_next = true;
+                        if first && !title.is_empty() {
+                            line.heading = Some((tag.as_bytes()[1] - b'0', title.clone()));
+                            first = false;
+                        }
+                    }
+                }
+                push_space(out, style.size * 0.35);
+            }
+            "p" | "figure" | "dl" | "address" | "details" | "fieldset" => {
+                push_space(out, PARAGRAPH_SPACING);
+                self.block_children(element, &style, x, width, out, depth);
+                push_space(out, PARAGRAPH_SPACING);
+            }
+            "ul" | "ol" => self.list(element, &style, x, width, out, depth),
+            "li" => self.list_item(element, &style, "•".to_string(), x, width, out, depth),
+            "dd" => self.block_children(element, &style, x + LIST_INDENT, width - LIST_INDENT, out, depth),
+            "blockquote" => {
+                push_space(out, PARAGRAPH_SPACING);
+                let mut inner = Vec::new();
+                self.block_children(element, &style, x + 16.0, width - 16.0, &mut inner, depth);
+                trim_spaces(&mut inner);
+                for block in inner {
+                    // Margins inside the quote keep the bar continuous
+                    let mut line = match block {
+                        Block::Line(line) => line,
+                        Block::Space(height) => Line { height, ..Default::default() },
+                        Block::PageBreak => {
+                            out.push(Block::PageBreak);
+                            continue;
+                        }
+                    };
+                    line.draws.insert(0, Draw::Rect { x: x + 2.0, y: 0.0, w: 3.0, h: line.height, color: BORDER_COLOR });
+                    out.push(Block::Line(line));
+                }
+                push_space(out, PARAGRAPH_SPACING);
+            }
+            "pre" => {
+                push_space(out, PARAGRAPH_SPACING);
+                let mut inlines = Vec::new();
+                self.inline_children(element, &style, &mut inlines);
+                // A newline right after <pre> or before </pre> isn't content
+                if let Some(Inline::Text(text, _)) = inlines.first_mut() {
+                    if let Some(rest) = text.strip_prefix("\r\n").or_else(|| text.strip_prefix('\n')) {
+                        *text = rest.to_string();
+                    }
+                }
+                if let Some(Inline::Text(text, _)) = inlines.last_mut() {
+                    let trimmed = text.trim_end_matches(['\n', '\r']).len();
+                    text.truncate(trimmed);
+                }
+                let padding = 6.0;
+                let mut lines = Vec::new();
+                self.flow(&mut inlines, x + padding, width - 2.0 * padding, TextAlign::Left, &mut lines);
+                let shade = |height: f32| Line {
+                    height,
+                    draws: vec![Draw::Rect { x, y: 0.0, w: width, h: height, color: SHADE_COLOR }],
+                    ..Default::default()
+                };
+                out.push(Block::Line(shade(padding)));
+                for block in lines {
+                    if let Block::Line(mut line) = block {
+                        line.draws.insert(0, Draw::Rect { x, y: 0.0, w: width, h: line.height, color: SHADE_COLOR });
+                        out.push(Block::Line(line));
+                    }
+                }
+                out.push(Block::Line(shade(padding)));
+                push_space(out, PARAGRAPH_SPACING);
+            }
+            "hr" => {
+                push_space(out, PARAGRAPH_SPACING);
+                out.push(Block::Line(Line {
+                    height: 1.0,
+                    draws: vec![Draw::Rule { x1: x, y1: 0.5, x2: x + width, y2: 0.5, width: 0.75, color: BORDER_COLOR }],
+                    anchors: std::mem::take(&mut self.pending_anchors),
+                    ..Default::default()
+                }));
+                push_space(out, PARAGRAPH_SPACING);
+            }
+            "table" => {
+                push_space(out, PARAGRAPH_SPACING);
+                self.table(element, &style, x, width, out);
+                push_space(out, PARAGRAPH_SPACING);
+            }
+            _ => self.block_children(element, &style, x, width, out, depth),
+        }
+    }
+
+    fn inline_children(&mut self, element: &HtmlElement, style: &TextStyle, inlines: &mut Vec<Inline>) {
+        for child in &element.children {
+            match child {
+                HtmlNode::Text(text) => inlines.push(Inline::Text(text.clone(), style.clone())),
+                HtmlNode::Element(e) if is_block(e) => {
+                    // Blocks inside inline elements still start on their own line
+                    inlines.push(Inline::Break { size: style.size, hard: false });
+                    self.inline(e, style, inlines);
+                    inlines.push(Inline::Break { size: style.size, hard: false });
+                }
+                HtmlNode::Element(e) => self.inline(e, style, inlines),
+            }
+        }
+    }
+
+    fn inline(&mut self, element: &HtmlElement, parent: &TextStyle, inlines: &mut Vec<Inline>) {
+        let tag = element.tag.as_str();
+        if HIDDEN_ELEMENTS.contains(&tag) {
+            return;
+        }
+        let id = element.attr("id").or_else(|| if tag == "a" { element.attr("name") } else { None });
+        if let Some(id) = id {
+            inlines.push(Inline::Anchor(id.to_string()));
+        }
+        match tag {
+            "br" => inlines.push(Inline::Break { size: parent.size, hard: true }),
+            "img" => self.image(element, parent, inlines),
+            "input" => {
+                if element.attr("type").map(|t| t.eq_ignore_ascii_case("checkbox")).unwrap_or(false) {
+                    let checked = element.attr("checked").is_some();
+                    let face = self.fonts.face_for(parent);
+                    let symbol = if checked { '☑' } else { '☐' };
+                    let text = if self.fonts.faces[face].has_glyph(symbol) {
+                        format!("{} ", symbol)
+                    } else if checked {
+                        "[x] ".to_string()
+                    } else {
+                        "[ ] ".to_string()
+                    };
+                    inlines.push(Inline::Text(text, parent.clone()));
+                }
+            }
+            _ => {
+                let style = element_style(element, parent);
+                self.inline_children(element, &style, inlines);
+            }
+        }
+    }
+
+    fn image(&mut self, element: &HtmlElement, style: &TextStyle, inlines: &mut Vec<Inline>) {
+        let source = element.attr("src").unwrap_or("").trim();
+        match self.images.load(source) {
+            Some(image) => {
+                let (natural_width, natural_height) = self.images.images[image].size;
+                let width = element.attr("width").and_then(css_length).or_else(|| element.style("width").as_deref().and_then(css_length));
+                let height = element.attr("height").and_then(css_length).or_else(|| element.style("height").as_deref().and_then(css_length));
+                let (width, height) = match (width, height) {
+                    (Some(w), Some(h)) => (w, h),
+                    (Some(w), None) => (w, natural_height * w / natural_width),
+                    (None, Some(h)) => (natural_width * h / natural_height, h),
+                    (None, None) => (natural_width, natural_height),
+                };
+                inlines.push(Inline::Image { image, width, height, style: style.clone() });
+            }
+            None => {
+                // Images that can't be loaded show their description instead
+                let alt = element.attr("alt").unwrap_or("").trim();
+                if !alt.is_empty() {
+                    let style = TextStyle { italic: true, color: MUTED_COLOR, ..style.clone() };
+                    inlines.push(Inline::Text(format!("[{}]", alt), style));
+                }
+            }
+        }
+    }
+
+    fn list(&mut self, list: &HtmlElement, style: &TextStyle, x: f32, width: f32, out: &mut Vec<Block>, depth: usize) {
+        if depth == 0 {
+            push_space(out, PARAGRAPH_SPACING);
+        }
+        let ordered = list.tag == "ol";
+        let kind = list.attr("type").unwrap_or("1").to_string();
+        let mut number: i64 = list.attr("start").and_then(|s| s.trim().parse().ok()).unwrap_or(1);
+        for item in list.elements() {
+            if item.tag != "li" {
+                self.block(item, style, x + LIST_INDENT, width - LIST_INDENT, out, depth + 1);
+                continue;
+            }
+            if let Some(value) = item.attr("value").and_then(|v| v.trim().parse().ok()) {
+                number = value;
+            }
+            let marker = if ordered {
+                format!("{}.", list_number(number, &kind))
+            } else {
+                ["•", "◦", "▪"][depth % 3].to_string()
+            };
+            number += 1;
+            self.list_item(item, style, marker, x, width, out, depth);
+            push_space(out, 2.0);
+        }
+        if depth == 0 {
+            push_space(out, PARAGRAPH_SPACING);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn list_item(&mut self, item: &HtmlElement, parent: &TextStyle, marker: String, x: f32, width: f32, out: &mut Vec<Block>, depth: usize) {
+        let style = element_style(item, parent);
+        if let Some(id) = item.attr("id") {
+            self.pending_anchors.push(id.to_string());
+        }
+        let mut blocks = Vec::new();
+        self.block_children(item, &style, x + LIST_INDENT, width - LIST_INDENT, &mut blocks, depth + 1);
+
+        // Task list items show their checkbox instead of a bullet
+        let task = item.elements().next().map(|e| e.tag == "input").unwrap_or(false);
+        if !task {
+            let marker_style = TextStyle { bold: false, italic: false, underline: false, link: None, background: None, ..style.clone() };
+            let face = self.fonts.face_for(&marker_style);
+            let marker = if marker.chars().all(|c| self.fonts.faces[face].has_glyph(c)) { marker } else { "-".to_string() };
+            let marker_width = self.fonts.measure(face, &marker, marker_style.size);
+            let first_line = blocks.iter_mut().find_map(|b| match b {
+                Block::Line(line) => Some(line),
+                _ => None,
+            });
+            let line = match first_line {
+                Some(line) => line,
+                None => {
+                    let (ascent, descent) = self.fonts.vertical(face, marker_style.size);
+                    blocks.push(Block::Line(Line {
+                        height: marker_style.size * LINE_HEIGHT,
+                        baseline: (marker_style.size * LINE_HEIGHT + ascent - descent) / 2.0,
+                        ..Default::default()
+                    }));
+                    match blocks.last_mut() {
+                        Some(Block::Line(line)) => line,
+                        _ => unreachable!(),
+                    }
+                }
+            };
+            line.draws.push(Draw::Text {
+                x: x + LIST_INDENT - 6.0 - marker_width,
+                y: line.baseline,
+                face,
+                size: marker_style.size,
+                color: marker_style.color,
+                text: marker,
+                width: marker_width,
+            });
+        }
+        for block in blocks {
+            match block {
+                Block::Space(height) => push_space(out, height),
+                other => out.push(other),
+            }
+        }
+    }
+
+    fn table(&mut self, table: &HtmlElement, style: &TextStyle, x: f32, width: f32, out: &mut Vec<Block>) {
+        let mut rows: Vec<&HtmlElement> = Vec::new();
+        for child in table.elements() {
+            match child.tag.as_str() {
+                "caption" => self.block_children(child, &element_style(child, style), x, width, out, 0),
+                "thead" | "tbody" | "tfoot" => rows.extend(child.elements().filter(|r| r.tag == "tr")),
+                "tr" => rows.push(child),
+                _ => {}
+            }
+        }
+
+        // Place the cells on a grid, honouring column and row spans
+        struct Cell<'a> {
+            element: &'a HtmlElement,
+            row: usize,
+            column: usize,
+            columns: usize,
+            rows: usize,
+        }
+        let mut cells: Vec<Cell> = Vec::new();
+        let mut occupied: Vec<Vec<bool>> = vec![Vec::new(); rows.len()];
+        for (r, row) in rows.iter().enumerate() {
+            let mut column = 0;
+            for cell in row.elements().filter(|c| c.tag == "td" || c.tag == "th") {
+                while occupied[r].get(column).copied().unwrap_or(false) {
+                    column += 1;
+                }
+                let span = |name: &str| cell.attr(name).and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(1).max(1);
+                let columns = span("colspan").min(1000);
+                let row_span = span("rowspan").min(rows.len() - r);
+                for occupied_row in occupied.iter_mut().skip(r).take(row_span) {
+                    if occupied_row.len() < column + columns {
+                        occupied_row.resize(column + columns, false);
+                    }
+                    occupied_row[column..column + columns].iter_mut().for_each(|o| *o = true);
+                }
+                cells.push(Cell { element: cell, row: r, column, columns, rows: row_span });
+                column += columns;
+            }
+        }
+        let column_count = occupied.iter().map(Vec::len).max().unwrap_or(0);
+        if column_count == 0 {
+            return;
+        }
+
+        // Column widths from the narrowest and widest each column's content allows
+        let mut min = vec![0.0f32; column_count];
+        let mut max = vec![0.0f32; column_count];
+        let mut measured = Vec::with_capacity(cells.len());
+        for cell in &cells {
+            let (cell_min, cell_max) = self.measure(cell.element, &element_style(cell.element, style));
+            measured.push((cell_min + 2.0 * CELL_PADDING, cell_max + 2.0 * CELL_PADDING));
+        }
+        for pass_spans in [false, true] {
+            for (cell, &(cell_min, cell_max)) in cells.iter().zip(&measured) {
+                if (cell.columns > 1) != pass_spans {
+                    continue;
+                }
+                let range = cell.column..cell.column + cell.columns;
+                let (have_min, have_max): (f32, f32) = (min[range.clone()].iter().sum(), max[range.clone()].iter().sum());
+                let share = cell.columns as f32;
+                if cell_min > have_min {
+                    min[range.clone()].iter_mut().for_each(|m| *m += (cell_min - have_min) / share);
+                }
+                if cell_max > have_max {
+                    max[range].iter_mut().for_each(|m| *m += (cell_max - have_max) / share);
+                }
+            }
+        }
+        let widths = column_widths(&min, &max, width, !self.measuring);
+        if self.measuring {
+            self.longest_word = self.longest_word.max(min.iter().sum());
+        }
+        let offsets: Vec<f32> = widths.iter().scan(0.0, |acc, w| {
+            let offset = *acc;
+            *acc += w;
+            Some(offset)
+        }).collect();
+
+        // Lay out every cell at its column width
+        let mut contents = Vec::with_capacity(cells.len());
+        for cell in &cells {
+            let cell_width: f32 = widths[cell.column..cell.column + cell.columns].iter().sum();
+            let mut blocks = Vec::new();
+            self.block_children(cell.element, &element_style(cell.element, style), 0.0, cell_width - 2.0 * CELL_PADDING, &mut blocks, 0);
+            trim_spaces(&mut blocks);
+            let height: f32 = blocks.iter().map(block_height).sum();
+            contents.push((blocks, height));
+        }
+
+        let mut row_heights = vec![BASE_FONT_SIZE * LINE_HEIGHT * 0.5; rows.len()];
+        for (cell, (_, height)) in cells.iter().zip(&contents) {
+            if cell.rows == 1 {
+                row_heights[cell.row] = row_heights[cell.row].max(height + 2.0 * CELL_PADDING);
+            }
+        }
+        for (cell, (_, height)) in cells.iter().zip(&contents) {
+            let spanned: f32 = row_heights[cell.row..cell.row + cell.rows].iter().sum();
+            if cell.rows > 1 && height + 2.0 * CELL_PADDING > spanned {
+                row_heights[cell.row + cell.rows - 1] += height + 2.0 * CELL_PADDING - spanned;
+            }
+        }
+
+        // Rows joined by a row span stay together; a group taller than a page
+        // is cut between lines of text into strips that each fit on one
+        let mut group_start = 0;
+        let mut group_end = 0;
+        let mut contents: Vec<Option<(Vec<Block>, f32)>> = contents.into_iter().map(Some).collect();
+        for r in 0..rows.len() {
+            for cell in cells.iter().filter(|c| c.row == r) {
+                group_end = group_end.max(r + cell.rows - 1);
+            }
+            if r < group_end {
+                continue;
+            }
+            let group_height: f32 = row_heights[group_start..=r].iter().sum();
+            // Cell boxes, and the cells' lines with their distance from the top of the group
+            let mut boxes = Vec::new();
+            let mut pieces: Vec<(f32, Line)> = Vec::new();
+            for (index, cell) in cells.iter().enumerate().filter(|(_, c)| c.row >= group_start && c.row <= r) {
+                let cell_x = x + offsets[cell.column];
+                let cell_y: f32 = row_heights[group_start..cell.row].iter().sum();
+                let cell_w: f32 = widths[cell.column..cell.column + cell.columns].iter().sum();
+                let cell_h: f32 = row_heights[cell.row..cell.row + cell.rows].iter().sum();
+                let header = cell.element.tag == "th" || rows[cell.row].attr("class").map(|c| c.contains("header")).unwrap_or(false);
+                boxes.push((cell_x, cell_y, cell_w, cell_h, header));
+                let (blocks, _) = contents[index].take().unwrap_or_default();
+                let mut top = cell_y + CELL_PADDING;
+                for block in blocks {
+                    match block {
+                        Block::Line(mut inner) => {
+                            inner.draws.iter_mut().for_each(|draw| draw.shift(cell_x + CELL_PADDING, 0.0));
+                            let height = inner.height;
+                            pieces.push((top, inner));
+                            top += height;
+                        }
+                        Block::Space(height) => top += height,
+                        Block::PageBreak => {}
+                    }
+                }
+            }
+
+            // Cut at the lowest line top that fits the page and doesn't pass
+            // through another cell's line, or at the page height if none does
+            let mut cuts = vec![0.0f32];
+            while group_height - cuts[cuts.len() - 1] > CONTENT_HEIGHT {
+                let start = cuts[cuts.len() - 1];
+                let limit = start + CONTENT_HEIGHT;
+                let straddled = |at: f32| pieces.iter().any(|(top, line)| *top < at && top + line.height > at);
+                let cut = pieces
+                    .iter()
+                    .map(|(top, _)| *top)
+                    .filter(|&top| top > start && top <= limit && !straddled(top))
+                    .fold(start, f32::max);
+                cuts.push(if cut > start { cut } else { limit });
+            }
+            cuts.push(group_height);
+
+            for band in cuts.windows(2) {
+                let (start, end) = (band[0], band[1]);
+                let mut line = Line { height: end - start, ..Default::default() };
+                let mut borders = Vec::new();
+                for &(cell_x, cell_y, cell_w, cell_h, header) in &boxes {
+                    let top = cell_y.max(start) - start;
+                    let bottom = (cell_y + cell_h).min(end) - start;
+                    if bottom <= top {
+                        continue;
+                    }
+                    if header {
+                        line.draws.push(Draw::Rect { x: cell_x, y: top, w: cell_w, h: bottom - top, color: SHADE_COLOR });
+                    }
+                    for (x1, y1, x2, y2) in [
+                        (cell_x, top, cell_x + cell_w, top),
+                        (cell_x, bottom, cell_x + cell_w, bottom),
+                        (cell_x, top, cell_x, bottom),
+                        (cell_x + cell_w, top, cell_x + cell_w, bottom),
+                    ] {
+                        borders.push(Draw::Rule { x1, y1, x2, y2, width: 0.5, color: BORDER_COLOR });
+                    }
+                }
+                let (here, rest): (Vec<_>, Vec<_>) = pieces.into_iter().partition(|(top, _)| *top < end);
+                pieces = rest;
+                for (top, inner) in here {
+                    for mut draw in inner.draws {
+                        draw.shift(0.0, top - start);
+                        line.draws.push(draw);
+                    }
+                    line.anchors.extend(inner.anchors);
+                }
+                line.draws.extend(borders);
+                line.anchors.splice(0..0, std::mem::take(&mut self.pending_anchors));
+                out.push(Block::Line(line));
+            }
+            group_start = r + 1;
+            group_end = r + 1;
+        }
+    }
+
+    /// The narrowest width a cell's content fits in without overflowing,
+    /// and the width it takes with no wrapping at all
+    fn measure(&mut self, element: &HtmlElement, style: &TextStyle) -> (f32, f32) {
+        let saved_anchors = std::mem::take(&mut self.pending_anchors);
+        let saved_longest = self.longest_word;
+        let saved_measuring = self.measuring;
+        self.measuring = true;
+        self.longest_word = 0.0;
+        let mut blocks = Vec::new();
+        self.block_children(element, style, 0.0, 1.0e6, &mut blocks, 0);
+        let widest = blocks
+            .iter()
+            .filter_map(|b| match b {
+                Block::Line(line) => Some(line),
+                _ => None,
+            })
+            .flat_map(|line| line.draws.iter())
+            .map(Draw::right)
+            .fold(0.0, f32::max);
+        let narrowest = self.longest_word;
+        self.measuring = saved_measuring;
+        self.longest_word = saved_longest.max(narrowest);
+        self.pending_anchors = saved_anchors;
+        (narrowest, widest.max(narrowest))
+    }
+
+    /// Break inline content into lines that fit the width
+    fn flow(&mut self, inlines: &mut Vec<Inline>, x: f32, width: f32, align: TextAlign, out: &mut Vec<Block>) {
+        if inlines.is_empty() {
+            return;
+        }
+        let width = width.max(1.0);
+        let atoms = self.atoms(std::mem::take(inlines), width);
+        let mut line: Vec<Atom> = Vec::new();
+        let mut line_width = 0.0;
+        let mut anchors = Vec::new();
+        let mut pending_space: Option<Atom> = None;
+        let mut atoms = atoms.into_iter().peekable();
+        while let Some(atom) = atoms.next() {
+            match atom {
+                Atom::Space { .. } => {
+                    // Spaces at the start of a line are dropped
+                    if !line.is_empty() {
+                        pending_space = Some(atom);
+                    }
+                }
+                Atom::Break { size, hard } => {
+                    if hard || !line.is_empty() {
+                        self.finish_line(&mut line, &mut anchors, Some(size), x, width, align, out);
+                    }
+                    line_width = 0.0;
+                    pending_space = None;
+                }
+                Atom::Anchor(id) => anchors.push(id),
+                Atom::Word { .. } | Atom::Image { .. } => {
+                    // Words split across differently styled runs stay together
+                    let mut unit = vec![atom];
+                    if matches!(unit[0], Atom::Word { .. }) {
+                        while matches!(atoms.peek(), Some(Atom::Word { .. })) {
+                            unit.push(atoms.next().unwrap());
+                        }
+                    }
+                    let unit_width: f32 = unit.iter().map(Atom::width).sum();
+                    self.longest_word = self.longest_word.max(unit_width.min(width));
+                    let space_width = pending_space.as_ref().map(Atom::width).unwrap_or(0.0);
+                    if !line.is_empty() && line_width + space_width + unit_width > width {
+                        self.finish_line(&mut line, &mut anchors, None, x, width, align, out);
+                        line_width = 0.0;
+                        pending_space = None;
+                    }
+                    if let Some(space) = pending_space.take() {
+                        line_width += space.width();
+                        line.push(space);
+                    }
+                    if unit_width > width && line.is_empty() {
+                        for (n, piece) in self.split_unit(unit, width).into_iter().enumerate() {
+                            if n > 0 {
+                                self.finish_line(&mut line, &mut anchors, None, x, width, align, out);
+                            }
+                            line_width = piece.iter().map(Atom::width).sum();
+                            line.extend(piece);
+                        }
+                    } else {
+                        line_width += unit_width;
+                        line.extend(unit);
+                    }
+                }
+            }
+        }
+        if !line.is_empty() {
+            self.finish_line(&mut line, &mut anchors, None, x, width, align, out);
+        } else {
+            self.pending_anchors.extend(anchors);
+        }
+    }
+
+    fn atoms(&mut self, inlines: Vec<Inline>, line_width: f32) -> Vec<Atom> {
+        let mut atoms = Vec::new();
+        for inline in inlines {
+            match inline {
+                Inline::Text(text, style) => {
+                    let face = self.fonts.face_for(&style);
+                    // Soft hyphens and zero-width spaces only mark break opportunities
+                    let text: String = text.chars().filter(|c| !matches!(c, '\u{ad}' | '\u{200b}' | '\u{feff}')).collect();
+                    if style.pre {
+                        for (n, part) in text.split('\n').enumerate() {
+                            if n > 0 {
+                                atoms.push(Atom::Break { size: style.size, hard: true });
+                            }
+                            let part = part.replace('\t', "    ").replace('\r', "");
+                            if !part.is_empty() {
+                                let width = self.fonts.measure(face, &part, style.size);
+                                atoms.push(Atom::Word { text: part, style: style.clone(), face, width });
+                            }
+                        }
+                        continue;
+                    }
+                    let mut word = String::new();
+                    for c in text.chars() {
+                        if matches!(c, ' ' | '\n' | '\t' | '\r' | '\x0c') {
+                            if !word.is_empty() {
+                                let width = self.fonts.measure(face, &word, style.size);
+                                atoms.push(Atom::Word { text: std::mem::take(&mut word), style: style.clone(), face, width });
+                            }
+                            if !matches!(atoms.last(), Some(Atom::Space { .. })) {
+                                let width = self.fonts.measure(face, " ", style.size);
+                                atoms.push(Atom::Space { style: style.clone(), face, width });
+                            }
+                        } else {
+                            word.push(c);
+                        }
+                    }
+                    if !word.is_empty() {
+                        let width = self.fonts.measure(face, &word, style.size);
+                        atoms.push(Atom::Word { text: word, style, face, width });
+                    }
+                }
+                Inline::Image { image, mut width, mut height, style } => {
+                    // Images shrink to fit the line and the page
+                    if width > 0.0 && height > 0.0 {
+                        let line_width = if self.measuring { CONTENT_WIDTH } else { line_width };
+                        let scale = (line_width / width).min(CONTENT_HEIGHT * 0.9 / height).min(1.0);
+                        width *= scale;
+                        height *= scale;
+                        atoms.push(Atom::Image { image, width, height, style });
+                    }
+                }
+                Inline::Break { size, hard } => atoms.push(Atom::Break { size, hard }),
+                Inline::Anchor(id) => atoms.push(Atom::Anchor(id)),
+            }
+        }
+        atoms
+    }
+
+    /// Split a word wider than the line into pieces that fit
+    fn split_unit(&mut self, unit: Vec<Atom>, width: f32) -> Vec<Vec<Atom>> {
+        let mut pieces = vec![Vec::new()];
+        let mut piece_width = 0.0;
+        for atom in unit {
+            let Atom::Word { text, style, face, .. } = atom else {
+                pieces.last_mut().unwrap().push(atom);
+                continue;
+            };
+            let mut current = String::new();
+            for c in text.chars() {
+                let mut buffer = [0u8; 4];
+                let char_width = self.fonts.measure(face, c.encode_utf8(&mut buffer), style.size);
+                if piece_width + char_width > width && (piece_width > 0.0 || !current.is_empty()) {
+                    if !current.is_empty() {
+                        let w = self.fonts.measure(face, &current, style.size);
+                        pieces.last_mut().unwrap().push(Atom::Word { text: std::mem::take(&mut current), style: style.clone(), face, width: w });
+                    }
+                    pieces.push(Vec::new());
+                    piece_width = 0.0;
+                }
+                current.push(c);
+                piece_width += char_width;
+            }
+            if !current.is_empty() {
+                let w = self.fonts.measure(face, &current, style.size);
+                pieces.last_mut().unwrap().push(Atom::Word { text: current, style, face, width: w });
+            }
+        }
+        pieces.retain(|p| !p.is_empty());
+        pieces
+    }
+
+    /// Turn the atoms of one line into positioned draws
+    #[allow(clippy::too_many_arguments)]
+    fn finish_line(
+        &mut self,
+        atoms: &mut Vec<Atom>,
+        anchors: &mut Vec<String>,
+        empty_size: Option<f32>,
+        x: f32,
+        width: f32,
+        align: TextAlign,
+        out: &mut Vec<Block>,
+    ) {
+        while matches!(atoms.last(), Some(Atom::Space { .. })) {
+            atoms.pop();
+        }
+        let mut line_anchors = std::mem::take(&mut self.pending_anchors);
+        line_anchors.append(anchors);
+        if atoms.is_empty() {
+            // An empty line from <br><br> still takes up a line of space
+            let height = empty_size.unwrap_or(BASE_FONT_SIZE) * LINE_HEIGHT;
+            out.push(Block::Line(Line { height, baseline: height * 0.8, anchors: line_anchors, ..Default::default() }));
+            return;
+        }
+
+        // Merge adjacent text of the same style into runs
+        enum Run {
+            Text { text: String, style: TextStyle, face: usize, width: f32 },
+            Image { image: usize, width: f32, height: f32, style: TextStyle },
+        }
+        let mut runs: Vec<Run> = Vec::new();
+        for atom in atoms.drain(..) {
+            let (text, style, face, width) = match atom {
+                Atom::Word { text, style, face, width } => (text, style, face, width),
+                Atom::Space { style, face, width } => (" ".to_string(), style, face, width),
+                Atom::Image { image, width, height, style } => {
+                    runs.push(Run::Image { image, width, height, style });
+                    continue;
+                }
+                _ => continue,
+            };
+            if let Some(Run::Text { text: run_text, style: run_style, face: run_face, width: run_width }) = runs.last_mut() {
+                if *run_style == style && *run_face == face {
+                    run_text.push_str(&text);
+                    *run_width += width;
+                    continue;
+                }
+            }
+            runs.push(Run::Text { text, style, face, width });
+        }
+
+        // Every run gets half-leading above and below, as in CSS
+        let mut above: f32 = 0.0;
+        let mut below: f32 = 0.0;
+        for run in &runs {
+            match run {
+                Run::Text { style, face, .. } => {
+                    let (ascent, descent) = self.fonts.vertical(*face, style.size);
+                    let leading = (style.size * LINE_HEIGHT - (ascent - descent)) / 2.0;
+                    above = above.max(ascent + leading + style.rise);
+                    below = below.max(leading - descent - style.rise);
+                }
+                Run::Image { height, style, .. } => {
+                    above = above.max(height + style.rise);
+                    below = below.max(style.size * 0.2 - style.rise);
+                }
+            }
+        }
+        let baseline = above;
+        let height = above + below.max(0.0);
+
+        let total: f32 = runs
+            .iter()
+            .map(|r| match r {
+                Run::Text { width, .. } | Run::Image { width, .. } => *width,
+            })
+            .sum();
+        let mut cursor = x + match align {
+            _ if self.measuring => 0.0,
+            TextAlign::Left => 0.0,
+            TextAlign::Center => ((width - total) / 2.0).max(0.0),
+            TextAlign::Right => (width - total).max(0.0),
+        };
+
+        let mut draws = Vec::new();
+        for run in runs {
+            match run {
+                Run::Text { text, style, face, width } => {
+                    let y = baseline - style.rise;
+                    let (ascent, descent) = self.fonts.vertical(face, style.size);
+                    if let Some(background) = style.background {
+                        draws.push(Draw::Rect { x: cursor - 1.0, y: y - ascent, w: width + 2.0, h: ascent - descent, color: background });
+                    }
+                    draws.push(Draw::Text { x: cursor, y, face, size: style.size, color: style.color, text, width });
+                    let thickness = (style.size / 18.0).max(0.4);
+                    if style.underline {
+                        let rule_y = y + style.size * 0.12;
+                        draws.push(Draw::Rule { x1: cursor, y1: rule_y, x2: cursor + width, y2: rule_y, width: thickness, color: style.color });
+                    }
+                    if style.strike {
+                        let rule_y = y - style.size * 0.3;
+                        draws.push(Draw::Rule { x1: cursor, y1: rule_y, x2: cursor + width, y2: rule_y, width: thickness, color: style.color });
+                    }
+                    if let Some(target) = style.link {
+                        draws.push(Draw::Link { x: cursor, y: y - ascent, w: width, h: ascent - descent, target });
+                    }
+                    cursor += width;
+                }
+                Run::Image { image, width, height, style } => {
+                    let y = baseline - style.rise - height;
+                    draws.push(Draw::Image { x: cursor, y, w: width, h: height, image });
+                    if let Some(target) = style.link {
+                        draws.push(Draw::Link { x: cursor, y, w: width, h: height, target });
+                    }
+                    cursor += width;
+                }
+            }
+        }
+        out.push(Block::Line(Line { height, baseline, draws, anchors: line_anchors, ..Default::default() }));
+    }
+}
+
+/// Add vertical space, collapsing with the space before it like CSS margins
+fn push_space(out: &mut Vec<Block>, height: f32) {
+    if let Some(Block::Space(previous)) = out.last_mut() {
+        *previous = previous.max(height);
+    } else {
+        out.push(Block::Space(height));
+    }
+}
+
+fn trim_spaces(blocks: &mut Vec<Block>) {
+    while matches!(blocks.last(), Some(Block::Space(_))) {
+        blocks.pop();
+    }
+    let leading = blocks.iter().take_while(|b| matches!(b, Block::Space(_))).count();
+    blocks.drain(..leading);
+}
+
+fn block_height(block: &Block) -> f32 {
+    match block {
+        Block::Line(line) => line.height,
+        Block::Space(height) => *height,
+        Block::PageBreak => 0.0,
+    }
+}
+
+/// Share the available width between columns: every column gets its
+/// minimum, then the rest goes to columns in proportion to how much more
+/// they would like
+fn column_widths(min: &[f32], max: &[f32], available: f32, fill: bool) -> Vec<f32> {
+    let min_total: f32 = min.iter().sum();
+    let max_total: f32 = max.iter().sum();
+    if min_total >= available {
+        // Too narrow for the content; shrink proportionally rather than overflow
+        return min.iter().map(|m| m * available / min_total.max(1.0)).collect();
+    }
+    if max_total <= available {
+        if !fill || max_total <= 0.0 {
+            return max.to_vec();
+        }
+        return max.iter().map(|m| m * available / max_total).collect();
+    }
+    let extra = available - min_total;
+    let wanted = max_total - min_total;
+    min.iter().zip(max).map(|(lo, hi)| lo + (hi - lo) * extra / wanted.max(1.0e-3)).collect()
+}
+
+// ---------------------------------------------------------------------------
+// Pagination
+// ---------------------------------------------------------------------------
+
+struct Page {
+    /// Lines with their distance from the top of the content area
+    lines: Vec<(f32, Line)>,
+}
+
+struct Heading {
+    level: u8,
+    title: String,
+    page: usize,
+    top: f32,
+}
+
+struct Paged {
+    pages: Vec<Page>,
+    /// Element id to page index and distance from the top of the content area
+    anchors: HashMap<String, (usize, f32)>,
+    headings: Vec<Heading>,
+}
+
+fn paginate(blocks: Vec<Block>) -> Paged {
+    let mut paged = Paged { pages: vec![Page { lines: Vec::new() }], anchors: HashMap::new(), headings: Vec::new() };
+    let mut blocks: Vec<Block> = blocks;
+    trim_spaces(&mut blocks);
+
+    // Height a line needs on its page, including the line it must stay with
+    let keep_heights: Vec<f32> = (0..blocks.len())
+        .map(|i| match &blocks[i] {
+            Block::Line(line) if line.keep_with_next => {
+                let mut height = line.height;
+                for next in &blocks[i + 1..] {
+                    match next {
+                        Block::Space(space) => height += space,
+                        Block::Line(next) => {
+                            height += next.height;
+                            if !next.keep_with_next {
+                                break;
+                            }
+                        }
+                        Block::PageBreak => break,
+                    }
+                }
+                height.min(CONTENT_HEIGHT)
+            }
+            Block::Line(line) => line.height,
+            _ => 0.0,
+        })
+        .collect();
+
+    let mut top = 0.0;
+    let mut space = 0.0;
+    for (block, needed) in blocks.into_iter().zip(keep_heights) {
+        match block {
+            Block::Space(height) => space = f32::max(space, height),
+            Block::PageBreak => {
+                if !paged.pages.last().unwrap().lines.is_empty() {
+                    paged.pages.push(Page { lines: Vec::new() });
+                    top = 0.0;
+                }
+                space = 0.0;
+            }
+            Block::Line(line) => {
+                let on_page = !paged.pages.last().unwrap().lines.is_empty();
+                if on_page && top + space + needed > CONTENT_HEIGHT {
+                    paged.pages.push(Page { lines: Vec::new() });
+                    top = 0.0;
+                }
+                // Margins are dropped at the top of a page
+                if !paged.pages.last().unwrap().lines.is_empty() {
+                    top += space;
+                }
+                space = 0.0;
+                let page = paged.pages.len() - 1;
+                for anchor in &line.anchors {
+                    paged.anchors.entry(anchor.clone()).or_insert((page, top));
+                }
+                if let Some((level, title)) = &line.heading {
+                    paged.headings.push(Heading { level: *level, title: title.clone(), page, top });
+                }
+                let height = line.height;
+                paged.pages.last_mut().unwrap().lines.push((top, line));
+                top += height;
+            }
+        }
+    }
+    paged
+}
+
+// ---------------------------------------------------------------------------
+// Fonts
+// ---------------------------------------------------------------------------
+
+struct FontFace {
+    data: Vec<u8>,
+    index: u32,
+    name: String,
+    units_per_em: f32,
+    ascent: f32,
+    descent: f32,
+    bbox: [f32; 4],
+    italic_angle: f32,
+    cff: bool,
+    /// Glyph id and advance width for each character looked up so far
+    glyphs: HashMap<char, (u16, f32)>,
+    /// Glyphs used in the document, with the character each one stands for
+    used: BTreeMap<u16, char>,
+}
+
+impl FontFace {
+    fn load(data: Vec<u8>, index: u32, name: String) -> Option<FontFace> {
+        let face = ttf_parser::Face::parse(&data, index).ok()?;
+        let units_per_em = face.units_per_em() as f32;
+        let bbox = face.global_bounding_box();
+        let ascent = face.typographic_ascender().unwrap_or_else(|| face.ascender()) as f32;
+        let descent = face.typographic_descender().unwrap_or_else(|| face.descender()) as f32;
+        let italic_angle = face.italic_angle().unwrap_or(0.0);
+        let cff = face.tables().cff.is_some();
+        Some(FontFace {
+            name,
+            index,
+            units_per_em,
+            ascent,
+            descent,
+            bbox: [bbox.x_min as f32, bbox.y_min as f32, bbox.x_max as f32, bbox.y_max as f32],
+            italic_angle,
+            cff,
+            glyphs: HashMap::new(),
+            used: BTreeMap::new(),
+            data,
+        })
+    }
+
+    fn glyph(&mut self, c: char) -> (u16, f32) {
+        if let Some(&glyph) = self.glyphs.get(&c) {
+            return glyph;
+        }
+        let glyph = ttf_parser::Face::parse(&self.data, self.index)
+            .ok()
+            .and_then(|face| {
+                let id = face.glyph_index(c)?;
+                Some((id.0, face.glyph_hor_advance(id).unwrap_or(0) as f32))
+            })
+            .unwrap_or((0, 0.0));
+        self.glyphs.insert(c, glyph);
+        glyph
+    }
+
+    fn has_glyph(&mut self, c: char) -> bool {
+        self.glyph(c).0 != 0
+    }
+
+    /// Text width at the given size, in points
+    fn measure(&mut self, text: &str, size: f32) -> f32 {
+        let units: f32 = text.chars().map(|c| self.glyph(c).1).sum();
+        units * size / self.units_per_em
+    }
+
+    /// Glyph ids of the text as a hex string for an Identity-H font
+    fn encode(&mut self, text: &str) -> String {
+        let mut hex = String::with_capacity(text.len() * 4);
+        for c in text.chars() {
+            let (glyph, _) = self.glyph(c);
+            self.used.entry(glyph).or_insert(c);
+            let _ = write!(hex, "{:04X}", glyph);
+        }
+        hex
+    }
+}
+
+struct FontSet {
+    db: fontdb::Database,
+    faces: Vec<FontFace>,
+    /// Face index for each (mono, bold, italic) combination
+    variants: HashMap<(bool, bool, bool), usize>,
+    by_id: HashMap<fontdb::ID, usize>,
+}
+
+impl FontSet {
+    fn load() -> Result<FontSet, String> {
+        let mut db = fontdb::Database::new();
+        db.load_system_fonts();
+        let mut fonts = FontSet { db, faces: Vec::new(), variants: HashMap::new(), by_id: HashMap::new() };
+        if fonts.variant(false, false, false).is_none() {
+            return Err("No usable system font was found to render the document".to_string());
+        }
+        Ok(fonts)
+    }
+
+    fn variant(&mut self, mono: bool, bold: bool, italic: bool) -> Option<usize> {
+        if let Some(&index) = self.variants.get(&(mono, bold, italic)) {
+            return Some(index);
+        }
+        let family = if mono { "Courier" } else { "Helvetica" };
+        let name = match (bold, italic) {
+            (false, false) => family.to_string(),
+            (true, false) => format!("{}-Bold", family),
+            (false, true) => format!("{}-Oblique", family),
+            (true, true) => format!("{}-BoldOblique", family),
+        };
+        let id = find_system_font(&self.db, &name, if mono { 1 } else { 0 })?;
+        let index = match self.by_id.get(&id) {
+            Some(&index) => index,
+            None => {
+                let info = self.db.face(id)?;
+                let name = info.post_script_name.clone();
+                let face = self
+                    .db
+                    .with_face_data(id, |data, index| FontFace::load(data.to_vec(), index, name.clone()))
+                    .flatten()?;
+                self.faces.push(face);
+                self.by_id.insert(id, self.faces.len() - 1);
+                self.faces.len() - 1
+            }
+        };
+        self.variants.insert((mono, bold, italic), index);
+        Some(index)
+    }
+
+    fn face_for(&mut self, style: &TextStyle) -> usize {
+        self.variant(style.mono, style.bold, style.italic)
+            .or_else(|| self.variant(style.mono, false, false))
+            .or_else(|| self.variant(false, false, false))
+            .unwrap_or(0)
+    }
+
+    fn measure(&mut self, face: usize, text: &str, size: f32) -> f32 {
+        self.faces[face].measure(text, size)
+    }
+
+    /// Ascent and descent at the given size, descent being negative
+    fn vertical(&self, face: usize, size: f32) -> (f32, f32) {
+        let face = &self.faces[face];
+        (face.ascent * size / face.units_per_em, face.descent * size / face.units_per_em)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Images
+// ---------------------------------------------------------------------------
+
+struct LoadedImage {
+    /// Natural size in points
+    size: (f32, f32),
+    stream: Stream,
+    mask: Option<Stream>,
+}
+
+struct ImageSet {
+    base_dir: PathBuf,
+    images: Vec<LoadedImage>,
+    by_source: HashMap<String, Option<usize>>,
+}
+
+impl ImageSet {
+    fn load(&mut self, source: &str) -> Option<usize> {
+        if let Some(&cached) = self.by_source.get(source) {
+            return cached;
+        }
+        let loaded = read_image_source(source, &self.base_dir).and_then(|(bytes, svg)| decode_image(&bytes, svg));
+        let index = loaded.map(|image| {
+            self.images.push(image);
+            self.images.len() - 1
+        });
+        self.by_source.insert(source.to_string(), index);
+        index
+    }
+
+}
+
+/// The bytes of an image source, and whether they are SVG. Sources are
+/// data: URIs or local paths relative to base_dir; remote images are
+/// never fetched.
+pub fn read_image_source(source: &str, base_dir: &Path) -> Option<(Vec<u8>, bool)> {
+    if let Some(data) = source.strip_prefix("data:") {
+        let (meta, payload) = data.split_once(',')?;
+        let bytes = if meta.ends_with(";base64") {
+            let cleaned: String = payload.chars().filter(|c| !c.is_whitespace()).collect();
+            base64::engine::general_purpose::STANDARD.decode(cleaned).ok()?
+        } else {
+            percent_decode(payload).into_bytes()
+        };
+        return Some((bytes, meta.contains("svg")));
+    }
+    if source.contains("://") && !source.starts_with("file://") {
+        return None;
+    }
+    let path = source.strip_prefix("file://").unwrap_or(source);
+    let path = percent_decode(path.split(['?', '#']).next().unwrap_or(path));
+    let path = Path::new(&path);
+    let path = if path.is_absolute() { path.to_path_buf() } else { base_dir.join(path) };
+    let bytes = fs::read(&path).ok()?;
+    let svg = path.extension().map(|e| e.eq_ignore_ascii_case("svg") || e.eq_ignore_ascii_case("svgz")).unwrap_or(false);
+    Some((bytes, svg))
+}
+
+/// Rasterize an SVG at twice its size, returning the pixels and the
+/// natural size in points
+pub fn rasterize_svg(bytes: &[u8]) -> Option<(image::RgbaImage, (f32, f32))> {
+    let tree = usvg::Tree::from_data(bytes, &usvg::Options::default()).ok()?;
+    let size = tree.size();
+    let scale = 2.0;
+    let mut pixmap = tiny_skia::Pixmap::new((size.width() * scale).ceil() as u32, (size.height() * scale).ceil() as u32)?;
+    resvg::render(&tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+    Some((pixmap_to_rgba(&pixmap), (size.width() * 0.75, size.height() * 0.75)))
+}
+
+pub fn is_svg(bytes: &[u8], svg: bool) -> bool {
+    svg || bytes.starts_with(b"<svg") || bytes.starts_with(b"<?xml")
+}
+
+fn decode_image(bytes: &[u8], svg: bool) -> Option<LoadedImage> {
+    if is_svg(bytes, svg) {
+        let (rgba, size) = rasterize_svg(bytes)?;
+        let mut image = raster_image(image::DynamicImage::ImageRgba8(rgba));
+        image.size = size;
+        return Some(image);
+    }
+
+    let format = image::guess_format(bytes).ok()?;
+    if format == image::ImageFormat::Jpeg {
+        // JPEGs go into the PDF as they are
+        let decoded = image::load_from_memory_with_format(bytes, format).ok()?;
+        let color_space = match decoded.color() {
+            image::ColorType::L8 | image::ColorType::L16 => Some("DeviceGray"),
+            image::ColorType::Rgb8 | image::ColorType::Rgb16 => Some("DeviceRGB"),
+            _ => None,
+        };
+        if let Some(color_space) = color_space {
+            let mut stream = Stream::new(
+                dictionary! {
+                    "Type" => "XObject",
+                    "Subtype" => "Image",
+                    "Width" => decoded.width() as i64,
+                    "Height" => decoded.height() as i64,
+                    "ColorSpace" => color_space,
+                    "BitsPerComponent" => 8,
+                    "Filter" => "DCTDecode",
+                },
+                bytes.to_vec(),
+            );
+            stream.allows_compression = false;
+            let size = (decoded.width() as f32 * 0.75, decoded.height() as f32 * 0.75);
+            return Some(LoadedImage { size, stream, mask: None });
+        }
+        return Some(raster_image(decoded));
+    }
+    Some(raster_image(image::load_from_memory_with_format(bytes, format).ok()?))
+}
+
+fn raster_image(decoded: image::DynamicImage) -> LoadedImage {
+    let rgba = decoded.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+    let mut alpha = Vec::with_capacity((width * height) as usize);
+    for pixel in rgba.pixels() {
+        rgb.extend_from_slice(&pixel.0[..3]);
+        alpha.push(pixel.0[3]);
+    }
+    let image_dict = |color_space: &str| {
+        dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => width as i64,
+            "Height" => height as i64,
+            "ColorSpace" => color_space,
+            "BitsPerComponent" => 8,
+        }
+    };
+    let mask = alpha.iter().any(|&a| a < 255).then(|| Stream::new(image_dict("DeviceGray"), alpha));
+    LoadedImage {
+        size: (width as f32 * 0.75, height as f32 * 0.75),
+        stream: Stream::new(image_dict("DeviceRGB"), rgb),
+        mask,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// PDF output
+// ---------------------------------------------------------------------------
+
+fn write_pdf(paged: Paged, mut fonts: FontSet, images: ImageSet, title: Option<String>, output_path: &str) -> Result<usize, String> {
+    let mut doc = Document::with_version("1.7");
+    let pages_id = doc.new_object_id();
+    let page_ids: Vec<ObjectId> = paged.pages.iter().map(|_| doc.new_object_id()).collect();
+    let mut used_images = BTreeSet::new();
+
+    for (page, &page_id) in paged.pages.iter().zip(&page_ids) {
+        let mut content = String::new();
+        let mut annotations = Vec::new();
+        for (top, line) in &page.lines {
+            let origin_x = MARGIN;
+            let origin_y = PAGE_HEIGHT - MARGIN - top;
+            for draw in &line.draws {
+                match draw {
+                    Draw::Rect { x, y, w, h, color } => {
+                        let _ = writeln!(
+                            content,
+                            "{} rg {} {} {} {} re f",
+                            rgb(color), num(origin_x + x), num(origin_y - y - h), num(*w), num(*h)
+                        );
+                    }
+                    Draw::Rule { x1, y1, x2, y2, width, color } => {
+                        let _ = writeln!(
+                            content,
+                            "{} RG {} w {} {} m {} {} l S",
+                            rgb(color), num(*width), num(origin_x + x1), num(origin_y - y1), num(origin_x + x2), num(origin_y - y2)
+                        );
+                    }
+                    Draw::Text { x, y, face, size, color, text, .. } => {
+                        let hex = fonts.faces[*face].encode(text);
+                        let _ = writeln!(
+                            content,
+                            "BT {} rg /F{} {} Tf {} {} Td <{}> Tj ET",
+                            rgb(color), face, num(*size), num(origin_x + x), num(origin_y - y), hex
+                        );
+                    }
+                    Draw::Image { x, y, w, h, image } => {
+                        used_images.insert(*image);
+                        let _ = writeln!(
+                            content,
+                            "q {} 0 0 {} {} {} cm /Im{} Do Q",
+                            num(*w), num(*h), num(origin_x + x), num(origin_y - y - h), image
+                        );
+                    }
+                    Draw::Link { x, y, w, h, target } => {
+                        let rect = vec![
+                            Object::Real(origin_x + x),
+                            Object::Real(origin_y - y - h),
+                            Object::Real(origin_x + x + w),
+                            Object::Real(origin_y - y),
+                        ];
+                        let action = match target.strip_prefix('#') {
+                            // Links to ids that don't exist are dropped
+                            Some(id) => paged.anchors.get(&percent_decode(id)).map(|&(page, top)| {
+                                (
+                                    "Dest",
+                                    Object::Array(vec![
+                                        page_ids[page].into(),
+                                        "XYZ".into(),
+                                        Object::Null,
+                                        Object::Real(PAGE_HEIGHT - MARGIN - top),
+                                        Object::Null,
+                                    ]),
+                                )
+                            }),
+                            None => Some((
+                                "A",
+                                Object::Dictionary(dictionary! {
+                                    "S" => "URI",
+                                    "URI" => Object::string_literal(target.as_str()),
+                                }),
+                            )),
+                        };
+                        if let Some((key, value)) = action {
+                            let mut annotation = dictionary! {
+                                "Type" => "Annot",
+                                "Subtype" => "Link",
+                                "Rect" => rect,
+                                "Border" => vec![0.into(), 0.into(), 0.into()],
+                                "F" => 4,
+                            };
+                            annotation.set(key, value);
+                            annotations.push(Object::Reference(doc.add_object(annotation)));
+                        }
+                    }
+                }
+            }
+        }
+        let content_id = doc.add_object(Stream::new(Dictionary::new(), content.into_bytes()));
+        let mut page_dict = dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+        };
+        if !annotations.is_empty() {
+            page_dict.set("Annots", annotations);
+        }
+        doc.objects.insert(page_id, Object::Dictionary(page_dict));
+    }
+
+    // Shared resources for every page
+    let mut font_resources = Dictionary::new();
+    for (index, face) in fonts.faces.iter().enumerate() {
+        if !face.used.is_empty() {
+            let font_id = embed_font(&mut doc, face)?;
+            font_resources.set(format!("F{}", index), font_id);
+        }
+    }
+    let mut image_resources = Dictionary::new();
+    let mut images = images.images;
+    for index in used_images {
+        let image = &mut images[index];
+        let mut stream = std::mem::replace(&mut image.stream, Stream::new(Dictionary::new(), Vec::new()));
+        if let Some(mask) = image.mask.take() {
+            stream.dict.set("SMask", doc.add_object(mask));
+        }
+        image_resources.set(format!("Im{}", index), doc.add_object(stream));
+    }
+    let resources_id = doc.add_object(dictionary! {
+        "Font" => font_resources,
+        "XObject" => image_resources,
+        "ProcSet" => vec!["PDF".into(), "Text".into(), "ImageB".into(), "ImageC".into()],
+    });
+
+    let page_count = page_ids.len();
+    doc.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => page_ids.iter().map(|&id| Object::Reference(id)).collect::<Vec<_>>(),
+            "Count" => page_count as i64,
+            "Resources" => resources_id,
+            "MediaBox" => vec![0.into(), 0.into(), Object::Real(PAGE_WIDTH), Object::Real(PAGE_HEIGHT)],
+        }),
+    );
+
+    let mut catalog = dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    };
+    if let Some(outlines_id) = build_outline(&mut doc, &paged.headings, &page_ids) {
+        catalog.set("Outlines", outlines_id);
+        catalog.set("PageMode", "UseOutlines");
+    }
+    let catalog_id = doc.add_object(catalog);
+    doc.trailer.set("Root", catalog_id);
+
+    let mut info = dictionary! {
+        "Producer" => Object::string_literal("FileFlow"),
+        "CreationDate" => Object::string_literal(pdf_date_now()),
+    };
+    if let Some(title) = title {
+        info.set("Title", lopdf::text_string(&title));
+    }
+    let info_id = doc.add_object(info);
+    doc.trailer.set("Info", info_id);
+
+    doc.compress();
+    save_pdf(&mut doc, output_path)?;
+    Ok(page_count)
+}
+
+fn num(value: f32) -> String {
+    let rounded = (value * 100.0).round() / 100.0;
+    if rounded == rounded.trunc() {
+        format!("{}", rounded as i64)
+    } else {
+        format!("{}", rounded)
+    }
+}
+
+fn rgb(color: &[f32; 3]) -> String {
+    format!("{} {} {}", num(color[0]), num(color[1]), num(color[2]))
+}
+
+/// Bookmarks from the document's headings, nested by level
+fn build_outline(doc: &mut Document, headings: &[Heading], page_ids: &[ObjectId]) -> Option<ObjectId> {
+    if headings.is_empty() {
+        return None;
+    }
+    let outlines_id = doc.new_object_id();
+    let ids: Vec<ObjectId> = headings.iter().map(|_| doc.new_object_id()).collect();
+
+    // Parent of each heading: the closest earlier heading of a higher level
+    let mut parents: Vec<Option<usize>> = Vec::with_capacity(headings.len());
+    for (i, heading) in headings.iter().enumerate() {
+        let mut parent = i.checked_sub(1);
+        while let Some(p) = parent {
+            if headings[p].level < heading.level {
+                break;
+            }
+            parent = parents[p];
+        }
+        parents.push(parent);
+    }
+    let children = |parent: Option<usize>| -> Vec<usize> { (0..headings.len()).filter(|&i| parents[i] == parent).collect() };
+    let descendants = |index: usize| -> i64 {
+        let mut count = 0;
+        let mut stack = vec![index];
+        while let Some(node) = stack.pop() {
+            for child in (0..headings.len()).filter(|&i| parents[i] == Some(node)) {
+                count += 1;
+                stack.push(child);
+            }
+        }
+        count
+    };
+
+    for (i, heading) in headings.iter().enumerate() {
+        let siblings = children(parents[i]);
+        let position = siblings.iter().position(|&s| s == i).unwrap();
+        let kids = children(Some(i));
+        let mut item = dictionary! {
+            "Title" => lopdf::text_string(&heading.title),
+            "Parent" => parents[i].map(|p| ids[p]).unwrap_or(outlines_id),
+            "Dest" => vec![
+                page_ids[heading.page].into(),
+                "XYZ".into(),
+                Object::Null,
+                Object::Real(PAGE_HEIGHT - MARGIN - heading.top),
+                Object::Null,
+            ],
+        };
+        if position > 0 {
+            item.set("Prev", ids[siblings[position - 1]]);
+        }
+        if let Some(&next) = siblings.get(position + 1) {
+            item.set("Next", ids[next]);
+        }
+        if let (Some(&first), Some(&last)) = (kids.first(), kids.last()) {
+            item.set("First", ids[first]);
+            item.set("Last", ids[last]);
+            // Only the top two levels start expanded
+            let count = descendants(i);
+            item.set("Count", if heading.level <= 2 { count } else { -count });
+        }
+        doc.objects.insert(ids[i], Object::Dictionary(item));
+    }
+    let top = children(None);
+    doc.objects.insert(
+        outlines_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Outlines",
+            "First" => ids[top[0]],
+            "Last" => ids[*top.last().unwrap()],
+            "Count" => headings.len() as i64,
+        }),
+    );
+    Some(outlines_id)
+}
+
+/// Embed a face as a Type 0 font with Identity-H encoding, so text is
+/// written as glyph ids. TrueType fonts are subset to the used glyphs.
+fn embed_font(doc: &mut Document, face: &FontFace) -> Result<ObjectId, String> {
+    let scale = 1000.0 / face.units_per_em;
+    let tag = subset_tag(face.used.keys());
+    let base_font = format!("{}+{}", tag, face.name.replace(' ', ""));
+
+    let (font_file_key, font_file) = if face.cff {
+        let stream = Stream::new(dictionary! { "Subtype" => "OpenType" }, face.data.clone());
+        ("FontFile3", stream)
+    } else {
+        let glyphs: BTreeSet<u16> = face.used.keys().copied().collect();
+        let subset = subset_truetype(&face.data, face.index, &glyphs).unwrap_or_else(|| face.data.clone());
+        let length = subset.len() as i64;
+        ("FontFile2", Stream::new(dictionary! { "Length1" => length }, subset))
+    };
+    let font_file_id = doc.add_object(font_file);
+
+    let mut flags = 32; // nonsymbolic
+    if face.italic_angle != 0.0 {
+        flags |= 64;
+    }
+    if face.name.to_lowercase().contains("mono") {
+        flags |= 1;
+    }
+    let mut descriptor = dictionary! {
+        "Type" => "FontDescriptor",
+        "FontName" => base_font.as_str(),
+        "Flags" => flags,
+        "FontBBox" => face.bbox.iter().map(|v| Object::Real(v * scale)).collect::<Vec<_>>(),
+        "ItalicAngle" => Object::Real(face.italic_angle),
+        "Ascent" => Object::Real(face.ascent * scale),
+        "Descent" => Object::Real(face.descent * scale),
+        "CapHeight" => Object::Real(face.ascent * scale * 0.9),
+        "StemV" => 80,
+    };
+    descriptor.set(font_file_key, font_file_id);
+    let descriptor_id = doc.add_object(descriptor);
+
+    // Widths of the used glyphs, in runs of consecutive ids
+    let parsed = ttf_parser::Face::parse(&face.data, face.index).map_err(|e| format!("Failed to read font {}: {}", face.name, e))?;
+    let mut widths: Vec<Object> = Vec::new();
+    let mut run: Vec<Object> = Vec::new();
+    let mut run_start = 0u16;
+    let mut previous: Option<u16> = None;
+    for &glyph in face.used.keys() {
+        if previous.map(|p| p + 1 != glyph).unwrap_or(false) {
+            widths.push(run_start.into());
+            widths.push(Object::Array(std::mem::take(&mut run)));
+        }
+        if run.is_empty() {
+            run_start = glyph;
+        }
+        let advance = parsed.glyph_hor_advance(ttf_parser::GlyphId(glyph)).unwrap_or(0) as f32;
+        run.push(Object::Real((advance * scale).round()));
+        previous = Some(glyph);
+    }
+    if !run.is_empty() {
+        widths.push(run_start.into());
+        widths.push(Object::Array(run));
+    }
+
+    let mut cid_font = dictionary! {
+        "Type" => "Font",
+        "Subtype" => if face.cff { "CIDFontType0" } else { "CIDFontType2" },
+        "BaseFont" => base_font.as_str(),
+        "CIDSystemInfo" => dictionary! {
+            "Registry" => Object::string_literal("Adobe"),
+            "Ordering" => Object::string_literal("Identity"),
+            "Supplement" => 0,
+        },
+        "FontDescriptor" => descriptor_id,
+        "DW" => 1000,
+        "W" => widths,
+    };
+    if !face.cff {
+        cid_font.set("CIDToGIDMap", "Identity");
+    }
+    let cid_font_id = doc.add_object(cid_font);
+    let to_unicode_id = doc.add_object(Stream::new(Dictionary::new(), to_unicode_cmap(&face.used).into_bytes()));
+
+    Ok(doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type0",
+        "BaseFont" => base_font.as_str(),
+        "Encoding" => "Identity-H",
+        "DescendantFonts" => vec![Object::Reference(cid_font_id)],
+        "ToUnicode" => to_unicode_id,
+    }))
+}
+
+/// Six capital letters derived from the glyph set, as subset fonts are named
+fn subset_tag<'a>(glyphs: impl Iterator<Item = &'a u16>) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for glyph in glyphs {
+        for byte in glyph.to_be_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    (0..6)
+        .map(|_| {
+            let letter = (b'A' + (hash % 26) as u8) as char;
+            hash /= 26;
+            letter
+        })
+        .collect()
+}
+
+fn to_unicode_cmap(used: &BTreeMap<u16, char>) -> String {
+    let mut cmap = String::from(
+        "/CIDInit /ProcSet findresource begin\n12 dict begin\nbegincmap\n\
+         /CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n\
+         /CMapName /Adobe-Identity-UCS def\n/CMapType 2 def\n\
+         1 begincodespacerange\n<0000> <FFFF>\nendcodespacerange\n",
+    );
+    let entries: Vec<(&u16, &char)> = used.iter().filter(|(&glyph, _)| glyph != 0).collect();
+    for chunk in entries.chunks(100) {
+        let _ = writeln!(cmap, "{} beginbfchar", chunk.len());
+        for (glyph, c) in chunk {
+            let mut units = [0u16; 2];
+            let hex: String = c.encode_utf16(&mut units).iter().map(|u| format!("{:04X}", u)).collect();
+            let _ = writeln!(cmap, "<{:04X}> <{}>", glyph, hex);
+        }
+        cmap.push_str("endbfchar\n");
+    }
+    cmap.push_str("endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend\n");
+    cmap
+}
+
+// ---------------------------------------------------------------------------
+// TrueType subsetting
+// ---------------------------------------------------------------------------
+
+/// Strip every glyph outline the document doesn't use. Glyph ids are kept
+/// as they are, so the PDF can keep using Identity mappings; unused glyphs
+/// simply become empty.
+fn subset_truetype(data: &[u8], index: u32, used: &BTreeSet<u16>) -> Option<Vec<u8>> {
+    let font = font_offset(data, index)?;
+    let table_count = read_u16(data, font + 4)? as usize;
+    let mut tables: BTreeMap<[u8; 4], &[u8]> = BTreeMap::new();
+    for i in 0..table_count {
+        let record = font + 12 + i * 16;
+        let tag: [u8; 4] = data.get(record..record + 4)?.try_into().ok()?;
+        let offset = read_u32(data, record + 8)? as usize;
+        let length = read_u32(data, record + 12)? as usize;
+        tables.insert(tag, data.get(offset..offset + length)?);
+    }
+
+    let head = *tables.get(b"head")?;
+    let long_loca = read_u16(head, 50)? == 1;
+    let glyph_count = read_u16(tables.get(b"maxp")?, 4)? as usize;
+    let loca = *tables.get(b"loca")?;
+    let glyf = *tables.get(b"glyf")?;
+    let glyph_range = |glyph: usize| -> Option<(usize, usize)> {
+        if long_loca {
+            Some((read_u32(loca, glyph * 4)? as usize, read_u32(loca, glyph * 4 + 4)? as usize))
+        } else {
+            Some((read_u16(loca, glyph * 2)? as usize * 2, read_u16(loca, glyph * 2 + 2)? as usize * 2))
+        }
+    };
+
+    // Composite glyphs pull in the glyphs they are built from
+    let mut keep: BTreeSet<u16> = used.clone();
+    keep.insert(0);
+    let mut queue: Vec<u16> = keep.iter().copied().collect();
+    while let Some(glyph) = queue.pop() {
+        if glyph as usize >= glyph_count {
+            continue;
+        }
+        let (start, end) = glyph_range(glyph as usize)?;
+        let outline = glyf.get(start..end)?;
+        if outline.len() < 10 || (read_u16(outline, 0)? as i16) >= 0 {
+            continue;
+        }
+        let mut offset = 10;
+        loop {
+            let flags = read_u16(outline, offset)?;
+            let component = read_u16(outline, offset + 2)?;
+            if keep.insert(component) {
+                queue.push(component);
+            }
+            offset += 4 + if flags & 0x0001 != 0 { 4 } else { 2 };
+            offset += if flags & 0x0008 != 0 {
+                2
+            } else if flags & 0x0040 != 0 {
+                4
+            } else if flags & 0x0080 != 0 {
+                8
+            } else {
+                0
+            };
+            if flags & 0x0020 == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut new_glyf = Vec::new();
+    let mut new_loca = Vec::with_capacity((glyph_count + 1) * 4);
+    for glyph in 0..glyph_count {
+        new_loca.extend_from_slice(&(new_glyf.len() as u32).to_be_bytes());
+        if keep.contains(&(glyph as u16)) {
+            let (start, end) = glyph_range(glyph)?;
+            new_glyf.extend_from_slice(glyf.get(start..end)?);
+            while new_glyf.len() % 4 != 0 {
+                new_glyf.push(0);
+            }
+        }
+    }
+    new_loca.extend_from_slice(&(new_glyf.len() as u32).to_be_bytes());
+
+    let mut new_head = head.to_vec();
+    new_head[8..12].copy_from_slice(&[0, 0, 0, 0]);
+    new_head[50..52].copy_from_slice(&1u16.to_be_bytes());
+
+    let mut out_tables: BTreeMap<[u8; 4], Vec<u8>> = BTreeMap::new();
+    for tag in [b"cvt ", b"fpgm", b"prep", b"hhea", b"hmtx", b"maxp", b"OS/2", b"name", b"post", b"cmap"] {
+        if let Some(table) = tables.get(tag) {
+            out_tables.insert(*tag, table.to_vec());
+        }
+    }
+    out_tables.insert(*b"head", new_head);
+    out_tables.insert(*b"loca", new_loca);
+    out_tables.insert(*b"glyf", new_glyf);
+
+    // Table directory, tables sorted by tag as the format requires
+    let count = out_tables.len() as u16;
+    let mut search_range = 1u16;
+    let mut entry_selector = 0u16;
+    while search_range * 2 <= count {
+        search_range *= 2;
+        entry_selector += 1;
+    }
+    let mut font_data = Vec::new();
+    font_data.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+    font_data.extend_from_slice(&count.to_be_bytes());
+    font_data.extend_from_slice(&(search_range * 16).to_be_bytes());
+    font_data.extend_from_slice(&entry_selector.to_be_bytes());
+    font_data.extend_from_slice(&(count * 16 - search_range * 16).to_be_bytes());
+    let mut offset = 12 + out_tables.len() * 16;
+    let mut head_offset = 0;
+    for (tag, table) in &out_tables {
+        if tag == b"head" {
+            head_offset = offset;
+        }
+        font_data.extend_from_slice(tag);
+        font_data.extend_from_slice(&table_checksum(table).to_be_bytes());
+        font_data.extend_from_slice(&(offset as u32).to_be_bytes());
+        font_data.extend_from_slice(&(table.len() as u32).to_be_bytes());
+        offset += (table.len() + 3) & !3;
+    }
+    for table in out_tables.values() {
+        font_data.extend_from_slice(table);
+        while font_data.len() % 4 != 0 {
+            font_data.push(0);
+        }
+    }
+    let adjustment = 0xB1B0_AFBAu32.wrapping_sub(table_checksum(&font_data));
+    font_data[head_offset + 8..head_offset + 12].copy_from_slice(&adjustment.to_be_bytes());
+    Some(font_data)
+}
+
+/// Offset of a font's table directory, looking inside TrueType collections
+fn font_offset(data: &[u8], index: u32) -> Option<usize> {
+    if data.get(0..4)? == b"ttcf" {
+        let count = read_u32(data, 8)?;
+        if index >= count {
+            return None;
+        }
+        return read_u32(data, 12 + index as usize * 4).map(|o| o as usize);
+    }
+    Some(0)
+}
+
+fn table_checksum(table: &[u8]) -> u32 {
+    table.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0u8; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
@@ -1,35 +1,62 @@
-use image::{ImageFormat, GenericImageView};
+use image::{DynamicImage, ImageDecoder, ImageFormat, GenericImageView, Rgba, RgbaImage};
 use std::fs::File;
 use std::io::{BufWriter, Cursor};
 use std::path::Path;
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use resvg::tiny_skia;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use resvg::usvg::{fontdb, Options, Tree};
 use crate::{ConversionResult, get_image_format};
+use crate::converters::text_tools::escape_markup;
+
+/// Text or logo overlay stamped onto images after decoding
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct WatermarkOptions {
+    pub text: Option<String>,
+    pub logo_path: Option<String>,
+    /// One of "center", "top-left", "top-right", "bottom-left", "bottom-right" (default)
+    pub position: Option<String>,
+    /// 0.0 (invisible) to 1.0 (opaque), defaults to 0.5
+    pub opacity: Option<f32>,
+    /// Overlay width relative to the image width, defaults to 0.25
+    pub scale: Option<f32>,
+    /// Repeat the overlay across the whole image instead of placing it once
+    pub tile: Option<bool>,
+    /// Hex text color such as "#ffffff"
+    pub color: Option<String>,
+}
+
+/// Output color type, bit depth and alpha handling for image conversion
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ColorOptions {
+    /// One of "rgb", "rgba", "gray", "gray-alpha"; defaults to the source color type
+    pub color_type: Option<String>,
+    /// Bits per channel, 8 or 16; defaults to the source depth where the format allows
+    pub bit_depth: Option<u8>,
+    /// "keep" (default) or "flatten" onto `background`
+    pub alpha: Option<String>,
+    /// Hex background used when flattening alpha, defaults to white
+    pub background: Option<String>,
+    /// Convert pixels from the embedded ICC profile to sRGB
+    pub to_srgb: Option<bool>,
+}
 
 pub fn convert_image(
     input_path: &str,
     output_format: &str,
     output_path: &str,
     quality: Option<u8>,
+    watermark: Option<&WatermarkOptions>,
+    color: Option<&ColorOptions>,
 ) -> ConversionResult {
-    let img = match image::open(input_path) {
-        Ok(img) => img,
+    let (img, format) = match load_for_output(input_path, output_format, watermark, color) {
+        Ok(loaded) => loaded,
         Err(e) => {
             return ConversionResult {
                 success: false,
                 output_path: None,
-                error: Some(format!("Failed to open image: {}", e)),
-                data: None,
-            };
-        }
-    };
-
-    let format = match get_image_format(output_format) {
-        Some(f) => f,
-        None => {
-            return ConversionResult {
-                success: false,
-                output_path: None,
-                error: Some(format!("Unsupported output format: {}", output_format)),
+                error: Some(e),
                 data: None,
             };
         }
@@ -75,6 +102,390 @@ pub fn convert_image(
     }
 }
 
+/// Size budget for the optimize mode of image conversion
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OptimizeOptions {
+    /// Largest acceptable output size in bytes
+    pub max_bytes: u64,
+    /// Lowest encoder quality the search may settle on, defaults to 40
+    pub min_quality: Option<u8>,
+    /// Shrink the dimensions once the quality floor alone can't meet the budget
+    pub allow_resize: Option<bool>,
+}
+
+/// Convert an image while searching encoder quality (and optionally dimensions)
+/// for the best result that fits within a byte budget
+pub fn optimize_image(
+    input_path: &str,
+    output_format: &str,
+    output_path: &str,
+    quality: Option<u8>,
+    options: &OptimizeOptions,
+    watermark: Option<&WatermarkOptions>,
+    color: Option<&ColorOptions>,
+) -> ConversionResult {
+    let (img, format) = match load_for_output(input_path, output_format, watermark, color) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(e),
+                data: None,
+            };
+        }
+    };
+
+    let original_bytes = std::fs::metadata(input_path).map(|m| m.len()).unwrap_or(0);
+    let max_quality = quality.unwrap_or(90).clamp(1, 100);
+    let min_quality = options.min_quality.unwrap_or(40).clamp(1, max_quality);
+    let allow_resize = options.allow_resize.unwrap_or(false);
+
+    let mut candidate = img;
+    let mut best: Option<(Vec<u8>, Option<u8>, u32, u32)> = None;
+    let mut fits = false;
+
+    // Each pass searches quality at the current size, then shrinks if allowed
+    for _ in 0..12 {
+        let search = if matches!(format, ImageFormat::Jpeg | ImageFormat::WebP) {
+            search_quality(&candidate, format, min_quality, max_quality, options.max_bytes)
+        } else {
+            // The other encoders are lossless, so only resizing can help
+            encode_image(&candidate, format, max_quality).map(|bytes| {
+                let fits = bytes.len() as u64 <= options.max_bytes;
+                (bytes, None, fits)
+            })
+        };
+
+        let (bytes, chosen_quality, pass_fits) = match search {
+            Ok(r) => r,
+            Err(e) => {
+                return ConversionResult {
+                    success: false,
+                    output_path: None,
+                    error: Some(format!("Failed to encode image: {}", e)),
+                    data: None,
+                };
+            }
+        };
+
+        let (width, height) = candidate.dimensions();
+        let size = bytes.len() as u64;
+        best = Some((bytes, chosen_quality, width, height));
+        fits = pass_fits;
+
+        if fits || !allow_resize || width.min(height) <= 16 {
+            break;
+        }
+
+        // Encoded size scales roughly with pixel count
+        let factor = ((options.max_bytes as f64 / size as f64).sqrt() * 0.95).clamp(0.3, 0.95);
+        let new_width = ((width as f64 * factor) as u32).max(16);
+        let new_height = ((height as f64 * factor) as u32).max(16);
+        candidate = candidate.resize_exact(new_width, new_height, image::imageops::FilterType::Lanczos3);
+    }
+
+    let (bytes, chosen_quality, width, height) = match best {
+        Some(b) => b,
+        None => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some("Failed to encode image".to_string()),
+                data: None,
+            };
+        }
+    };
+
+    // Without a quality setting there is no smaller encoding to fall back on,
+    // so an oversized lossless (PNG, ...) result is a failure, not a best effort
+    if !fits && chosen_quality.is_none() {
+        let hint = if allow_resize { "even at the smallest size tried" } else { "at full size; allow resizing to shrink it" };
+        return ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some(format!(
+                "The {} encoder is lossless and the image is {} bytes, over the {} byte budget {}",
+                output_format.to_uppercase(),
+                bytes.len(),
+                options.max_bytes,
+                hint
+            )),
+            data: None,
+        };
+    }
+
+    if let Err(e) = std::fs::write(output_path, &bytes) {
+        return ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some(format!("Failed to save image: {}", e)),
+            data: None,
+        };
+    }
+
+    let output_bytes = bytes.len() as u64;
+    let saved_bytes = original_bytes.saturating_sub(output_bytes);
+    let report = serde_json::json!({
+        "quality": chosen_quality,
+        "width": width,
+        "height": height,
+        "budget_bytes": options.max_bytes,
+        "fits_budget": fits,
+        "original_bytes": original_bytes,
+        "output_bytes": output_bytes,
+        "saved_bytes": saved_bytes,
+        "saved_percent": if original_bytes > 0 {
+            (saved_bytes as f64 / original_bytes as f64 * 1000.0).round() / 10.0
+        } else {
+            0.0
+        },
+    });
+
+    ConversionResult {
+        success: true,
+        output_path: Some(output_path.to_string()),
+        error: None,
+        data: Some(serde_json::to_string_pretty(&report).unwrap()),
+    }
+}
+
+/// Open an image and get it ready for encoding in `output_format`: resolve
+/// the format, apply the watermark and convert its colors
+fn load_for_output(
+    input_path: &str,
+    output_format: &str,
+    watermark: Option<&WatermarkOptions>,
+    color: Option<&ColorOptions>,
+) -> Result<(DynamicImage, ImageFormat), String> {
+    let to_srgb = color.and_then(|c| c.to_srgb).unwrap_or(false);
+    let mut img = open_image(input_path, to_srgb).map_err(|e| format!("Failed to open image: {}", e))?;
+    let format = get_image_format(output_format).ok_or_else(|| format!("Unsupported output format: {}", output_format))?;
+    if let Some(wm) = watermark {
+        apply_watermark(&mut img, wm).map_err(|e| format!("Failed to apply watermark: {}", e))?;
+    }
+    let img = prepare_color(img, format, color).map_err(|e| format!("Failed to convert colors: {}", e))?;
+    Ok((img, format))
+}
+
+/// Decode an image, optionally converting it from its embedded ICC profile to sRGB
+pub fn open_image(input_path: &str, to_srgb: bool) -> Result<DynamicImage, String> {
+    let reader = image::ImageReader::open(input_path)
+        .map_err(|e| e.to_string())?
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?;
+    let mut decoder = reader.into_decoder().map_err(|e| e.to_string())?;
+    let icc = decoder.icc_profile().unwrap_or(None);
+    let img = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+
+    match icc {
+        Some(icc) if to_srgb => icc_to_srgb(img, &icc),
+        _ => Ok(img),
+    }
+}
+
+/// Transform pixel data from an ICC profile into sRGB, keeping 16-bit depth.
+/// RGB profiles keep the image in color and gray profiles keep it in gray;
+/// CMYK and other profiles describe data the decoder has already turned into
+/// RGB, so those images are left as decoded.
+fn icc_to_srgb(img: DynamicImage, icc: &[u8]) -> Result<DynamicImage, String> {
+    let source = moxcms::ColorProfile::new_from_slice(icc)
+        .map_err(|e| format!("Invalid ICC profile: {:?}", e))?;
+    let gray = match (source.color_space, img.color().has_color()) {
+        (moxcms::DataColorSpace::Rgb, true) => false,
+        (moxcms::DataColorSpace::Gray, false) => true,
+        _ => return Ok(img),
+    };
+    let srgb = moxcms::ColorProfile::new_srgb();
+    let has_alpha = img.color().has_alpha();
+    let is_16bit = img.color().bytes_per_pixel() / img.color().channel_count() > 1;
+    let (src_layout, dst_layout) = match (gray, has_alpha) {
+        (false, false) => (moxcms::Layout::Rgb, moxcms::Layout::Rgb),
+        (false, true) => (moxcms::Layout::Rgba, moxcms::Layout::Rgba),
+        (true, false) => (moxcms::Layout::Gray, moxcms::Layout::Rgb),
+        (true, true) => (moxcms::Layout::GrayAlpha, moxcms::Layout::Rgba),
+    };
+    let (width, height) = img.dimensions();
+    let unsupported = |e: moxcms::CmsError| format!("Unsupported ICC profile: {:?}", e);
+    let rgb_len = (width * height) as usize * if has_alpha { 4 } else { 3 };
+
+    let converted = if is_16bit {
+        let transform = source
+            .create_transform_16bit(src_layout, &srgb, dst_layout, moxcms::TransformOptions::default())
+            .map_err(unsupported)?;
+        let src: Vec<u16> = match (gray, has_alpha) {
+            (false, false) => img.to_rgb16().into_raw(),
+            (false, true) => img.to_rgba16().into_raw(),
+            (true, false) => img.to_luma16().into_raw(),
+            (true, true) => img.to_luma_alpha16().into_raw(),
+        };
+        let mut dst = vec![0u16; rgb_len];
+        transform.transform(&src, &mut dst).map_err(unsupported)?;
+        if has_alpha {
+            DynamicImage::ImageRgba16(image::ImageBuffer::from_raw(width, height, dst).ok_or("Invalid image buffer")?)
+        } else {
+            DynamicImage::ImageRgb16(image::ImageBuffer::from_raw(width, height, dst).ok_or("Invalid image buffer")?)
+        }
+    } else {
+        let transform = source
+            .create_transform_8bit(src_layout, &srgb, dst_layout, moxcms::TransformOptions::default())
+            .map_err(unsupported)?;
+        let src: Vec<u8> = match (gray, has_alpha) {
+            (false, false) => img.to_rgb8().into_raw(),
+            (false, true) => img.to_rgba8().into_raw(),
+            (true, false) => img.to_luma8().into_raw(),
+            (true, true) => img.to_luma_alpha8().into_raw(),
+        };
+        let mut dst = vec![0u8; rgb_len];
+        transform.transform(&src, &mut dst).map_err(unsupported)?;
+        if has_alpha {
+            DynamicImage::ImageRgba8(image::ImageBuffer::from_raw(width, height, dst).ok_or("Invalid image buffer")?)
+        } else {
+            DynamicImage::ImageRgb8(image::ImageBuffer::from_raw(width, height, dst).ok_or("Invalid image buffer")?)
+        }
+    };
+
+    // A gray profile maps to neutral sRGB, so the image can stay single-channel
+    Ok(match (gray, has_alpha, is_16bit) {
+        (false, _, _) => converted,
+        (true, false, false) => DynamicImage::ImageLuma8(converted.to_luma8()),
+        (true, true, false) => DynamicImage::ImageLumaA8(converted.to_luma_alpha8()),
+        (true, false, true) => DynamicImage::ImageLuma16(converted.to_luma16()),
+        (true, true, true) => DynamicImage::ImageLumaA16(converted.to_luma_alpha16()),
+    })
+}
+
+/// Resolve the requested color type, bit depth and alpha handling against
+/// what the output format can actually encode
+pub fn prepare_color(img: DynamicImage, format: ImageFormat, options: Option<&ColorOptions>) -> Result<DynamicImage, String> {
+    let source = img.color();
+    let source_depth = source.bytes_per_pixel() / source.channel_count() * 8;
+    let color_type = options.and_then(|o| o.color_type.as_deref());
+
+    let gray = match color_type {
+        None => !source.has_color(),
+        Some("gray") | Some("gray-alpha") => true,
+        Some("rgb") | Some("rgba") => false,
+        Some(other) => return Err(format!("Unknown color type: {}", other)),
+    };
+    let format_has_alpha = !matches!(format, ImageFormat::Jpeg);
+    let flatten = options.and_then(|o| o.alpha.as_deref()) == Some("flatten");
+    let alpha = match color_type {
+        None => source.has_alpha(),
+        Some(t) => t.ends_with('a') || t.ends_with("alpha"),
+    } && format_has_alpha && !flatten;
+
+    let wide = match options.and_then(|o| o.bit_depth).unwrap_or(source_depth.min(16)) {
+        8 => false,
+        16 => true,
+        other => return Err(format!("Unsupported bit depth: {}", other)),
+    } && matches!(format, ImageFormat::Png | ImageFormat::Tiff);
+
+    // Composite onto the background instead of silently dropping alpha
+    let img = if source.has_alpha() && !alpha {
+        let background = options
+            .and_then(|o| o.background.as_deref())
+            .and_then(parse_hex_color)
+            .unwrap_or(Rgba([255, 255, 255, 255]));
+        flatten_alpha(&img, background)
+    } else {
+        img
+    };
+
+    Ok(match (gray, alpha, wide) {
+        (true, false, false) => DynamicImage::ImageLuma8(img.to_luma8()),
+        (true, true, false) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        (true, false, true) => DynamicImage::ImageLuma16(img.to_luma16()),
+        (true, true, true) => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        (false, false, false) => DynamicImage::ImageRgb8(img.to_rgb8()),
+        (false, true, false) => DynamicImage::ImageRgba8(img.to_rgba8()),
+        (false, false, true) => DynamicImage::ImageRgb16(img.to_rgb16()),
+        (false, true, true) => DynamicImage::ImageRgba16(img.to_rgba16()),
+    })
+}
+
+fn flatten_alpha(img: &DynamicImage, background: Rgba<u8>) -> DynamicImage {
+    let rgba = img.to_rgba32f();
+    let bg = [
+        background[0] as f32 / 255.0,
+        background[1] as f32 / 255.0,
+        background[2] as f32 / 255.0,
+    ];
+    let flattened = image::Rgb32FImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let p = rgba.get_pixel(x, y);
+        let a = p[3];
+        image::Rgb([
+            p[0] * a + bg[0] * (1.0 - a),
+            p[1] * a + bg[1] * (1.0 - a),
+            p[2] * a + bg[2] * (1.0 - a),
+        ])
+    });
+    DynamicImage::ImageRgb32F(flattened)
+}
+
+/// Binary-search the highest JPEG or WebP quality whose output fits `max_bytes`.
+/// Falls back to the `min_quality` encoding when nothing fits.
+fn search_quality(
+    img: &DynamicImage,
+    format: ImageFormat,
+    min_quality: u8,
+    max_quality: u8,
+    max_bytes: u64,
+) -> Result<(Vec<u8>, Option<u8>, bool), image::ImageError> {
+    let (mut low, mut high) = (min_quality, max_quality);
+    let mut found: Option<(Vec<u8>, u8)> = None;
+
+    while low <= high {
+        let mid = low + (high - low) / 2;
+        let bytes = encode_image(img, format, mid)?;
+        if bytes.len() as u64 <= max_bytes {
+            found = Some((bytes, mid));
+            low = mid + 1;
+        } else if mid == min_quality {
+            break;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    match found {
+        Some((bytes, q)) => Ok((bytes, Some(q), true)),
+        None => Ok((encode_image(img, format, min_quality)?, Some(min_quality), false)),
+    }
+}
+
+/// Encode an image into memory, honoring quality for JPEG and (lossy) WebP
+fn encode_image(img: &DynamicImage, format: ImageFormat, quality: u8) -> Result<Vec<u8>, image::ImageError> {
+    let mut buffer = Cursor::new(Vec::new());
+    if format == ImageFormat::Jpeg {
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
+        img.write_with_encoder(encoder)?;
+    } else if format == ImageFormat::WebP {
+        return encode_lossy_webp(img, quality);
+    } else {
+        img.write_to(&mut buffer, format)?;
+    }
+    Ok(buffer.into_inner())
+}
+
+/// Encode lossy WebP through libwebp; the `image` encoder only writes lossless
+fn encode_lossy_webp(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, image::ImageError> {
+    let (width, height) = img.dimensions();
+    let encoded = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        webp::Encoder::from_rgba(&rgba, width, height).encode_simple(false, quality as f32)
+    } else {
+        let rgb = img.to_rgb8();
+        webp::Encoder::from_rgb(&rgb, width, height).encode_simple(false, quality as f32)
+    };
+    encoded.map(|memory| memory.to_vec()).map_err(|e| {
+        image::ImageError::Encoding(image::error::EncodingError::new(
+            image::error::ImageFormatHint::Exact(ImageFormat::WebP),
+            format!("{:?}", e),
+        ))
+    })
+}
+
 pub fn resize_image(
     input_path: &str,
     output_path: &str,
@@ -142,3 +553,617 @@ pub fn get_image_info(input_path: &str) -> Result<(u32, u32, String), String> {
     let color_type = format!("{:?}", img.color());
     Ok((width, height, color_type))
 }
+
+/// Parse a "#rrggbb" or "#rrggbbaa" hex color
+pub fn parse_hex_color(color: &str) -> Option<Rgba<u8>> {
+    let hex = color.trim().trim_start_matches('#');
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    match hex.len() {
+        6 => Some(Rgba([channel(0)?, channel(2)?, channel(4)?, 255])),
+        8 => Some(Rgba([channel(0)?, channel(2)?, channel(4)?, channel(6)?])),
+        _ => None,
+    }
+}
+
+/// Stamp a text or logo overlay onto an image in place
+pub fn apply_watermark(img: &mut DynamicImage, options: &WatermarkOptions) -> Result<(), String> {
+    let (width, height) = img.dimensions();
+    let scale = options.scale.unwrap_or(0.25).clamp(0.01, 1.0);
+    let target_width = ((width as f32 * scale) as u32).max(1);
+
+    let mut overlay = if let Some(logo_path) = &options.logo_path {
+        render_logo(logo_path, target_width)?
+    } else if let Some(text) = options.text.as_deref().filter(|t| !t.trim().is_empty()) {
+        let color = options
+            .color
+            .as_deref()
+            .and_then(parse_hex_color)
+            .unwrap_or(Rgba([255, 255, 255, 255]));
+        render_text(text, color, target_width)?
+    } else {
+        return Err("Watermark needs either text or a logo".to_string());
+    };
+
+    let opacity = options.opacity.unwrap_or(0.5).clamp(0.0, 1.0);
+    for pixel in overlay.pixels_mut() {
+        pixel[3] = (pixel[3] as f32 * opacity).round() as u8;
+    }
+
+    let had_alpha = img.color().has_alpha();
+    let mut canvas = img.to_rgba8();
+    let (ow, oh) = overlay.dimensions();
+
+    if options.tile.unwrap_or(false) {
+        // Leave half an overlay of space between repeats
+        let step_x = (ow + ow / 2).max(1) as usize;
+        let step_y = (oh + oh / 2).max(1) as usize;
+        for y in (0..height).step_by(step_y) {
+            for x in (0..width).step_by(step_x) {
+                image::imageops::overlay(&mut canvas, &overlay, x as i64, y as i64);
+            }
+        }
+    } else {
+        let margin = (width.min(height) / 50) as i64;
+        let (max_x, max_y) = (width as i64 - ow as i64, height as i64 - oh as i64);
+        let (x, y) = match options.position.as_deref().unwrap_or("bottom-right") {
+            "center" => (max_x / 2, max_y / 2),
+            "top-left" => (margin, margin),
+            "top-right" => (max_x - margin, margin),
+            "bottom-left" => (margin, max_y - margin),
+            _ => (max_x - margin, max_y - margin),
+        };
+        image::imageops::overlay(&mut canvas, &overlay, x, y);
+    }
+
+    *img = if had_alpha {
+        DynamicImage::ImageRgba8(canvas)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+    };
+    Ok(())
+}
+
+fn render_logo(logo_path: &str, target_width: u32) -> Result<RgbaImage, String> {
+    if logo_path.to_lowercase().ends_with(".svg") {
+        let svg_data = std::fs::read(logo_path).map_err(|e| format!("Failed to read logo: {}", e))?;
+        let tree = Tree::from_data(&svg_data, &Options::default())
+            .map_err(|e| format!("Failed to parse logo SVG: {}", e))?;
+        let size = tree.size();
+        let factor = target_width as f32 / size.width();
+        render_tree(&tree, factor)
+    } else {
+        let logo = image::open(logo_path).map_err(|e| format!("Failed to open logo: {}", e))?;
+        let target_height = ((logo.height() as f32 * target_width as f32 / logo.width() as f32) as u32).max(1);
+        Ok(logo
+            .resize_exact(target_width, target_height, image::imageops::FilterType::Lanczos3)
+            .to_rgba8())
+    }
+}
+
+fn render_text(text: &str, color: Rgba<u8>, target_width: u32) -> Result<RgbaImage, String> {
+    // Render large, then scale down to the requested width
+    let cropped = rasterize_text(text, color, 100.0, true, &system_fonts())?;
+    let target_height = ((cropped.height() as f32 * target_width as f32 / cropped.width() as f32) as u32).max(1);
+    Ok(image::imageops::resize(&cropped, target_width, target_height, image::imageops::FilterType::Lanczos3))
+}
+
+/// The system fonts, scanned on first use and shared by every piece of
+/// text rendered afterwards
+fn system_fonts() -> Arc<fontdb::Database> {
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut db = fontdb::Database::new();
+            db.load_system_fonts();
+            Arc::new(db)
+        })
+        .clone()
+}
+
+/// Rasterize a single line of text with the given fonts, cropped to the glyphs
+fn rasterize_text(
+    text: &str,
+    color: Rgba<u8>,
+    font_size: f32,
+    bold: bool,
+    fonts: &Arc<fontdb::Database>,
+) -> Result<RgbaImage, String> {
+    let escaped = escape_markup(text);
+    // Generous canvas so nothing is clipped before cropping
+    let canvas_width = (text.chars().count() as f32 * font_size).max(font_size) + font_size * 0.4;
+    let svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}"><text x="{}" y="{}" font-family="Arial, Helvetica, DejaVu Sans, Liberation Sans, sans-serif" font-size="{}" font-weight="{}" fill="rgb({},{},{})">{}</text></svg>"#,
+        canvas_width.ceil(),
+        (font_size * 1.6).ceil(),
+        font_size * 0.2,
+        font_size * 1.2,
+        font_size,
+        if bold { "bold" } else { "normal" },
+        color[0], color[1], color[2], escaped
+    );
+
+    let opt = Options { fontdb: fonts.clone(), ..Options::default() };
+    let tree = Tree::from_data(svg.as_bytes(), &opt).map_err(|e| e.to_string())?;
+    let rendered = render_tree(&tree, 1.0)?;
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in rendered.enumerate_pixels() {
+        if pixel[3] > 0 {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    if min_x > max_x {
+        return Err("No font available to render text".to_string());
+    }
+
+    Ok(image::imageops::crop_imm(&rendered, min_x, min_y, max_x - min_x + 1, max_y - min_y + 1).to_image())
+}
+
+/// Render a parsed SVG tree at the given scale onto a transparent RGBA image
+pub fn render_tree(tree: &Tree, factor: f32) -> Result<RgbaImage, String> {
+    let size = tree.size();
+    let width = ((size.width() * factor) as u32).max(1);
+    let height = ((size.height() * factor) as u32).max(1);
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| "Failed to create image buffer".to_string())?;
+    resvg::render(tree, tiny_skia::Transform::from_scale(factor, factor), &mut pixmap.as_mut());
+    Ok(pixmap_to_rgba(&pixmap))
+}
+
+/// Convert a premultiplied tiny-skia pixmap into a straight-alpha RGBA image
+pub fn pixmap_to_rgba(pixmap: &tiny_skia::Pixmap) -> RgbaImage {
+    let mut out = RgbaImage::new(pixmap.width(), pixmap.height());
+    for (dst, src) in out.pixels_mut().zip(pixmap.pixels()) {
+        let c = src.demultiply();
+        *dst = Rgba([c.red(), c.green(), c.blue(), c.alpha()]);
+    }
+    out
+}
+
+/// Wrap a decoded image as a PDF image object, JPEG-compressed when a quality is given
+pub fn image_to_pdf_xobject(img: &DynamicImage, jpeg_quality: Option<u8>) -> Result<printpdf::ImageXObject, String> {
+    // PDF pages are opaque, so composite transparency onto white first
+    let rgb = if img.color().has_alpha() {
+        prepare_color(img.clone(), ImageFormat::Jpeg, None)?.to_rgb8()
+    } else {
+        img.to_rgb8()
+    };
+    let (width, height) = rgb.dimensions();
+
+    let (image_data, image_filter) = match jpeg_quality {
+        Some(q) => {
+            let bytes = encode_image(&DynamicImage::ImageRgb8(rgb), ImageFormat::Jpeg, q)
+                .map_err(|e| e.to_string())?;
+            (bytes, Some(printpdf::ImageFilter::DCT))
+        }
+        None => (rgb.into_raw(), None),
+    };
+
+    Ok(printpdf::ImageXObject {
+        width: printpdf::Px(width as usize),
+        height: printpdf::Px(height as usize),
+        color_space: printpdf::ColorSpace::Rgb,
+        bits_per_component: printpdf::ColorBits::Bit8,
+        interpolate: true,
+        image_data,
+        image_filter,
+        smask: None,
+        clipping_bbox: None,
+    })
+}
+
+/// Layout options for contact sheets
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ContactSheetOptions {
+    /// Defaults to a roughly square grid
+    pub columns: Option<u32>,
+    /// Size of the square box each thumbnail is fitted into, defaults to 256
+    pub cell_size: Option<u32>,
+    /// Gap between cells and around the edge, defaults to 16
+    pub spacing: Option<u32>,
+    /// Print the file name under each thumbnail, defaults to true
+    pub captions: Option<bool>,
+    /// Hex background color, defaults to white
+    pub background: Option<String>,
+    /// Hex caption color, defaults to dark gray
+    pub caption_color: Option<String>,
+    /// JPEG quality for JPEG and PDF output
+    pub quality: Option<u8>,
+}
+
+/// Lay out many images (folders and PDF pages included) in a captioned grid
+/// and write it as PNG, JPEG or a single-page PDF
+pub fn create_contact_sheet(
+    input_paths: &[String],
+    output_path: &str,
+    options: &ContactSheetOptions,
+) -> ConversionResult {
+    // Sheets can be built concurrently, so each call gets its own folder
+    static SHEET_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let work_dir = std::env::temp_dir().join(format!(
+        "fileflow_sheet_{}_{}",
+        std::process::id(),
+        SHEET_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let (sources, failures) = collect_sheet_sources(input_paths, &work_dir);
+
+    let cell = options.cell_size.unwrap_or(256).max(16);
+    let spacing = options.spacing.unwrap_or(16);
+    let captions = options.captions.unwrap_or(true);
+    let font_size = (cell as f32 / 12.0).clamp(10.0, 24.0);
+    let caption_height = if captions { (font_size * 1.6) as u32 } else { 0 };
+    let background = options
+        .background
+        .as_deref()
+        .and_then(parse_hex_color)
+        .unwrap_or(Rgba([255, 255, 255, 255]));
+    let caption_color = options
+        .caption_color
+        .as_deref()
+        .and_then(parse_hex_color)
+        .unwrap_or(Rgba([51, 51, 51, 255]));
+
+    let fonts = if captions { Some(system_fonts()) } else { None };
+    let mut thumbnails = Vec::new();
+    let mut skipped = 0;
+    for (path, caption) in &sources {
+        match image::open(path) {
+            Ok(img) => thumbnails.push((img.thumbnail(cell, cell).to_rgba8(), caption.clone())),
+            Err(_) => skipped += 1,
+        }
+    }
+    let _ = std::fs::remove_dir_all(&work_dir);
+
+    if thumbnails.is_empty() {
+        let error = if failures.is_empty() {
+            "No readable images were found".to_string()
+        } else {
+            format!("No readable images were found; {}", failures.join("; "))
+        };
+        return ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some(error),
+            data: None,
+        };
+    }
+
+    let count = thumbnails.len() as u32;
+    let columns = options
+        .columns
+        .unwrap_or_else(|| (count as f64).sqrt().ceil() as u32)
+        .clamp(1, count);
+    let rows = count.div_ceil(columns);
+    let width = columns * cell + (columns + 1) * spacing;
+    let height = rows * (cell + caption_height) + (rows + 1) * spacing;
+    let mut sheet = RgbaImage::from_pixel(width, height, background);
+
+    for (i, (thumb, caption)) in thumbnails.iter().enumerate() {
+        let (col, row) = (i as u32 % columns, i as u32 / columns);
+        let cell_x = spacing + col * (cell + spacing);
+        let cell_y = spacing + row * (cell + caption_height + spacing);
+
+        // Center the thumbnail within its box
+        let x = cell_x + (cell - thumb.width()) / 2;
+        let y = cell_y + (cell - thumb.height()) / 2;
+        image::imageops::overlay(&mut sheet, thumb, x as i64, y as i64);
+
+        if let Some(fonts) = &fonts {
+            if let Ok(mut label) = rasterize_text(caption, caption_color, font_size, false, fonts) {
+                if label.width() > cell {
+                    let scaled_height = ((label.height() * cell / label.width()).max(1)).min(caption_height);
+                    label = image::imageops::resize(&label, cell, scaled_height, image::imageops::FilterType::Triangle);
+                }
+                let label_x = cell_x + (cell - label.width()) / 2;
+                let label_y = cell_y + cell + caption_height.saturating_sub(label.height()) / 2;
+                image::imageops::overlay(&mut sheet, &label, label_x as i64, label_y as i64);
+            }
+        }
+    }
+
+    let sheet = DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(sheet).to_rgb8());
+    let extension = Path::new(output_path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("png")
+        .to_lowercase();
+
+    let saved = if extension == "pdf" {
+        save_image_as_pdf_page(&sheet, output_path, options.quality.unwrap_or(90))
+    } else {
+        let format = get_image_format(&extension).unwrap_or(ImageFormat::Png);
+        encode_image(&sheet, format, options.quality.unwrap_or(85))
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| std::fs::write(output_path, bytes).map_err(|e| e.to_string()))
+    };
+
+    match saved {
+        Ok(_) => ConversionResult {
+            success: true,
+            output_path: Some(output_path.to_string()),
+            error: None,
+            data: Some({
+                let mut summary = if skipped > 0 {
+                    format!("Placed {} image(s) in a {}x{} grid ({} skipped)", count, columns, rows, skipped)
+                } else {
+                    format!("Placed {} image(s) in a {}x{} grid", count, columns, rows)
+                };
+                for failure in &failures {
+                    summary.push_str(&format!("\n{}", failure));
+                }
+                summary
+            }),
+        },
+        Err(e) => ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some(format!("Failed to save contact sheet: {}", e)),
+            data: None,
+        },
+    }
+}
+
+/// Expand folders and PDFs into (image path, caption) pairs, rendering PDF
+/// pages into `work_dir`. PDFs that fail to render are described in the
+/// second list.
+fn collect_sheet_sources(input_paths: &[String], work_dir: &Path) -> (Vec<(String, String)>, Vec<String>) {
+    let mut sources = Vec::new();
+    let mut failures = Vec::new();
+    for input in input_paths {
+        let path = Path::new(input);
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(input)
+            .to_string();
+
+        if path.is_dir() {
+            let mut entries: Vec<_> = std::fs::read_dir(path)
+                .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+                .unwrap_or_default();
+            entries.sort();
+            for entry in entries {
+                let ext = entry.extension().and_then(|e| e.to_str()).unwrap_or("");
+                if entry.is_file() && get_image_format(ext).is_some() {
+                    let entry_name = entry.file_name().and_then(|n| n.to_str()).unwrap_or("").to_string();
+                    sources.push((entry.to_string_lossy().to_string(), entry_name));
+                }
+            }
+        } else if name.to_lowercase().ends_with(".pdf") {
+            let page_dir = work_dir.join(format!("{}", sources.len()));
+            if let Err(e) = std::fs::create_dir_all(&page_dir) {
+                failures.push(format!("Could not render {}: {}", name, e));
+                continue;
+            }
+            let target = page_dir.join("page.png").to_string_lossy().to_string();
+            let result = crate::converters::pdf_converter::convert_pdf_to_image(input, &target, "png", &Default::default(), None);
+            if !result.success {
+                failures.push(format!("Could not render {}: {}", name, result.error.unwrap_or_default()));
+                continue;
+            }
+            // The result lists every rendered page in order
+            let report: serde_json::Value = result
+                .data
+                .as_deref()
+                .and_then(|d| serde_json::from_str(d).ok())
+                .unwrap_or_default();
+            let pages = report["pages"].as_array().cloned().unwrap_or_default();
+            for page in pages {
+                if let (Some(number), Some(file)) = (page["page"].as_u64(), page["file"].as_str()) {
+                    sources.push((file.to_string(), format!("{} p.{}", name, number)));
+                }
+            }
+        } else {
+            sources.push((input.clone(), name));
+        }
+    }
+    (sources, failures)
+}
+
+/// Write one image as a single PDF page sized to the image at 150 DPI
+fn save_image_as_pdf_page(img: &DynamicImage, output_path: &str, quality: u8) -> Result<(), String> {
+    let dpi = 150.0;
+    let xobject = image_to_pdf_xobject(img, Some(quality))?;
+    let width_mm = printpdf::Mm::from(xobject.width.into_pt(dpi));
+    let height_mm = printpdf::Mm::from(xobject.height.into_pt(dpi));
+
+    let (doc, page, layer) = printpdf::PdfDocument::new("Contact Sheet", width_mm, height_mm, "Layer 1");
+    let layer = doc.get_page(page).get_layer(layer);
+    printpdf::Image::from(xobject).add_to_layer(
+        layer,
+        printpdf::ImageTransform {
+            dpi: Some(dpi),
+            ..Default::default()
+        },
+    );
+
+    let file = File::create(output_path).map_err(|e| e.to_string())?;
+    doc.save(&mut BufWriter::new(file)).map_err(|e| e.to_string())
+}
+
+/// Combine several images into one multi-page TIFF
+pub fn images_to_tiff(input_paths: &[String], output_path: &str) -> ConversionResult {
+    if input_paths.is_empty() {
+        return ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some("No images were given".to_string()),
+            data: None,
+        };
+    }
+
+    match write_tiff_pages(input_paths, output_path) {
+        Ok(pages) => ConversionResult {
+            success: true,
+            output_path: Some(output_path.to_string()),
+            error: None,
+            data: Some(format!("Combined {} page(s) into TIFF", pages)),
+        },
+        Err(e) => {
+            // Don't leave a partial TIFF behind
+            let _ = std::fs::remove_file(output_path);
+            ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(e),
+                data: None,
+            }
+        }
+    }
+}
+
+fn write_tiff_pages(input_paths: &[String], output_path: &str) -> Result<usize, String> {
+    let file = File::create(output_path).map_err(|e| format!("Failed to create output file: {}", e))?;
+    let mut encoder =
+        tiff::encoder::TiffEncoder::new(BufWriter::new(file)).map_err(|e| format!("Failed to start TIFF: {}", e))?;
+
+    for input in input_paths {
+        let img = image::open(input).map_err(|e| format!("Failed to open image {}: {}", input, e))?;
+        let (width, height) = img.dimensions();
+        let written = match img.color() {
+            // Grayscale and bilevel scans stay one channel
+            image::ColorType::L8 | image::ColorType::L16 => {
+                encoder.write_image::<tiff::encoder::colortype::Gray8>(width, height, img.to_luma8().as_raw())
+            }
+            color if color.has_alpha() => {
+                encoder.write_image::<tiff::encoder::colortype::RGBA8>(width, height, img.to_rgba8().as_raw())
+            }
+            _ => encoder.write_image::<tiff::encoder::colortype::RGB8>(width, height, img.to_rgb8().as_raw()),
+        };
+        written.map_err(|e| format!("Failed to write TIFF page: {}", e))?;
+    }
+    Ok(input_paths.len())
+}
+
+/// Split a multi-page TIFF into one image file per page
+pub fn split_tiff(input_path: &str, output_dir: &str, format: &str) -> ConversionResult {
+    let file = match File::open(input_path) {
+        Ok(f) => f,
+        Err(e) => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Failed to open TIFF file: {}", e)),
+                data: None,
+            };
+        }
+    };
+
+    let mut decoder = match tiff::decoder::Decoder::new(std::io::BufReader::new(file)) {
+        Ok(d) => d,
+        Err(e) => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Failed to read TIFF file: {}", e)),
+                data: None,
+            };
+        }
+    };
+
+    if let Err(e) = std::fs::create_dir_all(output_dir) {
+        return ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some(format!("Failed to create output directory: {}", e)),
+            data: None,
+        };
+    }
+
+    let image_format = get_image_format(format).unwrap_or(ImageFormat::Png);
+    let stem = Path::new(input_path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("page");
+
+    let mut page = 0;
+    loop {
+        page += 1;
+        let img = match decode_tiff_page(&mut decoder) {
+            Ok(img) => img,
+            Err(e) => {
+                return ConversionResult {
+                    success: false,
+                    output_path: None,
+                    error: Some(format!("Failed to decode TIFF page {}: {}", page, e)),
+                    data: None,
+                };
+            }
+        };
+
+        let page_path = Path::new(output_dir).join(format!("{}-{}.{}", stem, page, format));
+        let saved = prepare_color(img, image_format, None)
+            .and_then(|img| img.save_with_format(&page_path, image_format).map_err(|e| e.to_string()));
+        if let Err(e) = saved {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Failed to save page {}: {}", page, e)),
+                data: None,
+            };
+        }
+
+        if !decoder.more_images() {
+            break;
+        }
+        if let Err(e) = decoder.next_image() {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Failed to read TIFF page {}: {}", page + 1, e)),
+                data: None,
+            };
+        }
+    }
+
+    ConversionResult {
+        success: true,
+        output_path: Some(output_dir.to_string()),
+        error: None,
+        data: Some(format!("Extracted {} page(s) to {}", page, format.to_uppercase())),
+    }
+}
+
+fn decode_tiff_page<R: std::io::Read + std::io::Seek>(decoder: &mut tiff::decoder::Decoder<R>) -> Result<DynamicImage, String> {
+    use tiff::decoder::DecodingResult as D;
+    use tiff::ColorType as T;
+
+    let (width, height) = decoder.dimensions().map_err(|e| e.to_string())?;
+    let colortype = decoder.colortype().map_err(|e| e.to_string())?;
+    let data = decoder.read_image().map_err(|e| e.to_string())?;
+    let invalid = || "Invalid TIFF page data".to_string();
+
+    Ok(match (colortype, data) {
+        (T::Gray(8), D::U8(d)) => DynamicImage::ImageLuma8(image::ImageBuffer::from_raw(width, height, d).ok_or_else(invalid)?),
+        (T::Gray(16), D::U16(d)) => DynamicImage::ImageLuma16(image::ImageBuffer::from_raw(width, height, d).ok_or_else(invalid)?),
+        (T::GrayA(8), D::U8(d)) => DynamicImage::ImageLumaA8(image::ImageBuffer::from_raw(width, height, d).ok_or_else(invalid)?),
+        (T::GrayA(16), D::U16(d)) => DynamicImage::ImageLumaA16(image::ImageBuffer::from_raw(width, height, d).ok_or_else(invalid)?),
+        (T::RGB(8), D::U8(d)) => DynamicImage::ImageRgb8(image::ImageBuffer::from_raw(width, height, d).ok_or_else(invalid)?),
+        (T::RGB(16), D::U16(d)) => DynamicImage::ImageRgb16(image::ImageBuffer::from_raw(width, height, d).ok_or_else(invalid)?),
+        (T::RGBA(8), D::U8(d)) => DynamicImage::ImageRgba8(image::ImageBuffer::from_raw(width, height, d).ok_or_else(invalid)?),
+        (T::RGBA(16), D::U16(d)) => DynamicImage::ImageRgba16(image::ImageBuffer::from_raw(width, height, d).ok_or_else(invalid)?),
+        (T::Gray(1), D::U8(d)) => {
+            // Bilevel scans are packed eight pixels per byte, rows padded to a byte
+            let row_bytes = width.div_ceil(8) as usize;
+            let pixels = image::GrayImage::from_fn(width, height, |x, y| {
+                let byte = d.get(y as usize * row_bytes + x as usize / 8).copied().unwrap_or(0);
+                let bit = (byte >> (7 - x % 8)) & 1;
+                image::Luma([if bit == 1 { 255 } else { 0 }])
+            });
+            DynamicImage::ImageLuma8(pixels)
+        }
+        (T::CMYK(8), D::U8(d)) => {
+            let pixels = image::RgbImage::from_fn(width, height, |x, y| {
+                let i = (y as usize * width as usize + x as usize) * 4;
+                let k = 255 - *d.get(i + 3).unwrap_or(&0) as u32;
+                let channel = |c: u8| ((255 - c as u32) * k / 255) as u8;
+                image::Rgb([channel(d[i]), channel(d[i + 1]), channel(d[i + 2])])
+            });
+            DynamicImage::ImageRgb8(pixels)
+        }
+        (other, _) => return Err(format!("Unsupported TIFF color type: {:?}", other)),
+    })
+}
@@ -0,0 +1,511 @@
+use std::fs;
+use std::path::Path;
+use crate::ConversionResult;
+use crate::converters::html_renderer::{
+    collapse_whitespace, find_element, html_markup, is_block, parse_html, read_image_source, text_content, HtmlElement,
+    HtmlNode, HIDDEN_ELEMENTS,
+};
+use crate::converters::pdf_tools::finish;
+
+/// Convert an HTML file to Markdown. Pictures embedded as data URIs are
+/// written to a `{name}_files` folder next to the Markdown file; linked
+/// pictures keep their paths.
+pub fn convert_html_to_markdown(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_html_to_markdown(input_path, output_path), output_path)
+}
+
+fn try_convert_html_to_markdown(input_path: &str, output_path: &str) -> Result<String, String> {
+    let html = fs::read_to_string(input_path).map_err(|e| format!("Failed to read file: {}", e))?;
+    let root = parse_html(&html);
+    let mut nodes = find_element(&root, "body").unwrap_or(&root).children.clone();
+
+    let output = Path::new(output_path);
+    let folder = format!("{}_files", output.file_stem().and_then(|s| s.to_str()).unwrap_or("document"));
+    let dir = output.parent().unwrap_or(Path::new("")).join(&folder);
+    let mut images = 0;
+    save_data_images(&mut nodes, &dir, &folder, &mut images)?;
+
+    let markdown = nodes_to_markdown(&nodes);
+    fs::write(output_path, &markdown).map_err(|e| format!("Failed to write Markdown file: {}", e))?;
+    Ok(format!("Converted {} line(s) of Markdown with {} image(s) extracted", markdown.lines().count(), images))
+}
+
+/// Write data: URI pictures to files and point the images at them
+fn save_data_images(nodes: &mut [HtmlNode], dir: &Path, folder: &str, count: &mut usize) -> Result<(), String> {
+    for node in nodes.iter_mut() {
+        let HtmlNode::Element(element) = node else {
+            continue;
+        };
+        let src = element.attr("src").filter(|s| s.starts_with("data:")).map(str::to_string);
+        if let (true, Some(src)) = (element.tag == "img", src) {
+            if let Some((bytes, _)) = read_image_source(&src, dir) {
+                let mime = src[5..].split([';', ',']).next().unwrap_or("").to_lowercase();
+                let extension = match mime.as_str() {
+                    "image/jpeg" | "image/jpg" => "jpg",
+                    "image/gif" => "gif",
+                    "image/webp" => "webp",
+                    "image/bmp" => "bmp",
+                    "image/svg+xml" => "svg",
+                    _ => "png",
+                };
+                *count += 1;
+                let name = format!("image_{:03}.{}", count, extension);
+                fs::create_dir_all(dir).map_err(|e| format!("Failed to create image folder: {}", e))?;
+                fs::write(dir.join(&name), bytes).map_err(|e| format!("Failed to write image: {}", e))?;
+                element.attrs.retain(|(n, _)| n != "src");
+                element.attrs.push(("src".to_string(), format!("{}/{}", folder, name)));
+            }
+        }
+        save_data_images(&mut element.children, dir, folder, count)?;
+    }
+    Ok(())
+}
+
+/// Convert an HTML document to GitHub-flavored Markdown, keeping headings,
+/// emphasis, links, images, nested lists, code blocks and tables. Tables
+/// with merged or block content are kept as HTML.
+pub fn html_to_markdown(html: &str) -> String {
+    let root = parse_html(html);
+    let body = find_element(&root, "body").unwrap_or(&root);
+    nodes_to_markdown(&body.children)
+}
+
+/// Convert parsed HTML content to Markdown
+pub fn nodes_to_markdown(nodes: &[HtmlNode]) -> String {
+    let markdown = blocks(nodes).join("\n\n");
+    if markdown.is_empty() {
+        markdown
+    } else {
+        format!("{}\n", markdown)
+    }
+}
+
+/// Markdown for each block in the content; runs of text and inline
+/// elements between blocks become paragraphs
+fn blocks(nodes: &[HtmlNode]) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut run: Vec<&HtmlNode> = Vec::new();
+    for node in nodes {
+        match node {
+            HtmlNode::Element(e) if is_block(e) => {
+                flush_paragraph(&mut run, &mut out);
+                block(e, &mut out);
+            }
+            _ => run.push(node),
+        }
+    }
+    flush_paragraph(&mut run, &mut out);
+    out
+}
+
+fn flush_paragraph(run: &mut Vec<&HtmlNode>, out: &mut Vec<String>) {
+    let text = inlines(run.drain(..));
+    let text = trim_paragraph(&text);
+    if !text.is_empty() {
+        out.push(escape_line_starts(text));
+    }
+}
+
+/// Paragraph text without the spaces and hard breaks around it
+fn trim_paragraph(text: &str) -> &str {
+    let mut text = text.trim_matches([' ', '\n']);
+    while let Some(rest) = text.strip_prefix("\\\n").or_else(|| text.strip_suffix("\\\n")) {
+        text = rest.trim_matches([' ', '\n']);
+    }
+    text
+}
+
+fn block(element: &HtmlElement, out: &mut Vec<String>) {
+    let tag = element.tag.as_str();
+    if HIDDEN_ELEMENTS.contains(&tag) {
+        return;
+    }
+    match tag {
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let text = inlines(element.children.iter()).replace("\\\n", " ").replace('\n', " ");
+            let text = text.trim();
+            if !text.is_empty() {
+                let level = (tag.as_bytes()[1] - b'0') as usize;
+                out.push(format!("{} {}", "#".repeat(level), text));
+            }
+        }
+        "p" => {
+            let text = inlines(element.children.iter());
+            let text = trim_paragraph(&text);
+            if !text.is_empty() {
+                out.push(escape_line_starts(text));
+            }
+        }
+        "ul" | "ol" => {
+            if let Some(list) = list(element) {
+                out.push(list);
+            }
+        }
+        "li" => {
+            // An item outside a list
+            out.extend(blocks(&element.children));
+        }
+        "blockquote" => {
+            let inner = blocks(&element.children).join("\n\n");
+            if !inner.is_empty() {
+                out.push(
+                    inner
+                        .lines()
+                        .map(|line| if line.is_empty() { ">".to_string() } else { format!("> {}", line) })
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                );
+            }
+        }
+        "section" | "div" | "aside" if has_class(element, "footnotes") => match footnote_definitions(element) {
+            Some(definitions) => out.push(definitions),
+            None => out.extend(blocks(&element.children)),
+        },
+        "pre" => out.push(code_block(element)),
+        "hr" => out.push("---".to_string()),
+        "table" => out.push(table(element)),
+        "dl" => {
+            for item in element.elements() {
+                match item.tag.as_str() {
+                    "dt" => {
+                        let term = inlines(item.children.iter());
+                        let term = term.trim();
+                        if !term.is_empty() {
+                            out.push(format!("**{}**", term));
+                        }
+                    }
+                    "dd" => {
+                        let inner = blocks(&item.children).join("\n\n");
+                        if !inner.is_empty() {
+                            out.push(indent(&inner, ": ", "  "));
+                        }
+                    }
+                    _ => out.extend(blocks(&item.children)),
+                }
+            }
+        }
+        _ => out.extend(blocks(&element.children)),
+    }
+}
+
+fn has_class(element: &HtmlElement, class: &str) -> bool {
+    element.attr("class").is_some_and(|c| c.split_whitespace().any(|c| c == class))
+}
+
+/// GFM footnote definitions from a footnotes section whose items have ids
+/// like `fn1`; the links back to the references are dropped
+fn footnote_definitions(element: &HtmlElement) -> Option<String> {
+    let list = element.elements().find(|e| e.tag == "ol")?;
+    let mut definitions = Vec::new();
+    for item in list.elements().filter(|e| e.tag == "li") {
+        let label = item.attr("id")?.strip_prefix("fn")?;
+        let mut item = item.clone();
+        remove_back_links(&mut item);
+        let text = blocks(&item.children).join("\n\n");
+        definitions.push(indent(&text, &format!("[^{}]: ", label), "    "));
+    }
+    Some(definitions.join("\n"))
+}
+
+fn remove_back_links(element: &mut HtmlElement) {
+    element.children.retain(|child| match child {
+        HtmlNode::Element(e) => !(e.tag == "a" && has_class(e, "footnote-back")),
+        HtmlNode::Text(_) => true,
+    });
+    for child in element.children.iter_mut() {
+        if let HtmlNode::Element(e) = child {
+            remove_back_links(e);
+        }
+    }
+}
+
+/// The label of a footnote reference written as `<sup><a href="#fn1">`
+fn footnote_ref(element: &HtmlElement) -> Option<&str> {
+    let mut children = element
+        .children
+        .iter()
+        .filter(|c| !matches!(c, HtmlNode::Text(t) if t.trim().is_empty()));
+    match (children.next(), children.next()) {
+        (Some(HtmlNode::Element(link)), None) if link.tag == "a" => {
+            link.attr("href")?.strip_prefix("#fn").filter(|l| !l.is_empty() && !l.starts_with("ref"))
+        }
+        _ => None,
+    }
+}
+
+/// A list with its items indented under their markers. Lists whose items
+/// hold paragraphs are loose and keep blank lines between the items.
+fn list(element: &HtmlElement) -> Option<String> {
+    let ordered = element.tag == "ol";
+    let mut number: i64 = element.attr("start").and_then(|s| s.trim().parse().ok()).unwrap_or(1);
+    let items: Vec<&HtmlElement> = element.elements().filter(|e| e.tag == "li").collect();
+    let loose = items.iter().any(|item| item.elements().any(|e| e.tag == "p"));
+    let mut rendered = Vec::new();
+    for item in items {
+        if let Some(value) = item.attr("value").and_then(|v| v.trim().parse().ok()) {
+            number = value;
+        }
+        let marker = if ordered { format!("{}. ", number) } else { "- ".to_string() };
+        number += 1;
+        let content = blocks(&item.children).join(if loose { "\n\n" } else { "\n" });
+        let padding = " ".repeat(marker.len());
+        rendered.push(if content.is_empty() { marker.trim_end().to_string() } else { indent(&content, &marker, &padding) });
+    }
+    if rendered.is_empty() {
+        None
+    } else {
+        Some(rendered.join(if loose { "\n\n" } else { "\n" }))
+    }
+}
+
+/// Prefix the first line with `first` and the others with `rest`, leaving
+/// blank lines empty
+fn indent(text: &str, first: &str, rest: &str) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| match (n, line.is_empty()) {
+            (0, _) => format!("{}{}", first, line),
+            (_, true) => String::new(),
+            _ => format!("{}{}", rest, line),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn code_block(element: &HtmlElement) -> String {
+    let code = text_content(element);
+    let code = code.strip_suffix('\n').unwrap_or(&code);
+    let language = element
+        .elements()
+        .find(|e| e.tag == "code")
+        .and_then(|c| c.attr("class"))
+        .or_else(|| element.attr("class"))
+        .and_then(|class| class.split_whitespace().find_map(|c| c.strip_prefix("language-").or_else(|| c.strip_prefix("lang-"))))
+        .unwrap_or("");
+    let mut fence = "```".to_string();
+    while code.contains(fence.as_str()) {
+        fence.push('`');
+    }
+    format!("{}{}\n{}\n{}", fence, language, code, fence)
+}
+
+/// A GFM table, or the table's HTML when it has merged cells or block
+/// content that pipe tables can't hold
+fn table(element: &HtmlElement) -> String {
+    let mut rows: Vec<&HtmlElement> = Vec::new();
+    let mut caption = None;
+    for child in element.elements() {
+        match child.tag.as_str() {
+            "tr" => rows.push(child),
+            "thead" | "tbody" | "tfoot" => rows.extend(child.elements().filter(|e| e.tag == "tr")),
+            "caption" => caption = Some(collapse_whitespace(&text_content(child))),
+            _ => {}
+        }
+    }
+    let cells: Vec<Vec<&HtmlElement>> = rows
+        .iter()
+        .map(|row| row.elements().filter(|e| e.tag == "td" || e.tag == "th").collect())
+        .collect();
+    let merged = cells.iter().flatten().any(|cell| {
+        ["colspan", "rowspan"].iter().any(|a| cell.attr(a).and_then(|v| v.trim().parse::<u32>().ok()).unwrap_or(1) > 1)
+    });
+    let nested = cells.iter().flatten().any(|cell| has_complex_content(cell));
+    let columns = cells.iter().map(Vec::len).max().unwrap_or(0);
+    if merged || nested || columns == 0 {
+        let mut html = String::new();
+        html_markup(&[HtmlNode::Element(element.clone())], &mut html);
+        return html;
+    }
+
+    let alignment = |cell: &HtmlElement| {
+        let align = cell.attr("align").map(str::to_string).or_else(|| cell.style("text-align"));
+        match align.as_deref().map(str::trim) {
+            Some("center") => ":---:",
+            Some("right") => "---:",
+            Some("left") => ":---",
+            _ => "---",
+        }
+    };
+    let row_line = |row: &[&HtmlElement]| {
+        let texts: Vec<String> = (0..columns).map(|c| row.get(c).map(|cell| table_cell(cell)).unwrap_or_default()).collect();
+        format!("| {} |", texts.join(" | "))
+    };
+    let mut lines = Vec::new();
+    if let Some(caption) = caption.filter(|c| !c.is_empty()) {
+        lines.push(format!("*{}*", escape_inline(&caption)));
+        lines.push(String::new());
+    }
+    lines.push(row_line(&cells[0]));
+    let aligns: Vec<&str> = (0..columns).map(|c| cells[0].get(c).map(|cell| alignment(cell)).unwrap_or("---")).collect();
+    lines.push(format!("| {} |", aligns.join(" | ")));
+    lines.extend(cells[1..].iter().map(|row| row_line(row)));
+    lines.join("\n")
+}
+
+fn has_complex_content(element: &HtmlElement) -> bool {
+    element.elements().any(|e| matches!(e.tag.as_str(), "table" | "ul" | "ol" | "pre" | "blockquote" | "dl") || has_complex_content(e))
+}
+
+fn table_cell(cell: &HtmlElement) -> String {
+    blocks(&cell.children)
+        .join("<br>")
+        .replace("\\\n", "<br>")
+        .replace('\n', " ")
+        .replace('|', "\\|")
+}
+
+/// Markdown for a run of inline content. Whitespace is collapsed, and
+/// emphasis markers are kept tight against the text they wrap.
+fn inlines<'a>(nodes: impl Iterator<Item = &'a HtmlNode>) -> String {
+    let mut md = String::new();
+    for node in nodes {
+        match node {
+            HtmlNode::Text(text) => push_text(&mut md, &escape_inline(text)),
+            HtmlNode::Element(e) => inline(e, &mut md),
+        }
+    }
+    md
+}
+
+fn push_text(md: &mut String, text: &str) {
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !md.is_empty() && !md.ends_with([' ', '\n']) {
+                md.push(' ');
+            }
+        } else {
+            md.push(c);
+        }
+    }
+}
+
+fn inline(element: &HtmlElement, md: &mut String) {
+    let tag = element.tag.as_str();
+    if HIDDEN_ELEMENTS.contains(&tag) {
+        return;
+    }
+    let bold_style = element.style("font-weight").is_some_and(|w| w == "bold" || w.parse::<u32>().is_ok_and(|w| w >= 600));
+    let italic_style = element.style("font-style").is_some_and(|s| s == "italic" || s == "oblique");
+    match tag {
+        "br" => {
+            while md.ends_with(' ') {
+                md.pop();
+            }
+            md.push_str("\\\n");
+        }
+        "img" => {
+            let src = element.attr("src").unwrap_or("");
+            if !src.is_empty() {
+                md.push_str(&format!("![{}]({}{})", escape_inline(element.attr("alt").unwrap_or("")), link_target(src), link_title(element)));
+            }
+        }
+        "a" => {
+            let text = inlines(element.children.iter());
+            match element.attr("href").filter(|h| !h.is_empty()) {
+                Some(href) if text.trim() == href && href.contains("://") => md.push_str(&format!("<{}>", href)),
+                Some(href) => wrap(md, &text, "[", &format!("]({}{})", link_target(href), link_title(element))),
+                None => md.push_str(&text),
+            }
+        }
+        "code" | "kbd" | "samp" | "tt" => {
+            let code = collapse_whitespace(&text_content(element));
+            if !code.is_empty() {
+                let mut ticks = "`".to_string();
+                while code.contains(ticks.as_str()) {
+                    ticks.push('`');
+                }
+                let pad = if code.starts_with('`') || code.ends_with('`') { " " } else { "" };
+                md.push_str(&format!("{0}{1}{2}{1}{0}", ticks, pad, code));
+            }
+        }
+        "strong" | "b" => wrap(md, &inlines(element.children.iter()), "**", "**"),
+        "em" | "i" | "cite" | "dfn" | "var" => wrap(md, &inlines(element.children.iter()), "*", "*"),
+        "del" | "s" | "strike" => wrap(md, &inlines(element.children.iter()), "~~", "~~"),
+        "sup" if footnote_ref(element).is_some() => {
+            md.push_str(&format!("[^{}]", footnote_ref(element).unwrap_or_default()));
+        }
+        "sub" | "sup" | "u" | "mark" | "ins" => {
+            wrap(md, &inlines(element.children.iter()), &format!("<{}>", tag), &format!("</{}>", tag))
+        }
+        "input" if element.attr("type").is_some_and(|t| t.eq_ignore_ascii_case("checkbox")) => {
+            md.push_str(if element.attr("checked").is_some() { "[x] " } else { "[ ] " });
+        }
+        _ if bold_style && italic_style => wrap(md, &inlines(element.children.iter()), "***", "***"),
+        _ if bold_style => wrap(md, &inlines(element.children.iter()), "**", "**"),
+        _ if italic_style => wrap(md, &inlines(element.children.iter()), "*", "*"),
+        // Block content inside inline context reads as a line of its own
+        _ if is_block(element) => {
+            let inner = blocks(&element.children).join("\\\n");
+            if !inner.is_empty() {
+                if !md.is_empty() && !md.ends_with('\n') {
+                    md.push_str("\\\n");
+                }
+                md.push_str(&inner);
+            }
+        }
+        _ => md.push_str(&inlines(element.children.iter())),
+    }
+}
+
+/// Wrap text in markers, moving leading and trailing spaces outside so
+/// the markers touch the text
+fn wrap(md: &mut String, text: &str, open: &str, close: &str) {
+    let core = text.trim_matches(' ');
+    if core.is_empty() {
+        push_text(md, text);
+        return;
+    }
+    if text.starts_with(' ') {
+        push_text(md, " ");
+    }
+    md.push_str(&format!("{}{}{}", open, core, close));
+    if text.ends_with(' ') {
+        md.push(' ');
+    }
+}
+
+fn link_target(href: &str) -> String {
+    if href.contains([' ', '(', ')']) {
+        format!("<{}>", href.replace('<', "%3C").replace('>', "%3E"))
+    } else {
+        href.to_string()
+    }
+}
+
+fn link_title(element: &HtmlElement) -> String {
+    element
+        .attr("title")
+        .filter(|t| !t.is_empty())
+        .map(|t| format!(" \"{}\"", t.replace('"', "\\\"")))
+        .unwrap_or_default()
+}
+
+fn escape_inline(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']' | '<' | '>' | '~') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Escape text at the start of a line that would otherwise read as a
+/// heading, quote, list item or rule
+fn escape_line_starts(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            let rest = &line[digits..];
+            if digits > 0 && (rest.starts_with(". ") || rest.starts_with(") ") || rest == "." || rest == ")") {
+                format!("{}\\{}", &line[..digits], rest)
+            } else if line.starts_with(['#', '+', '-', '=']) {
+                format!("\\{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
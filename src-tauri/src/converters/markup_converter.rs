@@ -391,7 +391,7 @@ pub fn convert_toml_to_json(input_path: &str, output_path: &str) -> ConversionRe
     }
 }
 
-fn toml_to_json_value(toml_val: &toml::Value) -> Value {
+pub fn toml_to_json_value(toml_val: &toml::Value) -> Value {
     match toml_val {
         toml::Value::String(s) => json!(s),
         toml::Value::Integer(i) => json!(i),
@@ -494,6 +494,21 @@ fn json_to_toml_value(json_val: &Value) -> toml::Value {
     }
 }
 
+/// Render Markdown to an HTML fragment, with GitHub-style tables,
+/// strikethrough, footnotes and task lists
+pub fn markdown_to_html(markdown: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    let parser = Parser::new_ext(markdown, options);
+    let mut html_content = String::new();
+    html::push_html(&mut html_content, parser);
+    html_content
+}
+
 /// Convert Markdown to HTML
 pub fn convert_markdown_to_html(input_path: &str, output_path: &str) -> ConversionResult {
     let content = match fs::read_to_string(input_path) {
@@ -513,17 +528,7 @@ pub fn convert_markdown_to_html(input_path: &str, output_path: &str) -> Conversi
         .and_then(|n| n.to_str())
         .unwrap_or("Document");
 
-    // Set up parser options
-    let mut options = Options::empty();
-    options.insert(Options::ENABLE_STRIKETHROUGH);
-    options.insert(Options::ENABLE_TABLES);
-    options.insert(Options::ENABLE_FOOTNOTES);
-    options.insert(Options::ENABLE_TASKLISTS);
-
-    let parser = Parser::new_ext(&content, options);
-
-    let mut html_content = String::new();
-    html::push_html(&mut html_content, parser);
+    let html_content = markdown_to_html(&content);
 
     let html_output = format!(
         r#"<!DOCTYPE html>
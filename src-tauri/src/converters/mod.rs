@@ -1,6 +1,22 @@
 pub mod image_converter;
 pub mod text_converter;
 pub mod pdf_converter;
+pub mod pdf_renderer;
+pub mod pdf_tools;
+pub mod text_tools;
+pub mod pdf_security;
+pub mod pdf_forms;
+pub mod pdfa;
+pub mod html_renderer;
+pub mod docx_writer;
+pub mod docx_reader;
+pub mod rich_text;
+pub mod odt_converter;
+pub mod rtf_converter;
+pub mod doc_reader;
+pub mod epub_converter;
+pub mod markdown_writer;
+pub mod embedded_images;
 pub mod data_converter;
 pub mod media_converter;
 pub mod document_converter;
@@ -8,10 +24,27 @@ pub mod spreadsheet_converter;
 pub mod archive_converter;
 pub mod svg_converter;
 pub mod markup_converter;
+pub mod file_compare;
 
 pub use image_converter::*;
 pub use text_converter::*;
 pub use pdf_converter::*;
+pub use pdf_renderer::*;
+pub use pdf_tools::*;
+pub use text_tools::*;
+pub use pdf_security::*;
+pub use pdf_forms::*;
+pub use pdfa::*;
+pub use html_renderer::*;
+pub use docx_writer::*;
+pub use docx_reader::*;
+pub use rich_text::*;
+pub use odt_converter::*;
+pub use rtf_converter::*;
+pub use doc_reader::*;
+pub use epub_converter::*;
+pub use markdown_writer::*;
+pub use embedded_images::*;
 pub use data_converter::*;
 pub use media_converter::*;
 pub use document_converter::*;
@@ -19,3 +52,4 @@ pub use spreadsheet_converter::*;
 pub use archive_converter::*;
 pub use svg_converter::*;
 pub use markup_converter::*;
+pub use file_compare::*;
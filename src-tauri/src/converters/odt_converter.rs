@@ -0,0 +1,1458 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use crate::ConversionResult;
+use crate::converters::docx_reader::{parse_xml, XmlChild, XmlNode};
+use crate::converters::docx_writer::{hex_color, picture_data};
+use crate::converters::embedded_images::{open_package, read_entry, resolve_href};
+use crate::converters::html_renderer::{
+    css_length, element_style, find_element, is_block, parse_html, read_image_source, text_content, HtmlElement, HtmlNode,
+    TextAlign, TextStyle, HIDDEN_ELEMENTS,
+};
+use crate::converters::markdown_writer::html_to_markdown;
+use crate::converters::markup_converter::markdown_to_html;
+use crate::converters::pdf_tools::finish;
+use crate::converters::rich_text::{
+    embedded_sources, html_document, image_mime, plain_text, Block, Format, Inline, List, ListItem, Paragraph,
+    ParagraphKind, RichDocument, TableCell, TableRow,
+};
+use crate::converters::text_tools::{
+    collect_text, escape_markup, is_monospace_font, list_number, safe_file_name, unique_file_name,
+};
+
+const ODT_MIME: &str = "application/vnd.oasis.opendocument.text";
+/// Text width of the A4 page with 2cm margins written by the ODT writer
+const CONTENT_WIDTH_CM: f32 = 17.0;
+const LIST_INDENT_CM: f32 = 0.635;
+
+// ---------------------------------------------------------------------------
+// Reading
+// ---------------------------------------------------------------------------
+
+/// Extract the text of an ODT document, keeping paragraphs, list markers
+/// and table rows
+pub fn convert_odt_to_txt(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_odt_to_txt(input_path, output_path), output_path)
+}
+
+fn try_convert_odt_to_txt(input_path: &str, output_path: &str) -> Result<String, String> {
+    let doc = read_odt(input_path)?;
+    fs::write(output_path, plain_text(&doc, "\n\n")).map_err(|e| format!("Failed to write text file: {}", e))?;
+    Ok(doc.summary())
+}
+
+/// Convert an ODT document to HTML with headings, lists, tables, links,
+/// footnotes and pictures embedded as data URIs
+pub fn convert_odt_to_html(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_odt_to_html(input_path, output_path), output_path)
+}
+
+fn try_convert_odt_to_html(input_path: &str, output_path: &str) -> Result<String, String> {
+    let doc = read_odt(input_path)?;
+    let html = html_document(&doc, &embedded_sources(&doc), input_path);
+    fs::write(output_path, &html).map_err(|e| format!("Failed to write HTML file: {}", e))?;
+    Ok(doc.summary())
+}
+
+/// Convert an ODT document to Markdown through its HTML rendering.
+/// Pictures are written to a `{name}_files` folder next to the Markdown
+/// file and footnotes become GFM footnotes.
+pub fn convert_odt_to_markdown(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_odt_to_markdown(input_path, output_path), output_path)
+}
+
+fn try_convert_odt_to_markdown(input_path: &str, output_path: &str) -> Result<String, String> {
+    let doc = read_odt(input_path)?;
+
+    let mut sources = HashMap::new();
+    if !doc.images.is_empty() {
+        let output = Path::new(output_path);
+        let folder = format!("{}_files", output.file_stem().and_then(|s| s.to_str()).unwrap_or("document"));
+        let dir = output.parent().unwrap_or(Path::new("")).join(&folder);
+        fs::create_dir_all(&dir).map_err(|e| format!("Failed to create image folder: {}", e))?;
+        let mut paths: Vec<&String> = doc.images.keys().collect();
+        paths.sort();
+        let mut used_names = Vec::new();
+        for path in paths {
+            let name = unique_file_name(safe_file_name(path).unwrap_or("image"), &mut used_names);
+            fs::write(dir.join(&name), &doc.images[path]).map_err(|e| format!("Failed to write {}: {}", name, e))?;
+            sources.insert(path.clone(), format!("{}/{}", folder, name).replace(' ', "%20"));
+        }
+    }
+
+    let markdown = html_to_markdown(&html_document(&doc, &sources, input_path));
+    fs::write(output_path, &markdown).map_err(|e| format!("Failed to write Markdown file: {}", e))?;
+    Ok(doc.summary())
+}
+
+/// The text properties a style sets; `None` inherits
+#[derive(Debug, Clone, Default)]
+struct TextProps {
+    bold: Option<bool>,
+    italic: Option<bool>,
+    underline: Option<bool>,
+    strike: Option<bool>,
+    mono: Option<bool>,
+    vert: Option<Option<&'static str>>,
+    color: Option<Option<String>>,
+    background: Option<Option<String>>,
+}
+
+impl TextProps {
+    fn from_node(props: &XmlNode, mono_fonts: &[String]) -> Self {
+        let lines = |name: &str| props.attr(name).map(|s| s != "none");
+        TextProps {
+            bold: props
+                .attr("font-weight")
+                .map(|w| w == "bold" || w.parse::<u32>().map(|n| n >= 600).unwrap_or(false)),
+            italic: props.attr("font-style").map(|s| s == "italic" || s == "oblique"),
+            underline: lines("text-underline-style"),
+            strike: lines("text-line-through-style"),
+            mono: props
+                .attr("font-name")
+                .or_else(|| props.attr("font-family"))
+                .map(|f| mono_fonts.iter().any(|m| m == f) || is_monospace_font(f)),
+            vert: props.attr("text-position").map(|position| {
+                let shift = position.split_whitespace().next().unwrap_or("");
+                let percent = shift.trim_end_matches('%').parse::<f32>().unwrap_or(0.0);
+                if shift == "super" || percent > 0.0 {
+                    Some("sup")
+                } else if shift == "sub" || percent < 0.0 {
+                    Some("sub")
+                } else {
+                    None
+                }
+            }),
+            color: props
+                .attr("color")
+                .map(|c| Some(c.to_string()).filter(|c| !c.eq_ignore_ascii_case("#000000"))),
+            background: props
+                .attr("background-color")
+                .map(|c| Some(c.to_string()).filter(|c| c != "transparent")),
+        }
+    }
+
+    fn with(&self, over: &TextProps) -> TextProps {
+        TextProps {
+            bold: over.bold.or(self.bold),
+            italic: over.italic.or(self.italic),
+            underline: over.underline.or(self.underline),
+            strike: over.strike.or(self.strike),
+            mono: over.mono.or(self.mono),
+            vert: over.vert.or(self.vert),
+            color: over.color.clone().or_else(|| self.color.clone()),
+            background: over.background.clone().or_else(|| self.background.clone()),
+        }
+    }
+
+    fn resolve(&self) -> Format {
+        Format {
+            bold: self.bold.unwrap_or(false),
+            italic: self.italic.unwrap_or(false),
+            underline: self.underline.unwrap_or(false),
+            strike: self.strike.unwrap_or(false),
+            mono: self.mono.unwrap_or(false),
+            vert: self.vert.flatten(),
+            color: self.color.clone().flatten(),
+            background: self.background.clone().flatten(),
+            link: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct OdtStyle {
+    display_name: String,
+    parent: Option<String>,
+    text: TextProps,
+    align: Option<String>,
+    break_before: bool,
+    list_style: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct ListMarker {
+    ordered: bool,
+    /// ODF number format: "1", "a", "A", "i" or "I"
+    format: String,
+    start: usize,
+}
+
+impl Default for ListMarker {
+    fn default() -> Self {
+        ListMarker { ordered: false, format: String::new(), start: 1 }
+    }
+}
+
+fn read_odt(input_path: &str) -> Result<RichDocument, String> {
+    let mut archive = open_package(input_path, "ODT")?;
+    let content = read_entry(&mut archive, "content.xml").ok_or("Not an OpenDocument file: content.xml is missing")?;
+    let content = parse_xml(&content).map_err(|e| format!("Failed to read content.xml: {}", e))?;
+    let styles = read_entry(&mut archive, "styles.xml").and_then(|s| parse_xml(&s).ok());
+
+    let mut reader = OdtReader {
+        archive,
+        styles: HashMap::new(),
+        list_styles: HashMap::new(),
+        list_counters: HashMap::new(),
+        notes: Vec::new(),
+        images: HashMap::new(),
+        trailing_space: true,
+        paragraphs: 0,
+        tables: 0,
+        image_count: 0,
+    };
+    // Font faces with a fixed pitch mark code text
+    let mut mono_fonts = Vec::new();
+    for root in styles.iter().chain(std::iter::once(&content)) {
+        if let Some(faces) = root.child("font-face-decls") {
+            for face in faces.elements().filter(|f| f.attr("font-pitch") == Some("fixed")) {
+                if let Some(name) = face.attr("name") {
+                    mono_fonts.push(name.to_string());
+                }
+            }
+        }
+    }
+    for root in styles.iter().chain(std::iter::once(&content)) {
+        for section in ["styles", "automatic-styles"] {
+            if let Some(section) = root.child(section) {
+                reader.read_styles(section, &mono_fonts);
+            }
+        }
+    }
+
+    let text = content
+        .child("body")
+        .and_then(|b| b.child("text"))
+        .ok_or("This OpenDocument file has no text body")?;
+    let blocks = reader.blocks(text, None, 0);
+
+    let meta = read_entry(&mut reader.archive, "meta.xml").and_then(|m| parse_xml(&m).ok());
+    let meta_text = |name: &str| {
+        meta.as_ref()
+            .and_then(|m| m.descendant(name))
+            .map(|n| n.text().trim().to_string())
+            .filter(|t| !t.is_empty())
+    };
+    Ok(RichDocument {
+        blocks,
+        notes: reader.notes,
+        images: reader.images,
+        title: meta_text("title"),
+        author: meta_text("creator").or_else(|| meta_text("initial-creator")),
+        paragraphs: reader.paragraphs,
+        tables: reader.tables,
+        image_count: reader.image_count,
+    })
+}
+
+struct OdtReader {
+    archive: zip::ZipArchive<File>,
+    /// Styles keyed "family:name"
+    styles: HashMap<String, OdtStyle>,
+    /// List styles by name, with the marker of each level from 1
+    list_styles: HashMap<String, HashMap<usize, ListMarker>>,
+    /// Items numbered so far per (list style, level), for continued lists
+    list_counters: HashMap<(String, usize), usize>,
+    notes: Vec<Vec<Block>>,
+    images: HashMap<String, Vec<u8>>,
+    /// Whether the paragraph so far ends in a space, for collapsing whitespace
+    trailing_space: bool,
+    paragraphs: usize,
+    tables: usize,
+    image_count: usize,
+}
+
+impl OdtReader {
+    fn read_styles(&mut self, section: &XmlNode, mono_fonts: &[String]) {
+        for style in section.elements() {
+            let name = match style.attr("name") {
+                Some(n) => n.to_string(),
+                None => continue,
+            };
+            match style.name.as_str() {
+                "style" => {
+                    let paragraph = style.child("paragraph-properties");
+                    let def = OdtStyle {
+                        display_name: style.attr("display-name").map(str::to_string).unwrap_or_else(|| decode_style_name(&name)),
+                        parent: style.attr("parent-style-name").map(str::to_string),
+                        text: style
+                            .child("text-properties")
+                            .map(|t| TextProps::from_node(t, mono_fonts))
+                            .unwrap_or_default(),
+                        align: paragraph.and_then(|p| p.attr("text-align")).map(str::to_string),
+                        break_before: paragraph.and_then(|p| p.attr("break-before")) == Some("page"),
+                        list_style: style.attr("list-style-name").map(str::to_string),
+                    };
+                    let family = style.attr("family").unwrap_or("paragraph");
+                    self.styles.insert(format!("{}:{}", family, name), def);
+                }
+                "list-style" => {
+                    let mut levels = HashMap::new();
+                    for level in style.elements() {
+                        let number = level.attr("level").and_then(|l| l.parse().ok()).unwrap_or(1);
+                        let marker = match level.name.as_str() {
+                            "list-level-style-number" => {
+                                let format = level.attr("num-format").unwrap_or("1").to_string();
+                                ListMarker {
+                                    ordered: !format.is_empty(),
+                                    format,
+                                    start: level.attr("start-value").and_then(|s| s.parse().ok()).unwrap_or(1),
+                                }
+                            }
+                            "list-level-style-bullet" | "list-level-style-image" => ListMarker::default(),
+                            _ => continue,
+                        };
+                        levels.insert(number, marker);
+                    }
+                    self.list_styles.insert(name, levels);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// A style followed by its parents
+    fn style_chain(&self, family: &str, name: Option<&str>) -> Vec<&OdtStyle> {
+        let mut chain = Vec::new();
+        let mut next = name.map(str::to_string);
+        while let Some(style) = next.and_then(|n| self.styles.get(&format!("{}:{}", family, n))) {
+            if chain.len() >= 16 {
+                break;
+            }
+            chain.push(style);
+            next = style.parent.clone();
+        }
+        chain
+    }
+
+    fn text_props(&self, family: &str, name: Option<&str>) -> TextProps {
+        self.style_chain(family, name)
+            .iter()
+            .rev()
+            .fold(TextProps::default(), |props, style| props.with(&style.text))
+    }
+
+    fn blocks(&mut self, parent: &XmlNode, list_style: Option<&str>, level: usize) -> Vec<Block> {
+        let mut out = Vec::new();
+        for child in parent.elements() {
+            match child.name.as_str() {
+                "p" => self.paragraph(child, None, &mut out),
+                "h" => {
+                    let level = child.attr("outline-level").and_then(|l| l.parse().ok()).unwrap_or(1usize);
+                    self.paragraph(child, Some(level.clamp(1, 6)), &mut out);
+                }
+                "list" => {
+                    let list = self.list(child, list_style, level);
+                    out.push(list);
+                }
+                "table" => {
+                    let table = self.table(child);
+                    out.push(table);
+                }
+                "tracked-changes" | "sequence-decls" | "variable-decls" | "user-field-decls" | "forms"
+                | "soft-page-break" | "annotation" => {}
+                name if name.ends_with("-source") => {}
+                // Sections, indexes and other containers of paragraphs
+                _ => out.extend(self.blocks(child, list_style, level)),
+            }
+        }
+        out
+    }
+
+    fn paragraph(&mut self, p: &XmlNode, heading: Option<usize>, out: &mut Vec<Block>) {
+        let style_name = p.attr("style-name");
+        let chain = self.style_chain("paragraph", style_name);
+        let align = match chain.iter().find_map(|s| s.align.as_deref()) {
+            Some("center") => Some("center"),
+            Some("end") | Some("right") => Some("right"),
+            Some("justify") => Some("justify"),
+            _ => None,
+        };
+        let break_before = chain.iter().any(|s| s.break_before);
+        let names: Vec<String> = chain.iter().map(|s| s.display_name.to_lowercase()).collect();
+        let kind = if names.iter().any(|n| n.contains("quot")) {
+            ParagraphKind::Quote
+        } else if names.iter().any(|n| n.contains("preformatted") || n.contains("code") || n.contains("source")) {
+            ParagraphKind::Code
+        } else {
+            ParagraphKind::Normal
+        };
+        // Headings and code take their look from the block itself
+        let props = if heading.is_some() || kind == ParagraphKind::Code {
+            TextProps::default()
+        } else {
+            self.text_props("paragraph", style_name)
+        };
+
+        self.trailing_space = true;
+        let mut content = Vec::new();
+        self.inlines(p, &props, &mut content);
+        trim_end(&mut content);
+
+        if break_before {
+            out.push(Block::PageBreak);
+        }
+        if content.is_empty() && kind != ParagraphKind::Code {
+            return;
+        }
+        self.paragraphs += 1;
+        out.push(Block::Paragraph(Paragraph { content, kind, align, heading }));
+    }
+
+    fn inlines(&mut self, node: &XmlNode, props: &TextProps, out: &mut Vec<Inline>) {
+        for child in &node.children {
+            let element = match child {
+                XmlChild::Text(text) => {
+                    self.push_text(out, props.resolve(), text, true);
+                    continue;
+                }
+                XmlChild::Element(e) => e,
+            };
+            match element.name.as_str() {
+                "span" => {
+                    let span = props.with(&self.text_props("text", element.attr("style-name")));
+                    self.inlines(element, &span, out);
+                }
+                "a" => {
+                    let mut inner = Vec::new();
+                    self.inlines(element, props, &mut inner);
+                    if let Some(href) = element.attr("href") {
+                        for inline in inner.iter_mut() {
+                            if let Inline::Text(format, _) = inline {
+                                format.link = Some(href.to_string());
+                            }
+                        }
+                    }
+                    out.extend(inner);
+                }
+                "s" => {
+                    let count = element.attr("c").and_then(|c| c.parse().ok()).unwrap_or(1usize).min(256);
+                    self.push_text(out, props.resolve(), &" ".repeat(count), false);
+                }
+                "tab" => self.push_text(out, props.resolve(), "\t", false),
+                "line-break" => {
+                    out.push(Inline::Break);
+                    self.trailing_space = true;
+                }
+                "bookmark" | "bookmark-start" => {
+                    if let Some(name) = element.attr("name") {
+                        out.push(Inline::Anchor(name.to_string()));
+                    }
+                }
+                "note" => {
+                    let body = match element.child("note-body") {
+                        Some(b) => b,
+                        None => continue,
+                    };
+                    let trailing_space = self.trailing_space;
+                    let note = self.blocks(body, None, 0);
+                    self.trailing_space = trailing_space;
+                    self.notes.push(note);
+                    out.push(Inline::NoteRef(self.notes.len()));
+                }
+                "frame" => self.frame(element, props, out),
+                "soft-page-break" | "annotation" | "annotation-end" | "change" | "change-start" | "change-end"
+                | "bookmark-end" | "reference-mark-end" | "note-citation" => {}
+                // Fields, ruby text, metadata and other wrappers keep their text
+                _ => self.inlines(element, props, out),
+            }
+        }
+    }
+
+    fn push_text(&mut self, out: &mut Vec<Inline>, format: Format, text: &str, collapse: bool) {
+        let text = if collapse {
+            let mut collapsed = String::new();
+            for c in text.chars() {
+                if matches!(c, ' ' | '\t' | '\n' | '\r') {
+                    if !self.trailing_space {
+                        collapsed.push(' ');
+                        self.trailing_space = true;
+                    }
+                } else {
+                    collapsed.push(c);
+                    self.trailing_space = false;
+                }
+            }
+            collapsed
+        } else {
+            self.trailing_space = true;
+            text.to_string()
+        };
+        if text.is_empty() {
+            return;
+        }
+        match out.last_mut() {
+            Some(Inline::Text(last, existing)) if *last == format => existing.push_str(&text),
+            _ => out.push(Inline::Text(format, text)),
+        }
+    }
+
+    /// A picture, or the text of a text box
+    fn frame(&mut self, frame: &XmlNode, props: &TextProps, out: &mut Vec<Inline>) {
+        if let Some(image) = frame.child("image") {
+            let href = match image.attr("href") {
+                Some(h) => h,
+                None => return,
+            };
+            let target = if href.contains("://") {
+                href.to_string()
+            } else {
+                let path = resolve_href("", href);
+                if !self.images.contains_key(&path) {
+                    match read_entry(&mut self.archive, &path) {
+                        Some(bytes) => {
+                            self.images.insert(path.clone(), bytes);
+                        }
+                        None => return,
+                    }
+                }
+                path
+            };
+            let alt = ["title", "desc"]
+                .iter()
+                .find_map(|n| frame.child(n).map(|t| t.text().trim().to_string()).filter(|t| !t.is_empty()))
+                .unwrap_or_default();
+            let size = match (
+                frame.attr("width").and_then(odf_length_px),
+                frame.attr("height").and_then(odf_length_px),
+            ) {
+                (Some(w), Some(h)) => Some((w, h)),
+                _ => None,
+            };
+            self.image_count += 1;
+            out.push(Inline::Image(target, alt, size));
+            self.trailing_space = false;
+        } else if let Some(text_box) = frame.child("text-box") {
+            for (n, p) in text_box.elements().filter(|e| e.name == "p" || e.name == "h").enumerate() {
+                if n > 0 {
+                    out.push(Inline::Break);
+                }
+                self.inlines(p, props, out);
+            }
+        }
+    }
+
+    fn list(&mut self, list: &XmlNode, inherited: Option<&str>, level: usize) -> Block {
+        let style = list
+            .attr("style-name")
+            .or(inherited)
+            .map(str::to_string)
+            .or_else(|| {
+                // Lists without a style take the one of their paragraphs' style
+                let p = list.elements().find(|e| e.name == "list-item").and_then(|i| i.child("p"))?;
+                self.style_chain("paragraph", p.attr("style-name")).iter().find_map(|s| s.list_style.clone())
+            });
+        let mut marker = style
+            .as_ref()
+            .and_then(|s| self.list_styles.get(s))
+            .and_then(|levels| levels.get(&(level + 1)))
+            .cloned()
+            .unwrap_or_default();
+
+        let key = (style.clone().unwrap_or_default(), level);
+        let continues = list.attr("continue-numbering") == Some("true") || list.attr("continue-list").is_some();
+        let counter = if continues { self.list_counters.get(&key).copied().unwrap_or(0) } else { 0 };
+        marker.start += counter;
+
+        let mut items = Vec::new();
+        for item in list.elements().filter(|e| e.name == "list-item" || e.name == "list-header") {
+            if items.is_empty() {
+                if let Some(start) = item.attr("start-value").and_then(|s| s.parse().ok()) {
+                    marker.start = start;
+                }
+            }
+            items.push(self.blocks(item, style.as_deref(), level + 1));
+        }
+        self.list_counters.insert(key, marker.start + items.len() - 1);
+        let ordered = match marker.format.as_str() {
+            _ if !marker.ordered => None,
+            "a" => Some("a"),
+            "A" => Some("A"),
+            "i" => Some("i"),
+            "I" => Some("I"),
+            _ => Some("1"),
+        };
+        let items = items
+            .into_iter()
+            .enumerate()
+            .map(|(n, blocks)| ListItem {
+                marker: match ordered {
+                    Some(kind) => format!("{}.", list_number((marker.start + n) as i64, kind)),
+                    None => "\u{2022}".to_string(),
+                },
+                blocks,
+            })
+            .collect();
+        Block::List(List { ordered, start: marker.start, id: 0, items })
+    }
+
+    fn table(&mut self, table: &XmlNode) -> Block {
+        self.tables += 1;
+        let mut rows = Vec::new();
+        self.table_rows(table, false, &mut rows);
+        Block::Table(rows)
+    }
+
+    fn table_rows(&mut self, parent: &XmlNode, header: bool, rows: &mut Vec<TableRow>) {
+        for child in parent.elements() {
+            match child.name.as_str() {
+                "table-header-rows" => self.table_rows(child, true, rows),
+                "table-rows" | "table-row-group" => self.table_rows(child, header, rows),
+                "table-row" => {
+                    let mut cells = Vec::new();
+                    for cell in child.elements().filter(|c| c.name == "table-cell") {
+                        let span = |name: &str| cell.attr(name).and_then(|v| v.parse().ok()).unwrap_or(1usize).max(1);
+                        let blocks = self.blocks(cell, None, 0);
+                        // Repeated empty cells pad spreadsheets-style tables; keep a few
+                        let repeat = span("number-columns-repeated").min(if blocks.is_empty() { 1 } else { 64 });
+                        for _ in 0..repeat {
+                            cells.push(TableCell {
+                                columns: span("number-columns-spanned"),
+                                rows: span("number-rows-spanned"),
+                                blocks: blocks.clone(),
+                            });
+                        }
+                    }
+                    let has_content = cells.iter().any(|c| !c.blocks.is_empty());
+                    let repeat = child
+                        .attr("number-rows-repeated")
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or(1usize)
+                        .clamp(1, if has_content { 64 } else { 1 });
+                    for _ in 0..repeat {
+                        rows.push(TableRow { header, cells: cells.clone() });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Drop trailing whitespace at the end of a paragraph
+fn trim_end(content: &mut Vec<Inline>) {
+    while let Some(Inline::Text(_, text)) = content.last_mut() {
+        let trimmed = text.trim_end_matches(' ').len();
+        if trimmed > 0 {
+            text.truncate(trimmed);
+            break;
+        }
+        content.pop();
+    }
+}
+
+/// Style names encode characters other than letters and digits as `_XX_`
+fn decode_style_name(name: &str) -> String {
+    let mut out = String::new();
+    let mut rest = name;
+    while let Some(start) = rest.find('_') {
+        let after = &rest[start + 1..];
+        match after.find('_').filter(|&end| end > 0 && end <= 4) {
+            Some(end) if u32::from_str_radix(&after[..end], 16).is_ok() => {
+                out.push_str(&rest[..start]);
+                out.push(char::from_u32(u32::from_str_radix(&after[..end], 16).unwrap()).unwrap_or(' '));
+                rest = &after[end + 1..];
+            }
+            _ => {
+                out.push_str(&rest[..=start]);
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// An ODF length such as "2.5cm" in CSS pixels
+fn odf_length_px(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let units = [("cm", 96.0 / 2.54), ("mm", 96.0 / 25.4), ("in", 96.0), ("pt", 96.0 / 72.0), ("pc", 16.0), ("px", 1.0)];
+    let (number, scale) = units.iter().find_map(|(unit, scale)| value.strip_suffix(unit).map(|n| (n, *scale)))?;
+    number.trim().parse::<f32>().ok().filter(|v| *v > 0.0).map(|v| (v * scale).round() as u32)
+}
+
+// ---------------------------------------------------------------------------
+// Writing
+// ---------------------------------------------------------------------------
+
+/// Convert Markdown to an OpenDocument Text file
+pub fn convert_markdown_to_odt(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_markdown_to_odt(input_path, output_path), output_path)
+}
+
+fn try_convert_markdown_to_odt(input_path: &str, output_path: &str) -> Result<String, String> {
+    let markdown = fs::read_to_string(input_path).map_err(|e| format!("Failed to read Markdown file: {}", e))?;
+    let html = markdown_to_html(&markdown);
+    write_html_odt(&html, Path::new(input_path).parent().unwrap_or(Path::new("")), output_path)
+}
+
+/// Convert an HTML page to an OpenDocument Text file, mapping headings to
+/// the Heading styles and keeping emphasis, lists, tables, links and images
+pub fn convert_html_to_odt(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_html_to_odt(input_path, output_path), output_path)
+}
+
+fn try_convert_html_to_odt(input_path: &str, output_path: &str) -> Result<String, String> {
+    let bytes = fs::read(input_path).map_err(|e| format!("Failed to read HTML file: {}", e))?;
+    let html = String::from_utf8_lossy(&bytes);
+    write_html_odt(&html, Path::new(input_path).parent().unwrap_or(Path::new("")), output_path)
+}
+
+fn write_html_odt(html: &str, base_dir: &Path, output_path: &str) -> Result<String, String> {
+    let root = parse_html(html);
+    let body = find_element(&root, "body").unwrap_or(&root);
+    let title = find_element(&root, "title")
+        .or_else(|| find_element(body, "h1"))
+        .map(|t| text_content(t).split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|t| !t.is_empty());
+
+    let mut writer = OdtWriter {
+        base_dir,
+        automatic: String::new(),
+        text_styles: HashMap::new(),
+        paragraph_styles: HashMap::new(),
+        bullet_style: None,
+        list_styles: 0,
+        table_count: 0,
+        pictures: Vec::new(),
+        pending_bookmarks: Vec::new(),
+        page_break: false,
+        paragraphs: 0,
+    };
+    let mut text = String::new();
+    let ctx = OdtContext { style: "Text_20_body", heading: None };
+    writer.block_children(body, &TextStyle::default(), &ctx, &mut text);
+    if text.is_empty() {
+        text.push_str("<text:p text:style-name=\"Text_20_body\"/>");
+    }
+
+    let content = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<office:document-content {} office:version=\"1.2\">\
+         <office:font-face-decls>{}</office:font-face-decls>\
+         <office:automatic-styles>{}</office:automatic-styles>\
+         <office:body><office:text>{}</office:text></office:body></office:document-content>",
+        ODF_NAMESPACES, FONT_FACES, writer.automatic, text
+    );
+    let meta = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<office:document-meta {} office:version=\"1.2\"><office:meta>\
+         <meta:generator>FileFlow</meta:generator>{}</office:meta></office:document-meta>",
+        ODF_NAMESPACES,
+        title.map(|t| format!("<dc:title>{}</dc:title>", escape_markup(&t))).unwrap_or_default()
+    );
+
+    let mut manifest = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <manifest:manifest xmlns:manifest=\"urn:oasis:names:tc:opendocument:xmlns:manifest:1.0\" manifest:version=\"1.2\">\n",
+    );
+    manifest.push_str(&format!(
+        " <manifest:file-entry manifest:full-path=\"/\" manifest:version=\"1.2\" manifest:media-type=\"{}\"/>\n",
+        ODT_MIME
+    ));
+    for part in ["content.xml", "styles.xml", "meta.xml"] {
+        manifest.push_str(&format!(
+            " <manifest:file-entry manifest:full-path=\"{}\" manifest:media-type=\"text/xml\"/>\n",
+            part
+        ));
+    }
+    for (path, bytes) in &writer.pictures {
+        manifest.push_str(&format!(
+            " <manifest:file-entry manifest:full-path=\"{}\" manifest:media-type=\"{}\"/>\n",
+            path,
+            image_mime(bytes)
+        ));
+    }
+    manifest.push_str("</manifest:manifest>\n");
+
+    let file = File::create(output_path).map_err(|e| format!("Failed to create ODT file: {}", e))?;
+    let mut zip = zip::ZipWriter::new(file);
+    let write_error = |e: &dyn std::fmt::Display| format!("Failed to write ODT file: {}", e);
+    // The mimetype entry comes first and uncompressed so the type can be sniffed
+    let stored = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let deflated = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    zip.start_file("mimetype", stored).map_err(|e| write_error(&e))?;
+    zip.write_all(ODT_MIME.as_bytes()).map_err(|e| write_error(&e))?;
+    let styles = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", odt_styles());
+    for (name, data) in [
+        ("content.xml", content.as_bytes()),
+        ("styles.xml", styles.as_bytes()),
+        ("meta.xml", meta.as_bytes()),
+        ("META-INF/manifest.xml", manifest.as_bytes()),
+    ] {
+        zip.start_file(name, deflated).map_err(|e| write_error(&e))?;
+        zip.write_all(data).map_err(|e| write_error(&e))?;
+    }
+    for (path, bytes) in &writer.pictures {
+        zip.start_file(path.as_str(), stored).map_err(|e| write_error(&e))?;
+        zip.write_all(bytes).map_err(|e| write_error(&e))?;
+    }
+    zip.finish().map_err(|e| write_error(&e))?;
+
+    Ok(format!(
+        "Wrote {} paragraph(s), {} table(s) and {} image(s)",
+        writer.paragraphs,
+        writer.table_count,
+        writer.pictures.len()
+    ))
+}
+
+const ODF_NAMESPACES: &str = concat!(
+    "xmlns:office=\"urn:oasis:names:tc:opendocument:xmlns:office:1.0\" ",
+    "xmlns:style=\"urn:oasis:names:tc:opendocument:xmlns:style:1.0\" ",
+    "xmlns:text=\"urn:oasis:names:tc:opendocument:xmlns:text:1.0\" ",
+    "xmlns:table=\"urn:oasis:names:tc:opendocument:xmlns:table:1.0\" ",
+    "xmlns:draw=\"urn:oasis:names:tc:opendocument:xmlns:drawing:1.0\" ",
+    "xmlns:fo=\"urn:oasis:names:tc:opendocument:xmlns:xsl-fo-compatible:1.0\" ",
+    "xmlns:xlink=\"http://www.w3.org/1999/xlink\" ",
+    "xmlns:dc=\"http://purl.org/dc/elements/1.1/\" ",
+    "xmlns:meta=\"urn:oasis:names:tc:opendocument:xmlns:meta:1.0\" ",
+    "xmlns:svg=\"urn:oasis:names:tc:opendocument:xmlns:svg-compatible:1.0\""
+);
+
+const FONT_FACES: &str = concat!(
+    "<style:font-face style:name=\"Liberation Serif\" svg:font-family=\"'Liberation Serif'\" ",
+    "style:font-family-generic=\"roman\" style:font-pitch=\"variable\"/>",
+    "<style:font-face style:name=\"Liberation Sans\" svg:font-family=\"'Liberation Sans'\" ",
+    "style:font-family-generic=\"swiss\" style:font-pitch=\"variable\"/>",
+    "<style:font-face style:name=\"Liberation Mono\" svg:font-family=\"'Liberation Mono'\" ",
+    "style:font-family-generic=\"modern\" style:font-pitch=\"fixed\"/>"
+);
+
+/// The common styles every written document uses: body text, headings,
+/// quotations, preformatted text, table contents and links
+fn odt_styles() -> String {
+    let mut styles = String::from(
+        "<style:default-style style:family=\"paragraph\">\
+         <style:text-properties style:font-name=\"Liberation Serif\" fo:font-size=\"12pt\" fo:language=\"en\" fo:country=\"US\"/>\
+         </style:default-style>\
+         <style:style style:name=\"Standard\" style:family=\"paragraph\" style:class=\"text\"/>\
+         <style:style style:name=\"Text_20_body\" style:display-name=\"Text body\" style:family=\"paragraph\" \
+         style:parent-style-name=\"Standard\" style:class=\"text\">\
+         <style:paragraph-properties fo:margin-top=\"0cm\" fo:margin-bottom=\"0.247cm\" fo:line-height=\"115%\"/></style:style>\
+         <style:style style:name=\"Heading\" style:family=\"paragraph\" style:parent-style-name=\"Standard\" \
+         style:next-style-name=\"Text_20_body\" style:class=\"text\">\
+         <style:paragraph-properties fo:margin-top=\"0.423cm\" fo:margin-bottom=\"0.212cm\" fo:keep-with-next=\"always\"/>\
+         <style:text-properties style:font-name=\"Liberation Sans\" fo:font-weight=\"bold\"/></style:style>",
+    );
+    for (level, size) in [20, 16, 14, 13, 12, 11].into_iter().enumerate() {
+        styles.push_str(&format!(
+            "<style:style style:name=\"Heading_20_{0}\" style:display-name=\"Heading {0}\" style:family=\"paragraph\" \
+             style:parent-style-name=\"Heading\" style:next-style-name=\"Text_20_body\" style:default-outline-level=\"{0}\" \
+             style:class=\"text\"><style:text-properties fo:font-size=\"{1}pt\"/></style:style>",
+            level + 1,
+            size
+        ));
+    }
+    styles.push_str(
+        "<style:style style:name=\"Quotations\" style:family=\"paragraph\" style:parent-style-name=\"Standard\" style:class=\"html\">\
+         <style:paragraph-properties fo:margin-left=\"1cm\" fo:margin-right=\"1cm\" fo:margin-top=\"0cm\" fo:margin-bottom=\"0.247cm\"/>\
+         </style:style>\
+         <style:style style:name=\"Preformatted_20_Text\" style:display-name=\"Preformatted Text\" style:family=\"paragraph\" \
+         style:parent-style-name=\"Standard\" style:class=\"html\">\
+         <style:paragraph-properties fo:margin-top=\"0cm\" fo:margin-bottom=\"0.247cm\"/>\
+         <style:text-properties style:font-name=\"Liberation Mono\" fo:font-size=\"10pt\"/></style:style>\
+         <style:style style:name=\"Table_20_Contents\" style:display-name=\"Table Contents\" style:family=\"paragraph\" \
+         style:parent-style-name=\"Standard\" style:class=\"extra\"/>\
+         <style:style style:name=\"Table_20_Heading\" style:display-name=\"Table Heading\" style:family=\"paragraph\" \
+         style:parent-style-name=\"Table_20_Contents\" style:class=\"extra\">\
+         <style:text-properties fo:font-weight=\"bold\"/></style:style>\
+         <style:style style:name=\"Internet_20_link\" style:display-name=\"Internet link\" style:family=\"text\">\
+         <style:text-properties fo:color=\"#000080\" style:text-underline-style=\"solid\" style:text-underline-width=\"auto\" \
+         style:text-underline-color=\"font-color\"/></style:style>",
+    );
+    format!(
+        "<office:document-styles {} office:version=\"1.2\">\
+         <office:font-face-decls>{}</office:font-face-decls>\
+         <office:styles>{}</office:styles>\
+         <office:automatic-styles><style:page-layout style:name=\"pm1\">\
+         <style:page-layout-properties fo:page-width=\"21.001cm\" fo:page-height=\"29.7cm\" style:print-orientation=\"portrait\" \
+         fo:margin-top=\"2cm\" fo:margin-bottom=\"2cm\" fo:margin-left=\"2cm\" fo:margin-right=\"2cm\"/></style:page-layout>\
+         </office:automatic-styles>\
+         <office:master-styles><style:master-page style:name=\"Standard\" style:page-layout-name=\"pm1\"/></office:master-styles>\
+         </office:document-styles>",
+        ODF_NAMESPACES, FONT_FACES, styles
+    )
+}
+
+/// Where the paragraphs of a block end up
+#[derive(Debug, Clone)]
+struct OdtContext {
+    style: &'static str,
+    heading: Option<u8>,
+}
+
+/// The paragraph being filled with inline content
+struct OdtParagraph {
+    xml: String,
+    empty: bool,
+    /// Whether the content so far ends in whitespace, for collapsing spaces
+    trailing_space: bool,
+}
+
+impl OdtParagraph {
+    fn new() -> Self {
+        OdtParagraph { xml: String::new(), empty: true, trailing_space: true }
+    }
+}
+
+struct OdtWriter<'a> {
+    base_dir: &'a Path,
+    /// Automatic style definitions for content.xml
+    automatic: String,
+    /// Generated text and paragraph styles by their properties
+    text_styles: HashMap<String, String>,
+    paragraph_styles: HashMap<String, String>,
+    bullet_style: Option<String>,
+    list_styles: usize,
+    table_count: usize,
+    /// Package path and bytes of each picture
+    pictures: Vec<(String, Vec<u8>)>,
+    /// Element ids waiting for the next paragraph, where they become bookmarks
+    pending_bookmarks: Vec<String>,
+    page_break: bool,
+    paragraphs: usize,
+}
+
+impl OdtWriter<'_> {
+    fn block_children(&mut self, element: &HtmlElement, style: &TextStyle, ctx: &OdtContext, out: &mut String) {
+        let mut current = OdtParagraph::new();
+        for child in &element.children {
+            match child {
+                HtmlNode::Text(text) => self.text(&mut current, text, style),
+                HtmlNode::Element(e) if is_block(e) => {
+                    self.flush(&mut current, style, ctx, out);
+                    self.block(e, style, ctx, out);
+                }
+                HtmlNode::Element(e) => self.inline(e, style, &mut current),
+            }
+        }
+        self.flush(&mut current, style, ctx, out);
+    }
+
+    fn block(&mut self, element: &HtmlElement, parent: &TextStyle, ctx: &OdtContext, out: &mut String) {
+        let tag = element.tag.as_str();
+        if HIDDEN_ELEMENTS.contains(&tag) {
+            return;
+        }
+        let style = element_style(element, parent);
+        if element.style("page-break-before").as_deref() == Some("always")
+            || element.style("break-before").as_deref() == Some("page")
+        {
+            self.page_break = true;
+        }
+        if let Some(id) = element.attr("id") {
+            self.pending_bookmarks.push(id.to_string());
+        }
+
+        match tag {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                // Size and weight come from the heading style, not the spans
+                let style = TextStyle { size: parent.size, bold: parent.bold, ..style };
+                let level = tag.as_bytes()[1] - b'0';
+                let style_name = ["Heading_20_1", "Heading_20_2", "Heading_20_3", "Heading_20_4", "Heading_20_5", "Heading_20_6"]
+                    [level as usize - 1];
+                self.block_children(element, &style, &OdtContext { style: style_name, heading: Some(level) }, out);
+            }
+            "ul" | "ol" => self.list(element, &style, out),
+            "blockquote" | "dd" => self.block_children(element, &style, &OdtContext { style: "Quotations", heading: None }, out),
+            "pre" => {
+                let mut current = OdtParagraph::new();
+                let mut text = String::new();
+                collect_text(element, &mut text);
+                // A newline right after <pre> or before </pre> isn't content
+                let text = text.strip_prefix("\r\n").or_else(|| text.strip_prefix('\n')).unwrap_or(&text);
+                self.text(&mut current, text.trim_end_matches(['\n', '\r']), &style);
+                self.flush(&mut current, &style, &OdtContext { style: "Preformatted_20_Text", heading: None }, out);
+            }
+            "hr" => {
+                let mut current = OdtParagraph::new();
+                current.xml.push_str("* * *");
+                current.empty = false;
+                let centered = TextStyle { align: TextAlign::Center, ..style };
+                self.flush(&mut current, &centered, ctx, out);
+            }
+            "table" => self.table(element, &style, out),
+            _ => self.block_children(element, &style, ctx, out),
+        }
+    }
+
+    fn inline(&mut self, element: &HtmlElement, parent: &TextStyle, current: &mut OdtParagraph) {
+        let tag = element.tag.as_str();
+        if HIDDEN_ELEMENTS.contains(&tag) {
+            return;
+        }
+        let id = element.attr("id").or_else(|| if tag == "a" { element.attr("name") } else { None });
+        if let Some(id) = id {
+            current.xml.push_str(&format!("<text:bookmark text:name=\"{}\"/>", escape_markup(id)));
+        }
+        match tag {
+            "br" => {
+                current.xml.push_str("<text:line-break/>");
+                current.empty = false;
+                current.trailing_space = true;
+            }
+            "img" => self.image(element, parent, current),
+            "input" => {
+                if element.attr("type").map(|t| t.eq_ignore_ascii_case("checkbox")).unwrap_or(false) {
+                    let symbol = if element.attr("checked").is_some() { "\u{2611} " } else { "\u{2610} " };
+                    self.push_span(current, symbol, parent);
+                    current.trailing_space = true;
+                }
+            }
+            _ => {
+                let style = element_style(element, parent);
+                for child in &element.children {
+                    match child {
+                        HtmlNode::Text(text) => self.text(current, text, &style),
+                        HtmlNode::Element(e) => self.inline(e, &style, current),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add text with HTML whitespace collapsing, unless it's preformatted
+    fn text(&mut self, current: &mut OdtParagraph, text: &str, style: &TextStyle) {
+        if style.pre {
+            let text = text.replace('\r', "");
+            if !text.is_empty() {
+                self.push_span(current, &text, style);
+            }
+            return;
+        }
+        let mut collapsed = String::new();
+        for c in text.chars() {
+            if matches!(c, ' ' | '\n' | '\t' | '\r' | '\x0c') {
+                if !current.trailing_space {
+                    collapsed.push(' ');
+                    current.trailing_space = true;
+                }
+            } else if !matches!(c, '\u{ad}' | '\u{200b}' | '\u{feff}') {
+                collapsed.push(c);
+                current.trailing_space = false;
+            }
+        }
+        if !collapsed.is_empty() {
+            self.push_span(current, &collapsed, style);
+        }
+    }
+
+    /// Write text in a span of its style, inside a link if it has one
+    fn push_span(&mut self, current: &mut OdtParagraph, text: &str, style: &TextStyle) {
+        let mut xml = odf_text(text);
+        if let Some(name) = self.text_style(style) {
+            xml = format!("<text:span text:style-name=\"{}\">{}</text:span>", name, xml);
+        }
+        if let Some(link) = &style.link {
+            xml = format!(
+                "<text:a xlink:type=\"simple\" xlink:href=\"{}\" text:style-name=\"Internet_20_link\">{}</text:a>",
+                escape_markup(link),
+                xml
+            );
+        }
+        current.xml.push_str(&xml);
+        current.empty = false;
+    }
+
+    /// The automatic text style for a run's formatting, if it differs from
+    /// the paragraph's
+    fn text_style(&mut self, style: &TextStyle) -> Option<String> {
+        let default = TextStyle::default();
+        let mut props = String::new();
+        if style.bold {
+            props.push_str(" fo:font-weight=\"bold\" style:font-weight-asian=\"bold\" style:font-weight-complex=\"bold\"");
+        }
+        if style.italic {
+            props.push_str(" fo:font-style=\"italic\" style:font-style-asian=\"italic\" style:font-style-complex=\"italic\"");
+        }
+        if style.underline && style.link.is_none() {
+            props.push_str(
+                " style:text-underline-style=\"solid\" style:text-underline-width=\"auto\" style:text-underline-color=\"font-color\"",
+            );
+        }
+        if style.strike {
+            props.push_str(" style:text-line-through-style=\"solid\"");
+        }
+        if style.mono && !style.pre {
+            props.push_str(" style:font-name=\"Liberation Mono\"");
+        }
+        if style.color != default.color && style.link.is_none() {
+            props.push_str(&format!(" fo:color=\"#{}\"", hex_color(style.color)));
+        }
+        if style.rise > 0.0 {
+            props.push_str(" style:text-position=\"super 58%\"");
+        } else if style.rise < 0.0 {
+            props.push_str(" style:text-position=\"sub 58%\"");
+        } else if (style.size - default.size).abs() > 0.1 {
+            props.push_str(&format!(" fo:font-size=\"{}pt\"", style.size));
+        }
+        // Code spans get a grey background in HTML; marked text is highlighted
+        if let Some(background) = style.background {
+            props.push_str(&format!(" fo:background-color=\"#{}\"", hex_color(background)));
+        }
+        if props.is_empty() {
+            return None;
+        }
+        if let Some(name) = self.text_styles.get(&props) {
+            return Some(name.clone());
+        }
+        let name = format!("T{}", self.text_styles.len() + 1);
+        self.automatic.push_str(&format!(
+            "<style:style style:name=\"{}\" style:family=\"text\"><style:text-properties{}/></style:style>",
+            name, props
+        ));
+        self.text_styles.insert(props, name.clone());
+        Some(name)
+    }
+
+    fn flush(&mut self, current: &mut OdtParagraph, style: &TextStyle, ctx: &OdtContext, out: &mut String) {
+        if current.empty {
+            return;
+        }
+        let paragraph = std::mem::replace(current, OdtParagraph::new());
+        let align = match style.align {
+            TextAlign::Center => Some("center"),
+            TextAlign::Right => Some("end"),
+            TextAlign::Left => None,
+        };
+        let page_break = std::mem::take(&mut self.page_break);
+        let style_name = if align.is_some() || page_break {
+            self.paragraph_style(ctx.style, align, page_break)
+        } else {
+            ctx.style.to_string()
+        };
+        let bookmarks: String = self
+            .pending_bookmarks
+            .drain(..)
+            .map(|name| format!("<text:bookmark text:name=\"{}\"/>", escape_markup(&name)))
+            .collect();
+        match ctx.heading {
+            Some(level) => out.push_str(&format!(
+                "<text:h text:style-name=\"{}\" text:outline-level=\"{}\">{}{}</text:h>",
+                style_name, level, bookmarks, paragraph.xml
+            )),
+            None => out.push_str(&format!(
+                "<text:p text:style-name=\"{}\">{}{}</text:p>",
+                style_name, bookmarks, paragraph.xml
+            )),
+        }
+        self.paragraphs += 1;
+    }
+
+    fn paragraph_style(&mut self, parent: &str, align: Option<&str>, page_break: bool) -> String {
+        let mut props = String::new();
+        if let Some(align) = align {
+            props.push_str(&format!(" fo:text-align=\"{}\"", align));
+        }
+        if page_break {
+            props.push_str(" fo:break-before=\"page\"");
+        }
+        let key = format!("{}{}", parent, props);
+        if let Some(name) = self.paragraph_styles.get(&key) {
+            return name.clone();
+        }
+        let name = format!("P{}", self.paragraph_styles.len() + 1);
+        self.automatic.push_str(&format!(
+            "<style:style style:name=\"{}\" style:family=\"paragraph\" style:parent-style-name=\"{}\">\
+             <style:paragraph-properties{}/></style:style>",
+            name, parent, props
+        ));
+        self.paragraph_styles.insert(key, name.clone());
+        name
+    }
+
+    fn image(&mut self, element: &HtmlElement, style: &TextStyle, current: &mut OdtParagraph) {
+        let source = element.attr("src").unwrap_or("").trim();
+        let picture = read_image_source(source, self.base_dir).and_then(|(bytes, svg)| picture_data(&bytes, svg));
+        let Some((bytes, (natural_width, natural_height))) = picture else {
+            // Images that can't be loaded show their description instead
+            let alt = element.attr("alt").unwrap_or("").trim();
+            if !alt.is_empty() {
+                let style = TextStyle { italic: true, ..style.clone() };
+                self.push_span(current, &format!("[{}]", alt), &style);
+                current.trailing_space = false;
+            }
+            return;
+        };
+        let width = element.attr("width").and_then(css_length).or_else(|| element.style("width").as_deref().and_then(css_length));
+        let height = element.attr("height").and_then(css_length).or_else(|| element.style("height").as_deref().and_then(css_length));
+        let (width, height) = match (width, height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => (w, natural_height * w / natural_width),
+            (None, Some(h)) => (natural_width * h / natural_height, h),
+            (None, None) => (natural_width, natural_height),
+        };
+        let (width_cm, height_cm) = (width / 72.0 * 2.54, height / 72.0 * 2.54);
+        let scale = (CONTENT_WIDTH_CM / width_cm).min(1.0);
+
+        let extension = match image::guess_format(&bytes) {
+            Ok(image::ImageFormat::Jpeg) => "jpg",
+            Ok(image::ImageFormat::Gif) => "gif",
+            Ok(image::ImageFormat::Bmp) => "bmp",
+            Ok(image::ImageFormat::WebP) => "webp",
+            Ok(image::ImageFormat::Tiff) => "tif",
+            _ => "png",
+        };
+        let number = self.pictures.len() + 1;
+        let path = format!("Pictures/image{}.{}", number, extension);
+        let alt = element.attr("alt").unwrap_or("").trim();
+        let title = if alt.is_empty() { String::new() } else { format!("<svg:title>{}</svg:title>", escape_markup(alt)) };
+        let mut frame = format!(
+            "<draw:frame draw:name=\"Image{}\" text:anchor-type=\"as-char\" svg:width=\"{:.3}cm\" svg:height=\"{:.3}cm\" draw:z-index=\"0\">\
+             <draw:image xlink:href=\"{}\" xlink:type=\"simple\" xlink:show=\"embed\" xlink:actuate=\"onLoad\"/>{}</draw:frame>",
+            number,
+            width_cm * scale,
+            height_cm * scale,
+            path,
+            title
+        );
+        if let Some(link) = &style.link {
+            frame = format!("<text:a xlink:type=\"simple\" xlink:href=\"{}\">{}</text:a>", escape_markup(link), frame);
+        }
+        self.pictures.push((path, bytes));
+        current.xml.push_str(&frame);
+        current.empty = false;
+        current.trailing_space = false;
+    }
+
+    fn list(&mut self, list: &HtmlElement, style: &TextStyle, out: &mut String) {
+        let style_name = if list.tag == "ol" { self.ordered_list_style(list) } else { self.bullet_list_style() };
+        out.push_str(&format!("<text:list text:style-name=\"{}\">", style_name));
+        let item_ctx = OdtContext { style: "Text_20_body", heading: None };
+        for item in list.elements() {
+            let (open, close) = if item.tag == "li" {
+                ("<text:list-item>", "</text:list-item>")
+            } else {
+                ("<text:list-header>", "</text:list-header>")
+            };
+            if let Some(id) = item.attr("id") {
+                self.pending_bookmarks.push(id.to_string());
+            }
+            let mut content = String::new();
+            let item_style = element_style(item, style);
+            if item.tag == "li" {
+                self.block_children(item, &item_style, &item_ctx, &mut content);
+            } else {
+                self.block(item, style, &item_ctx, &mut content);
+            }
+            if content.is_empty() {
+                content.push_str("<text:p text:style-name=\"Text_20_body\"/>");
+            }
+            out.push_str(open);
+            out.push_str(&content);
+            out.push_str(close);
+        }
+        out.push_str("</text:list>");
+    }
+
+    /// The list style shared by every bulleted list
+    fn bullet_list_style(&mut self) -> String {
+        if let Some(name) = &self.bullet_style {
+            return name.clone();
+        }
+        self.list_styles += 1;
+        let name = format!("L{}", self.list_styles);
+        let mut levels = String::new();
+        for level in 1..=10 {
+            levels.push_str(&format!(
+                "<text:list-level-style-bullet text:level=\"{}\" text:bullet-char=\"{}\">{}</text:list-level-style-bullet>",
+                level,
+                ["\u{2022}", "\u{25e6}", "\u{25aa}"][(level - 1) % 3],
+                list_level_properties(level)
+            ));
+        }
+        self.automatic.push_str(&format!("<text:list-style style:name=\"{}\">{}</text:list-style>", name, levels));
+        self.bullet_style = Some(name.clone());
+        name
+    }
+
+    /// A list style of its own for each ordered list, so every list keeps
+    /// its own numbering type and start value
+    fn ordered_list_style(&mut self, list: &HtmlElement) -> String {
+        self.list_styles += 1;
+        let name = format!("L{}", self.list_styles);
+        let start = list.attr("start").and_then(|s| s.trim().parse::<usize>().ok()).unwrap_or(1);
+        let format = match list.attr("type").unwrap_or("1") {
+            f @ ("a" | "A" | "i" | "I") => f,
+            _ => "1",
+        };
+        let mut levels = String::new();
+        for level in 1..=10 {
+            levels.push_str(&format!(
+                "<text:list-level-style-number text:level=\"{}\" style:num-suffix=\".\" style:num-format=\"{}\" text:start-value=\"{}\">{}</text:list-level-style-number>",
+                level,
+                format,
+                start,
+                list_level_properties(level)
+            ));
+        }
+        self.automatic.push_str(&format!("<text:list-style style:name=\"{}\">{}</text:list-style>", name, levels));
+        name
+    }
+
+    fn table(&mut self, table: &HtmlElement, style: &TextStyle, out: &mut String) {
+        let mut rows: Vec<(&HtmlElement, bool)> = Vec::new();
+        for child in table.elements() {
+            match child.tag.as_str() {
+                "caption" => {
+                    let ctx = OdtContext { style: "Text_20_body", heading: None };
+                    let caption_style = element_style(child, style);
+                    self.block_children(child, &caption_style, &ctx, out);
+                }
+                "thead" => rows.extend(child.elements().filter(|r| r.tag == "tr").map(|r| (r, true))),
+                "tbody" | "tfoot" => rows.extend(child.elements().filter(|r| r.tag == "tr").map(|r| (r, false))),
+                "tr" => rows.push((child, false)),
+                _ => {}
+            }
+        }
+
+        // Place the cells on a grid, honouring column and row spans
+        struct Cell<'a> {
+            element: &'a HtmlElement,
+            row: usize,
+            column: usize,
+            columns: usize,
+            rows: usize,
+        }
+        let mut cells: Vec<Cell> = Vec::new();
+        let mut occupied: Vec<Vec<bool>> = vec![Vec::new(); rows.len()];
+        for (r, (row, _)) in rows.iter().enumerate() {
+            let mut column = 0;
+            for cell in row.elements().filter(|c| c.tag == "td" || c.tag == "th") {
+                while occupied[r].get(column).copied().unwrap_or(false) {
+                    column += 1;
+                }
+                let span = |name: &str| cell.attr(name).and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(1).max(1);
+                let columns = span("colspan").min(64);
+                let row_span = span("rowspan").min(rows.len() - r);
+                for occupied_row in occupied.iter_mut().skip(r).take(row_span) {
+                    if occupied_row.len() < column + columns {
+                        occupied_row.resize(column + columns, false);
+                    }
+                    occupied_row[column..column + columns].iter_mut().for_each(|o| *o = true);
+                }
+                cells.push(Cell { element: cell, row: r, column, columns, rows: row_span });
+                column += columns;
+            }
+        }
+        let column_count = occupied.iter().map(Vec::len).max().unwrap_or(0);
+        if column_count == 0 {
+            return;
+        }
+
+        if self.table_count == 0 {
+            self.automatic.push_str(
+                "<style:style style:name=\"Table\" style:family=\"table\">\
+                 <style:table-properties style:width=\"17cm\" table:align=\"margins\"/></style:style>\
+                 <style:style style:name=\"TableCell\" style:family=\"table-cell\">\
+                 <style:table-cell-properties fo:padding=\"0.1cm\" fo:border=\"0.5pt solid #999999\"/></style:style>\
+                 <style:style style:name=\"TableHeaderCell\" style:family=\"table-cell\">\
+                 <style:table-cell-properties fo:padding=\"0.1cm\" fo:border=\"0.5pt solid #999999\" fo:background-color=\"#f2f2f2\"/></style:style>",
+            );
+        }
+        self.table_count += 1;
+        out.push_str(&format!(
+            "<table:table table:name=\"Table{}\" table:style-name=\"Table\">\
+             <table:table-column table:number-columns-repeated=\"{}\"/>",
+            self.table_count, column_count
+        ));
+        // Leading thead rows repeat on every page
+        let header_rows = rows.iter().take_while(|(_, header)| *header).count();
+        for (r, (row, _)) in rows.iter().enumerate() {
+            if r == 0 && header_rows > 0 {
+                out.push_str("<table:table-header-rows>");
+            }
+            out.push_str("<table:table-row>");
+            let row_style = element_style(row, style);
+            for column in 0..column_count {
+                if let Some(cell) = cells.iter().find(|c| c.row == r && c.column == column) {
+                    let header = cell.element.tag == "th" || r < header_rows;
+                    let mut attrs = String::new();
+                    if cell.columns > 1 {
+                        attrs.push_str(&format!(" table:number-columns-spanned=\"{}\"", cell.columns));
+                    }
+                    if cell.rows > 1 {
+                        attrs.push_str(&format!(" table:number-rows-spanned=\"{}\"", cell.rows));
+                    }
+                    let ctx = OdtContext { style: if header { "Table_20_Heading" } else { "Table_20_Contents" }, heading: None };
+                    let mut content = String::new();
+                    let cell_style = element_style(cell.element, &row_style);
+                    // Header cells are bold through their paragraph style
+                    let cell_style = TextStyle { bold: cell_style.bold && !header, ..cell_style };
+                    self.block_children(cell.element, &cell_style, &ctx, &mut content);
+                    if content.is_empty() {
+                        content = format!("<text:p text:style-name=\"{}\"/>", ctx.style);
+                    }
+                    out.push_str(&format!(
+                        "<table:table-cell table:style-name=\"{}\" office:value-type=\"string\"{}>{}</table:table-cell>",
+                        if header { "TableHeaderCell" } else { "TableCell" },
+                        attrs,
+                        content
+                    ));
+                } else if occupied[r].get(column).copied().unwrap_or(false) {
+                    out.push_str("<table:covered-table-cell/>");
+                } else {
+                    out.push_str("<table:table-cell table:style-name=\"TableCell\"/>");
+                }
+            }
+            out.push_str("</table:table-row>");
+            if header_rows > 0 && r + 1 == header_rows {
+                out.push_str("</table:table-header-rows>");
+            }
+        }
+        out.push_str("</table:table>");
+    }
+}
+
+fn list_level_properties(level: usize) -> String {
+    format!(
+        "<style:list-level-properties text:list-level-position-and-space-mode=\"label-alignment\">\
+         <style:list-level-label-alignment text:label-followed-by=\"listtab\" text:list-tab-stop-position=\"{0:.3}cm\" \
+         fo:text-indent=\"-{1:.3}cm\" fo:margin-left=\"{0:.3}cm\"/></style:list-level-properties>",
+        LIST_INDENT_CM * level as f32,
+        LIST_INDENT_CM
+    )
+}
+
+/// Text as ODF markup: runs of spaces, tabs and newlines become
+/// `text:s`, `text:tab` and `text:line-break`
+fn odf_text(text: &str) -> String {
+    let mut xml = String::new();
+    let mut spaces = 0;
+    let mut line_start = true;
+    let flush_spaces = |xml: &mut String, spaces: &mut usize, line_start: bool| {
+        match *spaces {
+            0 => {}
+            1 if !line_start => xml.push(' '),
+            n if line_start => xml.push_str(&format!("<text:s text:c=\"{}\"/>", n)),
+            n => xml.push_str(&format!(" <text:s text:c=\"{}\"/>", n - 1)),
+        }
+        *spaces = 0;
+    };
+    for c in text.chars() {
+        match c {
+            ' ' => spaces += 1,
+            '\t' | '\n' => {
+                flush_spaces(&mut xml, &mut spaces, line_start);
+                xml.push_str(if c == '\t' { "<text:tab/>" } else { "<text:line-break/>" });
+                line_start = c == '\n';
+            }
+            _ => {
+                flush_spaces(&mut xml, &mut spaces, line_start);
+                line_start = false;
+                match c {
+                    '&' => xml.push_str("&amp;"),
+                    '<' => xml.push_str("&lt;"),
+                    '>' => xml.push_str("&gt;"),
+                    '"' => xml.push_str("&quot;"),
+                    c if (c as u32) < 0x20 => {}
+                    c => xml.push(c),
+                }
+            }
+        }
+    }
+    flush_spaces(&mut xml, &mut spaces, line_start);
+    xml
+}
+
@@ -2,10 +2,32 @@ use std::fs;
 use std::process::Command;
 use std::path::Path;
 use crate::ConversionResult;
+use crate::converters::image_converter::{apply_watermark, image_to_pdf_xobject, pixmap_to_rgba, WatermarkOptions};
+use crate::converters::pdf_renderer::render_pdf_page;
+use crate::converters::pdf_security::{load_pdf_with_password, open_pdf, PdfSecurity};
+use image::GenericImageView;
+use printpdf::{ColorBits, ColorSpace, Image, ImageFilter, ImageTransform, ImageXObject, Mm, PdfDocument, Px};
 
-pub fn extract_pdf_text(input_path: &str, output_path: &str) -> ConversionResult {
-    let text = match pdf_extract::extract_text(input_path) {
-        Ok(t) => t,
+/// How to lay out text pulled from a PDF
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PdfTextOptions {
+    /// Pages to extract, e.g. "1-3,5". Defaults to every page
+    pub pages: Option<String>,
+    /// "plain" (default) for reading-order text, or "layout" to keep columns
+    /// and spacing by placing text on a character grid
+    pub mode: Option<String>,
+    /// Written between pages; `{page}` is replaced by the next page number.
+    /// Defaults to a blank line
+    pub page_separator: Option<String>,
+    /// Write one `{name}-{page}.txt` file per page instead of a single file
+    pub per_page: Option<bool>,
+    /// Opens password-protected PDFs
+    pub password: Option<String>,
+}
+
+pub fn extract_pdf_text(input_path: &str, output_path: &str, options: &PdfTextOptions) -> ConversionResult {
+    let doc = match load_pdf_with_password(input_path, options.password.as_deref()) {
+        Ok(d) => d,
         Err(e) => {
             return ConversionResult {
                 success: false,
@@ -15,14 +37,91 @@ pub fn extract_pdf_text(input_path: &str, output_path: &str) -> ConversionResult
             };
         }
     };
+    let page_count = doc.get_pages().len() as u32;
+    let pages = match parse_page_ranges(options.pages.as_deref().unwrap_or("1-"), page_count) {
+        Ok(p) => p,
+        Err(e) => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(e),
+                data: None,
+            };
+        }
+    };
+
+    let layout = options.mode.as_deref() == Some("layout");
+    let mut page_texts = Vec::new();
+    for page in &pages {
+        let text = if layout {
+            collect_page_glyphs(&doc, *page).map(|(glyphs, _, _)| layout_text(&glyphs))
+        } else {
+            let mut text = String::new();
+            pdf_extract::output_doc_page(&doc, &mut pdf_extract::PlainTextOutput::new(&mut text), *page)
+                .map(|_| text)
+                .map_err(|e| e.to_string())
+        };
+        match text {
+            Ok(t) => page_texts.push((*page, t)),
+            Err(e) => {
+                return ConversionResult {
+                    success: false,
+                    output_path: None,
+                    error: Some(format!("Failed to extract text from page {}: {}", page, e)),
+                    data: None,
+                };
+            }
+        }
+    }
+
+    if options.per_page.unwrap_or(false) {
+        let base = Path::new(output_path);
+        let stem = base.file_stem().and_then(|s| s.to_str()).unwrap_or("output");
+        let dir = base.parent().unwrap_or(Path::new("."));
+        let width = page_count.to_string().len();
+
+        let mut files = Vec::new();
+        for (page, text) in &page_texts {
+            let file = dir
+                .join(format!("{}-{:0width$}.txt", stem, page, width = width))
+                .to_string_lossy()
+                .to_string();
+            if let Err(e) = fs::write(&file, text) {
+                return ConversionResult {
+                    success: false,
+                    output_path: None,
+                    error: Some(format!("Failed to write text file: {}", e)),
+                    data: None,
+                };
+            }
+            files.push(serde_json::json!({ "page": page, "file": file }));
+        }
+
+        let report = serde_json::json!({ "page_count": files.len(), "pages": files });
+        return ConversionResult {
+            success: true,
+            output_path: report["pages"][0]["file"].as_str().map(|f| f.to_string()),
+            error: None,
+            data: Some(serde_json::to_string_pretty(&report).unwrap()),
+        };
+    }
+
+    let separator = options.page_separator.as_deref().unwrap_or("\n\n");
+    let mut text = String::new();
+    for (i, (page, page_text)) in page_texts.iter().enumerate() {
+        if i > 0 {
+            text.push_str(&separator.replace("{page}", &page.to_string()));
+        }
+        text.push_str(page_text.trim_end_matches('\n'));
+    }
 
     match fs::write(output_path, &text) {
         Ok(_) => ConversionResult {
             success: true,
             output_path: Some(output_path.to_string()),
             error: None,
-            data: Some(if text.len() > 1000 {
-                format!("{}...", &text[..1000])
+            data: Some(if text.chars().count() > 1000 {
+                format!("{}...", text.chars().take(1000).collect::<String>())
             } else {
                 text
             }),
@@ -36,17 +135,390 @@ pub fn extract_pdf_text(input_path: &str, output_path: &str) -> ConversionResult
     }
 }
 
-pub fn get_pdf_info(input_path: &str) -> Result<PdfInfo, String> {
-    let doc = lopdf::Document::load(input_path).map_err(|e| e.to_string())?;
+/// Extract text as JSON: per page, blocks of lines with bounding boxes in
+/// points, measured from the top-left corner of the page
+pub fn extract_pdf_text_blocks(input_path: &str, output_path: &str, options: &PdfTextOptions) -> ConversionResult {
+    let doc = match load_pdf_with_password(input_path, options.password.as_deref()) {
+        Ok(d) => d,
+        Err(e) => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Failed to extract PDF text: {}", e)),
+                data: None,
+            };
+        }
+    };
+    let selected = match parse_page_ranges(options.pages.as_deref().unwrap_or("1-"), doc.get_pages().len() as u32) {
+        Ok(p) => p,
+        Err(e) => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(e),
+                data: None,
+            };
+        }
+    };
+
+    let mut page_reports = Vec::new();
+    let mut block_count = 0;
+    for page in selected {
+        let (glyphs, width, height) = match collect_page_glyphs(&doc, page) {
+            Ok(g) => g,
+            Err(e) => {
+                return ConversionResult {
+                    success: false,
+                    output_path: None,
+                    error: Some(format!("Failed to extract text from page {}: {}", page, e)),
+                    data: None,
+                };
+            }
+        };
+        let blocks = text_blocks(&glyphs);
+        block_count += blocks.len();
+        page_reports.push(serde_json::json!({
+            "page": page,
+            "width": round2(width),
+            "height": round2(height),
+            "blocks": blocks,
+        }));
+    }
+
+    let report = serde_json::json!({
+        "units": "points, origin at the top-left of the page",
+        "pages": page_reports,
+    });
+    match fs::write(output_path, serde_json::to_string_pretty(&report).unwrap()) {
+        Ok(_) => ConversionResult {
+            success: true,
+            output_path: Some(output_path.to_string()),
+            error: None,
+            data: Some(format!("Extracted {} text block(s) from {} page(s)", block_count, page_reports.len())),
+        },
+        Err(e) => ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some(format!("Failed to write JSON file: {}", e)),
+            data: None,
+        },
+    }
+}
+
+/// One positioned character, in points from the top-left of the page
+#[derive(Debug, Clone)]
+struct Glyph {
+    text: String,
+    x: f64,
+    baseline: f64,
+    advance: f64,
+    size: f64,
+}
+
+impl Glyph {
+    fn top(&self) -> f64 {
+        self.baseline - self.size * 0.8
+    }
+
+    fn bottom(&self) -> f64 {
+        self.baseline + self.size * 0.2
+    }
+
+    fn right(&self) -> f64 {
+        self.x + self.advance
+    }
+}
+
+/// Collects character positions from pdf-extract's text interpreter
+#[derive(Default)]
+struct GlyphCollector {
+    glyphs: Vec<Glyph>,
+    left: f64,
+    top: f64,
+    width: f64,
+    height: f64,
+}
+
+impl pdf_extract::OutputDev for GlyphCollector {
+    fn begin_page(&mut self, _page_num: u32, media_box: &pdf_extract::MediaBox, _art_box: Option<(f64, f64, f64, f64)>) -> Result<(), pdf_extract::OutputError> {
+        self.left = media_box.llx.min(media_box.urx);
+        self.top = media_box.ury.max(media_box.lly);
+        self.width = (media_box.urx - media_box.llx).abs();
+        self.height = (media_box.ury - media_box.lly).abs();
+        Ok(())
+    }
+
+    fn end_page(&mut self) -> Result<(), pdf_extract::OutputError> {
+        Ok(())
+    }
+
+    fn output_character(&mut self, trm: &pdf_extract::Transform, width: f64, _spacing: f64, font_size: f64, text: &str) -> Result<(), pdf_extract::OutputError> {
+        // Whitespace is implied by the gaps between the other characters
+        if text.trim().is_empty() {
+            return Ok(());
+        }
+        let horizontal_scale = (trm.m11 * trm.m11 + trm.m12 * trm.m12).sqrt();
+        let vertical_scale = (trm.m21 * trm.m21 + trm.m22 * trm.m22).sqrt();
+        let size = font_size * vertical_scale;
+        if size <= 0.0 {
+            return Ok(());
+        }
+        self.glyphs.push(Glyph {
+            text: text.to_string(),
+            x: trm.m31 - self.left,
+            baseline: self.top - trm.m32,
+            advance: width * font_size * horizontal_scale,
+            size,
+        });
+        Ok(())
+    }
+
+    fn begin_word(&mut self) -> Result<(), pdf_extract::OutputError> {
+        Ok(())
+    }
+
+    fn end_word(&mut self) -> Result<(), pdf_extract::OutputError> {
+        Ok(())
+    }
+
+    fn end_line(&mut self) -> Result<(), pdf_extract::OutputError> {
+        Ok(())
+    }
+}
+
+/// The characters on one page, plus the page's width and height
+fn collect_page_glyphs(doc: &lopdf::Document, page: u32) -> Result<(Vec<Glyph>, f64, f64), String> {
+    let mut collector = GlyphCollector::default();
+    pdf_extract::output_doc_page(doc, &mut collector, page).map_err(|e| e.to_string())?;
+    Ok((collector.glyphs, collector.width, collector.height))
+}
+
+/// Group characters into lines, top to bottom, each sorted left to right
+fn group_lines(glyphs: &[Glyph]) -> Vec<Vec<Glyph>> {
+    let mut sorted: Vec<Glyph> = glyphs.to_vec();
+    sorted.sort_by(|a, b| a.baseline.total_cmp(&b.baseline).then(a.x.total_cmp(&b.x)));
+
+    let mut lines: Vec<Vec<Glyph>> = Vec::new();
+    for glyph in sorted {
+        match lines.last_mut() {
+            Some(line) if (glyph.baseline - line[0].baseline).abs() < line[0].size.min(glyph.size) * 0.4 => line.push(glyph),
+            _ => lines.push(vec![glyph]),
+        }
+    }
+
+    for line in &mut lines {
+        line.sort_by(|a, b| a.x.total_cmp(&b.x));
+        // Drop characters drawn twice in the same spot (a common fake-bold trick)
+        line.dedup_by(|b, a| a.text == b.text && (a.x - b.x).abs() < a.size * 0.1);
+    }
+    lines
+}
+
+/// Join characters left to right, adding a space wherever the gap looks like one
+fn join_glyphs(glyphs: &[Glyph]) -> String {
+    let mut text = String::new();
+    let mut last_right: Option<f64> = None;
+    for glyph in glyphs {
+        if let Some(right) = last_right {
+            if glyph.x - right > glyph.size * 0.2 {
+                text.push(' ');
+            }
+        }
+        text.push_str(&glyph.text);
+        last_right = Some(glyph.right());
+    }
+    text
+}
+
+/// Place text on a fixed character grid so columns and indentation survive
+fn layout_text(glyphs: &[Glyph]) -> String {
+    let lines = group_lines(glyphs);
+    if lines.is_empty() {
+        return String::new();
+    }
+
+    // The grid cell is the typical character width on the page
+    let mut advances: Vec<f64> = glyphs.iter().map(|g| g.advance).filter(|a| *a > 0.0).collect();
+    advances.sort_by(|a, b| a.total_cmp(b));
+    let cell = advances.get(advances.len() / 2).copied().unwrap_or(5.0).max(1.0);
+
+    let mut output = String::new();
+    let mut previous_baseline: Option<f64> = None;
+    for line in &lines {
+        let size = line[0].size;
+        if let Some(previous) = previous_baseline {
+            // Keep paragraph gaps as blank lines
+            let blank_lines = ((line[0].baseline - previous) / (size * 1.2)).round() as i64 - 1;
+            for _ in 0..blank_lines.clamp(0, 3) {
+                output.push('\n');
+            }
+        }
+        previous_baseline = Some(line[0].baseline);
+
+        let mut row = String::new();
+        let mut columns = 0usize;
+        let mut last_right: Option<f64> = None;
+        for glyph in line {
+            let column = (glyph.x / cell).round().max(0.0) as usize;
+            if column > columns {
+                row.push_str(&" ".repeat(column - columns));
+                columns = column;
+            } else if last_right.map(|r| glyph.x - r > glyph.size * 0.2).unwrap_or(false) {
+                row.push(' ');
+                columns += 1;
+            }
+            row.push_str(&glyph.text);
+            columns += glyph.text.chars().count();
+            last_right = Some(glyph.right());
+        }
+        output.push_str(row.trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+/// Split lines into runs at wide gaps, then stack runs that sit under each
+/// other into blocks (paragraphs, table cells, columns)
+fn text_blocks(glyphs: &[Glyph]) -> Vec<serde_json::Value> {
+    struct Block {
+        lines: Vec<(String, [f64; 4])>,
+        bbox: [f64; 4],
+        size: f64,
+    }
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for line in group_lines(glyphs) {
+        let mut runs: Vec<Vec<Glyph>> = Vec::new();
+        for glyph in line {
+            match runs.last_mut() {
+                Some(run) if glyph.x - run.last().map(|g| g.right()).unwrap_or(glyph.x) < glyph.size * 1.5 => run.push(glyph),
+                _ => runs.push(vec![glyph]),
+            }
+        }
+
+        for run in runs {
+            let bbox = [
+                run.iter().map(|g| g.x).fold(f64::MAX, f64::min),
+                run.iter().map(|g| g.top()).fold(f64::MAX, f64::min),
+                run.iter().map(|g| g.right()).fold(f64::MIN, f64::max),
+                run.iter().map(|g| g.bottom()).fold(f64::MIN, f64::max),
+            ];
+            let size = run[0].size;
+            let text = join_glyphs(&run);
+
+            let target = blocks.iter_mut().rev().find(|block| {
+                let gap = bbox[1] - block.bbox[3];
+                let overlaps = bbox[0] < block.bbox[2] && bbox[2] > block.bbox[0];
+                let similar = (size / block.size).max(block.size / size) < 1.3;
+                overlaps && similar && gap > -size * 0.5 && gap < size
+            });
+            match target {
+                Some(block) => {
+                    block.bbox = [
+                        block.bbox[0].min(bbox[0]),
+                        block.bbox[1].min(bbox[1]),
+                        block.bbox[2].max(bbox[2]),
+                        block.bbox[3].max(bbox[3]),
+                    ];
+                    block.lines.push((text, bbox));
+                }
+                None => blocks.push(Block { lines: vec![(text, bbox)], bbox, size }),
+            }
+        }
+    }
+
+    blocks
+        .into_iter()
+        .map(|block| {
+            let text = block.lines.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>().join("\n");
+            serde_json::json!({
+                "text": text,
+                "bbox": block.bbox.map(round2),
+                "font_size": round2(block.size),
+                "lines": block
+                    .lines
+                    .iter()
+                    .map(|(t, b)| serde_json::json!({ "text": t, "bbox": b.map(round2) }))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect()
+}
+
+fn round2(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+pub fn get_pdf_info(input_path: &str, password: Option<&str>) -> Result<PdfInfo, String> {
+    let (doc, security) = open_pdf(input_path, password)?;
     
     let page_count = doc.get_pages().len();
     
     // Get PDF version
     let version = doc.version.clone();
-    
+
+    let xmp_metadata = doc
+        .catalog()
+        .and_then(|c| c.get_deref(b"Metadata", &doc))
+        .and_then(lopdf::Object::as_stream)
+        .ok()
+        .map(|s| String::from_utf8_lossy(&s.decompressed_content().unwrap_or_else(|_| s.content.clone())).to_string());
+
+    let mut pages = Vec::new();
+    let mut fonts: Vec<PdfFontInfo> = Vec::new();
+    for (page, page_id) in doc.get_pages() {
+        let page_dict = doc.get_dictionary(page_id).ok();
+        let inherited = |key: &[u8]| {
+            let mut node = page_dict;
+            for _ in 0..32 {
+                let dict = node?;
+                if let Ok(value) = dict.get_deref(key, &doc) {
+                    return Some(value.clone());
+                }
+                node = dict.get_deref(b"Parent", &doc).and_then(lopdf::Object::as_dict).ok();
+            }
+            None
+        };
+        let page_box: Vec<f64> = inherited(b"CropBox")
+            .or_else(|| inherited(b"MediaBox"))
+            .and_then(|b| b.as_array().ok().map(|a| a.iter().map(|n| n.as_float().unwrap_or(0.0) as f64).collect()))
+            .unwrap_or_else(|| vec![0.0, 0.0, 612.0, 792.0]);
+        let (width, height) = match page_box.as_slice() {
+            [x0, y0, x1, y1] => ((x1 - x0).abs(), (y1 - y0).abs()),
+            _ => (612.0, 792.0),
+        };
+        let resources = inherited(b"Resources")
+            .and_then(|r| r.as_dict().ok().cloned())
+            .unwrap_or_default();
+        let content = doc.get_page_content(page_id).unwrap_or_default();
+
+        pages.push(PdfPageInfo {
+            page,
+            width: round2(width),
+            height: round2(height),
+            rotation: inherited(b"Rotate").and_then(|r| r.as_i64().ok()).unwrap_or(0).rem_euclid(360),
+            has_text: shows_text(&doc, &content, &resources, 0),
+        });
+
+        for font in doc.get_page_fonts(page_id).unwrap_or_default().values() {
+            let info = describe_font(&doc, font);
+            if !fonts.iter().any(|f| f.name == info.name && f.font_type == info.font_type) {
+                fonts.push(info);
+            }
+        }
+    }
+    fonts.sort_by(|a, b| a.name.cmp(&b.name));
+
     Ok(PdfInfo {
         page_count,
         version,
+        metadata: read_pdf_metadata(&doc),
+        xmp_metadata,
+        has_text_layer: pages.iter().any(|p| p.has_text),
+        pages,
+        encrypted: security.is_some(),
+        security,
+        fonts,
     })
 }
 
@@ -54,121 +526,777 @@ pub fn get_pdf_info(input_path: &str) -> Result<PdfInfo, String> {
 pub struct PdfInfo {
     pub page_count: usize,
     pub version: String,
+    /// Fields of the document information dictionary
+    pub metadata: PdfMetadata,
+    /// The XMP packet attached to the document catalog
+    pub xmp_metadata: Option<String>,
+    pub pages: Vec<PdfPageInfo>,
+    pub encrypted: bool,
+    /// Encryption method and permissions, for encrypted PDFs
+    pub security: Option<PdfSecurity>,
+    pub fonts: Vec<PdfFontInfo>,
+    /// Whether any page draws text, i.e. the PDF isn't just scanned images
+    pub has_text_layer: bool,
+}
+
+/// The document information dictionary. Dates are ISO 8601 when they parse.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PdfMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Option<String>,
+    pub creator: Option<String>,
+    pub producer: Option<String>,
+    pub creation_date: Option<String>,
+    pub modification_date: Option<String>,
+    /// Any other entries, by key
+    pub custom: Option<std::collections::BTreeMap<String, String>>,
+}
+
+/// Size of a page's visible area (its crop box) in points
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PdfPageInfo {
+    pub page: u32,
+    pub width: f64,
+    pub height: f64,
+    /// Clockwise rotation applied when the page is displayed
+    pub rotation: i64,
+    pub has_text: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PdfFontInfo {
+    pub name: String,
+    /// Type1, TrueType, Type0, Type3, ...
+    pub font_type: String,
+    pub embedded: bool,
+    /// Only the glyphs used are embedded (a name like ABCDEF+Arial)
+    pub subset: bool,
+}
+
+/// Keys of the info dictionary that map to named PdfMetadata fields
+pub const PDF_INFO_KEYS: [&str; 8] = [
+    "Title", "Author", "Subject", "Keywords", "Creator", "Producer", "CreationDate", "ModDate",
+];
+
+pub fn read_pdf_metadata(doc: &lopdf::Document) -> PdfMetadata {
+    let info = match doc.trailer.get_deref(b"Info", doc).and_then(lopdf::Object::as_dict) {
+        Ok(info) => info,
+        Err(_) => return PdfMetadata::default(),
+    };
+    let text = |key: &[u8]| {
+        info.get_deref(key, doc)
+            .ok()
+            .and_then(|v| lopdf::decode_text_string(v).ok())
+            .map(|v| v.trim_start_matches('\u{feff}').to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let date = |key: &[u8]| text(key).map(|d| pdf_date_to_iso(&d).unwrap_or(d));
+
+    let custom: std::collections::BTreeMap<String, String> = info
+        .iter()
+        .filter(|(key, _)| !PDF_INFO_KEYS.iter().any(|k| k.as_bytes() == key.as_slice()))
+        .filter_map(|(key, _)| Some((String::from_utf8_lossy(key).to_string(), text(key)?)))
+        .collect();
+
+    PdfMetadata {
+        title: text(b"Title"),
+        author: text(b"Author"),
+        subject: text(b"Subject"),
+        keywords: text(b"Keywords"),
+        creator: text(b"Creator"),
+        producer: text(b"Producer"),
+        creation_date: date(b"CreationDate"),
+        modification_date: date(b"ModDate"),
+        custom: if custom.is_empty() { None } else { Some(custom) },
+    }
+}
+
+/// Turn a PDF date like "D:20240501103000+02'00'" into "2024-05-01T10:30:00+02:00"
+pub fn pdf_date_to_iso(date: &str) -> Option<String> {
+    let digits = date.trim().strip_prefix("D:").unwrap_or(date.trim());
+    let numeric_len = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+    if numeric_len < 4 {
+        return None;
+    }
+    let field = |start: usize, default: &str| digits.get(start..start + 2).filter(|_| numeric_len >= start + 2).unwrap_or(default).to_string();
+    let mut iso = format!(
+        "{}-{}-{}T{}:{}:{}",
+        &digits[..4],
+        field(4, "01"),
+        field(6, "01"),
+        field(8, "00"),
+        field(10, "00"),
+        field(12, "00")
+    );
+    let zone = &digits[numeric_len..];
+    if zone.starts_with('Z') {
+        iso.push('Z');
+    } else if zone.starts_with('+') || zone.starts_with('-') {
+        let parts: Vec<&str> = zone[1..].split('\'').filter(|p| !p.is_empty()).collect();
+        let hours = parts.first().copied().unwrap_or("00");
+        let minutes = parts.get(1).copied().unwrap_or("00");
+        iso.push_str(&format!("{}{}:{}", &zone[..1], hours, minutes));
+    }
+    Some(iso)
+}
+
+/// Turn an ISO 8601 date (or one already in PDF form) into a PDF date string
+pub fn iso_to_pdf_date(date: &str) -> Option<String> {
+    let date = date.trim();
+    if date.starts_with("D:") {
+        return Some(date.to_string());
+    }
+    let (day, time) = date.split_once(['T', ' ']).unwrap_or((date, ""));
+    let day: String = day.chars().filter(char::is_ascii_digit).collect();
+    if day.len() != 8 {
+        return None;
+    }
+    let zone_at = time.find(['Z', '+', '-']).unwrap_or(time.len());
+    let clock: String = time[..zone_at].split('.').next().unwrap_or("").chars().filter(char::is_ascii_digit).collect();
+    let mut pdf = format!("D:{}{}", day, clock);
+    let zone = &time[zone_at..];
+    if zone == "Z" {
+        pdf.push('Z');
+    } else if !zone.is_empty() {
+        let offset: String = zone[1..].chars().filter(char::is_ascii_digit).collect();
+        if offset.len() == 4 {
+            pdf.push_str(&format!("{}{}'{}'", &zone[..1], &offset[..2], &offset[2..]));
+        }
+    }
+    Some(pdf)
+}
+
+/// The current time as a PDF date, in UTC
+pub fn pdf_date_now() -> String {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let days = seconds.div_euclid(86_400);
+    let time = seconds.rem_euclid(86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "D:{:04}{:02}{:02}{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Whether a content stream, or a form it draws, shows any text
+fn shows_text(doc: &lopdf::Document, content: &[u8], resources: &lopdf::Dictionary, depth: usize) -> bool {
+    let content = crate::converters::pdf_renderer::strip_inline_images(content);
+    let operations = match lopdf::content::Content::decode(&content) {
+        Ok(c) => c.operations,
+        Err(_) => return false,
+    };
+    let xobjects = resources.get_deref(b"XObject", doc).and_then(lopdf::Object::as_dict).ok();
+    operations.iter().any(|op| match op.operator.as_str() {
+        "Tj" | "TJ" | "'" | "\"" => true,
+        "Do" if depth < 8 => op
+            .operands
+            .first()
+            .and_then(|n| n.as_name().ok())
+            .and_then(|name| xobjects.and_then(|x| x.get_deref(name, doc).ok()))
+            .and_then(|o| o.as_stream().ok())
+            .filter(|s| s.dict.get(b"Subtype").and_then(lopdf::Object::as_name).ok() == Some(b"Form"))
+            .map(|form| {
+                let form_content = form.decompressed_content().unwrap_or_else(|_| form.content.clone());
+                let form_resources = form
+                    .dict
+                    .get_deref(b"Resources", doc)
+                    .and_then(lopdf::Object::as_dict)
+                    .unwrap_or(resources);
+                shows_text(doc, &form_content, form_resources, depth + 1)
+            })
+            .unwrap_or(false),
+        _ => false,
+    })
+}
+
+fn describe_font(doc: &lopdf::Document, font: &lopdf::Dictionary) -> PdfFontInfo {
+    let name_of = |dict: &lopdf::Dictionary, key: &[u8]| {
+        dict.get(key)
+            .and_then(lopdf::Object::as_name)
+            .map(|n| String::from_utf8_lossy(n).to_string())
+            .unwrap_or_default()
+    };
+    let font_type = name_of(font, b"Subtype");
+    let name = match name_of(font, b"BaseFont") {
+        n if n.is_empty() => "(unnamed)".to_string(),
+        n => n,
+    };
+
+    // Composite fonts keep their descriptor on the descendant font
+    let descriptor_owner = if font_type == "Type0" {
+        font.get_deref(b"DescendantFonts", doc)
+            .and_then(lopdf::Object::as_array)
+            .ok()
+            .and_then(|fonts| fonts.first())
+            .and_then(|f| doc.dereference(f).ok())
+            .and_then(|(_, f)| f.as_dict().ok())
+    } else {
+        Some(font)
+    };
+    let embedded = font_type == "Type3"
+        || descriptor_owner
+            .and_then(|f| f.get_deref(b"FontDescriptor", doc).and_then(lopdf::Object::as_dict).ok())
+            .map(|d| [&b"FontFile"[..], b"FontFile2", b"FontFile3"].iter().any(|k| d.has(k)))
+            .unwrap_or(false);
+    let subset = name.len() > 7 && name.as_bytes()[6] == b'+' && name[..6].chars().all(|c| c.is_ascii_uppercase());
+
+    PdfFontInfo {
+        name,
+        font_type,
+        embedded,
+        subset,
+    }
+}
+
+/// Rasterization settings for PDF to image conversion
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PdfRenderOptions {
+    /// Output resolution, defaults to 150 DPI
+    pub dpi: Option<f32>,
+    /// Pages to render, e.g. "1-3,5,8-". Defaults to every page
+    pub pages: Option<String>,
+    /// "auto" (default) renders in-process and falls back to pdftoppm if that
+    /// fails or meets content it can't draw, "builtin" never runs pdftoppm,
+    /// "pdftoppm" always does. pdftoppm takes the password on its command
+    /// line, where other local users can read it, so "auto" doesn't fall
+    /// back when a password is set.
+    pub renderer: Option<String>,
+    /// Bundle all page images into `{name}.zip` instead of leaving loose files
+    pub zip: Option<bool>,
+    /// Opens password-protected PDFs
+    pub password: Option<String>,
+}
+
+/// Parse a page selection like "1-3,5,8-" into sorted, de-duplicated
+/// 1-based page numbers. Open ranges run to the last page.
+pub fn parse_page_ranges(spec: &str, page_count: u32) -> Result<Vec<u32>, String> {
+    let mut pages: Vec<u32> = parse_page_spans(spec, page_count)?
+        .into_iter()
+        .flat_map(|(first, last)| first..=last)
+        .collect();
+    pages.sort_unstable();
+    pages.dedup();
+    Ok(pages)
 }
 
-/// Convert PDF to PNG images using pdftoppm (poppler-utils)
-/// This creates one PNG per page, with the output path used as a base name
-pub fn convert_pdf_to_image(input_path: &str, output_path: &str, format: &str) -> ConversionResult {
+/// Parse a page selection into its `(first, last)` spans, in the order given
+pub fn parse_page_spans(spec: &str, page_count: u32) -> Result<Vec<(u32, u32)>, String> {
+    let mut spans = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let parse = |s: &str, default: u32| -> Result<u32, String> {
+            let s = s.trim();
+            if s.is_empty() {
+                return Ok(default);
+            }
+            s.parse::<u32>().map_err(|_| format!("Invalid page number '{}'", s))
+        };
+        let (first, last) = match part.split_once('-') {
+            Some((a, b)) => (parse(a, 1)?, parse(b, page_count)?),
+            None => {
+                let n = parse(part, 0)?;
+                (n, n)
+            }
+        };
+        if first == 0 || first > last || last > page_count {
+            return Err(format!("Page range '{}' is outside 1-{}", part, page_count));
+        }
+        spans.push((first, last));
+    }
+    if spans.is_empty() {
+        return Err("No pages selected".to_string());
+    }
+    Ok(spans)
+}
+
+/// Convert PDF pages to PNG or JPEG images.
+/// This creates one image per page, with the output path used as a base name
+pub fn convert_pdf_to_image(input_path: &str, output_path: &str, format: &str, options: &PdfRenderOptions, watermark: Option<&WatermarkOptions>) -> ConversionResult {
     let output_base = Path::new(output_path);
     let output_dir = output_base.parent().unwrap_or(Path::new("."));
     let stem = output_base.file_stem()
         .and_then(|s| s.to_str())
         .unwrap_or("output");
-    
-    // Build the output prefix (pdftoppm adds page numbers automatically)
     let output_prefix = output_dir.join(stem);
-    
-    // Try using pdftoppm (from poppler-utils) which is commonly available on Linux
-    let format_flag = match format {
-        "png" => "-png",
-        "jpg" | "jpeg" => "-jpeg",
-        _ => "-png",
+    let dpi = options.dpi.unwrap_or(150.0).clamp(10.0, 1200.0);
+
+    let doc = match load_pdf_with_password(input_path, options.password.as_deref()) {
+        Ok(d) => d,
+        Err(e) => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(e),
+                data: None,
+            };
+        }
     };
-    
-    let result = Command::new("pdftoppm")
-        .args([
-            format_flag,
-            "-r", "150", // 150 DPI resolution
-            input_path,
-            output_prefix.to_str().unwrap_or("output"),
-        ])
-        .output();
-    
-    match result {
-        Ok(output) => {
-            if output.status.success() {
-                // pdftoppm creates files like output-1.png, output-2.png, etc.
-                // Find the generated files
-                let generated_files: Vec<String> = std::fs::read_dir(output_dir)
-                    .map(|entries| {
-                        entries
-                            .filter_map(|e| e.ok())
-                            .filter(|e| {
-                                e.file_name()
-                                    .to_str()
-                                    .map(|name| name.starts_with(&format!("{}-", stem)) && name.ends_with(&format!(".{}", format)))
-                                    .unwrap_or(false)
-                            })
-                            .map(|e| e.path().to_string_lossy().to_string())
-                            .collect()
-                    })
-                    .unwrap_or_default();
-                
-                if generated_files.is_empty() {
-                    // Maybe it's a single page PDF, check for direct output
-                    let single_page = format!("{}-1.{}", output_prefix.to_str().unwrap_or(""), format);
-                    if Path::new(&single_page).exists() {
-                        // Rename to expected output path for single page
-                        if let Err(e) = std::fs::rename(&single_page, output_path) {
-                            return ConversionResult {
-                                success: false,
-                                output_path: None,
-                                error: Some(format!("Failed to rename output file: {}", e)),
-                                data: None,
-                            };
-                        }
-                        return ConversionResult {
-                            success: true,
-                            output_path: Some(output_path.to_string()),
-                            error: None,
-                            data: Some("Converted 1 page".to_string()),
-                        };
-                    }
-                    
-                    ConversionResult {
-                        success: false,
-                        output_path: None,
-                        error: Some("No output files were generated".to_string()),
-                        data: None,
-                    }
-                } else {
-                    // For multi-page PDFs, return the first page path
-                    // and mention how many pages were converted
-                    let first_file = generated_files.first().cloned();
-                    ConversionResult {
-                        success: true,
-                        output_path: first_file,
-                        error: None,
-                        data: Some(format!("Converted {} page(s) to {}", generated_files.len(), format.to_uppercase())),
-                    }
-                }
-            } else {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                ConversionResult {
-                    success: false,
-                    output_path: None,
-                    error: Some(format!("pdftoppm failed: {}", stderr)),
-                    data: None,
+    let page_ids = doc.get_pages();
+    let pages = match parse_page_ranges(options.pages.as_deref().unwrap_or("1-"), page_ids.len() as u32) {
+        Ok(p) => p,
+        Err(e) => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(e),
+                data: None,
+            };
+        }
+    };
+
+
+    // Pages are named `{stem}-{page}`, zero-padded to the document's page
+    // count so they sort in page order
+    let width = page_ids.len().to_string().len();
+    let page_files: Vec<(u32, String)> = pages
+        .iter()
+        .map(|page| (*page, format!("{}-{:0width$}.{}", output_prefix.to_string_lossy(), page, format, width = width)))
+        .collect();
+
+    let rendered = match options.renderer.as_deref().unwrap_or("auto") {
+        "pdftoppm" => pdftoppm_pages(input_path, options.password.as_deref(), format, dpi, &page_files, watermark),
+        renderer => {
+            let builtin = render_with_builtin(&doc, &page_ids, dpi, &page_files, watermark);
+            match builtin {
+                Err(e) if renderer == "auto" && options.password.is_some() => Err(format!(
+                    "{} (not falling back to pdftoppm, which would expose the password in the process list; \
+                     choose the pdftoppm renderer to allow it)",
+                    e
+                )),
+                Err(e) if renderer == "auto" => {
+                    pdftoppm_pages(input_path, options.password.as_deref(), format, dpi, &page_files, watermark)
+                        .map_err(|fallback| format!("{} (pdftoppm fallback: {})", e, fallback))
                 }
+                other => other,
             }
         }
-        Err(e) => {
-            // pdftoppm not found, suggest installation
-            if e.kind() == std::io::ErrorKind::NotFound {
-                ConversionResult {
+    };
+
+    if let Err(e) = rendered {
+        return ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some(e),
+            data: None,
+        };
+    }
+
+    let zip_path = if options.zip.unwrap_or(false) {
+        let zip_path = format!("{}.zip", output_prefix.to_string_lossy());
+        if let Err(e) = zip_page_files(&page_files, &zip_path) {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Failed to create ZIP: {}", e)),
+                data: None,
+            };
+        }
+        Some(zip_path)
+    } else {
+        None
+    };
+
+    let report = serde_json::json!({
+        "page_count": page_files.len(),
+        "document_pages": page_ids.len(),
+        "format": format,
+        "dpi": dpi,
+        "zip": zip_path,
+        "pages": page_files
+            .iter()
+            .map(|(page, file)| match &zip_path {
+                Some(_) => serde_json::json!({ "page": page, "file": Path::new(file).file_name().map(|n| n.to_string_lossy().to_string()) }),
+                None => serde_json::json!({ "page": page, "file": file }),
+            })
+            .collect::<Vec<_>>(),
+    });
+
+    // The first page stands in for the whole set unless they were zipped
+    ConversionResult {
+        success: true,
+        output_path: zip_path.or_else(|| page_files.first().map(|(_, file)| file.clone())),
+        error: None,
+        data: Some(serde_json::to_string_pretty(&report).unwrap()),
+    }
+}
+
+/// Render pages in-process into the given files
+fn render_with_builtin(
+    doc: &lopdf::Document,
+    page_ids: &std::collections::BTreeMap<u32, lopdf::ObjectId>,
+    dpi: f32,
+    page_files: &[(u32, String)],
+    watermark: Option<&WatermarkOptions>,
+) -> Result<(), String> {
+    for (page, file) in page_files {
+        let page_id = page_ids.get(page).ok_or_else(|| format!("Page {} not found", page))?;
+        let pixmap = render_pdf_page(doc, *page_id, dpi)
+            .map_err(|e| format!("Failed to render page {}: {}", page, e))?;
+        let img = image::DynamicImage::ImageRgba8(pixmap_to_rgba(&pixmap));
+        save_page_image(img, *page, file, watermark)?;
+    }
+    Ok(())
+}
+
+/// Watermark a rendered page, if asked to, and encode it once
+fn save_page_image(
+    mut img: image::DynamicImage,
+    page: u32,
+    file: &str,
+    watermark: Option<&WatermarkOptions>,
+) -> Result<(), String> {
+    if let Some(wm) = watermark {
+        apply_watermark(&mut img, wm).map_err(|e| format!("Failed to apply watermark: {}", e))?;
+    }
+    let saved = if file.ends_with(".jpg") || file.ends_with(".jpeg") {
+        img.to_rgb8().save(file)
+    } else {
+        img.to_rgba8().save(file)
+    };
+    saved.map_err(|e| format!("Failed to save page {}: {}", page, e))
+}
+
+/// Render pages with pdftoppm. A watermarked page is rendered to a
+/// lossless PNG first so it is only JPEG-compressed once, with the
+/// watermark on it.
+fn pdftoppm_pages(
+    input_path: &str,
+    password: Option<&str>,
+    format: &str,
+    dpi: f32,
+    page_files: &[(u32, String)],
+    watermark: Option<&WatermarkOptions>,
+) -> Result<(), String> {
+    if watermark.is_none() {
+        return render_with_pdftoppm(input_path, password, format, dpi, page_files);
+    }
+    let png_files: Vec<(u32, String)> = page_files
+        .iter()
+        .map(|(page, file)| (*page, Path::new(file).with_extension("png").to_string_lossy().to_string()))
+        .collect();
+    render_with_pdftoppm(input_path, password, "png", dpi, &png_files)?;
+    for ((page, file), (_, png)) in page_files.iter().zip(&png_files) {
+        let img = image::open(png).map_err(|e| format!("Failed to read page {}: {}", page, e))?;
+        save_page_image(img, *page, file, watermark)?;
+        if png != file {
+            let _ = fs::remove_file(png);
+        }
+    }
+    Ok(())
+}
+
+/// Render pages with poppler's pdftoppm, when it is installed. A password
+/// is passed as a command-line argument, so it shows in the process list.
+fn render_with_pdftoppm(input_path: &str, password: Option<&str>, format: &str, dpi: f32, page_files: &[(u32, String)]) -> Result<(), String> {
+    let (format_flag, extension) = match format {
+        "jpg" | "jpeg" => ("-jpeg", "jpg"),
+        _ => ("-png", "png"),
+    };
+    let dpi = format!("{}", dpi.round());
+
+    for (page, file) in page_files {
+        // -singlefile writes exactly `{prefix}.{extension}` with no page suffix
+        let prefix = Path::new(file).with_extension("");
+        let page = page.to_string();
+        let mut command = Command::new("pdftoppm");
+        if let Some(password) = password {
+            // We don't know which of the two passwords we were given
+            command.args(["-opw", password, "-upw", password]);
+        }
+        let result = command
+            .args([
+                format_flag,
+                "-singlefile",
+                "-r", &dpi,
+                "-f", &page,
+                "-l", &page,
+                input_path,
+                prefix.to_str().unwrap_or("output"),
+            ])
+            .output();
+
+        match result {
+            Ok(output) if !output.status.success() => {
+                return Err(format!("pdftoppm failed: {}", String::from_utf8_lossy(&output.stderr)));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err("pdftoppm was not found. Install poppler and make sure pdftoppm is on your PATH".to_string());
+            }
+            Err(e) => return Err(format!("Failed to run pdftoppm: {}", e)),
+        }
+
+        let written = prefix.with_extension(extension);
+        if written != Path::new(file) {
+            fs::rename(&written, file).map_err(|e| format!("Failed to rename page {}: {}", page, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Move the page images into a ZIP archive, in page order
+fn zip_page_files(page_files: &[(u32, String)], zip_path: &str) -> Result<(), String> {
+    let file = fs::File::create(zip_path).map_err(|e| e.to_string())?;
+    let mut zip = zip::ZipWriter::new(file);
+    // Images are already compressed
+    let entry_options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored);
+
+    for (_, page_file) in page_files {
+        let name = Path::new(page_file)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let bytes = fs::read(page_file).map_err(|e| e.to_string())?;
+        zip.start_file(name, entry_options).map_err(|e| e.to_string())?;
+        std::io::Write::write_all(&mut zip, &bytes).map_err(|e| e.to_string())?;
+    }
+    zip.finish().map_err(|e| e.to_string())?;
+
+    for (_, page_file) in page_files {
+        let _ = fs::remove_file(page_file);
+    }
+    Ok(())
+}
+
+/// Page layout for combining images into a PDF
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ImagesToPdfOptions {
+    /// "fit" (default) sizes each page to its image, or "a3", "a4", "a5", "letter", "legal"
+    pub page_size: Option<String>,
+    /// Blank border around each image, defaults to 0 for "fit" and 10 mm for paper sizes
+    pub margin_mm: Option<f32>,
+    /// Re-encode every page as JPEG at this quality. Without it JPEG inputs are
+    /// embedded untouched and everything else losslessly
+    pub jpeg_quality: Option<u8>,
+    /// Pixel density used to size "fit" pages, defaults to 150
+    pub dpi: Option<f32>,
+}
+
+fn paper_size_mm(name: &str) -> Option<(f32, f32)> {
+    match name.to_lowercase().as_str() {
+        "a3" => Some((297.0, 420.0)),
+        "a4" => Some((210.0, 297.0)),
+        "a5" => Some((148.0, 210.0)),
+        "letter" => Some((215.9, 279.4)),
+        "legal" => Some((215.9, 355.6)),
+        _ => None,
+    }
+}
+
+/// Number of color components in a JPEG's frame header, or None if the
+/// data has no start-of-frame marker
+pub(crate) fn jpeg_component_count(bytes: &[u8]) -> Option<u8> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    while pos + 4 <= bytes.len() {
+        if bytes[pos] != 0xFF {
+            return None;
+        }
+        let marker = bytes[pos + 1];
+        // Fill bytes before a marker
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        // SOF0-SOF15, except DHT (C4), JPG (C8) and DAC (CC)
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            return bytes.get(pos + 9).copied();
+        }
+        if marker == 0xDA {
+            return None;
+        }
+        pos += 2 + u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+    }
+    None
+}
+
+/// Combine images into a PDF with one page per image
+pub fn images_to_pdf(input_paths: &[String], output_path: &str, options: &ImagesToPdfOptions) -> ConversionResult {
+    let paper = options.page_size.as_deref().and_then(paper_size_mm);
+    let margin = options.margin_mm.unwrap_or(if paper.is_some() { 10.0 } else { 0.0 }).max(0.0);
+    let fit_dpi = options.dpi.unwrap_or(150.0).max(1.0);
+
+    let mut doc: Option<printpdf::PdfDocumentReference> = None;
+    for input in input_paths {
+        let img = match image::open(input) {
+            Ok(img) => img,
+            Err(e) => {
+                return ConversionResult {
                     success: false,
                     output_path: None,
-                    error: Some("PDF to image conversion requires 'poppler-utils'. Install with: sudo apt install poppler-utils".to_string()),
+                    error: Some(format!("Failed to open image {}: {}", input, e)),
                     data: None,
-                }
-            } else {
-                ConversionResult {
+                };
+            }
+        };
+        let (px_width, px_height) = img.dimensions();
+
+        // JPEG sources can go in as-is unless recompression was asked for
+        let is_jpeg = matches!(
+            Path::new(input).extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref(),
+            Some("jpg") | Some("jpeg")
+        );
+        // The decoder also turns CMYK and YCCK JPEGs into Rgb8, so the frame
+        // header has to confirm three components before the DCT data can be
+        // labelled RGB
+        let jpeg_bytes = if is_jpeg && options.jpeg_quality.is_none() && img.color() == image::ColorType::Rgb8 {
+            fs::read(input).ok().filter(|bytes| jpeg_component_count(bytes) == Some(3))
+        } else {
+            None
+        };
+        let xobject = if let Some(bytes) = jpeg_bytes {
+            Ok(ImageXObject {
+                width: Px(px_width as usize),
+                height: Px(px_height as usize),
+                color_space: ColorSpace::Rgb,
+                bits_per_component: ColorBits::Bit8,
+                interpolate: true,
+                image_data: bytes,
+                image_filter: Some(ImageFilter::DCT),
+                smask: None,
+                clipping_bbox: None,
+            })
+        } else {
+            image_to_pdf_xobject(&img, options.jpeg_quality)
+        };
+        let xobject = match xobject {
+            Ok(x) => x,
+            Err(e) => {
+                return ConversionResult {
                     success: false,
                     output_path: None,
-                    error: Some(format!("Failed to run pdftoppm: {}", e)),
+                    error: Some(format!("Failed to embed image {}: {}", input, e)),
                     data: None,
-                }
+                };
+            }
+        };
+
+        let (page_width, page_height, dpi) = match paper {
+            Some((w, h)) => {
+                // Match the paper orientation to the image
+                let (w, h) = if (px_width > px_height) != (w > h) { (h, w) } else { (w, h) };
+                let box_width = (w - 2.0 * margin).max(1.0);
+                let box_height = (h - 2.0 * margin).max(1.0);
+                let dpi = (px_width as f32 / (box_width / 25.4)).max(px_height as f32 / (box_height / 25.4));
+                (w, h, dpi)
+            }
+            None => (
+                px_width as f32 / fit_dpi * 25.4 + 2.0 * margin,
+                px_height as f32 / fit_dpi * 25.4 + 2.0 * margin,
+                fit_dpi,
+            ),
+        };
+        let image_width = px_width as f32 / dpi * 25.4;
+        let image_height = px_height as f32 / dpi * 25.4;
+
+        let layer = match &doc {
+            None => {
+                let (new_doc, page, layer) = PdfDocument::new("Images", Mm(page_width), Mm(page_height), "Layer 1");
+                let layer_ref = new_doc.get_page(page).get_layer(layer);
+                doc = Some(new_doc);
+                layer_ref
             }
+            Some(existing) => {
+                let (page, layer) = existing.add_page(Mm(page_width), Mm(page_height), "Layer 1");
+                existing.get_page(page).get_layer(layer)
+            }
+        };
+
+        Image::from(xobject).add_to_layer(
+            layer,
+            ImageTransform {
+                translate_x: Some(Mm((page_width - image_width) / 2.0)),
+                translate_y: Some(Mm((page_height - image_height) / 2.0)),
+                dpi: Some(dpi),
+                ..Default::default()
+            },
+        );
+    }
+
+    let doc = match doc {
+        Some(d) => d,
+        None => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some("No images were given".to_string()),
+                data: None,
+            };
+        }
+    };
+
+    let file = match fs::File::create(output_path) {
+        Ok(f) => f,
+        Err(e) => {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Failed to create output file: {}", e)),
+                data: None,
+            };
         }
+    };
+
+    match doc.save(&mut std::io::BufWriter::new(file)) {
+        Ok(_) => ConversionResult {
+            success: true,
+            output_path: Some(output_path.to_string()),
+            error: None,
+            data: Some(format!("Combined {} image(s) into PDF", input_paths.len())),
+        },
+        Err(e) => ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some(format!("Failed to save PDF: {}", e)),
+            data: None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_spans_keep_order_and_open_ends() {
+        assert_eq!(parse_page_spans("3-4, 1 ,8-", 10).unwrap(), vec![(3, 4), (1, 1), (8, 10)]);
+        assert_eq!(parse_page_spans("-2", 10).unwrap(), vec![(1, 2)]);
+        assert_eq!(parse_page_spans("1-", 1).unwrap(), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn page_spans_reject_bad_input() {
+        for spec in ["", " , ", "0", "11", "4-2", "2-11", "a", "1-b", "1--3"] {
+            assert!(parse_page_spans(spec, 10).is_err(), "accepted '{}'", spec);
+        }
+    }
+
+    #[test]
+    fn page_ranges_are_sorted_and_unique() {
+        assert_eq!(parse_page_ranges("5-6,1-2,2,6-", 7).unwrap(), vec![1, 2, 5, 6, 7]);
+        assert!(parse_page_ranges("8", 7).is_err());
     }
 }
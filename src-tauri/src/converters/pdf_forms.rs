@@ -0,0 +1,1082 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, Stream};
+use serde_json::{json, Map, Value};
+use crate::ConversionResult;
+use crate::converters::pdf_security::load_pdf_with_password;
+use crate::converters::text_tools::unique_file_name;
+use crate::converters::pdf_tools::{finish, flatten_inherited_attributes, load_pdf, save_pdf};
+
+// Field flags (Ff), as bit masks
+const READ_ONLY: i64 = 1;
+const REQUIRED: i64 = 1 << 1;
+const MULTILINE: i64 = 1 << 12;
+const PASSWORD: i64 = 1 << 13;
+const RADIO: i64 = 1 << 15;
+const PUSHBUTTON: i64 = 1 << 16;
+const COMBO: i64 = 1 << 17;
+const MULTI_SELECT: i64 = 1 << 21;
+const COMB: i64 = 1 << 24;
+
+/// Separator for several values in one cell, e.g. a multi-select list
+const VALUE_SEPARATOR: &str = "; ";
+
+/// Helvetica advance widths for ASCII 32-126, in thousandths of an em
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, 1015, 667, 667, 722, 722, 667, 611, 778,
+    722, 278, 500, 667, 556, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278,
+    278, 278, 469, 556, 333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556,
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+/// One AcroForm field
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PdfFormField {
+    /// Fully qualified name, e.g. "applicant.address.city"
+    pub name: String,
+    /// "text", "checkbox", "radio", "combo", "list", "button" or "signature"
+    pub field_type: String,
+    /// Checkboxes and radio groups report their export value, or "Off".
+    /// Multi-select lists join their values with "; "
+    pub value: Option<String>,
+    /// Choices of lists and combo boxes, export values of checkboxes and radio buttons
+    pub options: Vec<String>,
+    pub required: bool,
+    pub read_only: bool,
+    pub multiline: bool,
+    pub max_length: Option<i64>,
+    pub tooltip: Option<String>,
+    /// First page the field appears on
+    pub page: Option<u32>,
+}
+
+/// Values to fill into a PDF form
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PdfFormFillOptions {
+    /// Field values by fully qualified name. Checkboxes take true/false or an
+    /// export value, multi-select lists an array or "a; b"
+    pub values: Option<Map<String, Value>>,
+    /// A JSON object or a CSV file with field names as headers. Values given
+    /// directly take precedence
+    pub data_path: Option<String>,
+    /// CSV row to use, counting from 1 after the header. Defaults to 1
+    pub row: Option<usize>,
+    /// Draw the values into the page content and remove the form
+    pub flatten: Option<bool>,
+    /// Batch fill: name each output after this column instead of the row number
+    pub file_name_column: Option<String>,
+}
+
+/// A terminal field and the widget annotations that show it
+struct FieldNode {
+    id: ObjectId,
+    name: String,
+    widgets: Vec<ObjectId>,
+}
+
+/// Form-wide defaults from the AcroForm dictionary
+struct FormDefaults {
+    appearance: String,
+    quadding: i64,
+    fonts: Dictionary,
+}
+
+/// Parsed default appearance (DA) of a field
+struct TextStyle {
+    font: String,
+    size: f32,
+    color: String,
+    quadding: i64,
+}
+
+/// Read the fields of a PDF form
+pub fn read_pdf_form_fields(input_path: &str, password: Option<&str>) -> Result<Vec<PdfFormField>, String> {
+    let doc = load_pdf_with_password(input_path, password)?;
+    let pages = widget_pages(&doc);
+
+    let fields = collect_fields(&doc)
+        .iter()
+        .map(|field| {
+            let flags = field_flags(&doc, field.id);
+            let field_type = field_type(&doc, field.id);
+            let options = match field_type {
+                "checkbox" | "radio" => button_exports(&doc, field),
+                _ => choice_options(&doc, field.id).into_iter().map(|(export, _)| export).collect(),
+            };
+            PdfFormField {
+                name: field.name.clone(),
+                field_type: field_type.to_string(),
+                value: field_value(&doc, field),
+                options,
+                required: flags & REQUIRED != 0,
+                read_only: flags & READ_ONLY != 0,
+                multiline: field_type == "text" && flags & MULTILINE != 0,
+                max_length: inherited(&doc, field.id, b"MaxLen").and_then(|m| m.as_i64().ok()),
+                tooltip: doc.get_dictionary(field.id).ok()
+                    .and_then(|d| d.get(b"TU").ok())
+                    .and_then(|t| lopdf::decode_text_string(t).ok()),
+                page: field.widgets.iter().filter_map(|w| pages.get(w).copied()).min(),
+            }
+        })
+        .collect();
+    Ok(fields)
+}
+
+/// Export form fields to JSON, or to CSV when the output ends in .csv
+pub fn extract_pdf_form(input_path: &str, output_path: &str, password: Option<&str>) -> ConversionResult {
+    finish(try_extract_pdf_form(input_path, output_path, password), output_path)
+}
+
+fn try_extract_pdf_form(input_path: &str, output_path: &str, password: Option<&str>) -> Result<String, String> {
+    let fields = read_pdf_form_fields(input_path, password)?;
+
+    if output_path.to_lowercase().ends_with(".csv") {
+        let mut writer = csv::Writer::from_path(output_path).map_err(|e| format!("Failed to write CSV: {}", e))?;
+        let header = ["name", "type", "value", "options", "required", "read_only", "multiline", "max_length", "tooltip", "page"];
+        writer.write_record(header).map_err(|e| e.to_string())?;
+        for field in &fields {
+            writer
+                .write_record([
+                    field.name.clone(),
+                    field.field_type.clone(),
+                    field.value.clone().unwrap_or_default(),
+                    field.options.join(VALUE_SEPARATOR),
+                    field.required.to_string(),
+                    field.read_only.to_string(),
+                    field.multiline.to_string(),
+                    field.max_length.map(|m| m.to_string()).unwrap_or_default(),
+                    field.tooltip.clone().unwrap_or_default(),
+                    field.page.map(|p| p.to_string()).unwrap_or_default(),
+                ])
+                .map_err(|e| e.to_string())?;
+        }
+        writer.flush().map_err(|e| format!("Failed to write CSV: {}", e))?;
+    } else {
+        let json = serde_json::to_string_pretty(&fields).map_err(|e| e.to_string())?;
+        fs::write(output_path, json).map_err(|e| format!("Failed to write output: {}", e))?;
+    }
+    Ok(format!("Exported {} form field(s)", fields.len()))
+}
+
+/// Fill a PDF form from values, a JSON file or a CSV row, optionally flattening it
+pub fn fill_pdf_form(input_path: &str, output_path: &str, options: &PdfFormFillOptions) -> ConversionResult {
+    finish(try_fill_pdf_form(input_path, output_path, options), output_path)
+}
+
+fn try_fill_pdf_form(input_path: &str, output_path: &str, options: &PdfFormFillOptions) -> Result<String, String> {
+    let mut values = match &options.data_path {
+        Some(path) => {
+            let row = options.row.unwrap_or(1).max(1);
+            let rows = read_form_data(path)?;
+            let count = rows.len();
+            rows.into_iter()
+                .nth(row - 1)
+                .ok_or_else(|| format!("Row {} is past the end of the data ({} row(s))", row, count))?
+        }
+        None => Map::new(),
+    };
+    if let Some(direct) = &options.values {
+        values.extend(direct.clone());
+    }
+
+    let mut doc = load_pdf(input_path)?;
+    let report = fill_document(&mut doc, &values, options.flatten.unwrap_or(false))?;
+    save_pdf(&mut doc, output_path)?;
+    Ok(serde_json::to_string_pretty(&report).unwrap())
+}
+
+/// Fill one copy of a form per CSV (or JSON array) row into `output_dir`
+pub fn batch_fill_pdf_form(input_path: &str, data_path: &str, output_dir: &str, options: &PdfFormFillOptions) -> ConversionResult {
+    finish(try_batch_fill_pdf_form(input_path, data_path, output_dir, options), output_dir)
+}
+
+fn try_batch_fill_pdf_form(input_path: &str, data_path: &str, output_dir: &str, options: &PdfFormFillOptions) -> Result<String, String> {
+    let rows = read_form_data(data_path)?;
+    if rows.is_empty() {
+        return Err("The data file has no rows".to_string());
+    }
+    let template = load_pdf(input_path)?;
+
+    fs::create_dir_all(output_dir).map_err(|e| format!("Failed to create output directory: {}", e))?;
+    let stem = Path::new(input_path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("form");
+    let width = rows.len().to_string().len();
+
+    let mut used_names = Vec::new();
+    let mut files = Vec::new();
+    for (index, row) in rows.iter().enumerate() {
+        let number = index + 1;
+        let numbered = format!("{}-{:0width$}", stem, number, width = width);
+        let name = options
+            .file_name_column
+            .as_ref()
+            .and_then(|column| row.get(column))
+            .map(|value| sanitize_file_stem(&value_text(value)))
+            .filter(|name| !name.is_empty())
+            .unwrap_or(numbered);
+        let file_name = unique_file_name(&format!("{}.pdf", name), &mut used_names);
+        let file_path = Path::new(output_dir).join(file_name).to_string_lossy().to_string();
+
+        let mut values = row.clone();
+        if let Some(direct) = &options.values {
+            values.extend(direct.clone());
+        }
+        let mut doc = template.clone();
+        let report = fill_document(&mut doc, &values, options.flatten.unwrap_or(false))
+            .map_err(|e| format!("Row {}: {}", number, e))?;
+        save_pdf(&mut doc, &file_path)?;
+        files.push(json!({ "file": file_path, "row": number, "filled": report["filled"], "unknown_fields": report["unknown_fields"] }));
+    }
+
+    let report = json!({ "file_count": files.len(), "files": files });
+    Ok(serde_json::to_string_pretty(&report).unwrap())
+}
+
+/// Rows of field values from a CSV file (one per line after the header) or
+/// a JSON file holding an object, an array of objects, or an exported field list
+fn read_form_data(path: &str) -> Result<Vec<Map<String, Value>>, String> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    match extension.as_str() {
+        "csv" => {
+            let mut reader = csv::ReaderBuilder::new()
+                .flexible(true)
+                .from_path(path)
+                .map_err(|e| format!("Failed to read CSV: {}", e))?;
+            let headers = reader.headers().map_err(|e| format!("Failed to read CSV: {}", e))?.clone();
+            let mut rows = Vec::new();
+            for record in reader.records() {
+                let record = record.map_err(|e| format!("Failed to read CSV: {}", e))?;
+                let row: Map<String, Value> = headers
+                    .iter()
+                    .zip(record.iter())
+                    .filter(|(header, _)| !header.trim().is_empty())
+                    .map(|(header, cell)| (header.trim().to_string(), Value::String(cell.to_string())))
+                    .collect();
+                rows.push(row);
+            }
+            Ok(rows)
+        }
+        "json" => {
+            let content = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            let data: Value = serde_json::from_str(&content).map_err(|e| format!("Invalid JSON: {}", e))?;
+            match data {
+                Value::Object(values) => Ok(vec![values]),
+                Value::Array(items) => {
+                    // An exported field list fills back in as one set of values
+                    let exported = items.iter().all(|i| i.get("name").is_some() && i.get("field_type").is_some());
+                    if exported && !items.is_empty() {
+                        let values = items
+                            .iter()
+                            .filter_map(|i| Some((i["name"].as_str()?.to_string(), i.get("value")?.clone())))
+                            .filter(|(_, value)| !value.is_null())
+                            .collect();
+                        return Ok(vec![values]);
+                    }
+                    items
+                        .into_iter()
+                        .map(|item| match item {
+                            Value::Object(values) => Ok(values),
+                            _ => Err("Each JSON row must be an object of field values".to_string()),
+                        })
+                        .collect()
+                }
+                _ => Err("Form data must be a JSON object of field values".to_string()),
+            }
+        }
+        _ => Err("Form data must be a JSON or CSV file".to_string()),
+    }
+}
+
+/// Set field values, generate their appearances and optionally flatten the
+/// form. Reports how many fields were filled and which names didn't match.
+fn fill_document(doc: &mut Document, values: &Map<String, Value>, flatten: bool) -> Result<Value, String> {
+    let fields = collect_fields(doc);
+    if fields.is_empty() {
+        return Err("This PDF has no fillable form fields".to_string());
+    }
+    let mut defaults = form_defaults(doc);
+
+    let mut filled = 0;
+    let mut unknown = Vec::new();
+    for (name, value) in values {
+        match fields.iter().find(|f| &f.name == name) {
+            Some(field) => {
+                set_field_value(doc, field, value, &mut defaults)?;
+                filled += 1;
+            }
+            None => unknown.push(name.clone()),
+        }
+    }
+
+    if flatten {
+        flatten_form(doc, &fields);
+    } else {
+        let mut resources = acro_form(doc)
+            .and_then(|f| f.get_deref(b"DR", doc).and_then(Object::as_dict).ok())
+            .cloned()
+            .unwrap_or_default();
+        resources.set("Font", defaults.fonts.clone());
+        if let Some(form) = acro_form_mut(doc) {
+            // Our appearances use approximate metrics; viewers may redraw them
+            form.set("NeedAppearances", true);
+            form.set("DR", resources);
+        }
+    }
+    Ok(json!({ "filled": filled, "unknown_fields": unknown, "flattened": flatten }))
+}
+
+fn acro_form(doc: &Document) -> Option<&Dictionary> {
+    doc.catalog().ok()?.get_deref(b"AcroForm", doc).and_then(Object::as_dict).ok()
+}
+
+fn acro_form_mut(doc: &mut Document) -> Option<&mut Dictionary> {
+    match doc.catalog().ok()?.get(b"AcroForm").ok()? {
+        Object::Reference(id) => {
+            let id = *id;
+            doc.get_dictionary_mut(id).ok()
+        }
+        _ => doc.catalog_mut().ok()?.get_mut(b"AcroForm").and_then(Object::as_dict_mut).ok(),
+    }
+}
+
+fn form_defaults(doc: &Document) -> FormDefaults {
+    let form = acro_form(doc);
+    let fonts = form
+        .and_then(|f| f.get_deref(b"DR", doc).and_then(Object::as_dict).ok())
+        .and_then(|dr| dr.get_deref(b"Font", doc).and_then(Object::as_dict).ok())
+        .cloned()
+        .unwrap_or_default();
+    FormDefaults {
+        appearance: form
+            .and_then(|f| f.get(b"DA").and_then(Object::as_str).ok())
+            .map(|da| String::from_utf8_lossy(da).to_string())
+            .unwrap_or_else(|| "/Helv 0 Tf 0 g".to_string()),
+        quadding: form.and_then(|f| f.get(b"Q").and_then(Object::as_i64).ok()).unwrap_or(0),
+        fonts,
+    }
+}
+
+/// Every terminal field of the form with its fully qualified name
+fn collect_fields(doc: &Document) -> Vec<FieldNode> {
+    let mut fields = Vec::new();
+    let roots = acro_form(doc)
+        .and_then(|f| f.get_deref(b"Fields", doc).and_then(Object::as_array).ok())
+        .cloned()
+        .unwrap_or_default();
+    for root in roots {
+        if let Ok(id) = root.as_reference() {
+            walk_field(doc, id, "", &mut fields, 0);
+        }
+    }
+    fields
+}
+
+fn walk_field(doc: &Document, id: ObjectId, parent_name: &str, fields: &mut Vec<FieldNode>, depth: usize) {
+    let dict = match doc.get_dictionary(id) {
+        Ok(d) if depth < 32 => d,
+        _ => return,
+    };
+    let partial = dict.get(b"T").ok().and_then(|t| lopdf::decode_text_string(t).ok());
+    let name = match partial {
+        Some(partial) if parent_name.is_empty() => partial,
+        Some(partial) => format!("{}.{}", parent_name, partial),
+        None => parent_name.to_string(),
+    };
+
+    // Kids without a partial name are widgets of this field, not fields
+    let kids: Vec<ObjectId> = dict
+        .get(b"Kids")
+        .and_then(Object::as_array)
+        .map(|kids| kids.iter().filter_map(|k| k.as_reference().ok()).collect())
+        .unwrap_or_default();
+    let (children, widgets): (Vec<ObjectId>, Vec<ObjectId>) = kids
+        .into_iter()
+        .partition(|kid| doc.get_dictionary(*kid).map(|k| k.has(b"T")).unwrap_or(false));
+
+    if !children.is_empty() {
+        for child in children {
+            walk_field(doc, child, &name, fields, depth + 1);
+        }
+        return;
+    }
+    let mut field_widgets = Vec::new();
+    if dict.get(b"Subtype").and_then(Object::as_name).ok() == Some(b"Widget") {
+        field_widgets.push(id);
+    }
+    field_widgets.extend(widgets);
+    fields.push(FieldNode { id, name, widgets: field_widgets });
+}
+
+/// A field attribute, looked up through the field's ancestors
+fn inherited<'a>(doc: &'a Document, id: ObjectId, key: &[u8]) -> Option<&'a Object> {
+    let mut node = doc.get_dictionary(id).ok();
+    for _ in 0..32 {
+        let dict = node?;
+        if let Ok(value) = dict.get_deref(key, doc) {
+            return Some(value);
+        }
+        node = dict.get_deref(b"Parent", doc).and_then(Object::as_dict).ok();
+    }
+    None
+}
+
+fn field_flags(doc: &Document, id: ObjectId) -> i64 {
+    inherited(doc, id, b"Ff").and_then(|f| f.as_i64().ok()).unwrap_or(0)
+}
+
+fn field_type(doc: &Document, id: ObjectId) -> &'static str {
+    let flags = field_flags(doc, id);
+    match inherited(doc, id, b"FT").and_then(|t| t.as_name().ok()).unwrap_or(b"") {
+        b"Tx" => "text",
+        b"Btn" if flags & PUSHBUTTON != 0 => "button",
+        b"Btn" if flags & RADIO != 0 => "radio",
+        b"Btn" => "checkbox",
+        b"Ch" if flags & COMBO != 0 => "combo",
+        b"Ch" => "list",
+        b"Sig" => "signature",
+        _ => "unknown",
+    }
+}
+
+/// (export value, display text) for each entry of a choice field's Opt
+fn choice_options(doc: &Document, id: ObjectId) -> Vec<(String, String)> {
+    let options = match inherited(doc, id, b"Opt").and_then(|o| o.as_array().ok()) {
+        Some(o) => o,
+        None => return Vec::new(),
+    };
+    options
+        .iter()
+        .filter_map(|option| {
+            let option = match option {
+                Object::Reference(r) => doc.get_object(*r).ok()?,
+                other => other,
+            };
+            match option {
+                Object::Array(pair) if pair.len() == 2 => Some((
+                    lopdf::decode_text_string(&pair[0]).ok()?,
+                    lopdf::decode_text_string(&pair[1]).ok()?,
+                )),
+                other => {
+                    let text = lopdf::decode_text_string(other).ok()?;
+                    Some((text.clone(), text))
+                }
+            }
+        })
+        .collect()
+}
+
+/// The "on" appearance state of a checkbox or radio button widget
+fn on_state(doc: &Document, widget: ObjectId) -> Option<String> {
+    let normal = doc
+        .get_dictionary(widget)
+        .ok()?
+        .get_deref(b"AP", doc)
+        .and_then(Object::as_dict)
+        .ok()?
+        .get_deref(b"N", doc)
+        .and_then(Object::as_dict)
+        .ok()?;
+    normal
+        .iter()
+        .map(|(state, _)| String::from_utf8_lossy(state).to_string())
+        .find(|state| state != "Off")
+}
+
+/// (appearance state, export value) per widget of a checkbox or radio group.
+/// Opt, when present, holds the export values in widget order.
+fn button_states(doc: &Document, field: &FieldNode) -> Vec<(String, String)> {
+    let exports: Vec<String> = choice_options(doc, field.id).into_iter().map(|(export, _)| export).collect();
+    field
+        .widgets
+        .iter()
+        .enumerate()
+        .map(|(index, widget)| {
+            let state = on_state(doc, *widget).unwrap_or_else(|| "Yes".to_string());
+            let export = exports.get(index).cloned().unwrap_or_else(|| state.clone());
+            (state, export)
+        })
+        .collect()
+}
+
+fn button_exports(doc: &Document, field: &FieldNode) -> Vec<String> {
+    let mut exports: Vec<String> = Vec::new();
+    for (_, export) in button_states(doc, field) {
+        if !exports.contains(&export) {
+            exports.push(export);
+        }
+    }
+    exports
+}
+
+fn field_value(doc: &Document, field: &FieldNode) -> Option<String> {
+    let value = inherited(doc, field.id, b"V")?;
+    match value {
+        Object::Name(name) => {
+            let state = String::from_utf8_lossy(name).to_string();
+            // Report the export value rather than the appearance state
+            Some(
+                button_states(doc, field)
+                    .into_iter()
+                    .find(|(s, _)| *s == state)
+                    .map(|(_, export)| export)
+                    .unwrap_or(state),
+            )
+        }
+        Object::Array(items) => Some(
+            items
+                .iter()
+                .filter_map(|i| lopdf::decode_text_string(i).ok())
+                .collect::<Vec<_>>()
+                .join(VALUE_SEPARATOR),
+        ),
+        other => lopdf::decode_text_string(other).ok(),
+    }
+}
+
+/// Map of widget annotation to the 1-based page it's on
+fn widget_pages(doc: &Document) -> HashMap<ObjectId, u32> {
+    let mut pages = HashMap::new();
+    for (number, page_id) in doc.get_pages() {
+        let annots = doc
+            .get_dictionary(page_id)
+            .ok()
+            .and_then(|p| p.get_deref(b"Annots", doc).and_then(Object::as_array).ok());
+        for annot in annots.into_iter().flatten() {
+            if let Ok(id) = annot.as_reference() {
+                pages.entry(id).or_insert(number);
+            }
+        }
+    }
+    pages
+}
+
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        Value::Array(items) => items.iter().map(value_text).collect::<Vec<_>>().join(VALUE_SEPARATOR),
+        other => other.to_string(),
+    }
+}
+
+fn is_checked(value: &Value) -> bool {
+    match value {
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().unwrap_or(0.0) != 0.0,
+        Value::String(s) => matches!(s.trim().to_lowercase().as_str(), "true" | "yes" | "on" | "1" | "x" | "checked"),
+        _ => false,
+    }
+}
+
+fn set_field_value(doc: &mut Document, field: &FieldNode, value: &Value, defaults: &mut FormDefaults) -> Result<(), String> {
+    let flags = field_flags(doc, field.id);
+    match field_type(doc, field.id) {
+        kind @ ("text" | "combo") => {
+            let mut text = value_text(value);
+            if let Some(max) = inherited(doc, field.id, b"MaxLen").and_then(|m| m.as_i64().ok()) {
+                text = text.chars().take(max.max(0) as usize).collect();
+            }
+            // Combo boxes store the export value but show the display text
+            let shown = match kind {
+                "combo" => choice_options(doc, field.id)
+                    .into_iter()
+                    .find(|(export, _)| *export == text)
+                    .map(|(_, display)| display)
+                    .unwrap_or_else(|| text.clone()),
+                _ => text.clone(),
+            };
+            set_field_entry(doc, field.id, "V", lopdf::text_string(&text));
+            for &widget in &field.widgets {
+                let style = text_style(doc, field.id, widget, defaults);
+                let shown = if flags & PASSWORD != 0 { "*".repeat(shown.chars().count()) } else { shown.clone() };
+                let (width, height) = widget_size(doc, widget);
+                let comb = match inherited(doc, field.id, b"MaxLen").and_then(|m| m.as_i64().ok()) {
+                    Some(max) if flags & COMB != 0 && kind == "text" => Some(max.max(1) as usize),
+                    _ => None,
+                };
+                let multiline = kind == "text" && flags & MULTILINE != 0;
+                let content = text_appearance(&shown, width, height, &style, multiline, comb);
+                install_appearance(doc, widget, content, width, height, &style.font, defaults);
+            }
+        }
+        "list" => {
+            let options = choice_options(doc, field.id);
+            let mut selected: Vec<String> = match value {
+                Value::Array(items) => items.iter().map(value_text).collect(),
+                other => {
+                    let text = value_text(other);
+                    if options.iter().any(|(export, _)| *export == text) {
+                        vec![text]
+                    } else {
+                        text.split(VALUE_SEPARATOR.trim()).map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+                    }
+                }
+            };
+            if flags & MULTI_SELECT == 0 {
+                selected.truncate(1);
+            }
+            let indices: Vec<usize> = options
+                .iter()
+                .enumerate()
+                .filter(|(_, (export, _))| selected.contains(export))
+                .map(|(i, _)| i)
+                .collect();
+            let stored = match selected.len() {
+                0 => Object::Null,
+                1 => lopdf::text_string(&selected[0]),
+                _ => Object::Array(selected.iter().map(|s| lopdf::text_string(s)).collect()),
+            };
+            set_field_entry(doc, field.id, "V", stored);
+            set_field_entry(doc, field.id, "I", Object::Array(indices.iter().map(|&i| Object::Integer(i as i64)).collect()));
+            for &widget in &field.widgets {
+                let style = text_style(doc, field.id, widget, defaults);
+                let (width, height) = widget_size(doc, widget);
+                let lines: Vec<&str> = options.iter().map(|(_, display)| display.as_str()).collect();
+                let content = list_appearance(&lines, &indices, width, height, &style);
+                install_appearance(doc, widget, content, width, height, &style.font, defaults);
+            }
+        }
+        "checkbox" | "radio" => {
+            let states = button_states(doc, field);
+            let wanted = value_text(value);
+            // A radio group picks the widget by export value; a checkbox is on
+            // for a truthy value or its own export value
+            let chosen: Option<String> = states
+                .iter()
+                .find(|(state, export)| *export == wanted || *state == wanted)
+                .map(|(state, _)| state.clone())
+                .or_else(|| match states.first() {
+                    Some((state, _)) if is_checked(value) => Some(state.clone()),
+                    _ => None,
+                });
+            let chosen = chosen.unwrap_or_else(|| "Off".to_string());
+            set_field_entry(doc, field.id, "V", Object::Name(chosen.clone().into_bytes()));
+            for (&widget, (state, _)) in field.widgets.iter().zip(&states) {
+                if on_state(doc, widget).is_none() {
+                    add_check_appearance(doc, widget, state);
+                }
+                let shown = if *state == chosen { state.clone() } else { "Off".to_string() };
+                if let Ok(dict) = doc.get_dictionary_mut(widget) {
+                    dict.set("AS", Object::Name(shown.into_bytes()));
+                }
+            }
+        }
+        other => return Err(format!("Field '{}' is a {} field and can't be filled", field.name, other)),
+    }
+    Ok(())
+}
+
+fn set_field_entry(doc: &mut Document, id: ObjectId, key: &str, value: Object) {
+    if let Ok(dict) = doc.get_dictionary_mut(id) {
+        if matches!(value, Object::Null) {
+            dict.remove(key.as_bytes());
+        } else {
+            dict.set(key, value);
+        }
+    }
+}
+
+fn widget_size(doc: &Document, widget: ObjectId) -> (f32, f32) {
+    let rect: Vec<f32> = doc
+        .get_dictionary(widget)
+        .ok()
+        .and_then(|w| w.get(b"Rect").and_then(Object::as_array).ok())
+        .map(|r| r.iter().map(|n| n.as_float().unwrap_or(0.0)).collect())
+        .unwrap_or_default();
+    match rect.as_slice() {
+        [x0, y0, x1, y1] => ((x1 - x0).abs(), (y1 - y0).abs()),
+        _ => (0.0, 0.0),
+    }
+}
+
+/// The widget's DA (falling back to the field's and the form's), with a
+/// font the appearance can actually use
+fn text_style(doc: &mut Document, field: ObjectId, widget: ObjectId, defaults: &mut FormDefaults) -> TextStyle {
+    let own = |doc: &Document, id: ObjectId, key: &[u8]| doc.get_dictionary(id).ok().and_then(|d| d.get(key).ok()).cloned();
+    let appearance = own(doc, widget, b"DA")
+        .or_else(|| inherited(doc, field, b"DA").cloned())
+        .and_then(|da| da.as_str().ok().map(|s| String::from_utf8_lossy(s).to_string()))
+        .unwrap_or_else(|| defaults.appearance.clone());
+    let quadding = own(doc, widget, b"Q")
+        .or_else(|| inherited(doc, field, b"Q").cloned())
+        .and_then(|q| q.as_i64().ok())
+        .unwrap_or(defaults.quadding);
+
+    let tokens: Vec<&str> = appearance.split_whitespace().collect();
+    let (mut font, mut size, mut color) = ("Helv".to_string(), 0.0, Vec::new());
+    let mut i = 0;
+    while i < tokens.len() {
+        if i + 2 < tokens.len() && tokens[i + 2] == "Tf" && tokens[i].starts_with('/') {
+            font = tokens[i][1..].to_string();
+            size = tokens[i + 1].parse().unwrap_or(0.0);
+            i += 3;
+        } else {
+            color.push(tokens[i]);
+            i += 1;
+        }
+    }
+
+    // Text is written in WinAnsi, so composite fonts are swapped for Helvetica
+    let usable = defaults
+        .fonts
+        .get_deref(font.as_bytes(), doc)
+        .and_then(Object::as_dict)
+        .map(|f| f.get(b"Subtype").and_then(Object::as_name).ok() != Some(b"Type0"))
+        .unwrap_or(false);
+    if !usable {
+        font = "Helv".to_string();
+        if !defaults.fonts.has(b"Helv") {
+            let helvetica = doc.add_object(dictionary! {
+                "Type" => "Font",
+                "Subtype" => "Type1",
+                "BaseFont" => "Helvetica",
+                "Encoding" => "WinAnsiEncoding",
+            });
+            defaults.fonts.set("Helv", helvetica);
+        }
+    }
+
+    TextStyle {
+        font,
+        size,
+        color: if color.is_empty() { "0 g".to_string() } else { color.join(" ") },
+        quadding,
+    }
+}
+
+/// Replace a widget's normal appearance with a new form XObject
+fn install_appearance(doc: &mut Document, widget: ObjectId, content: Vec<u8>, width: f32, height: f32, font: &str, defaults: &FormDefaults) {
+    let font_entry = defaults.fonts.get(font.as_bytes()).cloned().unwrap_or(Object::Null);
+    let stream = Stream::new(
+        dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Form",
+            "BBox" => vec![0.into(), 0.into(), width.into(), height.into()],
+            "Resources" => dictionary! { "Font" => dictionary! { font => font_entry } },
+        },
+        content,
+    );
+    let appearance = doc.add_object(stream);
+    if let Ok(dict) = doc.get_dictionary_mut(widget) {
+        dict.set("AP", dictionary! { "N" => appearance });
+    }
+}
+
+/// Give a checkbox or radio widget without appearances a simple check mark
+fn add_check_appearance(doc: &mut Document, widget: ObjectId, state: &str) {
+    let (width, height) = widget_size(doc, widget);
+    let bbox = vec![0.into(), 0.into(), width.into(), height.into()];
+    let check = format!(
+        "q 0 g 0 G {} w 1 J 1 j {} {} m {} {} l {} {} l S Q\n",
+        num(height.min(width) * 0.1),
+        num(width * 0.2), num(height * 0.5),
+        num(width * 0.42), num(height * 0.25),
+        num(width * 0.8), num(height * 0.78),
+    );
+    let on = doc.add_object(Stream::new(dictionary! { "Type" => "XObject", "Subtype" => "Form", "BBox" => bbox.clone() }, check.into_bytes()));
+    let off = doc.add_object(Stream::new(dictionary! { "Type" => "XObject", "Subtype" => "Form", "BBox" => bbox }, Vec::new()));
+    if let Ok(dict) = doc.get_dictionary_mut(widget) {
+        dict.set("AP", dictionary! { "N" => dictionary! { state => on, "Off" => off } });
+    }
+}
+
+fn text_width(text: &str, size: f32) -> f32 {
+    text.chars()
+        .map(|c| match c as u32 {
+            32..=126 => HELVETICA_WIDTHS[c as usize - 32] as f32,
+            _ => 556.0,
+        })
+        .sum::<f32>()
+        * size
+        / 1000.0
+}
+
+/// Break text into lines that fit the width, at spaces where possible
+fn wrap_text(text: &str, width: f32, size: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        for word in paragraph.split(' ') {
+            let candidate = if line.is_empty() { word.to_string() } else { format!("{} {}", line, word) };
+            if text_width(&candidate, size) <= width || line.is_empty() {
+                line = candidate;
+            } else {
+                lines.push(std::mem::replace(&mut line, word.to_string()));
+            }
+            // Words longer than a line are broken by character
+            while text_width(&line, size) > width && line.chars().count() > 1 {
+                let mut head = String::new();
+                for c in line.chars() {
+                    if text_width(&format!("{}{}", head, c), size) > width && !head.is_empty() {
+                        break;
+                    }
+                    head.push(c);
+                }
+                line = line[head.len()..].to_string();
+                lines.push(head);
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn text_appearance(text: &str, width: f32, height: f32, style: &TextStyle, multiline: bool, comb: Option<usize>) -> Vec<u8> {
+    let inner = (width - 4.0).max(1.0);
+    let size = if style.size > 0.0 {
+        style.size
+    } else if multiline {
+        10.0
+    } else {
+        // Auto size: fit the height, then shrink to fit the width
+        let fit_height = ((height - 4.0) / 1.15).clamp(4.0, 12.0);
+        let measured = text_width(text, fit_height);
+        if measured > inner { (fit_height * inner / measured).max(4.0) } else { fit_height }
+    };
+
+    let mut out = format!(
+        "/Tx BMC\nq\n1 1 {} {} re W n\nBT\n/{} {} Tf {}\n",
+        num(width - 2.0), num(height - 2.0), style.font, num(size), style.color
+    );
+    let mut show = |x: f32, y: f32, line: &str| {
+        out.push_str(&format!("1 0 0 1 {} {} Tm {} Tj\n", num(x), num(y), pdf_string(line)));
+    };
+    let align = |line: &str| match style.quadding {
+        1 => (width - text_width(line, size)) / 2.0,
+        2 => width - 2.0 - text_width(line, size),
+        _ => 2.0,
+    };
+
+    if multiline {
+        let leading = size * 1.15;
+        let mut y = height - 2.0 - size;
+        for line in wrap_text(text, inner, size) {
+            show(align(&line), y, &line);
+            y -= leading;
+        }
+    } else {
+        let y = (height - size * 0.72) / 2.0;
+        match comb {
+            Some(cells) => {
+                let cell = width / cells as f32;
+                for (i, c) in text.chars().enumerate() {
+                    let glyph = c.to_string();
+                    show(cell * i as f32 + (cell - text_width(&glyph, size)) / 2.0, y, &glyph);
+                }
+            }
+            None => show(align(text), y, text),
+        }
+    }
+    out.push_str("ET\nQ\nEMC\n");
+    out.into_bytes()
+}
+
+fn list_appearance(lines: &[&str], selected: &[usize], width: f32, height: f32, style: &TextStyle) -> Vec<u8> {
+    let size = if style.size > 0.0 { style.size } else { 10.0 };
+    let leading = size * 1.15;
+    let mut out = format!("/Tx BMC\nq\n1 1 {} {} re W n\n", num(width - 2.0), num(height - 2.0));
+    // Highlight the selected rows like a viewer would
+    for &index in selected {
+        let top = height - 1.0 - leading * index as f32;
+        out.push_str(&format!("0.6 0.75 0.87 rg 1 {} {} {} re f\n", num(top - leading), num(width - 2.0), num(leading)));
+    }
+    out.push_str(&format!("BT\n/{} {} Tf {}\n", style.font, num(size), style.color));
+    for (index, line) in lines.iter().enumerate() {
+        let baseline = height - 1.0 - leading * (index + 1) as f32 + (leading - size * 0.72) / 2.0;
+        out.push_str(&format!("1 0 0 1 2 {} Tm {} Tj\n", num(baseline), pdf_string(line)));
+    }
+    out.push_str("ET\nQ\nEMC\n");
+    out.into_bytes()
+}
+
+/// A literal string in WinAnsi encoding; characters it lacks become '?'
+fn pdf_string(text: &str) -> String {
+    let mut out = String::from("(");
+    for c in text.chars() {
+        let code = match c as u32 {
+            0x20..=0x7E | 0xA0..=0xFF => c as u32 as u8,
+            0x20AC => 0x80,
+            0x2018 => 0x91,
+            0x2019 => 0x92,
+            0x201C => 0x93,
+            0x201D => 0x94,
+            0x2022 => 0x95,
+            0x2013 => 0x96,
+            0x2014 => 0x97,
+            _ => b'?',
+        };
+        match code {
+            b'(' | b')' | b'\\' => {
+                out.push('\\');
+                out.push(code as char);
+            }
+            0x20..=0x7E => out.push(code as char),
+            _ => out.push_str(&format!("\\{:03o}", code)),
+        }
+    }
+    out.push(')');
+    out
+}
+
+fn num(value: f32) -> String {
+    let text = format!("{:.2}", value);
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Draw each field widget's appearance into its page and remove the form
+fn flatten_form(doc: &mut Document, fields: &[FieldNode]) {
+    // Pages need their own resource dictionaries to add XObjects to
+    flatten_inherited_attributes(doc);
+    let widgets: HashSet<ObjectId> = fields.iter().flat_map(|f| f.widgets.iter().copied()).collect();
+
+    let mut counter = 0;
+    let page_ids: Vec<ObjectId> = doc.page_iter().collect();
+    for page_id in page_ids {
+        let annots = doc
+            .get_dictionary(page_id)
+            .ok()
+            .and_then(|p| p.get_deref(b"Annots", doc).and_then(Object::as_array).ok())
+            .cloned()
+            .unwrap_or_default();
+        if annots.is_empty() {
+            continue;
+        }
+
+        let mut kept = Vec::new();
+        let mut drawing = String::new();
+        for annot in annots {
+            let widget = match annot.as_reference() {
+                Ok(id) if widgets.contains(&id) => id,
+                _ => {
+                    kept.push(annot);
+                    continue;
+                }
+            };
+            if let Some((xobject, matrix)) = widget_placement(doc, widget) {
+                counter += 1;
+                let name = format!("FlatField{}", counter);
+                if doc.add_xobject(page_id, name.as_bytes(), xobject).is_ok() {
+                    let matrix: Vec<String> = matrix.iter().map(|v| num(*v)).collect();
+                    drawing.push_str(&format!("q {} cm /{} Do Q\n", matrix.join(" "), name));
+                }
+            }
+        }
+
+        if let Ok(page) = doc.get_dictionary_mut(page_id) {
+            if kept.is_empty() {
+                page.remove(b"Annots");
+            } else {
+                page.set("Annots", kept);
+            }
+        }
+        if !drawing.is_empty() {
+            wrap_page_contents(doc, page_id, drawing);
+        }
+    }
+
+    if let Ok(catalog) = doc.catalog_mut() {
+        catalog.remove(b"AcroForm");
+    }
+    doc.prune_objects();
+}
+
+/// The widget's current appearance stream and the matrix that maps its
+/// bounding box onto the annotation rectangle
+fn widget_placement(doc: &mut Document, widget: ObjectId) -> Option<(ObjectId, [f32; 6])> {
+    let dict = doc.get_dictionary(widget).ok()?;
+    // Hidden and no-view annotations aren't drawn
+    let flags = dict.get(b"F").and_then(Object::as_i64).unwrap_or(0);
+    if flags & (2 | 32) != 0 {
+        return None;
+    }
+    let normal = dict.get_deref(b"AP", doc).and_then(Object::as_dict).ok()?.get(b"N").ok()?;
+    let stream_id = match normal {
+        Object::Reference(id) => match doc.get_object(*id).ok()? {
+            Object::Stream(_) => *id,
+            Object::Dictionary(states) => {
+                let state = dict.get(b"AS").and_then(Object::as_name).ok()?;
+                states.get(state).and_then(Object::as_reference).ok()?
+            }
+            _ => return None,
+        },
+        Object::Dictionary(states) => {
+            let state = dict.get(b"AS").and_then(Object::as_name).ok()?;
+            states.get(state).and_then(Object::as_reference).ok()?
+        }
+        _ => return None,
+    };
+    let rect: Vec<f32> = dict.get(b"Rect").and_then(Object::as_array).ok()?.iter().map(|n| n.as_float().unwrap_or(0.0)).collect();
+
+    let stream = doc.get_object_mut(stream_id).and_then(Object::as_stream_mut).ok()?;
+    stream.dict.set("Type", "XObject");
+    stream.dict.set("Subtype", "Form");
+    let floats = |key: &[u8], default: Vec<f32>| -> Vec<f32> {
+        stream.dict.get(key).and_then(Object::as_array)
+            .map(|a| a.iter().map(|n| n.as_float().unwrap_or(0.0)).collect())
+            .unwrap_or(default)
+    };
+    let bbox = floats(b"BBox", vec![0.0, 0.0, 0.0, 0.0]);
+    let m = floats(b"Matrix", vec![1.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+    if bbox.len() != 4 || m.len() != 6 || rect.len() != 4 {
+        return None;
+    }
+
+    // Bounds of the transformed bounding box
+    let corners = [(bbox[0], bbox[1]), (bbox[2], bbox[1]), (bbox[0], bbox[3]), (bbox[2], bbox[3])];
+    let points: Vec<(f32, f32)> = corners
+        .iter()
+        .map(|&(x, y)| (m[0] * x + m[2] * y + m[4], m[1] * x + m[3] * y + m[5]))
+        .collect();
+    let min_x = points.iter().map(|p| p.0).fold(f32::MAX, f32::min);
+    let max_x = points.iter().map(|p| p.0).fold(f32::MIN, f32::max);
+    let min_y = points.iter().map(|p| p.1).fold(f32::MAX, f32::min);
+    let max_y = points.iter().map(|p| p.1).fold(f32::MIN, f32::max);
+    if max_x - min_x <= 0.0 || max_y - min_y <= 0.0 {
+        return None;
+    }
+
+    let (rx0, rx1) = (rect[0].min(rect[2]), rect[0].max(rect[2]));
+    let (ry0, ry1) = (rect[1].min(rect[3]), rect[1].max(rect[3]));
+    let sx = (rx1 - rx0) / (max_x - min_x);
+    let sy = (ry1 - ry0) / (max_y - min_y);
+    Some((stream_id, [sx, 0.0, 0.0, sy, rx0 - min_x * sx, ry0 - min_y * sy]))
+}
+
+/// Isolate the existing page content in q/Q and append a drawing after it
+fn wrap_page_contents(doc: &mut Document, page_id: ObjectId, drawing: String) {
+    let existing: Vec<Object> = match doc.get_dictionary(page_id).ok().and_then(|p| p.get(b"Contents").ok()) {
+        Some(Object::Reference(id)) => match doc.get_object(*id) {
+            Ok(Object::Array(items)) => items.clone(),
+            _ => vec![Object::Reference(*id)],
+        },
+        Some(Object::Array(items)) => items.clone(),
+        _ => Vec::new(),
+    };
+    let open = doc.add_object(Stream::new(Dictionary::new(), b"q\n".to_vec()));
+    let close = doc.add_object(Stream::new(Dictionary::new(), format!("Q\n{}", drawing).into_bytes()));
+
+    let mut contents = vec![Object::Reference(open)];
+    contents.extend(existing);
+    contents.push(Object::Reference(close));
+    if let Ok(page) = doc.get_dictionary_mut(page_id) {
+        page.set("Contents", contents);
+    }
+}
+
+fn sanitize_file_stem(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, '-' | '_' | ' ' | '.') { c } else { '_' })
+        .collect::<String>()
+        .trim()
+        .trim_matches('.')
+        .to_string()
+}
@@ -0,0 +1,1375 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
+use lopdf::content::Content;
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use resvg::tiny_skia::{
+    self, Color, FillRule, LineCap, LineJoin, Mask, Paint, Path, PathBuilder, Pixmap,
+    PixmapPaint, Stroke, Transform,
+};
+use resvg::usvg::fontdb;
+
+/// Highest CID a composite font can map to a (16-bit) glyph id
+const MAX_CID: u32 = 0xFFFF;
+/// Largest decoded image, in bytes of RGBA, the renderer will allocate
+const MAX_IMAGE_BYTES: usize = 1 << 30;
+
+/// Render one PDF page to a pixmap in-process.
+///
+/// Covers the parts of the imaging model that ordinary documents rely on:
+/// vector paths, clipping, images (raw, Flate and JPEG, with soft masks),
+/// form XObjects and text drawn with embedded TrueType/CFF fonts. Fonts that
+/// aren't embedded (or use Type 1 programs) fall back to a similar system font.
+/// Pages that use shadings, patterns, Type 3 fonts, inline images or JPX,
+/// CCITT and JBIG2 images are an error rather than a page with parts missing,
+/// so callers can fall back to another renderer.
+pub fn render_pdf_page(doc: &Document, page_id: ObjectId, dpi: f32) -> Result<Pixmap, String> {
+    let page = doc.get_dictionary(page_id).map_err(|e| e.to_string())?;
+
+    let media_box = inherited(doc, page, b"CropBox")
+        .or_else(|| inherited(doc, page, b"MediaBox"))
+        .and_then(|o| o.as_array().ok())
+        .map(|a| a.iter().map(number).collect::<Vec<f32>>())
+        .filter(|a| a.len() == 4)
+        .unwrap_or_else(|| vec![0.0, 0.0, 612.0, 792.0]);
+    let (x0, y0) = (media_box[0].min(media_box[2]), media_box[1].min(media_box[3]));
+    let (x1, y1) = (media_box[0].max(media_box[2]), media_box[1].max(media_box[3]));
+    let rotate = inherited(doc, page, b"Rotate")
+        .and_then(|o| o.as_i64().ok())
+        .unwrap_or(0)
+        .rem_euclid(360);
+
+    let scale = dpi / 72.0;
+    let (page_width, page_height) = ((x1 - x0) * scale, (y1 - y0) * scale);
+    let (width, height) = if rotate == 90 || rotate == 270 {
+        (page_height, page_width)
+    } else {
+        (page_width, page_height)
+    };
+
+    let mut pixmap = Pixmap::new(width.ceil().max(1.0) as u32, height.ceil().max(1.0) as u32)
+        .ok_or_else(|| "Page is too large to render".to_string())?;
+    pixmap.fill(Color::WHITE);
+
+    // PDF user space has its origin at the bottom left; flip into device space
+    let base = Transform::from_row(scale, 0.0, 0.0, -scale, -x0 * scale, y1 * scale);
+    let rotation = match rotate {
+        90 => Transform::from_row(0.0, 1.0, -1.0, 0.0, width, 0.0),
+        180 => Transform::from_row(-1.0, 0.0, 0.0, -1.0, width, height),
+        270 => Transform::from_row(0.0, -1.0, 1.0, 0.0, 0.0, height),
+        _ => Transform::identity(),
+    };
+
+    let resources = inherited(doc, page, b"Resources")
+        .and_then(|o| o.as_dict().ok())
+        .cloned()
+        .unwrap_or_default();
+    let content = doc.get_page_content(page_id).map_err(|e| e.to_string())?;
+
+    let mut renderer = Renderer {
+        doc,
+        pixmap,
+        fonts: HashMap::new(),
+        system_fonts: None,
+        depth: 0,
+        skipped: BTreeSet::new(),
+    };
+    let state = GraphicsState::new(rotation.pre_concat(base));
+    renderer.run(&content, &resources, state);
+    if !renderer.skipped.is_empty() {
+        let skipped: Vec<String> = renderer.skipped.into_iter().collect();
+        return Err(format!("The page uses {}, which the built-in renderer can't draw", skipped.join(", ")));
+    }
+    Ok(renderer.pixmap)
+}
+
+/// Look up a page attribute, following the page tree for inheritable keys
+fn inherited<'a>(doc: &'a Document, page: &'a Dictionary, key: &[u8]) -> Option<&'a Object> {
+    let mut node = page;
+    for _ in 0..32 {
+        if let Ok(value) = node.get(key) {
+            return doc.dereference(value).ok().map(|(_, o)| o);
+        }
+        node = node.get_deref(b"Parent", doc).and_then(Object::as_dict).ok()?;
+    }
+    None
+}
+
+fn number(object: &Object) -> f32 {
+    object.as_float().unwrap_or(0.0)
+}
+
+#[derive(Clone)]
+enum ColorSpace {
+    Gray,
+    Rgb,
+    Cmyk,
+    Indexed(Box<ColorSpace>, Vec<u8>),
+    /// Separation/DeviceN tints and patterns: approximated with gray
+    Other(usize),
+}
+
+impl ColorSpace {
+    fn components(&self) -> usize {
+        match self {
+            ColorSpace::Gray | ColorSpace::Indexed(..) => 1,
+            ColorSpace::Rgb => 3,
+            ColorSpace::Cmyk => 4,
+            ColorSpace::Other(n) => *n,
+        }
+    }
+
+    /// Convert component values in 0..1 (or an index) to RGB in 0..1
+    fn to_rgb(&self, values: &[f32]) -> [f32; 3] {
+        let get = |i: usize| values.get(i).copied().unwrap_or(0.0).clamp(0.0, 1.0);
+        match self {
+            ColorSpace::Gray => [get(0); 3],
+            ColorSpace::Rgb => [get(0), get(1), get(2)],
+            ColorSpace::Cmyk => {
+                let k = get(3);
+                [(1.0 - get(0)) * (1.0 - k), (1.0 - get(1)) * (1.0 - k), (1.0 - get(2)) * (1.0 - k)]
+            }
+            ColorSpace::Indexed(base, lookup) => {
+                let n = base.components();
+                let index = values.first().copied().unwrap_or(0.0).max(0.0) as usize;
+                let entry: Vec<f32> = (0..n)
+                    .map(|c| lookup.get(index * n + c).copied().unwrap_or(0) as f32 / 255.0)
+                    .collect();
+                base.to_rgb(&entry)
+            }
+            // A full tint of a spot color prints dark
+            ColorSpace::Other(_) => [1.0 - get(0); 3],
+        }
+    }
+}
+
+fn resolve_color_space(doc: &Document, object: &Object, resources: &Dictionary) -> ColorSpace {
+    let object = match doc.dereference(object) {
+        Ok((_, o)) => o,
+        Err(_) => return ColorSpace::Gray,
+    };
+    match object {
+        Object::Name(name) => match name.as_slice() {
+            b"DeviceGray" | b"G" | b"CalGray" => ColorSpace::Gray,
+            b"DeviceRGB" | b"RGB" | b"CalRGB" | b"Lab" => ColorSpace::Rgb,
+            b"DeviceCMYK" | b"CMYK" => ColorSpace::Cmyk,
+            b"Pattern" => ColorSpace::Other(1),
+            other => resources
+                .get_deref(b"ColorSpace", doc)
+                .and_then(Object::as_dict)
+                .and_then(|spaces| spaces.get(other))
+                .map(|named| resolve_color_space(doc, named, &Dictionary::new()))
+                .unwrap_or(ColorSpace::Gray),
+        },
+        Object::Array(items) => {
+            let family = items.first().and_then(|o| o.as_name().ok()).unwrap_or(b"");
+            match family {
+                b"ICCBased" => {
+                    let n = items
+                        .get(1)
+                        .and_then(|o| doc.dereference(o).ok())
+                        .and_then(|(_, o)| o.as_stream().ok())
+                        .and_then(|s| s.dict.get(b"N").and_then(Object::as_i64).ok())
+                        .unwrap_or(3);
+                    match n {
+                        1 => ColorSpace::Gray,
+                        4 => ColorSpace::Cmyk,
+                        _ => ColorSpace::Rgb,
+                    }
+                }
+                b"Indexed" | b"I" => {
+                    let base = items
+                        .get(1)
+                        .map(|b| resolve_color_space(doc, b, resources))
+                        .unwrap_or(ColorSpace::Rgb);
+                    let lookup = items
+                        .get(3)
+                        .and_then(|o| doc.dereference(o).ok())
+                        .map(|(_, o)| match o {
+                            Object::String(bytes, _) => bytes.clone(),
+                            Object::Stream(s) => s.decompressed_content().unwrap_or_else(|_| s.content.clone()),
+                            _ => Vec::new(),
+                        })
+                        .unwrap_or_default();
+                    ColorSpace::Indexed(Box::new(base), lookup)
+                }
+                b"CalRGB" | b"Lab" => ColorSpace::Rgb,
+                b"CalGray" => ColorSpace::Gray,
+                b"DeviceN" => ColorSpace::Other(
+                    items.get(1).and_then(|o| o.as_array().ok()).map(|a| a.len()).unwrap_or(1),
+                ),
+                b"Pattern" => ColorSpace::Other(1),
+                _ => ColorSpace::Other(1),
+            }
+        }
+        _ => ColorSpace::Gray,
+    }
+}
+
+#[derive(Clone)]
+struct GraphicsState {
+    ctm: Transform,
+    fill_space: ColorSpace,
+    stroke_space: ColorSpace,
+    fill: [f32; 3],
+    stroke: [f32; 3],
+    fill_alpha: f32,
+    stroke_alpha: f32,
+    line_width: f32,
+    line_cap: LineCap,
+    line_join: LineJoin,
+    miter_limit: f32,
+    clip: Option<Rc<Mask>>,
+    font: Option<Rc<PdfFont>>,
+    font_size: f32,
+    char_spacing: f32,
+    word_spacing: f32,
+    horizontal_scale: f32,
+    leading: f32,
+    rise: f32,
+    render_mode: i64,
+}
+
+impl GraphicsState {
+    fn new(ctm: Transform) -> Self {
+        GraphicsState {
+            ctm,
+            fill_space: ColorSpace::Gray,
+            stroke_space: ColorSpace::Gray,
+            fill: [0.0; 3],
+            stroke: [0.0; 3],
+            fill_alpha: 1.0,
+            stroke_alpha: 1.0,
+            line_width: 1.0,
+            line_cap: LineCap::Butt,
+            line_join: LineJoin::Miter,
+            miter_limit: 10.0,
+            clip: None,
+            font: None,
+            font_size: 12.0,
+            char_spacing: 0.0,
+            word_spacing: 0.0,
+            horizontal_scale: 1.0,
+            leading: 0.0,
+            rise: 0.0,
+            render_mode: 0,
+        }
+    }
+
+    fn paint(&self, rgb: [f32; 3], alpha: f32) -> Paint<'static> {
+        let mut paint = Paint::default();
+        paint.set_color(Color::from_rgba(rgb[0], rgb[1], rgb[2], alpha.clamp(0.0, 1.0)).unwrap_or(Color::BLACK));
+        paint.anti_alias = true;
+        paint
+    }
+}
+
+/// Where a font's glyph outlines come from
+enum GlyphSource {
+    TrueType(Vec<u8>, u32),
+    Cff(Vec<u8>),
+    None,
+}
+
+struct PdfFont {
+    /// Two-byte codes (Type0 fonts) instead of one byte per glyph
+    composite: bool,
+    source: GlyphSource,
+    /// Glyph names from the font's /Differences array, by code
+    differences: HashMap<u32, String>,
+    /// Advance widths in thousandths of text space, by code
+    widths: HashMap<u32, f32>,
+    default_width: f32,
+    cid_to_gid: Option<Vec<u16>>,
+    /// CFF CID-keyed fonts index glyphs by CID
+    cff_cid_map: HashMap<u16, u16>,
+    symbolic: bool,
+    /// Character for each code of a simple font
+    unicode: HashMap<u32, Option<char>>,
+    outlines: RefCell<HashMap<u32, Option<(Path, f32)>>>,
+}
+
+struct Renderer<'a> {
+    doc: &'a Document,
+    pixmap: Pixmap,
+    fonts: HashMap<Vec<u8>, Rc<PdfFont>>,
+    system_fonts: Option<Rc<fontdb::Database>>,
+    depth: usize,
+    /// Kinds of content that were left out of the page
+    skipped: BTreeSet<String>,
+}
+
+impl<'a> Renderer<'a> {
+    fn run(&mut self, content: &[u8], resources: &Dictionary, mut state: GraphicsState) {
+        let content = strip_inline_images(content);
+        if let std::borrow::Cow::Owned(_) = content {
+            self.skipped.insert("inline images".to_string());
+        }
+        let operations = match Content::decode(&content) {
+            Ok(c) => c.operations,
+            Err(_) => {
+                self.skipped.insert("an undecodable content stream".to_string());
+                return;
+            }
+        };
+
+        let mut stack: Vec<GraphicsState> = Vec::new();
+        let mut path = PathBuilder::new();
+        let mut pending_clip: Option<FillRule> = None;
+        let mut text_matrix = Transform::identity();
+        let mut line_matrix = Transform::identity();
+        let (mut cur_x, mut cur_y) = (0.0f32, 0.0f32);
+
+        for op in operations {
+            let args = &op.operands;
+            let arg = |i: usize| args.get(i).map(number).unwrap_or(0.0);
+            match op.operator.as_str() {
+                "q" => stack.push(state.clone()),
+                "Q" => {
+                    if let Some(s) = stack.pop() {
+                        state = s;
+                    }
+                }
+                "cm" => {
+                    let m = Transform::from_row(arg(0), arg(1), arg(2), arg(3), arg(4), arg(5));
+                    state.ctm = state.ctm.pre_concat(m);
+                }
+                "w" => state.line_width = arg(0),
+                "J" => {
+                    state.line_cap = match arg(0) as i32 {
+                        1 => LineCap::Round,
+                        2 => LineCap::Square,
+                        _ => LineCap::Butt,
+                    }
+                }
+                "j" => {
+                    state.line_join = match arg(0) as i32 {
+                        1 => LineJoin::Round,
+                        2 => LineJoin::Bevel,
+                        _ => LineJoin::Miter,
+                    }
+                }
+                "M" => state.miter_limit = arg(0),
+                "gs" => {
+                    if let Some(gs) = args
+                        .first()
+                        .and_then(|n| n.as_name().ok())
+                        .and_then(|n| self.named_resource(resources, b"ExtGState", n))
+                        .and_then(|o| o.as_dict().ok())
+                    {
+                        if let Ok(v) = gs.get(b"CA") {
+                            state.stroke_alpha = number(v);
+                        }
+                        if let Ok(v) = gs.get(b"ca") {
+                            state.fill_alpha = number(v);
+                        }
+                        if let Ok(v) = gs.get(b"LW") {
+                            state.line_width = number(v);
+                        }
+                    }
+                }
+
+                // Path construction
+                "m" => {
+                    path.move_to(arg(0), arg(1));
+                    (cur_x, cur_y) = (arg(0), arg(1));
+                }
+                "l" => {
+                    path.line_to(arg(0), arg(1));
+                    (cur_x, cur_y) = (arg(0), arg(1));
+                }
+                "c" => {
+                    path.cubic_to(arg(0), arg(1), arg(2), arg(3), arg(4), arg(5));
+                    (cur_x, cur_y) = (arg(4), arg(5));
+                }
+                "v" => {
+                    path.cubic_to(cur_x, cur_y, arg(0), arg(1), arg(2), arg(3));
+                    (cur_x, cur_y) = (arg(2), arg(3));
+                }
+                "y" => {
+                    path.cubic_to(arg(0), arg(1), arg(2), arg(3), arg(2), arg(3));
+                    (cur_x, cur_y) = (arg(2), arg(3));
+                }
+                "h" => path.close(),
+                "re" => {
+                    let (x, y, w, h) = (arg(0), arg(1), arg(2), arg(3));
+                    path.move_to(x, y);
+                    path.line_to(x + w, y);
+                    path.line_to(x + w, y + h);
+                    path.line_to(x, y + h);
+                    path.close();
+                    (cur_x, cur_y) = (x, y);
+                }
+                "W" => pending_clip = Some(FillRule::Winding),
+                "W*" => pending_clip = Some(FillRule::EvenOdd),
+
+                // Path painting
+                "f" | "F" | "f*" | "B" | "B*" | "b" | "b*" | "S" | "s" | "n" => {
+                    let mut builder = std::mem::take(&mut path);
+                    if matches!(op.operator.as_str(), "b" | "b*" | "s") {
+                        builder.close();
+                    }
+                    if let Some(finished) = builder.finish() {
+                        let rule = if op.operator.ends_with('*') { FillRule::EvenOdd } else { FillRule::Winding };
+                        if matches!(op.operator.as_str(), "f" | "F" | "f*" | "B" | "B*" | "b" | "b*") {
+                            self.fill(&finished, rule, &state);
+                        }
+                        if matches!(op.operator.as_str(), "B" | "B*" | "b" | "b*" | "S" | "s") {
+                            self.stroke(&finished, &state);
+                        }
+                        if let Some(clip_rule) = pending_clip.take() {
+                            self.clip(&finished, clip_rule, &mut state);
+                        }
+                    }
+                    pending_clip = None;
+                }
+
+                // Color
+                "g" => {
+                    state.fill_space = ColorSpace::Gray;
+                    state.fill = [arg(0); 3];
+                }
+                "G" => {
+                    state.stroke_space = ColorSpace::Gray;
+                    state.stroke = [arg(0); 3];
+                }
+                "rg" => {
+                    state.fill_space = ColorSpace::Rgb;
+                    state.fill = [arg(0), arg(1), arg(2)];
+                }
+                "RG" => {
+                    state.stroke_space = ColorSpace::Rgb;
+                    state.stroke = [arg(0), arg(1), arg(2)];
+                }
+                "k" => {
+                    state.fill_space = ColorSpace::Cmyk;
+                    state.fill = ColorSpace::Cmyk.to_rgb(&[arg(0), arg(1), arg(2), arg(3)]);
+                }
+                "K" => {
+                    state.stroke_space = ColorSpace::Cmyk;
+                    state.stroke = ColorSpace::Cmyk.to_rgb(&[arg(0), arg(1), arg(2), arg(3)]);
+                }
+                "cs" | "CS" => {
+                    let space = args
+                        .first()
+                        .map(|o| resolve_color_space(self.doc, o, resources))
+                        .unwrap_or(ColorSpace::Gray);
+                    // Selecting a color space resets the color to its initial value (black)
+                    let initial = match space {
+                        ColorSpace::Cmyk => [0.0, 0.0, 0.0],
+                        _ => space.to_rgb(&[0.0, 0.0, 0.0, 1.0]),
+                    };
+                    if op.operator == "cs" {
+                        state.fill_space = space;
+                        state.fill = initial;
+                    } else {
+                        state.stroke_space = space;
+                        state.stroke = initial;
+                    }
+                }
+                "sc" | "scn" | "SC" | "SCN" => {
+                    // A pattern is selected by name
+                    if args.iter().any(|o| o.as_name().is_ok()) {
+                        self.skipped.insert("patterns".to_string());
+                    }
+                    let values: Vec<f32> = args.iter().filter_map(|o| o.as_float().ok()).collect();
+                    if op.operator.starts_with('s') {
+                        state.fill = state.fill_space.to_rgb(&values);
+                    } else {
+                        state.stroke = state.stroke_space.to_rgb(&values);
+                    }
+                }
+
+                // Text
+                "BT" => {
+                    text_matrix = Transform::identity();
+                    line_matrix = Transform::identity();
+                }
+                "ET" => {}
+                "Tf" => {
+                    state.font = args
+                        .first()
+                        .and_then(|n| n.as_name().ok())
+                        .and_then(|n| self.load_font(resources, n));
+                    state.font_size = arg(1);
+                }
+                "Tc" => state.char_spacing = arg(0),
+                "Tw" => state.word_spacing = arg(0),
+                "Tz" => state.horizontal_scale = arg(0) / 100.0,
+                "TL" => state.leading = arg(0),
+                "Ts" => state.rise = arg(0),
+                "Tr" => state.render_mode = arg(0) as i64,
+                "Td" | "TD" => {
+                    if op.operator == "TD" {
+                        state.leading = -arg(1);
+                    }
+                    line_matrix = line_matrix.pre_concat(Transform::from_translate(arg(0), arg(1)));
+                    text_matrix = line_matrix;
+                }
+                "Tm" => {
+                    line_matrix = Transform::from_row(arg(0), arg(1), arg(2), arg(3), arg(4), arg(5));
+                    text_matrix = line_matrix;
+                }
+                "T*" => {
+                    line_matrix = line_matrix.pre_concat(Transform::from_translate(0.0, -state.leading));
+                    text_matrix = line_matrix;
+                }
+                "Tj" | "'" | "\"" => {
+                    if op.operator != "Tj" {
+                        if op.operator == "\"" {
+                            state.word_spacing = arg(0);
+                            state.char_spacing = arg(1);
+                        }
+                        line_matrix = line_matrix.pre_concat(Transform::from_translate(0.0, -state.leading));
+                        text_matrix = line_matrix;
+                    }
+                    if let Some(Ok(bytes)) = args.last().map(Object::as_str) {
+                        self.show_text(bytes, &state, &mut text_matrix);
+                    }
+                }
+                "TJ" => {
+                    if let Some(Ok(items)) = args.first().map(Object::as_array) {
+                        for item in items {
+                            match item {
+                                Object::String(bytes, _) => self.show_text(bytes, &state, &mut text_matrix),
+                                other => {
+                                    let adjust = -number(other) / 1000.0 * state.font_size * state.horizontal_scale;
+                                    text_matrix = text_matrix.pre_concat(Transform::from_translate(adjust, 0.0));
+                                }
+                            }
+                        }
+                    }
+                }
+
+                "sh" => {
+                    self.skipped.insert("shadings".to_string());
+                }
+
+                // XObjects
+                "Do" => {
+                    if let Some(name) = args.first().and_then(|n| n.as_name().ok()) {
+                        self.draw_xobject(resources, name, &state);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn named_resource<'r>(&self, resources: &'r Dictionary, category: &[u8], name: &[u8]) -> Option<&'r Object>
+    where
+        'a: 'r,
+    {
+        let doc = self.doc;
+        let group = resources.get_deref(category, doc).and_then(Object::as_dict).ok()?;
+        group.get_deref(name, doc).ok()
+    }
+
+    fn fill(&mut self, path: &Path, rule: FillRule, state: &GraphicsState) {
+        let paint = state.paint(state.fill, state.fill_alpha);
+        self.pixmap
+            .fill_path(path, &paint, rule, state.ctm, state.clip.as_deref());
+    }
+
+    fn stroke(&mut self, path: &Path, state: &GraphicsState) {
+        let paint = state.paint(state.stroke, state.stroke_alpha);
+        let stroke = Stroke {
+            width: state.line_width,
+            line_cap: state.line_cap,
+            line_join: state.line_join,
+            miter_limit: state.miter_limit,
+            ..Default::default()
+        };
+        self.pixmap
+            .stroke_path(path, &paint, &stroke, state.ctm, state.clip.as_deref());
+    }
+
+    fn clip(&mut self, path: &Path, rule: FillRule, state: &mut GraphicsState) {
+        let mask = match &state.clip {
+            Some(existing) => {
+                let mut mask = (**existing).clone();
+                mask.intersect_path(path, rule, true, state.ctm);
+                mask
+            }
+            None => {
+                let mut mask = match Mask::new(self.pixmap.width(), self.pixmap.height()) {
+                    Some(m) => m,
+                    None => return,
+                };
+                mask.fill_path(path, rule, true, state.ctm);
+                mask
+            }
+        };
+        state.clip = Some(Rc::new(mask));
+    }
+
+    fn draw_xobject(&mut self, resources: &Dictionary, name: &[u8], state: &GraphicsState) {
+        let stream = match self
+            .named_resource(resources, b"XObject", name)
+            .and_then(|o| o.as_stream().ok())
+        {
+            Some(s) => s,
+            None => return,
+        };
+
+        match stream.dict.get(b"Subtype").and_then(Object::as_name).unwrap_or(b"") {
+            b"Image" => self.draw_image(stream, resources, state),
+            b"Form" => {
+                // Guard against forms that (directly or not) draw themselves
+                if self.depth > 16 {
+                    return;
+                }
+                let content = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+                let form_resources = stream
+                    .dict
+                    .get_deref(b"Resources", self.doc)
+                    .and_then(Object::as_dict)
+                    .cloned()
+                    .unwrap_or_else(|_| resources.clone());
+                let mut form_state = state.clone();
+                if let Ok(matrix) = stream.dict.get(b"Matrix").and_then(Object::as_array) {
+                    let m: Vec<f32> = matrix.iter().map(number).collect();
+                    if m.len() == 6 {
+                        form_state.ctm = form_state
+                            .ctm
+                            .pre_concat(Transform::from_row(m[0], m[1], m[2], m[3], m[4], m[5]));
+                    }
+                }
+                self.depth += 1;
+                self.run(&content, &form_resources, form_state);
+                self.depth -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    fn draw_image(&mut self, stream: &Stream, resources: &Dictionary, state: &GraphicsState) {
+        let image = match decode_image_xobject(self.doc, stream, resources, state.fill) {
+            Some(img) => img,
+            None => {
+                let kind = image_stream_data(stream)
+                    .and_then(|(_, filter)| filter)
+                    .map(|filter| format!("{} images", filter.trim_end_matches("Decode")))
+                    .unwrap_or_else(|| "undecodable images".to_string());
+                self.skipped.insert(kind);
+                return;
+            }
+        };
+        let (width, height) = (image.width(), image.height());
+
+        // Images occupy the unit square; row 0 is the top edge
+        let transform = state
+            .ctm
+            .pre_concat(Transform::from_row(1.0 / width as f32, 0.0, 0.0, -1.0 / height as f32, 0.0, 1.0));
+        let paint = PixmapPaint {
+            opacity: state.fill_alpha,
+            quality: tiny_skia::FilterQuality::Bilinear,
+            ..Default::default()
+        };
+        self.pixmap
+            .draw_pixmap(0, 0, image.as_ref(), &paint, transform, state.clip.as_deref());
+    }
+
+    fn load_font(&mut self, resources: &Dictionary, name: &[u8]) -> Option<Rc<PdfFont>> {
+        let doc = self.doc;
+        let group = resources.get(b"Font").ok()?;
+        let (group_id, group) = doc.dereference(group).ok()?;
+        let font_ref = group.as_dict().ok()?.get(name).ok()?;
+        let (font_id, font_object) = doc.dereference(font_ref).ok()?;
+        let font_dict = font_object.as_dict().ok()?;
+
+        // Cache by object id where there is one, else by resource path
+        let key = match font_id.or(group_id) {
+            Some((num, gen)) => format!("{}:{}:{}", num, gen, String::from_utf8_lossy(name)).into_bytes(),
+            None => name.to_vec(),
+        };
+        if let Some(font) = self.fonts.get(&key) {
+            return Some(font.clone());
+        }
+
+        let font = Rc::new(self.build_font(font_dict));
+        self.fonts.insert(key, font.clone());
+        Some(font)
+    }
+
+    fn build_font(&mut self, font: &Dictionary) -> PdfFont {
+        let doc = self.doc;
+        let subtype = font.get(b"Subtype").and_then(Object::as_name).unwrap_or(b"");
+        let composite = subtype == b"Type0";
+        if subtype == b"Type3" {
+            self.skipped.insert("Type 3 fonts".to_string());
+        }
+
+        let descendant = if composite {
+            font.get_deref(b"DescendantFonts", doc)
+                .and_then(Object::as_array)
+                .ok()
+                .and_then(|a| a.first())
+                .and_then(|o| doc.dereference(o).ok())
+                .and_then(|(_, o)| o.as_dict().ok())
+        } else {
+            None
+        };
+        let metrics_dict = descendant.unwrap_or(font);
+        let descriptor = metrics_dict
+            .get_deref(b"FontDescriptor", doc)
+            .and_then(Object::as_dict)
+            .ok();
+
+        let mut widths = HashMap::new();
+        // Zero means "use the advance from the glyph outline font"
+        let default_width = if composite {
+            metrics_dict.get(b"DW").map(number).unwrap_or(1000.0)
+        } else {
+            descriptor
+                .and_then(|d| d.get(b"MissingWidth").ok())
+                .map(number)
+                .unwrap_or(0.0)
+        };
+        if composite {
+            if let Ok(w) = metrics_dict.get_deref(b"W", doc).and_then(Object::as_array) {
+                // CIDs beyond 0xFFFF can't map to a glyph id, so ranges are
+                // clamped there rather than trusting the file's bounds
+                let cid = |o: &Object| number(o).clamp(0.0, MAX_CID as f32) as u32;
+                let mut i = 0;
+                while i < w.len() {
+                    let first = cid(&w[i]);
+                    match w.get(i + 1).and_then(|o| doc.dereference(o).ok()).map(|(_, o)| o) {
+                        Some(Object::Array(list)) => {
+                            for (offset, width) in list.iter().enumerate() {
+                                match u32::try_from(offset).ok().and_then(|o| first.checked_add(o)) {
+                                    Some(code) if code <= MAX_CID => widths.insert(code, number(width)),
+                                    _ => break,
+                                };
+                            }
+                            i += 2;
+                        }
+                        Some(last) => {
+                            let width = w.get(i + 2).map(number).unwrap_or(default_width);
+                            for code in first..=cid(last) {
+                                widths.insert(code, width);
+                            }
+                            i += 3;
+                        }
+                        None => break,
+                    }
+                }
+            }
+        } else {
+            let first_char = font.get(b"FirstChar").map(number).unwrap_or(0.0) as u32;
+            if let Ok(w) = font.get_deref(b"Widths", doc).and_then(Object::as_array) {
+                for (i, width) in w.iter().enumerate() {
+                    let width = doc.dereference(width).map(|(_, o)| number(o)).unwrap_or(0.0);
+                    widths.insert(first_char + i as u32, width);
+                }
+            }
+        }
+
+        let mut differences = HashMap::new();
+        if let Ok(encoding) = font.get_deref(b"Encoding", doc).and_then(Object::as_dict) {
+            if let Ok(diffs) = encoding.get(b"Differences").and_then(Object::as_array) {
+                let mut code = 0u32;
+                for item in diffs {
+                    match item {
+                        Object::Integer(n) => code = *n as u32,
+                        Object::Name(glyph) => {
+                            differences.insert(code, String::from_utf8_lossy(glyph).to_string());
+                            code += 1;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let font_file = |key: &[u8]| {
+            descriptor
+                .and_then(|d| d.get_deref(key, doc).ok())
+                .and_then(|o| o.as_stream().ok())
+                .map(|s| s.decompressed_content().unwrap_or_else(|_| s.content.clone()))
+        };
+        let source = if let Some(data) = font_file(b"FontFile2") {
+            GlyphSource::TrueType(data, 0)
+        } else if let Some(data) = font_file(b"FontFile3") {
+            // Bare CFF, or an OpenType wrapper around one
+            if ttf_parser::Face::parse(&data, 0).is_ok() {
+                GlyphSource::TrueType(data, 0)
+            } else {
+                GlyphSource::Cff(data)
+            }
+        } else {
+            self.system_font(font, descriptor)
+        };
+
+        let cid_to_gid = descendant
+            .and_then(|d| d.get_deref(b"CIDToGIDMap", doc).ok())
+            .and_then(|o| o.as_stream().ok())
+            .map(|s| {
+                let data = s.decompressed_content().unwrap_or_else(|_| s.content.clone());
+                data.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect()
+            });
+
+        let mut cff_cid_map = HashMap::new();
+        if let GlyphSource::Cff(data) = &source {
+            if let Some(table) = ttf_parser::cff::Table::parse(data) {
+                for gid in 0..table.number_of_glyphs() {
+                    if let Some(cid) = table.glyph_cid(ttf_parser::GlyphId(gid)) {
+                        cff_cid_map.insert(cid, gid);
+                    }
+                }
+            }
+        }
+
+        let flags = descriptor
+            .and_then(|d| d.get(b"Flags").ok())
+            .and_then(|o| o.as_i64().ok())
+            .unwrap_or(0);
+
+        // Simple fonts are drawn from system fonts by character, so resolve
+        // every code through the font's encoding up front
+        let mut unicode = HashMap::new();
+        if !composite {
+            if let Ok(encoding) = font.get_font_encoding(doc) {
+                for code in 0u32..256 {
+                    let c = differences
+                        .get(&code)
+                        .and_then(|name| glyph_name_to_char(name))
+                        .or_else(|| {
+                            encoding
+                                .bytes_to_string(&[code as u8])
+                                .ok()
+                                .and_then(|s| s.chars().next())
+                                .filter(|c| *c != '\u{FFFD}')
+                        });
+                    unicode.insert(code, c);
+                }
+            }
+        }
+
+        PdfFont {
+            composite,
+            source,
+            differences,
+            widths,
+            default_width,
+            cid_to_gid,
+            cff_cid_map,
+            symbolic: flags & 4 != 0,
+            unicode,
+            outlines: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Pick a system font resembling a non-embedded PDF font
+    fn system_font(&mut self, font: &Dictionary, descriptor: Option<&Dictionary>) -> GlyphSource {
+        let db = self
+            .system_fonts
+            .get_or_insert_with(|| {
+                let mut db = fontdb::Database::new();
+                db.load_system_fonts();
+                Rc::new(db)
+            })
+            .clone();
+
+        let base = font
+            .get(b"BaseFont")
+            .and_then(Object::as_name)
+            .map(|n| String::from_utf8_lossy(n).to_string())
+            .unwrap_or_default();
+        let flags = descriptor
+            .and_then(|d| d.get(b"Flags").ok())
+            .and_then(|o| o.as_i64().ok())
+            .unwrap_or(0);
+
+        let id = match find_system_font(&db, &base, flags) {
+            Some(id) => id,
+            None => return GlyphSource::None,
+        };
+        db.with_face_data(id, |data, index| GlyphSource::TrueType(data.to_vec(), index))
+            .unwrap_or(GlyphSource::None)
+    }
+
+    fn show_text(&mut self, bytes: &[u8], state: &GraphicsState, text_matrix: &mut Transform) {
+        let font = match &state.font {
+            Some(f) => f.clone(),
+            None => return,
+        };
+
+        let codes: Vec<u32> = if font.composite {
+            bytes
+                .chunks(2)
+                .map(|c| c.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+                .collect()
+        } else {
+            bytes.iter().map(|b| *b as u32).collect()
+        };
+
+        let paint = state.paint(state.fill, state.fill_alpha);
+        let visible = !matches!(state.render_mode, 3 | 7);
+
+        for code in codes {
+            if visible {
+                if let Some((glyph, units_per_em)) = font.outline(code) {
+                    let glyph_scale = 1.0 / units_per_em;
+                    let transform = state
+                        .ctm
+                        .pre_concat(*text_matrix)
+                        .pre_concat(Transform::from_row(
+                            state.font_size * state.horizontal_scale,
+                            0.0,
+                            0.0,
+                            state.font_size,
+                            0.0,
+                            state.rise,
+                        ))
+                        .pre_concat(Transform::from_scale(glyph_scale, glyph_scale));
+                    self.pixmap
+                        .fill_path(&glyph, &paint, FillRule::Winding, transform, state.clip.as_deref());
+                } else if matches!(font.source, GlyphSource::None) && !font.is_blank(code) {
+                    // No embedded or system font to draw with; fail so auto falls back to pdftoppm
+                    self.skipped.insert("text in fonts that couldn't be loaded".to_string());
+                }
+            }
+
+            let width = match font.widths.get(&code).copied().unwrap_or(font.default_width) {
+                w if w > 0.0 => w / 1000.0,
+                _ => font.advance(code),
+            };
+            let word_spacing = if !font.composite && code == 32 { state.word_spacing } else { 0.0 };
+            let advance = (width * state.font_size + state.char_spacing + word_spacing) * state.horizontal_scale;
+            *text_matrix = text_matrix.pre_concat(Transform::from_translate(advance, 0.0));
+        }
+    }
+}
+
+impl PdfFont {
+    /// Whether a code shows as white space, which has no outline to draw
+    fn is_blank(&self, code: u32) -> bool {
+        match self.unicode.get(&code) {
+            Some(Some(c)) => c.is_whitespace(),
+            Some(None) => false,
+            None => !self.composite && code == 32,
+        }
+    }
+
+    /// Advance width from the glyph font itself, as a fraction of the em
+    fn advance(&self, code: u32) -> f32 {
+        if let GlyphSource::TrueType(data, index) = &self.source {
+            if let Ok(face) = ttf_parser::Face::parse(data, *index) {
+                if let Some(gid) = self.simple_truetype_glyph(&face, code) {
+                    let advance = face.glyph_hor_advance(gid).unwrap_or(0) as f32;
+                    return advance / face.units_per_em() as f32;
+                }
+            }
+        }
+        0.5
+    }
+
+    /// Glyph outline in font units, plus the font's units per em
+    fn outline(&self, code: u32) -> Option<(Path, f32)> {
+        if let Some(cached) = self.outlines.borrow().get(&code) {
+            return cached.clone();
+        }
+
+        let mut builder = GlyphPath(PathBuilder::new());
+        let result = match &self.source {
+            GlyphSource::TrueType(data, index) => ttf_parser::Face::parse(data, *index).ok().and_then(|face| {
+                let gid = if self.composite {
+                    match &self.cid_to_gid {
+                        Some(map) => map.get(code as usize).map(|g| ttf_parser::GlyphId(*g)),
+                        None => Some(ttf_parser::GlyphId(code as u16)),
+                    }
+                } else {
+                    self.simple_truetype_glyph(&face, code)
+                }?;
+                face.outline_glyph(gid, &mut builder)?;
+                Some(face.units_per_em() as f32)
+            }),
+            GlyphSource::Cff(data) => ttf_parser::cff::Table::parse(data).and_then(|table| {
+                let gid = if self.composite {
+                    self.cff_cid_map
+                        .get(&(code as u16))
+                        .copied()
+                        .map(ttf_parser::GlyphId)
+                        .or(Some(ttf_parser::GlyphId(code as u16)))
+                } else {
+                    self.differences
+                        .get(&code)
+                        .and_then(|name| table.glyph_index_by_name(name))
+                        .or_else(|| table.glyph_index(code as u8))
+                }?;
+                table.outline(gid, &mut builder).ok()?;
+                // CFF matrices are almost always the 1/1000 default
+                let units = 1.0 / table.matrix().sx.abs().max(f32::EPSILON);
+                Some(units)
+            }),
+            GlyphSource::None => None,
+        };
+
+        let outline = result.and_then(|units| builder.0.finish().map(|p| (p, units)));
+        self.outlines.borrow_mut().insert(code, outline.clone());
+        outline
+    }
+
+    fn simple_truetype_glyph(&self, face: &ttf_parser::Face, code: u32) -> Option<ttf_parser::GlyphId> {
+        if let Some(name) = self.differences.get(&code) {
+            if let Some(gid) = face.glyph_index_by_name(name) {
+                return Some(gid);
+            }
+        }
+
+        // Symbolic fonts map codes through a (3,0) cmap, usually offset into the private use area
+        if self.symbolic {
+            if let Some(cmap) = face.tables().cmap {
+                for subtable in cmap.subtables {
+                    if subtable.platform_id == ttf_parser::PlatformId::Windows && subtable.encoding_id == 0 {
+                        if let Some(gid) = subtable
+                            .glyph_index(0xF000 + code)
+                            .or_else(|| subtable.glyph_index(code))
+                        {
+                            return Some(gid);
+                        }
+                    }
+                    if subtable.platform_id == ttf_parser::PlatformId::Macintosh {
+                        if let Some(gid) = subtable.glyph_index(code) {
+                            return Some(gid);
+                        }
+                    }
+                }
+            }
+        }
+
+        let c = self.unicode.get(&code).copied().unwrap_or_else(|| char::from_u32(code))?;
+        face.glyph_index(c)
+    }
+}
+
+struct GlyphPath(PathBuilder);
+
+impl ttf_parser::OutlineBuilder for GlyphPath {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.0.move_to(x, y);
+    }
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.0.line_to(x, y);
+    }
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        self.0.quad_to(x1, y1, x, y);
+    }
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        self.0.cubic_to(x1, y1, x2, y2, x, y);
+    }
+    fn close(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Pick the system font closest to a PDF base font name and descriptor
+/// flags: monospace, serif or sans, with matching weight and style
+pub fn find_system_font(db: &fontdb::Database, base_font: &str, flags: i64) -> Option<fontdb::ID> {
+    let base = base_font.to_lowercase();
+    let serif = base.contains("times") || base.contains("serif") && !base.contains("sans") || flags & 2 != 0;
+    let mono = base.contains("courier") || base.contains("mono") || flags & 1 != 0;
+    let families: Vec<fontdb::Family> = if mono {
+        vec![
+            fontdb::Family::Name("Courier New"),
+            fontdb::Family::Name("DejaVu Sans Mono"),
+            fontdb::Family::Name("Liberation Mono"),
+            fontdb::Family::Monospace,
+        ]
+    } else if serif {
+        vec![
+            fontdb::Family::Name("Times New Roman"),
+            fontdb::Family::Name("DejaVu Serif"),
+            fontdb::Family::Name("Liberation Serif"),
+            fontdb::Family::Serif,
+        ]
+    } else {
+        vec![
+            fontdb::Family::Name("Arial"),
+            fontdb::Family::Name("Helvetica"),
+            fontdb::Family::Name("DejaVu Sans"),
+            fontdb::Family::Name("Liberation Sans"),
+            fontdb::Family::SansSerif,
+        ]
+    };
+    let query = fontdb::Query {
+        families: &families,
+        weight: if base.contains("bold") { fontdb::Weight::BOLD } else { fontdb::Weight::NORMAL },
+        style: if base.contains("italic") || base.contains("oblique") {
+            fontdb::Style::Italic
+        } else {
+            fontdb::Style::Normal
+        },
+        ..Default::default()
+    };
+
+    db.query(&query).or_else(|| db.faces().next().map(|f| f.id))
+}
+
+/// Map the glyph names that commonly appear in /Differences to characters
+pub fn glyph_name_to_char(name: &str) -> Option<char> {
+    if name.chars().count() == 1 {
+        return name.chars().next();
+    }
+    if let Some(hex) = name.strip_prefix("uni").filter(|h| h.len() == 4) {
+        return u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+    }
+    if let Some(hex) = name.strip_prefix('u').filter(|h| (4..=6).contains(&h.len())) {
+        if let Ok(value) = u32::from_str_radix(hex, 16) {
+            return char::from_u32(value);
+        }
+    }
+    let c = match name {
+        "space" => ' ',
+        "exclam" => '!',
+        "quotedbl" => '"',
+        "numbersign" => '#',
+        "dollar" => '$',
+        "percent" => '%',
+        "ampersand" => '&',
+        "quotesingle" | "quoteright" => '\'',
+        "parenleft" => '(',
+        "parenright" => ')',
+        "asterisk" => '*',
+        "plus" => '+',
+        "comma" => ',',
+        "hyphen" | "minus" => '-',
+        "period" => '.',
+        "slash" => '/',
+        "zero" => '0',
+        "one" => '1',
+        "two" => '2',
+        "three" => '3',
+        "four" => '4',
+        "five" => '5',
+        "six" => '6',
+        "seven" => '7',
+        "eight" => '8',
+        "nine" => '9',
+        "colon" => ':',
+        "semicolon" => ';',
+        "less" => '<',
+        "equal" => '=',
+        "greater" => '>',
+        "question" => '?',
+        "at" => '@',
+        "bracketleft" => '[',
+        "backslash" => '\\',
+        "bracketright" => ']',
+        "underscore" => '_',
+        "quoteleft" => '‘',
+        "braceleft" => '{',
+        "bar" => '|',
+        "braceright" => '}',
+        "bullet" => '•',
+        "endash" => '–',
+        "emdash" => '—',
+        "quotedblleft" => '“',
+        "quotedblright" => '”',
+        "ellipsis" => '…',
+        "fi" => 'ﬁ',
+        "fl" => 'ﬂ',
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// Decode an image XObject on its own, outside of any page content.
+/// Stencil masks come out black.
+pub fn decode_pdf_image(doc: &Document, stream: &Stream, resources: &Dictionary) -> Option<Pixmap> {
+    decode_image_xobject(doc, stream, resources, [0.0; 3])
+}
+
+/// Decode an image XObject to a premultiplied pixmap, or None when the
+/// encoding isn't supported
+fn decode_image_xobject(doc: &Document, stream: &Stream, resources: &Dictionary, fill: [f32; 3]) -> Option<Pixmap> {
+    let dict = &stream.dict;
+    let width = u32::try_from(dict.get(b"Width").and_then(Object::as_i64).ok()?).ok()?;
+    let height = u32::try_from(dict.get(b"Height").and_then(Object::as_i64).ok()?).ok()?;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    // Refuse sizes whose RGBA buffer would be absurd before allocating it
+    let buffer_len = (width as usize).checked_mul(height as usize)?.checked_mul(4)?;
+    if buffer_len > MAX_IMAGE_BYTES {
+        return None;
+    }
+
+    let (data, filter) = image_stream_data(stream)?;
+    let stencil = dict.get(b"ImageMask").and_then(Object::as_bool).unwrap_or(false);
+
+    let mut rgba: Vec<u8> = if filter.as_deref() == Some("DCTDecode") {
+        let img = image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg).ok()?;
+        let mut rgba = img.to_rgba8();
+        if rgba.width() != width || rgba.height() != height {
+            rgba = image::imageops::resize(&rgba, width, height, image::imageops::FilterType::Triangle);
+        }
+        rgba.into_raw()
+    } else if filter.is_some() {
+        // JPX, CCITT and JBIG2 need dedicated decoders
+        return None;
+    } else if stencil {
+        // 1-bit stencil: painted samples take the current fill color
+        let invert = dict
+            .get(b"Decode")
+            .and_then(Object::as_array)
+            .map(|d| d.first().map(number).unwrap_or(0.0) > 0.5)
+            .unwrap_or(false);
+        let row_bytes = width.div_ceil(8) as usize;
+        let mut out = Vec::with_capacity(buffer_len);
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                let byte = data.get(y * row_bytes + x / 8).copied().unwrap_or(0xff);
+                let bit = (byte >> (7 - x % 8)) & 1;
+                let painted = (bit == 0) != invert;
+                out.extend_from_slice(&[
+                    (fill[0] * 255.0) as u8,
+                    (fill[1] * 255.0) as u8,
+                    (fill[2] * 255.0) as u8,
+                    if painted { 255 } else { 0 },
+                ]);
+            }
+        }
+        out
+    } else {
+        let bpc = dict.get(b"BitsPerComponent").and_then(Object::as_i64).unwrap_or(8) as usize;
+        let space = dict
+            .get(b"ColorSpace")
+            .map(|cs| resolve_color_space(doc, cs, resources))
+            .unwrap_or(ColorSpace::Gray);
+        let components = space.components();
+        let row_bits = width as usize * components * bpc;
+        let row_bytes = row_bits.div_ceil(8);
+        let max = ((1u32 << bpc.min(16)) - 1) as f32;
+
+        let sample = |row: &[u8], index: usize| -> f32 {
+            match bpc {
+                8 => row.get(index).copied().unwrap_or(0) as f32,
+                16 => {
+                    let hi = row.get(index * 2).copied().unwrap_or(0) as u32;
+                    let lo = row.get(index * 2 + 1).copied().unwrap_or(0) as u32;
+                    ((hi << 8) | lo) as f32
+                }
+                1 | 2 | 4 => {
+                    let bit = index * bpc;
+                    let byte = row.get(bit / 8).copied().unwrap_or(0);
+                    ((byte >> (8 - bpc - bit % 8)) & ((1 << bpc) - 1) as u8) as f32
+                }
+                _ => 0.0,
+            }
+        };
+
+        let indexed = matches!(space, ColorSpace::Indexed(..));
+        let mut out = Vec::with_capacity(buffer_len);
+        let mut values = vec![0.0f32; components];
+        for y in 0..height as usize {
+            let row = data.get(y * row_bytes..).unwrap_or(&[]);
+            for x in 0..width as usize {
+                for (c, value) in values.iter_mut().enumerate() {
+                    let raw = sample(row, x * components + c);
+                    *value = if indexed { raw } else { raw / max };
+                }
+                let rgb = space.to_rgb(&values);
+                out.extend_from_slice(&[
+                    (rgb[0] * 255.0) as u8,
+                    (rgb[1] * 255.0) as u8,
+                    (rgb[2] * 255.0) as u8,
+                    255,
+                ]);
+            }
+        }
+        out
+    };
+
+    // Soft masks supply per-pixel alpha as a grayscale image
+    if let Some(mask) = dict
+        .get_deref(b"SMask", doc)
+        .ok()
+        .and_then(|o| o.as_stream().ok())
+        .and_then(|s| decode_image_xobject(doc, s, resources, [0.0; 3]))
+    {
+        let mask = if mask.width() != width || mask.height() != height {
+            let img = image::RgbaImage::from_raw(mask.width(), mask.height(), mask.data().to_vec())?;
+            image::imageops::resize(&img, width, height, image::imageops::FilterType::Triangle).into_raw()
+        } else {
+            mask.data().to_vec()
+        };
+        for (pixel, alpha) in rgba.chunks_exact_mut(4).zip(mask.chunks_exact(4)) {
+            pixel[3] = alpha[0];
+        }
+    }
+
+    // tiny-skia wants premultiplied alpha
+    for pixel in rgba.chunks_exact_mut(4) {
+        let a = pixel[3] as u32;
+        if a < 255 {
+            for c in &mut pixel[..3] {
+                *c = (*c as u32 * a / 255) as u8;
+            }
+        }
+    }
+
+    Pixmap::from_vec(rgba, tiny_skia::IntSize::from_wh(width, height)?)
+}
+
+/// Undo the generic stream filters, stopping before an image codec filter.
+/// Returns the data and the remaining codec filter name, if any.
+pub fn image_stream_data(stream: &Stream) -> Option<(Vec<u8>, Option<String>)> {
+    let filters = stream.filters().unwrap_or_default();
+    let codec_at = filters
+        .iter()
+        .position(|f| matches!(f.as_str(), "DCTDecode" | "JPXDecode" | "CCITTFaxDecode" | "JBIG2Decode"));
+    let (generic, codec) = match codec_at {
+        Some(i) => (&filters[..i], Some(filters[i].clone())),
+        None => (&filters[..], None),
+    };
+
+    if generic.is_empty() {
+        return Some((stream.content.clone(), codec));
+    }
+
+    // lopdf refuses to decompress image streams directly, so decode a copy
+    // that only carries the generic filters
+    let mut plain = stream.clone();
+    plain.dict.remove(b"Subtype");
+    plain.dict.set(
+        "Filter",
+        Object::Array(generic.iter().map(|f| Object::Name(f.as_bytes().to_vec())).collect()),
+    );
+    plain.decompressed_content().ok().map(|data| (data, codec))
+}
+
+/// Remove inline images (BI ... ID <binary> EI), which the content parser can't handle
+pub fn strip_inline_images(content: &[u8]) -> std::borrow::Cow<'_, [u8]> {
+    let is_delimiter = |b: Option<&u8>| b.map(|b| b.is_ascii_whitespace()).unwrap_or(true);
+    let find_token = |from: usize, token: &[u8]| {
+        (from..content.len().saturating_sub(token.len() - 1)).find(|&i| {
+            &content[i..i + token.len()] == token
+                && (i == 0 || is_delimiter(content.get(i - 1)))
+                && is_delimiter(content.get(i + token.len()))
+        })
+    };
+
+    let mut start = match find_token(0, b"BI") {
+        Some(i) => i,
+        None => return std::borrow::Cow::Borrowed(content),
+    };
+
+    let mut out = Vec::with_capacity(content.len());
+    let mut copied = 0;
+    loop {
+        out.extend_from_slice(&content[copied..start]);
+        let end = find_token(start, b"ID")
+            .and_then(|id| find_token(id + 3, b"EI"))
+            .map(|ei| ei + 2)
+            .unwrap_or(content.len());
+        copied = end;
+        match find_token(end, b"BI") {
+            Some(next) => start = next,
+            None => break,
+        }
+    }
+    out.extend_from_slice(&content[copied.min(content.len())..]);
+    std::borrow::Cow::Owned(out)
+}
@@ -0,0 +1,832 @@
+use aes::cipher::block_padding::{NoPadding, Pkcs7};
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, StringFormat};
+use md5::{Digest, Md5};
+use sha2::{Sha256, Sha384, Sha512};
+use crate::ConversionResult;
+use crate::converters::pdf_tools::{finish, load_pdf, save_pdf};
+
+/// Padding string from the PDF standard security handler (revisions 2-4)
+const PASSWORD_PADDING: [u8; 32] = [
+    0x28, 0xBF, 0x4E, 0x5E, 0x4E, 0x75, 0x8A, 0x41, 0x64, 0x00, 0x4E, 0x56, 0xFF, 0xFA, 0x01, 0x08,
+    0x2E, 0x2E, 0x00, 0xB6, 0xD0, 0x68, 0x3E, 0x80, 0x2F, 0x0C, 0xA9, 0xFE, 0x64, 0x53, 0x69, 0x7A,
+];
+
+/// Object streams are renamed while loading so lopdf keeps them intact
+/// until they have been decrypted
+const HIDDEN_OBJECT_STREAM: &str = "FileFlowEncryptedObjStm";
+
+/// Password protection to apply to a PDF
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PdfProtectOptions {
+    /// Needed to open the document. Leave empty to only restrict permissions
+    pub user_password: Option<String>,
+    /// Needed to change permissions. Defaults to a random password, so the
+    /// permissions can't be lifted
+    pub owner_password: Option<String>,
+    /// "aes256" (default) or "aes128"
+    pub encryption: Option<String>,
+    pub allow_print: Option<bool>,
+    pub allow_copy: Option<bool>,
+    pub allow_modify: Option<bool>,
+    pub allow_annotate: Option<bool>,
+    pub allow_fill_forms: Option<bool>,
+}
+
+/// How an encrypted PDF is protected
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PdfSecurity {
+    /// "RC4 40-bit", "RC4 128-bit", "AES-128" or "AES-256"
+    pub method: String,
+    /// Whether the document opens without a password
+    pub requires_password: bool,
+    pub allow_print: bool,
+    pub allow_copy: bool,
+    pub allow_modify: bool,
+    pub allow_annotate: bool,
+    pub allow_fill_forms: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CryptMethod {
+    Identity,
+    Rc4,
+    Aes128,
+    Aes256,
+}
+
+/// A file key plus the algorithms used for strings and streams
+struct Crypt {
+    key: Vec<u8>,
+    strings: CryptMethod,
+    streams: CryptMethod,
+}
+
+impl Crypt {
+    /// The key for one object: revisions 2-4 mix in the object number
+    fn object_key(&self, id: ObjectId, method: CryptMethod) -> Vec<u8> {
+        if method == CryptMethod::Aes256 {
+            return self.key.clone();
+        }
+        let mut hasher = Md5::new();
+        hasher.update(&self.key);
+        hasher.update(&id.0.to_le_bytes()[..3]);
+        hasher.update(id.1.to_le_bytes());
+        if method == CryptMethod::Aes128 {
+            hasher.update(b"sAlT");
+        }
+        let digest = hasher.finalize();
+        digest[..(self.key.len() + 5).min(16)].to_vec()
+    }
+
+    fn decrypt(&self, id: ObjectId, method: CryptMethod, data: &[u8]) -> Option<Vec<u8>> {
+        let key = self.object_key(id, method);
+        match method {
+            CryptMethod::Identity => None,
+            CryptMethod::Rc4 => Some(rc4(&key, data)),
+            CryptMethod::Aes128 | CryptMethod::Aes256 => {
+                if data.is_empty() {
+                    return Some(Vec::new());
+                }
+                if data.len() < 32 || !data.len().is_multiple_of(16) {
+                    return None;
+                }
+                let (iv, body) = data.split_at(16);
+                aes_cbc_decrypt(&key, iv, body)
+            }
+        }
+    }
+
+    fn encrypt(&self, id: ObjectId, method: CryptMethod, data: &[u8]) -> Vec<u8> {
+        let key = self.object_key(id, method);
+        match method {
+            CryptMethod::Identity => data.to_vec(),
+            CryptMethod::Rc4 => rc4(&key, data),
+            CryptMethod::Aes128 | CryptMethod::Aes256 => {
+                let iv = random_bytes(16);
+                let mut out = iv.clone();
+                out.extend(aes_cbc_encrypt(&key, &iv, data, true));
+                out
+            }
+        }
+    }
+}
+
+/// Open a PDF, decrypting it if it's password protected. Without a password,
+/// documents that open with an empty user password are still readable.
+pub fn load_pdf_with_password(input_path: &str, password: Option<&str>) -> Result<Document, String> {
+    open_pdf(input_path, password).map(|(doc, _)| doc)
+}
+
+/// Like `load_pdf_with_password`, also describing the protection that was removed
+pub fn open_pdf(input_path: &str, password: Option<&str>) -> Result<(Document, Option<PdfSecurity>), String> {
+    let mut candidates: Vec<&str> = password.into_iter().collect();
+    candidates.push("");
+    open_with_candidates(input_path, &candidates)
+}
+
+fn open_with_candidates(input_path: &str, candidates: &[&str]) -> Result<(Document, Option<PdfSecurity>), String> {
+    let doc = Document::load(input_path).map_err(|e| format!("Failed to read PDF: {}", e))?;
+    let encrypt = match doc.get_encrypted() {
+        Ok(dict) => dict.clone(),
+        Err(_) => return Ok((doc, None)),
+    };
+    let file_id = document_id(&doc);
+
+    let mut crypt = None;
+    for password in candidates {
+        if let Some(found) = authenticate(&encrypt, &file_id, password.as_bytes())? {
+            crypt = Some(found);
+            break;
+        }
+    }
+    let crypt = match crypt {
+        Some(c) => c,
+        None if candidates.iter().any(|p| !p.is_empty()) => return Err("The password is incorrect".to_string()),
+        None => return Err("This PDF is password protected. Enter its password to open it".to_string()),
+    };
+    let security = describe_security(&encrypt, &file_id);
+
+    // Load again with object streams set aside, since lopdf can't parse them encrypted
+    let mut doc = Document::load_filtered(input_path, hide_object_stream)
+        .map_err(|e| format!("Failed to read PDF: {}", e))?;
+    let encrypt_id = doc.trailer.get(b"Encrypt").and_then(Object::as_reference).ok();
+    let encrypt_metadata = encrypt.get(b"EncryptMetadata").and_then(Object::as_bool).unwrap_or(true);
+
+    let mut object_streams = Vec::new();
+    for (&id, object) in doc.objects.iter_mut() {
+        if Some(id) == encrypt_id {
+            continue;
+        }
+        match object {
+            Object::Stream(stream) => {
+                let skip = stream.dict.type_is(b"XRef") || (!encrypt_metadata && stream.dict.type_is(b"Metadata"));
+                if !skip {
+                    if let Some(plain) = crypt.decrypt(id, crypt.streams, &stream.content) {
+                        stream.set_content(plain);
+                    }
+                    decrypt_strings_in_dictionary(&crypt, id, &mut stream.dict);
+                }
+                if stream.dict.type_is(HIDDEN_OBJECT_STREAM.as_bytes()) {
+                    stream.dict.set("Type", "ObjStm");
+                    object_streams.push(id);
+                }
+            }
+            other => decrypt_strings(&crypt, id, other),
+        }
+    }
+
+    // Objects stored inside object streams aren't encrypted individually
+    for id in object_streams {
+        if let Some(Object::Stream(mut stream)) = doc.objects.remove(&id) {
+            if let Ok(contents) = lopdf::ObjectStream::new(&mut stream) {
+                for (inner_id, object) in contents.objects {
+                    doc.objects.entry(inner_id).or_insert(object);
+                }
+            }
+        }
+    }
+
+    doc.trailer.remove(b"Encrypt");
+    if let Some(id) = encrypt_id {
+        doc.objects.remove(&id);
+    }
+    Ok((doc, Some(security)))
+}
+
+/// Load filter: lopdf keeps the object as edited here and only looks at
+/// whether something is returned
+fn hide_object_stream(id: ObjectId, object: &mut Object) -> Option<(ObjectId, Object)> {
+    if let Object::Stream(stream) = object {
+        if stream.dict.type_is(b"ObjStm") {
+            stream.dict.set("Type", HIDDEN_OBJECT_STREAM);
+        }
+    }
+    Some((id, Object::Null))
+}
+
+fn decrypt_strings(crypt: &Crypt, id: ObjectId, object: &mut Object) {
+    match object {
+        Object::String(bytes, _) => {
+            if let Some(plain) = crypt.decrypt(id, crypt.strings, bytes) {
+                *bytes = plain;
+            }
+        }
+        Object::Array(items) => {
+            for item in items {
+                decrypt_strings(crypt, id, item);
+            }
+        }
+        Object::Dictionary(dict) => decrypt_strings_in_dictionary(crypt, id, dict),
+        _ => {}
+    }
+}
+
+fn decrypt_strings_in_dictionary(crypt: &Crypt, id: ObjectId, dict: &mut Dictionary) {
+    // Signature contents are stored in the clear
+    let is_signature = dict.type_is(b"Sig");
+    for (key, value) in dict.iter_mut() {
+        if is_signature && key.as_slice() == b"Contents" {
+            continue;
+        }
+        decrypt_strings(crypt, id, value);
+    }
+}
+
+/// Remove password protection from a PDF, given its user or owner password
+pub fn decrypt_pdf(input_path: &str, output_path: &str, password: &str) -> ConversionResult {
+    finish(try_decrypt_pdf(input_path, output_path, password), output_path)
+}
+
+fn try_decrypt_pdf(input_path: &str, output_path: &str, password: &str) -> Result<String, String> {
+    let (mut doc, security) = open_with_candidates(input_path, &[password])?;
+    let security = security.ok_or_else(|| "This PDF isn't password protected".to_string())?;
+    save_pdf(&mut doc, output_path)?;
+    Ok(format!("Removed {} protection", security.method))
+}
+
+/// Encrypt a PDF with a user and/or owner password and permission flags
+pub fn protect_pdf(input_path: &str, output_path: &str, options: &PdfProtectOptions) -> ConversionResult {
+    finish(try_protect_pdf(input_path, output_path, options), output_path)
+}
+
+fn try_protect_pdf(input_path: &str, output_path: &str, options: &PdfProtectOptions) -> Result<String, String> {
+    let mut doc = load_pdf(input_path)?;
+    let method = encrypt_document(&mut doc, options)?;
+    save_pdf(&mut doc, output_path)?;
+    Ok(format!("Protected with {}", method))
+}
+
+/// Encrypt every string and stream of a document in place and install the
+/// security handler. Returns the method used, e.g. "AES-256".
+pub fn encrypt_document(doc: &mut Document, options: &PdfProtectOptions) -> Result<String, String> {
+    if doc.is_encrypted() {
+        return Err("The PDF is already encrypted".to_string());
+    }
+    let user = options.user_password.clone().unwrap_or_default();
+    let owner = match options.owner_password.as_deref() {
+        Some(owner) if !owner.is_empty() => owner.to_string(),
+        _ => random_bytes(16).iter().map(|b| format!("{:02x}", b)).collect(),
+    };
+    let permissions = permission_bits(options);
+
+    // Both handlers need a file identifier
+    let file_id = match document_id(doc) {
+        id if !id.is_empty() => id,
+        _ => {
+            let id = random_bytes(16);
+            doc.trailer.set(
+                "ID",
+                Object::Array(vec![
+                    Object::String(id.clone(), StringFormat::Hexadecimal),
+                    Object::String(id.clone(), StringFormat::Hexadecimal),
+                ]),
+            );
+            id
+        }
+    };
+
+    let (encrypt, crypt, method) = match options.encryption.as_deref().unwrap_or("aes256") {
+        "aes256" => {
+            let (encrypt, key) = aes256_handler(user.as_bytes(), owner.as_bytes(), permissions);
+            let crypt = Crypt { key, strings: CryptMethod::Aes256, streams: CryptMethod::Aes256 };
+            // AES-256 is an extension to PDF 1.7 (standard in PDF 2.0)
+            if doc.version.as_str() < "1.7" {
+                doc.version = "1.7".to_string();
+            }
+            if let Ok(catalog) = doc.catalog_mut() {
+                catalog.set(
+                    "Extensions",
+                    dictionary! { "ADBE" => dictionary! { "BaseVersion" => Object::Name(b"1.7".to_vec()), "ExtensionLevel" => 8 } },
+                );
+            }
+            (encrypt, crypt, "AES-256")
+        }
+        "aes128" => {
+            let (encrypt, key) = aes128_handler(user.as_bytes(), owner.as_bytes(), permissions, &file_id);
+            let crypt = Crypt { key, strings: CryptMethod::Aes128, streams: CryptMethod::Aes128 };
+            if doc.version.as_str() < "1.6" {
+                doc.version = "1.6".to_string();
+            }
+            (encrypt, crypt, "AES-128")
+        }
+        other => return Err(format!("Unsupported encryption '{}'. Use aes256 or aes128", other)),
+    };
+
+    // The file is rewritten with a plain cross-reference table, so object
+    // and xref streams from the original are no longer used
+    doc.objects.retain(|_, o| !matches!(o, Object::Stream(s) if s.dict.type_is(b"XRef") || s.dict.type_is(b"ObjStm")));
+    for (&id, object) in doc.objects.iter_mut() {
+        encrypt_object(&crypt, id, object);
+    }
+    let encrypt_id = doc.add_object(encrypt);
+    doc.trailer.set("Encrypt", encrypt_id);
+    Ok(method.to_string())
+}
+
+fn encrypt_object(crypt: &Crypt, id: ObjectId, object: &mut Object) {
+    match object {
+        Object::String(bytes, format) => {
+            *bytes = crypt.encrypt(id, crypt.strings, bytes);
+            // Ciphertext is binary, so keep it out of literal strings
+            *format = StringFormat::Hexadecimal;
+        }
+        Object::Array(items) => {
+            for item in items {
+                encrypt_object(crypt, id, item);
+            }
+        }
+        Object::Dictionary(dict) => {
+            for (_, value) in dict.iter_mut() {
+                encrypt_object(crypt, id, value);
+            }
+        }
+        Object::Stream(stream) => {
+            let content = crypt.encrypt(id, crypt.streams, &stream.content);
+            stream.set_content(content);
+            // Compressing after encryption would change the bytes readers decrypt
+            stream.allows_compression = false;
+            for (_, value) in stream.dict.iter_mut() {
+                encrypt_object(crypt, id, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The P entry: bits 3 (print), 4 (modify), 5 (copy), 6 (annotate),
+/// 9 (fill forms), 10 (accessibility), 11 (assemble) and 12 (high-quality print)
+fn permission_bits(options: &PdfProtectOptions) -> i32 {
+    // Reserved bits 7-8 and 13-32 must be set
+    let mut bits: u32 = 0xFFFF_F0C0;
+    let allow = |flag: Option<bool>| flag.unwrap_or(true);
+    if allow(options.allow_print) {
+        bits |= (1 << 2) | (1 << 11);
+    }
+    if allow(options.allow_modify) {
+        bits |= (1 << 3) | (1 << 10);
+    }
+    if allow(options.allow_copy) {
+        bits |= 1 << 4;
+    }
+    if allow(options.allow_annotate) {
+        bits |= 1 << 5;
+    }
+    if allow(options.allow_fill_forms) || allow(options.allow_annotate) {
+        bits |= 1 << 8;
+    }
+    // Text extraction for screen readers stays allowed
+    bits |= 1 << 9;
+    bits as i32
+}
+
+fn describe_security(encrypt: &Dictionary, file_id: &[u8]) -> PdfSecurity {
+    let revision = encrypt.get(b"R").and_then(Object::as_i64).unwrap_or(0);
+    let method = match stream_method(encrypt) {
+        CryptMethod::Aes256 => "AES-256".to_string(),
+        CryptMethod::Aes128 => "AES-128".to_string(),
+        _ if revision == 2 => "RC4 40-bit".to_string(),
+        _ => format!("RC4 {}-bit", encrypt.get(b"Length").and_then(Object::as_i64).unwrap_or(40)),
+    };
+    let p = encrypt.get(b"P").and_then(Object::as_i64).unwrap_or(-1) as u32;
+    let bit = |n: u32| p & (1 << (n - 1)) != 0;
+    PdfSecurity {
+        method,
+        requires_password: authenticate(encrypt, file_id, b"").ok().flatten().is_none(),
+        allow_print: bit(3),
+        allow_copy: bit(5),
+        allow_modify: bit(4),
+        allow_annotate: bit(6),
+        allow_fill_forms: bit(6) || bit(9),
+    }
+}
+
+fn document_id(doc: &Document) -> Vec<u8> {
+    doc.trailer
+        .get(b"ID")
+        .and_then(Object::as_array)
+        .ok()
+        .and_then(|ids| ids.first())
+        .and_then(|id| id.as_str().ok())
+        .map(|id| id.to_vec())
+        .unwrap_or_default()
+}
+
+/// The method of a V4/V5 crypt filter, or RC4 for older handlers
+fn crypt_filter_method(encrypt: &Dictionary, filter_key: &[u8]) -> CryptMethod {
+    let version = encrypt.get(b"V").and_then(Object::as_i64).unwrap_or(0);
+    if version < 4 {
+        return CryptMethod::Rc4;
+    }
+    let name = encrypt.get(filter_key).and_then(Object::as_name).unwrap_or(b"Identity");
+    if name == b"Identity" {
+        return CryptMethod::Identity;
+    }
+    let cfm = encrypt
+        .get(b"CF")
+        .and_then(Object::as_dict)
+        .and_then(|filters| filters.get(name))
+        .and_then(Object::as_dict)
+        .and_then(|filter| filter.get(b"CFM"))
+        .and_then(Object::as_name)
+        .unwrap_or(b"None");
+    match cfm {
+        b"AESV2" => CryptMethod::Aes128,
+        b"AESV3" => CryptMethod::Aes256,
+        b"V2" => CryptMethod::Rc4,
+        _ => CryptMethod::Identity,
+    }
+}
+
+fn stream_method(encrypt: &Dictionary) -> CryptMethod {
+    crypt_filter_method(encrypt, b"StmF")
+}
+
+/// Check a password against the standard security handler. Returns the
+/// decryption setup when it matches either the user or owner password.
+fn authenticate(encrypt: &Dictionary, file_id: &[u8], password: &[u8]) -> Result<Option<Crypt>, String> {
+    let filter = encrypt.get(b"Filter").and_then(Object::as_name).unwrap_or(b"");
+    if filter != b"Standard" {
+        return Err(format!("Unsupported PDF security handler: {}", String::from_utf8_lossy(filter)));
+    }
+    let revision = encrypt.get(b"R").and_then(Object::as_i64).unwrap_or(0);
+    let bytes = |key: &[u8]| encrypt.get(key).and_then(Object::as_str).map(|s| s.to_vec()).unwrap_or_default();
+    let (o, u) = (bytes(b"O"), bytes(b"U"));
+
+    let key = match revision {
+        2..=4 => {
+            let length = match encrypt.get(b"V").and_then(Object::as_i64).unwrap_or(0) {
+                1 => 5,
+                _ => (encrypt.get(b"Length").and_then(Object::as_i64).unwrap_or(40) / 8).clamp(5, 16) as usize,
+            };
+            let p = encrypt.get(b"P").and_then(Object::as_i64).unwrap_or(0) as i32;
+            let encrypt_metadata = encrypt.get(b"EncryptMetadata").and_then(Object::as_bool).unwrap_or(true);
+            let compute = |user: &[u8]| rc4_file_key(user, &o, p, file_id, revision, length, encrypt_metadata);
+
+            let as_user = compute(password);
+            if user_hash(&as_user, revision, file_id)[..16] == u[..16.min(u.len())] {
+                Some(as_user)
+            } else {
+                // The owner password decrypts O back to the user password
+                let user_password = owner_to_user_password(password, &o, revision, length);
+                let as_owner = compute(&user_password);
+                if user_hash(&as_owner, revision, file_id)[..16] == u[..16.min(u.len())] {
+                    Some(as_owner)
+                } else {
+                    None
+                }
+            }
+        }
+        5 | 6 => {
+            let (oe, ue) = (bytes(b"OE"), bytes(b"UE"));
+            if o.len() < 48 || u.len() < 48 || oe.len() < 32 || ue.len() < 32 {
+                return Err("The PDF's encryption dictionary is damaged".to_string());
+            }
+            let password = &password[..password.len().min(127)];
+            if aes256_hash(password, &o[32..40], &u[..48], revision) == o[..32] {
+                let key = aes256_hash(password, &o[40..48], &u[..48], revision);
+                aes_cbc_decrypt_raw(&key, &oe[..32])
+            } else if aes256_hash(password, &u[32..40], &[], revision) == u[..32] {
+                let key = aes256_hash(password, &u[40..48], &[], revision);
+                aes_cbc_decrypt_raw(&key, &ue[..32])
+            } else {
+                None
+            }
+        }
+        other => return Err(format!("Unsupported PDF encryption revision {}", other)),
+    };
+
+    Ok(key.map(|key| Crypt {
+        key,
+        strings: crypt_filter_method(encrypt, b"StrF"),
+        streams: stream_method(encrypt),
+    }))
+}
+
+fn padded_password(password: &[u8]) -> Vec<u8> {
+    let mut padded: Vec<u8> = password.iter().take(32).copied().collect();
+    padded.extend_from_slice(&PASSWORD_PADDING[..32 - padded.len()]);
+    padded
+}
+
+/// Algorithm 2: the file key derived from a user password
+fn rc4_file_key(password: &[u8], o: &[u8], p: i32, file_id: &[u8], revision: i64, length: usize, encrypt_metadata: bool) -> Vec<u8> {
+    let mut hasher = Md5::new();
+    hasher.update(padded_password(password));
+    hasher.update(o);
+    hasher.update(p.to_le_bytes());
+    hasher.update(file_id);
+    if revision >= 4 && !encrypt_metadata {
+        hasher.update([0xFF; 4]);
+    }
+    let mut key = hasher.finalize().to_vec();
+    if revision >= 3 {
+        for _ in 0..50 {
+            key = Md5::digest(&key[..length]).to_vec();
+        }
+    }
+    key.truncate(length);
+    key
+}
+
+/// Algorithms 4 and 5: the U value for a file key (first 16 bytes significant)
+fn user_hash(key: &[u8], revision: i64, file_id: &[u8]) -> Vec<u8> {
+    if revision == 2 {
+        return rc4(key, &PASSWORD_PADDING);
+    }
+    let mut hasher = Md5::new();
+    hasher.update(PASSWORD_PADDING);
+    hasher.update(file_id);
+    let mut value = rc4(key, &hasher.finalize());
+    for i in 1..=19u8 {
+        let round_key: Vec<u8> = key.iter().map(|b| b ^ i).collect();
+        value = rc4(&round_key, &value);
+    }
+    value.resize(32, 0);
+    value
+}
+
+/// The RC4 key derived from an owner password (algorithm 3, steps a-d)
+fn owner_key(owner: &[u8], revision: i64, length: usize) -> Vec<u8> {
+    let mut key = Md5::digest(padded_password(owner)).to_vec();
+    if revision >= 3 {
+        for _ in 0..50 {
+            key = Md5::digest(&key).to_vec();
+        }
+    }
+    key.truncate(length);
+    key
+}
+
+/// Algorithm 7: recover the padded user password from O
+fn owner_to_user_password(owner: &[u8], o: &[u8], revision: i64, length: usize) -> Vec<u8> {
+    let key = owner_key(owner, revision, length);
+    if revision == 2 {
+        return rc4(&key, o);
+    }
+    let mut value = o.to_vec();
+    for i in (0..=19u8).rev() {
+        let round_key: Vec<u8> = key.iter().map(|b| b ^ i).collect();
+        value = rc4(&round_key, &value);
+    }
+    value
+}
+
+/// Revision 4 handler with AES-128 crypt filters. Returns the encryption
+/// dictionary and the file key.
+fn aes128_handler(user: &[u8], owner: &[u8], permissions: i32, file_id: &[u8]) -> (Dictionary, Vec<u8>) {
+    let revision = 4;
+    let length = 16;
+
+    // Algorithm 3: O is the padded user password encrypted with the owner key
+    let key = owner_key(owner, revision, length);
+    let mut o = rc4(&key, &padded_password(user));
+    for i in 1..=19u8 {
+        let round_key: Vec<u8> = key.iter().map(|b| b ^ i).collect();
+        o = rc4(&round_key, &o);
+    }
+
+    let file_key = rc4_file_key(user, &o, permissions, file_id, revision, length, true);
+    let u = user_hash(&file_key, revision, file_id);
+
+    let encrypt = dictionary! {
+        "Filter" => "Standard",
+        "V" => 4,
+        "R" => revision,
+        "Length" => 128,
+        "CF" => dictionary! {
+            "StdCF" => dictionary! { "Type" => "CryptFilter", "CFM" => "AESV2", "AuthEvent" => "DocOpen", "Length" => 16 },
+        },
+        "StmF" => "StdCF",
+        "StrF" => "StdCF",
+        "O" => Object::String(o, StringFormat::Hexadecimal),
+        "U" => Object::String(u, StringFormat::Hexadecimal),
+        "P" => permissions,
+    };
+    (encrypt, file_key)
+}
+
+/// Revision 6 handler with AES-256 crypt filters. Returns the encryption
+/// dictionary and the file key.
+fn aes256_handler(user: &[u8], owner: &[u8], permissions: i32) -> (Dictionary, Vec<u8>) {
+    let revision = 6;
+    let user = &user[..user.len().min(127)];
+    let owner = &owner[..owner.len().min(127)];
+    let file_key = random_bytes(32);
+
+    let salts = random_bytes(16);
+    let mut u = aes256_hash(user, &salts[..8], &[], revision);
+    u.extend_from_slice(&salts);
+    let ue = aes_cbc_encrypt(&aes256_hash(user, &salts[8..], &[], revision), &[0; 16], &file_key, false);
+
+    let salts = random_bytes(16);
+    let mut o = aes256_hash(owner, &salts[..8], &u, revision);
+    o.extend_from_slice(&salts);
+    let oe = aes_cbc_encrypt(&aes256_hash(owner, &salts[8..], &u, revision), &[0; 16], &file_key, false);
+
+    // Perms repeats P so readers can detect tampering with it
+    let mut perms = (permissions as u32 as u64 | 0xFFFF_FFFF_0000_0000).to_le_bytes().to_vec();
+    perms.extend_from_slice(b"Tadb");
+    perms.extend(random_bytes(4));
+    let perms = aes_ecb_encrypt_block(&file_key, &perms);
+
+    let encrypt = dictionary! {
+        "Filter" => "Standard",
+        "V" => 5,
+        "R" => revision,
+        "Length" => 256,
+        "CF" => dictionary! {
+            "StdCF" => dictionary! { "Type" => "CryptFilter", "CFM" => "AESV3", "AuthEvent" => "DocOpen", "Length" => 32 },
+        },
+        "StmF" => "StdCF",
+        "StrF" => "StdCF",
+        "O" => Object::String(o, StringFormat::Hexadecimal),
+        "U" => Object::String(u, StringFormat::Hexadecimal),
+        "OE" => Object::String(oe, StringFormat::Hexadecimal),
+        "UE" => Object::String(ue, StringFormat::Hexadecimal),
+        "P" => permissions,
+        "Perms" => Object::String(perms, StringFormat::Hexadecimal),
+        "EncryptMetadata" => true,
+    };
+    (encrypt, file_key)
+}
+
+/// Password hash for revisions 5 (plain SHA-256) and 6 (algorithm 2.B)
+fn aes256_hash(password: &[u8], salt: &[u8], user_key: &[u8], revision: i64) -> Vec<u8> {
+    let mut k = Sha256::new().chain_update(password).chain_update(salt).chain_update(user_key).finalize().to_vec();
+    if revision < 6 {
+        return k;
+    }
+    let mut round = 0usize;
+    loop {
+        let mut block = Vec::with_capacity((password.len() + k.len() + user_key.len()) * 64);
+        for _ in 0..64 {
+            block.extend_from_slice(password);
+            block.extend_from_slice(&k);
+            block.extend_from_slice(user_key);
+        }
+        let e = aes_cbc_encrypt(&k[..16], &k[16..32], &block, false);
+        let selector = e[..16].iter().map(|&b| b as u32).sum::<u32>() % 3;
+        k = match selector {
+            0 => Sha256::digest(&e).to_vec(),
+            1 => Sha384::digest(&e).to_vec(),
+            _ => Sha512::digest(&e).to_vec(),
+        };
+        round += 1;
+        if round >= 64 && (*e.last().unwrap() as usize) <= round - 32 {
+            break;
+        }
+    }
+    k.truncate(32);
+    k
+}
+
+fn aes_cbc_encrypt(key: &[u8], iv: &[u8], data: &[u8], pad: bool) -> Vec<u8> {
+    macro_rules! run {
+        ($cipher:ty) => {{
+            let encryptor = cbc::Encryptor::<$cipher>::new_from_slices(key, iv).expect("AES key and IV sizes");
+            if pad {
+                encryptor.encrypt_padded_vec_mut::<Pkcs7>(data)
+            } else {
+                encryptor.encrypt_padded_vec_mut::<NoPadding>(data)
+            }
+        }};
+    }
+    if key.len() == 32 {
+        run!(aes::Aes256)
+    } else {
+        run!(aes::Aes128)
+    }
+}
+
+fn aes_cbc_decrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    if key.len() == 32 {
+        cbc::Decryptor::<aes::Aes256>::new_from_slices(key, iv).ok()?.decrypt_padded_vec_mut::<Pkcs7>(data).ok()
+    } else {
+        cbc::Decryptor::<aes::Aes128>::new_from_slices(key, iv).ok()?.decrypt_padded_vec_mut::<Pkcs7>(data).ok()
+    }
+}
+
+/// AES-256 with a zero IV and no padding, as used for OE and UE
+fn aes_cbc_decrypt_raw(key: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    cbc::Decryptor::<aes::Aes256>::new_from_slices(key, &[0; 16])
+        .ok()?
+        .decrypt_padded_vec_mut::<NoPadding>(data)
+        .ok()
+}
+
+fn aes_ecb_encrypt_block(key: &[u8], block: &[u8]) -> Vec<u8> {
+    // A single block in CBC mode with a zero IV is plain ECB
+    aes_cbc_encrypt(key, &[0; 16], block, false)
+}
+
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut s: Vec<u8> = (0..=255).collect();
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+        s.swap(i, j as usize);
+    }
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|&byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(s[i as usize]);
+            s.swap(i as usize, j as usize);
+            byte ^ s[s[i as usize].wrapping_add(s[j as usize]) as usize]
+        })
+        .collect()
+}
+
+fn random_bytes(count: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; count];
+    getrandom::getrandom(&mut bytes).expect("the system random number generator is unavailable");
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn round_trip(crypt: &Crypt, method: CryptMethod) {
+        let id = (12, 0);
+        let plain = b"The quick brown fox jumps over the lazy dog".to_vec();
+        let encrypted = crypt.encrypt(id, method, &plain);
+        assert_ne!(encrypted, plain);
+        assert_eq!(crypt.decrypt(id, method, &encrypted), Some(plain));
+        // Another object gets another key, except with AES-256
+        if method != CryptMethod::Aes256 {
+            assert_ne!(crypt.decrypt((13, 0), method, &encrypted), Some(b"The quick brown fox jumps over the lazy dog".to_vec()));
+        }
+    }
+
+    #[test]
+    fn rc4_matches_known_vector() {
+        assert_eq!(hex(&rc4(b"Key", b"Plaintext")), "bbf316e8d940af0ad3");
+        assert_eq!(rc4(b"Key", &rc4(b"Key", b"Plaintext")), b"Plaintext");
+    }
+
+    #[test]
+    fn rc4_objects_round_trip() {
+        let crypt = Crypt { key: vec![1, 2, 3, 4, 5], strings: CryptMethod::Rc4, streams: CryptMethod::Rc4 };
+        round_trip(&crypt, CryptMethod::Rc4);
+    }
+
+    #[test]
+    fn aes128_passwords_unlock_the_file_key() {
+        let file_id = b"0123456789abcdef";
+        let (encrypt, file_key) = aes128_handler(b"user", b"owner", -4, file_id);
+        for password in [&b"user"[..], b"owner"] {
+            let crypt = authenticate(&encrypt, file_id, password).unwrap().unwrap();
+            assert_eq!(crypt.key, file_key);
+            assert!(crypt.strings == CryptMethod::Aes128 && crypt.streams == CryptMethod::Aes128);
+            round_trip(&crypt, CryptMethod::Aes128);
+        }
+        assert!(authenticate(&encrypt, file_id, b"wrong").unwrap().is_none());
+    }
+
+    #[test]
+    fn aes256_passwords_unlock_the_file_key() {
+        let (encrypt, file_key) = aes256_handler(b"user", b"owner", -4);
+        for password in [&b"user"[..], b"owner"] {
+            let crypt = authenticate(&encrypt, b"", password).unwrap().unwrap();
+            assert_eq!(crypt.key, file_key);
+            assert!(crypt.strings == CryptMethod::Aes256 && crypt.streams == CryptMethod::Aes256);
+            round_trip(&crypt, CryptMethod::Aes256);
+        }
+        assert!(authenticate(&encrypt, b"", b"wrong").unwrap().is_none());
+    }
+
+    #[test]
+    fn revision_6_hash_matches_reference() {
+        let salt: Vec<u8> = (0..8).collect();
+        assert_eq!(
+            hex(&aes256_hash(b"user", &salt, &[], 6)),
+            "731758c09c8b0160a34721d18bdd24220abada0070aa3f05b8103fd5b8d05f17"
+        );
+        let salt: Vec<u8> = (8..16).collect();
+        let user_key: Vec<u8> = (0..48).collect();
+        assert_eq!(
+            hex(&aes256_hash(b"owner", &salt, &user_key, 6)),
+            "400c13628b144fe2fbb850b65729e9ecb63c00fbb817c685725f25de85af0521"
+        );
+        // Revision 5 is a single SHA-256
+        assert_eq!(aes256_hash(b"user", &salt, &[], 5), Sha256::digest(b"user\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f").to_vec());
+    }
+
+    #[test]
+    fn perms_repeats_the_permissions() {
+        let permissions = permission_bits(&PdfProtectOptions { allow_print: Some(true), ..Default::default() });
+        let (encrypt, file_key) = aes256_handler(b"", b"owner", permissions);
+        let perms = encrypt.get(b"Perms").and_then(Object::as_str).unwrap();
+        let perms = aes_cbc_decrypt_raw(&file_key, perms).unwrap();
+        assert_eq!(perms[..4], permissions.to_le_bytes());
+        assert_eq!(perms[4..8], [0xFF; 4]);
+        assert_eq!(&perms[8..12], b"Tadb");
+        assert_eq!(encrypt.get(b"P").and_then(Object::as_i64).unwrap(), permissions as i64);
+    }
+}
@@ -0,0 +1,1046 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use lopdf::{dictionary, Document, Object, ObjectId};
+use crate::ConversionResult;
+use crate::converters::pdf_converter::{
+    iso_to_pdf_date, jpeg_component_count, parse_page_ranges, parse_page_spans, pdf_date_now, read_pdf_metadata, PdfMetadata,
+};
+use crate::converters::pdf_renderer::{image_stream_data, strip_inline_images};
+use crate::converters::text_tools::escape_markup;
+
+/// How to split a PDF into several files
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SplitPdfOptions {
+    /// One output file per range, e.g. "1-3,4-10,11-"
+    pub ranges: Option<String>,
+    /// Start a new file every N pages when no ranges are given (defaults to 1)
+    pub every: Option<u32>,
+}
+
+/// Page attributes a page can inherit from its ancestors in the page tree
+const INHERITABLE: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+/// Open a PDF for editing. Encrypted files are refused, even ones that open
+/// without a password: saving them would drop the protection and the owner's
+/// restrictions, which only `decrypt_pdf` may remove given the password.
+pub fn load_pdf(input_path: &str) -> Result<Document, String> {
+    let doc = Document::load(input_path).map_err(|e| format!("Failed to read PDF: {}", e))?;
+    if doc.is_encrypted() {
+        return Err("This PDF is password protected. Remove its protection with its password before editing it".to_string());
+    }
+    Ok(doc)
+}
+
+/// Write an edited PDF back to disk
+pub fn save_pdf(doc: &mut Document, output_path: &str) -> Result<(), String> {
+    // The file is rewritten in full, so links to the original's
+    // cross-reference sections no longer apply
+    doc.trailer.remove(b"Prev");
+    doc.trailer.remove(b"XRefStm");
+    doc.save(output_path)
+        .map(|_| ())
+        .map_err(|e| format!("Failed to write PDF: {}", e))
+}
+
+/// Turn a report or an error into a ConversionResult
+pub fn finish(result: Result<String, String>, output_path: &str) -> ConversionResult {
+    match result {
+        Ok(message) => ConversionResult {
+            success: true,
+            output_path: Some(output_path.to_string()),
+            error: None,
+            data: Some(message),
+        },
+        Err(e) => ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some(e),
+            data: None,
+        },
+    }
+}
+
+/// Combine several PDFs into one, in the given order.
+/// Each document's bookmarks are carried over after the previous document's.
+pub fn merge_pdfs(input_paths: &[String], output_path: &str) -> ConversionResult {
+    finish(try_merge_pdfs(input_paths, output_path), output_path)
+}
+
+fn try_merge_pdfs(input_paths: &[String], output_path: &str) -> Result<String, String> {
+    if input_paths.len() < 2 {
+        return Err("Select at least two PDFs to merge".to_string());
+    }
+
+    let mut merged = Document::with_version("1.4");
+    let mut page_ids: Vec<ObjectId> = Vec::new();
+    let mut outline_items: Vec<ObjectId> = Vec::new();
+    let mut next_id = 1;
+
+    for path in input_paths {
+        let mut doc = load_pdf(path).map_err(|e| format!("{}: {}", path, e))?;
+        flatten_inherited_attributes(&mut doc);
+        // Give every document its own range of object numbers
+        doc.renumber_objects_with(next_id);
+        next_id = doc.max_id + 1;
+
+        page_ids.extend(doc.page_iter());
+        if let Ok(root) = doc.catalog().and_then(|c| c.get(b"Outlines")).and_then(Object::as_reference) {
+            outline_items.extend(outline_children(&doc, root));
+        }
+        if doc.version > merged.version {
+            merged.version = doc.version.clone();
+        }
+        merged.objects.extend(doc.objects);
+    }
+    merged.max_id = next_id - 1;
+
+    let pages_id = merged.new_object_id();
+    for page_id in &page_ids {
+        if let Ok(page) = merged.get_dictionary_mut(*page_id) {
+            page.set("Parent", Object::Reference(pages_id));
+        }
+    }
+    merged.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => page_ids.iter().map(|id| Object::Reference(*id)).collect::<Vec<Object>>(),
+            "Count" => page_ids.len() as i64,
+        }),
+    );
+
+    let mut catalog = dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    };
+    if !outline_items.is_empty() {
+        let outlines_id = merged.new_object_id();
+        merged.objects.insert(outlines_id, Object::Dictionary(dictionary! { "Type" => "Outlines" }));
+        link_outline_items(&mut merged, outlines_id, &outline_items);
+        catalog.set("Outlines", outlines_id);
+    }
+    let catalog_id = merged.add_object(catalog);
+    merged.trailer.set("Root", catalog_id);
+
+    merged.prune_objects();
+    merged.renumber_objects();
+    save_pdf(&mut merged, output_path)?;
+    Ok(format!("Merged {} file(s) into {} page(s)", input_paths.len(), page_ids.len()))
+}
+
+/// Split a PDF into one file per range, or into chunks of N pages.
+/// Files are written to `output_dir` as `{stem}-{first}-{last}.pdf`.
+pub fn split_pdf(input_path: &str, output_dir: &str, options: &SplitPdfOptions) -> ConversionResult {
+    finish(try_split_pdf(input_path, output_dir, options), output_dir)
+}
+
+fn try_split_pdf(input_path: &str, output_dir: &str, options: &SplitPdfOptions) -> Result<String, String> {
+    let doc = load_pdf(input_path)?;
+    let page_count = doc.get_pages().len() as u32;
+
+    let spans = match &options.ranges {
+        Some(ranges) => parse_page_spans(ranges, page_count)?,
+        None => {
+            let every = options.every.unwrap_or(1).max(1);
+            (1..=page_count)
+                .step_by(every as usize)
+                .map(|first| (first, first.saturating_add(every - 1).min(page_count)))
+                .collect()
+        }
+    };
+
+    fs::create_dir_all(output_dir).map_err(|e| format!("Failed to create output directory: {}", e))?;
+    let stem = Path::new(input_path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("document");
+    let width = page_count.to_string().len();
+
+    let mut parts = Vec::new();
+    for (first, last) in spans {
+        let name = if first == last {
+            format!("{}-{:0width$}.pdf", stem, first, width = width)
+        } else {
+            format!("{}-{:0width$}-{:0width$}.pdf", stem, first, last, width = width)
+        };
+        let part_path = Path::new(output_dir).join(&name).to_string_lossy().to_string();
+
+        let mut part = doc.clone();
+        select_pages(&mut part, &(first..=last).collect::<Vec<u32>>())?;
+        save_pdf(&mut part, &part_path)?;
+        parts.push(serde_json::json!({ "file": part_path, "first_page": first, "last_page": last }));
+    }
+
+    let report = serde_json::json!({ "file_count": parts.len(), "files": parts });
+    Ok(serde_json::to_string_pretty(&report).unwrap())
+}
+
+/// Keep only the selected pages, e.g. "1-3,7"
+pub fn extract_pdf_pages(input_path: &str, output_path: &str, pages: &str) -> ConversionResult {
+    finish(try_extract_pdf_pages(input_path, output_path, pages), output_path)
+}
+
+fn try_extract_pdf_pages(input_path: &str, output_path: &str, pages: &str) -> Result<String, String> {
+    let mut doc = load_pdf(input_path)?;
+    let selected = parse_page_ranges(pages, doc.get_pages().len() as u32)?;
+    select_pages(&mut doc, &selected)?;
+    save_pdf(&mut doc, output_path)?;
+    Ok(format!("Extracted {} page(s)", selected.len()))
+}
+
+/// Remove the selected pages, e.g. "2,5-6"
+pub fn delete_pdf_pages(input_path: &str, output_path: &str, pages: &str) -> ConversionResult {
+    finish(try_delete_pdf_pages(input_path, output_path, pages), output_path)
+}
+
+fn try_delete_pdf_pages(input_path: &str, output_path: &str, pages: &str) -> Result<String, String> {
+    let mut doc = load_pdf(input_path)?;
+    let page_count = doc.get_pages().len() as u32;
+    let deleted: HashSet<u32> = parse_page_ranges(pages, page_count)?.into_iter().collect();
+    let kept: Vec<u32> = (1..=page_count).filter(|p| !deleted.contains(p)).collect();
+    if kept.is_empty() {
+        return Err("Cannot delete every page of a PDF".to_string());
+    }
+    select_pages(&mut doc, &kept)?;
+    save_pdf(&mut doc, output_path)?;
+    Ok(format!("Deleted {} page(s), {} remaining", deleted.len(), kept.len()))
+}
+
+/// Rotate pages clockwise by a multiple of 90 degrees. All pages when `pages` is None.
+pub fn rotate_pdf_pages(input_path: &str, output_path: &str, pages: Option<&str>, degrees: i64) -> ConversionResult {
+    finish(try_rotate_pdf_pages(input_path, output_path, pages, degrees), output_path)
+}
+
+fn try_rotate_pdf_pages(input_path: &str, output_path: &str, pages: Option<&str>, degrees: i64) -> Result<String, String> {
+    if degrees % 90 != 0 {
+        return Err("Rotation must be a multiple of 90 degrees".to_string());
+    }
+    let mut doc = load_pdf(input_path)?;
+    flatten_inherited_attributes(&mut doc);
+    let page_ids = doc.get_pages();
+    let selected = parse_page_ranges(pages.unwrap_or("1-"), page_ids.len() as u32)?;
+
+    for page in &selected {
+        if let Some(page_id) = page_ids.get(page) {
+            let page = doc.get_dictionary_mut(*page_id).map_err(|e| e.to_string())?;
+            let current = page.get(b"Rotate").and_then(Object::as_i64).unwrap_or(0);
+            page.set("Rotate", (current + degrees).rem_euclid(360));
+        }
+    }
+    save_pdf(&mut doc, output_path)?;
+    Ok(format!("Rotated {} page(s) by {} degrees", selected.len(), degrees))
+}
+
+/// Put pages in a new order, e.g. "3,1-2". Pages left out of the order
+/// follow the listed ones in their original order.
+pub fn reorder_pdf_pages(input_path: &str, output_path: &str, order: &str) -> ConversionResult {
+    finish(try_reorder_pdf_pages(input_path, output_path, order), output_path)
+}
+
+fn try_reorder_pdf_pages(input_path: &str, output_path: &str, order: &str) -> Result<String, String> {
+    let mut doc = load_pdf(input_path)?;
+    let page_count = doc.get_pages().len() as u32;
+
+    let mut new_order: Vec<u32> = Vec::new();
+    for (first, last) in parse_page_spans(order, page_count)? {
+        for page in first..=last {
+            if new_order.contains(&page) {
+                return Err(format!("Page {} is listed more than once", page));
+            }
+            new_order.push(page);
+        }
+    }
+    let rest: Vec<u32> = (1..=page_count).filter(|p| !new_order.contains(p)).collect();
+    new_order.extend(rest);
+
+    select_pages(&mut doc, &new_order)?;
+    save_pdf(&mut doc, output_path)?;
+    Ok(format!("Reordered {} page(s)", new_order.len()))
+}
+
+/// Rebuild the page tree from the given 1-based page numbers, in order,
+/// then drop bookmarks and objects that only served the removed pages
+pub fn select_pages(doc: &mut Document, pages: &[u32]) -> Result<(), String> {
+    let page_ids = doc.get_pages();
+    let selected: Vec<ObjectId> = pages
+        .iter()
+        .map(|p| page_ids.get(p).copied().ok_or_else(|| format!("Page {} not found", p)))
+        .collect::<Result<_, _>>()?;
+
+    flatten_inherited_attributes(doc);
+    let pages_id = doc
+        .catalog()
+        .and_then(|c| c.get(b"Pages"))
+        .and_then(Object::as_reference)
+        .map_err(|e| format!("PDF has no page tree: {}", e))?;
+
+    for page_id in &selected {
+        let page = doc.get_dictionary_mut(*page_id).map_err(|e| e.to_string())?;
+        page.set("Parent", Object::Reference(pages_id));
+    }
+    let root = doc.get_dictionary_mut(pages_id).map_err(|e| e.to_string())?;
+    root.set("Kids", selected.iter().map(|id| Object::Reference(*id)).collect::<Vec<Object>>());
+    root.set("Count", selected.len() as i64);
+
+    prune_outlines(doc);
+    doc.prune_objects();
+    Ok(())
+}
+
+/// Copy inherited page attributes onto each page, so pages can be moved to a
+/// different parent without losing their resources, size or rotation
+pub fn flatten_inherited_attributes(doc: &mut Document) {
+    let mut updates = Vec::new();
+    for page_id in doc.page_iter() {
+        let page = match doc.get_dictionary(page_id) {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        for key in INHERITABLE {
+            if page.has(key) {
+                continue;
+            }
+            let mut node = page;
+            for _ in 0..32 {
+                match node.get_deref(b"Parent", doc).and_then(Object::as_dict) {
+                    Ok(parent) => {
+                        if let Ok(value) = parent.get(key) {
+                            updates.push((page_id, key, value.clone()));
+                            break;
+                        }
+                        node = parent;
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+
+    for (page_id, key, value) in updates {
+        if let Ok(page) = doc.get_dictionary_mut(page_id) {
+            page.set(key.to_vec(), value);
+        }
+    }
+}
+
+/// The direct children of an outline node, following /First and /Next
+fn outline_children(doc: &Document, parent_id: ObjectId) -> Vec<ObjectId> {
+    let mut children = Vec::new();
+    let mut next = doc
+        .get_dictionary(parent_id)
+        .and_then(|d| d.get(b"First"))
+        .and_then(Object::as_reference)
+        .ok();
+    while let Some(id) = next {
+        // Guard against cycles in damaged files
+        if children.contains(&id) {
+            break;
+        }
+        children.push(id);
+        next = doc
+            .get_dictionary(id)
+            .and_then(|d| d.get(b"Next"))
+            .and_then(Object::as_reference)
+            .ok();
+    }
+    children
+}
+
+/// Make `items` the children of `parent_id`, in order
+fn link_outline_items(doc: &mut Document, parent_id: ObjectId, items: &[ObjectId]) {
+    for (i, id) in items.iter().enumerate() {
+        if let Ok(item) = doc.get_dictionary_mut(*id) {
+            item.set("Parent", Object::Reference(parent_id));
+            match i.checked_sub(1).map(|prev| items[prev]) {
+                Some(prev) => item.set("Prev", Object::Reference(prev)),
+                None => {
+                    item.remove(b"Prev");
+                }
+            }
+            match items.get(i + 1) {
+                Some(next) => item.set("Next", Object::Reference(*next)),
+                None => {
+                    item.remove(b"Next");
+                }
+            }
+        }
+    }
+
+    if let Ok(parent) = doc.get_dictionary_mut(parent_id) {
+        match (items.first(), items.last()) {
+            (Some(first), Some(last)) => {
+                // A negative count marks a collapsed item; keep it collapsed
+                let collapsed = parent.get(b"Count").and_then(Object::as_i64).map(|c| c < 0).unwrap_or(false);
+                let count = items.len() as i64;
+                parent.set("First", Object::Reference(*first));
+                parent.set("Last", Object::Reference(*last));
+                parent.set("Count", if collapsed { -count } else { count });
+            }
+            _ => {
+                parent.remove(b"First");
+                parent.remove(b"Last");
+                parent.remove(b"Count");
+            }
+        }
+    }
+}
+
+/// Drop bookmarks that point at pages no longer in the document. Items whose
+/// page is gone but which still have surviving children are kept as headings.
+fn prune_outlines(doc: &mut Document) {
+    let root_id = match doc.catalog().and_then(|c| c.get(b"Outlines")).and_then(Object::as_reference) {
+        Ok(id) => id,
+        Err(_) => return,
+    };
+    let kept: HashSet<ObjectId> = doc.page_iter().collect();
+    let mut visited = HashSet::new();
+    if prune_outline_children(doc, root_id, &kept, &mut visited) == 0 {
+        if let Ok(catalog) = doc.catalog_mut() {
+            catalog.remove(b"Outlines");
+        }
+    }
+}
+
+fn prune_outline_children(
+    doc: &mut Document,
+    parent_id: ObjectId,
+    kept: &HashSet<ObjectId>,
+    visited: &mut HashSet<ObjectId>,
+) -> usize {
+    let mut survivors = Vec::new();
+    for id in outline_children(doc, parent_id) {
+        if !visited.insert(id) {
+            continue;
+        }
+        let surviving_children = prune_outline_children(doc, id, kept, visited);
+        if let Some(page) = outline_target(doc, id) {
+            if !kept.contains(&page) {
+                if surviving_children == 0 {
+                    continue;
+                }
+                if let Ok(item) = doc.get_dictionary_mut(id) {
+                    item.remove(b"Dest");
+                    item.remove(b"A");
+                }
+            }
+        }
+        survivors.push(id);
+    }
+    link_outline_items(doc, parent_id, &survivors);
+    survivors.len()
+}
+
+/// The page an outline item jumps to, when it names one directly
+fn outline_target(doc: &Document, item_id: ObjectId) -> Option<ObjectId> {
+    let item = doc.get_dictionary(item_id).ok()?;
+    let dest = match item.get(b"Dest") {
+        Ok(dest) => dest,
+        Err(_) => {
+            let action = item.get_deref(b"A", doc).and_then(Object::as_dict).ok()?;
+            if action.get(b"S").and_then(Object::as_name).ok()? != b"GoTo" {
+                return None;
+            }
+            action.get(b"D").ok()?
+        }
+    };
+    // Named destinations are left alone
+    let (_, dest) = doc.dereference(dest).ok()?;
+    dest.as_array().ok()?.first()?.as_reference().ok()
+}
+
+/// Settings for shrinking a PDF
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PdfOptimizeOptions {
+    /// Downsample images displayed above this resolution (defaults to 150 DPI)
+    pub image_dpi: Option<f32>,
+    /// JPEG quality for downsampled images (defaults to 75)
+    pub jpeg_quality: Option<u8>,
+    /// Also drop document info, XMP metadata, thumbnails and editor-private data
+    pub strip_metadata: Option<bool>,
+}
+
+/// Shrink a PDF: downsample oversized images, merge duplicate objects,
+/// compress plain streams and drop unused objects. Reports sizes as JSON.
+pub fn optimize_pdf(input_path: &str, output_path: &str, options: &PdfOptimizeOptions) -> ConversionResult {
+    finish(try_optimize_pdf(input_path, output_path, options), output_path)
+}
+
+fn try_optimize_pdf(input_path: &str, output_path: &str, options: &PdfOptimizeOptions) -> Result<String, String> {
+    let original_bytes = fs::metadata(input_path).map(|m| m.len()).unwrap_or(0);
+    let mut doc = load_pdf(input_path)?;
+    let target_dpi = options.image_dpi.unwrap_or(150.0).max(36.0);
+    let quality = options.jpeg_quality.unwrap_or(75).clamp(1, 100);
+
+    let metadata_removed = if options.strip_metadata.unwrap_or(false) {
+        strip_pdf_metadata(&mut doc)
+    } else {
+        0
+    };
+
+    flatten_inherited_attributes(&mut doc);
+    let downsampled = downsample_images(&mut doc, target_dpi, quality);
+    let deduplicated = deduplicate_objects(&mut doc);
+
+    let plain_streams = |doc: &Document| {
+        doc.objects
+            .values()
+            .filter(|o| matches!(o, Object::Stream(s) if s.dict.get(b"Filter").is_err()))
+            .count()
+    };
+    let plain_before = plain_streams(&doc);
+    doc.compress();
+    let compressed = plain_before - plain_streams(&doc);
+
+    let removed = doc.prune_objects().len();
+    doc.renumber_objects();
+    save_pdf(&mut doc, output_path)?;
+
+    let output_bytes = fs::metadata(output_path).map(|m| m.len()).unwrap_or(0);
+    let saved = original_bytes as i64 - output_bytes as i64;
+    let report = serde_json::json!({
+        "original_bytes": original_bytes,
+        "output_bytes": output_bytes,
+        "saved_bytes": saved,
+        "saved_percent": if original_bytes > 0 { (saved as f64 / original_bytes as f64 * 1000.0).round() / 10.0 } else { 0.0 },
+        "downsampled_images": downsampled,
+        "deduplicated_objects": deduplicated,
+        "compressed_streams": compressed,
+        "removed_objects": removed,
+        "metadata_entries_removed": metadata_removed,
+    });
+    Ok(serde_json::to_string_pretty(&report).unwrap())
+}
+
+/// Remove document-level and per-object metadata. Returns how many entries were dropped.
+fn strip_pdf_metadata(doc: &mut Document) -> usize {
+    let mut removed = 0;
+    if doc.trailer.remove(b"Info").is_some() {
+        removed += 1;
+    }
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        for key in [&b"Metadata"[..], b"PieceInfo", b"Thumb"] {
+            if dict.remove(key).is_some() {
+                removed += 1;
+            }
+        }
+    }
+    removed
+}
+
+/// Re-encode images shown above `target_dpi` as smaller JPEGs
+fn downsample_images(doc: &mut Document, target_dpi: f32, quality: u8) -> usize {
+    let mut count = 0;
+    for (id, dpi) in image_resolutions(doc) {
+        // Leave a little headroom so images just over the target aren't recompressed
+        if dpi <= target_dpi * 1.1 {
+            continue;
+        }
+        let resampled = match doc.get_object(id).and_then(Object::as_stream) {
+            Ok(stream) => resample_image(doc, stream, target_dpi / dpi, quality),
+            Err(_) => None,
+        };
+        if let Some(stream) = resampled {
+            doc.objects.insert(id, Object::Stream(stream));
+            count += 1;
+        }
+    }
+    count
+}
+
+/// The highest resolution each image XObject is displayed at, in pixels per inch
+fn image_resolutions(doc: &Document) -> HashMap<ObjectId, f32> {
+    let mut found = HashMap::new();
+    for page_id in doc.page_iter() {
+        let content = match doc.get_page_content(page_id) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let resources = doc
+            .get_dictionary(page_id)
+            .and_then(|page| page.get_deref(b"Resources", doc))
+            .and_then(Object::as_dict)
+            .cloned()
+            .unwrap_or_default();
+        scan_image_placements(doc, &content, &resources, [1.0, 0.0, 0.0, 1.0, 0.0, 0.0], &mut found, 0);
+    }
+    found
+}
+
+fn scan_image_placements(
+    doc: &Document,
+    content: &[u8],
+    resources: &lopdf::Dictionary,
+    base: [f32; 6],
+    found: &mut HashMap<ObjectId, f32>,
+    depth: usize,
+) {
+    let content = strip_inline_images(content);
+    let operations = match lopdf::content::Content::decode(&content) {
+        Ok(c) => c.operations,
+        Err(_) => return,
+    };
+    let xobjects = resources.get_deref(b"XObject", doc).and_then(Object::as_dict).ok();
+
+    let mut ctm = base;
+    let mut stack = Vec::new();
+    for op in operations {
+        match op.operator.as_str() {
+            "q" => stack.push(ctm),
+            "Q" => ctm = stack.pop().unwrap_or(base),
+            "cm" => {
+                let m: Vec<f32> = op.operands.iter().map(|o| o.as_float().unwrap_or(0.0)).collect();
+                if m.len() == 6 {
+                    ctm = concat_matrix([m[0], m[1], m[2], m[3], m[4], m[5]], ctm);
+                }
+            }
+            "Do" => {
+                let id = op
+                    .operands
+                    .first()
+                    .and_then(|n| n.as_name().ok())
+                    .and_then(|name| xobjects.and_then(|x| x.get(name).ok()))
+                    .and_then(|r| r.as_reference().ok());
+                let (id, stream) = match id.and_then(|id| doc.get_object(id).and_then(Object::as_stream).ok().map(|s| (id, s))) {
+                    Some(found) => found,
+                    None => continue,
+                };
+
+                match stream.dict.get(b"Subtype").and_then(Object::as_name).unwrap_or(b"") {
+                    b"Image" => {
+                        let width = stream.dict.get(b"Width").and_then(Object::as_i64).unwrap_or(0) as f32;
+                        let height = stream.dict.get(b"Height").and_then(Object::as_i64).unwrap_or(0) as f32;
+                        // The image fills the unit square, so the matrix columns give its size in points
+                        let shown_width = (ctm[0] * ctm[0] + ctm[1] * ctm[1]).sqrt() / 72.0;
+                        let shown_height = (ctm[2] * ctm[2] + ctm[3] * ctm[3]).sqrt() / 72.0;
+                        if shown_width > 0.0 && shown_height > 0.0 {
+                            let dpi = (width / shown_width).max(height / shown_height);
+                            let entry = found.entry(id).or_insert(0.0);
+                            *entry = entry.max(dpi);
+                        }
+                    }
+                    b"Form" if depth < 8 => {
+                        let form_content = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+                        let form_resources = stream
+                            .dict
+                            .get_deref(b"Resources", doc)
+                            .and_then(Object::as_dict)
+                            .unwrap_or(resources);
+                        let matrix: Vec<f32> = stream
+                            .dict
+                            .get(b"Matrix")
+                            .and_then(Object::as_array)
+                            .map(|m| m.iter().map(|o| o.as_float().unwrap_or(0.0)).collect())
+                            .unwrap_or_default();
+                        let form_ctm = match matrix.as_slice() {
+                            [a, b, c, d, e, f] => concat_matrix([*a, *b, *c, *d, *e, *f], ctm),
+                            _ => ctm,
+                        };
+                        scan_image_placements(doc, &form_content, form_resources, form_ctm, found, depth + 1);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// `m` applied before `n`, in PDF's row-vector convention
+fn concat_matrix(m: [f32; 6], n: [f32; 6]) -> [f32; 6] {
+    [
+        m[0] * n[0] + m[1] * n[2],
+        m[0] * n[1] + m[1] * n[3],
+        m[2] * n[0] + m[3] * n[2],
+        m[2] * n[1] + m[3] * n[3],
+        m[4] * n[0] + m[5] * n[2] + n[4],
+        m[4] * n[1] + m[5] * n[3] + n[5],
+    ]
+}
+
+/// Scale an 8-bit gray or RGB image by `factor` and re-encode it as JPEG.
+/// Returns None for images that can't be handled or wouldn't get smaller.
+fn resample_image(doc: &Document, stream: &lopdf::Stream, factor: f32, quality: u8) -> Option<lopdf::Stream> {
+    let dict = &stream.dict;
+    if dict.get(b"ImageMask").and_then(Object::as_bool).unwrap_or(false) {
+        return None;
+    }
+    // Negative or oversized dimensions are malformed; leave those images alone
+    let width = u32::try_from(dict.get(b"Width").and_then(Object::as_i64).ok()?).ok()?;
+    let height = u32::try_from(dict.get(b"Height").and_then(Object::as_i64).ok()?).ok()?;
+    if dict.get(b"BitsPerComponent").and_then(Object::as_i64).unwrap_or(8) != 8 {
+        return None;
+    }
+
+    let (data, codec) = image_stream_data(stream)?;
+    let (img, components) = match codec.as_deref() {
+        Some("DCTDecode") => {
+            // The image crate decodes CMYK and YCCK JPEGs to RGB, so check
+            // the frame header; those are left alone
+            let components = jpeg_component_count(&data)?;
+            if !matches!(components, 1 | 3) {
+                return None;
+            }
+            (image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg).ok()?, components)
+        }
+        Some(_) => return None,
+        None => {
+            let pixels = usize::try_from(width).ok()?.checked_mul(usize::try_from(height).ok()?)?;
+            match color_components(doc, dict.get(b"ColorSpace").ok()?)? {
+                1 => (image::DynamicImage::ImageLuma8(image::GrayImage::from_raw(width, height, data.get(..pixels)?.to_vec())?), 1),
+                3 => (image::DynamicImage::ImageRgb8(image::RgbImage::from_raw(width, height, data.get(..pixels.checked_mul(3)?)?.to_vec())?), 3),
+                _ => return None,
+            }
+        }
+    };
+
+    let new_width = ((width as f32 * factor).round() as u32).max(1);
+    let new_height = ((height as f32 * factor).round() as u32).max(1);
+    let resized = img.resize_exact(new_width, new_height, image::imageops::FilterType::Triangle);
+    let resized = match resized {
+        image::DynamicImage::ImageLuma8(_) => resized,
+        other => image::DynamicImage::ImageRgb8(other.to_rgb8()),
+    };
+
+    let mut encoded = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, quality)
+        .encode_image(&resized)
+        .ok()?;
+    if encoded.len() >= stream.content.len() {
+        return None;
+    }
+
+    let mut new_dict = dict.clone();
+    new_dict.set("Width", new_width as i64);
+    new_dict.set("Height", new_height as i64);
+    new_dict.set("BitsPerComponent", 8);
+    new_dict.set("Filter", "DCTDecode");
+    new_dict.remove(b"DecodeParms");
+    // The original color space and Decode array still describe the samples
+    // unless the component count changed
+    if resized.color().channel_count() != components {
+        let space = if resized.color().channel_count() == 1 { "DeviceGray" } else { "DeviceRGB" };
+        new_dict.set("ColorSpace", space);
+        new_dict.remove(b"Decode");
+    }
+    let mut new_stream = lopdf::Stream::new(new_dict, Vec::new()).with_compression(false);
+    new_stream.set_content(encoded);
+    Some(new_stream)
+}
+
+/// Number of components for gray and RGB color spaces, None for anything else
+fn color_components(doc: &Document, space: &Object) -> Option<u8> {
+    let (_, space) = doc.dereference(space).ok()?;
+    match space {
+        Object::Name(name) => match name.as_slice() {
+            b"DeviceGray" | b"CalGray" => Some(1),
+            b"DeviceRGB" | b"CalRGB" => Some(3),
+            _ => None,
+        },
+        Object::Array(items) => match items.first()?.as_name().ok()? {
+            b"CalGray" => Some(1),
+            b"CalRGB" => Some(3),
+            b"ICCBased" => {
+                let (_, profile) = doc.dereference(items.get(1)?).ok()?;
+                match profile.as_stream().ok()?.dict.get(b"N").and_then(Object::as_i64).ok()? {
+                    1 => Some(1),
+                    3 => Some(3),
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Point references at a single copy of identical objects. Returns how many
+/// duplicates were removed.
+fn deduplicate_objects(doc: &mut Document) -> usize {
+    let mut total = 0;
+    // Merging objects can make the objects that refer to them identical too
+    for _ in 0..8 {
+        let mut seen: HashMap<u64, Vec<ObjectId>> = HashMap::new();
+        let mut replace: HashMap<ObjectId, ObjectId> = HashMap::new();
+
+        for (id, object) in &doc.objects {
+            // Pages and tree nodes must stay distinct even when they look alike
+            let structural = match object {
+                Object::Dictionary(dict) => {
+                    dict.has(b"Parent")
+                        || matches!(dict.get(b"Type").and_then(Object::as_name), Ok(b"Page" | b"Pages" | b"Catalog"))
+                }
+                _ => false,
+            };
+            if structural {
+                continue;
+            }
+
+            let mut hasher = DefaultHasher::new();
+            hash_object(object, &mut hasher);
+            let candidates = seen.entry(hasher.finish()).or_default();
+            match candidates.iter().find(|other| same_object(&doc.objects[other], object)) {
+                Some(original) => {
+                    replace.insert(*id, *original);
+                }
+                None => candidates.push(*id),
+            }
+        }
+
+        if replace.is_empty() {
+            break;
+        }
+        total += replace.len();
+        doc.traverse_objects(|object| {
+            if let Object::Reference(id) = object {
+                if let Some(original) = replace.get(id) {
+                    *id = *original;
+                }
+            }
+        });
+        for id in replace.keys() {
+            doc.objects.remove(id);
+        }
+    }
+    total
+}
+
+fn same_object(a: &Object, b: &Object) -> bool {
+    match (a, b) {
+        // Streams also carry their position in the source file, which doesn't matter here
+        (Object::Stream(a), Object::Stream(b)) => a.dict == b.dict && a.content == b.content,
+        _ => a == b,
+    }
+}
+
+fn hash_object<H: Hasher>(object: &Object, hasher: &mut H) {
+    std::mem::discriminant(object).hash(hasher);
+    match object {
+        Object::Null => {}
+        Object::Boolean(b) => b.hash(hasher),
+        Object::Integer(i) => i.hash(hasher),
+        Object::Real(r) => r.to_bits().hash(hasher),
+        Object::Name(n) => n.hash(hasher),
+        Object::String(s, _) => s.hash(hasher),
+        Object::Array(items) => {
+            for item in items {
+                hash_object(item, hasher);
+            }
+        }
+        Object::Dictionary(dict) => hash_dictionary(dict, hasher),
+        Object::Stream(stream) => {
+            hash_dictionary(&stream.dict, hasher);
+            stream.content.hash(hasher);
+        }
+        Object::Reference(id) => id.hash(hasher),
+    }
+}
+
+fn hash_dictionary<H: Hasher>(dict: &lopdf::Dictionary, hasher: &mut H) {
+    // Key order doesn't affect equality, so it mustn't affect the hash
+    let mut entries: Vec<(&Vec<u8>, &Object)> = dict.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    for (key, value) in entries {
+        key.hash(hasher);
+        hash_object(value, hasher);
+    }
+}
+
+/// Changes to a PDF's document metadata
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PdfMetadataOptions {
+    /// Fields to set. An empty string removes the field, custom keys included.
+    /// Dates may be ISO 8601 or PDF dates.
+    pub metadata: Option<PdfMetadata>,
+    /// Remove the info dictionary and XMP packet before applying `metadata`
+    pub strip: Option<bool>,
+}
+
+/// Edit or strip the document info dictionary, keeping any XMP packet in
+/// step with it. Reports the resulting metadata as JSON.
+pub fn edit_pdf_metadata(input_path: &str, output_path: &str, options: &PdfMetadataOptions) -> ConversionResult {
+    finish(try_edit_pdf_metadata(input_path, output_path, options), output_path)
+}
+
+fn try_edit_pdf_metadata(input_path: &str, output_path: &str, options: &PdfMetadataOptions) -> Result<String, String> {
+    let mut doc = load_pdf(input_path)?;
+    let strip = options.strip.unwrap_or(false);
+
+    let old_xmp = doc
+        .catalog()
+        .and_then(|c| c.get_deref(b"Metadata", &doc))
+        .and_then(Object::as_stream)
+        .ok()
+        .map(|s| String::from_utf8_lossy(&s.decompressed_content().unwrap_or_else(|_| s.content.clone())).to_string());
+    if strip {
+        doc.trailer.remove(b"Info");
+        if let Ok(catalog) = doc.catalog_mut() {
+            catalog.remove(b"Metadata");
+        }
+    }
+
+    let changes = options.metadata.clone().unwrap_or_default();
+    let mut updates: Vec<(String, Option<String>)> = [
+        ("Title", changes.title),
+        ("Author", changes.author),
+        ("Subject", changes.subject),
+        ("Keywords", changes.keywords),
+        ("Creator", changes.creator),
+        ("Producer", changes.producer),
+        ("CreationDate", changes.creation_date),
+        ("ModDate", changes.modification_date),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| value.map(|v| (key.to_string(), Some(v))))
+    .collect();
+    for (key, value) in changes.custom.unwrap_or_default() {
+        updates.push((key, Some(value)));
+    }
+    for (_, value) in updates.iter_mut() {
+        if value.as_deref().map(str::is_empty).unwrap_or(true) {
+            *value = None;
+        }
+    }
+    // Any edit counts as a modification, unless the caller picked the date
+    if !updates.is_empty() && !updates.iter().any(|(key, _)| key == "ModDate") {
+        updates.push(("ModDate".to_string(), Some(pdf_date_now())));
+    }
+
+    if !updates.is_empty() {
+        let info_id = match doc.trailer.get(b"Info").and_then(Object::as_reference) {
+            Ok(id) => id,
+            Err(_) => {
+                let inline = doc.trailer.get(b"Info").and_then(Object::as_dict).cloned().unwrap_or_default();
+                let id = doc.add_object(inline);
+                doc.trailer.set("Info", id);
+                id
+            }
+        };
+        let info = doc
+            .get_object_mut(info_id)
+            .and_then(Object::as_dict_mut)
+            .map_err(|_| "The PDF's info dictionary is damaged".to_string())?;
+        for (key, value) in updates {
+            match value {
+                Some(value) if key == "CreationDate" || key == "ModDate" => {
+                    let date = iso_to_pdf_date(&value).ok_or_else(|| format!("Invalid date for {}: '{}'", key, value))?;
+                    info.set(key, Object::string_literal(date));
+                }
+                Some(value) => info.set(key, lopdf::text_string(&value)),
+                None => {
+                    info.remove(key.as_bytes());
+                }
+            }
+        }
+    }
+
+    // Rewrite an existing XMP packet so readers that prefer it agree with the info dictionary
+    if let (false, Some(old_xmp)) = (strip, old_xmp) {
+        let xmp = build_xmp(&read_pdf_metadata(&doc), pdfa_conformance(&old_xmp));
+        set_xmp_metadata(&mut doc, &xmp)?;
+    }
+
+    doc.prune_objects();
+    save_pdf(&mut doc, output_path)?;
+    Ok(serde_json::to_string_pretty(&read_pdf_metadata(&doc)).unwrap())
+}
+
+/// Attach an XMP packet to the document catalog, replacing any existing one
+pub fn set_xmp_metadata(doc: &mut Document, xmp: &str) -> Result<(), String> {
+    // Metadata is left uncompressed so that file-level tools can find it
+    let stream = lopdf::Stream::new(
+        dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+        xmp.as_bytes().to_vec(),
+    )
+    .with_compression(false);
+    let id = doc.add_object(stream);
+    doc.catalog_mut()
+        .map_err(|_| "The PDF has no document catalog".to_string())?
+        .set("Metadata", id);
+    Ok(())
+}
+
+/// The PDF/A part and conformance level an XMP packet claims, e.g. (1, "B")
+pub fn pdfa_conformance(xmp: &str) -> Option<(u8, String)> {
+    let value = |name: &str| {
+        // Either an attribute (pdfaid:part="1") or an element (<pdfaid:part>1</pdfaid:part>)
+        let attribute = format!("pdfaid:{}=", name);
+        if let Some(at) = xmp.find(&attribute) {
+            let rest = &xmp[at + attribute.len()..];
+            let quote = rest.chars().next()?;
+            return rest[1..].split(quote).next().map(|v| v.trim().to_string());
+        }
+        let open = format!("<pdfaid:{}>", name);
+        let start = xmp.find(&open)? + open.len();
+        let end = xmp[start..].find('<')? + start;
+        Some(xmp[start..end].trim().to_string())
+    };
+    let part = value("part")?.parse().ok()?;
+    Some((part, value("conformance").unwrap_or_default().to_uppercase()))
+}
+
+/// Build an XMP packet mirroring the info dictionary, optionally claiming
+/// PDF/A conformance
+pub fn build_xmp(metadata: &PdfMetadata, pdfa: Option<(u8, String)>) -> String {
+    let mut fields = String::new();
+    if let Some(title) = &metadata.title {
+        fields.push_str(&format!(
+            "   <dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>\n",
+            escape_markup(title)
+        ));
+    }
+    if let Some(author) = &metadata.author {
+        fields.push_str(&format!("   <dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>\n", escape_markup(author)));
+    }
+    if let Some(subject) = &metadata.subject {
+        fields.push_str(&format!(
+            "   <dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>\n",
+            escape_markup(subject)
+        ));
+    }
+    let simple = [
+        ("pdf:Keywords", &metadata.keywords),
+        ("pdf:Producer", &metadata.producer),
+        ("xmp:CreatorTool", &metadata.creator),
+        ("xmp:CreateDate", &metadata.creation_date),
+        ("xmp:ModifyDate", &metadata.modification_date),
+        ("xmp:MetadataDate", &metadata.modification_date),
+    ];
+    for (name, value) in simple {
+        if let Some(value) = value {
+            fields.push_str(&format!("   <{0}>{1}</{0}>\n", name, escape_markup(value)));
+        }
+    }
+    if let Some((part, conformance)) = &pdfa {
+        fields.push_str(&format!("   <pdfaid:part>{}</pdfaid:part>\n", part));
+        if !conformance.is_empty() {
+            fields.push_str(&format!("   <pdfaid:conformance>{}</pdfaid:conformance>\n", escape_markup(conformance)));
+        }
+    }
+
+    // Trailing padding lets other tools update the packet in place
+    let padding = format!("{}\n", " ".repeat(99)).repeat(20);
+    format!(
+        r#"<?xpacket begin="{}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:pdf="http://ns.adobe.com/pdf/1.3/"
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:pdfaid="http://www.aiim.org/pdfa/ns/id/">
+   <dc:format>application/pdf</dc:format>
+{}  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+{}<?xpacket end="w"?>"#,
+        '\u{feff}', fields, padding
+    )
+}
@@ -0,0 +1,780 @@
+use std::collections::HashMap;
+use std::fs;
+use lopdf::content::Content;
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use md5::{Digest, Md5};
+use resvg::usvg::fontdb;
+use crate::ConversionResult;
+use crate::converters::pdf_converter::{pdf_date_now, read_pdf_metadata, PDF_INFO_KEYS};
+use crate::converters::pdf_renderer::{find_system_font, glyph_name_to_char, strip_inline_images};
+use crate::converters::text_tools::escape_markup;
+use crate::converters::pdf_tools::{build_xmp, finish, load_pdf, pdfa_conformance, set_xmp_metadata};
+
+/// Characters for WinAnsiEncoding codes 0x80-0x9F; the rest match Latin-1
+const WIN_ANSI_HIGH: [Option<char>; 32] = [
+    Some('€'), None, Some('‚'), Some('ƒ'), Some('„'), Some('…'), Some('†'), Some('‡'),
+    Some('ˆ'), Some('‰'), Some('Š'), Some('‹'), Some('Œ'), None, Some('Ž'), None,
+    None, Some('‘'), Some('’'), Some('“'), Some('”'), Some('•'), Some('–'), Some('—'),
+    Some('˜'), Some('™'), Some('š'), Some('›'), Some('œ'), None, Some('ž'), Some('Ÿ'),
+];
+
+/// Result of checking a PDF against the basic PDF/A requirements
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PdfaReport {
+    /// The conformance the XMP metadata claims, e.g. "PDF/A-2B"
+    pub claimed: Option<String>,
+    /// Whether a conformance is claimed and every check passed
+    pub compliant: bool,
+    pub checks: Vec<PdfaCheck>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PdfaCheck {
+    pub rule: String,
+    pub passed: bool,
+    pub detail: Option<String>,
+}
+
+/// Rewrite a PDF as PDF/A-1b or PDF/A-2b: embed substitutes for fonts that
+/// aren't embedded, add an sRGB output intent and matching XMP metadata,
+/// and remove features the standard forbids. Reports the conformance checks.
+pub fn convert_to_pdfa(input_path: &str, output_path: &str, level: &str) -> ConversionResult {
+    finish(try_convert_to_pdfa(input_path, output_path, level), output_path)
+}
+
+fn try_convert_to_pdfa(input_path: &str, output_path: &str, level: &str) -> Result<String, String> {
+    let part = match level.to_lowercase().trim_start_matches("pdf/a-").trim_start_matches("pdfa-") {
+        "1b" => 1,
+        "2b" => 2,
+        other => return Err(format!("Unsupported PDF/A level '{}'. Use 1b or 2b", other)),
+    };
+    let mut doc = load_pdf(input_path)?;
+
+    let embedded = embed_missing_fonts(&mut doc)?;
+    if part == 1 {
+        if let Some(reason) = find_transparency(&doc) {
+            return Err(format!("PDF/A-1b doesn't allow transparency ({}). Use PDF/A-2b instead", reason));
+        }
+    }
+    remove_forbidden_features(&mut doc, part);
+    set_archival_metadata(&mut doc, part, &fs::read(input_path).unwrap_or_default())?;
+    add_srgb_output_intent(&mut doc)?;
+
+    if part == 1 {
+        // PDF/A-1 is based on PDF 1.4: no object or cross-reference streams
+        doc.objects.retain(|_, o| !matches!(o, Object::Stream(s) if s.dict.type_is(b"XRef") || s.dict.type_is(b"ObjStm")));
+        doc.reference_table.cross_reference_type = lopdf::xref::XrefType::CrossReferenceTable;
+        doc.version = "1.4".to_string();
+    } else if doc.version.as_str() > "1.7" {
+        doc.version = "1.7".to_string();
+    }
+    doc.compress();
+    doc.prune_objects();
+    save_archival_pdf(&mut doc, output_path)?;
+
+    let report = validate_pdfa(output_path)?;
+    let failed: Vec<String> = report
+        .checks
+        .iter()
+        .filter(|c| !c.passed)
+        .map(|c| format!("{}: {}", c.rule, c.detail.clone().unwrap_or_default()))
+        .collect();
+    if !failed.is_empty() {
+        return Err(format!("The PDF couldn't be made PDF/A-{}B: {}", part, failed.join("; ")));
+    }
+    let summary = serde_json::json!({ "embedded_fonts": embedded, "report": report });
+    Ok(serde_json::to_string_pretty(&summary).unwrap())
+}
+
+/// Check whether a PDF claims PDF/A conformance and meets the requirements
+/// that can be verified without a full validator: header, encryption, file
+/// identifier, XMP identification, output intent and device colors, fonts
+/// and forbidden features
+pub fn validate_pdfa(input_path: &str) -> Result<PdfaReport, String> {
+    let bytes = fs::read(input_path).map_err(|e| format!("Failed to read PDF: {}", e))?;
+    let doc = Document::load_mem(&bytes).map_err(|e| format!("Failed to read PDF: {}", e))?;
+
+    let xmp = doc
+        .catalog()
+        .ok()
+        .and_then(|c| c.get_deref(b"Metadata", &doc).and_then(Object::as_stream).ok());
+    let xmp_text = xmp.map(|s| String::from_utf8_lossy(&s.decompressed_content().unwrap_or_else(|_| s.content.clone())).to_string());
+    let claim = xmp_text.as_deref().and_then(pdfa_conformance);
+    let part = claim.as_ref().map(|(part, _)| *part).unwrap_or(2);
+
+    let mut checks = Vec::new();
+    let mut check = |rule: &str, problem: Option<String>| {
+        checks.push(PdfaCheck { rule: rule.to_string(), passed: problem.is_none(), detail: problem });
+    };
+
+    // A comment with at least four bytes above 127 must follow the header
+    let second_line = bytes.split(|&b| b == b'\n' || b == b'\r').find(|l| !l.is_empty() && !l.starts_with(b"%PDF-"));
+    check(
+        "header",
+        match second_line {
+            Some(line) if line.starts_with(b"%") && line.iter().filter(|&&b| b > 127).count() >= 4 => None,
+            _ => Some("The header isn't followed by a binary comment line".to_string()),
+        },
+    );
+    check("encryption", doc.is_encrypted().then(|| "The file is encrypted".to_string()));
+    let has_id = doc.trailer.get(b"ID").and_then(Object::as_array).map(|ids| ids.len() == 2).unwrap_or(false);
+    check("file_identifier", (!has_id).then(|| "The trailer has no file identifier (ID)".to_string()));
+    check(
+        "xmp_metadata",
+        match (xmp, &claim) {
+            (None, _) => Some("There is no XMP metadata stream".to_string()),
+            (Some(_), None) => Some("The XMP metadata doesn't claim PDF/A conformance (pdfaid)".to_string()),
+            (Some(stream), Some(_)) if part == 1 && stream.dict.has(b"Filter") => {
+                Some("PDF/A-1 metadata streams must not be compressed".to_string())
+            }
+            _ => None,
+        },
+    );
+    check("info_matches_xmp", metadata_mismatch(&doc, xmp_text.as_deref().unwrap_or("")));
+    check("output_intent", output_intent_problem(&doc));
+    check("device_colors", device_color_problem(&doc));
+    let missing = unembedded_fonts(&doc);
+    check(
+        "fonts_embedded",
+        (!missing.is_empty()).then(|| format!("Fonts not embedded: {}", missing.join(", "))),
+    );
+    check("lzw_compression", has_filter(&doc, b"LZWDecode").then(|| "LZW-compressed streams are not allowed".to_string()));
+    check(
+        "jpeg2000",
+        (part == 1 && has_filter(&doc, b"JPXDecode")).then(|| "PDF/A-1 doesn't allow JPEG 2000 (JPXDecode) images".to_string()),
+    );
+    check("actions", forbidden_action(&doc));
+    let embedded_files = doc
+        .catalog()
+        .ok()
+        .and_then(|c| c.get_deref(b"Names", &doc).and_then(Object::as_dict).ok())
+        .map(|names| names.has(b"EmbeddedFiles"))
+        .unwrap_or(false);
+    check(
+        "embedded_files",
+        (embedded_files && part == 1).then(|| "PDF/A-1 doesn't allow embedded files".to_string()),
+    );
+    check("annotations", annotation_problem(&doc));
+    if part == 1 {
+        check("transparency", find_transparency(&doc));
+        let streams = doc.objects.values().any(|o| matches!(o, Object::Stream(s) if s.dict.type_is(b"ObjStm") || s.dict.type_is(b"XRef")));
+        check(
+            "object_streams",
+            (streams || matches!(doc.reference_table.cross_reference_type, lopdf::xref::XrefType::CrossReferenceStream))
+                .then(|| "PDF/A-1 doesn't allow object or cross-reference streams".to_string()),
+        );
+        check(
+            "optional_content",
+            doc.catalog().map(|c| c.has(b"OCProperties")).unwrap_or(false)
+                .then(|| "PDF/A-1 doesn't allow optional content (layers)".to_string()),
+        );
+    }
+
+    Ok(PdfaReport {
+        claimed: claim.as_ref().map(|(part, level)| format!("PDF/A-{}{}", part, level)),
+        compliant: claim.is_some() && checks.iter().all(|c| c.passed),
+        checks,
+    })
+}
+
+/// Embed a system font in place of every simple font that isn't embedded.
+/// Returns the names of the fonts that were replaced.
+fn embed_missing_fonts(doc: &mut Document) -> Result<Vec<String>, String> {
+    let font_ids: Vec<ObjectId> = doc
+        .objects
+        .iter()
+        .filter(|(_, o)| matches!(o, Object::Dictionary(d) if d.type_is(b"Font")))
+        .map(|(id, _)| *id)
+        .filter(|id| !font_is_embedded(doc, *id))
+        .collect();
+    if font_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut db = fontdb::Database::new();
+    db.load_system_fonts();
+    let mut programs: HashMap<fontdb::ID, ObjectId> = HashMap::new();
+    let mut replaced = Vec::new();
+
+    for font_id in font_ids {
+        let font = doc.get_dictionary(font_id).map_err(|e| e.to_string())?.clone();
+        let subtype = font.get(b"Subtype").and_then(Object::as_name).unwrap_or(b"");
+        let base = font.get(b"BaseFont").and_then(Object::as_name).map(|n| String::from_utf8_lossy(n).to_string()).unwrap_or_default();
+        if !matches!(subtype, b"Type1" | b"TrueType" | b"MMType1") || matches!(base.as_str(), "Symbol" | "ZapfDingbats") {
+            return Err(format!("The font '{}' isn't embedded and can't be substituted", base));
+        }
+        let flags = font
+            .get_deref(b"FontDescriptor", doc)
+            .and_then(Object::as_dict)
+            .and_then(|d| d.get(b"Flags"))
+            .and_then(Object::as_i64)
+            .unwrap_or(0);
+
+        let face_id = find_system_font(&db, &base, flags).ok_or_else(|| format!("No system font found to embed in place of '{}'", base))?;
+        let name = db.face(face_id).map(|f| f.post_script_name.replace(' ', "")).unwrap_or_default();
+        let substitute = db
+            .with_face_data(face_id, |data, index| substitute_font(doc, &font, &name, data, index))
+            .flatten()
+            .ok_or_else(|| format!("The system font chosen for '{}' can't be embedded as TrueType", base))?;
+
+        let program = match programs.get(&face_id) {
+            Some(id) => *id,
+            None => {
+                let data = db.with_face_data(face_id, |data, _| data.to_vec()).unwrap_or_default();
+                let id = doc.add_object(Stream::new(dictionary! { "Length1" => data.len() as i64 }, data));
+                programs.insert(face_id, id);
+                id
+            }
+        };
+        let mut descriptor = substitute.descriptor;
+        descriptor.set("FontFile2", program);
+        let descriptor_id = doc.add_object(descriptor);
+
+        let font = doc.get_dictionary_mut(font_id).map_err(|e| e.to_string())?;
+        font.set("Subtype", "TrueType");
+        font.set("BaseFont", Object::Name(substitute.name.clone().into_bytes()));
+        font.set("FirstChar", substitute.first_char);
+        font.set("LastChar", substitute.first_char + substitute.widths.len() as i64 - 1);
+        font.set("Widths", substitute.widths.into_iter().map(Object::Integer).collect::<Vec<_>>());
+        font.set("FontDescriptor", descriptor_id);
+        font.set("Encoding", substitute.encoding);
+        replaced.push(format!("{} → {}", base, substitute.name));
+    }
+    replaced.sort();
+    replaced.dedup();
+    Ok(replaced)
+}
+
+struct SubstituteFont {
+    name: String,
+    descriptor: Dictionary,
+    first_char: i64,
+    widths: Vec<i64>,
+    encoding: Object,
+}
+
+/// Metrics and widths of a TrueType font standing in for a simple PDF font
+fn substitute_font(doc: &Document, font: &Dictionary, name: &str, data: &[u8], index: u32) -> Option<SubstituteFont> {
+    let face = ttf_parser::Face::parse(data, index).ok()?;
+    // Only single TrueType-outline fonts can go into FontFile2
+    if index != 0 || face.tables().glyf.is_none() {
+        return None;
+    }
+    let scale = 1000.0 / face.units_per_em() as f32;
+    let name = if name.is_empty() { "EmbeddedFont".to_string() } else { name.to_string() };
+
+    // Non-symbolic TrueType fonts must use WinAnsi or MacRoman, optionally
+    // with Differences
+    let mut differences: HashMap<u8, char> = HashMap::new();
+    let encoding = match font.get_deref(b"Encoding", doc) {
+        Ok(Object::Dictionary(dict)) => {
+            let mut code = 0i64;
+            for item in dict.get(b"Differences").and_then(Object::as_array).map(|a| a.as_slice()).unwrap_or(&[]) {
+                match item {
+                    Object::Integer(n) => code = *n,
+                    Object::Name(glyph) => {
+                        if let Some(c) = glyph_name_to_char(&String::from_utf8_lossy(glyph)) {
+                            differences.insert(code as u8, c);
+                        }
+                        code += 1;
+                    }
+                    _ => {}
+                }
+            }
+            let mut dict = dict.clone();
+            if dict.get(b"BaseEncoding").and_then(Object::as_name).ok() != Some(b"MacRomanEncoding") {
+                dict.set("BaseEncoding", "WinAnsiEncoding");
+            }
+            Object::Dictionary(dict)
+        }
+        Ok(Object::Name(name)) if name == b"MacRomanEncoding" => Object::Name(name.clone()),
+        _ => Object::Name(b"WinAnsiEncoding".to_vec()),
+    };
+
+    let first_char = font.get(b"FirstChar").and_then(Object::as_i64).unwrap_or(32).clamp(0, 255);
+    let last_char = font.get(b"LastChar").and_then(Object::as_i64).unwrap_or(255).clamp(first_char, 255);
+    let missing_width = face.glyph_hor_advance(ttf_parser::GlyphId(0)).unwrap_or(0) as f32 * scale;
+    let widths = (first_char..=last_char)
+        .map(|code| {
+            let c = differences.get(&(code as u8)).copied().or_else(|| match code {
+                0x80..=0x9F => WIN_ANSI_HIGH[code as usize - 0x80],
+                _ => char::from_u32(code as u32),
+            });
+            c.and_then(|c| face.glyph_index(c))
+                .and_then(|g| face.glyph_hor_advance(g))
+                .map(|w| w as f32 * scale)
+                .unwrap_or(missing_width)
+                .round() as i64
+        })
+        .collect();
+
+    let bbox = face.global_bounding_box();
+    let scaled = |v: i16| (v as f32 * scale).round() as i64;
+    let mut flags = 32; // non-symbolic
+    if face.is_monospaced() {
+        flags |= 1;
+    }
+    if face.is_italic() {
+        flags |= 64;
+    }
+    let descriptor = dictionary! {
+        "Type" => "FontDescriptor",
+        "FontName" => Object::Name(name.clone().into_bytes()),
+        "Flags" => flags,
+        "FontBBox" => vec![scaled(bbox.x_min).into(), scaled(bbox.y_min).into(), scaled(bbox.x_max).into(), scaled(bbox.y_max).into()],
+        "ItalicAngle" => face.italic_angle().unwrap_or(0.0),
+        "Ascent" => scaled(face.ascender()),
+        "Descent" => scaled(face.descender()),
+        "CapHeight" => scaled(face.capital_height().unwrap_or(face.ascender())),
+        "StemV" => if face.is_bold() { 120 } else { 80 },
+        "MissingWidth" => missing_width.round() as i64,
+    };
+    Some(SubstituteFont { name, descriptor, first_char, widths, encoding })
+}
+
+fn font_is_embedded(doc: &Document, font_id: ObjectId) -> bool {
+    let font = match doc.get_dictionary(font_id) {
+        Ok(f) => f,
+        Err(_) => return true,
+    };
+    // Type 3 glyphs are content streams, so there's nothing to embed
+    let font = match font.get(b"Subtype").and_then(Object::as_name) {
+        Ok(b"Type3") => return true,
+        Ok(b"Type0") => match font
+            .get_deref(b"DescendantFonts", doc)
+            .and_then(Object::as_array)
+            .ok()
+            .and_then(|d| d.first())
+            .and_then(|d| match d {
+                Object::Reference(id) => doc.get_dictionary(*id).ok(),
+                Object::Dictionary(dict) => Some(dict),
+                _ => None,
+            }) {
+            Some(descendant) => descendant,
+            None => return false,
+        },
+        _ => font,
+    };
+    font.get_deref(b"FontDescriptor", doc)
+        .and_then(Object::as_dict)
+        .map(|d| d.has(b"FontFile") || d.has(b"FontFile2") || d.has(b"FontFile3"))
+        .unwrap_or(false)
+}
+
+fn unembedded_fonts(doc: &Document) -> Vec<String> {
+    let mut names: Vec<String> = doc
+        .objects
+        .iter()
+        .filter(|(_, o)| matches!(o, Object::Dictionary(d) if d.type_is(b"Font")))
+        .filter(|(id, _)| !font_is_embedded(doc, **id))
+        .map(|(_, o)| {
+            o.as_dict().ok()
+                .and_then(|d| d.get(b"BaseFont").and_then(Object::as_name).ok())
+                .map(|n| String::from_utf8_lossy(n).to_string())
+                .unwrap_or_else(|| "unnamed".to_string())
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Describe the first use of transparency, which PDF/A-1 forbids
+fn find_transparency(doc: &Document) -> Option<String> {
+    for object in doc.objects.values() {
+        let dict = match object {
+            Object::Dictionary(d) => d,
+            Object::Stream(s) => &s.dict,
+            _ => continue,
+        };
+        if dict.get(b"Subtype").and_then(Object::as_name).ok() == Some(b"Image") && dict.has(b"SMask") {
+            return Some("an image has a soft mask".to_string());
+        }
+        if dict.type_is(b"ExtGState") {
+            if dict.get(b"SMask").and_then(Object::as_name).map(|n| n != b"None").unwrap_or(dict.has(b"SMask")) {
+                return Some("a graphics state uses a soft mask".to_string());
+            }
+            for key in [b"CA".as_slice(), b"ca"] {
+                if dict.get(key).and_then(Object::as_float).map(|a| a < 1.0).unwrap_or(false) {
+                    return Some("a graphics state sets constant alpha".to_string());
+                }
+            }
+            if dict.get(b"BM").and_then(Object::as_name).map(|m| m != b"Normal" && m != b"Compatible").unwrap_or(false) {
+                return Some("a graphics state sets a blend mode".to_string());
+            }
+        }
+        if let Ok(group) = dict.get_deref(b"Group", doc).and_then(Object::as_dict) {
+            if group.get(b"S").and_then(Object::as_name).ok() == Some(b"Transparency") {
+                return Some("a page or form is a transparency group".to_string());
+            }
+        }
+    }
+    None
+}
+
+fn has_filter(doc: &Document, filter: &[u8]) -> bool {
+    doc.objects.values().any(|o| match o {
+        Object::Stream(s) => match s.dict.get(b"Filter") {
+            Ok(Object::Name(name)) => name == filter,
+            Ok(Object::Array(names)) => names.iter().any(|n| n.as_name().ok() == Some(filter)),
+            _ => false,
+        },
+        _ => false,
+    })
+}
+
+fn forbidden_action(doc: &Document) -> Option<String> {
+    const FORBIDDEN: [&[u8]; 6] = [b"JavaScript", b"Launch", b"Sound", b"Movie", b"ResetForm", b"ImportData"];
+    if let Ok(catalog) = doc.catalog() {
+        if catalog.has(b"AA") {
+            return Some("The document has additional actions (AA)".to_string());
+        }
+        let javascript = catalog
+            .get_deref(b"Names", doc)
+            .and_then(Object::as_dict)
+            .map(|n| n.has(b"JavaScript"))
+            .unwrap_or(false);
+        if javascript {
+            return Some("The document contains JavaScript".to_string());
+        }
+    }
+    for object in doc.objects.values() {
+        if let Object::Dictionary(dict) = object {
+            if let Ok(kind) = dict.get(b"S").and_then(Object::as_name) {
+                if FORBIDDEN.contains(&kind) && !dict.type_is(b"Border") {
+                    return Some(format!("{} actions are not allowed", String::from_utf8_lossy(kind)));
+                }
+            }
+            if dict.type_is(b"Page") && dict.has(b"AA") {
+                return Some("A page has additional actions (AA)".to_string());
+            }
+        }
+    }
+    None
+}
+
+fn annotation_problem(doc: &Document) -> Option<String> {
+    for object in doc.objects.values() {
+        let dict = match object {
+            Object::Dictionary(d) if d.has(b"Rect") && d.has(b"Subtype") && (d.type_is(b"Annot") || d.has(b"P") || d.has(b"F") || d.has(b"AP")) => d,
+            _ => continue,
+        };
+        let subtype = dict.get(b"Subtype").and_then(Object::as_name).unwrap_or(b"");
+        if subtype == b"Popup" {
+            continue;
+        }
+        let flags = dict.get(b"F").and_then(Object::as_i64).unwrap_or(0);
+        if flags & 4 == 0 || flags & (1 | 2 | 32) != 0 {
+            return Some(format!("A {} annotation is hidden or not set to print", String::from_utf8_lossy(subtype)));
+        }
+    }
+    None
+}
+
+fn pdfa_output_intent(doc: &Document) -> Option<&Dictionary> {
+    let intents = doc
+        .catalog()
+        .ok()
+        .and_then(|c| c.get_deref(b"OutputIntents", doc).and_then(Object::as_array).ok());
+    intents
+        .into_iter()
+        .flatten()
+        .filter_map(|i| match i {
+            Object::Reference(id) => doc.get_dictionary(*id).ok(),
+            Object::Dictionary(d) => Some(d),
+            _ => None,
+        })
+        .find(|i| i.get(b"S").and_then(Object::as_name).ok() == Some(b"GTS_PDFA1"))
+}
+
+fn output_intent_problem(doc: &Document) -> Option<String> {
+    let intent = match pdfa_output_intent(doc) {
+        Some(i) => i,
+        None => return Some("There is no PDF/A output intent".to_string()),
+    };
+    match intent.get_deref(b"DestOutputProfile", doc).and_then(Object::as_stream) {
+        Ok(profile) => {
+            let components = profile.dict.get(b"N").and_then(Object::as_i64).unwrap_or(0);
+            (![1, 3, 4].contains(&components)).then(|| "The output intent profile has no valid color space".to_string())
+        }
+        Err(_) => Some("The output intent has no ICC profile".to_string()),
+    }
+}
+
+/// Device colors must be covered by the output intent: an RGB intent rules
+/// out DeviceCMYK and a CMYK intent rules out DeviceRGB, unless the document
+/// remaps them with a DefaultCMYK or DefaultRGB color space
+fn device_color_problem(doc: &Document) -> Option<String> {
+    let components = pdfa_output_intent(doc)?
+        .get_deref(b"DestOutputProfile", doc)
+        .and_then(Object::as_stream)
+        .ok()?
+        .dict
+        .get(b"N")
+        .and_then(Object::as_i64)
+        .ok()?;
+    let (space, default, operators, intent): (&[u8], &[u8], [&str; 2], &str) = match components {
+        3 => (b"DeviceCMYK", b"DefaultCMYK", ["k", "K"], "RGB"),
+        4 => (b"DeviceRGB", b"DefaultRGB", ["rg", "RG"], "CMYK"),
+        _ => return None,
+    };
+
+    // Remapping is per page in the standard; any default space is taken as covering the document
+    let remapped = doc.objects.values().any(|o| match o {
+        Object::Dictionary(d) => d.has(default),
+        _ => false,
+    });
+    if remapped {
+        return None;
+    }
+
+    let problem = || Some(format!("{} colors are used, which the {} output intent doesn't cover", String::from_utf8_lossy(space), intent));
+    if doc.objects.values().any(|o| match o {
+        Object::Dictionary(d) => uses_color_space(d, space),
+        Object::Stream(s) => uses_color_space(&s.dict, space),
+        _ => false,
+    }) {
+        return problem();
+    }
+
+    let mut contents: Vec<Vec<u8>> = doc.get_pages().values().filter_map(|id| doc.get_page_content(*id).ok()).collect();
+    contents.extend(doc.objects.values().filter_map(|o| match o {
+        Object::Stream(s) if s.dict.get(b"Subtype").and_then(Object::as_name).ok() == Some(b"Form") => {
+            Some(s.decompressed_content().unwrap_or_else(|_| s.content.clone()))
+        }
+        _ => None,
+    }));
+    let sets_device_color = contents.iter().any(|content| {
+        Content::decode(&strip_inline_images(content))
+            .map(|c| c.operations.iter().any(|op| operators.contains(&op.operator.as_str())))
+            .unwrap_or(false)
+    });
+    if sets_device_color {
+        problem()
+    } else {
+        None
+    }
+}
+
+/// Whether a dictionary names a device color space outside of an ICC
+/// profile's /Alternate, which readers only fall back on
+fn uses_color_space(dict: &Dictionary, space: &[u8]) -> bool {
+    fn names(object: &Object, space: &[u8]) -> bool {
+        match object {
+            Object::Name(name) => name == space,
+            Object::Array(items) => items.iter().any(|i| names(i, space)),
+            Object::Dictionary(d) => uses_color_space(d, space),
+            _ => false,
+        }
+    }
+    dict.iter().any(|(key, value)| key.as_slice() != b"Alternate" && names(value, space))
+}
+
+/// Info dictionary entries must match their XMP counterparts
+fn metadata_mismatch(doc: &Document, xmp: &str) -> Option<String> {
+    let metadata = read_pdf_metadata(doc);
+    let fields = [
+        ("Title", &metadata.title),
+        ("Author", &metadata.author),
+        ("Subject", &metadata.subject),
+        ("Keywords", &metadata.keywords),
+        ("Creator", &metadata.creator),
+        ("Producer", &metadata.producer),
+    ];
+    let mismatched: Vec<&str> = fields
+        .iter()
+        .filter(|(_, value)| value.as_ref().map(|v| !xmp.contains(&escape_markup(v))).unwrap_or(false))
+        .map(|(name, _)| *name)
+        .collect();
+    (!mismatched.is_empty()).then(|| format!("Not mirrored in XMP: {}", mismatched.join(", ")))
+}
+
+fn remove_forbidden_features(doc: &mut Document, part: u8) {
+    if let Ok(catalog) = doc.catalog_mut() {
+        catalog.remove(b"AA");
+        if part == 1 {
+            // Layers written by PDF generators carry no meaning once archived
+            catalog.remove(b"OCProperties");
+        }
+    }
+    let names_id = doc.catalog().ok().and_then(|c| c.get(b"Names").and_then(Object::as_reference).ok());
+    let names = match names_id {
+        Some(id) => doc.get_dictionary_mut(id).ok(),
+        None => doc.catalog_mut().ok().and_then(|c| c.get_mut(b"Names").and_then(Object::as_dict_mut).ok()),
+    };
+    if let Some(names) = names {
+        names.remove(b"JavaScript");
+        if part == 1 {
+            names.remove(b"EmbeddedFiles");
+        }
+    }
+
+    for object in doc.objects.values_mut() {
+        if let Object::Dictionary(dict) = object {
+            if dict.type_is(b"Page") {
+                dict.remove(b"AA");
+            }
+            // Annotations must print and stay visible
+            let is_annotation = dict.has(b"Rect") && dict.has(b"Subtype") && (dict.type_is(b"Annot") || dict.has(b"P") || dict.has(b"AP"));
+            if is_annotation && dict.get(b"Subtype").and_then(Object::as_name).ok() != Some(b"Popup") {
+                let flags = dict.get(b"F").and_then(Object::as_i64).unwrap_or(0);
+                dict.set("F", (flags | 4) & !(1 | 2 | 32));
+            }
+        }
+    }
+}
+
+/// Limit the info dictionary to the keys XMP mirrors, fill in the dates,
+/// then write XMP that claims the PDF/A part
+fn set_archival_metadata(doc: &mut Document, part: u8, original: &[u8]) -> Result<(), String> {
+    let info_id = match doc.trailer.get(b"Info").and_then(Object::as_reference) {
+        Ok(id) => id,
+        Err(_) => {
+            let id = doc.add_object(Dictionary::new());
+            doc.trailer.set("Info", id);
+            id
+        }
+    };
+    let now = pdf_date_now();
+    let info = doc.get_dictionary_mut(info_id).map_err(|e| e.to_string())?;
+    let extra: Vec<Vec<u8>> = info
+        .iter()
+        .map(|(key, _)| key.clone())
+        .filter(|key| !PDF_INFO_KEYS.iter().any(|k| k.as_bytes() == key.as_slice()))
+        .collect();
+    for key in extra {
+        info.remove(&key);
+    }
+    if !info.has(b"CreationDate") {
+        info.set("CreationDate", Object::string_literal(now.clone()));
+    }
+    info.set("ModDate", Object::string_literal(now));
+
+    let metadata = read_pdf_metadata(doc);
+    set_xmp_metadata(doc, &build_xmp(&metadata, Some((part, "B".to_string()))))?;
+
+    if doc.trailer.get(b"ID").is_err() {
+        let id = Md5::digest(original).to_vec();
+        doc.trailer.set(
+            "ID",
+            vec![Object::String(id.clone(), StringFormat::Hexadecimal), Object::String(id, StringFormat::Hexadecimal)],
+        );
+    }
+    Ok(())
+}
+
+fn add_srgb_output_intent(doc: &mut Document) -> Result<(), String> {
+    let profile = Stream::new(dictionary! { "N" => 3 }, srgb_icc_profile());
+    let profile_id = doc.add_object(profile);
+    let intent = doc.add_object(dictionary! {
+        "Type" => "OutputIntent",
+        "S" => "GTS_PDFA1",
+        "OutputConditionIdentifier" => Object::string_literal("sRGB IEC61966-2.1"),
+        "RegistryName" => Object::string_literal("http://www.color.org"),
+        "Info" => Object::string_literal("sRGB IEC61966-2.1"),
+        "DestOutputProfile" => profile_id,
+    });
+    doc.catalog_mut()
+        .map_err(|_| "The PDF has no document catalog".to_string())?
+        .set("OutputIntents", vec![Object::Reference(intent)]);
+    Ok(())
+}
+
+/// Save with the binary comment line PDF/A requires after the header
+fn save_archival_pdf(doc: &mut Document, output_path: &str) -> Result<(), String> {
+    doc.trailer.remove(b"Prev");
+    doc.trailer.remove(b"XRefStm");
+    // lopdf writes "%PDF-{version}" as the first line, so the comment rides
+    // along with the version; each of these characters is two bytes above 127
+    let version = doc.version.clone();
+    doc.version = format!("{}\n%\u{e2}\u{e3}\u{cf}\u{d3}", version);
+    let result = doc.save(output_path).map(|_| ()).map_err(|e| format!("Failed to write PDF: {}", e));
+    doc.version = version;
+    result
+}
+
+/// A version 2 ICC display profile for sRGB: D50-adapted primaries and the
+/// sRGB tone curve as a 1024-entry table
+fn srgb_icc_profile() -> Vec<u8> {
+    fn s15(value: f64) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+    fn xyz(x: f64, y: f64, z: f64) -> Vec<u8> {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for v in [x, y, z] {
+            tag.extend(s15(v));
+        }
+        tag
+    }
+
+    let description = b"sRGB IEC61966-2.1";
+    let mut desc = b"desc\0\0\0\0".to_vec();
+    desc.extend((description.len() as u32 + 1).to_be_bytes());
+    desc.extend(description);
+    desc.push(0);
+    desc.extend([0u8; 8]); // no Unicode description
+    desc.extend([0u8; 3]); // no ScriptCode description
+    desc.extend([0u8; 67]);
+
+    let mut cprt = b"text\0\0\0\0".to_vec();
+    cprt.extend(b"No copyright, use freely\0");
+
+    let mut curve = b"curv\0\0\0\0".to_vec();
+    curve.extend(1024u32.to_be_bytes());
+    for i in 0..1024 {
+        let v = i as f64 / 1023.0;
+        let linear = if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) };
+        curve.extend(((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+
+    // The three tone curves share one tag body
+    let tags: Vec<(&[u8; 4], usize)> = vec![
+        (b"desc", 0), (b"cprt", 1), (b"wtpt", 2), (b"rXYZ", 3), (b"gXYZ", 4), (b"bXYZ", 5),
+        (b"rTRC", 6), (b"gTRC", 6), (b"bTRC", 6),
+    ];
+    let bodies = [
+        desc,
+        cprt,
+        xyz(0.9642, 1.0, 0.8249),
+        xyz(0.4361, 0.2225, 0.0139),
+        xyz(0.3851, 0.7169, 0.0971),
+        xyz(0.1431, 0.0606, 0.7141),
+        curve,
+    ];
+
+    let mut offsets = Vec::new();
+    let mut data = Vec::new();
+    let mut offset = 128 + 4 + tags.len() * 12;
+    for body in &bodies {
+        offsets.push((offset, body.len()));
+        data.extend(body);
+        while data.len() % 4 != 0 {
+            data.push(0);
+        }
+        offset = 128 + 4 + tags.len() * 12 + data.len();
+    }
+    let size = 128 + 4 + tags.len() * 12 + data.len();
+
+    let mut profile = Vec::with_capacity(size);
+    profile.extend((size as u32).to_be_bytes());
+    profile.extend([0u8; 4]); // preferred CMM
+    profile.extend([2, 0x10, 0, 0]); // version 2.1
+    profile.extend(b"mntrRGB XYZ ");
+    profile.extend([0u8; 12]); // creation date
+    profile.extend(b"acsp");
+    profile.extend([0u8; 24]); // platform, flags, manufacturer, model, attributes
+    profile.extend([0u8; 4]); // perceptual intent
+    profile.extend(s15(0.9642));
+    profile.extend(s15(1.0));
+    profile.extend(s15(0.8249));
+    profile.extend([0u8; 48]); // creator, profile ID and reserved bytes
+    profile.extend((tags.len() as u32).to_be_bytes());
+    for (signature, body) in &tags {
+        let (offset, length) = offsets[*body];
+        profile.extend(*signature);
+        profile.extend((offset as u32).to_be_bytes());
+        profile.extend((length as u32).to_be_bytes());
+    }
+    profile.extend(data);
+    profile
+}
@@ -0,0 +1,586 @@
+use std::collections::HashMap;
+use std::path::Path;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use crate::converters::text_tools::escape_markup;
+
+// ---------------------------------------------------------------------------
+// Document model shared by the RTF, DOC and ODT readers
+// ---------------------------------------------------------------------------
+
+/// Character formatting of a piece of text
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Format {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strike: bool,
+    pub mono: bool,
+    pub vert: Option<&'static str>,
+    /// CSS colors
+    pub color: Option<String>,
+    pub background: Option<String>,
+    pub link: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Inline {
+    Text(Format, String),
+    Break,
+    /// A picture: its path among the document's images or a URL, alt text
+    /// and display size in pixels
+    Image(String, String, Option<(u32, u32)>),
+    Anchor(String),
+    NoteRef(usize),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ParagraphKind {
+    #[default]
+    Normal,
+    Quote,
+    Code,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Paragraph {
+    pub content: Vec<Inline>,
+    pub kind: ParagraphKind,
+    /// CSS text alignment
+    pub align: Option<&'static str>,
+    pub heading: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct List {
+    /// The HTML list type ("1", "a", "A", "i" or "I"), None for bullets
+    pub ordered: Option<&'static str>,
+    pub start: usize,
+    /// The list of the source document the items belong to
+    pub id: usize,
+    pub items: Vec<ListItem>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListItem {
+    /// The marker written in plain text, such as "3." or a bullet
+    pub marker: String,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Block {
+    Paragraph(Paragraph),
+    List(List),
+    Table(Vec<TableRow>),
+    PageBreak,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableRow {
+    pub header: bool,
+    pub cells: Vec<TableCell>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableCell {
+    pub columns: usize,
+    pub rows: usize,
+    pub blocks: Vec<Block>,
+}
+
+pub struct RichDocument {
+    pub blocks: Vec<Block>,
+    pub notes: Vec<Vec<Block>>,
+    /// Embedded pictures by path
+    pub images: HashMap<String, Vec<u8>>,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub paragraphs: usize,
+    pub tables: usize,
+    pub image_count: usize,
+}
+
+impl RichDocument {
+    pub fn summary(&self) -> String {
+        format!(
+            "Converted {} paragraph(s), {} table(s), {} image(s) and {} footnote(s)",
+            self.paragraphs,
+            self.tables,
+            self.image_count,
+            self.notes.len()
+        )
+    }
+}
+
+/// Keep the bytes of a picture found inline in the document and return the
+/// path an `Inline::Image` refers to it by
+pub fn store_image(images: &mut HashMap<String, Vec<u8>>, bytes: Vec<u8>) -> String {
+    let extension = image::guess_format(&bytes)
+        .ok()
+        .and_then(|f| f.extensions_str().first().copied())
+        .unwrap_or("bin");
+    let path = format!("image{}.{}", images.len() + 1, extension);
+    images.insert(path.clone(), bytes);
+    path
+}
+
+/// An RGB color as a CSS hex color
+pub fn css_color([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02X}{:02X}{:02X}", r, g, b)
+}
+
+/// A list paragraph from a format that marks items one paragraph at a time
+#[derive(Debug, Clone)]
+pub struct ListEntry {
+    pub level: usize,
+    /// The list of the source document the item belongs to
+    pub id: usize,
+    pub ordered: Option<&'static str>,
+    pub number: usize,
+    pub marker: String,
+}
+
+/// Append a paragraph to `blocks`, nesting a list item into the list that
+/// ends them. A top-level item of another list or kind starts a new list
+pub fn push_paragraph(blocks: &mut Vec<Block>, paragraph: Paragraph, list: Option<ListEntry>) {
+    match list {
+        Some(entry) => push_list_item(blocks, entry.level, false, entry, paragraph),
+        None => blocks.push(Block::Paragraph(paragraph)),
+    }
+}
+
+fn push_list_item(blocks: &mut Vec<Block>, depth: usize, nested: bool, entry: ListEntry, paragraph: Paragraph) {
+    if depth > 0 {
+        if let Some(Block::List(list)) = blocks.last_mut() {
+            if let Some(item) = list.items.last_mut() {
+                return push_list_item(&mut item.blocks, depth - 1, true, entry, paragraph);
+            }
+        }
+    }
+    let item = ListItem { marker: entry.marker, blocks: vec![Block::Paragraph(paragraph)] };
+    match blocks.last_mut() {
+        Some(Block::List(list)) if nested || (list.id == entry.id && list.ordered == entry.ordered) => {
+            list.items.push(item)
+        }
+        _ => blocks.push(Block::List(List {
+            ordered: entry.ordered,
+            start: entry.number,
+            id: entry.id,
+            items: vec![item],
+        })),
+    }
+}
+
+/// Merge flags of a cell, for formats that mark merged cells instead of
+/// giving their spans
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CellMerge {
+    pub first_column: bool,
+    pub merged_column: bool,
+    pub first_row: bool,
+    pub merged_row: bool,
+}
+
+/// Turns the merge flags of a table's rows into cell spans as the rows arrive
+#[derive(Debug, Default)]
+pub struct MergedCells {
+    /// The row and cell a vertical merge started in, per cell position
+    origins: Vec<Option<(usize, usize)>>,
+}
+
+impl MergedCells {
+    pub fn push_row(&mut self, rows: &mut Vec<TableRow>, header: bool, cells: Vec<(Vec<Block>, CellMerge)>) {
+        let mut row = TableRow { header, cells: Vec::new() };
+        for (n, (blocks, merge)) in cells.into_iter().enumerate() {
+            if merge.merged_row {
+                if let Some((r, c)) = self.origins.get(n).copied().flatten() {
+                    if let Some(origin) = rows.get_mut(r).and_then(|row| row.cells.get_mut(c)) {
+                        origin.rows += 1;
+                    }
+                }
+                continue;
+            }
+            if merge.merged_column {
+                if let Some(last) = row.cells.last_mut() {
+                    last.columns += 1;
+                    continue;
+                }
+            }
+            if self.origins.len() <= n {
+                self.origins.resize(n + 1, None);
+            }
+            self.origins[n] = merge.first_row.then_some((rows.len(), row.cells.len()));
+            row.cells.push(TableCell { columns: 1, rows: 1, blocks });
+        }
+        rows.push(row);
+    }
+}
+
+pub fn image_mime(bytes: &[u8]) -> &'static str {
+    match image::guess_format(bytes) {
+        Ok(image::ImageFormat::Png) => "image/png",
+        Ok(image::ImageFormat::Jpeg) => "image/jpeg",
+        Ok(image::ImageFormat::Gif) => "image/gif",
+        Ok(image::ImageFormat::Bmp) => "image/bmp",
+        Ok(image::ImageFormat::WebP) => "image/webp",
+        Ok(image::ImageFormat::Tiff) => "image/tiff",
+        _ if bytes.starts_with(b"<svg") || bytes.starts_with(b"<?xml") => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The document's pictures as data URIs, for HTML that stands alone
+pub fn embedded_sources(doc: &RichDocument) -> HashMap<String, String> {
+    doc.images
+        .iter()
+        .map(|(path, bytes)| (path.clone(), format!("data:{};base64,{}", image_mime(bytes), BASE64.encode(bytes))))
+        .collect()
+}
+
+// ---------------------------------------------------------------------------
+// HTML output
+// ---------------------------------------------------------------------------
+
+/// The document as a standalone HTML page, with pictures pointing at `sources`
+pub fn html_document(doc: &RichDocument, sources: &HashMap<String, String>, input_path: &str) -> String {
+    let mut body = String::new();
+    html_blocks(&doc.blocks, sources, &mut body);
+    if !doc.notes.is_empty() {
+        body.push_str("<section class=\"footnotes\">\n<ol>\n");
+        for (n, note) in doc.notes.iter().enumerate() {
+            let mut content = String::new();
+            html_blocks(note, sources, &mut content);
+            let back = format!(" <a href=\"#fnref{}\" class=\"footnote-back\">\u{21a9}</a>", n + 1);
+            match content.rfind("</p>") {
+                Some(pos) => content.insert_str(pos, &back),
+                None => content.push_str(&back),
+            }
+            body.push_str(&format!("<li id=\"fn{}\">{}</li>\n", n + 1, content.trim_end()));
+        }
+        body.push_str("</ol>\n</section>\n");
+    }
+
+    let title = doc.title.clone().unwrap_or_else(|| {
+        Path::new(input_path)
+            .file_stem()
+            .and_then(|n| n.to_str())
+            .unwrap_or("Document")
+            .to_string()
+    });
+    let author = doc
+        .author
+        .as_ref()
+        .map(|a| format!("\n    <meta name=\"author\" content=\"{}\">", escape_markup(a)))
+        .unwrap_or_default();
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">{}
+    <title>{}</title>
+    <style>
+        body {{
+            font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif;
+            max-width: 800px;
+            margin: 40px auto;
+            padding: 20px;
+            line-height: 1.6;
+        }}
+        table {{
+            border-collapse: collapse;
+            width: 100%;
+            margin: 20px 0;
+        }}
+        th, td {{
+            border: 1px solid #ddd;
+            padding: 8px;
+            text-align: left;
+            vertical-align: top;
+        }}
+        th {{ background: #f5f5f5; }}
+        img {{ max-width: 100%; height: auto; }}
+        blockquote {{ margin: 1em 0; padding-left: 1em; border-left: 4px solid #ddd; color: #555; }}
+        pre {{ background: #f5f5f5; padding: 12px; overflow-x: auto; }}
+        .footnotes {{ border-top: 1px solid #ddd; margin-top: 2em; font-size: 0.9em; }}
+    </style>
+</head>
+<body>
+{}</body>
+</html>"#,
+        author,
+        escape_markup(&title),
+        body
+    )
+}
+
+fn html_blocks(blocks: &[Block], sources: &HashMap<String, String>, out: &mut String) {
+    let mut i = 0;
+    while i < blocks.len() {
+        match &blocks[i] {
+            Block::Paragraph(Paragraph { heading: Some(level), content, .. }) => {
+                out.push_str(&format!("<h{0}>{1}</h{0}>\n", level, html_inlines(content, sources)));
+            }
+            Block::Paragraph(Paragraph { kind: ParagraphKind::Code, .. }) => {
+                let mut lines = Vec::new();
+                while let Some(Block::Paragraph(Paragraph { kind: ParagraphKind::Code, heading: None, content, .. })) =
+                    blocks.get(i)
+                {
+                    lines.push(html_inlines(content, sources));
+                    i += 1;
+                }
+                out.push_str(&format!("<pre>{}</pre>\n", lines.join("\n")));
+                continue;
+            }
+            Block::Paragraph(Paragraph { kind: ParagraphKind::Quote, .. }) => {
+                out.push_str("<blockquote>\n");
+                while let Some(Block::Paragraph(Paragraph { kind: ParagraphKind::Quote, heading: None, content, .. })) =
+                    blocks.get(i)
+                {
+                    out.push_str(&format!("<p>{}</p>\n", html_inlines(content, sources)));
+                    i += 1;
+                }
+                out.push_str("</blockquote>\n");
+                continue;
+            }
+            Block::Paragraph(paragraph) => {
+                let style = paragraph
+                    .align
+                    .map(|a| format!(" style=\"text-align: {}\"", a))
+                    .unwrap_or_default();
+                out.push_str(&format!("<p{}>{}</p>\n", style, html_inlines(&paragraph.content, sources)));
+            }
+            Block::List(list) => {
+                let tag = match list.ordered {
+                    Some(kind) => {
+                        out.push_str("<ol");
+                        if kind != "1" {
+                            out.push_str(&format!(" type=\"{}\"", kind));
+                        }
+                        if list.start != 1 {
+                            out.push_str(&format!(" start=\"{}\"", list.start));
+                        }
+                        out.push_str(">\n");
+                        "ol"
+                    }
+                    None => {
+                        out.push_str("<ul>\n");
+                        "ul"
+                    }
+                };
+                for item in &list.items {
+                    let mut content = String::new();
+                    html_blocks(&item.blocks, sources, &mut content);
+                    out.push_str(&format!("<li>{}</li>\n", unwrap_paragraph(content.trim_end())));
+                }
+                out.push_str(&format!("</{}>\n", tag));
+            }
+            Block::Table(rows) => {
+                out.push_str("<table>\n");
+                for row in rows {
+                    out.push_str("<tr>\n");
+                    let tag = if row.header { "th" } else { "td" };
+                    for cell in &row.cells {
+                        let mut attrs = String::new();
+                        if cell.columns > 1 {
+                            attrs.push_str(&format!(" colspan=\"{}\"", cell.columns));
+                        }
+                        if cell.rows > 1 {
+                            attrs.push_str(&format!(" rowspan=\"{}\"", cell.rows));
+                        }
+                        let mut content = String::new();
+                        html_blocks(&cell.blocks, sources, &mut content);
+                        out.push_str(&format!("<{0}{1}>{2}</{0}>\n", tag, attrs, unwrap_paragraph(content.trim_end())));
+                    }
+                    out.push_str("</tr>\n");
+                }
+                out.push_str("</table>\n");
+            }
+            Block::PageBreak => out.push_str("<div style=\"page-break-before: always\"></div>\n"),
+        }
+        i += 1;
+    }
+}
+
+fn html_inlines(content: &[Inline], sources: &HashMap<String, String>) -> String {
+    let mut html = String::new();
+    let mut open_link: Option<&str> = None;
+    for inline in content {
+        let link = match inline {
+            Inline::Text(format, _) => format.link.as_deref(),
+            _ => None,
+        };
+        if open_link != link {
+            if open_link.is_some() {
+                html.push_str("</a>");
+            }
+            if let Some(href) = link {
+                html.push_str(&format!("<a href=\"{}\">", escape_markup(href)));
+            }
+            open_link = link;
+        }
+        match inline {
+            Inline::Text(format, text) => {
+                let mut inner = escape_markup(text).replace('\t', "\u{2003}");
+                let mut styles = Vec::new();
+                if let Some(color) = &format.color {
+                    styles.push(format!("color: {}", color));
+                }
+                if let Some(background) = &format.background {
+                    styles.push(format!("background: {}", background));
+                }
+                if !styles.is_empty() && format.link.is_none() {
+                    inner = format!("<span style=\"{}\">{}</span>", escape_markup(&styles.join("; ")), inner);
+                }
+                if let Some(tag) = format.vert {
+                    inner = format!("<{0}>{1}</{0}>", tag, inner);
+                }
+                for (on, tag) in [
+                    (format.mono, "code"),
+                    (format.strike, "s"),
+                    (format.underline && format.link.is_none(), "u"),
+                    (format.italic, "em"),
+                    (format.bold, "strong"),
+                ] {
+                    if on {
+                        inner = format!("<{0}>{1}</{0}>", tag, inner);
+                    }
+                }
+                html.push_str(&inner);
+            }
+            Inline::Break => html.push_str("<br>"),
+            Inline::Image(target, alt, size) => {
+                let src = sources.get(target).unwrap_or(target);
+                let size = size.map(|(w, h)| format!(" width=\"{}\" height=\"{}\"", w, h)).unwrap_or_default();
+                html.push_str(&format!("<img src=\"{}\" alt=\"{}\"{}>", escape_markup(src), escape_markup(alt), size));
+            }
+            Inline::Anchor(name) => html.push_str(&format!("<a id=\"{}\"></a>", escape_markup(name))),
+            Inline::NoteRef(n) => html.push_str(&format!("<sup><a href=\"#fn{0}\" id=\"fnref{0}\">{0}</a></sup>", n)),
+        }
+    }
+    if open_link.is_some() {
+        html.push_str("</a>");
+    }
+    html
+}
+
+/// A list item or cell is written without the `<p>` around its first
+/// paragraph when no other paragraph follows it
+fn unwrap_paragraph(html: &str) -> String {
+    if let Some(rest) = html.strip_prefix("<p>") {
+        if let Some(end) = rest.find("</p>") {
+            let (inner, after) = (&rest[..end], &rest[end + 4..]);
+            if !inner.contains("<p") && !after.contains("<p") {
+                return format!("{}{}", inner, after);
+            }
+        }
+    }
+    html.to_string()
+}
+
+// ---------------------------------------------------------------------------
+// Plain text output
+// ---------------------------------------------------------------------------
+
+/// The document as plain text with blocks joined by `separator`, followed
+/// by the numbered footnotes
+pub fn plain_text(doc: &RichDocument, separator: &str) -> String {
+    let mut lines = Vec::new();
+    text_blocks(&doc.blocks, &mut lines);
+    let mut text = lines.join(separator);
+    if !doc.notes.is_empty() {
+        text.push_str("\n\n");
+        for (n, note) in doc.notes.iter().enumerate() {
+            let mut note_lines = Vec::new();
+            text_blocks(note, &mut note_lines);
+            text.push_str(&format!("[{}] {}\n", n + 1, note_lines.join(" ").trim()));
+        }
+    }
+    format!("{}\n", text.trim_end())
+}
+
+fn plain_inlines(content: &[Inline]) -> String {
+    let mut text = String::new();
+    for inline in content {
+        match inline {
+            Inline::Text(_, t) => text.push_str(t),
+            Inline::Break => text.push('\n'),
+            Inline::Image(_, alt, _) if !alt.is_empty() => text.push_str(&format!("[{}]", alt)),
+            Inline::NoteRef(n) => text.push_str(&format!("[{}]", n)),
+            _ => {}
+        }
+    }
+    text
+}
+
+fn text_blocks(blocks: &[Block], out: &mut Vec<String>) {
+    let mut i = 0;
+    while i < blocks.len() {
+        match &blocks[i] {
+            Block::Paragraph(Paragraph { kind: ParagraphKind::Code, heading: None, .. }) => {
+                let mut lines = Vec::new();
+                while let Some(Block::Paragraph(Paragraph { kind: ParagraphKind::Code, heading: None, content, .. })) =
+                    blocks.get(i)
+                {
+                    lines.push(plain_inlines(content));
+                    i += 1;
+                }
+                out.push(lines.join("\n"));
+                continue;
+            }
+            Block::Paragraph(paragraph) => out.push(plain_inlines(&paragraph.content)),
+            Block::List(list) => {
+                let mut lines = Vec::new();
+                for item in &list.items {
+                    let bullet = format!("{} ", if item.marker.is_empty() { "\u{2022}" } else { item.marker.as_str() });
+                    let mut item_lines = Vec::new();
+                    text_blocks(&item.blocks, &mut item_lines);
+                    let body = item_lines.join("\n");
+                    for (l, line) in body.lines().enumerate() {
+                        let prefix = if l == 0 { bullet.clone() } else { " ".repeat(bullet.chars().count()) };
+                        lines.push(format!("{}{}", prefix, line));
+                    }
+                    if body.is_empty() {
+                        lines.push(bullet.trim_end().to_string());
+                    }
+                }
+                out.push(lines.join("\n"));
+            }
+            Block::Table(rows) => {
+                let grid = table_grid(rows, |blocks| {
+                    let mut cell = Vec::new();
+                    text_blocks(blocks, &mut cell);
+                    cell.join(" ").replace('\n', " ")
+                });
+                let lines: Vec<String> = grid.iter().map(|row| row.join("\t")).collect();
+                out.push(lines.join("\n"));
+            }
+            Block::PageBreak => {}
+        }
+        i += 1;
+    }
+}
+
+/// Cell texts laid out on the table grid, with spanned positions left empty
+fn table_grid(rows: &[TableRow], cell_text: impl Fn(&[Block]) -> String) -> Vec<Vec<String>> {
+    let mut grid: Vec<Vec<Option<String>>> = vec![Vec::new(); rows.len()];
+    for (r, row) in rows.iter().enumerate() {
+        let mut column = 0;
+        for cell in &row.cells {
+            while grid[r].get(column).map(Option::is_some).unwrap_or(false) {
+                column += 1;
+            }
+            let text = cell_text(&cell.blocks);
+            for (dr, grid_row) in grid.iter_mut().skip(r).take(cell.rows).enumerate() {
+                if grid_row.len() < column + cell.columns {
+                    grid_row.resize(column + cell.columns, None);
+                }
+                for dc in 0..cell.columns {
+                    grid_row[column + dc] = Some(if dr == 0 && dc == 0 { text.clone() } else { String::new() });
+                }
+            }
+            column += cell.columns;
+        }
+    }
+    grid.into_iter().map(|row| row.into_iter().map(Option::unwrap_or_default).collect()).collect()
+}
@@ -0,0 +1,1788 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use encoding_rs::{Encoding, WINDOWS_1252};
+use crate::ConversionResult;
+use crate::converters::docx_writer::picture_data;
+use crate::converters::html_renderer::{
+    css_length, element_style, find_element, is_block, parse_html, read_image_source, text_content, HtmlElement, HtmlNode,
+    TextAlign, TextStyle, HIDDEN_ELEMENTS,
+};
+use crate::converters::markup_converter::markdown_to_html;
+use crate::converters::pdf_tools::finish;
+use crate::converters::rich_text::{
+    css_color, embedded_sources, html_document, plain_text, push_paragraph, store_image, Block, CellMerge, Format,
+    Inline, ListEntry, MergedCells, Paragraph, RichDocument,
+};
+use crate::converters::text_tools::{collect_text, is_monospace_font, list_number};
+
+/// Text width of the A4 page with 2cm margins, in twips
+const CONTENT_WIDTH_TWIPS: i32 = 9638;
+
+// ---------------------------------------------------------------------------
+// Reading
+// ---------------------------------------------------------------------------
+
+/// Convert RTF to plain text, one line per paragraph
+pub fn convert_rtf_to_txt(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_rtf_to_txt(input_path, output_path), output_path)
+}
+
+fn try_convert_rtf_to_txt(input_path: &str, output_path: &str) -> Result<String, String> {
+    let doc = read_rtf(input_path)?;
+    fs::write(output_path, plain_text(&doc, "\n")).map_err(|e| format!("Failed to write text file: {}", e))?;
+    Ok(doc.summary())
+}
+
+/// Convert RTF to HTML, keeping bold, italic, underline, colors, links,
+/// lists, tables, footnotes and PNG/JPEG pictures
+pub fn convert_rtf_to_html(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_rtf_to_html(input_path, output_path), output_path)
+}
+
+fn try_convert_rtf_to_html(input_path: &str, output_path: &str) -> Result<String, String> {
+    let doc = read_rtf(input_path)?;
+    let html = html_document(&doc, &embedded_sources(&doc), input_path);
+    fs::write(output_path, &html).map_err(|e| format!("Failed to write HTML file: {}", e))?;
+    Ok(doc.summary())
+}
+
+fn read_rtf(input_path: &str) -> Result<RichDocument, String> {
+    let data = fs::read(input_path).map_err(|e| format!("Failed to read RTF file: {}", e))?;
+    let start = data
+        .windows(5)
+        .take(1024)
+        .position(|w| w == b"{\\rtf")
+        .ok_or("Not an RTF file: the {\\rtf header is missing")?;
+    let mut reader = RtfReader::new();
+    reader.read(&data[start..]);
+    Ok(reader.finish())
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Open,
+    Close,
+    Word(&'a str, Option<i32>),
+    Symbol(u8),
+    Hex(u8),
+    Text(u8),
+    Binary(&'a [u8]),
+}
+
+/// Splits RTF into groups, control words, control symbols and text bytes
+struct Tokenizer<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            let byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+            return Some(match byte {
+                b'{' => Token::Open,
+                b'}' => Token::Close,
+                // Line ends in the file carry no meaning
+                b'\r' | b'\n' => continue,
+                b'\\' => self.control(),
+                _ => Token::Text(byte),
+            });
+        }
+    }
+}
+
+impl<'a> Tokenizer<'a> {
+    fn control(&mut self) -> Token<'a> {
+        let data = self.data;
+        let start = self.pos;
+        while self.pos < data.len() && data[self.pos].is_ascii_alphabetic() && self.pos - start < 32 {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            let symbol = match data.get(self.pos) {
+                Some(&s) => s,
+                None => return Token::Symbol(b'\\'),
+            };
+            self.pos += 1;
+            return match symbol {
+                b'\'' => {
+                    let hex = data.get(self.pos..self.pos + 2).and_then(|h| std::str::from_utf8(h).ok());
+                    match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                        Some(value) => {
+                            self.pos += 2;
+                            Token::Hex(value)
+                        }
+                        None => Token::Symbol(b'\''),
+                    }
+                }
+                // An escaped line end is a paragraph break
+                b'\r' | b'\n' => Token::Word("par", None),
+                _ => Token::Symbol(symbol),
+            };
+        }
+        let name = std::str::from_utf8(&data[start..self.pos]).unwrap_or("");
+
+        let number_start = self.pos;
+        if data.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        let digits_start = self.pos;
+        while self.pos < data.len() && data[self.pos].is_ascii_digit() && self.pos - digits_start < 10 {
+            self.pos += 1;
+        }
+        let parameter = if self.pos > digits_start {
+            std::str::from_utf8(&data[number_start..self.pos]).ok().and_then(|n| n.parse::<i64>().ok())
+        } else {
+            self.pos = number_start;
+            None
+        };
+        // A single space delimits the control word and is not text
+        if data.get(self.pos) == Some(&b' ') {
+            self.pos += 1;
+        }
+        let parameter = parameter.map(|p| p.clamp(i32::MIN as i64, i32::MAX as i64) as i32);
+
+        if name == "bin" {
+            let length = parameter.unwrap_or(0).max(0) as usize;
+            let end = (self.pos + length).min(data.len());
+            let bytes = &data[self.pos..end];
+            self.pos = end;
+            return Token::Binary(bytes);
+        }
+        Token::Word(name, parameter)
+    }
+}
+
+/// Where the text of the current group goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Destination {
+    Body,
+    Footnote,
+    FontTable,
+    ColorTable,
+    StyleSheet,
+    Info,
+    Title,
+    Author,
+    ListText,
+    FieldInstruction,
+    Bookmark,
+    Picture,
+    Skip,
+}
+
+impl Destination {
+    fn is_body(self) -> bool {
+        matches!(self, Destination::Body | Destination::Footnote)
+    }
+}
+
+/// Destination groups whose content never reaches the output
+const SKIPPED_DESTINATIONS: &[&str] = &[
+    "filetbl", "listtable", "listoverridetable", "revtbl", "rsidtbl", "header", "headerl", "headerr", "headerf",
+    "footer", "footerl", "footerr", "footerf", "nonshppict", "pn", "xe", "tc", "txe", "rxe", "annotation", "atnid",
+    "atnauthor", "atntime", "atnref", "atrfstart", "atrfend", "bkmkend", "do", "objdata", "objclass", "template",
+    "generator", "xmlnstbl", "mmathPr", "themedata", "colorschememapping", "latentstyles", "datastore", "pgdsctbl",
+    "listpicture", "protusertbl", "userprops", "docvar", "fchars", "lchars", "pnseclvl", "ftnsep", "ftnsepc", "ftncn",
+    "aftnsep", "aftnsepc", "aftncn", "private", "blipuid", "picprop", "shpinst", "fldtype", "formfield", "datafield",
+];
+
+#[derive(Debug, Clone, Default)]
+struct CharFormat {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strike: bool,
+    vert: Option<&'static str>,
+    font: Option<i32>,
+    color: i32,
+    background: i32,
+    hidden: bool,
+}
+
+#[derive(Debug, Clone)]
+struct GroupState {
+    destination: Destination,
+    format: CharFormat,
+    link: Option<String>,
+    /// Fallback characters that follow each \uN
+    unicode_skip: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ParagraphProps {
+    align: Option<&'static str>,
+    style: Option<i32>,
+    outline: Option<i32>,
+    in_table: bool,
+    list: bool,
+    level: usize,
+    page_break: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Font {
+    encoding: Option<&'static Encoding>,
+    mono: bool,
+}
+
+#[derive(Debug, Default)]
+struct FontDefinition {
+    id: Option<i32>,
+    encoding: Option<&'static Encoding>,
+    mono: bool,
+    name: String,
+}
+
+#[derive(Debug, Default)]
+struct StyleDefinition {
+    id: Option<i32>,
+    character: bool,
+    outline: Option<i32>,
+    name: String,
+}
+
+#[derive(Debug, Default)]
+struct Picture {
+    hex: Vec<u8>,
+    binary: Vec<u8>,
+    kind: Option<&'static str>,
+    width: i32,
+    height: i32,
+    goal_width: i32,
+    goal_height: i32,
+    scale_x: i32,
+    scale_y: i32,
+}
+
+/// The text being built; footnotes swap in a fresh one while they're read
+#[derive(Debug, Default)]
+struct Body {
+    blocks: Vec<Block>,
+    content: Vec<Inline>,
+    props: ParagraphProps,
+    list_marker: Option<String>,
+    /// Paragraphs of the table cell being read, and the finished cells of the row
+    cell: Vec<Block>,
+    row_cells: Vec<Vec<Block>>,
+    row_merges: Vec<CellMerge>,
+    cell_merge: CellMerge,
+    row_header: bool,
+    table_open: bool,
+    merges: MergedCells,
+}
+
+struct RtfReader {
+    state: GroupState,
+    stack: Vec<GroupState>,
+    /// Whether the next token is the first of its group, and follows \*
+    fresh: bool,
+    star: bool,
+    code_page: &'static Encoding,
+    default_font: Option<i32>,
+    fonts: HashMap<i32, Font>,
+    font: FontDefinition,
+    colors: Vec<Option<[u8; 3]>>,
+    color: Option<[u8; 3]>,
+    /// Paragraph styles: name and outline level
+    styles: HashMap<i32, (String, Option<i32>)>,
+    style: StyleDefinition,
+    title: String,
+    author: String,
+    pending_bytes: Vec<u8>,
+    pending_units: Vec<u16>,
+    skip_chars: usize,
+    /// Text of the list marker, field instruction or bookmark being read
+    destination_text: String,
+    /// Open fields: group depth and hyperlink target
+    fields: Vec<(usize, Option<String>)>,
+    picture: Picture,
+    body: Body,
+    saved_bodies: Vec<Body>,
+    notes: Vec<Vec<Block>>,
+    images: HashMap<String, Vec<u8>>,
+    paragraphs: usize,
+    tables: usize,
+    image_count: usize,
+}
+
+impl RtfReader {
+    fn new() -> Self {
+        RtfReader {
+            state: GroupState {
+                destination: Destination::Body,
+                format: CharFormat::default(),
+                link: None,
+                unicode_skip: 1,
+            },
+            stack: Vec::new(),
+            fresh: false,
+            star: false,
+            code_page: WINDOWS_1252,
+            default_font: None,
+            fonts: HashMap::new(),
+            font: FontDefinition::default(),
+            colors: Vec::new(),
+            color: None,
+            styles: HashMap::new(),
+            style: StyleDefinition::default(),
+            title: String::new(),
+            author: String::new(),
+            pending_bytes: Vec::new(),
+            pending_units: Vec::new(),
+            skip_chars: 0,
+            destination_text: String::new(),
+            fields: Vec::new(),
+            picture: Picture::default(),
+            body: Body::default(),
+            saved_bodies: Vec::new(),
+            notes: Vec::new(),
+            images: HashMap::new(),
+            paragraphs: 0,
+            tables: 0,
+            image_count: 0,
+        }
+    }
+
+    fn read(&mut self, data: &[u8]) {
+        for token in (Tokenizer { data, pos: 0 }) {
+            let fresh = std::mem::replace(&mut self.fresh, false);
+            match token {
+                Token::Text(byte) => self.byte(byte, false),
+                Token::Hex(byte) => self.byte(byte, true),
+                Token::Word("u", Some(value)) => {
+                    self.flush_bytes();
+                    if self.state.destination != Destination::Picture {
+                        self.pending_units.push(if value < 0 { (value + 65536) as u16 } else { value as u16 });
+                    }
+                    self.skip_chars = self.state.unicode_skip;
+                }
+                token => {
+                    self.flush_text();
+                    match token {
+                        Token::Open => {
+                            self.stack.push(self.state.clone());
+                            self.fresh = true;
+                            self.star = false;
+                            self.skip_chars = 0;
+                        }
+                        Token::Close => self.close_group(),
+                        Token::Word(word, parameter) => self.word(word, parameter, fresh),
+                        Token::Symbol(symbol) => self.symbol(symbol, fresh),
+                        Token::Binary(bytes) if self.state.destination == Destination::Picture => {
+                            self.picture.binary.extend_from_slice(bytes);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        self.flush_text();
+        // Text after the last \par is a paragraph too
+        if !self.body.content.is_empty() {
+            self.end_paragraph();
+        }
+    }
+
+    fn finish(mut self) -> RichDocument {
+        while let Some(saved) = self.saved_bodies.pop() {
+            self.body = saved;
+        }
+        let text = |s: &str| Some(s.trim().to_string()).filter(|s| !s.is_empty());
+        RichDocument {
+            blocks: std::mem::take(&mut self.body.blocks),
+            notes: self.notes,
+            images: self.images,
+            title: text(&self.title),
+            author: text(&self.author),
+            paragraphs: self.paragraphs,
+            tables: self.tables,
+            image_count: self.image_count,
+        }
+    }
+
+    fn byte(&mut self, byte: u8, hex: bool) {
+        if self.skip_chars > 0 {
+            self.skip_chars -= 1;
+            return;
+        }
+        match self.state.destination {
+            Destination::Picture => {
+                if !hex && byte.is_ascii_hexdigit() {
+                    self.picture.hex.push(byte);
+                }
+                return;
+            }
+            Destination::FontTable | Destination::StyleSheet | Destination::ColorTable if byte == b';' && !hex => {
+                self.flush_text();
+                self.end_table_entry();
+                return;
+            }
+            Destination::Skip | Destination::Info | Destination::ColorTable => return,
+            _ => {}
+        }
+        self.flush_units();
+        self.pending_bytes.push(byte);
+    }
+
+    fn flush_text(&mut self) {
+        self.flush_bytes();
+        self.flush_units();
+    }
+
+    /// Decode bytes in the code page of the current font
+    fn flush_bytes(&mut self) {
+        if self.pending_bytes.is_empty() {
+            return;
+        }
+        let bytes = std::mem::take(&mut self.pending_bytes);
+        let encoding = self
+            .state
+            .format
+            .font
+            .or(self.default_font)
+            .and_then(|f| self.fonts.get(&f))
+            .and_then(|f| f.encoding)
+            .unwrap_or(self.code_page);
+        let (text, _) = encoding.decode_without_bom_handling(&bytes);
+        self.emit(&text);
+    }
+
+    fn flush_units(&mut self) {
+        if self.pending_units.is_empty() {
+            return;
+        }
+        let units = std::mem::take(&mut self.pending_units);
+        self.emit(&String::from_utf16_lossy(&units));
+    }
+
+    fn emit(&mut self, text: &str) {
+        match self.state.destination {
+            Destination::Body | Destination::Footnote => {
+                if self.state.format.hidden {
+                    return;
+                }
+                let format = self.resolve_format();
+                match self.body.content.last_mut() {
+                    Some(Inline::Text(last, existing)) if *last == format => existing.push_str(text),
+                    _ => self.body.content.push(Inline::Text(format, text.to_string())),
+                }
+            }
+            Destination::FontTable => self.font.name.push_str(text),
+            Destination::StyleSheet => self.style.name.push_str(text),
+            Destination::Title => self.title.push_str(text),
+            Destination::Author => self.author.push_str(text),
+            Destination::ListText | Destination::FieldInstruction | Destination::Bookmark => {
+                self.destination_text.push_str(text)
+            }
+            _ => {}
+        }
+    }
+
+    /// Emit a special character, counting it as a \uN fallback character
+    fn special(&mut self, text: &str) {
+        if self.skip_chars > 0 {
+            self.skip_chars -= 1;
+            return;
+        }
+        self.emit(text);
+    }
+
+    fn resolve_format(&self) -> Format {
+        let format = &self.state.format;
+        let color = |index: i32| {
+            usize::try_from(index)
+                .ok()
+                .and_then(|i| self.colors.get(i).copied().flatten())
+        };
+        let font = format.font.or(self.default_font).and_then(|f| self.fonts.get(&f));
+        Format {
+            bold: format.bold,
+            italic: format.italic,
+            underline: format.underline,
+            strike: format.strike,
+            mono: font.map(|f| f.mono).unwrap_or(false),
+            vert: format.vert,
+            color: color(format.color).filter(|c| *c != [0, 0, 0]).map(css_color),
+            background: color(format.background).filter(|c| *c != [255, 255, 255]).map(css_color),
+            link: self.state.link.clone(),
+        }
+    }
+
+    fn close_group(&mut self) {
+        if self.fields.last().map(|f| f.0) == Some(self.stack.len()) {
+            self.fields.pop();
+        }
+        let parent = match self.stack.pop() {
+            Some(parent) => parent,
+            None => return,
+        };
+        let closed = std::mem::replace(&mut self.state, parent);
+        self.skip_chars = 0;
+        if matches!(closed.destination, Destination::FontTable | Destination::StyleSheet) {
+            self.end_table_entry();
+        }
+        if closed.destination != self.state.destination {
+            self.end_destination(closed.destination);
+        }
+    }
+
+    fn end_destination(&mut self, destination: Destination) {
+        match destination {
+            Destination::ListText if self.state.destination.is_body() => {
+                self.body.list_marker = Some(std::mem::take(&mut self.destination_text));
+            }
+            Destination::FieldInstruction => {
+                let link = hyperlink_target(&std::mem::take(&mut self.destination_text));
+                if let Some(field) = self.fields.last_mut() {
+                    field.1 = link;
+                }
+            }
+            Destination::Bookmark => {
+                let name = std::mem::take(&mut self.destination_text).trim().to_string();
+                if !name.is_empty() && self.state.destination.is_body() {
+                    self.body.content.push(Inline::Anchor(name));
+                }
+            }
+            Destination::Picture => {
+                let picture = std::mem::take(&mut self.picture);
+                if self.state.destination.is_body() {
+                    if let Some(image) = picture_inline(picture, &mut self.images) {
+                        self.image_count += 1;
+                        self.body.content.push(image);
+                    }
+                }
+            }
+            Destination::Footnote => {
+                if !self.body.content.is_empty() {
+                    self.end_paragraph();
+                }
+                let note = std::mem::take(&mut self.body.blocks);
+                if let Some(saved) = self.saved_bodies.pop() {
+                    self.body = saved;
+                }
+                self.notes.push(note);
+                self.body.content.push(Inline::NoteRef(self.notes.len()));
+            }
+            _ => {}
+        }
+    }
+
+    /// A font, color or style definition ends at its semicolon
+    fn end_table_entry(&mut self) {
+        match self.state.destination {
+            Destination::FontTable => {
+                let font = std::mem::take(&mut self.font);
+                if let Some(id) = font.id {
+                    let mono = font.mono || is_monospace_font(font.name.trim());
+                    self.fonts.insert(id, Font { encoding: font.encoding, mono });
+                }
+            }
+            Destination::ColorTable => {
+                let color = self.color.take();
+                self.colors.push(color);
+            }
+            Destination::StyleSheet => {
+                let style = std::mem::take(&mut self.style);
+                if !style.character && !style.name.trim().is_empty() {
+                    let name = style.name.trim().to_lowercase();
+                    self.styles.insert(style.id.unwrap_or(0), (name, style.outline));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn word(&mut self, word: &str, parameter: Option<i32>, fresh: bool) {
+        let star = std::mem::take(&mut self.star);
+        let destination = self.state.destination;
+        if destination == Destination::Skip {
+            return;
+        }
+        let on = parameter != Some(0);
+        match word {
+            "ansicpg" => {
+                if let Some(encoding) = parameter.and_then(code_page_encoding) {
+                    self.code_page = encoding;
+                }
+            }
+            "mac" => self.code_page = code_page_encoding(10000).unwrap_or(WINDOWS_1252),
+            "deff" => self.default_font = parameter,
+            "fonttbl" => self.state.destination = Destination::FontTable,
+            "colortbl" => self.state.destination = Destination::ColorTable,
+            "stylesheet" => self.state.destination = Destination::StyleSheet,
+            "info" => self.state.destination = Destination::Info,
+            "title" if destination == Destination::Info => self.state.destination = Destination::Title,
+            "author" if destination == Destination::Info => self.state.destination = Destination::Author,
+            "listtext" | "pntext" => {
+                self.destination_text.clear();
+                self.state.destination = Destination::ListText;
+            }
+            "pict" => {
+                self.picture = Picture { scale_x: 100, scale_y: 100, ..Picture::default() };
+                self.state.destination = Destination::Picture;
+            }
+            "shppict" => {}
+            "field" => self.fields.push((self.stack.len(), None)),
+            "fldinst" => {
+                self.destination_text.clear();
+                self.state.destination = Destination::FieldInstruction;
+            }
+            "fldrslt" => self.state.link = self.fields.last().and_then(|f| f.1.clone()),
+            "bkmkstart" => {
+                self.destination_text.clear();
+                self.state.destination = Destination::Bookmark;
+            }
+            "footnote" if destination.is_body() => {
+                let body = std::mem::take(&mut self.body);
+                self.saved_bodies.push(body);
+                self.state.destination = Destination::Footnote;
+            }
+            _ if SKIPPED_DESTINATIONS.contains(&word) => self.state.destination = Destination::Skip,
+            // Unknown destinations marked with \* are ignorable
+            _ if star && fresh => self.state.destination = Destination::Skip,
+            _ => match destination {
+                Destination::FontTable => self.font_word(word, parameter),
+                Destination::ColorTable => {
+                    let value = parameter.unwrap_or(0).clamp(0, 255) as u8;
+                    let color = self.color.get_or_insert([0, 0, 0]);
+                    match word {
+                        "red" => color[0] = value,
+                        "green" => color[1] = value,
+                        "blue" => color[2] = value,
+                        _ => {}
+                    }
+                }
+                Destination::StyleSheet => match word {
+                    "s" => self.style.id = parameter,
+                    "cs" | "ds" | "ts" => self.style.character = true,
+                    "outlinelevel" => self.style.outline = parameter,
+                    _ => {}
+                },
+                Destination::Picture => self.picture_word(word, parameter),
+                _ => {
+                    if !self.format_word(word, parameter, on) && destination.is_body() {
+                        self.body_word(word, parameter);
+                    }
+                }
+            },
+        }
+    }
+
+    fn font_word(&mut self, word: &str, parameter: Option<i32>) {
+        match word {
+            "f" => self.font.id = parameter,
+            // An explicit \cpg wins over the character set
+            "fcharset" if self.font.encoding.is_none() => {
+                self.font.encoding = parameter.and_then(charset_code_page).and_then(code_page_encoding);
+            }
+            "cpg" => {
+                if let Some(encoding) = parameter.and_then(code_page_encoding) {
+                    self.font.encoding = Some(encoding);
+                }
+            }
+            "fprq" => self.font.mono |= parameter == Some(1),
+            "fmodern" => self.font.mono = true,
+            _ => {}
+        }
+    }
+
+    fn picture_word(&mut self, word: &str, parameter: Option<i32>) {
+        let value = parameter.unwrap_or(0);
+        match word {
+            "pngblip" => self.picture.kind = Some("png"),
+            "jpegblip" => self.picture.kind = Some("jpeg"),
+            "picw" => self.picture.width = value,
+            "pich" => self.picture.height = value,
+            "picwgoal" => self.picture.goal_width = value,
+            "pichgoal" => self.picture.goal_height = value,
+            "picscalex" => self.picture.scale_x = value,
+            "picscaley" => self.picture.scale_y = value,
+            _ => {}
+        }
+    }
+
+    /// Character formatting and special characters; false for other words
+    fn format_word(&mut self, word: &str, parameter: Option<i32>, on: bool) -> bool {
+        let format = &mut self.state.format;
+        match word {
+            "plain" => *format = CharFormat::default(),
+            "b" => format.bold = on,
+            "i" => format.italic = on,
+            "ulnone" => format.underline = false,
+            "ulc" | "ulhair" => {}
+            w if w.starts_with("ul") => format.underline = on,
+            "strike" | "striked" => format.strike = on,
+            "super" => format.vert = Some("sup"),
+            "sub" => format.vert = Some("sub"),
+            "nosupersub" => format.vert = None,
+            "up" => format.vert = if parameter.unwrap_or(6) > 0 { Some("sup") } else { None },
+            "dn" => format.vert = if parameter.unwrap_or(6) > 0 { Some("sub") } else { None },
+            "f" => format.font = parameter,
+            "cf" => format.color = parameter.unwrap_or(0),
+            "cb" | "chcbpat" | "highlight" => format.background = parameter.unwrap_or(0),
+            "v" => format.hidden = on,
+            "uc" => self.state.unicode_skip = parameter.unwrap_or(1).max(0) as usize,
+            "tab" => self.special("\t"),
+            "emdash" => self.special("\u{2014}"),
+            "endash" => self.special("\u{2013}"),
+            "bullet" => self.special("\u{2022}"),
+            "lquote" => self.special("\u{2018}"),
+            "rquote" => self.special("\u{2019}"),
+            "ldblquote" => self.special("\u{201c}"),
+            "rdblquote" => self.special("\u{201d}"),
+            "emspace" => self.special("\u{2003}"),
+            "enspace" => self.special("\u{2002}"),
+            "qmspace" => self.special("\u{2005}"),
+            "zwj" => self.special("\u{200d}"),
+            "zwnj" => self.special("\u{200c}"),
+            "zwbo" => self.special("\u{200b}"),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Paragraph and table structure
+    fn body_word(&mut self, word: &str, parameter: Option<i32>) {
+        let body = &mut self.body;
+        match word {
+            "par" | "sect" => self.end_paragraph(),
+            "page" => {
+                self.end_paragraph();
+                if !self.body.props.in_table {
+                    self.body.blocks.push(Block::PageBreak);
+                }
+            }
+            "line" => body.content.push(Inline::Break),
+            "pard" => body.props = ParagraphProps::default(),
+            "ql" => body.props.align = None,
+            "qc" => body.props.align = Some("center"),
+            "qr" => body.props.align = Some("right"),
+            "qj" => body.props.align = Some("justify"),
+            "s" => body.props.style = parameter,
+            "outlinelevel" => body.props.outline = parameter,
+            "intbl" => body.props.in_table = true,
+            "ls" => body.props.list = true,
+            "ilvl" => body.props.level = parameter.unwrap_or(0).clamp(0, 8) as usize,
+            "pagebb" => body.props.page_break = true,
+            "cell" => self.end_cell(),
+            // Nested tables are flattened into their outer cell
+            "nestcell" => self.special("\t"),
+            "nestrow" => body.content.push(Inline::Break),
+            "row" => self.end_row(),
+            "trowd" => {
+                body.row_merges.clear();
+                body.cell_merge = CellMerge::default();
+                body.row_header = false;
+            }
+            "trhdr" => body.row_header = true,
+            "clmgf" => body.cell_merge.first_column = true,
+            "clmrg" => body.cell_merge.merged_column = true,
+            "clvmgf" => body.cell_merge.first_row = true,
+            "clvmrg" => body.cell_merge.merged_row = true,
+            "cellx" => {
+                let merge = std::mem::take(&mut body.cell_merge);
+                body.row_merges.push(merge);
+            }
+            _ => {}
+        }
+    }
+
+    fn symbol(&mut self, symbol: u8, fresh: bool) {
+        match symbol {
+            b'\\' | b'{' | b'}' => self.byte(symbol, true),
+            b'~' => self.special("\u{a0}"),
+            b'_' => self.special("\u{2011}"),
+            b'*' if fresh => {
+                self.star = true;
+                self.fresh = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn end_paragraph(&mut self) {
+        let mut content = std::mem::take(&mut self.body.content);
+        trim(&mut content);
+        let marker = self.body.list_marker.take();
+        let props = &self.body.props;
+        let list = if props.list || marker.is_some() {
+            let marker = marker.unwrap_or_default().trim().to_string();
+            let ordered = ordered_marker(&marker);
+            Some(ListEntry {
+                level: props.level,
+                id: 0,
+                ordered: ordered.map(|o| o.0),
+                number: ordered.map(|o| o.1).unwrap_or(1),
+                marker,
+            })
+        } else {
+            None
+        };
+        if content.is_empty() && list.is_none() {
+            if !props.in_table {
+                self.body.table_open = false;
+            }
+            return;
+        }
+
+        let style = props.style.and_then(|s| self.styles.get(&s));
+        let outline = props.outline.or_else(|| style.and_then(|s| s.1));
+        let heading = match style.map(|s| s.0.as_str()) {
+            Some(name) if name.starts_with("heading ") => name[8..].trim().parse::<usize>().ok(),
+            Some("title") => Some(1),
+            _ => outline.filter(|o| (0..9).contains(o)).map(|o| o as usize + 1),
+        }
+        .filter(|_| list.is_none())
+        .map(|level| level.clamp(1, 6));
+
+        if heading.is_some() {
+            // Headings are bold through their style
+            for inline in content.iter_mut() {
+                if let Inline::Text(format, _) = inline {
+                    format.bold = false;
+                }
+            }
+        }
+        let paragraph = Paragraph { content, align: props.align, heading, ..Paragraph::default() };
+        self.paragraphs += 1;
+        if props.in_table {
+            push_paragraph(&mut self.body.cell, paragraph, list);
+        } else {
+            if props.page_break {
+                self.body.blocks.push(Block::PageBreak);
+            }
+            push_paragraph(&mut self.body.blocks, paragraph, list);
+            self.body.table_open = false;
+        }
+    }
+
+    fn end_cell(&mut self) {
+        let in_table = std::mem::replace(&mut self.body.props.in_table, true);
+        self.end_paragraph();
+        self.body.props.in_table = in_table;
+        let cell = std::mem::take(&mut self.body.cell);
+        self.body.row_cells.push(cell);
+    }
+
+    fn end_row(&mut self) {
+        let body = &mut self.body;
+        let cells = std::mem::take(&mut body.row_cells);
+        if cells.is_empty() {
+            return;
+        }
+        if !body.table_open || !matches!(body.blocks.last(), Some(Block::Table(_))) {
+            body.blocks.push(Block::Table(Vec::new()));
+            body.merges = MergedCells::default();
+            self.tables += 1;
+        }
+        if let Some(Block::Table(rows)) = body.blocks.last_mut() {
+            let cells = cells
+                .into_iter()
+                .enumerate()
+                .map(|(n, blocks)| (blocks, body.row_merges.get(n).copied().unwrap_or_default()))
+                .collect();
+            body.merges.push_row(rows, body.row_header, cells);
+        }
+        body.table_open = true;
+    }
+}
+
+/// The target of a HYPERLINK field instruction
+pub fn hyperlink_target(instruction: &str) -> Option<String> {
+    let mut args = Vec::new();
+    let mut chars = instruction.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut arg = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    // Backslashes are doubled inside quoted field arguments
+                    '\\' if chars.peek() == Some(&'\\') => {
+                        chars.next();
+                        arg.push('\\');
+                    }
+                    _ => arg.push(c),
+                }
+            }
+            args.push((arg, true));
+        } else {
+            let mut arg = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                arg.push(c);
+                chars.next();
+            }
+            args.push((arg, false));
+        }
+    }
+    let mut args = args.into_iter();
+    if !args.next()?.0.eq_ignore_ascii_case("HYPERLINK") {
+        return None;
+    }
+    let mut url = None;
+    let mut anchor = None;
+    while let Some((arg, quoted)) = args.next() {
+        match arg.as_str() {
+            "\\l" if !quoted => anchor = args.next().map(|a| a.0),
+            "\\o" | "\\t" if !quoted => {
+                args.next();
+            }
+            a if a.starts_with('\\') && !quoted => {}
+            _ => {
+                if url.is_none() {
+                    url = Some(arg);
+                }
+            }
+        }
+    }
+    match (url, anchor) {
+        (Some(url), Some(anchor)) => Some(format!("{}#{}", url, anchor)),
+        (Some(url), None) => Some(url),
+        (None, Some(anchor)) => Some(format!("#{}", anchor)),
+        (None, None) => None,
+    }
+    .filter(|target| !target.is_empty())
+}
+
+fn picture_inline(picture: Picture, images: &mut HashMap<String, Vec<u8>>) -> Option<Inline> {
+    picture.kind?;
+    let bytes = if picture.binary.is_empty() {
+        picture
+            .hex
+            .chunks_exact(2)
+            .filter_map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+            .collect()
+    } else {
+        picture.binary
+    };
+    image::guess_format(&bytes).ok()?;
+    // Goal sizes are in twips; picw/pich are pixels for PNG and JPEG
+    let scale = |goal: i32, pixels: i32, percent: i32| {
+        let size = if goal > 0 { goal as f32 / 15.0 } else { pixels as f32 };
+        (size * percent.max(1) as f32 / 100.0).round() as u32
+    };
+    let width = scale(picture.goal_width, picture.width, picture.scale_x);
+    let height = scale(picture.goal_height, picture.height, picture.scale_y);
+    let size = if width > 0 && height > 0 { Some((width, height)) } else { None };
+    Some(Inline::Image(store_image(images, bytes), String::new(), size))
+}
+
+/// The code page of a font character set; 0 and 1 use the document's
+fn charset_code_page(charset: i32) -> Option<i32> {
+    Some(match charset {
+        77 => 10000,
+        128 => 932,
+        129 => 949,
+        134 => 936,
+        136 => 950,
+        161 => 1253,
+        162 => 1254,
+        163 => 1258,
+        177 => 1255,
+        178 => 1256,
+        186 => 1257,
+        204 => 1251,
+        222 => 874,
+        238 => 1250,
+        _ => return None,
+    })
+}
+
+fn code_page_encoding(code_page: i32) -> Option<&'static Encoding> {
+    let label = match code_page {
+        437 | 850 | 1252 => "windows-1252".to_string(),
+        866 => "ibm866".to_string(),
+        874 => "windows-874".to_string(),
+        932 => "shift_jis".to_string(),
+        936 => "gbk".to_string(),
+        949 => "euc-kr".to_string(),
+        950 => "big5".to_string(),
+        1250..=1258 => format!("windows-{}", code_page),
+        10000 => "macintosh".to_string(),
+        20866 => "koi8-r".to_string(),
+        28591..=28606 => format!("iso-8859-{}", code_page - 28590),
+        65001 => "utf-8".to_string(),
+        _ => return None,
+    };
+    Encoding::for_label(label.as_bytes())
+}
+
+/// Drop spaces at the start and end of a paragraph; indented code keeps
+/// its leading spaces
+fn trim(content: &mut Vec<Inline>) {
+    while let Some(Inline::Text(format, text)) = content.first_mut() {
+        if format.mono {
+            break;
+        }
+        let trimmed = text.trim_start_matches([' ', '\t']);
+        if !trimmed.is_empty() {
+            *text = trimmed.to_string();
+            break;
+        }
+        content.remove(0);
+    }
+    while let Some(Inline::Text(_, text)) = content.last_mut() {
+        let trimmed = text.trim_end_matches([' ', '\t']).len();
+        if trimmed > 0 {
+            text.truncate(trimmed);
+            break;
+        }
+        content.pop();
+    }
+}
+
+/// The HTML list type and start value for a list marker such as "3.",
+/// "b)" or "iv."; bullets give None
+fn ordered_marker(marker: &str) -> Option<(&'static str, usize)> {
+    let trimmed = marker.trim();
+    let core = trimmed.trim_start_matches('(').trim_end_matches(['.', ')']);
+    let punctuated = core.len() < trimmed.len();
+    if core.is_empty() {
+        return None;
+    }
+    if core.chars().all(|c| c.is_ascii_digit()) {
+        return Some(("1", core.parse().unwrap_or(1)));
+    }
+    if !punctuated {
+        return None;
+    }
+    let roman = |s: &str| {
+        let mut total = 0;
+        let mut last = 0;
+        for c in s.chars().rev() {
+            let value = match c.to_ascii_lowercase() {
+                'i' => 1,
+                'v' => 5,
+                'x' => 10,
+                'l' => 50,
+                'c' => 100,
+                _ => return None,
+            };
+            if value < last {
+                total -= value;
+            } else {
+                total += value;
+                last = value;
+            }
+        }
+        Some(total.max(1) as usize)
+    };
+    let upper = core.chars().all(|c| c.is_ascii_uppercase());
+    let lower = core.chars().all(|c| c.is_ascii_lowercase());
+    if core.len() == 1 && (upper || lower) && !core.eq_ignore_ascii_case("i") {
+        let letter = core.chars().next().unwrap_or('a');
+        let start = letter.to_ascii_lowercase() as usize - 'a' as usize + 1;
+        return Some((if upper { "A" } else { "a" }, start));
+    }
+    match roman(core) {
+        Some(start) if upper => Some(("I", start)),
+        Some(start) if lower => Some(("i", start)),
+        _ => None,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Writing
+// ---------------------------------------------------------------------------
+
+/// Convert a text file to RTF. Paragraphs are separated by blank lines;
+/// code keeps every line and uses a monospace font.
+pub fn convert_text_to_rtf(input_path: &str, output_path: &str, code: bool) -> ConversionResult {
+    finish(try_convert_text_to_rtf(input_path, output_path, code), output_path)
+}
+
+fn try_convert_text_to_rtf(input_path: &str, output_path: &str, code: bool) -> Result<String, String> {
+    let text = fs::read_to_string(input_path).map_err(|e| format!("Failed to read text file: {}", e))?;
+    let text = text.replace("\r\n", "\n");
+    let mut body = String::new();
+    let mut paragraphs = 0;
+    if code {
+        for line in text.lines() {
+            body.push_str(&format!("\\pard\\plain\\f2\\fs20 {}\\par\n", rtf_text(line)));
+            paragraphs += 1;
+        }
+    } else {
+        for paragraph in text.split("\n\n").map(str::trim_end).filter(|p| !p.trim().is_empty()) {
+            let paragraph = paragraph.trim_start_matches('\n');
+            body.push_str(&format!("\\pard\\plain{} {}\\par\n", BODY_FORMAT, rtf_text(paragraph)));
+            paragraphs += 1;
+        }
+    }
+    let rtf = rtf_document(&body, &[], None);
+    fs::write(output_path, rtf).map_err(|e| format!("Failed to write RTF file: {}", e))?;
+    Ok(format!("Wrote {} paragraph(s)", paragraphs))
+}
+
+/// Convert Markdown to RTF
+pub fn convert_markdown_to_rtf(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_markdown_to_rtf(input_path, output_path), output_path)
+}
+
+fn try_convert_markdown_to_rtf(input_path: &str, output_path: &str) -> Result<String, String> {
+    let markdown = fs::read_to_string(input_path).map_err(|e| format!("Failed to read Markdown file: {}", e))?;
+    let html = markdown_to_html(&markdown);
+    write_html_rtf(&html, Path::new(input_path).parent().unwrap_or(Path::new("")), output_path)
+}
+
+/// Convert an HTML page to RTF with heading styles, emphasis, colors,
+/// lists, tables, links and pictures
+pub fn convert_html_to_rtf(input_path: &str, output_path: &str) -> ConversionResult {
+    finish(try_convert_html_to_rtf(input_path, output_path), output_path)
+}
+
+fn try_convert_html_to_rtf(input_path: &str, output_path: &str) -> Result<String, String> {
+    let bytes = fs::read(input_path).map_err(|e| format!("Failed to read HTML file: {}", e))?;
+    let html = String::from_utf8_lossy(&bytes);
+    write_html_rtf(&html, Path::new(input_path).parent().unwrap_or(Path::new("")), output_path)
+}
+
+/// Paragraph and character properties of body text
+const BODY_FORMAT: &str = "\\sa160\\sl276\\slmult1\\f0\\fs24";
+const HEADING_SIZES: [u32; 6] = [20, 16, 14, 13, 12, 11];
+
+fn rtf_document(body: &str, colors: &[[u8; 3]], title: Option<&str>) -> String {
+    let mut rtf = String::from(
+        "{\\rtf1\\ansi\\ansicpg1252\\deff0\\deflang1033\n\
+         {\\fonttbl{\\f0\\froman\\fcharset0\\fprq2 Times New Roman;}{\\f1\\fswiss\\fcharset0\\fprq2 Arial;}\
+         {\\f2\\fmodern\\fcharset0\\fprq1 Courier New;}{\\f3\\ftech\\fcharset2\\fprq2 Symbol;}}\n{\\colortbl;",
+    );
+    for [r, g, b] in colors {
+        rtf.push_str(&format!("\\red{}\\green{}\\blue{};", r, g, b));
+    }
+    rtf.push_str("}\n{\\stylesheet{\\s0\\sa160\\sl276\\slmult1\\f0\\fs24 Normal;}");
+    for (n, size) in HEADING_SIZES.iter().enumerate() {
+        rtf.push_str(&format!(
+            "{{\\s{0}\\outlinelevel{1}\\keepn\\sb240\\sa120\\sbasedon0\\snext0\\b\\f1\\fs{2} heading {0};}}",
+            n + 1,
+            n,
+            size * 2
+        ));
+    }
+    rtf.push_str("}\n");
+    if let Some(title) = title {
+        rtf.push_str(&format!("{{\\info{{\\title {}}}}}\n", rtf_text(title)));
+    }
+    rtf.push_str("\\paperw11906\\paperh16838\\margl1134\\margr1134\\margt1134\\margb1134\\viewkind4\\uc1\n");
+    rtf.push_str(body);
+    rtf.push_str("}\n");
+    rtf
+}
+
+fn write_html_rtf(html: &str, base_dir: &Path, output_path: &str) -> Result<String, String> {
+    let root = parse_html(html);
+    let body = find_element(&root, "body").unwrap_or(&root);
+    let title = find_element(&root, "title")
+        .or_else(|| find_element(body, "h1"))
+        .map(|t| text_content(t).split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|t| !t.is_empty());
+
+    let mut writer = RtfWriter {
+        base_dir,
+        colors: Vec::new(),
+        pending_marker: None,
+        pending_bookmarks: Vec::new(),
+        page_break: false,
+        paragraphs: 0,
+        tables: 0,
+        images: 0,
+    };
+    let mut content = String::new();
+    writer.block_children(body, &TextStyle::default(), &RtfContext::default(), &mut content);
+
+    let rtf = rtf_document(&content, &writer.colors, title.as_deref());
+    fs::write(output_path, rtf).map_err(|e| format!("Failed to write RTF file: {}", e))?;
+    Ok(format!(
+        "Wrote {} paragraph(s), {} table(s) and {} image(s)",
+        writer.paragraphs, writer.tables, writer.images
+    ))
+}
+
+/// Where the paragraphs of a block end up
+#[derive(Debug, Clone, Default)]
+struct RtfContext {
+    heading: Option<usize>,
+    /// Left and right indents in twips
+    indent: i32,
+    right: i32,
+    in_list: bool,
+    list_level: usize,
+    in_table: bool,
+}
+
+/// The paragraph being filled with inline content
+struct RtfParagraph {
+    rtf: String,
+    empty: bool,
+    /// Whether the content so far ends in whitespace, for collapsing spaces
+    trailing_space: bool,
+}
+
+impl RtfParagraph {
+    fn new() -> Self {
+        RtfParagraph { rtf: String::new(), empty: true, trailing_space: true }
+    }
+}
+
+struct RtfWriter<'a> {
+    base_dir: &'a Path,
+    /// Color table entries after the automatic color
+    colors: Vec<[u8; 3]>,
+    /// The list marker of the next paragraph
+    pending_marker: Option<String>,
+    /// Element ids waiting for the next paragraph, where they become bookmarks
+    pending_bookmarks: Vec<String>,
+    page_break: bool,
+    paragraphs: usize,
+    tables: usize,
+    images: usize,
+}
+
+impl RtfWriter<'_> {
+    fn block_children(&mut self, element: &HtmlElement, style: &TextStyle, ctx: &RtfContext, out: &mut String) {
+        let mut current = RtfParagraph::new();
+        for child in &element.children {
+            match child {
+                HtmlNode::Text(text) => self.text(&mut current, text, style),
+                HtmlNode::Element(e) if is_block(e) => {
+                    self.flush(&mut current, style, ctx, out);
+                    self.block(e, style, ctx, out);
+                }
+                HtmlNode::Element(e) => self.inline(e, style, &mut current),
+            }
+        }
+        self.flush(&mut current, style, ctx, out);
+    }
+
+    fn block(&mut self, element: &HtmlElement, parent: &TextStyle, ctx: &RtfContext, out: &mut String) {
+        let tag = element.tag.as_str();
+        if HIDDEN_ELEMENTS.contains(&tag) {
+            return;
+        }
+        let style = element_style(element, parent);
+        if element.style("page-break-before").as_deref() == Some("always")
+            || element.style("break-before").as_deref() == Some("page")
+        {
+            self.page_break = true;
+        }
+        if let Some(id) = element.attr("id") {
+            self.pending_bookmarks.push(id.to_string());
+        }
+
+        match tag {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                // Size and weight come from the heading style, not the runs
+                let style = TextStyle { size: parent.size, bold: parent.bold, ..style };
+                let level = (tag.as_bytes()[1] - b'0') as usize;
+                self.block_children(element, &style, &RtfContext { heading: Some(level), ..ctx.clone() }, out);
+            }
+            "ul" | "ol" => self.list(element, &style, ctx, out),
+            "blockquote" | "dd" => {
+                let quote = RtfContext { indent: ctx.indent + 720, right: ctx.right + 720, ..ctx.clone() };
+                self.block_children(element, &style, &quote, out);
+            }
+            "pre" => {
+                let mut current = RtfParagraph::new();
+                let mut text = String::new();
+                collect_text(element, &mut text);
+                // A newline right after <pre> or before </pre> isn't content
+                let text = text.strip_prefix("\r\n").or_else(|| text.strip_prefix('\n')).unwrap_or(&text);
+                self.text(&mut current, text.trim_end_matches(['\n', '\r']), &style);
+                self.flush(&mut current, &style, ctx, out);
+            }
+            "hr" => {
+                out.push_str(&format!("{}\\brdrb\\brdrs\\brdrw10\\brsp20", self.paragraph_props(ctx, &style)));
+                out.push_str("\\par\n");
+            }
+            "table" if !ctx.in_table => self.table(element, &style, ctx, out),
+            _ => self.block_children(element, &style, ctx, out),
+        }
+    }
+
+    fn inline(&mut self, element: &HtmlElement, parent: &TextStyle, current: &mut RtfParagraph) {
+        let tag = element.tag.as_str();
+        if HIDDEN_ELEMENTS.contains(&tag) {
+            return;
+        }
+        let id = element.attr("id").or_else(|| if tag == "a" { element.attr("name") } else { None });
+        if let Some(id) = id {
+            current.rtf.push_str(&bookmark(id));
+        }
+        match tag {
+            "br" => {
+                current.rtf.push_str("\\line ");
+                current.empty = false;
+                current.trailing_space = true;
+            }
+            "img" => self.image(element, parent, current),
+            "input" => {
+                if element.attr("type").map(|t| t.eq_ignore_ascii_case("checkbox")).unwrap_or(false) {
+                    let symbol = if element.attr("checked").is_some() { "\u{2611} " } else { "\u{2610} " };
+                    self.push_run(current, symbol, parent);
+                    current.trailing_space = true;
+                }
+            }
+            _ => {
+                let style = element_style(element, parent);
+                for child in &element.children {
+                    match child {
+                        HtmlNode::Text(text) => self.text(current, text, &style),
+                        HtmlNode::Element(e) => self.inline(e, &style, current),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add text with HTML whitespace collapsing, unless it's preformatted
+    fn text(&mut self, current: &mut RtfParagraph, text: &str, style: &TextStyle) {
+        if style.pre {
+            let text = text.replace('\r', "");
+            if !text.is_empty() {
+                self.push_run(current, &text, style);
+            }
+            return;
+        }
+        let mut collapsed = String::new();
+        for c in text.chars() {
+            if matches!(c, ' ' | '\n' | '\t' | '\r' | '\x0c') {
+                if !current.trailing_space {
+                    collapsed.push(' ');
+                    current.trailing_space = true;
+                }
+            } else if !matches!(c, '\u{ad}' | '\u{200b}' | '\u{feff}') {
+                collapsed.push(c);
+                current.trailing_space = false;
+            }
+        }
+        if !collapsed.is_empty() {
+            self.push_run(current, &collapsed, style);
+        }
+    }
+
+    /// Write text as a group with its character formatting, inside a
+    /// HYPERLINK field if it has a link
+    fn push_run(&mut self, current: &mut RtfParagraph, text: &str, style: &TextStyle) {
+        let default = TextStyle::default();
+        let mut props = String::new();
+        if style.bold {
+            props.push_str("\\b");
+        }
+        if style.italic {
+            props.push_str("\\i");
+        }
+        if style.underline || style.link.is_some() {
+            props.push_str("\\ul");
+        }
+        if style.strike {
+            props.push_str("\\strike");
+        }
+        if style.mono && !style.pre {
+            props.push_str("\\f2");
+        }
+        if style.link.is_some() {
+            let index = self.color_index([0.02, 0.39, 0.76]);
+            props.push_str(&format!("\\cf{}", index));
+        } else if style.color != default.color {
+            let index = self.color_index(style.color);
+            props.push_str(&format!("\\cf{}", index));
+        }
+        if let Some(background) = style.background {
+            let index = self.color_index(background);
+            props.push_str(&format!("\\chcbpat{}", index));
+        }
+        if style.rise > 0.0 {
+            props.push_str("\\super");
+        } else if style.rise < 0.0 {
+            props.push_str("\\sub");
+        } else if (style.size - default.size).abs() > 0.1 && !style.pre {
+            props.push_str(&format!("\\fs{}", (style.size * 2.0).round() as i32));
+        }
+        let mut run = if props.is_empty() {
+            rtf_text(text)
+        } else {
+            format!("{{{} {}}}", props, rtf_text(text))
+        };
+        if let Some(link) = &style.link {
+            run = format!("{{\\field{{\\*\\fldinst{{{}}}}}{{\\fldrslt{{{}}}}}}}", hyperlink_instruction(link), run);
+        }
+        current.rtf.push_str(&run);
+        current.empty = false;
+    }
+
+    /// The color table index of a color, adding it when it's new
+    fn color_index(&mut self, color: [f32; 3]) -> usize {
+        let rgb = color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+        match self.colors.iter().position(|c| *c == rgb) {
+            Some(index) => index + 1,
+            None => {
+                self.colors.push(rgb);
+                self.colors.len()
+            }
+        }
+    }
+
+    fn paragraph_props(&mut self, ctx: &RtfContext, style: &TextStyle) -> String {
+        let mut props = String::from("\\pard\\plain");
+        if ctx.in_table {
+            props.push_str("\\intbl");
+        }
+        match ctx.heading {
+            Some(level) => props.push_str(&format!(
+                "\\s{0}\\outlinelevel{1}\\keepn\\sb240\\sa120\\b\\f1\\fs{2}",
+                level,
+                level - 1,
+                HEADING_SIZES[level - 1] * 2
+            )),
+            None if style.pre => props.push_str("\\sa0\\f2\\fs20"),
+            None => props.push_str(BODY_FORMAT),
+        }
+        if std::mem::take(&mut self.page_break) && !ctx.in_table {
+            props.push_str("\\pagebb");
+        }
+        match style.align {
+            TextAlign::Center => props.push_str("\\qc"),
+            TextAlign::Right => props.push_str("\\qr"),
+            TextAlign::Left => {}
+        }
+        if ctx.indent > 0 {
+            props.push_str(&format!("\\li{}", ctx.indent));
+        }
+        if ctx.right > 0 {
+            props.push_str(&format!("\\ri{}", ctx.right));
+        }
+        props
+    }
+
+    fn flush(&mut self, current: &mut RtfParagraph, style: &TextStyle, ctx: &RtfContext, out: &mut String) {
+        if current.empty {
+            return;
+        }
+        let paragraph = std::mem::replace(current, RtfParagraph::new());
+        let mut props = self.paragraph_props(ctx, style);
+        if let Some(marker) = self.pending_marker.take() {
+            props.push_str(&format!("\\ilvl{}\\fi-360", ctx.list_level));
+            props.push_str(&marker);
+        }
+        let bookmarks: String = self.pending_bookmarks.drain(..).map(|name| bookmark(&name)).collect();
+        out.push_str(&format!("{} {}{}\\par\n", props, bookmarks, paragraph.rtf));
+        self.paragraphs += 1;
+    }
+
+    fn image(&mut self, element: &HtmlElement, style: &TextStyle, current: &mut RtfParagraph) {
+        let source = element.attr("src").unwrap_or("").trim();
+        let picture = read_image_source(source, self.base_dir).and_then(|(bytes, svg)| picture_data(&bytes, svg));
+        let Some((bytes, (natural_width, natural_height))) = picture else {
+            // Images that can't be loaded show their description instead
+            let alt = element.attr("alt").unwrap_or("").trim();
+            if !alt.is_empty() {
+                let style = TextStyle { italic: true, ..style.clone() };
+                self.push_run(current, &format!("[{}]", alt), &style);
+                current.trailing_space = false;
+            }
+            return;
+        };
+        // RTF readers take PNG and JPEG; anything else is re-encoded
+        let (bytes, blip) = match image::guess_format(&bytes) {
+            Ok(image::ImageFormat::Png) => (bytes, "pngblip"),
+            Ok(image::ImageFormat::Jpeg) => (bytes, "jpegblip"),
+            _ => {
+                let mut png = Vec::new();
+                let converted = image::load_from_memory(&bytes)
+                    .ok()
+                    .and_then(|img| img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).ok());
+                if converted.is_none() {
+                    return;
+                }
+                (png, "pngblip")
+            }
+        };
+        let width = element.attr("width").and_then(css_length).or_else(|| element.style("width").as_deref().and_then(css_length));
+        let height = element.attr("height").and_then(css_length).or_else(|| element.style("height").as_deref().and_then(css_length));
+        let (width, height) = match (width, height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => (w, natural_height * w / natural_width),
+            (None, Some(h)) => (natural_width * h / natural_height, h),
+            (None, None) => (natural_width, natural_height),
+        };
+        let scale = (CONTENT_WIDTH_TWIPS as f32 / (width * 20.0)).min(1.0);
+
+        let mut hex = String::with_capacity(bytes.len() * 2 + bytes.len() / 64);
+        for (n, byte) in bytes.iter().enumerate() {
+            if n % 64 == 0 {
+                hex.push('\n');
+            }
+            hex.push_str(&format!("{:02x}", byte));
+        }
+        let mut picture = format!(
+            "{{\\pict\\{}\\picw{}\\pich{}\\picwgoal{}\\pichgoal{}{}}}",
+            blip,
+            (natural_width / 0.75).round() as i32,
+            (natural_height / 0.75).round() as i32,
+            (width * 20.0 * scale).round() as i32,
+            (height * 20.0 * scale).round() as i32,
+            hex
+        );
+        if let Some(link) = &style.link {
+            picture = format!("{{\\field{{\\*\\fldinst{{{}}}}}{{\\fldrslt{{{}}}}}}}", hyperlink_instruction(link), picture);
+        }
+        self.images += 1;
+        current.rtf.push_str(&picture);
+        current.empty = false;
+        current.trailing_space = false;
+    }
+
+    fn list(&mut self, list: &HtmlElement, style: &TextStyle, ctx: &RtfContext, out: &mut String) {
+        let ordered = list.tag == "ol";
+        let start = list.attr("start").and_then(|s| s.trim().parse::<usize>().ok()).unwrap_or(1);
+        let kind = match list.attr("type").unwrap_or("1") {
+            k @ ("a" | "A" | "i" | "I") => k,
+            _ => "1",
+        };
+        // Nested lists put their markers under the text of the parent item
+        let indent = ctx.indent + if ctx.in_list { 360 } else { 720 };
+        let level = if ctx.in_list { ctx.list_level + 1 } else { 0 };
+        let item_ctx = RtfContext { heading: None, indent, in_list: true, list_level: level, ..ctx.clone() };
+        for (n, item) in list.elements().enumerate() {
+            if let Some(id) = item.attr("id") {
+                self.pending_bookmarks.push(id.to_string());
+            }
+            self.pending_marker = Some(if ordered {
+                let number = start + n;
+                let format = match kind {
+                    "a" => "\\pnlcltr",
+                    "A" => "\\pnucltr",
+                    "i" => "\\pnlcrm",
+                    "I" => "\\pnucrm",
+                    _ => "\\pndec",
+                };
+                let label = list_number(number as i64, kind);
+                format!(
+                    "{{\\pntext\\f0 {}.\\tab}}{{\\*\\pn\\pnlvlbody{}\\pnstart{}\\pnindent360{{\\pntxta .}}}}",
+                    label, format, number
+                )
+            } else {
+                "{\\pntext\\f3\\'b7\\tab}{\\*\\pn\\pnlvlblt\\pnf3\\pnindent360{\\pntxtb\\'b7}}".to_string()
+            });
+            let before = out.len();
+            let item_style = element_style(item, style);
+            if item.tag == "li" {
+                self.block_children(item, &item_style, &item_ctx, out);
+            } else {
+                self.block(item, style, &item_ctx, out);
+            }
+            // Items without text still get their marker
+            if out.len() == before || self.pending_marker.is_some() {
+                let marker = self.pending_marker.take().unwrap_or_default();
+                let props = self.paragraph_props(&item_ctx, &item_style);
+                out.insert_str(before, &format!("{}\\ilvl{}\\fi-360{} \\par\n", props, level, marker));
+                self.paragraphs += 1;
+            }
+        }
+        self.pending_marker = None;
+    }
+
+    fn table(&mut self, table: &HtmlElement, style: &TextStyle, ctx: &RtfContext, out: &mut String) {
+        let mut rows: Vec<(&HtmlElement, bool)> = Vec::new();
+        for child in table.elements() {
+            match child.tag.as_str() {
+                "caption" => {
+                    let caption_style = element_style(child, style);
+                    self.block_children(child, &caption_style, ctx, out);
+                }
+                "thead" => rows.extend(child.elements().filter(|r| r.tag == "tr").map(|r| (r, true))),
+                "tbody" | "tfoot" => rows.extend(child.elements().filter(|r| r.tag == "tr").map(|r| (r, false))),
+                "tr" => rows.push((child, false)),
+                _ => {}
+            }
+        }
+
+        // Place the cells on a grid, honouring column and row spans
+        struct Cell<'a> {
+            element: &'a HtmlElement,
+            row: usize,
+            column: usize,
+            columns: usize,
+            rows: usize,
+        }
+        let mut cells: Vec<Cell> = Vec::new();
+        let mut owners: Vec<Vec<Option<usize>>> = vec![Vec::new(); rows.len()];
+        for (r, (row, _)) in rows.iter().enumerate() {
+            let mut column = 0;
+            for cell in row.elements().filter(|c| c.tag == "td" || c.tag == "th") {
+                while owners[r].get(column).copied().flatten().is_some() {
+                    column += 1;
+                }
+                let span = |name: &str| cell.attr(name).and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(1).max(1);
+                let columns = span("colspan").min(63);
+                let row_span = span("rowspan").min(rows.len() - r);
+                for owner_row in owners.iter_mut().skip(r).take(row_span) {
+                    if owner_row.len() < column + columns {
+                        owner_row.resize(column + columns, None);
+                    }
+                    owner_row[column..column + columns].iter_mut().for_each(|o| *o = Some(cells.len()));
+                }
+                cells.push(Cell { element: cell, row: r, column, columns, rows: row_span });
+                column += columns;
+            }
+        }
+        let column_count = owners.iter().map(Vec::len).max().unwrap_or(0);
+        if column_count == 0 {
+            return;
+        }
+        self.tables += 1;
+        let header_color = self.color_index([0.95, 0.95, 0.95]);
+        let column_width = (CONTENT_WIDTH_TWIPS - ctx.indent) / column_count as i32;
+        let header_rows = rows.iter().take_while(|(_, header)| *header).count();
+        let cell_ctx = RtfContext { in_table: true, ..RtfContext::default() };
+
+        for (r, (row, _)) in rows.iter().enumerate() {
+            let mut definition = format!("\\trowd\\trgaph108\\trleft{}", ctx.indent);
+            if r < header_rows {
+                definition.push_str("\\trhdr");
+            }
+            let mut content = String::new();
+            let row_style = element_style(row, style);
+            for column in 0..column_count {
+                let owner = owners[r].get(column).copied().flatten().map(|i| &cells[i]);
+                let header = owner.map(|c| c.element.tag == "th").unwrap_or(false) || r < header_rows;
+                if let Some(cell) = owner {
+                    if cell.columns > 1 {
+                        definition.push_str(if cell.column == column { "\\clmgf" } else { "\\clmrg" });
+                    }
+                    if cell.rows > 1 {
+                        definition.push_str(if cell.row == r { "\\clvmgf" } else { "\\clvmrg" });
+                    }
+                }
+                definition.push_str(
+                    "\\clbrdrt\\brdrs\\brdrw10\\brdrcf0\\clbrdrl\\brdrs\\brdrw10\\clbrdrb\\brdrs\\brdrw10\\clbrdrr\\brdrs\\brdrw10",
+                );
+                if header {
+                    definition.push_str(&format!("\\clcbpat{}", header_color));
+                }
+                definition.push_str(&format!("\\cellx{}", ctx.indent + column_width * (column as i32 + 1)));
+
+                match owner {
+                    Some(cell) if cell.row == r && cell.column == column => {
+                        let cell_style = element_style(cell.element, &row_style);
+                        let cell_style = TextStyle { bold: cell_style.bold || header, ..cell_style };
+                        let mut cell_content = String::new();
+                        self.block_children(cell.element, &cell_style, &cell_ctx, &mut cell_content);
+                        // The last paragraph of a cell ends with \cell instead of \par
+                        match cell_content.strip_suffix("\\par\n") {
+                            Some(paragraphs) => content.push_str(&format!("{}\\cell\n", paragraphs)),
+                            None => content.push_str("\\pard\\plain\\intbl\\f0\\fs24\\cell\n"),
+                        }
+                    }
+                    _ => content.push_str("\\pard\\plain\\intbl\\f0\\fs24\\cell\n"),
+                }
+            }
+            out.push_str(&definition);
+            out.push('\n');
+            out.push_str(&content);
+            out.push_str("\\row\n");
+        }
+        // A paragraph after the table keeps the next one out of it
+        out.push_str("\\pard\\plain\\sa0\\fs8\\par\n");
+    }
+}
+
+fn bookmark(name: &str) -> String {
+    format!("{{\\*\\bkmkstart {0}}}{{\\*\\bkmkend {0}}}", rtf_text(name))
+}
+
+fn hyperlink_instruction(link: &str) -> String {
+    match link.strip_prefix('#') {
+        Some(anchor) => format!("HYPERLINK \\\\l \"{}\"", rtf_text(anchor)),
+        None => format!("HYPERLINK \"{}\"", rtf_text(&link.replace('\\', "\\\\"))),
+    }
+}
+
+/// Text as RTF: special characters escaped, tabs and newlines as control
+/// words and anything outside ASCII as \uN with a `?` fallback
+fn rtf_text(text: &str) -> String {
+    let mut rtf = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => rtf.push_str("\\\\"),
+            '{' => rtf.push_str("\\{"),
+            '}' => rtf.push_str("\\}"),
+            '\t' => rtf.push_str("\\tab "),
+            '\n' => rtf.push_str("\\line "),
+            '\u{a0}' => rtf.push_str("\\~"),
+            c if (c as u32) < 0x20 => {}
+            c if c.is_ascii() => rtf.push(c),
+            c => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    rtf.push_str(&format!("\\u{}?", *unit as i16));
+                }
+            }
+        }
+    }
+    rtf
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(rtf: &str) -> Vec<Token<'_>> {
+        Tokenizer { data: rtf.as_bytes(), pos: 0 }.collect()
+    }
+
+    fn text(rtf: &str) -> String {
+        let mut reader = RtfReader::new();
+        reader.read(rtf.as_bytes());
+        plain_text(&reader.finish(), "\n").trim_end().to_string()
+    }
+
+    #[test]
+    fn tokenizer_splits_controls_and_text() {
+        assert_eq!(
+            tokens("{\\b1 x\\'e9\\u-3913 \\\\\r\n}"),
+            vec![
+                Token::Open,
+                Token::Word("b", Some(1)),
+                Token::Text(b'x'),
+                Token::Hex(0xe9),
+                Token::Word("u", Some(-3913)),
+                Token::Symbol(b'\\'),
+                Token::Close,
+            ]
+        );
+        assert_eq!(tokens("\\'zz"), vec![Token::Symbol(b'\''), Token::Text(b'z'), Token::Text(b'z')]);
+        assert_eq!(tokens("\\bin3 {}}x"), vec![Token::Binary(b"{}}"), Token::Text(b'x')]);
+    }
+
+    #[test]
+    fn unicode_escapes_skip_their_fallback() {
+        assert_eq!(text("{\\rtf1 caf\\u233?s}"), "cafés");
+        assert_eq!(text("{\\rtf1\\uc2 \\u8364\\'80\\'80!}"), "€!");
+        // Negative values wrap into the upper half of the BMP
+        assert_eq!(text("{\\rtf1 \\u-3913?}"), "\u{f0b7}");
+    }
+
+    #[test]
+    fn hex_escapes_use_the_code_page() {
+        assert_eq!(text("{\\rtf1\\ansi\\ansicpg1252 na\\'efve \\'80}"), "naïve €");
+    }
+
+    #[test]
+    fn ignorable_destinations_are_skipped() {
+        assert_eq!(text("{\\rtf1 a{\\*\\generator Foo;}b{\\*\\unknown {nested} text}c}"), "abc");
+        assert_eq!(text("{\\rtf1{\\fonttbl{\\f0 Arial;}}{\\colortbl;\\red255\\green0\\blue0;}Body}"), "Body");
+    }
+}
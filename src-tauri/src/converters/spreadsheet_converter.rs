@@ -603,7 +603,7 @@ where <R as Reader<BufReader<fs::File>>>::Error: std::fmt::Display {
     }
 }
 
-fn cell_to_string(cell: &Data) -> String {
+pub fn cell_to_string(cell: &Data) -> String {
     match cell {
         Data::Empty => String::new(),
         Data::String(s) => s.clone(),
@@ -1,10 +1,11 @@
 use std::fs;
 use crate::ConversionResult;
+use crate::converters::image_converter::{apply_watermark, pixmap_to_rgba, WatermarkOptions};
 use resvg::tiny_skia;
 use resvg::usvg::{Options, Tree};
 
 /// Convert SVG to PNG
-pub fn convert_svg_to_png(input_path: &str, output_path: &str, scale: Option<f32>) -> ConversionResult {
+pub fn convert_svg_to_png(input_path: &str, output_path: &str, scale: Option<f32>, watermark: Option<&WatermarkOptions>) -> ConversionResult {
     let svg_data = match fs::read(input_path) {
         Ok(data) => data,
         Err(e) => {
@@ -63,6 +64,32 @@ pub fn convert_svg_to_png(input_path: &str, output_path: &str, scale: Option<f32
     let transform = tiny_skia::Transform::from_scale(scale_factor, scale_factor);
     resvg::render(&tree, transform, &mut pixmap.as_mut());
 
+    if let Some(wm) = watermark {
+        let mut img = image::DynamicImage::ImageRgba8(pixmap_to_rgba(&pixmap));
+        if let Err(e) = apply_watermark(&mut img, wm) {
+            return ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Failed to apply watermark: {}", e)),
+                data: None,
+            };
+        }
+        return match img.save_with_format(output_path, image::ImageFormat::Png) {
+            Ok(_) => ConversionResult {
+                success: true,
+                output_path: Some(output_path.to_string()),
+                error: None,
+                data: None,
+            },
+            Err(e) => ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Failed to save PNG: {}", e)),
+                data: None,
+            },
+        };
+    }
+
     match pixmap.save_png(output_path) {
         Ok(_) => ConversionResult {
             success: true,
@@ -80,7 +107,7 @@ pub fn convert_svg_to_png(input_path: &str, output_path: &str, scale: Option<f32
 }
 
 /// Convert SVG to JPEG
-pub fn convert_svg_to_jpg(input_path: &str, output_path: &str, scale: Option<f32>, quality: Option<u8>) -> ConversionResult {
+pub fn convert_svg_to_jpg(input_path: &str, output_path: &str, scale: Option<f32>, quality: Option<u8>, watermark: Option<&WatermarkOptions>) -> ConversionResult {
     let svg_data = match fs::read(input_path) {
         Ok(data) => data,
         Err(e) => {
@@ -152,6 +179,21 @@ pub fn convert_svg_to_jpg(input_path: &str, output_path: &str, scale: Option<f32
     let img = image::RgbImage::from_raw(width, height, rgb_data);
     match img {
         Some(img) => {
+            let img = match watermark {
+                Some(wm) => {
+                    let mut dynamic = image::DynamicImage::ImageRgb8(img);
+                    if let Err(e) = apply_watermark(&mut dynamic, wm) {
+                        return ConversionResult {
+                            success: false,
+                            output_path: None,
+                            error: Some(format!("Failed to apply watermark: {}", e)),
+                            data: None,
+                        };
+                    }
+                    dynamic.to_rgb8()
+                }
+                None => img,
+            };
             let quality_val = quality.unwrap_or(85);
             let mut output_file = match std::fs::File::create(output_path) {
                 Ok(f) => f,
@@ -0,0 +1,96 @@
+use crate::converters::html_renderer::{HtmlElement, HtmlNode};
+
+/// Escape text for HTML or XML element content and attribute values
+pub fn escape_markup(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Format a list counter in the style of an HTML list `type`: "1" (the
+/// default), "a"/"A" for letters (z is followed by aa, ab, ...), "i"/"I" for
+/// Roman numerals, "01" for zero-padded numbers and "" for no number.
+/// Counters the style can't show fall back to decimal.
+pub fn list_number(number: i64, kind: &str) -> String {
+    match kind {
+        "" => String::new(),
+        "a" | "A" if number > 0 => {
+            let mut n = number;
+            let mut letters = Vec::new();
+            while n > 0 {
+                n -= 1;
+                letters.push((b'a' + (n % 26) as u8) as char);
+                n /= 26;
+            }
+            let label: String = letters.into_iter().rev().collect();
+            if kind == "A" { label.to_uppercase() } else { label }
+        }
+        "i" | "I" if number > 0 && number < 4000 => {
+            let numerals = [
+                (1000, "m"), (900, "cm"), (500, "d"), (400, "cd"), (100, "c"), (90, "xc"),
+                (50, "l"), (40, "xl"), (10, "x"), (9, "ix"), (5, "v"), (4, "iv"), (1, "i"),
+            ];
+            let mut n = number;
+            let mut label = String::new();
+            for (value, numeral) in numerals {
+                while n >= value {
+                    label.push_str(numeral);
+                    n -= value;
+                }
+            }
+            if kind == "I" { label.to_uppercase() } else { label }
+        }
+        "01" => format!("{:02}", number),
+        _ => number.to_string(),
+    }
+}
+
+/// Whether a font family name looks like a fixed-pitch code font
+pub fn is_monospace_font(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ["courier", "consolas", "menlo", "monaco", "mono", "lucida console", "source code"]
+        .iter()
+        .any(|m| name.contains(m))
+}
+
+/// Append an element's text to `out`, with `<br>` as a newline
+pub fn collect_text(element: &HtmlElement, out: &mut String) {
+    for child in &element.children {
+        match child {
+            HtmlNode::Text(text) => out.push_str(text),
+            HtmlNode::Element(e) if e.tag == "br" => out.push('\n'),
+            HtmlNode::Element(e) => collect_text(e, out),
+        }
+    }
+}
+
+/// The last component of a path taken from a package or link, fit to be
+/// joined onto an output folder. Both `/` and `\` count as separators, and
+/// `.`, `..`, empty names and names with a drive prefix are refused so a
+/// crafted archive can't write outside the folder.
+pub fn safe_file_name(path: &str) -> Option<&str> {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path).trim();
+    if name.is_empty() || name == "." || name == ".." || name.contains(':') || name.contains('\0') {
+        return None;
+    }
+    Some(name)
+}
+
+/// A file name not yet in `used_names` (compared case-insensitively),
+/// numbering repeats as `name-2.ext`, `name-3.ext`, ..., and record it
+pub fn unique_file_name(name: &str, used_names: &mut Vec<String>) -> String {
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let mut file_name = name.to_string();
+    let mut n = 1;
+    while used_names.contains(&file_name.to_lowercase()) {
+        n += 1;
+        file_name = if extension.is_empty() {
+            format!("{}-{}", stem, n)
+        } else {
+            format!("{}-{}.{}", stem, n, extension)
+        };
+    }
+    used_names.push(file_name.to_lowercase());
+    file_name
+}
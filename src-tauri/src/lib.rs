@@ -80,7 +80,8 @@ pub fn get_file_type(extension: &str) -> String {
         // Code
         "py" | "rb" | "php" | "java" | "c" | "cpp" | "h" | "hpp" | "rs" | "go" | "swift" => "code".to_string(),
         // Documents
-        "doc" | "docx" => "docx".to_string(),
+        "doc" => "doc".to_string(),
+        "docx" => "docx".to_string(),
         "rtf" => "rtf".to_string(),
         "epub" => "epub".to_string(),
         "odt" => "odt".to_string(),
@@ -99,18 +100,27 @@ pub fn get_file_type(extension: &str) -> String {
     }
 }
 
-pub fn get_supported_outputs(file_type: &str, _extension: &str) -> Vec<String> {
+pub fn get_supported_outputs(file_type: &str, extension: &str) -> Vec<String> {
     match file_type {
         // Images
-        "image" => vec![
-            "png".to_string(),
-            "jpg".to_string(),
-            "jpeg".to_string(),
-            "gif".to_string(),
-            "bmp".to_string(),
-            "ico".to_string(),
-            "webp".to_string(),
-        ],
+        "image" => {
+            let mut outputs = vec![
+                "png".to_string(),
+                "jpg".to_string(),
+                "jpeg".to_string(),
+                "gif".to_string(),
+                "bmp".to_string(),
+                "ico".to_string(),
+                "webp".to_string(),
+                "tiff".to_string(),
+                "pdf".to_string(),
+            ];
+            // Multi-page TIFFs can be split into one image per page
+            if matches!(extension.to_lowercase().as_str(), "tif" | "tiff") {
+                outputs.push("folder".to_string());
+            }
+            outputs
+        }
         // SVG
         "svg" => vec![
             "png".to_string(),
@@ -119,8 +129,11 @@ pub fn get_supported_outputs(file_type: &str, _extension: &str) -> Vec<String> {
         // PDF
         "pdf" => vec![
             "txt".to_string(),
+            "json".to_string(),
             "png".to_string(),
             "jpg".to_string(),
+            "zip".to_string(),
+            "images".to_string(), // Embedded images
         ],
         // Text
         "text" | "config" | "code" | "script" | "style" => vec![
@@ -128,18 +141,28 @@ pub fn get_supported_outputs(file_type: &str, _extension: &str) -> Vec<String> {
             "md".to_string(),
             "pdf".to_string(),
             "html".to_string(),
+            "docx".to_string(),
+            "rtf".to_string(),
         ],
         // Markdown
         "markdown" => vec![
             "html".to_string(),
             "pdf".to_string(),
             "txt".to_string(),
+            "docx".to_string(),
+            "odt".to_string(),
+            "rtf".to_string(),
+            "epub".to_string(),
         ],
         // HTML
         "html" => vec![
             "txt".to_string(),
             "md".to_string(),
             "pdf".to_string(),
+            "docx".to_string(),
+            "odt".to_string(),
+            "rtf".to_string(),
+            "epub".to_string(),
         ],
         // JSON
         "json" => vec![
@@ -172,9 +195,19 @@ pub fn get_supported_outputs(file_type: &str, _extension: &str) -> Vec<String> {
             "csv".to_string(),
             "txt".to_string(),
             "html".to_string(),
+            "docx".to_string(),
         ],
         // Documents (DOCX)
         "docx" => vec![
+            "txt".to_string(),
+            "html".to_string(),
+            "md".to_string(),
+            "pdf".to_string(),
+            "epub".to_string(),
+            "images".to_string(),
+        ],
+        // Word 97-2003
+        "doc" => vec![
             "txt".to_string(),
             "html".to_string(),
             "pdf".to_string(),
@@ -183,15 +216,21 @@ pub fn get_supported_outputs(file_type: &str, _extension: &str) -> Vec<String> {
         "rtf" => vec![
             "txt".to_string(),
             "html".to_string(),
+            "pdf".to_string(),
         ],
         // EPUB
         "epub" => vec![
             "txt".to_string(),
             "html".to_string(),
+            "md".to_string(),
+            "pdf".to_string(),
+            "images".to_string(),
         ],
         // ODT
         "odt" => vec![
             "txt".to_string(),
+            "html".to_string(),
+            "md".to_string(),
             "pdf".to_string(),
         ],
         // Spreadsheets (XLSX/XLS)
@@ -199,12 +238,14 @@ pub fn get_supported_outputs(file_type: &str, _extension: &str) -> Vec<String> {
             "csv".to_string(),
             "json".to_string(),
             "html".to_string(),
+            "docx".to_string(),
         ],
         // ODS
         "ods" => vec![
             "csv".to_string(),
             "json".to_string(),
             "html".to_string(),
+            "docx".to_string(),
         ],
         // Archives
         "zip" | "tar" | "gzip" => vec![
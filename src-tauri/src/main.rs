@@ -55,14 +55,33 @@ fn convert_file(
         };
     }
 
-    match file_info.file_type.as_str() {
-        "image" => {
-            let quality = options.as_ref().and_then(|o| o.quality);
-            convert_image(&input_path, &output_format, &output_path, quality)
-        }
+    let watermark = options.as_ref().and_then(|o| o.watermark.as_ref());
+
+    let result = match file_info.file_type.as_str() {
+        "image" => match output_format.as_str() {
+            "pdf" => {
+                let pdf_options = options.as_ref().and_then(|o| o.pdf_pages.clone()).unwrap_or_default();
+                images_to_pdf(&[input_path.clone()], &output_path, &pdf_options)
+            }
+            "folder" => {
+                let extract_dir = Path::new(&output_dir)
+                    .join(format!("{}_pages", stem))
+                    .to_string_lossy()
+                    .to_string();
+                split_tiff(&input_path, &extract_dir, "png")
+            }
+            _ => {
+                let quality = options.as_ref().and_then(|o| o.quality);
+                let color = options.as_ref().and_then(|o| o.color.as_ref());
+                match options.as_ref().and_then(|o| o.optimize.as_ref()) {
+                    Some(optimize) => optimize_image(&input_path, &output_format, &output_path, quality, optimize, watermark, color),
+                    None => convert_image(&input_path, &output_format, &output_path, quality, watermark, color),
+                }
+            }
+        },
         "svg" => match output_format.as_str() {
-            "png" => convert_svg_to_png(&input_path, &output_path, options.as_ref().and_then(|o| o.scale)),
-            "jpg" | "jpeg" => convert_svg_to_jpg(&input_path, &output_path, options.as_ref().and_then(|o| o.scale), options.as_ref().and_then(|o| o.quality)),
+            "png" => convert_svg_to_png(&input_path, &output_path, options.as_ref().and_then(|o| o.scale), watermark),
+            "jpg" | "jpeg" => convert_svg_to_jpg(&input_path, &output_path, options.as_ref().and_then(|o| o.scale), options.as_ref().and_then(|o| o.quality), watermark),
             _ => ConversionResult {
                 success: false,
                 output_path: None,
@@ -71,8 +90,31 @@ fn convert_file(
             },
         },
         "pdf" => match output_format.as_str() {
-            "txt" => extract_pdf_text(&input_path, &output_path),
-            "png" | "jpg" | "jpeg" => convert_pdf_to_image(&input_path, &output_path, &output_format),
+            "txt" => {
+                let text_options = options.as_ref().and_then(|o| o.pdf_text.clone()).unwrap_or_default();
+                extract_pdf_text(&input_path, &output_path, &text_options)
+            }
+            "json" => {
+                let text_options = options.as_ref().and_then(|o| o.pdf_text.clone()).unwrap_or_default();
+                extract_pdf_text_blocks(&input_path, &output_path, &text_options)
+            }
+            "png" | "jpg" | "jpeg" => {
+                let render_options = options.as_ref().and_then(|o| o.pdf_render.clone()).unwrap_or_default();
+                convert_pdf_to_image(&input_path, &output_path, &output_format, &render_options, watermark)
+            }
+            "zip" => {
+                // Every page as PNG, bundled into one archive
+                let mut render_options = options.as_ref().and_then(|o| o.pdf_render.clone()).unwrap_or_default();
+                render_options.zip = Some(true);
+                convert_pdf_to_image(&input_path, &output_path, "png", &render_options, watermark)
+            }
+            "images" => {
+                let images_dir = Path::new(&output_dir)
+                    .join(format!("{}_images", stem))
+                    .to_string_lossy()
+                    .to_string();
+                extract_pdf_images(&input_path, &images_dir)
+            }
             _ => ConversionResult {
                 success: false,
                 output_path: None,
@@ -85,6 +127,8 @@ fn convert_file(
             "html" => convert_text_to_html(&input_path, &output_path),
             "md" => convert_text_to_markdown(&input_path, &output_path),
             "txt" => convert_to_plain_text(&input_path, &output_path),
+            "docx" => convert_text_to_docx(&input_path, &output_path, file_info.file_type != "text"),
+            "rtf" => convert_text_to_rtf(&input_path, &output_path, file_info.file_type != "text"),
             _ => ConversionResult {
                 success: false,
                 output_path: None,
@@ -94,7 +138,14 @@ fn convert_file(
         },
         "markdown" => match output_format.as_str() {
             "html" => convert_markdown_to_html(&input_path, &output_path),
-            "pdf" => convert_text_to_pdf(&input_path, &output_path),
+            "pdf" => convert_via_html(&input_path, &output_path, convert_markdown_to_html),
+            "docx" => convert_markdown_to_docx(&input_path, &output_path),
+            "odt" => convert_markdown_to_odt(&input_path, &output_path),
+            "rtf" => convert_markdown_to_rtf(&input_path, &output_path),
+            "epub" => {
+                let epub_options = options.as_ref().and_then(|o| o.epub.clone()).unwrap_or_default();
+                convert_markdown_to_epub(&input_path, &output_path, &epub_options)
+            }
             "txt" => convert_to_plain_text(&input_path, &output_path),
             _ => ConversionResult {
                 success: false,
@@ -105,8 +156,15 @@ fn convert_file(
         },
         "html" => match output_format.as_str() {
             "txt" => convert_to_plain_text(&input_path, &output_path),
-            "md" => convert_text_to_markdown(&input_path, &output_path),
-            "pdf" => convert_text_to_pdf(&input_path, &output_path),
+            "md" => convert_html_to_markdown(&input_path, &output_path),
+            "pdf" => convert_html_to_pdf(&input_path, &output_path),
+            "docx" => convert_html_to_docx(&input_path, &output_path),
+            "odt" => convert_html_to_odt(&input_path, &output_path),
+            "rtf" => convert_html_to_rtf(&input_path, &output_path),
+            "epub" => {
+                let epub_options = options.as_ref().and_then(|o| o.epub.clone()).unwrap_or_default();
+                convert_html_to_epub(&input_path, &output_path, &epub_options)
+            }
             _ => ConversionResult {
                 success: false,
                 output_path: None,
@@ -162,6 +220,7 @@ fn convert_file(
             ("csv" | "tsv", "html") => convert_csv_to_html(&input_path, &output_path),
             ("json", "csv") => convert_json_to_csv(&input_path, &output_path),
             ("csv" | "tsv", "txt") => convert_to_plain_text(&input_path, &output_path),
+            ("csv" | "tsv", "docx") => convert_csv_to_docx(&input_path, &output_path),
             _ => ConversionResult {
                 success: false,
                 output_path: None,
@@ -174,18 +233,22 @@ fn convert_file(
         },
         "docx" => match output_format.as_str() {
             "txt" => convert_docx_to_txt(&input_path, &output_path),
-            "html" => convert_docx_to_html(&input_path, &output_path),
-            "pdf" => {
-                // First convert to HTML, then to PDF via text converter
-                let temp_html = format!("{}.temp.html", output_path);
-                let html_result = convert_docx_to_html(&input_path, &temp_html);
-                if html_result.success {
-                    let pdf_result = convert_text_to_pdf(&temp_html, &output_path);
-                    let _ = fs::remove_file(&temp_html);
-                    pdf_result
-                } else {
-                    html_result
-                }
+            "html" => {
+                let html_options = options.as_ref().and_then(|o| o.docx_html.clone()).unwrap_or_default();
+                convert_docx_to_html_with_options(&input_path, &output_path, &html_options)
+            }
+            "md" => convert_docx_to_markdown(&input_path, &output_path),
+            "pdf" => convert_via_html(&input_path, &output_path, convert_docx_to_html),
+            "epub" => {
+                let epub_options = options.as_ref().and_then(|o| o.epub.clone()).unwrap_or_default();
+                convert_docx_to_epub(&input_path, &output_path, &epub_options)
+            }
+            "images" => {
+                let images_dir = Path::new(&output_dir)
+                    .join(format!("{}_images", stem))
+                    .to_string_lossy()
+                    .to_string();
+                extract_docx_images(&input_path, &images_dir)
             }
             _ => ConversionResult {
                 success: false,
@@ -194,17 +257,21 @@ fn convert_file(
                 data: None,
             },
         },
+        "doc" => match output_format.as_str() {
+            "txt" => convert_doc_to_txt(&input_path, &output_path),
+            "html" => convert_doc_to_html(&input_path, &output_path),
+            "pdf" => convert_via_html(&input_path, &output_path, convert_doc_to_html),
+            _ => ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Unsupported DOC conversion to {}", output_format)),
+                data: None,
+            },
+        },
         "rtf" => match output_format.as_str() {
             "txt" => convert_rtf_to_txt(&input_path, &output_path),
-            "html" => {
-                // Convert RTF to TXT first, then wrap in HTML
-                let txt_result = convert_rtf_to_txt(&input_path, &output_path);
-                if txt_result.success {
-                    convert_text_to_html(&output_path, &output_path)
-                } else {
-                    txt_result
-                }
-            }
+            "html" => convert_rtf_to_html(&input_path, &output_path),
+            "pdf" => convert_via_html(&input_path, &output_path, convert_rtf_to_html),
             _ => ConversionResult {
                 success: false,
                 output_path: None,
@@ -214,7 +281,27 @@ fn convert_file(
         },
         "epub" => match output_format.as_str() {
             "txt" => convert_epub_to_txt(&input_path, &output_path),
-            "html" => convert_epub_to_html(&input_path, &output_path),
+            "html" => {
+                let html_options = options.as_ref().and_then(|o| o.epub_html.clone()).unwrap_or_default();
+                if html_options.split.unwrap_or(false) {
+                    let chapters_dir = Path::new(&output_dir)
+                        .join(format!("{}_chapters", stem))
+                        .to_string_lossy()
+                        .to_string();
+                    split_epub_to_html(&input_path, &chapters_dir, &html_options)
+                } else {
+                    convert_epub_to_html_with_options(&input_path, &output_path, &html_options)
+                }
+            }
+            "md" => convert_epub_to_markdown(&input_path, &output_path),
+            "pdf" => convert_via_html(&input_path, &output_path, convert_epub_to_html),
+            "images" => {
+                let images_dir = Path::new(&output_dir)
+                    .join(format!("{}_images", stem))
+                    .to_string_lossy()
+                    .to_string();
+                extract_epub_images(&input_path, &images_dir)
+            }
             _ => ConversionResult {
                 success: false,
                 output_path: None,
@@ -222,10 +309,23 @@ fn convert_file(
                 data: None,
             },
         },
+        "odt" => match output_format.as_str() {
+            "txt" => convert_odt_to_txt(&input_path, &output_path),
+            "html" => convert_odt_to_html(&input_path, &output_path),
+            "md" => convert_odt_to_markdown(&input_path, &output_path),
+            "pdf" => convert_via_html(&input_path, &output_path, convert_odt_to_html),
+            _ => ConversionResult {
+                success: false,
+                output_path: None,
+                error: Some(format!("Unsupported ODT conversion to {}", output_format)),
+                data: None,
+            },
+        },
         "xlsx" => match output_format.as_str() {
             "csv" => convert_xlsx_to_csv(&input_path, &output_path),
             "json" => convert_xlsx_to_json(&input_path, &output_path),
             "html" => convert_xlsx_to_html(&input_path, &output_path),
+            "docx" => convert_spreadsheet_to_docx(&input_path, &output_path),
             _ => ConversionResult {
                 success: false,
                 output_path: None,
@@ -237,6 +337,7 @@ fn convert_file(
             "csv" => convert_ods_to_csv(&input_path, &output_path),
             "json" => convert_ods_to_json(&input_path, &output_path),
             "html" => convert_ods_to_html(&input_path, &output_path),
+            "docx" => convert_spreadsheet_to_docx(&input_path, &output_path),
             _ => ConversionResult {
                 success: false,
                 output_path: None,
@@ -350,7 +451,74 @@ fn convert_file(
             error: Some(format!("Unsupported file type: {}", file_info.file_type)),
             data: None,
         },
+    };
+
+    if !result.success || output_format != "pdf" {
+        return result;
+    }
+
+    // PDF/A conversion and password protection are applied to generated
+    // PDFs afterwards, replacing the output only once they succeed
+    let pdfa = options.as_ref().and_then(|o| o.pdfa.as_deref());
+    let protect = options.as_ref().and_then(|o| o.protect.as_ref());
+    if pdfa.is_some() && protect.is_some() {
+        return ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some("PDF/A files can't be password protected".to_string()),
+            data: None,
+        };
+    }
+    if let Some(level) = pdfa {
+        let archived = rewrite_output(&output_path, |input, output| convert_to_pdfa(input, output, level));
+        return if archived.success { result } else { archived };
+    }
+    match protect {
+        Some(protect) => {
+            let protected = rewrite_output(&output_path, |input, output| protect_pdf(input, output, protect));
+            if protected.success { result } else { protected }
+        }
+        _ => result,
+    }
+}
+
+/// Run a PDF post-processing step from `output_path` into a temporary file and
+/// move that over the output on success. On failure the unprocessed output is
+/// removed so no half-finished file is left behind
+fn rewrite_output(output_path: &str, step: impl FnOnce(&str, &str) -> ConversionResult) -> ConversionResult {
+    let temp_path = format!("{}.temp.pdf", output_path);
+    let result = step(output_path, &temp_path);
+    if !result.success {
+        let _ = fs::remove_file(&temp_path);
+        let _ = fs::remove_file(output_path);
+        return result;
+    }
+    if let Err(e) = fs::rename(&temp_path, output_path) {
+        let _ = fs::remove_file(&temp_path);
+        let _ = fs::remove_file(output_path);
+        return ConversionResult {
+            success: false,
+            output_path: None,
+            error: Some(format!("Failed to replace output file: {}", e)),
+            data: None,
+        };
     }
+    result
+}
+
+/// Convert a document to HTML next to the output, then lay that out as a
+/// PDF, resolving images against the original document's folder
+fn convert_via_html(input_path: &str, output_path: &str, to_html: fn(&str, &str) -> ConversionResult) -> ConversionResult {
+    let temp_html = format!("{}.temp.html", output_path);
+    let html_result = to_html(input_path, &temp_html);
+    if !html_result.success {
+        let _ = fs::remove_file(&temp_html);
+        return html_result;
+    }
+    let base_dir = Path::new(input_path).parent().unwrap_or(Path::new("")).to_string_lossy().to_string();
+    let pdf_result = convert_html_to_pdf_with_base(&temp_html, &base_dir, output_path);
+    let _ = fs::remove_file(&temp_html);
+    pdf_result
 }
 
 #[tauri::command]
@@ -364,6 +532,137 @@ fn resize_image_cmd(
     resize_image(&input_path, &output_path, width, height, maintain_aspect)
 }
 
+#[tauri::command]
+fn create_contact_sheet_cmd(
+    input_paths: Vec<String>,
+    output_path: String,
+    options: Option<ContactSheetOptions>,
+) -> ConversionResult {
+    create_contact_sheet(&input_paths, &output_path, &options.unwrap_or_default())
+}
+
+#[tauri::command]
+fn images_to_pdf_cmd(
+    input_paths: Vec<String>,
+    output_path: String,
+    options: Option<ImagesToPdfOptions>,
+) -> ConversionResult {
+    images_to_pdf(&input_paths, &output_path, &options.unwrap_or_default())
+}
+
+#[tauri::command]
+fn images_to_tiff_cmd(input_paths: Vec<String>, output_path: String) -> ConversionResult {
+    images_to_tiff(&input_paths, &output_path)
+}
+
+#[tauri::command]
+fn merge_pdfs_cmd(input_paths: Vec<String>, output_path: String) -> ConversionResult {
+    merge_pdfs(&input_paths, &output_path)
+}
+
+#[tauri::command]
+fn split_pdf_cmd(input_path: String, output_dir: String, options: Option<SplitPdfOptions>) -> ConversionResult {
+    split_pdf(&input_path, &output_dir, &options.unwrap_or_default())
+}
+
+#[tauri::command]
+fn extract_pdf_pages_cmd(input_path: String, output_path: String, pages: String) -> ConversionResult {
+    extract_pdf_pages(&input_path, &output_path, &pages)
+}
+
+#[tauri::command]
+fn delete_pdf_pages_cmd(input_path: String, output_path: String, pages: String) -> ConversionResult {
+    delete_pdf_pages(&input_path, &output_path, &pages)
+}
+
+#[tauri::command]
+fn rotate_pdf_pages_cmd(
+    input_path: String,
+    output_path: String,
+    pages: Option<String>,
+    degrees: i64,
+) -> ConversionResult {
+    rotate_pdf_pages(&input_path, &output_path, pages.as_deref(), degrees)
+}
+
+#[tauri::command]
+fn reorder_pdf_pages_cmd(input_path: String, output_path: String, order: String) -> ConversionResult {
+    reorder_pdf_pages(&input_path, &output_path, &order)
+}
+
+#[tauri::command]
+fn optimize_pdf_cmd(
+    input_path: String,
+    output_path: String,
+    options: Option<PdfOptimizeOptions>,
+) -> ConversionResult {
+    optimize_pdf(&input_path, &output_path, &options.unwrap_or_default())
+}
+
+#[tauri::command]
+fn edit_pdf_metadata_cmd(
+    input_path: String,
+    output_path: String,
+    options: Option<PdfMetadataOptions>,
+) -> ConversionResult {
+    edit_pdf_metadata(&input_path, &output_path, &options.unwrap_or_default())
+}
+
+#[tauri::command]
+fn protect_pdf_cmd(input_path: String, output_path: String, options: PdfProtectOptions) -> ConversionResult {
+    protect_pdf(&input_path, &output_path, &options)
+}
+
+#[tauri::command]
+fn decrypt_pdf_cmd(input_path: String, output_path: String, password: String) -> ConversionResult {
+    decrypt_pdf(&input_path, &output_path, &password)
+}
+
+#[tauri::command]
+fn convert_to_pdfa_cmd(input_path: String, output_path: String, level: String) -> ConversionResult {
+    convert_to_pdfa(&input_path, &output_path, &level)
+}
+
+#[tauri::command]
+fn validate_pdfa_cmd(input_path: String) -> Result<PdfaReport, String> {
+    validate_pdfa(&input_path)
+}
+
+#[tauri::command]
+fn get_pdf_form_fields_cmd(input_path: String, password: Option<String>) -> Result<Vec<PdfFormField>, String> {
+    read_pdf_form_fields(&input_path, password.as_deref())
+}
+
+#[tauri::command]
+fn extract_pdf_form_cmd(input_path: String, output_path: String, password: Option<String>) -> ConversionResult {
+    extract_pdf_form(&input_path, &output_path, password.as_deref())
+}
+
+#[tauri::command]
+fn fill_pdf_form_cmd(input_path: String, output_path: String, options: Option<PdfFormFillOptions>) -> ConversionResult {
+    fill_pdf_form(&input_path, &output_path, &options.unwrap_or_default())
+}
+
+#[tauri::command]
+fn batch_fill_pdf_form_cmd(
+    input_path: String,
+    data_path: String,
+    output_dir: String,
+    options: Option<PdfFormFillOptions>,
+) -> ConversionResult {
+    batch_fill_pdf_form(&input_path, &data_path, &output_dir, &options.unwrap_or_default())
+}
+
+#[tauri::command]
+fn compare_files_cmd(
+    old_path: String,
+    new_path: String,
+    output_path: String,
+    options: Option<CompareOptions>,
+) -> ConversionResult {
+    compare_files(&old_path, &new_path, &output_path, &options.unwrap_or_default())
+}
+
 #[tauri::command]
 fn get_image_preview_cmd(input_path: String, max_size: u32) -> Result<String, String> {
     get_image_preview(&input_path, max_size)
@@ -380,8 +679,8 @@ fn get_image_info_cmd(input_path: String) -> Result<ImageInfo, String> {
 }
 
 #[tauri::command]
-fn get_pdf_info_cmd(input_path: String) -> Result<PdfInfo, String> {
-    get_pdf_info(&input_path)
+fn get_pdf_info_cmd(input_path: String, password: Option<String>) -> Result<PdfInfo, String> {
+    get_pdf_info(&input_path, password.as_deref())
 }
 
 #[tauri::command]
@@ -428,6 +727,17 @@ pub struct ConvertOptions {
     pub width: Option<u32>,
     pub height: Option<u32>,
     pub scale: Option<f32>,
+    pub watermark: Option<WatermarkOptions>,
+    pub optimize: Option<OptimizeOptions>,
+    pub color: Option<ColorOptions>,
+    pub pdf_pages: Option<ImagesToPdfOptions>,
+    pub pdf_render: Option<PdfRenderOptions>,
+    pub pdf_text: Option<PdfTextOptions>,
+    pub protect: Option<PdfProtectOptions>,
+    pub pdfa: Option<String>,
+    pub docx_html: Option<DocxHtmlOptions>,
+    pub epub: Option<EpubOptions>,
+    pub epub_html: Option<EpubHtmlOptions>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -446,6 +756,26 @@ fn main() {
             get_supported_formats,
             convert_file,
             resize_image_cmd,
+            create_contact_sheet_cmd,
+            images_to_pdf_cmd,
+            images_to_tiff_cmd,
+            merge_pdfs_cmd,
+            split_pdf_cmd,
+            extract_pdf_pages_cmd,
+            delete_pdf_pages_cmd,
+            rotate_pdf_pages_cmd,
+            reorder_pdf_pages_cmd,
+            optimize_pdf_cmd,
+            edit_pdf_metadata_cmd,
+            protect_pdf_cmd,
+            decrypt_pdf_cmd,
+            convert_to_pdfa_cmd,
+            validate_pdfa_cmd,
+            get_pdf_form_fields_cmd,
+            extract_pdf_form_cmd,
+            fill_pdf_form_cmd,
+            batch_fill_pdf_form_cmd,
+            compare_files_cmd,
             get_image_preview_cmd,
             get_image_info_cmd,
             get_pdf_info_cmd,